@@ -0,0 +1,42 @@
+//! Device attributes
+//!
+//! A device exposes its tunables and state as attributes, small text values
+//! the kernel shows as files under `/sys/devices/<device>/`, like the ring
+//! sizes of a network interface. Reading a file calls `show`, writing one
+//! calls `store` with what was written, surrounding whitespace trimmed.
+
+use alloc::string::String;
+
+use crate::{RegisterError, ops};
+
+/// Reads an attribute, given the context the device was registered with
+pub type Show = fn(usize) -> Result<String, &'static str>;
+
+/// Sets an attribute, rejecting invalid values with the reason
+pub type Store = fn(usize, &str) -> Result<(), &'static str>;
+
+/// One attribute of a device
+pub struct Attribute {
+    /// File name
+    pub name: &'static str,
+    /// Current value
+    pub show: Show,
+    /// Set the value; `None` if the attribute is read-only
+    pub store: Option<Store>,
+}
+
+/// Expose the attributes of a device, passing `context` to their functions
+///
+/// Fails if a device with the same name has attributes already.
+pub fn register(
+    device: &str,
+    attributes: &'static [Attribute],
+    context: usize,
+) -> Result<(), RegisterError> {
+    (ops().register_attributes)(device, attributes, context)
+}
+
+/// Remove the attributes of a device that went away
+pub fn unregister(device: &str) {
+    (ops().unregister_attributes)(device)
+}
@@ -0,0 +1,70 @@
+//! Block devices
+//!
+//! A block device reads and writes whole blocks addressed by their logical
+//! block address (LBA). Registered devices are listed by the kernel under
+//! their name and can hold a hibernation image or a file system.
+
+use alloc::sync::Arc;
+
+use crate::{RegisterError, ops};
+
+/// Why a block request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BlockError {
+    /// The request reaches past the last block
+    OutOfRange,
+    /// The buffer isn't a whole number of blocks
+    BufferSize,
+    /// The device isn't ready, e.g. while the system is suspended
+    NotReady,
+    /// The device reported an error
+    Io,
+}
+
+/// A device storing data in fixed-size blocks
+pub trait BlockDevice: Send + Sync {
+    /// Unique name, like `nvme0n1`
+    fn name(&self) -> &str;
+
+    /// Size of a block in bytes
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device
+    fn block_count(&self) -> u64;
+
+    /// Read blocks starting at `lba` into `buffer`, whose length is a
+    /// multiple of the block size
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buffer`, whose length is a multiple of the block size, to the
+    /// blocks starting at `lba`
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Make every completed write durable
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// Check a request of `len` bytes at `lba` against a device, returning the
+/// number of blocks it covers
+pub fn check_request(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    let block_size = device.block_size();
+    if block_size == 0 || !len.is_multiple_of(block_size) {
+        return Err(BlockError::BufferSize);
+    }
+    let blocks = (len / block_size) as u64;
+    match lba.checked_add(blocks) {
+        Some(end) if end <= device.block_count() => Ok(blocks),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Make a block device available to the kernel
+///
+/// A device registered again under the same name replaces the old one, so a
+/// driver can register its devices each time it probes.
+pub fn register(device: Arc<dyn BlockDevice>) -> Result<(), RegisterError> {
+    (ops().register_block_device)(device)
+}
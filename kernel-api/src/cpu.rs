@@ -0,0 +1,16 @@
+//! Processors
+
+use crate::ops;
+
+/// Index of the CPU the caller runs on
+///
+/// The task may move to another CPU right after, so this is only a hint,
+/// e.g. to pick the queue pair of a device.
+pub fn current_cpu() -> usize {
+    (ops().current_cpu)()
+}
+
+/// Number of CPUs running tasks
+pub fn online_cpus() -> usize {
+    (ops().online_cpus)()
+}
@@ -0,0 +1,81 @@
+//! Memory for DMA
+//!
+//! DMA buffers are physically contiguous and zeroed when allocated, and
+//! reachable by the CPU through the returned virtual address.
+
+use core::slice;
+
+use crate::ops;
+
+/// Why DMA memory couldn't be allocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DmaError {
+    /// Not enough contiguous frames are free
+    OutOfMemory,
+    /// The kernel was built without DMA support
+    Unavailable,
+}
+
+/// Frames handed out by the kernel for DMA
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaRegion {
+    /// Address for the device
+    pub phys_addr: u64,
+    /// Address for the CPU
+    pub virt_addr: *mut u8,
+    /// Size in 4 KiB frames
+    pub frames: usize,
+}
+
+unsafe impl Send for DmaRegion {}
+unsafe impl Sync for DmaRegion {}
+
+/// A DMA buffer, freed when dropped
+///
+/// The device must be done with it before it is dropped.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    region: DmaRegion,
+}
+
+impl DmaBuffer {
+    /// Allocate `frames` contiguous, zeroed frames
+    pub fn new_zeroed(frames: usize) -> Result<Self, DmaError> {
+        (ops().dma_alloc)(frames).map(|region| Self { region })
+    }
+
+    /// Address to hand to the device
+    pub fn phys_addr(&self) -> u64 {
+        self.region.phys_addr
+    }
+
+    /// Size in bytes
+    pub fn len(&self) -> usize {
+        self.region.frames * 4096
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Address for the CPU, for memory the device reads or writes while the
+    /// buffer is shared
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.region.virt_addr
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.region.virt_addr, self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.region.virt_addr, self.len()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe { (ops().dma_free)(self.region) };
+    }
+}
@@ -0,0 +1,92 @@
+//! Interrupts and deferred work
+//!
+//! A driver takes vectors for its device's MSI or MSI-X interrupts from the
+//! kernel's dynamic range. Handlers run in interrupt context, so they only
+//! note what happened and schedule a `Work` item; the kernel's deferred-work
+//! task runs the item's function shortly after, with interrupts enabled and
+//! free to sleep.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::ops;
+
+/// Interrupt handler, called with the data given when its vector was taken
+pub type Handler = fn(usize);
+
+/// Take a free vector and have it call `handler` with `data`
+///
+/// `owner` names the driver in the kernel's vector listing. Returns `None`
+/// if every vector is taken.
+pub fn allocate_vector(owner: &'static str, handler: Handler, data: usize) -> Option<u8> {
+    (ops().allocate_vector)(owner, handler, data)
+}
+
+/// Give back a vector taken with `allocate_vector`
+///
+/// The device must no longer send it.
+pub fn release_vector(vector: u8) {
+    (ops().release_vector)(vector)
+}
+
+/// Number of vectors `allocate_vector` can still hand out
+pub fn available_vectors() -> usize {
+    (ops().available_vectors)()
+}
+
+/// Work an interrupt handler hands to the deferred-work task
+///
+/// Scheduling an item that is already pending does nothing, so a burst of
+/// interrupts is handled by one run. The pending flag is cleared before the
+/// function runs, so an interrupt arriving during the run schedules another.
+pub struct Work {
+    pub name: &'static str,
+    func: fn(),
+    pending: AtomicBool,
+    runs: AtomicU64,
+}
+
+impl Work {
+    pub const fn new(name: &'static str, func: fn()) -> Self {
+        Self {
+            name,
+            func,
+            pending: AtomicBool::new(false),
+            runs: AtomicU64::new(0),
+        }
+    }
+
+    /// Have the deferred-work task look at the item, needed once before
+    /// `schedule` does anything
+    ///
+    /// Must not be called from interrupt handlers.
+    pub fn register(&'static self) {
+        (ops().register_work)(self)
+    }
+
+    /// Have the deferred-work task run the item
+    ///
+    /// May be called from interrupt handlers.
+    pub fn schedule(&'static self) {
+        if !self.pending.swap(true, Ordering::AcqRel) {
+            (ops().wake_worker)();
+        }
+    }
+
+    /// Whether the item is scheduled and hasn't run yet
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Run the item if it is scheduled, for the kernel's deferred-work task
+    pub fn run_pending(&self) {
+        if self.pending.swap(false, Ordering::AcqRel) {
+            (self.func)();
+            self.runs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Times the item ran
+    pub fn runs(&self) -> u64 {
+        self.runs.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,115 @@
+//! Stable interfaces between locOS and its drivers
+//!
+//! A driver written against this crate needs nothing from the kernel's own
+//! modules: block devices, network interfaces, serial terminals, device
+//! attributes and shell commands are registered through it, and logging, DMA
+//! memory, interrupt vectors, deferred work, wait queues and sleeping are used
+//! through it. The kernel installs its side of the API with `install` early
+//! during boot, before any driver is probed.
+//!
+//! Finding a device on its bus and mapping its registers isn't covered yet;
+//! PCIe drivers still get their device, BARs and MSI-X table from the
+//! kernel's `pci` module.
+//!
+//! # Stability
+//!
+//! `VERSION` tracks changes to the API rather than to the kernel:
+//!
+//! - adding an item, a defaulted trait method or an error variant bumps the
+//!   minor version; error enums are `#[non_exhaustive]` so matching on them
+//!   keeps compiling
+//! - changing or removing an item, or adding a trait method without a
+//!   default, bumps the major version
+//!
+//! Kernel internals must not leak into this crate; anything a driver needs
+//! from the kernel goes through `KernelOps`.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod attr;
+pub mod block;
+pub mod cpu;
+pub mod dma;
+pub mod irq;
+pub mod log;
+pub mod net;
+pub mod shell;
+pub mod tty;
+pub mod wait;
+
+use alloc::sync::Arc;
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// Version of the API, see the crate docs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+pub const VERSION: Version = Version { major: 0, minor: 4 };
+
+/// Why something couldn't be registered with the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RegisterError {
+    /// Something with the same name is registered already
+    AlreadyRegistered,
+    /// The kernel was built without the subsystem
+    Unsupported,
+}
+
+/// The kernel's side of the API, installed once at boot
+///
+/// Drivers don't call these directly but through the functions and types of
+/// the modules of this crate.
+pub struct KernelOps {
+    pub log: fn(log::Level, fmt::Arguments),
+    /// Block until the condition holds, waking up whenever `wake_all` is
+    /// called with the same key
+    pub wait: fn(usize, &mut dyn FnMut() -> bool),
+    pub wake_all: fn(usize),
+    pub sleep_ms: fn(u64),
+    pub current_cpu: fn() -> usize,
+    pub online_cpus: fn() -> usize,
+    /// Allocate physically contiguous, zeroed frames
+    pub dma_alloc: fn(usize) -> Result<dma::DmaRegion, dma::DmaError>,
+    /// Free frames returned by `dma_alloc`
+    pub dma_free: unsafe fn(dma::DmaRegion),
+    pub allocate_vector: fn(&'static str, irq::Handler, usize) -> Option<u8>,
+    pub release_vector: fn(u8),
+    pub available_vectors: fn() -> usize,
+    pub register_work: fn(&'static irq::Work),
+    /// Wake the deferred-work task, after a work item was scheduled
+    pub wake_worker: fn(),
+    pub register_block_device: fn(Arc<dyn block::BlockDevice>) -> Result<(), RegisterError>,
+    pub register_net_interface: fn(Arc<dyn net::NetInterface>) -> Result<(), RegisterError>,
+    pub register_command: fn(&'static shell::ShellCommand) -> Result<(), RegisterError>,
+    pub register_tty: fn(Arc<dyn tty::SerialDevice>) -> Result<(), RegisterError>,
+    pub register_attributes:
+        fn(&str, &'static [attr::Attribute], usize) -> Result<(), RegisterError>,
+    pub unregister_attributes: fn(&str),
+}
+
+static OPS: AtomicPtr<KernelOps> = AtomicPtr::new(ptr::null_mut());
+
+/// Install the kernel's side of the API
+///
+/// Called once by the kernel at boot.
+pub fn install(ops: &'static KernelOps) {
+    OPS.store(ptr::from_ref(ops).cast_mut(), Ordering::Release);
+}
+
+fn ops() -> &'static KernelOps {
+    let ops = OPS.load(Ordering::Acquire);
+    assert!(
+        !ops.is_null(),
+        "kernel API used before the kernel installed it"
+    );
+    unsafe { &*ops }
+}
@@ -0,0 +1,47 @@
+//! Network interfaces
+//!
+//! An interface sends and receives Ethernet frames; protocols are the
+//! network stack's business.
+
+use alloc::sync::Arc;
+
+use crate::{RegisterError, ops};
+
+/// Why a frame couldn't be sent or received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NetError {
+    /// The link is down
+    LinkDown,
+    /// The frame is larger than the MTU allows
+    FrameTooLarge,
+    /// The transmit queue is full, try again later
+    QueueFull,
+    /// The device reported an error
+    Io,
+}
+
+/// A network interface
+pub trait NetInterface: Send + Sync {
+    /// Unique name, like `eth0`
+    fn name(&self) -> &str;
+
+    fn mac_address(&self) -> [u8; 6];
+
+    /// Largest payload of a frame in bytes
+    fn mtu(&self) -> usize;
+
+    fn link_up(&self) -> bool;
+
+    /// Queue a frame for sending
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Copy the next received frame into `buffer`, returning its length, or
+    /// None if no frame is waiting
+    fn receive(&self, buffer: &mut [u8]) -> Result<Option<usize>, NetError>;
+}
+
+/// Make a network interface available to the kernel
+pub fn register(interface: Arc<dyn NetInterface>) -> Result<(), RegisterError> {
+    (ops().register_net_interface)(interface)
+}
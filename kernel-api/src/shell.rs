@@ -0,0 +1,20 @@
+//! Shell commands
+
+use crate::{RegisterError, ops};
+
+/// A command run from the shell prompt
+pub struct ShellCommand {
+    /// Name typed at the prompt
+    pub name: &'static str,
+    /// One line description shown by `help`
+    pub help: &'static str,
+    /// Entry point, called with the arguments following the name
+    pub run: fn(&[&str]),
+}
+
+/// Add a command to the shell
+///
+/// Fails if a command with the same name exists, built-in commands included.
+pub fn register(command: &'static ShellCommand) -> Result<(), RegisterError> {
+    (ops().register_command)(command)
+}
@@ -0,0 +1,91 @@
+//! Serial terminals
+//!
+//! A serial device moves bytes both ways over a line with a configurable
+//! rate and framing, like a USB serial adapter. Registered devices are listed
+//! by the kernel under their name and can carry a console.
+
+use alloc::sync::Arc;
+
+use crate::{RegisterError, ops};
+
+/// Why a serial device couldn't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TtyError {
+    /// The device went away
+    Disconnected,
+    /// The device doesn't support the requested line coding
+    Unsupported,
+    /// The device reported an error
+    Io,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    OneAndHalf,
+    Two,
+}
+
+/// Rate and framing of a serial line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineCoding {
+    /// Bits per second
+    pub baud: u32,
+    /// 5, 6, 7, 8 or 16
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl LineCoding {
+    /// 115200 baud, 8 data bits, no parity, one stop bit
+    pub const DEFAULT: LineCoding = LineCoding {
+        baud: 115200,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+    };
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// A serial device
+///
+/// Reads and writes never block, so the console can use a device from any
+/// context; bytes are buffered and moved by the driver in the background.
+pub trait SerialDevice: Send + Sync {
+    /// Unique name, like `ttyACM0`
+    fn name(&self) -> &str;
+
+    fn line_coding(&self) -> LineCoding;
+
+    fn set_line_coding(&self, coding: LineCoding) -> Result<(), TtyError>;
+
+    /// Queue `bytes` for sending, returning how many fit in the buffer
+    fn write(&self, bytes: &[u8]) -> Result<usize, TtyError>;
+
+    /// Copy received bytes into `buffer`, returning how many there were
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, TtyError>;
+}
+
+/// Make a serial device available to the kernel
+///
+/// A device registered again under the same name replaces the old one, so a
+/// driver can register its devices each time it probes.
+pub fn register(device: Arc<dyn SerialDevice>) -> Result<(), RegisterError> {
+    (ops().register_tty)(device)
+}
@@ -0,0 +1,53 @@
+//! Wait queues
+//!
+//! A task waits on a queue until a condition holds, typically set by an
+//! interrupt handler that then wakes the queue. `sleep_ms` waits for time to
+//! pass instead.
+
+use crate::ops;
+
+/// Tasks waiting for a condition
+///
+/// The queue is identified by its address, so it must not move while tasks
+/// wait on it; keep it in a static or behind an `Arc`.
+pub struct WaitQueue {
+    /// Gives the queue a size, and so an address of its own
+    _key: u8,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { _key: 0 }
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Block until `condition` returns true
+    ///
+    /// The condition is checked before blocking and after every wakeup, with
+    /// interrupts disabled, so a wakeup between the check and blocking isn't
+    /// lost. It must not block itself.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        (ops().wait)(self.key(), &mut condition);
+    }
+
+    /// Wake every task waiting on the queue
+    pub fn wake_all(&self) {
+        (ops().wake_all)(self.key());
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sleep for at least `ms` milliseconds
+///
+/// Must be called from a task with interrupts enabled.
+pub fn sleep_ms(ms: u64) {
+    (ops().sleep_ms)(ms)
+}
@@ -1,5 +1,73 @@
+use std::{
+    env,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 fn main() {
     // add linker and listener
     println!("cargo:rerun-if-changed=linker.ld");
     println!("cargo:rustc-link-arg=-Tlinker.ld");
+
+    build_info();
+}
+
+/// Record what the kernel was built from, for `meta::build`
+///
+/// The build time is when this script last ran, which is whenever a commit is
+/// made, files are staged or the feature set changes.
+fn build_info() {
+    if let Some(git_dir) = output("git", &["rev-parse", "--absolute-git-dir"]) {
+        let git_dir = Path::new(&git_dir);
+        println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+        println!("cargo:rerun-if-changed={}", git_dir.join("index").display());
+        if let Some(head) = output("git", &["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}", git_dir.join(head).display());
+        }
+    }
+
+    let hash = output("git", &["rev-parse", "--short=12", "HEAD"]);
+    let dirty = output("git", &["status", "--porcelain", "--untracked-files=no"])
+        .is_some_and(|status| !status.is_empty());
+    let hash = match hash {
+        Some(hash) if dirty => hash + "-dirty",
+        Some(hash) => hash,
+        None => "unknown".into(),
+    };
+    println!("cargo:rustc-env=LOCOS_GIT_HASH={hash}");
+
+    // SOURCE_DATE_EPOCH pins the time for reproducible builds
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs())
+        });
+    println!("cargo:rustc-env=LOCOS_BUILD_TIME={build_time}");
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            let feature = name.strip_prefix("CARGO_FEATURE_")?;
+            (feature != "DEFAULT").then(|| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=LOCOS_FEATURES={}", features.join(" "));
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let rustc_version = output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=LOCOS_RUSTC={rustc_version}");
+}
+
+/// Trimmed standard output of a command, if it ran and succeeded
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
 }
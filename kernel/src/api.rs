@@ -0,0 +1,153 @@
+//! The kernel's side of `kernel_api`
+//!
+//! Drivers only see the `kernel_api` crate; this module backs it with the
+//! kernel's logging, scheduler, DMA allocator, interrupt vectors, deferred
+//! work and registries.
+
+use alloc::sync::Arc;
+use core::fmt;
+
+use kernel_api::{
+    KernelOps, RegisterError,
+    dma::{DmaError, DmaRegion},
+    log::Level,
+    net::NetInterface,
+};
+use x86_64::instructions::interrupts;
+
+use crate::{
+    block, cpu, debug, error, fs, info,
+    interrupts::vectors,
+    shell::commands,
+    tasks::{
+        deferred,
+        scheduler::{sleep_ticks, wait_for_event, wake_event_waiters},
+    },
+    time, trace, tty, warn,
+};
+
+static OPS: KernelOps = KernelOps {
+    log,
+    wait,
+    wake_all: wake_event_waiters,
+    sleep_ms,
+    current_cpu: cpu::current_cpu,
+    online_cpus: cpu::online_cpus,
+    dma_alloc,
+    dma_free,
+    allocate_vector: vectors::allocate,
+    release_vector: vectors::release,
+    available_vectors: vectors::available,
+    register_work: deferred::register,
+    wake_worker: deferred::wake_worker,
+    register_block_device: block::register,
+    register_net_interface,
+    register_command: commands::register,
+    register_tty: tty::register,
+    register_attributes: fs::sysfs::register,
+    unregister_attributes: fs::sysfs::unregister,
+};
+
+/// Install the kernel's side of the API, before any driver is probed
+pub fn init() {
+    kernel_api::install(&OPS);
+}
+
+#[allow(unused_variables)]
+fn log(level: Level, args: fmt::Arguments) {
+    match level {
+        Level::Error => {
+            error!("{}", args);
+        }
+        Level::Warn => {
+            warn!("{}", args);
+        }
+        Level::Info => {
+            info!("{}", args);
+        }
+        Level::Debug => {
+            debug!("{}", args);
+        }
+        Level::Trace => {
+            trace!("{}", args);
+        }
+    }
+}
+
+fn wait(key: usize, condition: &mut dyn FnMut() -> bool) {
+    let enabled = interrupts::are_enabled();
+    loop {
+        interrupts::disable();
+        if condition() {
+            break;
+        }
+        wait_for_event(key);
+    }
+    if enabled {
+        interrupts::enable();
+    }
+}
+
+fn sleep_ms(ms: u64) {
+    sleep_ticks(time::ms_to_ticks(ms));
+}
+
+/// Single frames come from the DMA pool while it has free buffers
+#[cfg(any(feature = "usb", feature = "nvme"))]
+fn dma_alloc(frames: usize) -> Result<DmaRegion, DmaError> {
+    use crate::pci::dma::{DMA_MANAGER, allocate_zeroed_dma};
+
+    let pooled = (frames == 1)
+        .then(|| DMA_MANAGER.lock().get_pool_4kb())
+        .flatten();
+    let buffer = match pooled {
+        Some(buffer) => {
+            // pooled buffers are reused, so whatever the last user left is cleared
+            unsafe { buffer.virt_addr.as_mut_ptr::<u8>().write_bytes(0, 4096) };
+            buffer
+        }
+        None => allocate_zeroed_dma(frames).map_err(|_| DmaError::OutOfMemory)?,
+    };
+    Ok(DmaRegion {
+        phys_addr: buffer.phys_addr.as_u64(),
+        virt_addr: buffer.virt_addr.as_mut_ptr(),
+        frames: buffer.size,
+    })
+}
+
+#[cfg(any(feature = "usb", feature = "nvme"))]
+unsafe fn dma_free(region: DmaRegion) {
+    use crate::pci::dma::{DMA_MANAGER, DmaBuffer, free_zeroed_dma};
+    use x86_64::{PhysAddr, VirtAddr};
+
+    let buffer = DmaBuffer {
+        phys_addr: PhysAddr::new(region.phys_addr),
+        virt_addr: VirtAddr::from_ptr(region.virt_addr),
+        size: region.frames,
+    };
+    let mut manager = DMA_MANAGER.lock();
+    if manager.is_pooled_4kb(&buffer) {
+        manager.free_buffer_4kb(buffer);
+        return;
+    }
+    drop(manager);
+    let _ = free_zeroed_dma(buffer);
+}
+
+#[cfg(not(any(feature = "usb", feature = "nvme")))]
+fn dma_alloc(_frames: usize) -> Result<DmaRegion, DmaError> {
+    Err(DmaError::Unavailable)
+}
+
+#[cfg(not(any(feature = "usb", feature = "nvme")))]
+unsafe fn dma_free(_region: DmaRegion) {}
+
+#[cfg(feature = "net")]
+fn register_net_interface(interface: Arc<dyn NetInterface>) -> Result<(), RegisterError> {
+    crate::net::register(interface)
+}
+
+#[cfg(not(feature = "net"))]
+fn register_net_interface(_interface: Arc<dyn NetInterface>) -> Result<(), RegisterError> {
+    Err(RegisterError::Unsupported)
+}
@@ -0,0 +1,87 @@
+//! Registered block devices
+//!
+//! Drivers register their devices through `kernel_api::block`, and the rest
+//! of the kernel looks them up here by name. Devices are handed out wrapped
+//! in their `sched::Queue`, so every request is scheduled.
+
+pub mod gpt;
+pub mod sched;
+
+#[cfg(test)]
+pub mod tests;
+
+use alloc::{sync::Arc, vec::Vec};
+
+use kernel_api::RegisterError;
+pub use kernel_api::block::{BlockDevice, BlockError, check_request};
+use x86_64::instructions::interrupts;
+
+use crate::{info, sync::Mutex};
+use sched::Queue;
+
+/// A registered device, whose requests go through its queue
+struct Scheduled {
+    device: Arc<dyn BlockDevice>,
+    queue: Queue,
+}
+
+impl BlockDevice for Scheduled {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.queue
+            .run(buffer.len(), || self.device.read_blocks(lba, buffer))
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        self.queue
+            .run(buffer.len(), || self.device.write_blocks(lba, buffer))
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.queue.run(0, || self.device.flush())
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new("BLOCK_DEVICES", Vec::new());
+
+/// Add a device, replacing one registered under the same name
+pub fn register(device: Arc<dyn BlockDevice>) -> Result<(), RegisterError> {
+    info!(
+        "block device {}: {} blocks of {} bytes",
+        device.name(),
+        device.block_count(),
+        device.block_size()
+    );
+    let device: Arc<dyn BlockDevice> = Arc::new(Scheduled {
+        device,
+        queue: Queue::new(),
+    });
+    interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        devices.retain(|registered| registered.name() != device.name());
+        devices.push(device);
+    });
+    Ok(())
+}
+
+/// Look a device up by name
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    interrupts::without_interrupts(|| {
+        DEVICES
+            .lock()
+            .iter()
+            .find(|device| device.name() == name)
+            .cloned()
+    })
+}
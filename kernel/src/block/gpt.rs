@@ -0,0 +1,236 @@
+//! GUID partition tables
+//!
+//! `write` lays out a new table on a device: a protective MBR claiming the
+//! whole disk for GPT, the primary header and entries after it, and their
+//! backups at the end of the disk. Partitions start on 1 MiB boundaries, as
+//! every other tool puts them. A partition can then be used as a device of
+//! its own through `Partition`, which registers as `<device>p<number>`.
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+
+use super::{BlockDevice, BlockError, check_request};
+use crate::{crc32::crc32, memory::kaslr};
+
+/// Entries in the table, the minimum the specification allows
+const ENTRY_COUNT: usize = 128;
+const ENTRY_SIZE: usize = 128;
+const HEADER_SIZE: usize = 92;
+
+/// Partitions start on multiples of this many bytes
+const ALIGNMENT: u64 = 1024 * 1024;
+
+/// A GUID, in the mixed-endian layout it has on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// The GUID written as `data1-data2-data3-data4`
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let a = data1.to_le_bytes();
+        let b = data2.to_le_bytes();
+        let c = data3.to_le_bytes();
+        Self([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], data4[0], data4[1], data4[2], data4[3],
+            data4[4], data4[5], data4[6], data4[7],
+        ])
+    }
+
+    /// A random (version 4) GUID
+    pub fn random() -> Self {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&kaslr::random().to_le_bytes());
+        bytes[8..].copy_from_slice(&kaslr::random().to_le_bytes());
+        bytes[7] = (bytes[7] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Self(bytes)
+    }
+}
+
+/// Type of the EFI System Partition, which firmware boots from
+pub const EFI_SYSTEM: Guid = Guid::new(
+    0xc12a_7328,
+    0xf81f,
+    0x11d2,
+    [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b],
+);
+
+/// A partition to create
+#[derive(Debug, Clone, Copy)]
+pub struct NewPartition<'a> {
+    pub kind: Guid,
+    pub name: &'a str,
+    /// Size in bytes, None for the rest of the disk
+    pub size: Option<u64>,
+}
+
+/// Why a table couldn't be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GptError {
+    /// The partitions don't fit on the device
+    TooSmall,
+    /// The table can't describe the partitions
+    TooManyPartitions,
+    Io(BlockError),
+}
+
+impl From<BlockError> for GptError {
+    fn from(error: BlockError) -> Self {
+        GptError::Io(error)
+    }
+}
+
+/// Write a new partition table to `device`, returning the first and last
+/// block of each partition
+///
+/// Anything the device held before is lost.
+pub fn write(
+    device: &dyn BlockDevice,
+    partitions: &[NewPartition],
+) -> Result<Vec<(u64, u64)>, GptError> {
+    if partitions.len() > ENTRY_COUNT {
+        return Err(GptError::TooManyPartitions);
+    }
+    let block_size = device.block_size() as u64;
+    let entry_blocks = ((ENTRY_COUNT * ENTRY_SIZE) as u64).div_ceil(block_size);
+    let last_block = device
+        .block_count()
+        .checked_sub(1)
+        .ok_or(GptError::TooSmall)?;
+    let first_usable = 2 + entry_blocks;
+    let last_usable = last_block
+        .checked_sub(1 + entry_blocks)
+        .ok_or(GptError::TooSmall)?;
+
+    // lay the partitions out one after the other
+    let alignment = (ALIGNMENT / block_size).max(1);
+    let mut next = first_usable.next_multiple_of(alignment);
+    let mut ranges = Vec::with_capacity(partitions.len());
+    for partition in partitions {
+        let last = match partition.size {
+            Some(size) => next + size.div_ceil(block_size) - 1,
+            None => last_usable,
+        };
+        if next > last || last > last_usable {
+            return Err(GptError::TooSmall);
+        }
+        ranges.push((next, last));
+        next = (last + 1).next_multiple_of(alignment);
+    }
+
+    let mut entries = vec![0u8; (entry_blocks * block_size) as usize];
+    for ((partition, &(first, last)), entry) in partitions
+        .iter()
+        .zip(&ranges)
+        .zip(entries.chunks_exact_mut(ENTRY_SIZE))
+    {
+        entry[0..16].copy_from_slice(&partition.kind.0);
+        entry[16..32].copy_from_slice(&Guid::random().0);
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        // attributes stay 0, the name is UTF-16 of up to 36 units
+        for (unit, bytes) in partition
+            .name
+            .encode_utf16()
+            .zip(entry[56..128].chunks_exact_mut(2))
+        {
+            bytes.copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    let entries_crc = crc32(&entries[..ENTRY_COUNT * ENTRY_SIZE]);
+
+    let disk = Guid::random();
+    let header = |current: u64, backup: u64, entries_at: u64| {
+        let mut block = vec![0u8; block_size as usize];
+        block[0..8].copy_from_slice(b"EFI PART");
+        block[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        block[12..16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        block[24..32].copy_from_slice(&current.to_le_bytes());
+        block[32..40].copy_from_slice(&backup.to_le_bytes());
+        block[40..48].copy_from_slice(&first_usable.to_le_bytes());
+        block[48..56].copy_from_slice(&last_usable.to_le_bytes());
+        block[56..72].copy_from_slice(&disk.0);
+        block[72..80].copy_from_slice(&entries_at.to_le_bytes());
+        block[80..84].copy_from_slice(&(ENTRY_COUNT as u32).to_le_bytes());
+        block[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+        block[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = crc32(&block[..HEADER_SIZE]);
+        block[16..20].copy_from_slice(&crc.to_le_bytes());
+        block
+    };
+
+    // the backup goes first, so a table is only found once it is complete
+    let backup_entries = last_block - entry_blocks;
+    device.write_blocks(backup_entries, &entries)?;
+    device.write_blocks(last_block, &header(last_block, 1, backup_entries))?;
+    device.write_blocks(2, &entries)?;
+    device.write_blocks(1, &header(1, last_block, 2))?;
+    device.write_blocks(0, &protective_mbr(block_size as usize, last_block))?;
+    device.flush()?;
+    Ok(ranges)
+}
+
+/// An MBR with a single partition of type 0xEE covering the disk, so tools
+/// that only know MBR leave it alone
+fn protective_mbr(block_size: usize, last_block: u64) -> Vec<u8> {
+    let mut block = vec![0u8; block_size];
+    let entry = &mut block[446..462];
+    // CHS addresses are meaningless on disks this size, they are set to
+    // what the specification asks for
+    entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    entry[4] = 0xee;
+    entry[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+    entry[12..16].copy_from_slice(&(last_block.min(u32::MAX as u64) as u32).to_le_bytes());
+    block[510] = 0x55;
+    block[511] = 0xaa;
+    block
+}
+
+/// A range of blocks of a device, as a device of its own
+pub struct Partition {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    first: u64,
+    count: u64,
+}
+
+impl Partition {
+    /// Partition `number`, counting from 1, spanning blocks `first` to
+    /// `last` of `device`
+    pub fn new(device: Arc<dyn BlockDevice>, number: usize, (first, last): (u64, u64)) -> Self {
+        Self {
+            name: format!("{}p{}", device.name(), number),
+            device,
+            first,
+            count: last + 1 - first,
+        }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buffer.len())?;
+        self.device.read_blocks(self.first + lba, buffer)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buffer.len())?;
+        self.device.write_blocks(self.first + lba, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
@@ -0,0 +1,337 @@
+//! Block I/O scheduling
+//!
+//! Every request to a registered block device goes through the device's
+//! `Queue`, which lets one request run at a time and picks the next one by
+//! the I/O priority of the task that made it:
+//!
+//! - real-time requests go first, then best-effort ones, lower levels first
+//! - idle requests only run once no other request ran for `io.idle_delay_ms`
+//!
+//! Requests of the same priority run in the order they were made. A task can
+//! also have its bandwidth capped, in which case its requests wait until the
+//! task earned enough bytes to make them; up to `io.burst_ms` worth of unused
+//! bandwidth is saved up for bursts.
+//!
+//! Settings are kept per task and dropped with `release` when it exits.
+
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    sync::Mutex,
+    sysctl::Sysctl,
+    tasks::scheduler::{TASKS, current_pid, sleep_ticks, wait_for_event, wake_event_waiters},
+    time,
+};
+
+/// Number of best-effort and real-time levels, 0 being the highest
+pub const LEVELS: u8 = 8;
+
+static IDLE_DELAY_MS: AtomicU64 = AtomicU64::new(100);
+static BURST_MS: AtomicU64 = AtomicU64::new(1000);
+
+pub static SYSCTLS: &[Sysctl] = &[
+    Sysctl {
+        name: "io.idle_delay_ms",
+        help: "time without other I/O before idle-class requests run",
+        get: || IDLE_DELAY_MS.load(Ordering::Relaxed),
+        set: |value| {
+            if value > 10_000 {
+                return Err("at most 10000 ms");
+            }
+            IDLE_DELAY_MS.store(value, Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Sysctl {
+        name: "io.burst_ms",
+        help: "unused bandwidth a capped task can save up, in ms of its cap",
+        get: || BURST_MS.load(Ordering::Relaxed),
+        set: |value| {
+            if !(1..=10_000).contains(&value) {
+                return Err("must be 1-10000 ms");
+            }
+            BURST_MS.store(value, Ordering::Relaxed);
+            Ok(())
+        },
+    },
+];
+
+/// Scheduling class of a task's I/O
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IoClass {
+    RealTime,
+    BestEffort,
+    Idle,
+}
+
+/// Class and level of a task's I/O, ordered from the highest priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IoPriority {
+    pub class: IoClass,
+    /// 0 to `LEVELS - 1`, always 0 for the idle class
+    pub level: u8,
+}
+
+impl IoPriority {
+    pub const DEFAULT: IoPriority = IoPriority {
+        class: IoClass::BestEffort,
+        level: 4,
+    };
+}
+
+impl fmt::Display for IoPriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.class {
+            IoClass::RealTime => write!(f, "realtime: prio {}", self.level),
+            IoClass::BestEffort => write!(f, "best-effort: prio {}", self.level),
+            IoClass::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+/// Why I/O settings couldn't be changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoSettingsError {
+    NoSuchTask,
+    InvalidLevel,
+    InvalidLimit,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TaskIo {
+    priority: IoPriority,
+    /// Bytes per second, None if not capped
+    limit: Option<u64>,
+    /// Bytes the task may still transfer, negative after a request larger
+    /// than what was saved up
+    tokens: i64,
+    /// Uptime in milliseconds the tokens were last topped up at
+    refilled: u64,
+}
+
+impl Default for TaskIo {
+    fn default() -> Self {
+        Self {
+            priority: IoPriority::DEFAULT,
+            limit: None,
+            tokens: 0,
+            refilled: time::uptime_ms(),
+        }
+    }
+}
+
+static TASK_IO: Mutex<BTreeMap<u64, TaskIo>> = Mutex::new("TASK_IO", BTreeMap::new());
+
+fn task_exists(pid: u64) -> bool {
+    TASKS
+        .read()
+        .is_some_and(|tasks| tasks.iter().any(|task| task.pid == pid))
+}
+
+fn update_task(pid: u64, f: impl FnOnce(&mut TaskIo)) -> Result<(), IoSettingsError> {
+    if !task_exists(pid) {
+        return Err(IoSettingsError::NoSuchTask);
+    }
+    interrupts::without_interrupts(|| f(TASK_IO.lock().entry(pid).or_default()));
+    Ok(())
+}
+
+pub fn io_priority(pid: u64) -> IoPriority {
+    interrupts::without_interrupts(|| {
+        TASK_IO
+            .lock()
+            .get(&pid)
+            .map_or(IoPriority::DEFAULT, |task| task.priority)
+    })
+}
+
+pub fn set_io_priority(pid: u64, priority: IoPriority) -> Result<(), IoSettingsError> {
+    if priority.level >= LEVELS || (priority.class == IoClass::Idle && priority.level != 0) {
+        return Err(IoSettingsError::InvalidLevel);
+    }
+    update_task(pid, |task| task.priority = priority)
+}
+
+/// Bandwidth cap of a task in bytes per second
+pub fn io_limit(pid: u64) -> Option<u64> {
+    interrupts::without_interrupts(|| TASK_IO.lock().get(&pid).and_then(|task| task.limit))
+}
+
+/// Cap the bandwidth of a task to `limit` bytes per second, or lift the cap
+pub fn set_io_limit(pid: u64, limit: Option<u64>) -> Result<(), IoSettingsError> {
+    if limit == Some(0) {
+        return Err(IoSettingsError::InvalidLimit);
+    }
+    update_task(pid, |task| {
+        task.limit = limit;
+        task.tokens = limit.map_or(0, burst);
+        task.refilled = time::uptime_ms();
+    })
+}
+
+/// Forget the settings of a task that exited
+pub fn release(pid: u64) {
+    interrupts::without_interrupts(|| TASK_IO.lock().remove(&pid));
+}
+
+/// Bytes a task capped at `limit` can save up
+fn burst(limit: u64) -> i64 {
+    (limit.saturating_mul(BURST_MS.load(Ordering::Relaxed)) / 1000).max(1) as i64
+}
+
+/// Wait until the current task may transfer `bytes` under its cap
+fn throttle(pid: u64, bytes: usize) {
+    loop {
+        let wait = interrupts::without_interrupts(|| {
+            let mut tasks = TASK_IO.lock();
+            let Some(task) = tasks.get_mut(&pid) else {
+                return 0;
+            };
+            let Some(limit) = task.limit else {
+                return 0;
+            };
+
+            let now = time::uptime_ms();
+            let earned = limit.saturating_mul(now - task.refilled) / 1000;
+            // leftover time is kept until it earns a whole byte
+            if earned > 0 {
+                task.tokens = (task.tokens + earned as i64).min(burst(limit));
+                task.refilled = now;
+            }
+            if task.tokens > 0 {
+                task.tokens -= bytes as i64;
+                return 0;
+            }
+            time::ms_to_ticks(((1 - task.tokens) as u64 * 1000).div_ceil(limit))
+        });
+        if wait == 0 {
+            return;
+        }
+        sleep_ticks(wait);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Waiter {
+    priority: IoPriority,
+    /// Order the request was made in
+    seq: u64,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    /// Whether a request is running
+    busy: bool,
+    waiting: Vec<Waiter>,
+    next_seq: u64,
+    /// Tick the last request that wasn't idle ran at
+    last_io: u64,
+}
+
+impl QueueState {
+    /// The request to run next
+    fn next(&self) -> Option<Waiter> {
+        self.waiting
+            .iter()
+            .copied()
+            .min_by_key(|waiter| (waiter.priority, waiter.seq))
+    }
+}
+
+/// Requests waiting for one block device
+pub struct Queue {
+    state: Mutex<QueueState>,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Queue {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(
+                "BLOCK_QUEUE",
+                QueueState {
+                    busy: false,
+                    waiting: Vec::new(),
+                    next_seq: 0,
+                    last_io: 0,
+                },
+            ),
+        }
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Run `request`, which transfers `bytes`, once the current task's turn
+    /// comes
+    ///
+    /// Must be called from a task with interrupts enabled.
+    pub fn run<R>(&self, bytes: usize, request: impl FnOnce() -> R) -> R {
+        let pid = current_pid();
+        let priority = io_priority(pid);
+        throttle(pid, bytes);
+
+        self.acquire(priority);
+        let result = request();
+        self.release(priority);
+        result
+    }
+
+    fn acquire(&self, priority: IoPriority) {
+        let seq = interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiting.push(Waiter { priority, seq });
+            seq
+        });
+
+        loop {
+            interrupts::disable();
+            let mut state = self.state.lock();
+            if !state.busy && state.next().is_some_and(|next| next.seq == seq) {
+                let idle_until =
+                    state.last_io + time::ms_to_ticks(IDLE_DELAY_MS.load(Ordering::Relaxed));
+                if priority.class != IoClass::Idle || time::ticks() >= idle_until {
+                    state.waiting.retain(|waiter| waiter.seq != seq);
+                    state.busy = true;
+                    drop(state);
+                    interrupts::enable();
+                    return;
+                }
+
+                // only idle requests are waiting, check again once the delay
+                // is over
+                drop(state);
+                interrupts::enable();
+                sleep_ticks(idle_until.saturating_sub(time::ticks()).max(1));
+                continue;
+            }
+            drop(state);
+            wait_for_event(self.key());
+        }
+    }
+
+    fn release(&self, priority: IoPriority) {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.busy = false;
+            if priority.class != IoClass::Idle {
+                state.last_io = time::ticks();
+            }
+        });
+        wake_event_waiters(self.key());
+    }
+}
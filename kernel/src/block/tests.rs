@@ -0,0 +1,159 @@
+//! Block device tests
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+use x86_64::instructions::interrupts;
+
+use super::{
+    BlockDevice, BlockError, check_request,
+    gpt::{self, EFI_SYSTEM, GptError, NewPartition},
+};
+use crate::{crc32::crc32, sync::Mutex};
+
+/// A device kept in memory, where only written blocks take space and the
+/// rest read as zeros
+pub struct MemoryDevice {
+    block_size: usize,
+    block_count: u64,
+    blocks: Mutex<BTreeMap<u64, Vec<u8>>>,
+}
+
+impl MemoryDevice {
+    pub fn new(block_size: usize, block_count: u64) -> Self {
+        Self {
+            block_size,
+            block_count,
+            blocks: Mutex::new("MEMORY_DEVICE", BTreeMap::new()),
+        }
+    }
+
+    /// Read `count` blocks from `lba` on
+    pub fn read(&self, lba: u64, count: usize) -> Vec<u8> {
+        let mut buffer = vec![0; count * self.block_size];
+        self.read_blocks(lba, &mut buffer).unwrap();
+        buffer
+    }
+}
+
+impl BlockDevice for MemoryDevice {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buffer.len())?;
+        interrupts::without_interrupts(|| {
+            let blocks = self.blocks.lock();
+            for (block, chunk) in (lba..).zip(buffer.chunks_exact_mut(self.block_size)) {
+                match blocks.get(&block) {
+                    Some(data) => chunk.copy_from_slice(data),
+                    None => chunk.fill(0),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buffer.len())?;
+        interrupts::without_interrupts(|| {
+            let mut blocks = self.blocks.lock();
+            for (block, chunk) in (lba..).zip(buffer.chunks_exact(self.block_size)) {
+                blocks.insert(block, chunk.to_vec());
+            }
+        });
+        Ok(())
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[test_case]
+fn test_crc32_check_value() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+}
+
+#[test_case]
+fn test_gpt_partition_ranges() {
+    // 4 MiB, so the partitions start on 2048 block boundaries
+    let device = MemoryDevice::new(512, 8192);
+    let ranges = gpt::write(
+        &device,
+        &[
+            NewPartition {
+                kind: EFI_SYSTEM,
+                name: "EFI",
+                size: Some(1024 * 1024),
+            },
+            NewPartition {
+                kind: EFI_SYSTEM,
+                name: "rest",
+                size: None,
+            },
+        ],
+    )
+    .unwrap();
+    // the last usable block is before the backup entries and header
+    assert_eq!(ranges, [(2048, 4095), (4096, 8158)]);
+
+    let too_large = NewPartition {
+        kind: EFI_SYSTEM,
+        name: "large",
+        size: Some(4 * 1024 * 1024),
+    };
+    assert_eq!(gpt::write(&device, &[too_large]), Err(GptError::TooSmall));
+}
+
+#[test_case]
+fn test_gpt_headers_and_crcs() {
+    let device = MemoryDevice::new(512, 8192);
+    let partition = NewPartition {
+        kind: EFI_SYSTEM,
+        name: "EFI",
+        size: None,
+    };
+    gpt::write(&device, &[partition]).unwrap();
+
+    let mbr = device.read(0, 1);
+    assert_eq!(mbr[446 + 4], 0xee);
+    assert_eq!(u32_at(&mbr, 446 + 12), 8191);
+    assert_eq!(mbr[510..], [0x55, 0xaa]);
+
+    // the primary header, then its backup in the last block
+    for (lba, backup, entries_at) in [(1, 8191, 2), (8191, 1, 8159)] {
+        let mut header = device.read(lba, 1);
+        assert_eq!(&header[0..8], b"EFI PART");
+        assert_eq!(u64_at(&header, 24), lba);
+        assert_eq!(u64_at(&header, 32), backup);
+        assert_eq!(u64_at(&header, 40), 34);
+        assert_eq!(u64_at(&header, 48), 8158);
+        assert_eq!(u64_at(&header, 72), entries_at);
+
+        // 128 entries of 128 bytes
+        let entries = device.read(entries_at, 32);
+        assert_eq!(&entries[0..16], &EFI_SYSTEM.0);
+        assert_eq!(u64_at(&entries, 32), 2048);
+        assert_eq!(u64_at(&entries, 40), 8158);
+        assert_eq!(u32_at(&header, 88), crc32(&entries));
+
+        // the header's CRC is taken with its own field zeroed
+        let crc = u32_at(&header, 16);
+        header[16..20].fill(0);
+        assert_eq!(crc, crc32(&header[..92]));
+    }
+}
@@ -0,0 +1,50 @@
+//! Kernel command line handling.
+//!
+//! The command line is passed by Limine (`cmdline:` in limine.conf) as a list
+//! of whitespace separated `key=value` pairs or bare `flag`s, e.g.
+//! `heap=slab`. Lookups never allocate, so they are safe to use before the
+//! heap is initialized.
+//!
+//! Limine's copy of the command line is in bootloader-reclaimable memory, so
+//! it is copied into the kernel when recorded.
+
+use spin::Once;
+
+/// Longest command line kept, anything past it is dropped
+const CMDLINE_MAX: usize = 4096;
+
+static CMDLINE: Once<([u8; CMDLINE_MAX], usize)> = Once::new();
+
+/// Records the kernel command line. Only the first call has any effect.
+pub fn init(cmdline: &str) {
+    CMDLINE.call_once(|| {
+        let mut len = cmdline.len().min(CMDLINE_MAX);
+        while !cmdline.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut buffer = [0; CMDLINE_MAX];
+        buffer[..len].copy_from_slice(&cmdline.as_bytes()[..len]);
+        (buffer, len)
+    });
+}
+
+/// Returns the raw kernel command line, or an empty string if none was given.
+pub fn cmdline() -> &'static str {
+    CMDLINE.get().map_or("", |(buffer, len)| {
+        core::str::from_utf8(&buffer[..*len]).unwrap_or("")
+    })
+}
+
+/// Returns the value of a `key=value` boot argument.
+pub fn get(key: &str) -> Option<&'static str> {
+    cmdline()
+        .split_whitespace()
+        .filter_map(|arg| arg.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+/// Returns true if a bare `flag` boot argument is present.
+pub fn has_flag(flag: &str) -> bool {
+    cmdline().split_whitespace().any(|arg| arg == flag)
+}
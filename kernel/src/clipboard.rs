@@ -0,0 +1,30 @@
+//! Kernel clipboard.
+//!
+//! A single clipboard shared by every terminal, so text copied in one can be
+//! pasted into another.
+
+use alloc::string::String;
+
+use crate::sync::Mutex;
+
+/// Maximum number of bytes kept on the clipboard
+pub const CLIPBOARD_CAPACITY: usize = 4096;
+
+static CLIPBOARD: Mutex<String> = Mutex::new("CLIPBOARD", String::new());
+
+/// Replace the clipboard contents, truncating to `CLIPBOARD_CAPACITY` bytes
+pub fn copy(text: &str) {
+    let mut end = text.len().min(CLIPBOARD_CAPACITY);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut clipboard = CLIPBOARD.lock();
+    clipboard.clear();
+    clipboard.push_str(&text[..end]);
+}
+
+/// Get a copy of the clipboard contents
+pub fn paste() -> String {
+    CLIPBOARD.lock().clone()
+}
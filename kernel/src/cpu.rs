@@ -0,0 +1,80 @@
+//! CPU identification and per-CPU state
+//!
+//! Only the bootstrap processor runs for now; this is the single place that
+//! knows it, so code that cares about CPUs (affinity, RCU grace periods) keeps
+//! working unchanged once application processors are brought up.
+//!
+//! Each CPU has a `PerCpu` block for state that code running before it can
+//! find the current task needs, like the syscall entry. Its kernel GS base
+//! points at the block, so the entry reaches it with `swapgs`; everywhere
+//! else the GS base stays 0 and the block is found by `current_cpu`.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use x86_64::{VirtAddr, registers::model_specific::KernelGsBase};
+
+/// Maximum number of CPUs supported, one bit each in an affinity mask
+pub const MAX_CPUS: usize = 64;
+
+/// Number of CPUs running the scheduler
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+/// Index of the CPU running this code
+pub fn current_cpu() -> usize {
+    0
+}
+
+/// Number of CPUs running the scheduler
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+/// Mask with one bit set for every online CPU
+pub fn online_mask() -> u64 {
+    match online_cpus() {
+        MAX_CPUS => u64::MAX,
+        count => (1 << count) - 1,
+    }
+}
+
+/// State of one CPU, laid out for the syscall entry
+#[repr(C)]
+pub struct PerCpu {
+    /// Stack pointer of the code making a syscall, kept while the entry
+    /// switches stacks
+    pub user_rsp: AtomicU64,
+    /// Top of the kernel stack of the user task running on this CPU, which
+    /// its syscalls run on
+    pub syscall_stack: AtomicU64,
+    /// Copying to or from user memory, see `memory::uaccess`
+    pub user_access: AtomicBool,
+}
+
+static PER_CPU: [PerCpu; MAX_CPUS] = [const {
+    PerCpu {
+        user_rsp: AtomicU64::new(0),
+        syscall_stack: AtomicU64::new(0),
+        user_access: AtomicBool::new(false),
+    }
+}; MAX_CPUS];
+
+/// The state of the CPU running this code
+pub fn this_cpu() -> &'static PerCpu {
+    &PER_CPU[current_cpu()]
+}
+
+/// Point the kernel GS base of the CPU running this code at its `PerCpu`
+pub fn init_this_cpu() {
+    KernelGsBase::write(VirtAddr::from_ptr(this_cpu()));
+}
+
+/// Make syscalls on this CPU run on the stack ending at `top`, called when
+/// switching to a user task
+pub fn set_syscall_stack(top: VirtAddr) {
+    this_cpu().syscall_stack.store(top.as_u64(), Ordering::Relaxed);
+}
+
+/// Top of the stack syscalls on this CPU run on
+pub fn syscall_stack() -> VirtAddr {
+    VirtAddr::new(this_cpu().syscall_stack.load(Ordering::Relaxed))
+}
@@ -0,0 +1,302 @@
+//! Kernel-wide errors
+//!
+//! Subsystems keep their own error enums, which say exactly what went wrong
+//! in their terms. Code calling across subsystems converts them into a
+//! `KError` with `?`, which keeps the original error for logs and maps it to
+//! an errno value for syscalls. Errors that don't come from a subsystem,
+//! like a bad user pointer, have their own variants.
+
+use core::fmt;
+
+use x86_64::structures::paging::{PageSize, mapper::MapToError};
+
+#[cfg(feature = "nvme")]
+use crate::pci::nvme::NvmeError;
+#[cfg(feature = "usb")]
+use crate::pci::usb::xhci::UsbError;
+use crate::{
+    block::BlockError,
+    fs::FsError,
+    pci::PciError,
+    syscall::uring::RingError,
+    tasks::{
+        children::WaitError,
+        kernelslab::StackAllocError,
+        scheduler::{AffinityError, KillError, StackGrowthError},
+    },
+    tty::TtyError,
+};
+
+/// Error numbers, with the same values as Linux
+///
+/// Syscalls return them negated.
+pub mod errno {
+    pub const EPERM: i64 = 1;
+    pub const ENOENT: i64 = 2;
+    pub const ESRCH: i64 = 3;
+    pub const EIO: i64 = 5;
+    pub const ENXIO: i64 = 6;
+    pub const EBADF: i64 = 9;
+    pub const ECHILD: i64 = 10;
+    pub const EAGAIN: i64 = 11;
+    pub const ENOMEM: i64 = 12;
+    pub const EFAULT: i64 = 14;
+    pub const EBUSY: i64 = 16;
+    pub const EEXIST: i64 = 17;
+    pub const ENODEV: i64 = 19;
+    pub const ENOTDIR: i64 = 20;
+    pub const EISDIR: i64 = 21;
+    pub const EINVAL: i64 = 22;
+    pub const EMFILE: i64 = 24;
+    pub const ENOTTY: i64 = 25;
+    pub const EFBIG: i64 = 27;
+    pub const ENOSPC: i64 = 28;
+    pub const ERANGE: i64 = 34;
+    pub const ENOSYS: i64 = 38;
+    pub const ENODATA: i64 = 61;
+    pub const EOPNOTSUPP: i64 = 95;
+    pub const ETIMEDOUT: i64 = 110;
+
+    /// Largest errno value, syscall results from `-MAX_ERRNO` to -1 are errors
+    pub const MAX_ERRNO: i64 = 4095;
+
+    /// Name of an errno value, as in `<errno.h>`
+    pub fn name(errno: i64) -> Option<&'static str> {
+        Some(match errno {
+            EPERM => "EPERM",
+            ENOENT => "ENOENT",
+            ESRCH => "ESRCH",
+            EIO => "EIO",
+            ENXIO => "ENXIO",
+            EBADF => "EBADF",
+            ECHILD => "ECHILD",
+            EAGAIN => "EAGAIN",
+            ENOMEM => "ENOMEM",
+            EFAULT => "EFAULT",
+            EBUSY => "EBUSY",
+            EEXIST => "EEXIST",
+            ENODEV => "ENODEV",
+            ENOTDIR => "ENOTDIR",
+            EISDIR => "EISDIR",
+            EINVAL => "EINVAL",
+            EMFILE => "EMFILE",
+            ENOTTY => "ENOTTY",
+            EFBIG => "EFBIG",
+            ENOSPC => "ENOSPC",
+            ERANGE => "ERANGE",
+            ENOSYS => "ENOSYS",
+            ENODATA => "ENODATA",
+            EOPNOTSUPP => "EOPNOTSUPP",
+            ETIMEDOUT => "ETIMEDOUT",
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KError {
+    /// An argument is out of its valid range
+    InvalidArgument,
+    /// A user pointer doesn't point into user space
+    BadAddress,
+    /// A user buffer is too small for the result
+    BufferTooSmall,
+    /// Frames or heap memory ran out
+    OutOfMemory,
+    NoSuchTask,
+    /// The call or request doesn't exist
+    NotImplemented,
+    /// The descriptor doesn't refer to a terminal
+    NotATerminal,
+    Fs(FsError),
+    Block(BlockError),
+    #[cfg(feature = "nvme")]
+    Nvme(NvmeError),
+    Pci(PciError),
+    StackAlloc(StackAllocError),
+    StackGrowth(StackGrowthError),
+    Ring(RingError),
+    Kill(KillError),
+    Affinity(AffinityError),
+    Wait(WaitError),
+    Tty(TtyError),
+    #[cfg(feature = "usb")]
+    Usb(UsbError),
+}
+
+impl KError {
+    /// The errno value describing the error, positive
+    pub fn errno(&self) -> i64 {
+        use errno::*;
+
+        match *self {
+            KError::InvalidArgument => EINVAL,
+            KError::BadAddress => EFAULT,
+            KError::BufferTooSmall => ERANGE,
+            KError::OutOfMemory => ENOMEM,
+            KError::NoSuchTask => ESRCH,
+            KError::NotImplemented => ENOSYS,
+            KError::NotATerminal => ENOTTY,
+            KError::Fs(e) => match e {
+                FsError::NotFound => ENOENT,
+                FsError::NotADirectory => ENOTDIR,
+                FsError::IsADirectory => EISDIR,
+                FsError::AlreadyExists => EEXIST,
+                FsError::InvalidPath => EINVAL,
+                FsError::BadDescriptor => EBADF,
+                FsError::InvalidSeek => EINVAL,
+                FsError::NoSuchOffset => ENXIO,
+                FsError::ShortBuffer => EINVAL,
+                FsError::TooManyOpenFiles => EMFILE,
+                FsError::FileTooLarge => EFBIG,
+                FsError::NoSpace => ENOSPC,
+                FsError::NotSupported => EOPNOTSUPP,
+                FsError::NoAttribute => ENODATA,
+                FsError::AttributeTooLarge => ERANGE,
+                FsError::DeviceAttribute(_) => EINVAL,
+                FsError::Io(e) => KError::Block(e).errno(),
+            },
+            KError::Block(e) => match e {
+                BlockError::OutOfRange => ENOSPC,
+                BlockError::BufferSize => EINVAL,
+                BlockError::NotReady => EAGAIN,
+                _ => EIO,
+            },
+            #[cfg(feature = "nvme")]
+            KError::Nvme(e) => match e {
+                NvmeError::ControllerNotFound => ENODEV,
+                NvmeError::AllocationFailed => ENOMEM,
+                NvmeError::InvalidNamespace => ENXIO,
+                NvmeError::QueueFull | NvmeError::NoIoQueue => EAGAIN,
+                NvmeError::BufferTooSmall => EINVAL,
+                NvmeError::ControllerResetTimeout
+                | NvmeError::ControllerEnableTimeout
+                | NvmeError::CommandTimeout
+                | NvmeError::ShutdownTimeout => ETIMEDOUT,
+                NvmeError::CommandNotCompleted
+                | NvmeError::CommandFailed(_)
+                | NvmeError::Aborted => EIO,
+                NvmeError::PciError => ENODEV,
+            },
+            KError::Pci(e) => match e {
+                PciError::AllocationFailed => ENOMEM,
+                _ => ENODEV,
+            },
+            KError::StackAlloc(_) => ENOMEM,
+            KError::StackGrowth(e) => match e {
+                StackGrowthError::NotUserTask => EINVAL,
+                _ => EFAULT,
+            },
+            KError::Ring(e) => match e {
+                RingError::InvalidEntries => EINVAL,
+                RingError::AlreadySetUp => EBUSY,
+                RingError::NotSetUp => ENXIO,
+                RingError::OutOfMemory => ENOMEM,
+            },
+            KError::Kill(e) => match e {
+                KillError::NoSuchTask => ESRCH,
+                KillError::KernelTask => EPERM,
+                KillError::Busy => EBUSY,
+            },
+            KError::Affinity(e) => match e {
+                AffinityError::NoSuchTask => ESRCH,
+                AffinityError::NoOnlineCpu => EINVAL,
+            },
+            KError::Wait(e) => match e {
+                WaitError::NoChildren => ECHILD,
+                WaitError::WouldBlock => EAGAIN,
+            },
+            KError::Tty(e) => match e {
+                TtyError::Disconnected => ENODEV,
+                TtyError::Unsupported => ENOTTY,
+                _ => EIO,
+            },
+            #[cfg(feature = "usb")]
+            KError::Usb(e) => match e {
+                UsbError::NotInitialized | UsbError::Gone => ENODEV,
+                UsbError::AllocationFailed => ENOMEM,
+                UsbError::NoSuchSlot | UsbError::NoSuchEndpoint => ENXIO,
+                UsbError::BufferTooLarge | UsbError::WrongTransferType => EINVAL,
+                UsbError::Timeout => ETIMEDOUT,
+                UsbError::Suspended | UsbError::RingFull => EAGAIN,
+                UsbError::Stalled | UsbError::Completion(_) | UsbError::BadDescriptor => EIO,
+            },
+        }
+    }
+
+    /// The error as a syscall return value, the negated errno
+    pub fn to_syscall(&self) -> u64 {
+        (-self.errno()) as u64
+    }
+}
+
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KError::InvalidArgument => write!(f, "invalid argument"),
+            KError::BadAddress => write!(f, "bad address"),
+            KError::BufferTooSmall => write!(f, "buffer too small"),
+            KError::OutOfMemory => write!(f, "out of memory"),
+            KError::NoSuchTask => write!(f, "no such task"),
+            KError::NotImplemented => write!(f, "not implemented"),
+            KError::NotATerminal => write!(f, "not a terminal"),
+            KError::Fs(e) => write!(f, "file system: {e:?}"),
+            KError::Block(e) => write!(f, "block device: {e:?}"),
+            #[cfg(feature = "nvme")]
+            KError::Nvme(e) => write!(f, "NVMe: {e:?}"),
+            KError::Pci(e) => write!(f, "PCI: {e:?}"),
+            KError::StackAlloc(e) => write!(f, "{e}"),
+            KError::StackGrowth(e) => write!(f, "stack growth: {e:?}"),
+            KError::Ring(e) => write!(f, "ring: {e:?}"),
+            KError::Kill(e) => write!(f, "kill: {e:?}"),
+            KError::Affinity(e) => write!(f, "affinity: {e:?}"),
+            KError::Wait(e) => write!(f, "wait: {e:?}"),
+            KError::Tty(e) => write!(f, "tty: {e:?}"),
+            #[cfg(feature = "usb")]
+            KError::Usb(e) => write!(f, "USB: {e:?}"),
+        }
+    }
+}
+
+impl core::error::Error for KError {}
+
+macro_rules! from_errors {
+    ($($(#[$attr:meta])* $variant:ident($error:ty),)*) => {
+        $(
+            $(#[$attr])*
+            impl From<$error> for KError {
+                fn from(error: $error) -> Self {
+                    KError::$variant(error)
+                }
+            }
+        )*
+    };
+}
+
+from_errors! {
+    Fs(FsError),
+    Block(BlockError),
+    #[cfg(feature = "nvme")]
+    Nvme(NvmeError),
+    Pci(PciError),
+    StackAlloc(StackAllocError),
+    StackGrowth(StackGrowthError),
+    Ring(RingError),
+    Kill(KillError),
+    Affinity(AffinityError),
+    Wait(WaitError),
+    Tty(TtyError),
+    #[cfg(feature = "usb")]
+    Usb(UsbError),
+}
+
+impl<S: PageSize> From<MapToError<S>> for KError {
+    fn from(error: MapToError<S>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => KError::OutOfMemory,
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+                KError::InvalidArgument
+            }
+        }
+    }
+}
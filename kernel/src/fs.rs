@@ -0,0 +1,616 @@
+//! Virtual file system
+//!
+//! Files and directories are inodes behind the `Inode` trait, so file system
+//! drivers only deal with offsets and names. The tree is rooted at a tmpfs
+//! mounted on `/`, and paths are resolved one component at a time.
+//!
+//! Each task has a root and a working directory. Its relative paths start
+//! at the working directory and its absolute paths at its root, which `..`
+//! never climbs above, so a task moved into a subtree with `chroot` can't
+//! reach anything outside it. Paths are normalized as strings before they
+//! are looked up, which is sound as long as there are no symbolic links.
+//!
+//! Open files are tracked per task in descriptor tables, numbered from 3
+//! since 0 to 2 are the console. Files may be sparse: writing or seeking past
+//! the end of a file leaves a hole that reads back as zeros, and `SEEK_DATA`
+//! and `SEEK_HOLE` find where the data is without reading it.
+//!
+//! Device attributes are files under `/sys`, see `sysfs`, and the files the
+//! bootloader loaded are under `/initramfs`, see `initramfs`.
+//!
+//! Inodes keep access, modification and change times from the wall clock,
+//! and can hold extended attributes: small named values stored alongside
+//! the file by drivers that support them.
+
+pub mod fat;
+pub mod initramfs;
+pub mod sysfs;
+pub mod tmpfs;
+
+#[cfg(test)]
+pub mod tests;
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Once;
+use x86_64::instructions::interrupts;
+
+use crate::{block::BlockError, sync::Mutex, time};
+
+/// Descriptor of the first open file, 0 to 2 being the console
+pub const FIRST_FD: u32 = 3;
+
+/// Open files per task
+const MAX_OPEN_FILES: usize = 64;
+
+/// Largest offset a file can be written at
+pub const MAX_FILE_SIZE: u64 = 1 << 40;
+
+/// Longest extended attribute name
+pub const XATTR_NAME_MAX: usize = 255;
+
+/// Largest extended attribute value
+pub const XATTR_SIZE_MAX: usize = 64 * 1024;
+
+/// Open flags, with the same values as Linux
+pub mod flags {
+    pub const O_RDONLY: u32 = 0;
+    pub const O_WRONLY: u32 = 1;
+    pub const O_RDWR: u32 = 2;
+    pub const O_ACCMODE: u32 = 3;
+    pub const O_CREAT: u32 = 0o100;
+    pub const O_TRUNC: u32 = 0o1000;
+    pub const O_APPEND: u32 = 0o2000;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    InvalidPath,
+    BadDescriptor,
+    /// The resulting offset would be negative or too large
+    InvalidSeek,
+    /// `SEEK_DATA` or `SEEK_HOLE` from at or past the end of the file
+    NoSuchOffset,
+    /// The buffer can't hold a whole record of a file read in records
+    ShortBuffer,
+    TooManyOpenFiles,
+    FileTooLarge,
+    NoSpace,
+    /// The file system doesn't support the operation
+    NotSupported,
+    /// No extended attribute with that name
+    NoAttribute,
+    /// Extended attribute name or value too long
+    AttributeTooLarge,
+    /// A device attribute couldn't be shown or rejected the value written,
+    /// with the reason
+    DeviceAttribute(&'static str),
+    /// The device backing the file system failed
+    Io(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        FsError::Io(error)
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File = 0,
+    Directory = 1,
+}
+
+/// File status, as copied out by sys_stat
+///
+/// Times are in milliseconds since the Unix epoch.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub kind: NodeKind,
+    /// Number of extended attributes
+    pub xattrs: u32,
+    /// Size in bytes, holes included
+    pub size: u64,
+    /// Bytes of storage used, less than the size for sparse files
+    pub allocated: u64,
+    /// Last read
+    pub accessed: u64,
+    /// Last change to the contents
+    pub modified: u64,
+    /// Last change to the contents or attributes
+    pub changed: u64,
+}
+
+/// Current time for timestamps, in milliseconds since the Unix epoch
+pub fn now() -> u64 {
+    time::unix_time_ms()
+}
+
+/// Check an extended attribute name and value against the limits
+pub fn check_xattr(name: &str, value: &[u8]) -> Result<(), FsError> {
+    if name.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    if name.len() > XATTR_NAME_MAX || value.len() > XATTR_SIZE_MAX {
+        return Err(FsError::AttributeTooLarge);
+    }
+    Ok(())
+}
+
+/// Where an lseek offset is relative to, with the same values as Linux
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Set = 0,
+    Current = 1,
+    End = 2,
+    /// Next byte at or after the offset that holds data
+    Data = 3,
+    /// Next byte at or after the offset that is in a hole, the end of the file
+    /// counting as one
+    Hole = 4,
+}
+
+impl Whence {
+    pub fn from_u32(n: u32) -> Option<Self> {
+        match n {
+            0 => Some(Whence::Set),
+            1 => Some(Whence::Current),
+            2 => Some(Whence::End),
+            3 => Some(Whence::Data),
+            4 => Some(Whence::Hole),
+            _ => None,
+        }
+    }
+}
+
+/// A file or directory of some file system
+///
+/// Inodes synchronize themselves, all methods take `&self`. File methods
+/// default to `IsADirectory` and directory methods to `NotADirectory`.
+pub trait Inode: Send + Sync {
+    fn kind(&self) -> NodeKind;
+
+    /// Size in bytes, holes included
+    fn size(&self) -> u64 {
+        0
+    }
+
+    /// Read at `offset`, returning how many bytes were read
+    ///
+    /// Holes read back as zeros, nothing is read past the end of the file.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Write at `offset`, growing the file if needed
+    ///
+    /// Anything between the old end of the file and `offset` becomes a hole.
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Shrink or grow the file, growing only adds a hole
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Start of the first data at or after `offset`, None if there is none
+    /// before the end of the file
+    fn next_data(&self, offset: u64) -> Option<u64> {
+        (offset < self.size()).then_some(offset)
+    }
+
+    /// Start of the first hole at or after `offset`, at most the size
+    fn next_hole(&self, offset: u64) -> u64 {
+        self.size().max(offset)
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Create an entry, failing if the name is taken
+    fn create(&self, _name: &str, _kind: NodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Names of the entries in the directory, sorted
+    fn entries(&self) -> Result<Vec<String>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Status of the inode, drivers without timestamps report them as 0
+    fn stat(&self) -> Stat {
+        let size = self.size();
+        Stat {
+            kind: self.kind(),
+            xattrs: 0,
+            size,
+            allocated: size,
+            accessed: 0,
+            modified: 0,
+            changed: 0,
+        }
+    }
+
+    fn get_xattr(&self, _name: &str) -> Result<Vec<u8>, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Create or replace an extended attribute
+    fn set_xattr(&self, _name: &str, _value: &[u8]) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn remove_xattr(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Names of the extended attributes, sorted
+    fn list_xattrs(&self) -> Result<Vec<String>, FsError> {
+        Ok(Vec::new())
+    }
+}
+
+static ROOT: Once<Arc<dyn Inode>> = Once::new();
+
+/// The root directory, a tmpfs mounted on first use with the device
+/// attributes on `/sys` and the boot files on `/initramfs`
+pub fn root() -> Arc<dyn Inode> {
+    ROOT.call_once(|| {
+        let root = tmpfs::Directory::new();
+        root.attach("sys", Arc::new(sysfs::Root));
+        root.attach("initramfs", Arc::new(initramfs::Directory::root()));
+        Arc::new(root)
+    })
+    .clone()
+}
+
+/// Components of `path` taken from the absolute path `cwd`, with `.` and
+/// `..` applied
+///
+/// `..` at the root stays at the root.
+fn normalize<'a>(cwd: &'a str, path: &'a str) -> Vec<&'a str> {
+    let mut components = Vec::new();
+    let base = if path.starts_with('/') { "" } else { cwd };
+    for component in base.split('/').chain(path.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components
+}
+
+/// Absolute path of `components` under `root`
+fn join(root: &str, components: &[&str]) -> String {
+    let mut path = root.trim_end_matches('/').to_string();
+    for component in components {
+        path.push('/');
+        path.push_str(component);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+/// Find the inode at a path, relative paths start at `/`
+pub fn resolve(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    let mut node = root();
+    for component in normalize("/", path) {
+        node = node.lookup(component)?;
+    }
+    Ok(node)
+}
+
+/// Find the directory a path is in, and the last component
+fn resolve_parent(path: &str) -> Result<(Arc<dyn Inode>, &str), FsError> {
+    let mut components = normalize("/", path);
+    let name = components.pop().ok_or(FsError::InvalidPath)?;
+    let mut parent = root();
+    for component in components {
+        parent = parent.lookup(component)?;
+    }
+    if parent.kind() != NodeKind::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    Ok((parent, name))
+}
+
+/// Root and working directory of a task
+struct TaskPaths {
+    /// Absolute path of the root
+    root: String,
+    /// Working directory, as an absolute path under the root
+    cwd: String,
+}
+
+/// Tasks that changed their root or working directory, the others have
+/// both at `/`
+static TASK_PATHS: Mutex<BTreeMap<u64, TaskPaths>> = Mutex::new("TASK_PATHS", BTreeMap::new());
+
+/// Root and working directory of task `pid`
+fn task_paths(pid: u64) -> (String, String) {
+    interrupts::without_interrupts(|| {
+        TASK_PATHS.lock().get(&pid).map_or_else(
+            || ("/".to_string(), "/".to_string()),
+            |paths| (paths.root.clone(), paths.cwd.clone()),
+        )
+    })
+}
+
+fn set_task_paths(pid: u64, root: String, cwd: String) {
+    interrupts::without_interrupts(|| TASK_PATHS.lock().insert(pid, TaskPaths { root, cwd }));
+}
+
+/// Absolute path of a path given by task `pid`
+pub fn task_path(pid: u64, path: &str) -> Result<String, FsError> {
+    if path.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    let (root, cwd) = task_paths(pid);
+    Ok(join(&root, &normalize(&cwd, path)))
+}
+
+/// Working directory of task `pid`, as seen from its root
+pub fn getcwd(pid: u64) -> String {
+    task_paths(pid).1
+}
+
+/// Change the working directory of task `pid`
+pub fn chdir(pid: u64, path: &str) -> Result<(), FsError> {
+    if path.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    let (root, cwd) = task_paths(pid);
+    let components = normalize(&cwd, path);
+    if resolve(&join(&root, &components))?.kind() != NodeKind::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    let cwd = join("/", &components);
+    set_task_paths(pid, root, cwd);
+    Ok(())
+}
+
+/// Confine task `pid` to the subtree at `path`, which becomes its root and
+/// working directory
+pub fn chroot(pid: u64, path: &str) -> Result<(), FsError> {
+    let root = task_path(pid, path)?;
+    if resolve(&root)?.kind() != NodeKind::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    set_task_paths(pid, root, "/".to_string());
+    Ok(())
+}
+
+/// Create a directory, relative paths start at `/`
+pub fn mkdir(path: &str) -> Result<(), FsError> {
+    let (parent, name) = resolve_parent(path)?;
+    parent.create(name, NodeKind::Directory).map(|_| ())
+}
+
+pub fn stat(path: &str) -> Result<Stat, FsError> {
+    Ok(resolve(path)?.stat())
+}
+
+pub fn get_xattr(path: &str, name: &str) -> Result<Vec<u8>, FsError> {
+    resolve(path)?.get_xattr(name)
+}
+
+pub fn set_xattr(path: &str, name: &str, value: &[u8]) -> Result<(), FsError> {
+    check_xattr(name, value)?;
+    resolve(path)?.set_xattr(name, value)
+}
+
+pub fn remove_xattr(path: &str, name: &str) -> Result<(), FsError> {
+    resolve(path)?.remove_xattr(name)
+}
+
+pub fn list_xattrs(path: &str) -> Result<Vec<String>, FsError> {
+    resolve(path)?.list_xattrs()
+}
+
+/// A file opened by a task
+#[derive(Clone)]
+struct OpenFile {
+    inode: Arc<dyn Inode>,
+    offset: u64,
+    flags: u32,
+}
+
+impl OpenFile {
+    fn readable(&self) -> bool {
+        self.flags & flags::O_ACCMODE != flags::O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & flags::O_ACCMODE != flags::O_RDONLY
+    }
+}
+
+/// Descriptor tables by task id, a descriptor indexes its task's table
+/// offset by `FIRST_FD`
+static OPEN_FILES: Mutex<BTreeMap<u64, Vec<Option<OpenFile>>>> =
+    Mutex::new("OPEN_FILES", BTreeMap::new());
+
+fn slot(fd: u32) -> Result<usize, FsError> {
+    fd.checked_sub(FIRST_FD)
+        .map(|slot| slot as usize)
+        .ok_or(FsError::BadDescriptor)
+}
+
+fn get_file(pid: u64, fd: u32) -> Result<OpenFile, FsError> {
+    let slot = slot(fd)?;
+    interrupts::without_interrupts(|| {
+        OPEN_FILES
+            .lock()
+            .get(&pid)
+            .and_then(|table| table.get(slot)?.clone())
+            .ok_or(FsError::BadDescriptor)
+    })
+}
+
+fn set_offset(pid: u64, fd: u32, offset: u64) {
+    let Ok(slot) = slot(fd) else {
+        return;
+    };
+    interrupts::without_interrupts(|| {
+        if let Some(Some(file)) = OPEN_FILES
+            .lock()
+            .get_mut(&pid)
+            .and_then(|table| table.get_mut(slot))
+        {
+            file.offset = offset;
+        }
+    })
+}
+
+/// Open a path for task `pid`, returning the new descriptor
+pub fn open(pid: u64, path: &str, open_flags: u32) -> Result<u32, FsError> {
+    let path = task_path(pid, path)?;
+    let inode = match resolve(&path) {
+        Ok(inode) => inode,
+        Err(FsError::NotFound) if open_flags & flags::O_CREAT != 0 => {
+            let (parent, name) = resolve_parent(&path)?;
+            parent.create(name, NodeKind::File)?
+        }
+        Err(e) => return Err(e),
+    };
+
+    let writable = open_flags & flags::O_ACCMODE != flags::O_RDONLY;
+    if inode.kind() == NodeKind::Directory && writable {
+        return Err(FsError::IsADirectory);
+    }
+    if open_flags & flags::O_TRUNC != 0 && writable {
+        inode.truncate(0)?;
+    }
+    install(pid, inode, open_flags)
+}
+
+/// Give task `pid` a descriptor for an inode that has no path, returning it
+pub fn install(pid: u64, inode: Arc<dyn Inode>, open_flags: u32) -> Result<u32, FsError> {
+    let file = OpenFile {
+        inode,
+        offset: 0,
+        flags: open_flags,
+    };
+    interrupts::without_interrupts(|| {
+        let mut open_files = OPEN_FILES.lock();
+        let table = open_files.entry(pid).or_default();
+        let slot = match table.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None if table.len() < MAX_OPEN_FILES => {
+                table.push(None);
+                table.len() - 1
+            }
+            None => return Err(FsError::TooManyOpenFiles),
+        };
+        table[slot] = Some(file);
+        Ok(slot as u32 + FIRST_FD)
+    })
+}
+
+pub fn close(pid: u64, fd: u32) -> Result<(), FsError> {
+    let slot = slot(fd)?;
+    // the inode is dropped after the lock is released
+    let _file = interrupts::without_interrupts(|| {
+        OPEN_FILES
+            .lock()
+            .get_mut(&pid)
+            .and_then(|table| table.get_mut(slot)?.take())
+            .ok_or(FsError::BadDescriptor)
+    })?;
+    Ok(())
+}
+
+/// Read at the file offset and advance it
+pub fn read(pid: u64, fd: u32, buf: &mut [u8]) -> Result<usize, FsError> {
+    let file = get_file(pid, fd)?;
+    if !file.readable() {
+        return Err(FsError::BadDescriptor);
+    }
+    let read = file.inode.read_at(file.offset, buf)?;
+    set_offset(pid, fd, file.offset + read as u64);
+    Ok(read)
+}
+
+/// Write at the file offset, or the end with `O_APPEND`, and advance it
+pub fn write(pid: u64, fd: u32, buf: &[u8]) -> Result<usize, FsError> {
+    let file = get_file(pid, fd)?;
+    if !file.writable() {
+        return Err(FsError::BadDescriptor);
+    }
+    let offset = if file.flags & flags::O_APPEND != 0 {
+        file.inode.size()
+    } else {
+        file.offset
+    };
+    let written = file.inode.write_at(offset, buf)?;
+    set_offset(pid, fd, offset + written as u64);
+    Ok(written)
+}
+
+/// Move the file offset, returning the new one
+///
+/// Seeking past the end is allowed; a write there leaves a hole behind.
+pub fn lseek(pid: u64, fd: u32, offset: i64, whence: Whence) -> Result<u64, FsError> {
+    let file = get_file(pid, fd)?;
+    let size = file.inode.size();
+    let relative = |base: u64| {
+        base.checked_add_signed(offset)
+            .filter(|&offset| offset <= MAX_FILE_SIZE)
+            .ok_or(FsError::InvalidSeek)
+    };
+
+    let new_offset = match whence {
+        Whence::Set => relative(0)?,
+        Whence::Current => relative(file.offset)?,
+        Whence::End => relative(size)?,
+        Whence::Data | Whence::Hole => {
+            let start = u64::try_from(offset).map_err(|_| FsError::InvalidSeek)?;
+            if start >= size {
+                return Err(FsError::NoSuchOffset);
+            }
+            if whence == Whence::Data {
+                file.inode.next_data(start).ok_or(FsError::NoSuchOffset)?
+            } else {
+                file.inode.next_hole(start).min(size)
+            }
+        }
+    };
+    set_offset(pid, fd, new_offset);
+    Ok(new_offset)
+}
+
+/// Number of files open across all tasks
+pub fn open_file_count() -> usize {
+    interrupts::without_interrupts(|| {
+        OPEN_FILES
+            .lock()
+            .values()
+            .map(|table| table.iter().flatten().count())
+            .sum()
+    })
+}
+
+/// Close every file a task has open and forget its root and working
+/// directory, called when it exits
+pub fn release(pid: u64) {
+    let _table = interrupts::without_interrupts(|| OPEN_FILES.lock().remove(&pid));
+    interrupts::without_interrupts(|| TASK_PATHS.lock().remove(&pid));
+}
@@ -0,0 +1,505 @@
+//! FAT32 formatting
+//!
+//! `mkfs` writes a new FAT32 file system holding a given set of files in
+//! one pass: the tree is laid out in memory, every file and directory gets
+//! a contiguous run of clusters in order, and the reserved sectors, both
+//! FATs and the data are written out once. This is what firmware needs on
+//! an EFI System Partition, and there is no driver to mount the result.
+//!
+//! Names that aren't already uppercase 8.3 get long file name entries
+//! alongside a generated `BASE~N.EXT` short name.
+
+use alloc::{format, vec, vec::Vec};
+
+use crate::{
+    block::{BlockDevice, BlockError},
+    memory::kaslr,
+    time::rtc::DateTime,
+};
+
+const RESERVED_SECTORS: u32 = 32;
+const FAT_COUNT: u32 = 2;
+const FSINFO_SECTOR: u32 = 1;
+const BACKUP_BOOT_SECTOR: u32 = 6;
+const ROOT_CLUSTER: u32 = 2;
+
+/// FAT32 needs at least this many clusters, or it would be read as FAT16
+const MIN_CLUSTERS: u32 = 65525;
+const MAX_CLUSTERS: u32 = 0x0fff_fff5;
+/// Largest cluster tried, the most every implementation reads
+const MAX_CLUSTER_BYTES: u32 = 4096;
+
+const END_OF_CHAIN: u32 = 0x0fff_ffff;
+const ENTRY_SIZE: usize = 32;
+/// Characters of a long name each entry holds
+const LFN_UNITS: usize = 13;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// Bytes written per request
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Why a file system couldn't be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    /// The device is too small or too large for FAT32
+    BadSize,
+    /// Blocks aren't 512 to 4096 bytes
+    BadBlockSize,
+    /// A path has an empty component or names a file as a directory
+    BadPath,
+    /// The files don't fit
+    NoSpace,
+    Io(BlockError),
+}
+
+impl From<BlockError> for FatError {
+    fn from(error: BlockError) -> Self {
+        FatError::Io(error)
+    }
+}
+
+/// Sizes of the regions of the file system, in sectors
+pub(super) struct Geometry {
+    pub(super) bytes_per_sector: u32,
+    pub(super) sectors_per_cluster: u32,
+    pub(super) total_sectors: u32,
+    pub(super) fat_sectors: u32,
+    pub(super) clusters: u32,
+}
+
+impl Geometry {
+    /// The largest clusters that still leave FAT32 enough of them
+    pub(super) fn new(device: &dyn BlockDevice) -> Result<Self, FatError> {
+        let bytes_per_sector = device.block_size() as u32;
+        if !bytes_per_sector.is_power_of_two() || !(512..=4096).contains(&bytes_per_sector) {
+            return Err(FatError::BadBlockSize);
+        }
+        let total_sectors = u32::try_from(device.block_count()).map_err(|_| FatError::BadSize)?;
+
+        let mut sectors_per_cluster = (MAX_CLUSTER_BYTES / bytes_per_sector).max(1);
+        loop {
+            let data = total_sectors
+                .checked_sub(RESERVED_SECTORS)
+                .ok_or(FatError::BadSize)?;
+            // sized for every sector being a cluster, which slightly overshoots
+            let fat_sectors = ((data / sectors_per_cluster + 2) * 4).div_ceil(bytes_per_sector);
+            let clusters = data.saturating_sub(FAT_COUNT * fat_sectors) / sectors_per_cluster;
+            if (MIN_CLUSTERS..MAX_CLUSTERS).contains(&clusters) {
+                return Ok(Self {
+                    bytes_per_sector,
+                    sectors_per_cluster,
+                    total_sectors,
+                    fat_sectors,
+                    clusters,
+                });
+            }
+            if clusters >= MAX_CLUSTERS || sectors_per_cluster == 1 {
+                return Err(FatError::BadSize);
+            }
+            sectors_per_cluster /= 2;
+        }
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        (self.sectors_per_cluster * self.bytes_per_sector) as usize
+    }
+
+    /// First sector of a cluster
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        (RESERVED_SECTORS + FAT_COUNT * self.fat_sectors) as u64
+            + (cluster - 2) as u64 * self.sectors_per_cluster as u64
+    }
+}
+
+enum NodeKind<'a> {
+    Directory(Vec<usize>),
+    File(&'a [u8]),
+}
+
+/// A file or directory to be written
+struct Node<'a> {
+    name: &'a str,
+    /// Index of the parent directory, the root is its own parent
+    parent: usize,
+    kind: NodeKind<'a>,
+    /// First cluster, 0 for empty files
+    cluster: u32,
+}
+
+/// Write a FAT32 file system to `device` holding `files`, given by path and
+/// contents, with directories created as their paths need them
+///
+/// Anything the device held before is lost.
+pub fn mkfs(
+    device: &dyn BlockDevice,
+    files: &[(&str, &[u8])],
+    label: &str,
+) -> Result<(), FatError> {
+    let geometry = Geometry::new(device)?;
+    let cluster_bytes = geometry.cluster_bytes();
+
+    let mut nodes = build_tree(files)?;
+
+    // clusters are handed out in tree order, starting with the root
+    let mut fat = vec![0u32; geometry.clusters as usize + 2];
+    fat[0] = 0x0fff_fff8;
+    fat[1] = END_OF_CHAIN;
+    let mut next = ROOT_CLUSTER;
+    for index in 0..nodes.len() {
+        let bytes = match &nodes[index].kind {
+            NodeKind::Directory(children) => {
+                directory_entries(&nodes, index, children) * ENTRY_SIZE
+            }
+            NodeKind::File(data) => data.len(),
+        };
+        let count = bytes.div_ceil(cluster_bytes) as u32;
+        if count == 0 {
+            continue;
+        }
+        if next + count > geometry.clusters + 2 {
+            return Err(FatError::NoSpace);
+        }
+        for cluster in next..next + count - 1 {
+            fat[cluster as usize] = cluster + 1;
+        }
+        fat[(next + count - 1) as usize] = END_OF_CHAIN;
+        nodes[index].cluster = next;
+        next += count;
+    }
+
+    let now = timestamp();
+    let label = short_label(label);
+
+    // data first, so the file system only appears once it is complete
+    for (index, node) in nodes.iter().enumerate() {
+        let lba = geometry.cluster_lba(node.cluster.max(2));
+        match &node.kind {
+            NodeKind::Directory(children) => {
+                let mut table = directory_table(&nodes, index, children, now, &label);
+                table.resize(table.len().next_multiple_of(cluster_bytes), 0);
+                write_chunked(device, lba, &table)?;
+            }
+            NodeKind::File(data) if node.cluster != 0 => write_chunked(device, lba, data)?,
+            NodeKind::File(_) => {}
+        }
+    }
+
+    let fat_bytes: Vec<u8> = fat.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+    for copy in 0..FAT_COUNT {
+        let lba = (RESERVED_SECTORS + copy * geometry.fat_sectors) as u64;
+        write_chunked(device, lba, &fat_bytes)?;
+    }
+
+    let free = geometry.clusters + 2 - next;
+    let reserved = reserved_sectors(&geometry, &label, free, next);
+    device.write_blocks(0, &reserved)?;
+    device.flush()?;
+    Ok(())
+}
+
+/// Lay the paths out as a tree, the root at index 0 and every directory
+/// before its contents
+fn build_tree<'a>(files: &[(&'a str, &'a [u8])]) -> Result<Vec<Node<'a>>, FatError> {
+    let mut nodes = vec![Node {
+        name: "",
+        parent: 0,
+        kind: NodeKind::Directory(Vec::new()),
+        cluster: 0,
+    }];
+    for &(path, data) in files {
+        let mut components = path.trim_start_matches('/').split('/').peekable();
+        let mut directory = 0;
+        while let Some(name) = components.next() {
+            if name.is_empty() || name == "." || name == ".." {
+                return Err(FatError::BadPath);
+            }
+            let NodeKind::Directory(children) = &nodes[directory].kind else {
+                return Err(FatError::BadPath);
+            };
+            let existing = children
+                .iter()
+                .copied()
+                .find(|&child| nodes[child].name.eq_ignore_ascii_case(name));
+            let last = components.peek().is_none();
+            let child = match existing {
+                Some(_) if last => return Err(FatError::BadPath),
+                Some(child) => child,
+                None => {
+                    nodes.push(Node {
+                        name,
+                        parent: directory,
+                        kind: if last {
+                            NodeKind::File(data)
+                        } else {
+                            NodeKind::Directory(Vec::new())
+                        },
+                        cluster: 0,
+                    });
+                    let child = nodes.len() - 1;
+                    if let NodeKind::Directory(children) = &mut nodes[directory].kind {
+                        children.push(child);
+                    }
+                    child
+                }
+            };
+            directory = child;
+        }
+    }
+    Ok(nodes)
+}
+
+/// Number of entries the table of directory `index` takes
+fn directory_entries(nodes: &[Node], index: usize, children: &[usize]) -> usize {
+    // the root holds the volume label, the others `.` and `..`
+    let own = if index == 0 { 1 } else { 2 };
+    own + children
+        .iter()
+        .map(|&child| {
+            let name = nodes[child].name;
+            1 + if exact_short_name(name).is_some() {
+                0
+            } else {
+                name.encode_utf16().count().div_ceil(LFN_UNITS)
+            }
+        })
+        .sum::<usize>()
+}
+
+/// Entries of directory `index`
+fn directory_table(
+    nodes: &[Node],
+    index: usize,
+    children: &[usize],
+    (time, date): (u16, u16),
+    label: &[u8; 11],
+) -> Vec<u8> {
+    let mut table = Vec::new();
+    let entry = |name: &[u8; 11], attributes: u8, cluster: u32, size: u32| {
+        let mut bytes = [0u8; ENTRY_SIZE];
+        bytes[0..11].copy_from_slice(name);
+        bytes[11] = attributes;
+        bytes[14..16].copy_from_slice(&time.to_le_bytes());
+        bytes[16..18].copy_from_slice(&date.to_le_bytes());
+        bytes[18..20].copy_from_slice(&date.to_le_bytes());
+        bytes[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        bytes[22..24].copy_from_slice(&time.to_le_bytes());
+        bytes[24..26].copy_from_slice(&date.to_le_bytes());
+        bytes[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        bytes[28..32].copy_from_slice(&size.to_le_bytes());
+        bytes
+    };
+
+    if index == 0 {
+        table.extend_from_slice(&entry(label, ATTR_VOLUME_ID, 0, 0));
+    } else {
+        let node = &nodes[index];
+        // `..` of a directory in the root points at cluster 0
+        let parent = if node.parent == 0 {
+            0
+        } else {
+            nodes[node.parent].cluster
+        };
+        table.extend_from_slice(&entry(b".          ", ATTR_DIRECTORY, node.cluster, 0));
+        table.extend_from_slice(&entry(b"..         ", ATTR_DIRECTORY, parent, 0));
+    }
+
+    let mut used: Vec<[u8; 11]> = Vec::new();
+    for &child in children {
+        let node = &nodes[child];
+        let short = match exact_short_name(node.name) {
+            Some(short) => short,
+            None => {
+                let short = generated_short_name(node.name, &used);
+                table.extend(long_name_entries(node.name, &short));
+                short
+            }
+        };
+        used.push(short);
+        let (attributes, size) = match node.kind {
+            NodeKind::Directory(_) => (ATTR_DIRECTORY, 0),
+            NodeKind::File(data) => (ATTR_ARCHIVE, data.len() as u32),
+        };
+        table.extend_from_slice(&entry(&short, attributes, node.cluster, size));
+    }
+    table
+}
+
+/// Whether a byte may appear in a short name
+fn short_name_byte(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&byte)
+}
+
+/// The short name spelling `name` exactly, if it is an uppercase 8.3 name
+pub(super) fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || extension.len() > 3
+        || !base.bytes().chain(extension.bytes()).all(short_name_byte)
+    {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(short)
+}
+
+/// A short name for `name` not among `used`: the name uppercased if that
+/// is 8.3, `BASE~N.EXT` otherwise
+pub(super) fn generated_short_name(name: &str, used: &[[u8; 11]]) -> [u8; 11] {
+    if let Some(short) = exact_short_name(&name.to_ascii_uppercase())
+        && !used.contains(&short)
+    {
+        return short;
+    }
+    let clean = |part: &str| -> Vec<u8> {
+        part.bytes()
+            .map(|byte| byte.to_ascii_uppercase())
+            .filter(|&byte| short_name_byte(byte))
+            .collect()
+    };
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let base = clean(base);
+    let extension = clean(extension);
+
+    let mut short = [b' '; 11];
+    for (slot, &byte) in short[8..].iter_mut().zip(&extension) {
+        *slot = byte;
+    }
+    for number in 1u32.. {
+        let tail = format!("~{number}");
+        let kept = base
+            .len()
+            .min(8 - tail.len())
+            .max(if base.is_empty() { 0 } else { 1 });
+        short[..8].fill(b' ');
+        short[..kept].copy_from_slice(&base[..kept]);
+        short[kept..kept + tail.len()].copy_from_slice(tail.as_bytes());
+        if !used.contains(&short) {
+            break;
+        }
+    }
+    short
+}
+
+/// Entries spelling `name` for the entry with `short`, last part first
+pub(super) fn long_name_entries(name: &str, short: &[u8; 11]) -> Vec<u8> {
+    let checksum = short
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte));
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let parts = units.len().div_ceil(LFN_UNITS);
+
+    let mut entries = Vec::with_capacity(parts * ENTRY_SIZE);
+    for part in (0..parts).rev() {
+        // the name ends with a NUL if it has room, then 0xFFFF padding
+        let mut chunk = [0xffffu16; LFN_UNITS];
+        let start = part * LFN_UNITS;
+        let end = units.len().min(start + LFN_UNITS);
+        chunk[..end - start].copy_from_slice(&units[start..end]);
+        if end - start < LFN_UNITS {
+            chunk[end - start] = 0;
+        }
+
+        let mut bytes = [0u8; ENTRY_SIZE];
+        bytes[0] = (part as u8 + 1) | if part + 1 == parts { 0x40 } else { 0 };
+        bytes[11] = ATTR_LONG_NAME;
+        bytes[13] = checksum;
+        let slots = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (offset, unit) in slots.zip(chunk) {
+            bytes[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entries.extend_from_slice(&bytes);
+    }
+    entries
+}
+
+/// The volume label, uppercased and padded to 11 bytes
+fn short_label(label: &str) -> [u8; 11] {
+    let mut short = [b' '; 11];
+    for (slot, byte) in short.iter_mut().zip(
+        label
+            .bytes()
+            .map(|byte| byte.to_ascii_uppercase())
+            .filter(|&byte| short_name_byte(byte) || byte == b' '),
+    ) {
+        *slot = byte;
+    }
+    short
+}
+
+/// Current time and date in the FAT encoding
+fn timestamp() -> (u16, u16) {
+    let now = DateTime::from_unix(super::now() / 1000);
+    let time = (now.hour as u16) << 11 | (now.minute as u16) << 5 | (now.second as u16 / 2);
+    let year = now.year.clamp(1980, 2107) - 1980;
+    let date = (year as u16) << 9 | (now.month as u16) << 5 | now.day as u16;
+    (time, date)
+}
+
+/// The boot sector, FSInfo sector and their backups
+fn reserved_sectors(geometry: &Geometry, label: &[u8; 11], free: u32, next_free: u32) -> Vec<u8> {
+    let sector = geometry.bytes_per_sector as usize;
+    let mut reserved = vec![0u8; RESERVED_SECTORS as usize * sector];
+
+    let boot = &mut reserved[..sector];
+    // a jump over the parameters, there is no boot code to run
+    boot[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"LOCOS   ");
+    boot[11..13].copy_from_slice(&(geometry.bytes_per_sector as u16).to_le_bytes());
+    boot[13] = geometry.sectors_per_cluster as u8;
+    boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    boot[16] = FAT_COUNT as u8;
+    boot[21] = 0xf8;
+    boot[24..26].copy_from_slice(&63u16.to_le_bytes());
+    boot[26..28].copy_from_slice(&255u16.to_le_bytes());
+    boot[32..36].copy_from_slice(&geometry.total_sectors.to_le_bytes());
+    boot[36..40].copy_from_slice(&geometry.fat_sectors.to_le_bytes());
+    boot[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+    boot[48..50].copy_from_slice(&(FSINFO_SECTOR as u16).to_le_bytes());
+    boot[50..52].copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    boot[67..71].copy_from_slice(&(kaslr::random() as u32).to_le_bytes());
+    boot[71..82].copy_from_slice(label);
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510] = 0x55;
+    boot[511] = 0xaa;
+
+    let fsinfo = &mut reserved[sector..2 * sector];
+    fsinfo[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    fsinfo[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    fsinfo[488..492].copy_from_slice(&free.to_le_bytes());
+    fsinfo[492..496].copy_from_slice(&next_free.to_le_bytes());
+    fsinfo[508..512].copy_from_slice(&0xaa55_0000u32.to_le_bytes());
+
+    let backup = BACKUP_BOOT_SECTOR as usize * sector;
+    reserved.copy_within(..2 * sector, backup);
+    reserved
+}
+
+/// Write `data` from `lba` on, padding the last block with zeros
+fn write_chunked(device: &dyn BlockDevice, lba: u64, data: &[u8]) -> Result<(), FatError> {
+    let block_size = device.block_size();
+    let mut lba = lba;
+    for chunk in data.chunks(CHUNK_SIZE) {
+        let blocks = chunk.len().div_ceil(block_size);
+        if chunk.len() == blocks * block_size {
+            device.write_blocks(lba, chunk)?;
+        } else {
+            let mut padded = chunk.to_vec();
+            padded.resize(blocks * block_size, 0);
+            device.write_blocks(lba, &padded)?;
+        }
+        lba += blocks as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! Files the bootloader loaded
+//!
+//! Mounted on `/initramfs`. Limine loads the kernel and every `module_path`
+//! of limine.conf into memory it never hands out, and each becomes a
+//! read-only file at the path it has on the boot volume, so
+//! `/initramfs/boot/kernel.elf` is the kernel that is running. Nothing is
+//! copied: reads come straight from where Limine put the file.
+//!
+//! Limine's descriptions of the files are in bootloader-reclaimable memory,
+//! so their paths are copied when recorded.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use limine::file::File;
+use spin::Once;
+
+use super::{FsError, Inode, NodeKind};
+
+/// A file Limine loaded
+pub struct BootFile {
+    /// Path on the boot volume, without the leading slash
+    pub path: String,
+    pub data: &'static [u8],
+}
+
+static FILES: Once<Vec<BootFile>> = Once::new();
+
+/// Record the kernel and the modules Limine loaded. Only the first call has
+/// any effect.
+///
+/// Must run after the heap is up and before bootloader memory is reclaimed.
+pub fn init(executable: Option<&File>, modules: &[&File]) {
+    FILES.call_once(|| {
+        executable
+            .into_iter()
+            .chain(modules.iter().copied())
+            .filter_map(|file| {
+                let path = file.path().to_str().ok()?;
+                let data =
+                    unsafe { core::slice::from_raw_parts(file.addr(), file.size() as usize) };
+                Some(BootFile {
+                    path: path.trim_start_matches('/').to_string(),
+                    data,
+                })
+            })
+            .collect()
+    });
+}
+
+/// Every file Limine loaded
+pub fn files() -> &'static [BootFile] {
+    FILES.get().map_or(&[], |files| files.as_slice())
+}
+
+/// Contents of the file at `path` on the boot volume
+pub fn find(path: &str) -> Option<&'static [u8]> {
+    let path = path.trim_start_matches('/');
+    files()
+        .iter()
+        .find(|file| file.path == path)
+        .map(|file| file.data)
+}
+
+/// A directory of the boot volume, holding the files under `prefix`
+pub struct Directory {
+    /// Path of the directory with a trailing slash, empty for `/initramfs`
+    prefix: String,
+}
+
+impl Directory {
+    pub fn root() -> Self {
+        Self {
+            prefix: String::new(),
+        }
+    }
+}
+
+impl Inode for Directory {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let path = self.prefix.clone() + name;
+        if let Some(data) = find(&path) {
+            return Ok(Arc::new(ReadOnlyFile { data }));
+        }
+        let prefix = path + "/";
+        if files().iter().any(|file| file.path.starts_with(&prefix)) {
+            return Ok(Arc::new(Directory { prefix }));
+        }
+        Err(FsError::NotFound)
+    }
+
+    fn entries(&self) -> Result<Vec<String>, FsError> {
+        let mut names: Vec<String> = files()
+            .iter()
+            .filter_map(|file| file.path.strip_prefix(&self.prefix))
+            .map(|rest| rest.split('/').next().unwrap_or(rest).to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn create(&self, _name: &str, _kind: NodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotSupported)
+    }
+}
+
+/// A loaded file, which can't be changed
+struct ReadOnlyFile {
+    data: &'static [u8],
+}
+
+impl Inode for ReadOnlyFile {
+    fn kind(&self) -> NodeKind {
+        NodeKind::File
+    }
+
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let start = (offset as usize).min(self.data.len());
+        let len = buf.len().min(self.data.len() - start);
+        buf[..len].copy_from_slice(&self.data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+}
@@ -0,0 +1,225 @@
+//! Device attributes as files
+//!
+//! Mounted on `/sys`. Devices register their attributes, through
+//! `kernel_api::attr` or `register`, and each becomes a file under
+//! `/sys/devices/<device>/`: reading it shows the current value, writing to
+//! it stores a new one, so a driver's knobs can be tuned with the file
+//! system rather than a shell command each.
+//!
+//! Nothing is stored, every read and write goes to the attribute's
+//! functions. A write must be a whole value written at offset 0.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use kernel_api::RegisterError;
+pub use kernel_api::attr::Attribute;
+use x86_64::instructions::interrupts;
+
+use super::{FsError, Inode, NodeKind};
+use crate::sync::Mutex;
+
+/// A device's attributes and the context passed to their functions
+struct Device {
+    name: String,
+    attributes: &'static [Attribute],
+    context: usize,
+}
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new("SYSFS_DEVICES", Vec::new());
+
+/// Expose the attributes of a device, failing if it has some already
+pub fn register(
+    device: &str,
+    attributes: &'static [Attribute],
+    context: usize,
+) -> Result<(), RegisterError> {
+    interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        if devices.iter().any(|registered| registered.name == device) {
+            return Err(RegisterError::AlreadyRegistered);
+        }
+        devices.push(Device {
+            name: device.to_string(),
+            attributes,
+            context,
+        });
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(())
+    })
+}
+
+/// Remove the attributes of a device, open files of them fail from then on
+pub fn unregister(device: &str) {
+    interrupts::without_interrupts(|| {
+        DEVICES
+            .lock()
+            .retain(|registered| registered.name != device)
+    });
+}
+
+/// The attribute `name` of `device`, with its context
+fn find(device: &str, name: &str) -> Option<(&'static Attribute, usize)> {
+    interrupts::without_interrupts(|| {
+        let devices = DEVICES.lock();
+        let device = devices
+            .iter()
+            .find(|registered| registered.name == device)?;
+        let attribute = device
+            .attributes
+            .iter()
+            .find(|attribute| attribute.name == name)?;
+        Some((attribute, device.context))
+    })
+}
+
+/// `/sys`
+pub struct Root;
+
+impl Inode for Root {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match name {
+            "devices" => Ok(Arc::new(Devices)),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn entries(&self) -> Result<Vec<String>, FsError> {
+        Ok(Vec::from(["devices".to_string()]))
+    }
+}
+
+/// `/sys/devices`, a directory per registered device
+struct Devices;
+
+impl Inode for Devices {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let registered = interrupts::without_interrupts(|| {
+            DEVICES.lock().iter().any(|device| device.name == name)
+        });
+        if !registered {
+            return Err(FsError::NotFound);
+        }
+        Ok(Arc::new(DeviceDirectory {
+            device: name.to_string(),
+        }))
+    }
+
+    fn entries(&self) -> Result<Vec<String>, FsError> {
+        Ok(interrupts::without_interrupts(|| {
+            DEVICES
+                .lock()
+                .iter()
+                .map(|device| device.name.clone())
+                .collect()
+        }))
+    }
+}
+
+/// `/sys/devices/<device>`, a file per attribute
+struct DeviceDirectory {
+    device: String,
+}
+
+impl Inode for DeviceDirectory {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        find(&self.device, name).ok_or(FsError::NotFound)?;
+        Ok(Arc::new(AttributeFile {
+            device: self.device.clone(),
+            name: name.to_string(),
+        }))
+    }
+
+    fn entries(&self) -> Result<Vec<String>, FsError> {
+        let mut names: Vec<String> = interrupts::without_interrupts(|| {
+            DEVICES
+                .lock()
+                .iter()
+                .find(|device| device.name == self.device)
+                .map(|device| {
+                    device
+                        .attributes
+                        .iter()
+                        .map(|attribute| attribute.name.to_string())
+                        .collect()
+                })
+                .ok_or(FsError::NotFound)
+        })?;
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// An attribute, looked up again on every access in case the device went
+/// away
+struct AttributeFile {
+    device: String,
+    name: String,
+}
+
+impl AttributeFile {
+    fn attribute(&self) -> Result<(&'static Attribute, usize), FsError> {
+        find(&self.device, &self.name).ok_or(FsError::NotFound)
+    }
+
+    /// The value as read from the file, with a newline
+    fn contents(&self) -> Result<String, FsError> {
+        let (attribute, context) = self.attribute()?;
+        let value = (attribute.show)(context).map_err(FsError::DeviceAttribute)?;
+        Ok(format!("{value}\n"))
+    }
+}
+
+impl Inode for AttributeFile {
+    fn kind(&self) -> NodeKind {
+        NodeKind::File
+    }
+
+    fn size(&self) -> u64 {
+        self.contents().map_or(0, |contents| contents.len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let contents = self.contents()?;
+        let Some(rest) = contents.as_bytes().get(offset as usize..) else {
+            return Ok(0);
+        };
+        let count = rest.len().min(buf.len());
+        buf[..count].copy_from_slice(&rest[..count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let (attribute, context) = self.attribute()?;
+        let store = attribute.store.ok_or(FsError::NotSupported)?;
+        if offset != 0 {
+            return Err(FsError::InvalidSeek);
+        }
+        let value = core::str::from_utf8(buf)
+            .map_err(|_| FsError::DeviceAttribute("not text"))?
+            .trim();
+        store(context, value).map_err(FsError::DeviceAttribute)?;
+        Ok(buf.len())
+    }
+
+    /// Opening with `O_TRUNC` truncates first, which means nothing here
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+//! File system tests
+
+use alloc::{sync::Arc, vec::Vec};
+
+use super::{
+    FsError, Inode, Whence,
+    fat::{self, FatError, Geometry, exact_short_name, generated_short_name, long_name_entries},
+    flags, install, lseek, release,
+    tmpfs::File,
+};
+use crate::block::tests::MemoryDevice;
+
+const PAGE: u64 = 4096;
+
+/// Stands in for a task, so the descriptors don't mix with a real one's
+const PID: u64 = u64::MAX;
+
+#[test_case]
+fn test_tmpfs_holes_read_as_zeros() {
+    let file = File::new();
+    file.write_at(2 * PAGE + 10, b"data").unwrap();
+    assert_eq!(file.size(), 2 * PAGE + 14);
+    // only the written page takes memory
+    assert_eq!(file.stat().allocated, PAGE);
+
+    let mut buf = [0xff; 16];
+    assert_eq!(file.read_at(PAGE - 8, &mut buf), Ok(16));
+    assert!(buf.iter().all(|&byte| byte == 0));
+    assert_eq!(file.read_at(2 * PAGE + 8, &mut buf), Ok(6));
+    assert_eq!(&buf[..6], b"\0\0data");
+}
+
+#[test_case]
+fn test_tmpfs_truncate_clears_the_tail() {
+    let file = File::new();
+    file.write_at(0, &[0xaa; 100]).unwrap();
+    file.truncate(10).unwrap();
+    file.truncate(100).unwrap();
+
+    let mut buf = [0xff; 100];
+    assert_eq!(file.read_at(0, &mut buf), Ok(100));
+    assert!(buf[..10].iter().all(|&byte| byte == 0xaa));
+    assert!(buf[10..].iter().all(|&byte| byte == 0));
+}
+
+#[test_case]
+fn test_tmpfs_next_data_and_hole() {
+    let file = File::new();
+    file.write_at(0, b"a").unwrap();
+    file.write_at(2 * PAGE, b"b").unwrap();
+    file.truncate(4 * PAGE).unwrap();
+
+    assert_eq!(file.next_data(0), Some(0));
+    assert_eq!(file.next_data(1), Some(1));
+    assert_eq!(file.next_data(PAGE), Some(2 * PAGE));
+    assert_eq!(file.next_data(3 * PAGE), None);
+
+    assert_eq!(file.next_hole(0), PAGE);
+    assert_eq!(file.next_hole(2 * PAGE), 3 * PAGE);
+    // the end of the file counts as a hole
+    assert_eq!(file.next_hole(3 * PAGE + 5), 3 * PAGE + 5);
+}
+
+#[test_case]
+fn test_lseek_data_and_hole() {
+    let file = Arc::new(File::new());
+    file.write_at(PAGE, b"data").unwrap();
+    let size = PAGE + 4;
+    let fd = install(PID, file, flags::O_RDWR).unwrap();
+
+    assert_eq!(lseek(PID, fd, 0, Whence::Data), Ok(PAGE));
+    assert_eq!(lseek(PID, fd, 0, Whence::Hole), Ok(0));
+    assert_eq!(lseek(PID, fd, PAGE as i64, Whence::Hole), Ok(size));
+    assert_eq!(
+        lseek(PID, fd, size as i64, Whence::Data),
+        Err(FsError::NoSuchOffset)
+    );
+    assert_eq!(
+        lseek(PID, fd, size as i64, Whence::Hole),
+        Err(FsError::NoSuchOffset)
+    );
+    assert_eq!(lseek(PID, fd, -1, Whence::Data), Err(FsError::InvalidSeek));
+
+    release(PID);
+}
+
+#[test_case]
+fn test_fat_cluster_size() {
+    // the largest clusters that leave FAT32 at least 65525 of them
+    let geometry = Geometry::new(&MemoryDevice::new(512, 2 * 1024 * 1024)).unwrap();
+    assert_eq!(geometry.sectors_per_cluster, 8);
+    assert_eq!(geometry.fat_sectors, 2048);
+    assert_eq!(geometry.clusters, 261628);
+
+    let geometry = Geometry::new(&MemoryDevice::new(512, 128 * 1024)).unwrap();
+    assert_eq!(geometry.sectors_per_cluster, 1);
+    assert_eq!(geometry.clusters, 128992);
+
+    let geometry = Geometry::new(&MemoryDevice::new(4096, 256 * 1024)).unwrap();
+    assert_eq!(geometry.bytes_per_sector, 4096);
+    assert_eq!(geometry.sectors_per_cluster, 1);
+    assert_eq!(geometry.total_sectors, 256 * 1024);
+}
+
+#[test_case]
+fn test_fat_bad_sizes() {
+    let error = |device: MemoryDevice| Geometry::new(&device).err();
+    // too few clusters even at one sector each, then too many at 4 KiB
+    assert_eq!(
+        error(MemoryDevice::new(512, 32 * 1024)),
+        Some(FatError::BadSize)
+    );
+    assert_eq!(
+        error(MemoryDevice::new(512, u32::MAX as u64)),
+        Some(FatError::BadSize)
+    );
+    assert_eq!(
+        error(MemoryDevice::new(512, 1 << 32)),
+        Some(FatError::BadSize)
+    );
+    assert_eq!(
+        error(MemoryDevice::new(1000, 1 << 20)),
+        Some(FatError::BadBlockSize)
+    );
+    assert_eq!(
+        error(MemoryDevice::new(8192, 1 << 20)),
+        Some(FatError::BadBlockSize)
+    );
+}
+
+#[test_case]
+fn test_fat_exact_short_names() {
+    assert_eq!(exact_short_name("BOOTX64.EFI"), Some(*b"BOOTX64 EFI"));
+    assert_eq!(exact_short_name("EFI"), Some(*b"EFI        "));
+    assert_eq!(exact_short_name("bootx64.efi"), None);
+    assert_eq!(exact_short_name("LONGNAME9.TXT"), None);
+    assert_eq!(exact_short_name("A.TEXT"), None);
+    assert_eq!(exact_short_name("A.B.C"), None);
+    assert_eq!(exact_short_name(".EFI"), None);
+}
+
+#[test_case]
+fn test_fat_generated_short_names() {
+    assert_eq!(generated_short_name("bootx64.efi", &[]), *b"BOOTX64 EFI");
+    assert_eq!(
+        generated_short_name("bootx64.efi", &[*b"BOOTX64 EFI"]),
+        *b"BOOTX6~1EFI"
+    );
+    assert_eq!(
+        generated_short_name("kernel-image.elf", &[]),
+        *b"KERNEL~1ELF"
+    );
+    assert_eq!(
+        generated_short_name("kernel-image.elf", &[*b"KERNEL~1ELF"]),
+        *b"KERNEL~2ELF"
+    );
+    // characters a short name can't hold are dropped
+    assert_eq!(generated_short_name("my file.txt", &[]), *b"MYFILE~1TXT");
+}
+
+#[test_case]
+fn test_fat_long_name_entries() {
+    // exactly 13 units fill one entry, with no room for the NUL
+    let entries = long_name_entries("kernel-image.", b"KERNEL~1   ");
+    assert_eq!(entries.len(), 32);
+    assert_eq!(entries[0], 0x41);
+    assert_eq!(entries[11], 0x0f);
+    assert_eq!(entries[1..3], [b'k', 0]);
+    assert_eq!(entries[30..32], [b'.', 0]);
+
+    // a 14th unit takes a second entry, which is written first
+    let entries = long_name_entries("kernel-image.elf", b"KERNEL~1ELF");
+    assert_eq!(entries.len(), 64);
+    assert_eq!(entries[0], 0x42);
+    assert_eq!(entries[32], 0x01);
+    assert_eq!(entries[13], 0x9d);
+    assert_eq!(entries[32 + 13], 0x9d);
+    assert_eq!(entries[1..7], [b'e', 0, b'l', 0, b'f', 0]);
+    assert_eq!(entries[7..11], [0, 0, 0xff, 0xff]);
+    assert_eq!(entries[30..32], [0xff, 0xff]);
+    assert_eq!(entries[33..35], [b'k', 0]);
+    assert_eq!(entries[62..64], [b'.', 0]);
+
+    let entries = long_name_entries("bootx64.efi", b"BOOTX64 EFI");
+    assert_eq!(entries[13], 0x1d);
+}
+
+#[test_case]
+fn test_fat_mkfs() {
+    // 64 MiB, which FAT32 only fits with one sector clusters
+    let device = MemoryDevice::new(512, 128 * 1024);
+    let loader = [0x5a; 1000];
+    fat::mkfs(
+        &device,
+        &[
+            ("/EFI/BOOT/BOOTX64.EFI", &loader),
+            ("kernel-image.elf", b"kernel\n"),
+        ],
+        "locos",
+    )
+    .unwrap();
+
+    let u16_at =
+        |bytes: &[u8], offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+    let u32_at = |bytes: &[u8], offset: usize| {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    };
+
+    let reserved = device.read(0, 32);
+    let boot = &reserved[..512];
+    assert_eq!(u16_at(boot, 11), 512);
+    assert_eq!(boot[13], 1);
+    assert_eq!(u16_at(boot, 14), 32);
+    assert_eq!(boot[16], 2);
+    assert_eq!(u32_at(boot, 32), 128 * 1024);
+    assert_eq!(u32_at(boot, 36), 1024);
+    assert_eq!(u32_at(boot, 44), 2);
+    assert_eq!(&boot[71..82], b"LOCOS      ");
+    assert_eq!(&boot[82..90], b"FAT32   ");
+    assert_eq!(boot[510..512], [0x55, 0xaa]);
+    assert_eq!(reserved[6 * 512..8 * 512], reserved[..2 * 512]);
+
+    // the root, EFI and BOOT take a cluster each, the loader two and the
+    // kernel one
+    let fsinfo = &reserved[512..1024];
+    assert_eq!(u32_at(fsinfo, 0), 0x4161_5252);
+    assert_eq!(u32_at(fsinfo, 484), 0x6141_7272);
+    assert_eq!(u32_at(fsinfo, 488), 128992 + 2 - 8);
+    assert_eq!(u32_at(fsinfo, 492), 8);
+
+    let fat = device.read(32, 1);
+    assert_eq!(device.read(32 + 1024, 1), fat);
+    let chain: Vec<u32> = (0..9).map(|cluster| u32_at(&fat, cluster * 4)).collect();
+    let end = 0x0fff_ffff;
+    assert_eq!(chain, [0x0fff_fff8, end, end, end, end, 6, end, end, 0]);
+
+    // the data starts after both FATs, with cluster 2
+    let data = 32 + 2 * 1024;
+    let root = device.read(data, 1);
+    assert_eq!(&root[0..11], b"LOCOS      ");
+    assert_eq!(root[11], 0x08);
+    assert_eq!(&root[32..43], b"EFI        ");
+    assert_eq!(root[32 + 11], 0x10);
+    assert_eq!(u16_at(&root, 32 + 26), 3);
+    assert_eq!(root[64], 0x42);
+    assert_eq!(&root[128..139], b"KERNEL~1ELF");
+    assert_eq!(u16_at(&root, 128 + 26), 7);
+    assert_eq!(u32_at(&root, 128 + 28), 7);
+
+    assert_eq!(device.read(data + 3, 2)[..1000], loader);
+}
@@ -0,0 +1,415 @@
+//! In-memory file system
+//!
+//! File data lives in frames taken from the frame allocator, one per 4 KiB
+//! page of the file. Pages are only allocated when written, so a page that
+//! was never written is a hole and reads back as zeros without taking any
+//! memory. Timestamps and extended attributes are kept with each inode.
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+};
+
+use super::{FsError, Inode, MAX_FILE_SIZE, NodeKind, Stat, now};
+use crate::{memory::FRAME_ALLOCATOR, sync::Mutex};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Pointer to the start of a page through the higher half direct map
+fn page_data(frame: PhysFrame, hhdm_offset: u64) -> *mut u8 {
+    VirtAddr::new(frame.start_address().as_u64() + hhdm_offset).as_mut_ptr()
+}
+
+fn hhdm_offset() -> u64 {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map_or(0, |allocator| allocator.hddm_offset)
+}
+
+/// Timestamps and extended attributes, kept under the lock of their inode
+struct Attributes {
+    accessed: u64,
+    modified: u64,
+    changed: u64,
+    xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl Attributes {
+    fn new() -> Self {
+        let now = now();
+        Self {
+            accessed: now,
+            modified: now,
+            changed: now,
+            xattrs: BTreeMap::new(),
+        }
+    }
+
+    fn touch_accessed(&mut self) {
+        self.accessed = now();
+    }
+
+    fn touch_modified(&mut self) {
+        self.modified = now();
+        self.changed = self.modified;
+    }
+
+    fn stat(&self, kind: NodeKind, size: u64, allocated: u64) -> Stat {
+        Stat {
+            kind,
+            xattrs: self.xattrs.len() as u32,
+            size,
+            allocated,
+            accessed: self.accessed,
+            modified: self.modified,
+            changed: self.changed,
+        }
+    }
+
+    fn get_xattr(&self, name: &str) -> Result<Vec<u8>, FsError> {
+        self.xattrs.get(name).cloned().ok_or(FsError::NoAttribute)
+    }
+
+    fn set_xattr(&mut self, name: &str, value: &[u8]) {
+        self.xattrs.insert(name.to_string(), value.to_vec());
+        self.changed = now();
+    }
+
+    fn remove_xattr(&mut self, name: &str) -> Result<(), FsError> {
+        self.xattrs.remove(name).ok_or(FsError::NoAttribute)?;
+        self.changed = now();
+        Ok(())
+    }
+
+    fn list_xattrs(&self) -> Vec<String> {
+        self.xattrs.keys().cloned().collect()
+    }
+}
+
+struct FileData {
+    size: u64,
+    /// Allocated pages by index, missing ones are holes
+    pages: BTreeMap<u64, PhysFrame>,
+    attributes: Attributes,
+}
+
+impl FileData {
+    /// Free the pages at or after `first`
+    fn free_pages_from(&mut self, first: u64) {
+        let freed = self.pages.split_off(&first);
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        if let Some(allocator) = allocator.as_mut() {
+            for frame in freed.into_values() {
+                unsafe { allocator.deallocate_frame(frame) };
+            }
+        }
+    }
+}
+
+/// A regular file
+pub struct File {
+    data: Mutex<FileData>,
+}
+
+impl File {
+    pub fn new() -> Self {
+        Self {
+            data: Mutex::new(
+                "TMPFS_FILE",
+                FileData {
+                    size: 0,
+                    pages: BTreeMap::new(),
+                    attributes: Attributes::new(),
+                },
+            ),
+        }
+    }
+}
+
+impl Default for File {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| self.data.lock().free_pages_from(0));
+    }
+}
+
+impl Inode for File {
+    fn kind(&self) -> NodeKind {
+        NodeKind::File
+    }
+
+    fn size(&self) -> u64 {
+        interrupts::without_interrupts(|| self.data.lock().size)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        interrupts::without_interrupts(|| {
+            let hhdm_offset = hhdm_offset();
+            let mut data = self.data.lock();
+            let len = data.size.saturating_sub(offset).min(buf.len() as u64) as usize;
+            data.attributes.touch_accessed();
+
+            let mut done = 0;
+            while done < len {
+                let position = offset + done as u64;
+                let in_page = (position % PAGE_SIZE) as usize;
+                let count = (PAGE_SIZE as usize - in_page).min(len - done);
+                let destination = &mut buf[done..done + count];
+                match data.pages.get(&(position / PAGE_SIZE)) {
+                    Some(&frame) => unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            page_data(frame, hhdm_offset).add(in_page),
+                            destination.as_mut_ptr(),
+                            count,
+                        )
+                    },
+                    None => destination.fill(0),
+                }
+                done += count;
+            }
+            Ok(len)
+        })
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        if offset.saturating_add(buf.len() as u64) > MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
+
+        interrupts::without_interrupts(|| {
+            let hhdm_offset = hhdm_offset();
+            let mut data = self.data.lock();
+
+            let mut done = 0;
+            while done < buf.len() {
+                let position = offset + done as u64;
+                let in_page = (position % PAGE_SIZE) as usize;
+                let count = (PAGE_SIZE as usize - in_page).min(buf.len() - done);
+                let index = position / PAGE_SIZE;
+
+                let frame = match data.pages.get(&index) {
+                    Some(&frame) => frame,
+                    None => {
+                        let Some(frame) = FRAME_ALLOCATOR
+                            .lock()
+                            .as_mut()
+                            .and_then(|allocator| allocator.allocate_frame())
+                        else {
+                            break;
+                        };
+                        unsafe { page_data(frame, hhdm_offset).write_bytes(0, PAGE_SIZE as usize) };
+                        data.pages.insert(index, frame);
+                        frame
+                    }
+                };
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        buf[done..].as_ptr(),
+                        page_data(frame, hhdm_offset).add(in_page),
+                        count,
+                    )
+                };
+                done += count;
+            }
+
+            if done == 0 && !buf.is_empty() {
+                return Err(FsError::NoSpace);
+            }
+            data.size = data.size.max(offset + done as u64);
+            data.attributes.touch_modified();
+            Ok(done)
+        })
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
+
+        interrupts::without_interrupts(|| {
+            let hhdm_offset = hhdm_offset();
+            let mut data = self.data.lock();
+            if size < data.size {
+                data.free_pages_from(size.div_ceil(PAGE_SIZE));
+                // the rest of a partial last page must read as zeros if the
+                // file grows again
+                let in_page = size % PAGE_SIZE;
+                if let Some(&frame) = data.pages.get(&(size / PAGE_SIZE))
+                    && in_page != 0
+                {
+                    unsafe {
+                        page_data(frame, hhdm_offset)
+                            .add(in_page as usize)
+                            .write_bytes(0, (PAGE_SIZE - in_page) as usize)
+                    };
+                }
+            }
+            data.size = size;
+            data.attributes.touch_modified();
+            Ok(())
+        })
+    }
+
+    fn next_data(&self, offset: u64) -> Option<u64> {
+        interrupts::without_interrupts(|| {
+            let data = self.data.lock();
+            let (&index, _) = data.pages.range(offset / PAGE_SIZE..).next()?;
+            let start = (index * PAGE_SIZE).max(offset);
+            (start < data.size).then_some(start)
+        })
+    }
+
+    fn next_hole(&self, offset: u64) -> u64 {
+        interrupts::without_interrupts(|| {
+            let data = self.data.lock();
+            let mut index = offset / PAGE_SIZE;
+            for (&allocated, _) in data.pages.range(index..) {
+                if allocated != index {
+                    break;
+                }
+                index += 1;
+            }
+            (index * PAGE_SIZE).max(offset).min(data.size.max(offset))
+        })
+    }
+
+    fn stat(&self) -> Stat {
+        interrupts::without_interrupts(|| {
+            let data = self.data.lock();
+            let allocated = data.pages.len() as u64 * PAGE_SIZE;
+            data.attributes.stat(NodeKind::File, data.size, allocated)
+        })
+    }
+
+    fn get_xattr(&self, name: &str) -> Result<Vec<u8>, FsError> {
+        interrupts::without_interrupts(|| self.data.lock().attributes.get_xattr(name))
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8]) -> Result<(), FsError> {
+        interrupts::without_interrupts(|| self.data.lock().attributes.set_xattr(name, value));
+        Ok(())
+    }
+
+    fn remove_xattr(&self, name: &str) -> Result<(), FsError> {
+        interrupts::without_interrupts(|| self.data.lock().attributes.remove_xattr(name))
+    }
+
+    fn list_xattrs(&self) -> Result<Vec<String>, FsError> {
+        Ok(interrupts::without_interrupts(|| {
+            self.data.lock().attributes.list_xattrs()
+        }))
+    }
+}
+
+struct DirectoryData {
+    entries: BTreeMap<String, Arc<dyn Inode>>,
+    attributes: Attributes,
+}
+
+/// A directory, holding its entries by name
+pub struct Directory {
+    data: Mutex<DirectoryData>,
+}
+
+impl Directory {
+    pub fn new() -> Self {
+        Self {
+            data: Mutex::new(
+                "TMPFS_DIRECTORY",
+                DirectoryData {
+                    entries: BTreeMap::new(),
+                    attributes: Attributes::new(),
+                },
+            ),
+        }
+    }
+
+    /// Add an inode of another file system as an entry, replacing any entry
+    /// with the same name
+    pub fn attach(&self, name: &str, node: Arc<dyn Inode>) {
+        interrupts::without_interrupts(|| {
+            self.data.lock().entries.insert(name.to_string(), node);
+        });
+    }
+}
+
+impl Default for Directory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Inode for Directory {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        interrupts::without_interrupts(|| self.data.lock().entries.get(name).cloned())
+            .ok_or(FsError::NotFound)
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        let node: Arc<dyn Inode> = match kind {
+            NodeKind::File => Arc::new(File::new()),
+            NodeKind::Directory => Arc::new(Directory::new()),
+        };
+        interrupts::without_interrupts(|| {
+            let mut data = self.data.lock();
+            if data.entries.contains_key(name) {
+                return Err(FsError::AlreadyExists);
+            }
+            data.entries.insert(name.to_string(), node.clone());
+            data.attributes.touch_modified();
+            Ok(node)
+        })
+    }
+
+    fn entries(&self) -> Result<Vec<String>, FsError> {
+        Ok(interrupts::without_interrupts(|| {
+            let mut data = self.data.lock();
+            data.attributes.touch_accessed();
+            data.entries.keys().cloned().collect()
+        }))
+    }
+
+    fn stat(&self) -> Stat {
+        interrupts::without_interrupts(|| {
+            let data = self.data.lock();
+            data.attributes
+                .stat(NodeKind::Directory, data.entries.len() as u64, 0)
+        })
+    }
+
+    fn get_xattr(&self, name: &str) -> Result<Vec<u8>, FsError> {
+        interrupts::without_interrupts(|| self.data.lock().attributes.get_xattr(name))
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8]) -> Result<(), FsError> {
+        interrupts::without_interrupts(|| self.data.lock().attributes.set_xattr(name, value));
+        Ok(())
+    }
+
+    fn remove_xattr(&self, name: &str) -> Result<(), FsError> {
+        interrupts::without_interrupts(|| self.data.lock().attributes.remove_xattr(name))
+    }
+
+    fn list_xattrs(&self) -> Result<Vec<String>, FsError> {
+        Ok(interrupts::without_interrupts(|| {
+            self.data.lock().attributes.list_xattrs()
+        }))
+    }
+}
@@ -8,7 +8,14 @@ use x86_64::{
     },
 };
 
+/// Interrupt stack table slots, for exceptions that may hit while the task's
+/// stack is unusable: overflowed, corrupted, or still the user's
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Size of each interrupt stack
+const IST_STACK_SIZE: usize = 4096 * 5;
 
 pub const KERNEL_CODE_SEGMENT_INDEX: u16 = 1;
 pub const KERNEL_DATA_SEGMENT_INDEX: u16 = 2;
@@ -65,15 +72,68 @@ pub fn init_gdt() {
     info!("gdt initialized");
 }
 
-/// Set up the Task State Segment (TSS) with an interrupt stack.
+/// Reload the GDT, segment registers and TSS, e.g. after a sleep state
+///
+/// # Safety
+/// `init_gdt` must have been called before.
+pub unsafe fn reload() {
+    use x86_64::instructions::{
+        segmentation::{CS, DS, ES, SS, Segment},
+        tables::{load_tss, sgdt},
+    };
+
+    GDT.0.load();
+    unsafe {
+        CS::set_reg(GDT.1.kernel_code_selector);
+        DS::set_reg(GDT.1.kernel_data_selector);
+        ES::set_reg(GDT.1.kernel_data_selector);
+        SS::set_reg(GDT.1.kernel_data_selector);
+
+        // loading a busy TSS faults, and it was marked busy when first loaded
+        let tss = (sgdt().base + TSS_SEGMENT_INDEX as u64 * 8).as_mut_ptr::<u64>();
+        *tss &= !(1 << 41);
+        load_tss(GDT.1.tss_selector);
+    }
+}
+
+/// The interrupt stacks of one CPU
+#[repr(C, align(16))]
+struct IstStacks {
+    double_fault: [u8; IST_STACK_SIZE],
+    nmi: [u8; IST_STACK_SIZE],
+    machine_check: [u8; IST_STACK_SIZE],
+}
+
+impl IstStacks {
+    const fn new() -> Self {
+        Self {
+            double_fault: [0; IST_STACK_SIZE],
+            nmi: [0; IST_STACK_SIZE],
+            machine_check: [0; IST_STACK_SIZE],
+        }
+    }
+}
+
+/// Interrupt stacks of the bootstrap processor, application processors will
+/// bring their own along with their TSS
+static mut BOOT_IST_STACKS: IstStacks = IstStacks::new();
+
+/// Top of an interrupt stack, stacks grow down
+fn stack_top(stack: *const [u8; IST_STACK_SIZE]) -> VirtAddr {
+    VirtAddr::from_ptr(stack) + IST_STACK_SIZE as u64
+}
+
+/// Set up the Task State Segment (TSS) with the interrupt stacks.
 static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-        let stack_start = VirtAddr::from_ptr(&raw const STACK);
-        stack_start + STACK_SIZE as u64
-    };
+    let stacks = &raw const BOOT_IST_STACKS;
+    unsafe {
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            stack_top(&raw const (*stacks).double_fault);
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = stack_top(&raw const (*stacks).nmi);
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] =
+            stack_top(&raw const (*stacks).machine_check);
+    }
 
     info!("tss initialized");
     tss
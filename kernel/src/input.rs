@@ -0,0 +1,106 @@
+//! Input handling shared by keyboard drivers.
+//!
+//! Drivers report key events to `devices`, which debounces them, tracks the
+//! state of each keyboard and queues the events for consumers.
+//!
+//! PS/2 keyboards repeat held keys themselves (typematic), but keyboards
+//! without hardware repeat rely on `SoftRepeat`, which generates repeated
+//! key presses from the timer using the same delay and rate as the
+//! configured typematic settings. A driver owns one per keyboard, feeds it
+//! the keyboard's events and polls it as time passes. The PS/2 driver does
+//! this when switched to software repeat; there is no USB HID keyboard
+//! driver yet, which would always need it.
+//!
+//! `latency` times key presses from the keyboard to the terminal, to catch
+//! the input path getting slower.
+
+pub mod devices;
+#[cfg(feature = "graphics")]
+pub mod latency;
+
+#[cfg(test)]
+pub mod tests;
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::ps2::keyboard::{KeyEvent, ScanCode};
+
+static REPEAT_DELAY_MS: AtomicU32 = AtomicU32::new(500);
+static REPEAT_RATE_HZ: AtomicU32 = AtomicU32::new(10);
+
+/// Whether PS/2 keyboards use software repeat instead of their own
+static SOFT_REPEAT_PS2: AtomicBool = AtomicBool::new(false);
+
+/// Delay before a held key starts repeating, and how often it repeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepeatSettings {
+    pub delay_ms: u32,
+    pub rate_hz: u32,
+}
+
+/// Current key repeat settings
+pub fn repeat_settings() -> RepeatSettings {
+    RepeatSettings {
+        delay_ms: REPEAT_DELAY_MS.load(Ordering::Relaxed),
+        rate_hz: REPEAT_RATE_HZ.load(Ordering::Relaxed),
+    }
+}
+
+/// Change the key repeat settings used by software repeat
+pub fn set_repeat_settings(settings: RepeatSettings) {
+    REPEAT_DELAY_MS.store(settings.delay_ms, Ordering::Relaxed);
+    REPEAT_RATE_HZ.store(settings.rate_hz.max(1), Ordering::Relaxed);
+}
+
+/// Whether PS/2 keyboards use software repeat instead of typematic
+pub fn soft_repeat_ps2() -> bool {
+    SOFT_REPEAT_PS2.load(Ordering::Relaxed)
+}
+
+/// Make PS/2 keyboards use software repeat, dropping their own repeats
+pub fn set_soft_repeat_ps2(enabled: bool) {
+    SOFT_REPEAT_PS2.store(enabled, Ordering::Relaxed);
+}
+
+/// Software key repeat for one keyboard
+///
+/// Times are milliseconds of uptime. Repeats are due at fixed intervals from
+/// the first one, so the rate doesn't drift with how often `poll` runs.
+#[derive(Debug, Default)]
+pub struct SoftRepeat {
+    /// Key being held and the time its next repeat is due
+    held: Option<(ScanCode, u64)>,
+}
+
+impl SoftRepeat {
+    /// Key currently being held down, if it repeats
+    pub fn held(&self) -> Option<ScanCode> {
+        self.held.map(|(scancode, _)| scancode)
+    }
+
+    /// Track a key event from the keyboard at time `now`
+    pub fn key(&mut self, event: KeyEvent, now: u64) {
+        match event {
+            KeyEvent::KeyDown(scancode) if !scancode.is_modifier() => {
+                self.held = Some((scancode, now + repeat_settings().delay_ms as u64));
+            }
+            KeyEvent::KeyUp(scancode) if self.held() == Some(scancode) => self.held = None,
+            _ => {}
+        }
+    }
+
+    /// Returns the held key if a repeat is due at time `now`
+    ///
+    /// At most one repeat is returned per call. Repeats missed by polling
+    /// less often than the rate aren't made up for later.
+    pub fn poll(&mut self, now: u64) -> Option<ScanCode> {
+        let (scancode, due) = self.held.as_mut()?;
+        if now < *due {
+            return None;
+        }
+
+        let period = 1000 / repeat_settings().rate_hz as u64;
+        *due = (*due + period).max(now);
+        Some(*scancode)
+    }
+}
@@ -0,0 +1,606 @@
+//! Input devices and event delivery
+//!
+//! Keyboard drivers register each keyboard they find and report the key
+//! events they decode. Events are normalized on the way in: unknown
+//! scancodes are dropped, a release only gets through for a key that is
+//! down, and a press of a key that is already down is marked as a repeat.
+//! A key pressed again within `input.debounce_ms` of its release is
+//! chattering; the press is dropped, and so is the release following it
+//! since the key never went down.
+//!
+//! Every device tracks its pressed keys and modifier and lock state, which
+//! each event carries. Events are broadcast to every subscriber, each with
+//! its own queue, so consumers don't take events away from each other.
+//! Interceptors, like hotkey handlers, see events first: they are asked in
+//! order of priority, and the first claiming an event gets it alone. A
+//! consumer that grabbed a device gets its events instead of anyone else.
+
+use alloc::{collections::vec_deque::VecDeque, string::String, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    ps2::keyboard::{KeyEvent, KeyboardState, ScanCode},
+    sync::Mutex,
+    sysctl::Sysctl,
+    tasks::scheduler::{wait_for_event, wake_event_waiters},
+    time,
+};
+
+/// Events queued before new ones are dropped, for each subscriber and grab
+const QUEUE_SIZE: usize = 256;
+
+static DEBOUNCE_MS: AtomicU64 = AtomicU64::new(10);
+
+pub static SYSCTLS: &[Sysctl] = &[Sysctl {
+    name: "input.debounce_ms",
+    help: "time a released key must stay up before a new press counts, 0 to never drop presses",
+    get: || DEBOUNCE_MS.load(Ordering::Relaxed),
+    set: |value| {
+        if value > 1000 {
+            return Err("must be at most 1000 ms");
+        }
+        DEBOUNCE_MS.store(value, Ordering::Relaxed);
+        Ok(())
+    },
+}];
+
+/// Time a released key must stay up before a new press counts
+pub fn debounce_ms() -> u64 {
+    DEBOUNCE_MS.load(Ordering::Relaxed)
+}
+
+/// Registered devices and subscribers
+static INPUT: Mutex<Input> = Mutex::new(
+    "INPUT",
+    Input {
+        devices: Vec::new(),
+        subscribers: Vec::new(),
+        next_subscriber: 0,
+    },
+);
+
+/// Index of a device, never reused
+pub type DeviceId = usize;
+
+/// Id of a subscriber, never reused
+pub type SubscriberId = usize;
+
+/// Decides whether an interceptor takes an event for itself
+///
+/// Called from interrupt handlers with the input lock held, so it must not
+/// block or use the input layer. A hotkey handler should claim the releases
+/// of its keys along with the presses.
+pub type Claims = fn(&InputEvent) -> bool;
+
+/// Why a device couldn't be grabbed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputError {
+    NoSuchDevice,
+    /// Another consumer holds the grab
+    Grabbed,
+}
+
+/// A key event as consumers see it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub device: DeviceId,
+    pub key: ScanCode,
+    pub pressed: bool,
+    /// A press of a key already down, repeated by the keyboard or in software
+    pub repeat: bool,
+    /// Modifier and lock state after the event
+    pub state: KeyboardState,
+    /// Tick the event was reported in
+    pub tick: u64,
+}
+
+impl InputEvent {
+    /// Character typed by a press, if any
+    pub fn to_char(&self) -> Option<char> {
+        if !self.pressed {
+            return None;
+        }
+        self.key
+            .to_char(self.state.shift_pressed(), self.state.caps_lock)
+    }
+}
+
+/// Counters of one device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    /// Events passed on
+    pub events: u64,
+    /// Presses dropped as chatter, with their releases
+    pub debounced: u64,
+    /// Events dropped as unknown, inconsistent, or because the grab's queue
+    /// was full
+    pub dropped: u64,
+}
+
+/// A key change that got through `KeyFilter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyChange {
+    pub key: ScanCode,
+    pub pressed: bool,
+    pub repeat: bool,
+}
+
+/// Key state of one device, normalizing and debouncing its events
+#[derive(Debug, Default)]
+pub struct KeyFilter {
+    state: KeyboardState,
+    pressed: Vec<ScanCode>,
+    /// Keys released within the debounce interval, with the tick of release
+    released: Vec<(ScanCode, u64)>,
+    stats: DeviceStats,
+}
+
+impl KeyFilter {
+    pub fn state(&self) -> KeyboardState {
+        self.state
+    }
+
+    /// Keys currently down
+    pub fn pressed(&self) -> &[ScanCode] {
+        &self.pressed
+    }
+
+    pub fn stats(&self) -> DeviceStats {
+        self.stats
+    }
+
+    /// Track `event` at tick `now`, returning the change to pass on, if any
+    ///
+    /// A press of a key released less than `debounce_ticks` ticks ago is
+    /// dropped.
+    pub fn filter(&mut self, event: KeyEvent, now: u64, debounce_ticks: u64) -> Option<KeyChange> {
+        self.released
+            .retain(|&(_, released)| now.saturating_sub(released) < debounce_ticks);
+
+        let change = match event {
+            KeyEvent::KeyDown(key) if self.pressed.contains(&key) => KeyChange {
+                key,
+                pressed: true,
+                repeat: true,
+            },
+            KeyEvent::KeyDown(key) => {
+                if self.released.iter().any(|&(released, _)| released == key) {
+                    self.stats.debounced += 1;
+                    return None;
+                }
+                self.pressed.push(key);
+                KeyChange {
+                    key,
+                    pressed: true,
+                    repeat: false,
+                }
+            }
+            KeyEvent::KeyUp(key) => {
+                let Some(index) = self.pressed.iter().position(|&pressed| pressed == key) else {
+                    self.stats.dropped += 1;
+                    return None;
+                };
+                self.pressed.swap_remove(index);
+                if debounce_ticks > 0 {
+                    self.released.push((key, now));
+                }
+                KeyChange {
+                    key,
+                    pressed: false,
+                    repeat: false,
+                }
+            }
+            KeyEvent::Unknown(_) => {
+                self.stats.dropped += 1;
+                return None;
+            }
+        };
+
+        if !change.repeat {
+            self.state.update(event);
+        }
+        self.stats.events += 1;
+        Some(change)
+    }
+
+    /// Release every key that is down, as when the keyboard was reset
+    fn release_all(&mut self) -> Vec<KeyChange> {
+        let keys: Vec<ScanCode> = self.pressed.drain(..).collect();
+        keys.into_iter()
+            .map(|key| {
+                self.state.update(KeyEvent::KeyUp(key));
+                self.stats.events += 1;
+                KeyChange {
+                    key,
+                    pressed: false,
+                    repeat: false,
+                }
+            })
+            .collect()
+    }
+}
+
+struct Device {
+    name: String,
+    filter: KeyFilter,
+    /// Events for the consumer holding the grab, if one does
+    grab: Option<VecDeque<InputEvent>>,
+    /// Whether the driver unregistered the device
+    gone: bool,
+}
+
+struct Subscriber {
+    id: SubscriberId,
+    name: &'static str,
+    /// Priority and predicate of an interceptor, None for a subscriber
+    /// getting every event nobody claimed
+    intercept: Option<(u8, Claims)>,
+    queue: VecDeque<InputEvent>,
+    delivered: u64,
+    dropped: u64,
+}
+
+impl Subscriber {
+    fn push(&mut self, event: InputEvent) {
+        if self.queue.len() < QUEUE_SIZE {
+            self.queue.push_back(event);
+            self.delivered += 1;
+        } else {
+            self.dropped += 1;
+        }
+    }
+}
+
+struct Input {
+    devices: Vec<Device>,
+    /// Interceptors first, by falling priority, then plain subscribers
+    subscribers: Vec<Subscriber>,
+    next_subscriber: SubscriberId,
+}
+
+impl Input {
+    fn device_mut(&mut self, id: DeviceId) -> Option<&mut Device> {
+        self.devices.get_mut(id).filter(|device| !device.gone)
+    }
+
+    /// Queue `change` for whoever reads `device`
+    fn deliver(&mut self, id: DeviceId, change: KeyChange) {
+        let device = &mut self.devices[id];
+        let event = InputEvent {
+            device: id,
+            key: change.key,
+            pressed: change.pressed,
+            repeat: change.repeat,
+            state: device.filter.state,
+            tick: time::ticks(),
+        };
+        if let Some(grab) = device.grab.as_mut() {
+            if grab.len() < QUEUE_SIZE {
+                grab.push_back(event);
+            } else {
+                device.filter.stats.dropped += 1;
+            }
+            return;
+        }
+
+        let claimed = self.subscribers.iter_mut().find(|subscriber| {
+            subscriber
+                .intercept
+                .is_some_and(|(_, claims)| claims(&event))
+        });
+        if let Some(interceptor) = claimed {
+            interceptor.push(event);
+            return;
+        }
+        self.subscribers
+            .iter_mut()
+            .filter(|subscriber| subscriber.intercept.is_none())
+            .for_each(|subscriber| subscriber.push(event));
+    }
+
+    fn subscriber_mut(&mut self, id: SubscriberId) -> &mut Subscriber {
+        self.subscribers
+            .iter_mut()
+            .find(|subscriber| subscriber.id == id)
+            .expect("subscription outlived its subscriber")
+    }
+}
+
+/// A registered device, for listing
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    pub name: String,
+    pub state: KeyboardState,
+    pub pressed: Vec<ScanCode>,
+    pub grabbed: bool,
+    pub stats: DeviceStats,
+}
+
+/// A subscriber, for listing
+#[derive(Debug, Clone)]
+pub struct SubscriberInfo {
+    pub id: SubscriberId,
+    pub name: &'static str,
+    /// Priority of an interceptor
+    pub priority: Option<u8>,
+    pub queued: usize,
+    pub delivered: u64,
+    /// Events dropped because the queue was full
+    pub dropped: u64,
+}
+
+/// Key of the event consumers wait on, woken whenever any queue gets events
+fn event_key() -> usize {
+    &raw const INPUT as usize
+}
+
+/// Register a device, returning the id its driver reports events with
+pub fn register(name: &str) -> DeviceId {
+    interrupts::without_interrupts(|| {
+        let mut input = INPUT.lock();
+        input.devices.push(Device {
+            name: String::from(name),
+            filter: KeyFilter::default(),
+            grab: None,
+            gone: false,
+        });
+        input.devices.len() - 1
+    })
+}
+
+/// Remove a device, releasing its keys
+///
+/// A grab on it stops getting events.
+pub fn unregister(id: DeviceId) {
+    release_all(id);
+    interrupts::without_interrupts(|| {
+        if let Some(device) = INPUT.lock().device_mut(id) {
+            device.gone = true;
+        }
+    });
+}
+
+/// Report an event from a device, returning the device state after it if it
+/// got through
+///
+/// May be called from interrupt handlers.
+pub fn report(id: DeviceId, event: KeyEvent) -> Option<KeyboardState> {
+    let debounce_ticks = match DEBOUNCE_MS.load(Ordering::Relaxed) {
+        0 => 0,
+        ms => time::ms_to_ticks(ms),
+    };
+    let state = interrupts::without_interrupts(|| {
+        let mut input = INPUT.lock();
+        let device = input.device_mut(id)?;
+        let change = device.filter.filter(event, time::ticks(), debounce_ticks)?;
+        let state = device.filter.state;
+        input.deliver(id, change);
+        Some(state)
+    });
+    if state.is_some() {
+        wake_event_waiters(event_key());
+    }
+    state
+}
+
+/// Release the keys a device has down, for a driver that lost track of them
+pub fn release_all(id: DeviceId) {
+    let released = interrupts::without_interrupts(|| {
+        let mut input = INPUT.lock();
+        let Some(device) = input.device_mut(id) else {
+            return false;
+        };
+        let changes = device.filter.release_all();
+        let released = !changes.is_empty();
+        for change in changes {
+            input.deliver(id, change);
+        }
+        released
+    });
+    if released {
+        wake_event_waiters(event_key());
+    }
+}
+
+/// Modifier and lock state of a device
+pub fn state(id: DeviceId) -> Option<KeyboardState> {
+    interrupts::without_interrupts(|| {
+        INPUT
+            .lock()
+            .device_mut(id)
+            .map(|device| device.filter.state)
+    })
+}
+
+/// Every registered device
+pub fn devices() -> Vec<DeviceInfo> {
+    interrupts::without_interrupts(|| {
+        let input = INPUT.lock();
+        input
+            .devices
+            .iter()
+            .enumerate()
+            .filter(|(_, device)| !device.gone)
+            .map(|(id, device)| DeviceInfo {
+                id,
+                name: device.name.clone(),
+                state: device.filter.state,
+                pressed: device.filter.pressed.clone(),
+                grabbed: device.grab.is_some(),
+                stats: device.filter.stats,
+            })
+            .collect()
+    })
+}
+
+/// Every subscriber, interceptors first
+pub fn subscribers() -> Vec<SubscriberInfo> {
+    interrupts::without_interrupts(|| {
+        INPUT
+            .lock()
+            .subscribers
+            .iter()
+            .map(|subscriber| SubscriberInfo {
+                id: subscriber.id,
+                name: subscriber.name,
+                priority: subscriber.intercept.map(|(priority, _)| priority),
+                queued: subscriber.queue.len(),
+                delivered: subscriber.delivered,
+                dropped: subscriber.dropped,
+            })
+            .collect()
+    })
+}
+
+/// A queue of the events of the devices nobody grabbed
+///
+/// Dropping it unsubscribes.
+#[derive(Debug)]
+pub struct Subscription {
+    id: SubscriberId,
+}
+
+/// Get every event no interceptor claims, from now on
+pub fn subscribe(name: &'static str) -> Subscription {
+    add_subscriber(name, None)
+}
+
+/// Get the events `claims` picks before plain subscribers see them
+///
+/// Interceptors are asked in order of falling `priority`, those of the same
+/// priority in the order they were added, and the first claiming an event
+/// takes it.
+pub fn intercept(name: &'static str, priority: u8, claims: Claims) -> Subscription {
+    add_subscriber(name, Some((priority, claims)))
+}
+
+fn add_subscriber(name: &'static str, intercept: Option<(u8, Claims)>) -> Subscription {
+    interrupts::without_interrupts(|| {
+        let mut input = INPUT.lock();
+        let id = input.next_subscriber;
+        input.next_subscriber += 1;
+
+        let priority = intercept.map(|(priority, _)| priority);
+        let index = input
+            .subscribers
+            .iter()
+            .position(|subscriber| subscriber.intercept.map(|(priority, _)| priority) < priority)
+            .unwrap_or(input.subscribers.len());
+        input.subscribers.insert(
+            index,
+            Subscriber {
+                id,
+                name,
+                intercept,
+                queue: VecDeque::new(),
+                delivered: 0,
+                dropped: 0,
+            },
+        );
+        Subscription { id }
+    })
+}
+
+impl Subscription {
+    pub fn id(&self) -> SubscriberId {
+        self.id
+    }
+
+    /// Next event in the queue
+    pub fn read_event(&self) -> Option<InputEvent> {
+        interrupts::without_interrupts(|| INPUT.lock().subscriber_mut(self.id).queue.pop_front())
+    }
+
+    /// Sleep until an event comes in
+    ///
+    /// Must be called from a task with interrupts enabled.
+    pub fn wait_event(&self) -> InputEvent {
+        loop {
+            interrupts::disable();
+            if let Some(event) = INPUT.lock().subscriber_mut(self.id).queue.pop_front() {
+                interrupts::enable();
+                return event;
+            }
+            wait_for_event(event_key());
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            INPUT
+                .lock()
+                .subscribers
+                .retain(|subscriber| subscriber.id != self.id)
+        });
+    }
+}
+
+/// Exclusive access to the events of one device
+///
+/// Dropping it gives the device back to the subscribers.
+#[derive(Debug)]
+pub struct Grab {
+    device: DeviceId,
+}
+
+/// Take the events of `device` away from every other consumer
+pub fn grab(device: DeviceId) -> Result<Grab, InputError> {
+    interrupts::without_interrupts(|| {
+        let mut input = INPUT.lock();
+        let device_state = input.device_mut(device).ok_or(InputError::NoSuchDevice)?;
+        if device_state.grab.is_some() {
+            return Err(InputError::Grabbed);
+        }
+        device_state.grab = Some(VecDeque::new());
+        Ok(Grab { device })
+    })
+}
+
+impl Grab {
+    pub fn device(&self) -> DeviceId {
+        self.device
+    }
+
+    /// Next event of the grabbed device
+    pub fn read_event(&self) -> Option<InputEvent> {
+        interrupts::without_interrupts(|| self.pop(&mut INPUT.lock()))
+    }
+
+    /// Sleep until the grabbed device reports an event, or return `None`
+    /// once it was unregistered
+    ///
+    /// Must be called from a task with interrupts enabled.
+    pub fn wait_event(&self) -> Option<InputEvent> {
+        loop {
+            interrupts::disable();
+            let mut input = INPUT.lock();
+            if let Some(event) = self.pop(&mut input) {
+                drop(input);
+                interrupts::enable();
+                return Some(event);
+            }
+            if input.devices[self.device].gone {
+                drop(input);
+                interrupts::enable();
+                return None;
+            }
+            drop(input);
+            wait_for_event(event_key());
+        }
+    }
+
+    fn pop(&self, input: &mut Input) -> Option<InputEvent> {
+        input.devices[self.device].grab.as_mut()?.pop_front()
+    }
+}
+
+impl Drop for Grab {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| INPUT.lock().devices[self.device].grab = None);
+    }
+}
@@ -0,0 +1,143 @@
+//! Keyboard to terminal latency
+//!
+//! Measures how long a key press takes to show up on the terminal, through
+//! every layer in between: the keyboard interrupt and the deferred work
+//! decoding its bytes, the input devices and their queues, and the consumer
+//! echoing the character. The press is injected with the PS/2 controller's
+//! loopback, so it looks like any other key to the kernel, and the terminal
+//! notes when the character was written to its back buffer.
+//!
+//! Presses are spaced by a gap: a short one measures typing in bursts, with
+//! the path hot, a long one isolated presses after the machine idled.
+
+use alloc::vec::Vec;
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use super::devices;
+use crate::{
+    ps2::{self, keyboard::ScanCode},
+    time,
+};
+
+/// Key pressed for every sample, not one the kernel prints on its own
+pub const PROBE_KEY: ScanCode = ScanCode::Grave;
+const PROBE_CHAR: char = '`';
+
+/// Latency a press may take before the input path counts as too slow, in
+/// microseconds
+pub const BUDGET_US: u64 = 10_000;
+
+/// Time a press has to show up before the measurement fails
+const TIMEOUT_MS: u64 = 1000;
+
+/// Character the terminal watches for, 0 when not measuring
+static WATCHED: AtomicU32 = AtomicU32::new(0);
+
+/// TSC when the watched character was written
+static SEEN: AtomicU64 = AtomicU64::new(0);
+
+/// Why a measurement failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyError {
+    /// The controller didn't take the scancode
+    Inject(&'static str),
+    /// The character never reached the terminal
+    Timeout,
+}
+
+/// Latency of each sample, in microseconds
+pub struct Latencies {
+    pub samples: Vec<u64>,
+}
+
+impl Latencies {
+    pub fn min(&self) -> u64 {
+        self.samples.iter().copied().min().unwrap_or(0)
+    }
+
+    pub fn max(&self) -> u64 {
+        self.samples.iter().copied().max().unwrap_or(0)
+    }
+
+    pub fn average(&self) -> u64 {
+        self.samples.iter().sum::<u64>() / self.samples.len().max(1) as u64
+    }
+
+    /// Value at or below which `per_mille` of the samples are
+    pub fn percentile(&self, per_mille: usize) -> u64 {
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        let rank = (sorted.len() * per_mille).div_ceil(1000).max(1);
+        sorted.get(rank - 1).copied().unwrap_or(0)
+    }
+}
+
+/// Note that `text` was written to the terminal, called by the terminal
+/// after drawing it
+pub fn observe(text: &str) {
+    let watched = WATCHED.load(Ordering::Relaxed);
+    if watched != 0 && text.chars().any(|character| character as u32 == watched) {
+        SEEN.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+        WATCHED.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Press `PROBE_KEY` `samples` times, `gap_ms` apart, timing each press
+/// until its character is on the terminal
+///
+/// `echo` is called while waiting and has to consume the input and print
+/// the characters typed, as the consumer being measured would. Gaps shorter
+/// than `input.debounce_ms` are lengthened, or the presses would be dropped
+/// as chattering.
+pub fn measure(
+    samples: usize,
+    gap_ms: u64,
+    mut echo: impl FnMut(),
+) -> Result<Latencies, LatencyError> {
+    let gap_ms = gap_ms.max(devices::debounce_ms() + 1);
+    let release = PROBE_KEY as u8 | 0x80;
+
+    let (start_ms, start_tsc) = (time::uptime_ms(), unsafe { _rdtsc() });
+    let mut cycles = Vec::with_capacity(samples);
+    for _ in 0..samples {
+        SEEN.store(0, Ordering::Relaxed);
+        WATCHED.store(PROBE_CHAR as u32, Ordering::Relaxed);
+        let pressed = unsafe { _rdtsc() };
+        let injected = ps2::inject_scancode(PROBE_KEY as u8);
+        if let Err(e) = injected {
+            WATCHED.store(0, Ordering::Relaxed);
+            return Err(LatencyError::Inject(e));
+        }
+
+        let deadline = time::uptime_ms() + TIMEOUT_MS;
+        let seen = loop {
+            echo();
+            let seen = SEEN.load(Ordering::Relaxed);
+            if seen != 0 {
+                break seen;
+            }
+            if time::uptime_ms() >= deadline {
+                WATCHED.store(0, Ordering::Relaxed);
+                return Err(LatencyError::Timeout);
+            }
+            core::hint::spin_loop();
+        };
+        cycles.push(seen.saturating_sub(pressed));
+
+        ps2::inject_scancode(release).map_err(LatencyError::Inject)?;
+        let next = time::uptime_ms() + gap_ms;
+        while time::uptime_ms() < next {
+            echo();
+            core::hint::spin_loop();
+        }
+    }
+
+    let elapsed_ms = (time::uptime_ms() - start_ms).max(1);
+    let tsc_per_us = ((unsafe { _rdtsc() } - start_tsc) / elapsed_ms / 1000).max(1);
+    Ok(Latencies {
+        samples: cycles.iter().map(|cycles| cycles / tsc_per_us).collect(),
+    })
+}
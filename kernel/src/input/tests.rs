@@ -0,0 +1,130 @@
+//! Input layer tests
+
+use super::devices::{KeyChange, KeyFilter};
+use crate::ps2::keyboard::{KeyEvent, ScanCode};
+
+fn change(key: ScanCode, pressed: bool, repeat: bool) -> Option<KeyChange> {
+    Some(KeyChange {
+        key,
+        pressed,
+        repeat,
+    })
+}
+
+#[test_case]
+fn test_filter_normalizes() {
+    let mut filter = KeyFilter::default();
+    assert_eq!(filter.filter(KeyEvent::KeyUp(ScanCode::A), 10, 0), None);
+    assert_eq!(filter.filter(KeyEvent::Unknown(0x70), 10, 0), None);
+
+    assert_eq!(
+        filter.filter(KeyEvent::KeyDown(ScanCode::A), 10, 0),
+        change(ScanCode::A, true, false)
+    );
+    assert_eq!(
+        filter.filter(KeyEvent::KeyDown(ScanCode::A), 11, 0),
+        change(ScanCode::A, true, true)
+    );
+    assert_eq!(filter.pressed(), &[ScanCode::A]);
+    assert_eq!(
+        filter.filter(KeyEvent::KeyUp(ScanCode::A), 12, 0),
+        change(ScanCode::A, false, false)
+    );
+    assert!(filter.pressed().is_empty());
+    assert_eq!(filter.stats().dropped, 2);
+}
+
+#[test_case]
+fn test_filter_debounces() {
+    let mut filter = KeyFilter::default();
+    filter.filter(KeyEvent::KeyDown(ScanCode::B), 10, 2);
+    filter.filter(KeyEvent::KeyUp(ScanCode::B), 10, 2);
+
+    // the bounce and the release that goes with it are dropped
+    assert_eq!(filter.filter(KeyEvent::KeyDown(ScanCode::B), 11, 2), None);
+    assert_eq!(filter.filter(KeyEvent::KeyUp(ScanCode::B), 11, 2), None);
+    assert_eq!(filter.stats().debounced, 1);
+
+    // other keys and later presses are unaffected
+    assert_eq!(
+        filter.filter(KeyEvent::KeyDown(ScanCode::C), 11, 2),
+        change(ScanCode::C, true, false)
+    );
+    assert_eq!(
+        filter.filter(KeyEvent::KeyDown(ScanCode::B), 12, 2),
+        change(ScanCode::B, true, false)
+    );
+}
+
+#[test_case]
+fn test_filter_tracks_modifiers() {
+    let mut filter = KeyFilter::default();
+    filter.filter(KeyEvent::KeyDown(ScanCode::LeftShift), 10, 0);
+    assert!(filter.state().shift_pressed());
+
+    // repeats of a lock key don't toggle it again
+    filter.filter(KeyEvent::KeyDown(ScanCode::CapsLock), 10, 0);
+    filter.filter(KeyEvent::KeyDown(ScanCode::CapsLock), 11, 0);
+    assert!(filter.state().caps_lock);
+
+    filter.filter(KeyEvent::KeyUp(ScanCode::LeftShift), 12, 0);
+    assert!(!filter.state().shift_pressed());
+}
+
+#[test_case]
+fn test_interceptors_take_events_first() {
+    use super::devices::{self, InputEvent};
+
+    fn f12(event: &InputEvent) -> bool {
+        event.key == ScanCode::F12
+    }
+    fn any_key(_: &InputEvent) -> bool {
+        true
+    }
+
+    let device = devices::register("test keyboard");
+    let first = devices::subscribe("first");
+    let second = devices::subscribe("second");
+    let low = devices::intercept("low", 1, any_key);
+    let hotkey = devices::intercept("hotkey", 2, f12);
+    devices::report(device, KeyEvent::KeyDown(ScanCode::F12));
+    devices::report(device, KeyEvent::KeyUp(ScanCode::F12));
+
+    // both subscribers see the same events once the catch-all is gone
+    drop(low);
+    devices::report(device, KeyEvent::KeyDown(ScanCode::A));
+
+    assert_eq!(hotkey.read_event().map(|event| event.pressed), Some(true));
+    assert_eq!(hotkey.read_event().map(|event| event.pressed), Some(false));
+    assert_eq!(hotkey.read_event(), None);
+    for subscriber in [&first, &second] {
+        assert_eq!(
+            subscriber.read_event().map(|event| event.key),
+            Some(ScanCode::A)
+        );
+        assert_eq!(subscriber.read_event(), None);
+    }
+    devices::unregister(device);
+}
+
+#[cfg(feature = "graphics")]
+#[test_case]
+fn test_keyboard_to_terminal_latency() {
+    use crate::input::latency;
+
+    let keys = super::devices::subscribe("latency test");
+    let echo = || {
+        if let Some(character) = keys.read_event().and_then(|event| event.to_char()) {
+            crate::print!("{}", character);
+        }
+    };
+    let latencies = latency::measure(20, 20, echo).expect("key presses didn't reach the terminal");
+    crate::println!();
+    assert_eq!(latencies.samples.len(), 20);
+    assert!(
+        latencies.percentile(990) <= latency::BUDGET_US,
+        "p99 key press latency {} us is over {} us",
+        latencies.percentile(990),
+        latency::BUDGET_US
+    );
+}
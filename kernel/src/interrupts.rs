@@ -1,6 +1,9 @@
 pub mod apic;
+pub mod fault;
 pub mod idt;
 pub mod pic;
+pub mod vectors;
+pub mod watchdog;
 
 pub use apic::setup_apic;
 pub use idt::init_idt;
@@ -1,14 +1,25 @@
-use crate::{error, info, pci::nvme::{NVME_ADMIN_VECTOR, NVME_IO_VECTOR}, tasks::scheduler::schedule, warn};
+use crate::{
+    error, info,
+    tasks::scheduler::{quantum_ms, schedule},
+    time::{PIT_HZ, pit_reload, timer_hz},
+    warn,
+};
+use crate::pci::aer::AER_VECTOR;
+#[cfg(feature = "usb")]
+use crate::pci::usb::xhci::XHCI_VECTOR;
 use acpi::{
     AcpiHandler, AcpiTables, InterruptModel,
     handler::PhysicalMapping,
     madt::{InterruptSourceOverrideEntry, Madt, MadtEntry},
 };
 use alloc::vec::Vec;
-use core::ptr::NonNull;
+use core::{
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
 use x2apic::{
-    ioapic::{IrqFlags, IrqMode, RedirectionTableEntry},
-    lapic::{LocalApicBuilder, xapic_base},
+    ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry},
+    lapic::{LocalApic, LocalApicBuilder, TimerMode, xapic_base},
 };
 use x86_64::{
     PhysAddr, VirtAddr,
@@ -22,14 +33,21 @@ use x86_64::{
 
 use crate::{
     debug,
-    memory::{FRAME_ALLOCATOR, PAGE_TABLE},
+    memory::{FRAME_ALLOCATOR, PAGE_TABLE, kaslr, reserved},
 };
 
-use super::{idt::IDT, pic::disable_legacy_pics};
+use super::{idt::IDT, pic::disable_legacy_pics, vectors};
 
 const PAGE_SIZE: usize = 0x1000;
-const X2APIC_EOI_MSR: u32 = 0x80B;
+pub(super) const X2APIC_EOI_MSR: u32 = 0x80B;
 
+/// LVT performance counter entry, as an x2APIC MSR and an xAPIC register offset
+const X2APIC_LVT_PERF_MSR: u32 = 0x834;
+const XAPIC_LVT_PERF_OFFSET: u64 = 0x340;
+/// NMI delivery mode in an LVT entry, which ignores the vector
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+// region bases, moved by the KASLR offset
 const IOAPICS_VIRTUAL_START: u64 = 0xFFFF_F000_0000_0000;
 const XAPIC_VIRTUAL_START: u64 = 0xFFFF_F100_0000_0000;
 const ACPI_MAPPINGS_START: u64 = 0xFFFF_F200_0000_0000;
@@ -38,14 +56,32 @@ const LAPIC_ERROR_VECTOR: u8 = 0x31;
 const LAPIC_SPURIOUS_VECTOR: u8 = 0xFF;
 const IOAPIC_TIMER_VECTOR: u8 = 0x20;
 const IOAPIC_TIMER_INPUT: u8 = 0;
-const KEYBOARD_VECTOR: u8 = 0x21;
+pub const KEYBOARD_VECTOR: u8 = 0x21;
 const KEYBOARD_IRQ: u8 = 1;
-const TIMER_RELOAD: u16 = (1193182u32 / 20) as u16;
+
+/// Time the LAPIC timer is measured against the PIT for
+const CALIBRATION_MS: u64 = 10;
+
+/// LAPIC timer count used before the timer is calibrated
+const UNCALIBRATED_TIMER_INITIAL: u32 = 10_000_000;
+
+/// LAPIC timer counts per millisecond, 0 until calibrated
+static LAPIC_COUNTS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Number of IO APICs mapped from `IOAPICS_VIRTUAL_START`
+static IOAPIC_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 /// Interrupt handler for the PIT.
 ///
 /// Acknowledges the interrupt by writing to the EOI MSR.
 extern "x86-interrupt" fn ioapic_timer_handler(_stack_frame: InterruptStackFrame) {
+    crate::sync::irq_enter();
+    crate::time::tick();
+    crate::ps2::keyboard::timer_tick();
+    crate::memory::reclaim::timer_tick();
+    crate::memory::oom::timer_tick();
+    crate::sync::irq_exit();
+
     unsafe {
         Msr::new(X2APIC_EOI_MSR).write(0);
     };
@@ -68,23 +104,30 @@ extern "x86-interrupt" fn lapic_error_handler(_stack_frame: InterruptStackFrame)
 }
 
 extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame) {
+    crate::sync::irq_enter();
     crate::ps2::keyboard::handle_interrupt();
+    crate::sync::irq_exit();
 
     unsafe {
         Msr::new(X2APIC_EOI_MSR).write(0);
     };
 }
 
-extern "x86-interrupt" fn nvme_admin_handler(_stack_frame: InterruptStackFrame) {
-    crate::pci::nvme::handle_admin_interrupt();
+extern "x86-interrupt" fn aer_handler(_stack_frame: InterruptStackFrame) {
+    crate::sync::irq_enter();
+    crate::pci::aer::handle_interrupt();
+    crate::sync::irq_exit();
 
     unsafe {
         Msr::new(X2APIC_EOI_MSR).write(0);
     };
 }
 
-extern "x86-interrupt" fn nvme_io_handler(_stack_frame: InterruptStackFrame) {
-    crate::pci::nvme::handle_io_interrupt();
+#[cfg(feature = "usb")]
+extern "x86-interrupt" fn xhci_handler(_stack_frame: InterruptStackFrame) {
+    crate::sync::irq_enter();
+    crate::pci::usb::xhci::handle_interrupt();
+    crate::sync::irq_exit();
 
     unsafe {
         Msr::new(X2APIC_EOI_MSR).write(0);
@@ -99,20 +142,14 @@ extern "x86-interrupt" fn nvme_io_handler(_stack_frame: InterruptStackFrame) {
 pub unsafe fn setup_apic(rsdp_addr: usize) {
     disable_legacy_pics();
 
-    let mut builder = LocalApicBuilder::new();
-    let mut lapic = builder
-        .timer_vector(LAPIC_TIMER_VECTOR as usize)
-        .error_vector(LAPIC_ERROR_VECTOR as usize)
-        .spurious_vector(LAPIC_SPURIOUS_VECTOR as usize);
-
-    match detect_lapic_support() {
+    let support = detect_lapic_support();
+    match support {
         ApicSupport::XApic => {
             let lapic_base = unsafe { xapic_base() };
             map_lapic_registers(
                 PhysAddr::new(lapic_base),
-                VirtAddr::new(XAPIC_VIRTUAL_START),
+                VirtAddr::new(kaslr::region(XAPIC_VIRTUAL_START)),
             );
-            lapic = lapic.set_xapic_base(XAPIC_VIRTUAL_START);
             error!(
                 "no x2apic support detected, using xAPIC. this will cause issues with the global timer"
             );
@@ -123,19 +160,29 @@ pub unsafe fn setup_apic(rsdp_addr: usize) {
         ApicSupport::X2Apic => (),
     }
 
-    let mut final_lapic = lapic.build().unwrap();
-
     unsafe {
         (&mut (*IDT.as_mut_ptr()))[LAPIC_TIMER_VECTOR]
             .set_handler_addr(VirtAddr::new(schedule as usize as u64));
         (&mut (*IDT.as_mut_ptr()))[LAPIC_ERROR_VECTOR].set_handler_fn(lapic_error_handler);
         (&mut (*IDT.as_mut_ptr()))[LAPIC_SPURIOUS_VECTOR].set_handler_fn(spurious_handler);
         (&mut (*IDT.as_mut_ptr()))[KEYBOARD_VECTOR].set_handler_fn(keyboard_handler);
-        (&mut (*IDT.as_mut_ptr()))[NVME_ADMIN_VECTOR].set_handler_fn(nvme_admin_handler);
-        (&mut (*IDT.as_mut_ptr()))[NVME_IO_VECTOR].set_handler_fn(nvme_io_handler);
+        (&mut (*IDT.as_mut_ptr()))[AER_VECTOR].set_handler_fn(aer_handler);
+        vectors::install();
+    }
+    vectors::reserve(LAPIC_TIMER_VECTOR, "LAPIC timer");
+    vectors::reserve(LAPIC_ERROR_VECTOR, "LAPIC error");
+    vectors::reserve(LAPIC_SPURIOUS_VECTOR, "spurious");
+    vectors::reserve(KEYBOARD_VECTOR, "keyboard");
+    vectors::reserve(AER_VECTOR, "AER");
+
+    #[cfg(feature = "usb")]
+    {
+        unsafe { (&mut (*IDT.as_mut_ptr()))[XHCI_VECTOR].set_handler_fn(xhci_handler) };
+        vectors::reserve(XHCI_VECTOR, "xHCI");
     }
 
-    unsafe { final_lapic.enable() };
+    let mut final_lapic = unsafe { enable_lapic(support) };
+    unsafe { calibrate_lapic_timer(&mut final_lapic) };
 
     // IO apic
     let mut tables = unsafe { AcpiTables::from_rsdp(KernelAcpiHandler, rsdp_addr).unwrap() };
@@ -144,12 +191,13 @@ pub unsafe fn setup_apic(rsdp_addr: usize) {
         panic!("No IO APIC found");
     }
 
-    for (virtaddr, &(ioapic_mmio, _)) in (IOAPICS_VIRTUAL_START..)
+    for (virtaddr, &(ioapic_mmio, _)) in (kaslr::region(IOAPICS_VIRTUAL_START)..)
         .step_by(PAGE_SIZE)
         .zip(ioapic_addrs.iter())
     {
         let virtaddr = VirtAddr::new(virtaddr);
         let ioapic_mmio = PhysAddr::new(ioapic_mmio as u64);
+        reserved::check(ioapic_mmio, PAGE_SIZE as u64, "I/O APIC");
 
         // Map the IO APIC MMIO region to the virtual address space
         unsafe { map_ioapic(ioapic_mmio, virtaddr) };
@@ -157,11 +205,9 @@ pub unsafe fn setup_apic(rsdp_addr: usize) {
 
     let mut ioapics = Vec::with_capacity(ioapic_addrs.len());
     for (i, &(_, gsi_base)) in ioapic_addrs.iter().enumerate() {
-        ioapics.push((
-            unsafe { x2apic::ioapic::IoApic::new(IOAPICS_VIRTUAL_START + (i * PAGE_SIZE) as u64) },
-            gsi_base,
-        ));
+        ioapics.push((unsafe { mapped_ioapic(i) }, gsi_base));
     }
+    IOAPIC_COUNT.store(ioapics.len(), Ordering::Relaxed);
 
     let mut interrupt_source_overrides = get_interrupt_source_overrides(&mut tables);
     let timer_override = interrupt_source_overrides
@@ -177,7 +223,7 @@ pub unsafe fn setup_apic(rsdp_addr: usize) {
     };
 
     unsafe {
-        setup_pit_timer(TIMER_RELOAD);
+        setup_pit_timer(pit_reload(timer_hz()));
     }
     setup_ioapic_timer(&mut ioapics, timer_gsi, unsafe { final_lapic.id() } as u8);
 
@@ -197,6 +243,166 @@ pub unsafe fn setup_apic(rsdp_addr: usize) {
     info!("apic initialized with {} IO APICs", ioapic_addrs.len());
 }
 
+/// Build the local APIC for the detected support, without touching it
+///
+/// The timer fires every scheduler quantum, see `sched.quantum_ms`.
+fn build_lapic(support: ApicSupport) -> LocalApic {
+    let mut builder = LocalApicBuilder::new();
+    let lapic = builder
+        .timer_vector(LAPIC_TIMER_VECTOR as usize)
+        .timer_mode(TimerMode::Periodic)
+        .timer_initial(lapic_timer_initial())
+        .error_vector(LAPIC_ERROR_VECTOR as usize)
+        .spurious_vector(LAPIC_SPURIOUS_VECTOR as usize);
+    if support == ApicSupport::XApic {
+        lapic.set_xapic_base(kaslr::region(XAPIC_VIRTUAL_START));
+    }
+    lapic.build().unwrap()
+}
+
+/// Build the local APIC for the detected support and enable it
+///
+/// # Safety
+/// The xAPIC registers must already be mapped at `XAPIC_VIRTUAL_START`.
+unsafe fn enable_lapic(support: ApicSupport) -> LocalApic {
+    let mut lapic = build_lapic(support);
+    unsafe { lapic.enable() };
+    lapic
+}
+
+/// LAPIC timer count lasting one scheduler quantum
+fn lapic_timer_initial() -> u32 {
+    match LAPIC_COUNTS_PER_MS.load(Ordering::Relaxed) {
+        0 => UNCALIBRATED_TIMER_INITIAL,
+        counts => (counts as u64 * quantum_ms()).min(u32::MAX as u64) as u32,
+    }
+}
+
+/// Measure how fast the LAPIC timer counts against PIT channel 2, then
+/// restart it with a period of one quantum
+///
+/// # Safety
+/// Must be called with interrupts disabled, on an enabled local APIC.
+unsafe fn calibrate_lapic_timer(lapic: &mut LocalApic) {
+    let mut control = Port::<u8>::new(0x61);
+    let mut mode = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+    let count = (PIT_HZ * CALIBRATION_MS / 1000) as u16;
+
+    unsafe {
+        // gate low holds the count, the speaker stays off
+        let gate_off = control.read() & !0x03;
+        control.write(gate_off);
+        mode.write(0b10110000); // channel 2, mode 0 (interrupt on terminal count), binary
+        channel2.write((count & 0xFF) as u8);
+        channel2.write((count >> 8) as u8);
+
+        lapic.set_timer_mode(TimerMode::OneShot);
+        lapic.set_timer_initial(u32::MAX);
+        control.write(gate_off | 0x01);
+        // channel 2's output shows up in bit 5; without a PIT the LAPIC
+        // timer runs out and the quantum stays uncalibrated
+        while control.read() & 0x20 == 0 && lapic.timer_current() != 0 {
+            core::hint::spin_loop();
+        }
+        let remaining = lapic.timer_current();
+        control.write(gate_off);
+
+        if remaining != 0 {
+            let counts = (u32::MAX - remaining) / CALIBRATION_MS as u32;
+            LAPIC_COUNTS_PER_MS.store(counts, Ordering::Relaxed);
+        }
+        lapic.set_timer_mode(TimerMode::Periodic);
+        lapic.set_timer_initial(lapic_timer_initial());
+    }
+    info!(
+        "LAPIC timer: {} counts/ms, quantum {} ms",
+        LAPIC_COUNTS_PER_MS.load(Ordering::Relaxed),
+        quantum_ms()
+    );
+}
+
+/// Restart the LAPIC timer with a period of the current quantum
+///
+/// Must be called with interrupts disabled.
+pub fn reload_lapic_timer() {
+    let mut lapic = build_lapic(detect_lapic_support());
+    unsafe { lapic.set_timer_initial(lapic_timer_initial()) };
+}
+
+/// Deliver performance counter overflows as NMIs, see `watchdog`
+///
+/// The CPU masks the entry on every delivery, so this is called again to
+/// unmask it after each overflow.
+pub fn set_perf_counter_nmi() {
+    match detect_lapic_support() {
+        ApicSupport::X2Apic => unsafe {
+            Msr::new(X2APIC_LVT_PERF_MSR).write(LVT_DELIVERY_NMI as u64);
+        },
+        ApicSupport::XApic => unsafe {
+            let register = kaslr::region(XAPIC_VIRTUAL_START) + XAPIC_LVT_PERF_OFFSET;
+            core::ptr::write_volatile(register as *mut u32, LVT_DELIVERY_NMI);
+        },
+        ApicSupport::None => {}
+    }
+}
+
+/// Reprogram the PIT with a new reload value, see `time::set_timer_hz`
+///
+/// Must be called with interrupts disabled.
+pub fn set_pit_reload(reload: u16) {
+    unsafe { setup_pit_timer(reload) };
+}
+
+/// The IO APIC mapped at index `index` by `setup_apic`
+///
+/// # Safety
+/// `index` must be below `IOAPIC_COUNT`, or about to be counted in it.
+unsafe fn mapped_ioapic(index: usize) -> IoApic {
+    let base = kaslr::region(IOAPICS_VIRTUAL_START);
+    unsafe { IoApic::new(base + (index * PAGE_SIZE) as u64) }
+}
+
+/// Interrupt controller state lost in a sleep state
+pub struct ApicState {
+    /// Redirection table of every IO APIC
+    redirections: Vec<Vec<RedirectionTableEntry>>,
+}
+
+/// Save the IO APIC redirection tables before the platform powers them off
+pub fn save_state() -> ApicState {
+    let redirections = (0..IOAPIC_COUNT.load(Ordering::Relaxed))
+        .map(|index| {
+            let mut ioapic = unsafe { mapped_ioapic(index) };
+            let entries = unsafe { ioapic.max_table_entry() };
+            (0..=entries)
+                .map(|irq| unsafe { ioapic.table_entry(irq) })
+                .collect()
+        })
+        .collect();
+    ApicState { redirections }
+}
+
+/// Bring the interrupt controllers and the PIT back after a sleep state
+///
+/// # Safety
+/// Must be called with interrupts disabled, after the IDT has been reloaded.
+pub unsafe fn restore_state(state: ApicState) {
+    disable_legacy_pics();
+    unsafe {
+        enable_lapic(detect_lapic_support());
+        setup_pit_timer(pit_reload(timer_hz()));
+    }
+
+    for (index, entries) in state.redirections.into_iter().enumerate() {
+        let mut ioapic = unsafe { mapped_ioapic(index) };
+        for (irq, entry) in entries.into_iter().enumerate() {
+            unsafe { ioapic.set_table_entry(irq as u8, entry) };
+        }
+    }
+    debug!("apic state restored");
+}
+
 #[allow(static_mut_refs)]
 /// Configures the IOAPIC timer and sets up the LAPIC timer interrupt handler.
 ///
@@ -220,6 +426,7 @@ fn setup_ioapic_timer(ioapics: &mut [(x2apic::ioapic::IoApic, u32)], timer_gsi:
     }
 
     unsafe { (&mut (*IDT.as_mut_ptr()))[IOAPIC_TIMER_VECTOR].set_handler_fn(ioapic_timer_handler) };
+    vectors::reserve(IOAPIC_TIMER_VECTOR, "PIT");
 
     for (ioapic, gsi_base) in ioapics.iter_mut() {
         if !(*gsi_base..*gsi_base + unsafe { ioapic.max_table_entry() } as u32 + 1)
@@ -335,8 +542,8 @@ impl AcpiHandler for KernelAcpiHandler {
         physical_address: usize,
         size: usize,
     ) -> PhysicalMapping<Self, T> {
-        // Use static mut for next available virtual address (single-threaded assumption).
-        static mut NEXT_ACPI_VIRT: u64 = ACPI_MAPPINGS_START;
+        // Use static mut for next available offset into the region (single-threaded assumption).
+        static mut NEXT_ACPI_OFFSET: u64 = 0;
 
         let phys_addr = physical_address as u64;
         let offset = (phys_addr & (PAGE_SIZE as u64 - 1)) as usize;
@@ -345,8 +552,8 @@ impl AcpiHandler for KernelAcpiHandler {
 
         // Allocate a contiguous virtual region for the mapping.
         let virt_base = {
-            let addr = unsafe { NEXT_ACPI_VIRT };
-            unsafe { NEXT_ACPI_VIRT += (num_pages * PAGE_SIZE) as u64 };
+            let addr = kaslr::region(ACPI_MAPPINGS_START) + unsafe { NEXT_ACPI_OFFSET };
+            unsafe { NEXT_ACPI_OFFSET += (num_pages * PAGE_SIZE) as u64 };
             addr
         };
 
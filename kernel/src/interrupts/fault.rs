@@ -0,0 +1,72 @@
+//! Diagnostics for faults
+//!
+//! Before panicking on a fault, the handlers report which task was running,
+//! the bounds of its stack and where the stack pointer was relative to them,
+//! so a stack overflow (RSP or the faulting address in the guard page) can be
+//! told apart from a stray pointer at a glance. Kernel instruction pointers
+//! are also given at their link address, for `addr2line`.
+
+use x86_64::{VirtAddr, structures::idt::InterruptStackFrame};
+
+use crate::{error, tasks::scheduler::current_task_info};
+
+/// Log the running task and where its stack pointer was when the fault hit
+///
+/// `fault_addr` is the faulting address for page faults.
+#[allow(unused_variables)]
+pub fn report_task(stack_frame: &InterruptStackFrame, fault_addr: Option<VirtAddr>) {
+    let Some(task) = current_task_info() else {
+        error!("fault in unknown task (scheduler busy or not started)");
+        return;
+    };
+
+    let rsp = stack_frame.stack_pointer;
+    let user_mode = stack_frame.code_segment.rpl() == x86_64::PrivilegeLevel::Ring3;
+    error!(
+        "fault in task {} {:?} ({} mode), rsp = {:#x}",
+        task.pid,
+        task.name,
+        if user_mode { "user" } else { "kernel" },
+        rsp
+    );
+
+    if user_mode {
+        if let Some((bottom, top)) = task.user_stack {
+            error!("  user stack: {:#x} - {:#x}", bottom, top);
+            let verdict = if (bottom..top).contains(&rsp) {
+                "rsp is within the user stack"
+            } else {
+                "rsp is outside the user stack: stray stack pointer"
+            };
+            error!("  {}", verdict);
+        }
+        return;
+    }
+
+    let rip = stack_frame.instruction_pointer.as_u64();
+    error!(
+        "  rip = {:#x} (link address {:#x})",
+        rip,
+        crate::memory::kaslr::link_address(rip)
+    );
+
+    let Some(stack) = task.kernel_stack else {
+        error!("  running on the boot stack, bounds unknown");
+        return;
+    };
+
+    error!(
+        "  kernel stack: {:#x} - {:#x}, guard page at {:#x}",
+        stack.bottom, stack.top, stack.guard_page
+    );
+    let overflow =
+        stack.in_guard_page(rsp) || fault_addr.is_some_and(|addr| stack.in_guard_page(addr));
+    let verdict = if overflow {
+        "stack pointer ran into the guard page: kernel stack overflow"
+    } else if stack.contains(rsp) {
+        "rsp is within the task's stack"
+    } else {
+        "rsp is outside the task's stack: stray stack pointer"
+    };
+    error!("  {}", verdict);
+}
@@ -1,6 +1,7 @@
-use crate::{info, tasks::scheduler::try_grow_user_stack};
+use crate::{error, info, memory::uaccess::{self, USER_LIMIT}, stats::{self, Counter}, tasks::scheduler::try_grow_user_stack, warn};
+use core::arch::x86_64::__cpuid;
 use spin::Lazy;
-use x86_64::{registers::control::Cr2, structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode}};
+use x86_64::{registers::control::{Cr2, Cr4, Cr4Flags}, structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode}};
 
 use crate::{println, serial_println};
 
@@ -9,6 +10,12 @@ use crate::{println, serial_println};
 /// - Breakpoint
 /// - Page Fault
 /// - Double Fault
+/// - NMI
+/// - Machine Check
+///
+/// Double faults, NMIs and machine checks run on their own stacks from the
+/// interrupt stack table, so they are reported even when the task's stack
+/// overflowed or is corrupted.
 pub static mut IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
     idt.breakpoint.set_handler_fn(breakpoint_handler);
@@ -19,6 +26,12 @@ pub static mut IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
         idt.double_fault
             .set_handler_fn(double_fault_handler)
             .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.non_maskable_interrupt
+            .set_handler_fn(nmi_handler)
+            .set_stack_index(crate::gdt::NMI_IST_INDEX);
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(crate::gdt::MACHINE_CHECK_IST_INDEX);
     }
     info!("idt initialized");
     idt
@@ -27,25 +40,58 @@ pub static mut IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
 /// Initialize the Interrupt Descriptor Table.
 pub fn init_idt() {
     unsafe { (*IDT).load() };
+
+    // machine checks shut the machine down unless they are enabled
+    if unsafe { __cpuid(1) }.edx & CPUID_MCE != 0 {
+        unsafe { Cr4::update(|cr4| cr4.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+    }
     info!("idt loaded");
 }
 
+/// CPUID leaf 1 EDX bit for machine check exceptions
+const CPUID_MCE: u32 = 1 << 7;
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     let fault_addr = Cr2::read().expect("Failed to read CR2");
+    stats::count(Counter::PageFaults);
 
-    if error_code.contains(PageFaultErrorCode::USER_MODE)
+    // copies in and out of user memory may run into the stack's next page too
+    let in_user_window = uaccess::in_user_window();
+    if (error_code.contains(PageFaultErrorCode::USER_MODE) || in_user_window)
         && unsafe { try_grow_user_stack(fault_addr).is_ok() } {
             return;
         }
 
+    // any other page a copy runs into fails the copy
+    if in_user_window
+        && fault_addr.as_u64() < USER_LIMIT
+        && let Some(fixup) = uaccess::fixup(stack_frame.instruction_pointer)
+    {
+        unsafe {
+            stack_frame
+                .as_mut()
+                .update(|frame| frame.instruction_pointer = fixup);
+        }
+        return;
+    }
+
+    if !error_code.contains(PageFaultErrorCode::USER_MODE)
+        && fault_addr.as_u64() < USER_LIMIT
+        && error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && !in_user_window
+    {
+        error!("kernel touched user memory outside copy_from_user/copy_to_user (SMEP/SMAP)");
+    }
+
+    super::fault::report_task(&stack_frame, Some(fault_addr));
     panic!(
         "EXCEPTION: PAGE FAULT at {:#x}\n{:#?}\nWith error: {:#?}",
         fault_addr, stack_frame, error_code,
@@ -56,6 +102,7 @@ extern "x86-interrupt" fn general_proction_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    super::fault::report_task(&stack_frame, None);
     panic!(
         "EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}\nWith error: {:#?}",
         stack_frame, error_code
@@ -66,5 +113,21 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    super::fault::report_task(&stack_frame, None);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
+
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    if super::watchdog::handle_nmi(&stack_frame) {
+        return;
+    }
+
+    #[allow(unused_variables)]
+    let rip = stack_frame.instruction_pointer;
+    warn!("NMI received at {:#x}", rip);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    super::fault::report_task(&stack_frame, None);
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
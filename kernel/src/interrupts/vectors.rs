@@ -0,0 +1,162 @@
+//! Interrupt vector allocation
+//!
+//! Most devices have a fixed vector with its own handler in `apic`, which is
+//! recorded here with `reserve`. Drivers that need a number of vectors only
+//! known at probe time, one per queue say, take them from the dynamic range
+//! with `allocate`: every vector there has a trampoline that counts the
+//! interrupt and calls the handler registered for it, with the data given at
+//! allocation, so the handler knows which of its vectors fired.
+//!
+//! `usage` lists every vector in use, for `lsirq`.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+use x86_64::{registers::model_specific::Msr, structures::idt::InterruptStackFrame};
+
+use super::{apic::X2APIC_EOI_MSR, idt::IDT};
+use crate::sync::Mutex;
+
+/// First vector handed out by `allocate`
+pub const DYNAMIC_START: u8 = 0x60;
+
+/// Number of vectors handed out by `allocate`
+pub const DYNAMIC_COUNT: usize = 32;
+
+/// Handler of a dynamic vector, called in the interrupt with the data given
+/// to `allocate`
+pub type Handler = fn(usize);
+
+/// A vector of the dynamic range
+struct Slot {
+    /// `Handler` as a pointer, 0 if the vector is free
+    handler: AtomicUsize,
+    data: AtomicUsize,
+    interrupts: AtomicU64,
+}
+
+static SLOTS: [Slot; DYNAMIC_COUNT] = [const {
+    Slot {
+        handler: AtomicUsize::new(0),
+        data: AtomicUsize::new(0),
+        interrupts: AtomicU64::new(0),
+    }
+}; DYNAMIC_COUNT];
+
+/// Owner of every vector in use
+static OWNERS: Mutex<[Option<&'static str>; 256]> = Mutex::new("IRQ_VECTORS", [None; 256]);
+
+/// A vector in use
+#[derive(Debug, Clone, Copy)]
+pub struct VectorInfo {
+    pub vector: u8,
+    pub owner: &'static str,
+    /// Interrupts taken, only counted for dynamic vectors
+    pub interrupts: Option<u64>,
+}
+
+extern "x86-interrupt" fn dynamic_handler<const SLOT: usize>(_stack_frame: InterruptStackFrame) {
+    crate::sync::irq_enter();
+    let slot = &SLOTS[SLOT];
+    slot.interrupts.fetch_add(1, Ordering::Relaxed);
+    let handler = slot.handler.load(Ordering::Acquire);
+    if handler != 0 {
+        let handler: Handler = unsafe { core::mem::transmute(handler) };
+        handler(slot.data.load(Ordering::Relaxed));
+    }
+    crate::sync::irq_exit();
+
+    unsafe {
+        Msr::new(X2APIC_EOI_MSR).write(0);
+    };
+}
+
+macro_rules! trampolines {
+    ($($slot:literal)*) => {
+        [$(dynamic_handler::<$slot> as extern "x86-interrupt" fn(InterruptStackFrame)),*]
+    };
+}
+
+static TRAMPOLINES: [extern "x86-interrupt" fn(InterruptStackFrame); DYNAMIC_COUNT] = trampolines!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+    16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+);
+
+/// Point the dynamic range of the IDT at the trampolines
+///
+/// # Safety
+/// Must be called while setting up the IDT, before any vector is allocated
+#[allow(static_mut_refs)]
+pub unsafe fn install() {
+    for (slot, trampoline) in TRAMPOLINES.iter().enumerate() {
+        unsafe {
+            (&mut (*IDT.as_mut_ptr()))[DYNAMIC_START + slot as u8].set_handler_fn(*trampoline);
+        }
+    }
+}
+
+/// Record that `vector` has a fixed handler, owned by `owner`
+pub fn reserve(vector: u8, owner: &'static str) {
+    OWNERS.lock()[vector as usize] = Some(owner);
+}
+
+/// Take a free vector of the dynamic range and have it call `handler` with
+/// `data`
+///
+/// Returns `None` if the range is used up.
+pub fn allocate(owner: &'static str, handler: Handler, data: usize) -> Option<u8> {
+    let mut owners = OWNERS.lock();
+    let slot = (0..DYNAMIC_COUNT).find(|&slot| owners[DYNAMIC_START as usize + slot].is_none())?;
+    owners[DYNAMIC_START as usize + slot] = Some(owner);
+
+    let slot_state = &SLOTS[slot];
+    slot_state.data.store(data, Ordering::Relaxed);
+    slot_state.interrupts.store(0, Ordering::Relaxed);
+    slot_state
+        .handler
+        .store(handler as usize, Ordering::Release);
+    Some(DYNAMIC_START + slot as u8)
+}
+
+/// Give back a vector taken with `allocate`
+///
+/// The device must no longer send it; a late interrupt is counted and
+/// otherwise ignored.
+pub fn release(vector: u8) {
+    let Some(slot) = (vector as usize)
+        .checked_sub(DYNAMIC_START as usize)
+        .filter(|&slot| slot < DYNAMIC_COUNT)
+    else {
+        return;
+    };
+    SLOTS[slot].handler.store(0, Ordering::Release);
+    OWNERS.lock()[vector as usize] = None;
+}
+
+/// Number of vectors `allocate` can still hand out
+pub fn available() -> usize {
+    let owners = OWNERS.lock();
+    owners[DYNAMIC_START as usize..][..DYNAMIC_COUNT]
+        .iter()
+        .filter(|owner| owner.is_none())
+        .count()
+}
+
+/// Every vector in use, in order
+pub fn usage() -> Vec<VectorInfo> {
+    let owners = *OWNERS.lock();
+    owners
+        .iter()
+        .enumerate()
+        .filter_map(|(vector, owner)| {
+            let slot = vector
+                .checked_sub(DYNAMIC_START as usize)
+                .filter(|&slot| slot < DYNAMIC_COUNT);
+            Some(VectorInfo {
+                vector: vector as u8,
+                owner: (*owner)?,
+                interrupts: slot.map(|slot| SLOTS[slot].interrupts.load(Ordering::Relaxed)),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,254 @@
+//! NMI watchdog, detecting hard lockups
+//!
+//! A CPU stuck with interrupts disabled stops taking timer interrupts, so
+//! nothing driven by them can notice. The watchdog has performance counter 0
+//! count unhalted core cycles and raise an NMI when it overflows, about once a
+//! second of busy CPU time, and NMIs get through with interrupts disabled.
+//! Each NMI checks whether the tick counter moved since the last one; once it
+//! hasn't for `watchdog.thresh_s` seconds, the interrupted RIP and the running
+//! task are reported, and the kernel panics if `watchdog.panic` is set.
+//!
+//! A halted CPU doesn't count cycles, so an idle system isn't taken for a
+//! stuck one. The counter needs Intel's architectural performance monitoring
+//! (CPUID leaf 0xA), which QEMU only offers with KVM; without it, or with
+//! `nmi_watchdog=0` on the command line, the watchdog stays off.
+
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
+
+use x86_64::{registers::model_specific::Msr, structures::idt::InterruptStackFrame};
+
+use crate::{bootargs, error, info, interrupts::apic, sysctl::Sysctl, time};
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38E;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// `IA32_PERFEVTSEL0` bits
+mod evtsel {
+    /// The unhalted core cycles event, unit mask 0
+    pub const UNHALTED_CORE_CYCLES: u64 = 0x3C;
+    pub const USR: u64 = 1 << 16;
+    pub const OS: u64 = 1 << 17;
+    /// Interrupt through the LAPIC on overflow
+    pub const INT: u64 = 1 << 20;
+    pub const EN: u64 = 1 << 22;
+}
+
+/// Longest period the counter can be loaded with; writes to `IA32_PMC0` are
+/// sign-extended from bit 31
+const MAX_PERIOD: u64 = 0x7FFF_FFFF;
+
+/// Time the TSC is measured against the timer for
+const CALIBRATION_MS: u64 = 100;
+
+const DEFAULT_THRESH_S: u64 = 10;
+const MAX_THRESH_S: u64 = 600;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Architectural performance monitoring version, from CPUID leaf 0xA
+static PMU_VERSION: AtomicU32 = AtomicU32::new(0);
+
+/// Width of the counter in bits
+static COUNTER_WIDTH: AtomicU32 = AtomicU32::new(0);
+
+/// Cycles between NMIs
+static PERIOD: AtomicU64 = AtomicU64::new(MAX_PERIOD);
+
+/// TSC counts per second, measured at boot
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Tick counter seen by the last NMI, and the TSC when it last moved
+static LAST_TICKS: AtomicU64 = AtomicU64::new(0);
+static LAST_PROGRESS: AtomicU64 = AtomicU64::new(0);
+
+/// Set once the current lockup was reported, so it is reported only once
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+static THRESH_S: AtomicU64 = AtomicU64::new(DEFAULT_THRESH_S);
+static PANIC: AtomicBool = AtomicBool::new(false);
+
+pub static SYSCTLS: &[Sysctl] = &[
+    Sysctl {
+        name: "watchdog.thresh_s",
+        help: "seconds without a timer interrupt before a hard lockup is reported",
+        get: || THRESH_S.load(Ordering::Relaxed),
+        set: |value| {
+            if !(1..=MAX_THRESH_S).contains(&value) {
+                return Err("must be 1-600 s");
+            }
+            THRESH_S.store(value, Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Sysctl {
+        name: "watchdog.panic",
+        help: "1 to panic on a hard lockup instead of only reporting it",
+        get: || PANIC.load(Ordering::Relaxed) as u64,
+        set: |value| {
+            if value > 1 {
+                return Err("must be 0 or 1");
+            }
+            PANIC.store(value == 1, Ordering::Relaxed);
+            Ok(())
+        },
+    },
+];
+
+/// Start the watchdog if the CPU can run it
+///
+/// Must be called with interrupts enabled and the timer running, the TSC
+/// being measured against it.
+pub fn init() {
+    if bootargs::get("nmi_watchdog") == Some("0") {
+        info!("NMI watchdog disabled on the command line");
+        return;
+    }
+
+    if unsafe { __cpuid(0) }.eax < 0xA {
+        info!("NMI watchdog: no performance monitoring");
+        return;
+    }
+    let leaf = unsafe { __cpuid(0xA) };
+    let version = leaf.eax & 0xFF;
+    let counters = (leaf.eax >> 8) & 0xFF;
+    let width = (leaf.eax >> 16) & 0xFF;
+    // a set bit in EBX means the event is missing
+    let cycles_missing = (leaf.eax >> 24) & 0xFF == 0 || leaf.ebx & 1 != 0;
+    if version == 0 || counters == 0 || width < 32 || cycles_missing {
+        info!("NMI watchdog: no usable performance counter");
+        return;
+    }
+    PMU_VERSION.store(version, Ordering::Relaxed);
+    COUNTER_WIDTH.store(width, Ordering::Relaxed);
+
+    let tsc_hz = calibrate_tsc();
+    if tsc_hz == 0 {
+        info!("NMI watchdog: TSC isn't running");
+        return;
+    }
+    TSC_HZ.store(tsc_hz, Ordering::Relaxed);
+    PERIOD.store(tsc_hz.clamp(1, MAX_PERIOD), Ordering::Relaxed);
+
+    LAST_TICKS.store(time::ticks(), Ordering::Relaxed);
+    LAST_PROGRESS.store(rdtsc(), Ordering::Relaxed);
+    unsafe {
+        Msr::new(IA32_PERFEVTSEL0).write(0);
+        load_counter();
+        apic::set_perf_counter_nmi();
+        Msr::new(IA32_PERFEVTSEL0).write(
+            evtsel::UNHALTED_CORE_CYCLES | evtsel::USR | evtsel::OS | evtsel::INT | evtsel::EN,
+        );
+        if version >= 2 {
+            let mut global_ctrl = Msr::new(IA32_PERF_GLOBAL_CTRL);
+            global_ctrl.write(global_ctrl.read() | 1);
+        }
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+    info!(
+        "NMI watchdog: every {} cycles, TSC at {} MHz",
+        PERIOD.load(Ordering::Relaxed),
+        tsc_hz / 1_000_000
+    );
+}
+
+/// TSC counts per second, measured over `CALIBRATION_MS` of uptime
+fn calibrate_tsc() -> u64 {
+    let wait_tick = || {
+        let start = time::uptime_ms();
+        while time::uptime_ms() == start {
+            x86_64::instructions::hlt();
+        }
+    };
+
+    wait_tick();
+    let (start_ms, start_tsc) = (time::uptime_ms(), rdtsc());
+    while time::uptime_ms() - start_ms < CALIBRATION_MS {
+        x86_64::instructions::hlt();
+    }
+    let (end_ms, end_tsc) = (time::uptime_ms(), rdtsc());
+    (end_tsc - start_tsc) * 1000 / (end_ms - start_ms)
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Start the counter a period away from overflowing
+///
+/// # Safety
+/// The CPU must have performance counter 0.
+unsafe fn load_counter() {
+    let period = PERIOD.load(Ordering::Relaxed);
+    unsafe { Msr::new(IA32_PMC0).write(period.wrapping_neg()) };
+}
+
+/// Whether counter 0 overflowed, and so raised the NMI
+///
+/// # Safety
+/// The watchdog must be enabled.
+unsafe fn overflowed() -> bool {
+    if PMU_VERSION.load(Ordering::Relaxed) >= 2 {
+        return unsafe { Msr::new(IA32_PERF_GLOBAL_STATUS).read() } & 1 != 0;
+    }
+    // the counter starts negative, and wraps to small positive values
+    let top = 1 << (COUNTER_WIDTH.load(Ordering::Relaxed) - 1);
+    let counter = unsafe { Msr::new(IA32_PMC0).read() };
+    counter & top == 0
+}
+
+/// Handle an NMI raised by the watchdog's counter
+///
+/// Returns false if the NMI came from elsewhere.
+pub fn handle_nmi(stack_frame: &InterruptStackFrame) -> bool {
+    if !ENABLED.load(Ordering::Relaxed) || !unsafe { overflowed() } {
+        return false;
+    }
+
+    unsafe {
+        load_counter();
+        if PMU_VERSION.load(Ordering::Relaxed) >= 2 {
+            Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);
+        }
+    }
+    apic::set_perf_counter_nmi();
+
+    check(stack_frame);
+    true
+}
+
+/// Report a lockup if the tick counter stopped for longer than the threshold
+fn check(stack_frame: &InterruptStackFrame) {
+    let now = rdtsc();
+    let ticks = time::ticks();
+    if LAST_TICKS.swap(ticks, Ordering::Relaxed) != ticks {
+        LAST_PROGRESS.store(now, Ordering::Relaxed);
+        REPORTED.store(false, Ordering::Relaxed);
+        return;
+    }
+
+    let tsc_hz = TSC_HZ.load(Ordering::Relaxed);
+    let stuck = now.wrapping_sub(LAST_PROGRESS.load(Ordering::Relaxed));
+    if stuck < THRESH_S.load(Ordering::Relaxed) * tsc_hz || REPORTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    #[allow(unused_variables)]
+    let rip = stack_frame.instruction_pointer.as_u64();
+    error!(
+        "hard lockup: no timer interrupt for {} ms, interrupts disabled at {:#x} (link address {:#x})",
+        stuck * 1000 / tsc_hz,
+        rip,
+        crate::memory::kaslr::link_address(rip)
+    );
+    super::fault::report_task(stack_frame, None);
+
+    if PANIC.load(Ordering::Relaxed) {
+        panic!("hard lockup");
+    }
+}
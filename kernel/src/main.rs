@@ -22,18 +22,36 @@ You should have received a copy of the GNU General Public License along with loc
 #![test_runner(crate::testing::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+pub mod api;
+pub mod block;
+pub mod bootargs;
+pub mod clipboard;
+pub mod cpu;
+pub mod crc32;
+pub mod error;
+pub mod fs;
 pub mod gdt;
+pub mod input;
 pub mod interrupts;
 pub mod memory;
 pub mod meta;
+#[cfg(feature = "net")]
+pub mod net;
 pub mod output;
 pub mod pci;
+pub mod power;
 pub mod ps2;
+pub mod qemu;
 pub mod serial;
 pub mod shell;
+pub mod stats;
+pub mod sync;
+pub mod sysctl;
 pub mod syscall;
 pub mod tasks;
 pub mod testing;
+pub mod time;
+pub mod tty;
 
 extern crate alloc;
 
@@ -46,14 +64,15 @@ use limine::{
     BaseRevision,
     memory_map::EntryType,
     request::{
-        FramebufferRequest, HhdmRequest, MemoryMapRequest, RequestsEndMarker, RequestsStartMarker,
-        RsdpRequest, StackSizeRequest,
+        ExecutableCmdlineRequest, ExecutableFileRequest, FramebufferRequest, HhdmRequest, MemoryMapRequest, ModuleRequest,
+        RequestsEndMarker, RequestsStartMarker, RsdpRequest, StackSizeRequest,
     },
 };
 use memory::{
     init_frame_allocator, init_heap, init_page_allocator,
     paging::{self, fill_page_list},
 };
+#[cfg(feature = "graphics")]
 use output::{flanterm_init, framebuffer::get_info_from_frambuffer};
 use x86_64::{VirtAddr, registers::debug};
 
@@ -74,6 +93,17 @@ unsafe extern "C" fn kernel_main() -> ! {
     init_gdt();
     init_idt();
 
+    if let Some(cmdline) = CMDLINE_REQUEST
+        .get_response()
+        .and_then(|response| response.cmdline().to_str().ok())
+    {
+        bootargs::init(cmdline);
+    }
+    qemu::init();
+    info!("{}", meta::BuildLine);
+    memory::kaslr::init();
+    memory::uaccess::init();
+
     let memory_regions = MEMORY_MAP_REQUEST
         .get_response()
         .expect("memory map request failed")
@@ -95,15 +125,22 @@ unsafe extern "C" fn kernel_main() -> ! {
     }
 
     debug!("Physical memory offset: {:#x}", physical_memory_offset);
-    unsafe { fill_page_list(memory_regions, physical_memory_offset as usize) };
-    debug!("Filling page list done");
-    unsafe { init_frame_allocator(memory_regions, physical_memory_offset) };
-
-    unsafe { paging::init(VirtAddr::new(physical_memory_offset)) };
-
-    unsafe {
-        init_heap().expect("heap initialization failed");
+    if let Some(response) = FRAMEBUFFER_REQUEST.get_response() {
+        for framebuffer in response.framebuffers() {
+            memory::reserved::reserve(
+                framebuffer.addr() as u64 - physical_memory_offset,
+                framebuffer.pitch() * framebuffer.height(),
+                "framebuffer",
+            );
+        }
     }
+    memory::reserved::init(
+        memory_regions,
+        physical_memory_offset,
+        RSDP_REQUEST.get_response().map(|response| response.address()),
+    );
+    // from here on the kernel can allocate, the heap takes over once it is up
+    memory::bootmem::init(memory_regions, physical_memory_offset);
 
     // sum all usable memory regions
     let usable_regions_sum = memory_regions
@@ -125,30 +162,55 @@ unsafe extern "C" fn kernel_main() -> ! {
         usable_regions_sum as f64 / (1024.0 * 1024.0 * 1024.0),
         usable_regions,
     );
-    init_page_allocator(usable_regions_sum);
 
-    let framebuffer_response = FRAMEBUFFER_REQUEST
-        .get_response()
-        .expect("framebuffer request failed");
-    let framebuffer = framebuffer_response
-        .framebuffers()
-        .next()
-        .expect("framebuffer not found");
-
-    if framebuffer.bpp() % 8 != 0 {
-        panic!("Framebuffer bpp is not a multiple of 8");
+    unsafe { fill_page_list(memory_regions, physical_memory_offset as usize) };
+    debug!("Filling page list done");
+    unsafe { init_frame_allocator(memory_regions, physical_memory_offset) };
+
+    unsafe { paging::init(VirtAddr::new(physical_memory_offset)) };
+
+    unsafe {
+        init_heap().expect("heap initialization failed");
     }
+    memory::bootmem::retire();
+
+    // drivers use the kernel through the API crate
+    api::init();
 
-    flanterm_init(
-        framebuffer.addr() as *mut u32,
-        get_info_from_frambuffer(&framebuffer),
+    fs::initramfs::init(
+        EXECUTABLE_FILE_REQUEST.get_response().map(|response| response.file()),
+        MODULE_REQUEST.get_response().map_or(&[], |response| response.modules()),
     );
 
+    init_page_allocator(usable_regions_sum);
+
+    #[cfg(feature = "graphics")]
+    {
+        let framebuffer_response = FRAMEBUFFER_REQUEST
+            .get_response()
+            .expect("framebuffer request failed");
+        let framebuffer = framebuffer_response
+            .framebuffers()
+            .next()
+            .expect("framebuffer not found");
+
+        if framebuffer.bpp() % 8 != 0 {
+            panic!("Framebuffer bpp is not a multiple of 8");
+        }
+
+        flanterm_init(
+            framebuffer.addr() as *mut u32,
+            get_info_from_frambuffer(&framebuffer),
+        );
+    }
+
     let rsdp_addr = RSDP_REQUEST
         .get_response()
         .expect("RSDP request failed")
         .address();
 
+    time::init();
+
     unsafe { setup_apic(rsdp_addr) };
 
     syscall::init_syscall();
@@ -157,6 +219,11 @@ unsafe extern "C" fn kernel_main() -> ! {
 
     pci::init_pci(rsdp_addr).expect("failed to initialize PCIe subsystem");
 
+    power::init(rsdp_addr);
+
+    // every bootloader response has been consumed by now
+    unsafe { memory::bootloader::reclaim(memory_regions) };
+
     #[cfg(test)]
     {
         // Clear console and run tests before starting kernel tasks
@@ -167,44 +234,66 @@ unsafe extern "C" fn kernel_main() -> ! {
     #[cfg(not(test))]
     {
         use crate::shell::task::locos_shell;
-        use crate::tasks::scheduler::ucreate_task;
-
-        const TEST_PROGRAM: &[u8] = &[
-            0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00,  // mov rax, 1 (sys_write)
-            0x48, 0xc7, 0xc7, 0x01, 0x00, 0x00, 0x00,  // mov rdi, 1 (stdout)
-            0x48, 0x8d, 0x35, 0x19, 0x00, 0x00, 0x00,  // lea rsi, [rip+25] (message)
-            0x48, 0xc7, 0xc2, 0x16, 0x00, 0x00, 0x00,  // mov rdx, 22 (length)
-            0x0f, 0x05,                                // syscall
-            0x48, 0xc7, 0xc0, 0x00, 0x00, 0x00, 0x00,  // mov rax, 0 (sys_exit)
-            0x48, 0xc7, 0xc7, 0x00, 0x00, 0x00, 0x00,  // mov rdi, 0 (exit code)
-            0x0f, 0x05,                                // syscall
-            // "Hello from userspace!\n"
-            0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x66, 0x72,
-            0x6f, 0x6d, 0x20, 0x75, 0x73, 0x65, 0x72, 0x73,
-            0x70, 0x61, 0x63, 0x65, 0x21, 0x0a,
-        ];
 
         kcreate_task(tprint_welcome, "print welcome message");
         kcreate_task(locos_shell, "locos shell");
-        
-        if let Err(e) = ucreate_task(VirtAddr::new(0x400000), Some(TEST_PROGRAM), "test_userspace") {
-            error!("Failed to create test userspace task: {}", e);
+        tasks::deferred::spawn_worker();
+        tasks::scheduler::spawn_reaper();
+        ps2::spawn_recovery_task();
+        syscall::uring::spawn_workers();
+        memory::reclaim::spawn_daemon();
+        memory::oom::spawn_daemon();
+
+        #[cfg(feature = "nvme")]
+        {
+            pci::nvme::cache::init();
+            power::hibernate::spawn_resume_task();
         }
-        
+
+        #[cfg(feature = "tests")]
+        spawn_test_program();
+
         kinit_multitasking();
 
         x86_64::instructions::interrupts::enable();
+        memory::irqsafe::arm();
 
         unsafe {
             core::arch::asm!("int {}", const LAPIC_TIMER_VECTOR);
         }
 
-        pci::nvme::init();
+        pci::init_drivers();
+        interrupts::watchdog::init();
     }
 
     hcf();
 }
 
+/// Spawns a tiny hand-assembled user program that exercises sys_write and sys_exit.
+#[cfg(all(feature = "tests", not(test)))]
+fn spawn_test_program() {
+    use crate::tasks::scheduler::ucreate_task;
+
+    const TEST_PROGRAM: &[u8] = &[
+        0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00,  // mov rax, 1 (sys_write)
+        0x48, 0xc7, 0xc7, 0x01, 0x00, 0x00, 0x00,  // mov rdi, 1 (stdout)
+        0x48, 0x8d, 0x35, 0x19, 0x00, 0x00, 0x00,  // lea rsi, [rip+25] (message)
+        0x48, 0xc7, 0xc2, 0x16, 0x00, 0x00, 0x00,  // mov rdx, 22 (length)
+        0x0f, 0x05,                                // syscall
+        0x48, 0xc7, 0xc0, 0x00, 0x00, 0x00, 0x00,  // mov rax, 0 (sys_exit)
+        0x48, 0xc7, 0xc7, 0x00, 0x00, 0x00, 0x00,  // mov rdi, 0 (exit code)
+        0x0f, 0x05,                                // syscall
+        // "Hello from userspace!\n"
+        0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x66, 0x72,
+        0x6f, 0x6d, 0x20, 0x75, 0x73, 0x65, 0x72, 0x73,
+        0x70, 0x61, 0x63, 0x65, 0x21, 0x0a,
+    ];
+
+    if let Err(e) = ucreate_task(VirtAddr::new(0x400000), Some(TEST_PROGRAM), "test_userspace") {
+        error!("Failed to create test userspace task: {}", e);
+    }
+}
+
 #[used]
 #[unsafe(link_section = ".requests")]
 pub static BASE_REVISION: BaseRevision = BaseRevision::new();
@@ -225,10 +314,22 @@ static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
 #[unsafe(link_section = ".requests")]
 static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 
+#[used]
+#[unsafe(link_section = ".requests")]
+static CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
+
 #[used]
 #[unsafe(link_section = ".requests")]
 static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
 
+#[used]
+#[unsafe(link_section = ".requests")]
+static EXECUTABLE_FILE_REQUEST: ExecutableFileRequest = ExecutableFileRequest::new();
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static MODULE_REQUEST: ModuleRequest = ModuleRequest::new();
+
 #[used]
 #[unsafe(link_section = ".requests_start_marker")]
 static _START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
@@ -240,6 +341,15 @@ static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     error!("{}", info);
+    error!("build: {}", meta::BuildLine);
+    error!(
+        "kernel loaded at {:#x} (slide {:#x})",
+        memory::kaslr::kernel_base(),
+        memory::kaslr::slide()
+    );
+    #[cfg(feature = "net")]
+    net::upload::after_panic(info);
+    qemu::notify_panic();
     hcf();
 }
 
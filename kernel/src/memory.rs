@@ -1,8 +1,19 @@
 pub mod alloc;
+pub mod bootloader;
+pub mod bootmem;
+pub mod compact;
 pub mod freelist;
+pub mod irqsafe;
+pub mod kaslr;
+pub mod oom;
 pub mod paging;
+pub mod reclaim;
+pub mod reserved;
+pub mod slab;
 pub mod tests;
+pub mod uaccess;
+pub mod vma;
 
 pub use alloc::{init_heap, init_page_allocator};
 pub use paging::FrameBuddyAllocatorForest;
-pub use paging::{FRAME_ALLOCATOR, PAGE_TABLE, init, init_frame_allocator};
+pub use paging::{FRAME_ALLOCATOR, MEMORY_MAP, PAGE_TABLE, init, init_frame_allocator};
@@ -2,12 +2,21 @@ extern crate alloc;
 
 use alloc::collections::VecDeque;
 use alloc::vec::Vec;
-use core::{alloc::GlobalAlloc, ptr::NonNull};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
 
-use crate::info;
-use spin::Mutex;
+use crate::{
+    bootargs, info,
+    stats::{self, Counter},
+    sync::{Mutex, in_irq},
+    warn,
+};
 use x86_64::{
     VirtAddr,
+    instructions::interrupts,
     structures::paging::{
         FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
         mapper::{MapToError, UnmapError},
@@ -16,12 +25,17 @@ use x86_64::{
 
 use super::{
     FRAME_ALLOCATOR, PAGE_TABLE,
+    bootmem, kaslr,
     freelist::{FreeList, Node},
+    irqsafe::{AllocationKind, EMERGENCY_POOL, check_allocation},
+    oom::{self, HEAP_ALARM},
+    slab::{SLAB_SIZE, SlabAlloc},
 };
 
-pub static PAGE_ALLOCATOR: Mutex<Option<PageAllocator>> = Mutex::new(None);
+pub static PAGE_ALLOCATOR: Mutex<Option<PageAllocator>> = Mutex::new("PAGE_ALLOCATOR", None);
 
-/// The start address for the PageAllocator region (must not overlap with heap).
+/// The start address for the PageAllocator region (must not overlap with heap),
+/// before the KASLR offset is added.
 pub const PAGEALLOC_START: u64 = 0xFFFF_9000_0000_0000;
 
 /// Initializes the global page allocator with a region sized for the available RAM.
@@ -39,41 +53,57 @@ pub fn init_page_allocator(available_ram_bytes: u64) {
     while pagealloc_size < available_ram_bytes {
         pagealloc_size <<= 1;
     }
-    let pagealloc_end = PAGEALLOC_START + pagealloc_size;
+    let pagealloc_start = kaslr::region(PAGEALLOC_START);
+    let pagealloc_end = pagealloc_start + pagealloc_size;
 
     let page_count = pagealloc_size / 4096;
     let levels = page_count.next_power_of_two().trailing_zeros() as usize + 1;
     alloc_lock.replace(PageAllocator::new(
-        VirtAddr::new(PAGEALLOC_START),
+        VirtAddr::new(pagealloc_start),
         VirtAddr::new(pagealloc_end),
         levels,
     ));
 
     info!(
         "Page allocator initialized: {:#?} - {:#?}, size managed: {} GiB",
-        VirtAddr::new(PAGEALLOC_START),
+        VirtAddr::new(pagealloc_start),
         VirtAddr::new(pagealloc_end),
         pagealloc_size / (1024 * 1024 * 1024)
     );
 }
 
 #[global_allocator]
-pub static ALLOCATOR: Locked<BuddyAlloc<21, 16>> = Locked::new(BuddyAlloc::new(
-    VirtAddr::new(HEAP_START as u64),
-    VirtAddr::new(HEAP_START as u64 + HEAP_SIZE as u64),
-));
+pub static ALLOCATOR: KernelHeap = KernelHeap::new();
 
+/// Start of the kernel heap, before the KASLR offset is added
 pub const HEAP_START: usize = 0xFFFF_8800_0000_0000;
 pub const HEAP_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
 
 /// Initialize a heap region in virtual memory and map it to physical frames
 ///
+/// The heap backend is selected here from the `heap=slab|buddy` boot argument,
+/// defaulting to buddy. The slab caches are opt-in with `heap=slab`.
+///
 /// # Safety
 /// This function is unsafe because the caller must guarantee that the
 /// given memory region is unused and that the frame allocator is valid
 pub unsafe fn init_heap() -> Result<(), MapToError<Size4KiB>> {
-    let heap_start = Page::containing_address(VirtAddr::new(HEAP_START as u64));
-    let heap_end = Page::containing_address(VirtAddr::new((HEAP_START + HEAP_SIZE - 1) as u64));
+    let backend = match bootargs::get("heap") {
+        None | Some("buddy") => HeapBackend::Buddy,
+        Some("slab") => HeapBackend::Slab,
+        #[allow(unused_variables)]
+        Some(other) => {
+            warn!("unknown heap backend {:?}, falling back to buddy", other);
+            HeapBackend::Buddy
+        }
+    };
+    ALLOCATOR.set_backend(backend);
+
+    let start = VirtAddr::new(kaslr::region(HEAP_START as u64));
+    ALLOCATOR.place(start);
+
+    let heap_start = Page::containing_address(start);
+    let heap_end = Page::containing_address(start + (HEAP_SIZE - 1) as u64);
 
     // Map all pages in the heap
     for page in Page::range_inclusive(heap_start, heap_end) {
@@ -95,10 +125,199 @@ pub unsafe fn init_heap() -> Result<(), MapToError<Size4KiB>> {
         }
     }
 
-    info!("heap initialized: {:#?} - {:#?}", heap_start, heap_end);
+    ALLOCATOR.live_heap.store(true, Ordering::Release);
+    info!(
+        "heap initialized: {:#?} - {:#?} ({:?} backend)",
+        heap_start,
+        heap_end,
+        ALLOCATOR.backend()
+    );
     Ok(())
 }
 
+/// The allocator serving kernel heap allocations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HeapBackend {
+    /// Small allocations come from slab caches refilled from the buddy heap
+    Slab = 0,
+    /// Every allocation goes straight to the buddy heap
+    Buddy = 1,
+}
+
+/// The kernel heap, dispatching to either the slab or the buddy allocator.
+///
+/// Both backends share the same buddy-managed region, with the slab caches
+/// carving their slabs out of it. The backend can be flipped with a boot
+/// argument to bisect allocator regressions without rebuilding.
+pub struct KernelHeap {
+    backend: AtomicU8,
+    /// Whether the heap is mapped, allocations come from boot memory until
+    /// it is
+    live_heap: AtomicBool,
+    /// Bytes handed out by the buddy heap, slabs included
+    in_use: AtomicUsize,
+    /// Bytes allocated and not yet freed, as requested by callers
+    live: AtomicUsize,
+    buddy: Locked<BuddyAlloc<21, 16>>,
+    slab: Locked<SlabAlloc>,
+}
+
+impl KernelHeap {
+    /// Creates the kernel heap over the `HEAP_START..HEAP_START + HEAP_SIZE` region
+    pub const fn new() -> Self {
+        Self {
+            backend: AtomicU8::new(HeapBackend::Buddy as u8),
+            live_heap: AtomicBool::new(false),
+            in_use: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            buddy: Locked::new(BuddyAlloc::new(
+                VirtAddr::new(HEAP_START as u64),
+                VirtAddr::new(HEAP_START as u64 + HEAP_SIZE as u64),
+            )),
+            slab: Locked::new(SlabAlloc::new()),
+        }
+    }
+
+    /// Returns the backend currently serving allocations
+    pub fn backend(&self) -> HeapBackend {
+        match self.backend.load(Ordering::Relaxed) {
+            0 => HeapBackend::Slab,
+            _ => HeapBackend::Buddy,
+        }
+    }
+
+    /// Selects the heap backend.
+    ///
+    /// Must be called before the first heap allocation, since memory is
+    /// always freed through the backend that is active at the time.
+    fn set_backend(&self, backend: HeapBackend) {
+        self.backend.store(backend as u8, Ordering::Relaxed);
+    }
+
+    /// Moves the heap to `start`, where it is mapped after KASLR picked the
+    /// region offset.
+    ///
+    /// Must be called before the first heap allocation.
+    fn place(&self, start: VirtAddr) {
+        *self.buddy.lock() = BuddyAlloc::new(start, start + HEAP_SIZE as u64);
+    }
+
+    /// Returns the number of bytes in use out of `HEAP_SIZE`
+    ///
+    /// Slabs count as used as a whole, free objects in them included.
+    pub fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes allocated and not yet freed
+    ///
+    /// Counts the sizes callers asked for, so unlike `in_use` it returns to
+    /// the same value once everything allocated since has been freed.
+    pub fn live(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    /// Allocates from the buddy heap, keeping track of its usage
+    unsafe fn buddy_alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.buddy.alloc(layout) };
+        if !ptr.is_null() {
+            let in_use = self.in_use.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            HEAP_ALARM.update(in_use, HEAP_SIZE);
+        }
+        ptr
+    }
+
+    /// Frees to the buddy heap, keeping track of its usage
+    unsafe fn buddy_dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.buddy.dealloc(ptr, layout) };
+        let in_use = self.in_use.fetch_sub(layout.size(), Ordering::Relaxed) - layout.size();
+        HEAP_ALARM.update(in_use, HEAP_SIZE);
+    }
+
+    /// Returns the slab size class for `layout` if the slab backend should serve it
+    fn slab_class(&self, layout: Layout) -> Option<usize> {
+        match self.backend() {
+            HeapBackend::Slab => SlabAlloc::size_class(layout),
+            HeapBackend::Buddy => None,
+        }
+    }
+}
+
+impl Default for KernelHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KernelHeap {
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
+        if !self.live_heap.load(Ordering::Acquire) {
+            check_allocation(AllocationKind::Heap, layout.size());
+            return bootmem::alloc(layout);
+        }
+
+        // handlers are served without a lock while the pool lasts
+        if in_irq()
+            && let Some(ptr) = EMERGENCY_POOL.alloc(layout)
+        {
+            return ptr;
+        }
+        check_allocation(AllocationKind::Heap, layout.size());
+
+        // the heap locks are only held with interrupts disabled, so a handler
+        // can't find them taken by the task it interrupted
+        interrupts::without_interrupts(|| {
+            let Some(class) = self.slab_class(layout) else {
+                return unsafe { self.buddy_alloc(layout) };
+            };
+
+            let slab_layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+            self.slab
+                .lock()
+                .allocate(class, || {
+                    NonNull::new(unsafe { self.buddy_alloc(slab_layout) })
+                })
+                .map_or(core::ptr::null_mut(), NonNull::as_ptr)
+        })
+    }
+
+    unsafe fn free(&self, ptr: *mut u8, layout: Layout) {
+        // boot memory is never reused
+        if bootmem::contains(ptr) {
+            return;
+        }
+        if EMERGENCY_POOL.contains(ptr) {
+            return unsafe { EMERGENCY_POOL.dealloc(ptr) };
+        }
+
+        // like in `allocate`, so handlers dropping heap memory don't deadlock
+        interrupts::without_interrupts(|| {
+            let Some(class) = self.slab_class(layout) else {
+                return unsafe { self.buddy_dealloc(ptr, layout) };
+            };
+
+            unsafe { self.slab.lock().deallocate(class, NonNull::new(ptr).unwrap()) };
+        })
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.allocate(layout) };
+        if !ptr.is_null() {
+            self.live.fetch_add(layout.size(), Ordering::Relaxed);
+            stats::count(Counter::Allocations);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.live.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { self.free(ptr, layout) };
+    }
+}
+
 /// A simple wrapper around spin::Mutex to provide safe interior mutability
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
@@ -322,6 +541,8 @@ pub struct PageAllocator {
     heap_end: VirtAddr,
     levels: usize,
     free_lists: Vec<VecDeque<NonNull<()>>>,
+    /// Pages in allocated blocks, rounding included
+    pages_in_use: usize,
 }
 
 unsafe impl Send for PageAllocator {}
@@ -372,9 +593,23 @@ impl PageAllocator {
             heap_end: virt_end,
             levels,
             free_lists,
+            pages_in_use: 0,
         }
     }
 
+    /// Returns the number of pages in the managed region
+    pub fn total_pages(&self) -> usize {
+        self.max_size() / 4096
+    }
+
+    /// Returns the number of pages in allocated blocks
+    ///
+    /// Allocations are rounded up to a power of two, so this can be more than
+    /// the pages asked for.
+    pub fn pages_in_use(&self) -> usize {
+        self.pages_in_use
+    }
+
     /// Returns the total size managed by the allocator in bytes.
     fn max_size(&self) -> usize {
         (self.heap_end.as_u64() - self.heap_start.as_u64()) as usize
@@ -484,19 +719,31 @@ impl PageAllocator {
             .get_level_from_size(size)
             .expect("Invalid size for page allocation");
 
-        let block = self
-            .get_free_block(level)
-            .expect("OOM while allocating pages");
+        let Some(block) = self.get_free_block(level) else {
+            warn!("page allocator has no free block of {} pages", size / 4096);
+            return Err(MapToError::FrameAllocationFailed);
+        };
 
-        let mut frame_alloc_lock = FRAME_ALLOCATOR.lock();
-        let frame_alloc = frame_alloc_lock.as_mut().unwrap();
-        let mut page_table_lock = PAGE_TABLE.lock();
-        let page_table = page_table_lock.as_mut().unwrap();
-        for page in ((block.as_ptr() as usize)..(block.as_ptr() as usize + size)).step_by(4096) {
-            let physframe = frame_alloc
-                .allocate_frame()
-                .ok_or(MapToError::FrameAllocationFailed)?;
+        let start = block.as_ptr() as usize;
+        for (mapped, page) in (start..start + size).step_by(4096).enumerate() {
+            let physframe = loop {
+                let frame = FRAME_ALLOCATOR.lock().as_mut().unwrap().allocate_frame();
+                if let Some(frame) = frame {
+                    break frame;
+                }
+                // kill a user task and try again, the locks must not be held
+                if !oom::out_of_memory("page allocator") {
+                    self.unmap_pages(start, mapped)
+                        .expect("failed to unmap pages of a failed allocation");
+                    self.merge_buddies(level, block);
+                    return Err(MapToError::FrameAllocationFailed);
+                }
+            };
 
+            let mut page_table_lock = PAGE_TABLE.lock();
+            let page_table = page_table_lock.as_mut().unwrap();
+            let mut frame_alloc_lock = FRAME_ALLOCATOR.lock();
+            let frame_alloc = frame_alloc_lock.as_mut().unwrap();
             unsafe {
                 page_table
                     .map_to(
@@ -509,6 +756,7 @@ impl PageAllocator {
             };
         }
 
+        self.pages_in_use += size / 4096;
         Ok(PageAllocLayout::new(
             Page::containing_address(VirtAddr::new(block.as_ptr() as u64)),
             num_pages,
@@ -527,27 +775,31 @@ impl PageAllocator {
             .get_level_from_size(size)
             .expect("Invalid size for page allocation");
 
-        let mut frame_alloc_lock = FRAME_ALLOCATOR.lock();
-        let frame_alloc = frame_alloc_lock.as_mut().unwrap();
+        self.unmap_pages(info.page.start_address().as_u64() as usize, size / 4096)?;
+
+        self.merge_buddies(
+            level,
+            NonNull::new(info.page.start_address().as_u64() as *mut ()).unwrap(),
+        );
+        self.pages_in_use -= size / 4096;
+
+        Ok(())
+    }
+
+    /// Unmaps `pages` pages from `start` and frees their frames
+    fn unmap_pages(&mut self, start: usize, pages: usize) -> Result<(), UnmapError> {
         let mut page_table_lock = PAGE_TABLE.lock();
         let page_table = page_table_lock.as_mut().unwrap();
+        let mut frame_alloc_lock = FRAME_ALLOCATOR.lock();
+        let frame_alloc = frame_alloc_lock.as_mut().unwrap();
 
-        for page in ((info.page.start_address().as_u64() as usize)
-            ..(info.page.start_address().as_u64() as usize + size))
-            .step_by(4096)
-        {
+        for page in (start..start + pages * 4096).step_by(4096) {
             let (frame, flusher) = page_table.unmap(Page::<Size4KiB>::containing_address(
                 VirtAddr::new(page as u64),
             ))?;
             unsafe { frame_alloc.deallocate_frame(frame) };
             flusher.flush();
         }
-
-        self.merge_buddies(
-            level,
-            NonNull::new(info.page.start_address().as_u64() as *mut ()).unwrap(),
-        );
-
         Ok(())
     }
 }
@@ -0,0 +1,179 @@
+//! Returning bootloader-reclaimable memory
+//!
+//! Limine keeps its page tables, the boot stack and its request responses in
+//! memory marked `BOOTLOADER_RECLAIMABLE`, which can add up to tens of MiB.
+//! Once `kernel_main` has consumed every response, `reclaim` moves what the
+//! kernel still uses out of that memory and adds the rest to the frame
+//! allocator:
+//!
+//! - the memory map is copied to the heap and recorded in `MEMORY_MAP`
+//! - page tables are copied into allocated frames and CR3 is switched over
+//! - the boot stack stays reserved, `kernel_main` keeps running on it as the
+//!   first task
+//!
+//! The command line is copied by `bootargs` as soon as it is recorded.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use limine::memory_map::{Entry, EntryType};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::interrupts,
+    registers::control::{Cr3, Cr4, Cr4Flags},
+    structures::paging::{OffsetPageTable, PageTable, PageTableFlags, PhysFrame},
+};
+
+use super::{
+    FRAME_ALLOCATOR, MEMORY_MAP, PAGE_TABLE,
+    paging::{FrameBuddyAllocatorForest, MIN_ALLOCATOR_FRAMES},
+};
+use crate::{STACK_SIZE, info, warn};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Kept above the stack pointer for `kernel_main`'s own frame
+const STACK_SLACK: u64 = 64 * 1024;
+
+/// Copy the memory map and page tables out of bootloader memory and hand it
+/// to the frame allocator
+///
+/// # Safety
+/// Must be called once, after the last bootloader response has been read and
+/// before any task is created or page table copied from the kernel's.
+pub unsafe fn reclaim(memory_map: &[&Entry]) {
+    let entries: &'static [Entry] = memory_map
+        .iter()
+        .map(|&&entry| entry)
+        .collect::<Vec<_>>()
+        .leak();
+    MEMORY_MAP.call_once(|| entries.iter().collect::<Vec<_>>().leak());
+
+    if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+        warn!("not reclaiming bootloader memory, 5-level paging is not supported");
+        return;
+    }
+
+    let reclaimable: Vec<(u64, u64)> = entries
+        .iter()
+        .filter(|entry| entry.entry_type == EntryType::BOOTLOADER_RECLAIMABLE)
+        .map(|entry| (entry.base, entry.base + entry.length))
+        .collect();
+    let ranges = without_boot_stack(&reclaimable);
+
+    let added = interrupts::without_interrupts(|| {
+        let mut page_table = PAGE_TABLE.lock();
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut()?;
+        let hhdm_offset = allocator.hddm_offset;
+
+        let (l4, flags) = Cr3::read();
+        let is_reclaimable = |phys: u64| {
+            reclaimable
+                .iter()
+                .any(|&(start, end)| (start..end).contains(&phys))
+        };
+        let new_l4 = relocate_table(allocator, l4.start_address().as_u64(), 4, &is_reclaimable)?;
+
+        if new_l4 != l4.start_address().as_u64() {
+            unsafe {
+                Cr3::write(PhysFrame::containing_address(PhysAddr::new(new_l4)), flags);
+                let table = &mut *VirtAddr::new(new_l4 + hhdm_offset).as_mut_ptr::<PageTable>();
+                page_table.replace(OffsetPageTable::new(table, VirtAddr::new(hhdm_offset)));
+            }
+        }
+
+        Some(
+            ranges
+                .iter()
+                .map(|&(start, end)| unsafe {
+                    allocator.add_region(
+                        PhysAddr::new(start),
+                        PhysAddr::new(end),
+                        MIN_ALLOCATOR_FRAMES,
+                    )
+                })
+                .sum::<usize>(),
+        )
+    });
+
+    #[allow(unused_variables)]
+    let Some(added) = added else {
+        warn!("not reclaiming bootloader memory, out of frames for page tables");
+        return;
+    };
+    info!(
+        "reclaimed {} KiB of bootloader memory",
+        added as u64 * PAGE_SIZE / 1024
+    );
+}
+
+/// Reclaimable ranges, page aligned, with the boot stack cut out
+fn without_boot_stack(reclaimable: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+    // the stack is addressed through the higher half direct map
+    let hhdm_offset = interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .lock()
+            .as_ref()
+            .map_or(0, |allocator| allocator.hddm_offset)
+    });
+    let rsp = rsp.saturating_sub(hhdm_offset);
+    let stack_start = rsp.saturating_sub(STACK_SIZE) & !(PAGE_SIZE - 1);
+    let stack_end = (rsp + STACK_SLACK).next_multiple_of(PAGE_SIZE);
+
+    let mut ranges = Vec::new();
+    for &(start, end) in reclaimable {
+        let (start, end) = (start.next_multiple_of(PAGE_SIZE), end & !(PAGE_SIZE - 1));
+        for (start, end) in [(start, end.min(stack_start)), (start.max(stack_end), end)] {
+            if start < end {
+                ranges.push((start, end));
+            }
+        }
+    }
+    ranges
+}
+
+/// Copy the page table at `table` and every table below it that is in
+/// reclaimable memory into allocated frames
+///
+/// Children are copied first and the entries pointing at them updated, so
+/// the tables stay valid throughout. Returns the address of the table, which
+/// only changes if it was copied, or None if frames ran out.
+fn relocate_table(
+    allocator: &mut FrameBuddyAllocatorForest,
+    table: u64,
+    level: u8,
+    is_reclaimable: &impl Fn(u64) -> bool,
+) -> Option<u64> {
+    let hhdm_offset = allocator.hddm_offset;
+    let entries = unsafe { &mut *VirtAddr::new(table + hhdm_offset).as_mut_ptr::<PageTable>() };
+
+    if level > 1 {
+        for entry in entries.iter_mut() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                continue;
+            }
+            let child = entry.addr().as_u64();
+            let relocated = relocate_table(allocator, child, level - 1, is_reclaimable)?;
+            if relocated != child {
+                entry.set_addr(PhysAddr::new(relocated), flags);
+            }
+        }
+    }
+
+    if !is_reclaimable(table) {
+        return Some(table);
+    }
+    let copy = allocator.allocate_contiguous_frames(1)?.as_u64();
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            (table + hhdm_offset) as *const u8,
+            (copy + hhdm_offset) as *mut u8,
+            PAGE_SIZE as usize,
+        )
+    };
+    Some(copy)
+}
@@ -0,0 +1,169 @@
+//! Allocations made before the heap is up
+//!
+//! The heap can only be mapped once the frame allocator and the page tables
+//! are set up, but code running before that, or in between, still wants to
+//! allocate. `init` sets a region of usable memory aside, before the frame
+//! allocator is built, and until `init_heap` is done every heap allocation is
+//! bumped out of it through the direct map instead. Nothing in it is ever
+//! freed: early allocations mostly live forever, and freeing one does nothing.
+//!
+//! Once the heap is live, `retire` hands the part never bumped into over to
+//! the frame allocator, keeping only the pages holding early allocations.
+
+use core::{
+    alloc::Layout,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use limine::memory_map::Entry;
+use x86_64::PhysAddr;
+
+use super::{FRAME_ALLOCATOR, paging::MIN_ALLOCATOR_FRAMES, reserved};
+use crate::{info, sync::Mutex, warn};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Size of the region set aside
+const BOOTMEM_SIZE: u64 = 1024 * 1024;
+
+/// Lowest address the region is placed at, memory below is kept for devices
+/// that can only reach it
+const BOOTMEM_MIN_ADDRESS: u64 = 0x10_0000;
+
+/// A bump allocator over physical addresses
+#[derive(Debug, Clone, Copy)]
+pub struct Bump {
+    pub start: u64,
+    pub next: u64,
+    pub end: u64,
+    pub allocations: usize,
+}
+
+impl Bump {
+    pub const fn new(start: u64, end: u64) -> Self {
+        Self {
+            start,
+            next: start,
+            end,
+            allocations: 0,
+        }
+    }
+
+    /// Start of `size` bytes aligned to `align`, or `None` if they don't fit
+    pub fn alloc(&mut self, size: u64, align: u64) -> Option<u64> {
+        let start = self.next.checked_next_multiple_of(align)?;
+        let end = start.checked_add(size)?;
+        if end > self.end {
+            return None;
+        }
+        self.next = end;
+        self.allocations += 1;
+        Some(start)
+    }
+
+    /// Pages never bumped into
+    pub fn unused_pages(&self) -> (u64, u64) {
+        (self.next.next_multiple_of(PAGE_SIZE), self.end)
+    }
+}
+
+struct Bootmem {
+    bump: Bump,
+    hhdm_offset: u64,
+    retired: bool,
+}
+
+/// A spin lock rather than a lock class, it is taken inside the global
+/// allocator
+static BOOTMEM: Mutex<Bootmem> = Mutex::new(
+    "BOOTMEM",
+    Bootmem {
+        bump: Bump::new(0, 0),
+        hhdm_offset: 0,
+        retired: false,
+    },
+);
+
+/// The region in the direct map, so frees are told apart without the lock
+static VIRT_START: AtomicU64 = AtomicU64::new(0);
+static VIRT_END: AtomicU64 = AtomicU64::new(0);
+
+/// Set the region aside, in the first usable range that fits it
+///
+/// Must be called after `reserved::init`, which would otherwise warn about
+/// the region lying in usable memory, and before the frame allocator is
+/// built.
+pub fn init(entries: &[&Entry], hhdm_offset: u64) {
+    let mut region = None;
+    reserved::for_each_usable(entries, |start, end| {
+        let start = start.max(BOOTMEM_MIN_ADDRESS);
+        if region.is_none() && end.saturating_sub(start) >= BOOTMEM_SIZE {
+            region = Some(start);
+        }
+    });
+    let Some(start) = region else {
+        warn!("no room for boot memory, allocating before the heap is up will fail");
+        return;
+    };
+
+    reserved::reserve(start, BOOTMEM_SIZE, "boot memory");
+    let mut bootmem = BOOTMEM.lock();
+    bootmem.bump = Bump::new(start, start + BOOTMEM_SIZE);
+    bootmem.hhdm_offset = hhdm_offset;
+    VIRT_START.store(start + hhdm_offset, Ordering::Relaxed);
+    VIRT_END.store(start + BOOTMEM_SIZE + hhdm_offset, Ordering::Relaxed);
+    info!("boot memory at {:#x}-{:#x}", start, start + BOOTMEM_SIZE);
+}
+
+/// Allocate from the region, returning null once it is used up or retired
+pub fn alloc(layout: Layout) -> *mut u8 {
+    let mut bootmem = BOOTMEM.lock();
+    if bootmem.retired {
+        return ptr::null_mut();
+    }
+    match bootmem
+        .bump
+        .alloc(layout.size() as u64, layout.align() as u64)
+    {
+        Some(phys) => (phys + bootmem.hhdm_offset) as *mut u8,
+        None => ptr::null_mut(),
+    }
+}
+
+/// Whether `ptr` was returned by `alloc`
+pub fn contains(ptr: *mut u8) -> bool {
+    (VIRT_START.load(Ordering::Relaxed)..VIRT_END.load(Ordering::Relaxed)).contains(&(ptr as u64))
+}
+
+/// Stop allocating from the region and give its unused pages to the frame
+/// allocator
+///
+/// Must be called once the heap is live.
+pub fn retire() {
+    let mut bootmem = BOOTMEM.lock();
+    if bootmem.retired {
+        return;
+    }
+    bootmem.retired = true;
+
+    let (start, end) = bootmem.bump.unused_pages();
+    VIRT_END.store(start + bootmem.hhdm_offset, Ordering::Relaxed);
+    #[allow(unused_variables)]
+    let returned = match FRAME_ALLOCATOR.lock().as_mut() {
+        Some(allocator) if start < end => unsafe {
+            allocator.add_region(
+                PhysAddr::new(start),
+                PhysAddr::new(end),
+                MIN_ALLOCATOR_FRAMES,
+            )
+        },
+        _ => 0,
+    };
+    info!(
+        "boot memory retired: {} allocations in {} KiB, {} KiB returned to the frame allocator",
+        bootmem.bump.allocations,
+        (bootmem.bump.next - bootmem.bump.start) / 1024,
+        returned as u64 * PAGE_SIZE / 1024
+    );
+}
@@ -0,0 +1,227 @@
+//! Memory compaction
+//!
+//! High-order allocations fail once free frames are scattered, even with
+//! plenty of memory free. Compaction looks for an aligned block made only of
+//! free and movable frames, migrates the movable frames out of it and frees
+//! it, so the buddy allocator merges it back into one block.
+//!
+//! The only movable frames so far are user pages. Each is mapped by exactly
+//! one user page table, which serves as the reverse map, so migrating a page
+//! means copying it and pointing that one entry at the copy. Page tables,
+//! kernel memory and user pages flagged `PINNED` stay put, and so do the
+//! pages of running tasks so that no TLB shootdown is needed.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    vec::Vec,
+};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame, page_table::PageTableEntry},
+};
+
+use crate::{debug, memory::FRAME_ALLOCATOR, tasks::scheduler::with_idle_user_tasks};
+
+use super::FrameBuddyAllocatorForest;
+
+/// User pages the kernel holds the physical address of, which must not move
+pub const PINNED: PageTableFlags = PageTableFlags::BIT_9;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Allocate contiguous physical frames, compacting memory if there is no free
+/// block large enough
+///
+/// Must be called from task context.
+pub fn allocate_contiguous_frames(frames: usize) -> Option<PhysAddr> {
+    let allocated = FRAME_ALLOCATOR
+        .lock()
+        .as_mut()?
+        .allocate_contiguous_frames(frames);
+    if allocated.is_some() || frames == 1 {
+        return allocated;
+    }
+    compact(frames)
+}
+
+/// Free up a block of `frames` frames and allocate it
+///
+/// Returns None if no block could be freed.
+pub fn compact(frames: usize) -> Option<PhysAddr> {
+    with_idle_user_tasks(|page_tables| {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut()?;
+
+        // another task may have freed a block in the meantime
+        if let Some(block) = allocator.allocate_contiguous_frames(frames) {
+            return Some(block);
+        }
+
+        let movable = movable_frames(page_tables, allocator.hddm_offset);
+        let free = free_frames(allocator);
+        let block = find_block(allocator, frames, &movable, &free)?;
+
+        #[allow(unused_variables)]
+        let migrated = migrate_block(allocator, block, frames, &movable)?;
+        debug!(
+            "compaction migrated {} page(s) out of {:#x} for a block of {} frames",
+            migrated,
+            block.as_u64(),
+            frames
+        );
+        allocator.allocate_contiguous_frames(frames)
+    })
+}
+
+/// Frames mapped by exactly one user page, with the entry mapping them
+///
+/// Frames that are mapped more than once or pinned map to None.
+fn movable_frames(
+    page_tables: &[PhysFrame],
+    hhdm_offset: u64,
+) -> BTreeMap<u64, Option<*mut PageTableEntry>> {
+    let table = |frame: PhysFrame| unsafe {
+        &mut *VirtAddr::new(frame.start_address().as_u64() + hhdm_offset).as_mut_ptr::<PageTable>()
+    };
+    let children = |table: &'static mut PageTable, entries: usize| {
+        table.iter_mut().take(entries).filter_map(|entry| {
+            let flags = entry.flags();
+            (flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE))
+                .then(|| entry.frame().ok())
+                .flatten()
+        })
+    };
+
+    let mut movable = BTreeMap::new();
+    for &l4 in page_tables {
+        // only the lower half belongs to the task
+        for l3 in children(table(l4), 256) {
+            for l2 in children(table(l3), 512) {
+                for l1 in children(table(l2), 512) {
+                    for entry in table(l1).iter_mut() {
+                        let flags = entry.flags();
+                        if !flags
+                            .contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+                        {
+                            continue;
+                        }
+                        let pinned = flags.contains(PINNED);
+                        movable
+                            .entry(entry.addr().as_u64())
+                            .and_modify(|mapping| *mapping = None)
+                            .or_insert((!pinned).then_some(entry as *mut PageTableEntry));
+                    }
+                }
+            }
+        }
+    }
+    movable
+}
+
+/// Free blocks as sorted (start, end) physical address ranges
+fn free_frames(allocator: &FrameBuddyAllocatorForest) -> Vec<(u64, u64)> {
+    let mut free = Vec::new();
+    allocator.for_each_free_block(|start, frames| {
+        free.push((start.as_u64(), start.as_u64() + frames as u64 * PAGE_SIZE))
+    });
+    free.sort_unstable();
+    free
+}
+
+fn is_free(free: &[(u64, u64)], frame: u64) -> bool {
+    let index = free.partition_point(|&(start, _)| start <= frame);
+    index > 0 && frame < free[index - 1].1
+}
+
+/// Find an aligned block of `frames` frames that only holds free and movable
+/// frames
+fn find_block(
+    allocator: &FrameBuddyAllocatorForest,
+    frames: usize,
+    movable: &BTreeMap<u64, Option<*mut PageTableEntry>>,
+    free: &[(u64, u64)],
+) -> Option<PhysAddr> {
+    let size = frames as u64 * PAGE_SIZE;
+    let mut regions = Vec::new();
+    allocator.for_each_region(|start, end, base| {
+        regions.push((start.as_u64(), end.as_u64(), base.as_u64()))
+    });
+
+    // only blocks holding a movable frame are worth looking at, any other
+    // would be free already
+    let candidates: BTreeSet<u64> = movable
+        .iter()
+        .filter(|(_, mapping)| mapping.is_some())
+        .filter_map(|(&frame, _)| {
+            let &(start, end, base) = regions
+                .iter()
+                .find(|&&(start, end, _)| (start..end).contains(&frame))?;
+            let block = base + (frame - base) / size * size;
+            (block >= start && block + size <= end).then_some(block)
+        })
+        .collect();
+
+    candidates
+        .into_iter()
+        .find(|&block| {
+            (block..block + size)
+                .step_by(PAGE_SIZE as usize)
+                .all(|frame| {
+                    is_free(free, frame) || movable.get(&frame).is_some_and(Option::is_some)
+                })
+        })
+        .map(PhysAddr::new)
+}
+
+/// Move every movable frame out of `block`, then free the block
+///
+/// Returns the number of pages migrated, or None if there weren't enough free
+/// frames outside the block. Pages already migrated stay where they are.
+fn migrate_block(
+    allocator: &mut FrameBuddyAllocatorForest,
+    block: PhysAddr,
+    frames: usize,
+    movable: &BTreeMap<u64, Option<*mut PageTableEntry>>,
+) -> Option<usize> {
+    let range = block.as_u64()..block.as_u64() + frames as u64 * PAGE_SIZE;
+    let hhdm_offset = allocator.hddm_offset;
+
+    // free frames inside the block taken while looking for destinations, and
+    // frames that have been migrated away from
+    let mut released = Vec::new();
+    let mut migrated = 0;
+    let mut complete = true;
+
+    'frames: for (&frame, mapping) in movable.range(range.clone()) {
+        let Some(entry) = *mapping else {
+            continue;
+        };
+        let destination = loop {
+            let Some(destination) = allocator.allocate_contiguous_frames(1) else {
+                complete = false;
+                break 'frames;
+            };
+            if !range.contains(&destination.as_u64()) {
+                break destination;
+            }
+            released.push(destination.as_u64());
+        };
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (frame + hhdm_offset) as *const u8,
+                (destination.as_u64() + hhdm_offset) as *mut u8,
+                PAGE_SIZE as usize,
+            );
+            let entry = &mut *entry;
+            entry.set_addr(destination, entry.flags());
+        }
+        released.push(frame);
+        migrated += 1;
+    }
+
+    for frame in released {
+        unsafe { allocator.deallocate_contiguous_frames(PhysAddr::new(frame), 1) };
+    }
+    complete.then_some(migrated)
+}
@@ -0,0 +1,176 @@
+//! Interrupt-safe allocation
+//!
+//! The frame allocator is guarded by a spin lock that interrupts are not
+//! disabled for, so an interrupt handler allocating frames while the task it
+//! interrupted is inside the allocator deadlocks. The heap takes its locks
+//! with interrupts disabled, so handlers may allocate and free, but their
+//! allocations are served from a small pre-allocated emergency pool that is
+//! managed without locks while it lasts.
+//!
+//! With the `alloc-debug` feature, every frame allocation or heap allocation
+//! the pool can't serve made inside an interrupt handler, or with interrupts
+//! disabled once the kernel is up, is logged and counted so the offending path
+//! can be fixed. Debug builds also fail an assertion on the ones made inside
+//! an interrupt handler.
+
+use core::{
+    alloc::Layout,
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[cfg(feature = "alloc-debug")]
+use core::sync::atomic::AtomicBool;
+
+#[cfg(feature = "alloc-debug")]
+use crate::{sync::in_irq, warn};
+
+/// Size of an emergency pool block
+pub const EMERGENCY_BLOCK_SIZE: usize = 256;
+
+/// Number of blocks in the emergency pool
+pub const EMERGENCY_BLOCKS: usize = 64;
+
+#[repr(C, align(256))]
+struct Block([u8; EMERGENCY_BLOCK_SIZE]);
+
+/// Fixed pool of blocks handed out without taking a lock
+pub struct EmergencyPool {
+    blocks: UnsafeCell<[Block; EMERGENCY_BLOCKS]>,
+    /// Bit `n` is set while block `n` is in use
+    used: AtomicU64,
+}
+
+// blocks are only ever accessed by whoever claimed them in `used`
+unsafe impl Sync for EmergencyPool {}
+
+impl EmergencyPool {
+    const fn new() -> Self {
+        Self {
+            blocks: UnsafeCell::new([const { Block([0; EMERGENCY_BLOCK_SIZE]) }; EMERGENCY_BLOCKS]),
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// Claim a block for `layout`, or return None if it doesn't fit or the
+    /// pool is exhausted
+    pub fn alloc(&self, layout: Layout) -> Option<*mut u8> {
+        if layout.size() > EMERGENCY_BLOCK_SIZE || layout.align() > EMERGENCY_BLOCK_SIZE {
+            return None;
+        }
+
+        let mut used = self.used.load(Ordering::Acquire);
+        loop {
+            let index = (!used).trailing_zeros() as usize;
+            if index >= EMERGENCY_BLOCKS {
+                return None;
+            }
+
+            match self.used.compare_exchange_weak(
+                used,
+                used | (1 << index),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let blocks = self.blocks.get() as *mut Block;
+                    return Some(unsafe { blocks.add(index) } as *mut u8);
+                }
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    /// Returns true if `ptr` points into the pool
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        let start = self.blocks.get() as usize;
+        (start..start + EMERGENCY_BLOCKS * EMERGENCY_BLOCK_SIZE).contains(&(ptr as usize))
+    }
+
+    /// Return a block to the pool
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by `alloc` and not freed since.
+    pub unsafe fn dealloc(&self, ptr: *mut u8) {
+        let index = (ptr as usize - self.blocks.get() as usize) / EMERGENCY_BLOCK_SIZE;
+        self.used.fetch_and(!(1 << index), Ordering::Release);
+    }
+
+    /// Number of blocks currently in use
+    pub fn in_use(&self) -> usize {
+        self.used.load(Ordering::Relaxed).count_ones() as usize
+    }
+}
+
+/// Pool serving heap allocations made from interrupt handlers
+pub static EMERGENCY_POOL: EmergencyPool = EmergencyPool::new();
+
+/// Kind of allocation being checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationKind {
+    Heap,
+    Frame,
+}
+
+/// Allocations flagged by the `alloc-debug` checks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FlaggedAllocations {
+    /// Allocations made inside an interrupt handler
+    pub in_irq: u64,
+    /// Allocations made with interrupts disabled outside of interrupt handlers
+    pub interrupts_disabled: u64,
+}
+
+static FLAGGED_IN_IRQ: AtomicU64 = AtomicU64::new(0);
+static FLAGGED_INTERRUPTS_DISABLED: AtomicU64 = AtomicU64::new(0);
+
+/// Number of flagged allocations logged before only counting them
+#[cfg(feature = "alloc-debug")]
+const LOGGED_ALLOCATIONS: u64 = 32;
+
+/// Set once the kernel runs with interrupts enabled, before that every
+/// allocation happens with interrupts disabled
+#[cfg(feature = "alloc-debug")]
+static ARMED: AtomicBool = AtomicBool::new(false);
+
+/// Start flagging allocations made with interrupts disabled
+///
+/// Called once interrupts have been enabled at the end of boot.
+pub fn arm() {
+    #[cfg(feature = "alloc-debug")]
+    ARMED.store(true, Ordering::Relaxed);
+}
+
+/// Check an allocation against the interrupt state, see the module docs
+#[inline]
+#[allow(unused_variables)]
+pub fn check_allocation(kind: AllocationKind, size: usize) {
+    #[cfg(feature = "alloc-debug")]
+    {
+        let (counter, reason) = if in_irq() {
+            (&FLAGGED_IN_IRQ, "inside an interrupt handler")
+        } else if ARMED.load(Ordering::Relaxed)
+            && !x86_64::instructions::interrupts::are_enabled()
+        {
+            (&FLAGGED_INTERRUPTS_DISABLED, "with interrupts disabled")
+        } else {
+            return;
+        };
+
+        if counter.fetch_add(1, Ordering::Relaxed) < LOGGED_ALLOCATIONS {
+            warn!("{:?} allocation of {} bytes {}", kind, size, reason);
+        }
+        debug_assert!(
+            !in_irq(),
+            "{kind:?} allocation of {size} bytes inside an interrupt handler"
+        );
+    }
+}
+
+/// Allocations flagged so far, always zero without `alloc-debug`
+pub fn flagged_allocations() -> FlaggedAllocations {
+    FlaggedAllocations {
+        in_irq: FLAGGED_IN_IRQ.load(Ordering::Relaxed),
+        interrupts_disabled: FLAGGED_INTERRUPTS_DISABLED.load(Ordering::Relaxed),
+    }
+}
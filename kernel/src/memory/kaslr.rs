@@ -0,0 +1,141 @@
+//! Kernel address space layout randomization
+//!
+//! The kernel is linked as a position-independent executable at
+//! `KERNEL_LINK_BASE`, and Limine loads it at a random address in the top
+//! 2 GiB (`kaslr: yes` in limine.conf) after applying its relocations. The
+//! entry stub then runs the relocation pass again for the address it actually
+//! runs at. Every relocation is `R_X86_64_RELATIVE`, so the pass writes the
+//! same words Limine did, and it lets the kernel also boot from a loader that
+//! doesn't relocate at all.
+//!
+//! The fixed virtual regions (the heap, the page allocator, kernel stacks and
+//! the MMIO windows) are given as bases, and all of them are moved by the same
+//! random offset picked at boot, see `region`. `nokaslr` on the command line
+//! keeps them at their bases.
+//!
+//! Addresses in fault reports are also given as link addresses, which is what
+//! `addr2line -e kernel.elf` expects.
+
+use core::{
+    arch::{naked_asm, x86_64::_rdtsc},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::instructions::random::RdRand;
+
+use crate::{bootargs, info};
+
+/// Address the kernel is linked at, the start of `linker.ld`
+pub const KERNEL_LINK_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+
+/// Granularity of the region offset, keeping regions aligned for huge pages
+const REGION_ALIGN: u64 = 2 * 1024 * 1024;
+
+/// Bound of the region offset; the regions are at least 1 TiB apart and none
+/// grows past 512 GiB
+const REGION_OFFSET_MAX: u64 = 256 * 1024 * 1024 * 1024;
+
+const R_X86_64_RELATIVE: u64 = 8;
+
+/// Difference between where the kernel runs and where it was linked
+static SLIDE: AtomicU64 = AtomicU64::new(0);
+
+/// Offset added to the base of every fixed virtual region
+static REGION_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// An entry of `.rela.dyn`
+#[repr(C)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+/// Entry point, applying the kernel's relocations before any Rust code reads
+/// a pointer from its data
+///
+/// The addresses are taken here, with RIP-relative `lea`s, because code
+/// compiled as position-independent may load them from the GOT, which is one
+/// of the things being relocated.
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+unsafe extern "C" fn kernel_entry() -> ! {
+    naked_asm!(
+        "lea rdi, [rip + __kernel_start]",
+        "lea rsi, [rip + __rela_start]",
+        "lea rdx, [rip + __rela_end]",
+        "call {relocate}",
+        "jmp {main}",
+        relocate = sym relocate,
+        main = sym crate::kernel_main,
+    )
+}
+
+/// Apply the `R_X86_64_RELATIVE` relocations in `start..end` for a kernel
+/// loaded at `base`
+///
+/// Nothing here may panic or use relocated data, hence the raw pointer walk
+/// and wrapping arithmetic.
+unsafe extern "C" fn relocate(base: u64, start: *const Rela, end: *const Rela) {
+    let slide = base.wrapping_sub(KERNEL_LINK_BASE);
+    SLIDE.store(slide, Ordering::Relaxed);
+
+    let mut rela = start;
+    while rela < end {
+        let entry = unsafe { rela.read() };
+        if entry.info & 0xFFFF_FFFF == R_X86_64_RELATIVE {
+            let target = entry.offset.wrapping_add(slide) as *mut u64;
+            unsafe { target.write_unaligned((entry.addend as u64).wrapping_add(slide)) };
+        }
+        rela = unsafe { rela.add(1) };
+    }
+}
+
+/// Pick the region offset, once the command line was recorded and before any
+/// region is mapped
+#[allow(unused_variables)]
+pub fn init() {
+    let slots = REGION_OFFSET_MAX / REGION_ALIGN;
+    let offset = if bootargs::has_flag("nokaslr") {
+        0
+    } else {
+        random() % slots * REGION_ALIGN
+    };
+    REGION_OFFSET.store(offset, Ordering::Relaxed);
+    info!(
+        "kernel at {:#x} (slide {:#x}), regions offset by {:#x}",
+        kernel_base(),
+        slide(),
+        offset
+    );
+}
+
+/// RDRAND if the CPU has it, the TSC otherwise
+pub(crate) fn random() -> u64 {
+    let rdrand = RdRand::new().and_then(|rdrand| (0..10).find_map(|_| rdrand.get_u64()));
+    rdrand.unwrap_or_else(|| {
+        // the low bits of the TSC vary the most between boots
+        let tsc = unsafe { _rdtsc() };
+        tsc ^ tsc.rotate_left(29) ^ tsc.rotate_left(47)
+    })
+}
+
+/// Where the kernel runs minus where it was linked
+pub fn slide() -> u64 {
+    SLIDE.load(Ordering::Relaxed)
+}
+
+/// Virtual address the kernel was loaded at
+pub fn kernel_base() -> u64 {
+    KERNEL_LINK_BASE.wrapping_add(slide())
+}
+
+/// Start of the fixed region linked at `base`
+pub fn region(base: u64) -> u64 {
+    base + REGION_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Link address of a kernel address, for symbolizing it
+pub fn link_address(address: u64) -> u64 {
+    address.wrapping_sub(slide())
+}
@@ -0,0 +1,251 @@
+//! Memory usage alarms and the OOM killer
+//!
+//! Kernel heap and DMA usage are checked as memory is handed out. An alarm is
+//! raised once usage crosses its threshold and cleared once it drops back
+//! below a lower one, so usage hovering around the threshold doesn't flood the
+//! log. Alarms are only recorded at allocation time, since the allocator locks
+//! may be held there; the next timer tick wakes the OOM task, which logs them.
+//!
+//! When frames run out somewhere the allocation can be retried, the caller
+//! runs `out_of_memory`, which kills a user task to free its memory: normal
+//! tasks before real-time ones, lower real-time priorities first, and the one
+//! holding the most frames among equals. Kernel tasks are never killed. If no
+//! user task is left to kill the allocation fails, and only the caller knows
+//! whether the kernel can go on without it.
+//!
+//! Tasks that can drop memory when asked wait on `notify_key`, which is woken
+//! whenever an alarm changes or a task was killed.
+
+use core::{
+    cmp::Reverse,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    block, fs, info,
+    memory::{FRAME_ALLOCATOR, vma},
+    syscall::{trace, uring},
+    tasks::scheduler::{
+        UserTaskMemory, kcreate_task, kill_task, killable_user_tasks, stop_task, wait_for_event,
+        wake_event_waiters,
+    },
+    warn,
+};
+
+/// Usage of some kind of memory, checked against a threshold
+pub struct Alarm {
+    pub name: &'static str,
+    /// Usage in percent at which the alarm is raised
+    raise_percent: usize,
+    /// Usage in percent under which a raised alarm is cleared
+    clear_percent: usize,
+    raised: AtomicBool,
+    /// Set when the alarm changed and the OOM task hasn't logged it yet
+    pending: AtomicBool,
+    used: AtomicUsize,
+    total: AtomicUsize,
+    /// Times the alarm was raised
+    count: AtomicU64,
+}
+
+impl Alarm {
+    pub const fn new(name: &'static str, raise_percent: usize, clear_percent: usize) -> Self {
+        Self {
+            name,
+            raise_percent,
+            clear_percent,
+            raised: AtomicBool::new(false),
+            pending: AtomicBool::new(false),
+            used: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Record the current usage, raising or clearing the alarm
+    ///
+    /// Doesn't log or allocate, so it can be called with allocator locks held.
+    pub fn update(&self, used: usize, total: usize) {
+        self.used.store(used, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+
+        let percent = used.saturating_mul(100) / total.max(1);
+        let changed = if percent >= self.raise_percent {
+            self.raised
+                .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        } else if percent < self.clear_percent {
+            self.raised
+                .compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        } else {
+            false
+        };
+
+        if changed {
+            if self.raised.load(Ordering::Relaxed) {
+                self.count.fetch_add(1, Ordering::Relaxed);
+            }
+            self.pending.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.raised.load(Ordering::Relaxed)
+    }
+
+    /// Log the alarm if it changed since the last call
+    fn report(&self) {
+        if !self.pending.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        #[allow(unused_variables)]
+        let (used, total) = (
+            self.used.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        );
+        if self.is_raised() {
+            warn!(
+                "{} usage above {}%: {} of {} KiB in use",
+                self.name,
+                self.raise_percent,
+                used / 1024,
+                total / 1024
+            );
+        } else {
+            info!(
+                "{} usage back under {}%: {} of {} KiB in use",
+                self.name,
+                self.clear_percent,
+                used / 1024,
+                total / 1024
+            );
+        }
+    }
+}
+
+/// Kernel heap usage out of `HEAP_SIZE`
+pub static HEAP_ALARM: Alarm = Alarm::new("kernel heap", 90, 75);
+
+/// Frames held by DMA buffers out of all frames
+pub static DMA_ALARM: Alarm = Alarm::new("DMA", 25, 15);
+
+static KILLS: AtomicU64 = AtomicU64::new(0);
+/// Pid of the last task killed, 0 if none was
+static LAST_VICTIM: AtomicU64 = AtomicU64::new(0);
+
+/// Alarms and kills since boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OomStats {
+    /// Times the heap alarm was raised
+    pub heap_alarms: u64,
+    /// Times the DMA alarm was raised
+    pub dma_alarms: u64,
+    /// User tasks killed to free memory
+    pub kills: u64,
+    pub last_victim: Option<u64>,
+}
+
+pub fn stats() -> OomStats {
+    OomStats {
+        heap_alarms: HEAP_ALARM.count.load(Ordering::Relaxed),
+        dma_alarms: DMA_ALARM.count.load(Ordering::Relaxed),
+        kills: KILLS.load(Ordering::Relaxed),
+        last_victim: Some(LAST_VICTIM.load(Ordering::Relaxed)).filter(|&pid| pid != 0),
+    }
+}
+
+/// Key woken with `wake_event_waiters` when an alarm changes or a task is
+/// killed
+pub fn notify_key() -> usize {
+    &raw const KILLS as usize
+}
+
+/// Key the OOM task waits on for alarms to log
+fn alarm_key() -> usize {
+    &raw const LAST_VICTIM as usize
+}
+
+/// Wake the OOM task if an alarm changed, called from the timer interrupt
+/// handler
+pub fn timer_tick() {
+    if HEAP_ALARM.pending.load(Ordering::Relaxed) || DMA_ALARM.pending.load(Ordering::Relaxed) {
+        wake_event_waiters(alarm_key());
+    }
+}
+
+/// Start the task logging alarms
+pub fn spawn_daemon() {
+    interrupts::without_interrupts(|| kcreate_task(oom_task, "oom"));
+}
+
+fn oom_task() -> ! {
+    loop {
+        interrupts::disable();
+        wait_for_event(alarm_key());
+
+        HEAP_ALARM.report();
+        DMA_ALARM.report();
+        interrupts::without_interrupts(|| wake_event_waiters(notify_key()));
+    }
+}
+
+/// The task to kill first
+fn pick_victim(tasks: &[UserTaskMemory]) -> Option<UserTaskMemory> {
+    tasks
+        .iter()
+        .copied()
+        .min_by_key(|task| (task.priority, Reverse(task.frames)))
+}
+
+/// Kill a user task to free memory after an allocation at `site` ran out of
+/// frames
+///
+/// Returns true if a task was killed and the allocation is worth retrying.
+/// Must be called without `FRAME_ALLOCATOR` or `PAGE_TABLE` held.
+#[allow(unused_variables)]
+pub fn out_of_memory(site: &str) -> bool {
+    let (free, total) = interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().as_ref().map_or((0, 0), |allocator| {
+            (allocator.free_frames(), allocator.total_frames())
+        })
+    });
+    warn!(
+        "out of memory in {}: {} of {} frames free",
+        site, free, total
+    );
+
+    let Some(victim) = pick_victim(&killable_user_tasks()) else {
+        warn!("out of memory: no user task left to kill");
+        return false;
+    };
+    // keep the victim from running while what it holds is released, its
+    // address space goes last since requests in flight may still use it
+    if let Err(e) = stop_task(victim.pid) {
+        warn!("out of memory: failed to kill task {}: {:?}", victim.pid, e);
+        return false;
+    }
+    uring::release(victim.pid);
+    fs::release(victim.pid);
+    vma::release(victim.pid);
+    trace::release(victim.pid);
+    block::sched::release(victim.pid);
+    if let Err(e) = kill_task(victim.pid) {
+        warn!("out of memory: failed to kill task {}: {:?}", victim.pid, e);
+        return false;
+    }
+
+    KILLS.fetch_add(1, Ordering::Relaxed);
+    LAST_VICTIM.store(victim.pid, Ordering::Relaxed);
+    warn!(
+        "out of memory: killed task {} ({}) holding {} KiB",
+        victim.pid,
+        victim.name,
+        victim.frames * 4
+    );
+    interrupts::without_interrupts(|| wake_event_waiters(notify_key()));
+    true
+}
@@ -4,10 +4,14 @@ use core::ptr::NonNull;
 use crate::debug;
 use crate::{
     info,
+    memory::irqsafe::{AllocationKind, check_allocation},
+    memory::reclaim,
+    memory::reserved,
+    sync::Mutex,
     memory::freelist::{DoubleFreeList, DoubleFreeListLink, DoubleFreeListNode},
 };
-use limine::memory_map::{Entry, EntryType};
-use spin::Mutex;
+use limine::memory_map::Entry;
+use spin::Once;
 use x86_64::{
     PhysAddr, VirtAddr,
     structures::paging::{
@@ -15,8 +19,17 @@ use x86_64::{
     },
 };
 
-pub static FRAME_ALLOCATOR: Mutex<Option<FrameBuddyAllocatorForest>> = Mutex::new(None);
-pub static PAGE_TABLE: Mutex<Option<OffsetPageTable>> = Mutex::new(None);
+/// Lock order: `PAGE_TABLE` is always taken before `FRAME_ALLOCATOR`
+pub static FRAME_ALLOCATOR: Mutex<Option<FrameBuddyAllocatorForest>> =
+    Mutex::new("FRAME_ALLOCATOR", None);
+pub static PAGE_TABLE: Mutex<Option<OffsetPageTable>> = Mutex::new("PAGE_TABLE", None);
+
+/// Smallest buddy allocator in the forest, in frames
+pub const MIN_ALLOCATOR_FRAMES: usize = 0b10000;
+
+/// The memory map from the bootloader, copied out of bootloader memory by
+/// `bootloader::reclaim`
+pub static MEMORY_MAP: Once<&'static [&'static Entry]> = Once::new();
 
 /// statically fills the page list with entries
 ///
@@ -39,38 +52,39 @@ pub unsafe fn fill_page_list(entries: &[&Entry], hhdm_offset: usize) {
         "DoubleFreeListNode must be aligned to 32 bytes"
     );
 
-    for entry in entries {
-        debug!(
-            "Processing entry: base = {:#x}, length = {:#x}, type = {:?}",
-            entry.base,
-            entry.length,
-            entry.entry_type == EntryType::USABLE
-        );
-        if !(entry.entry_type == EntryType::USABLE && entry.base != 0 && entry.length > 4096 * 4) {
-            debug!("Skipping entry: not usable or too small");
-            continue;
+    reserved::for_each_usable(entries, |start, end| {
+        debug!("Processing usable range: {:#x}-{:#x}", start, end);
+        if start == 0 || end - start <= 4096 * 4 {
+            debug!("Skipping range: too small");
+            return;
         }
 
-        let entry_base = entry.base as usize + hhdm_offset;
-        let needed_entries = entry.length as usize / 4096;
-
-        (0..needed_entries).for_each(|i| {
-            let offset = i * align_of::<DoubleFreeListNode>();
-            let ptr =
-                unsafe { (entry_base as *mut u8).add(offset) as usize } as *mut DoubleFreeListNode;
-            unsafe {
-                ptr.write(DoubleFreeListNode::new(
-                    DoubleFreeListLink::new(None, None),
-                    None,
-                ));
-            }
-        });
+        let base = start as usize + hhdm_offset;
+        let needed_entries = (end - start) as usize / 4096;
+        unsafe { write_page_list(base, needed_entries) };
+    });
+}
 
-        debug!(
-            "wrote to page list at {:#x} with {} entries",
-            entry_base, needed_entries
-        );
-    }
+/// Write empty page list entries for `frames` frames at `virt_base`
+///
+/// # Safety
+/// The memory must be mapped and unused.
+unsafe fn write_page_list(virt_base: usize, frames: usize) {
+    (0..frames).for_each(|i| {
+        let offset = i * align_of::<DoubleFreeListNode>();
+        let ptr = unsafe { (virt_base as *mut u8).add(offset) as usize } as *mut DoubleFreeListNode;
+        unsafe {
+            ptr.write(DoubleFreeListNode::new(
+                DoubleFreeListLink::new(None, None),
+                None,
+            ));
+        }
+    });
+
+    debug!(
+        "wrote to page list at {:#x} with {} entries",
+        virt_base, frames
+    );
 }
 
 /// A frame buddy allocator that manages multiple free lists for frames
@@ -211,6 +225,19 @@ impl<const L: usize> FrameBuddyAllocator<L> {
         Some(actual_page as u64)
     }
 
+    /// Calls `f` with the virtual address and size in pages of every free block.
+    fn for_each_free_block(&self, f: &mut impl FnMut(usize, usize)) {
+        for (level, list) in self.free_lists[..self.levels].iter().enumerate() {
+            let mut node = list.links.next;
+            while let Some(current) = node {
+                let block_index =
+                    (current.as_ptr() as usize - self.page_list_start) / align_of::<DoubleFreeListNode>();
+                f(self.page_list_start + block_index * 4096, self.block_size(level));
+                node = unsafe { current.as_ref() }.links.next;
+            }
+        }
+    }
+
     /// Deallocates a contiguous block of frames, merging with buddies if possible.
     ///
     /// # Safety
@@ -237,6 +264,10 @@ impl<const L: usize> FrameBuddyAllocator<L> {
 pub struct FrameBuddyAllocatorForest<const N: usize = 100, const L: usize = 26> {
     allocators: [Option<FrameBuddyAllocator<L>>; N],
     count: usize,
+    /// frames managed by all allocators
+    total_frames: usize,
+    /// frames not handed out
+    free_frames: usize,
     pub hddm_offset: u64,
 }
 
@@ -251,16 +282,13 @@ impl<const N: usize, const L: usize> FrameBuddyAllocatorForest<N, L> {
 
         let mut allocators = [const { None }; N];
         let mut count = 0;
+        let mut total_frames = 0;
         let mut allocator_configs = [(0usize, 0usize, 0usize, 0usize); N]; // (virt_start, frames, size_bytes, list_start)
         let mut allocator_count = 0;
 
-        for region in memory_regions {
-            if region.entry_type != EntryType::USABLE {
-                continue;
-            }
-
-            let start = region.base as usize;
-            let length = region.length as usize;
+        reserved::for_each_usable(memory_regions, |start, end| {
+            let start = start as usize;
+            let length = (end - start as u64) as usize;
 
             let total_frames = length / 4096;
 
@@ -268,7 +296,7 @@ impl<const N: usize, const L: usize> FrameBuddyAllocatorForest<N, L> {
                 (total_frames * align_of::<DoubleFreeListNode>()).next_multiple_of(4096);
 
             let mut current_start = start + pages_reserved_for_indexing;
-            let mut remaining_frames = total_frames - pages_reserved_for_indexing / 4096;
+            let mut remaining_frames = total_frames.saturating_sub(pages_reserved_for_indexing / 4096);
 
             while remaining_frames >= min_allocator_frames {
                 if allocator_count >= N {
@@ -299,7 +327,7 @@ impl<const N: usize, const L: usize> FrameBuddyAllocatorForest<N, L> {
                     .expect("Current start address overflow");
                 remaining_frames -= allocator_frames;
             }
-        }
+        });
 
         allocator_configs[..allocator_count]
             .sort_unstable_by_key(|&(_, frames, _, _)| core::cmp::Reverse(frames));
@@ -320,6 +348,7 @@ impl<const N: usize, const L: usize> FrameBuddyAllocatorForest<N, L> {
                     FrameBuddyAllocator::<L>::new(levels, virt_start, virt_end, start)
                 });
                 count += 1;
+                total_frames += frames;
             } else {
                 panic!("Allocator requires {} levels but maximum is {}", levels, L);
             }
@@ -328,12 +357,70 @@ impl<const N: usize, const L: usize> FrameBuddyAllocatorForest<N, L> {
         Self {
             allocators,
             count,
+            total_frames,
+            free_frames: total_frames,
             hddm_offset,
         }
     }
 }
 
 impl<const N: usize, const L: usize> FrameBuddyAllocatorForest<N, L> {
+    /// adds the frames from `start` to `end` to the forest, returning how many
+    /// were added
+    ///
+    /// The start of the range holds the page list of its allocators, and
+    /// frames left over once no further allocator of at least
+    /// `min_allocator_frames` fits are not added. Ranges too large for one
+    /// allocator of `L` levels are split over several. Used for memory freed
+    /// after boot, like the bootloader's.
+    ///
+    /// # Safety
+    /// The range must be page aligned, mapped in the direct map and unused.
+    pub unsafe fn add_region(
+        &mut self,
+        start: PhysAddr,
+        end: PhysAddr,
+        min_allocator_frames: usize,
+    ) -> usize {
+        let hddm_offset = self.hddm_offset as usize;
+        let start = start.as_u64() as usize;
+        let total_frames = (end.as_u64() as usize).saturating_sub(start) / 4096;
+        let pages_reserved_for_indexing =
+            (total_frames * align_of::<DoubleFreeListNode>()).next_multiple_of(4096);
+        if total_frames * 4096 <= pages_reserved_for_indexing {
+            return 0;
+        }
+
+        unsafe { write_page_list(start + hddm_offset, total_frames) };
+
+        let mut current_start = start + pages_reserved_for_indexing;
+        let mut remaining_frames = total_frames - pages_reserved_for_indexing / 4096;
+        let mut added = 0;
+        while remaining_frames >= min_allocator_frames && self.count < N {
+            let allocator_frames: usize = 1 << remaining_frames.ilog2().min(L as u32 - 1);
+            let levels = allocator_frames.trailing_zeros() as usize + 1;
+
+            let virt_start = current_start + hddm_offset;
+            self.allocators[self.count] = Some(unsafe {
+                FrameBuddyAllocator::<L>::new(
+                    levels,
+                    virt_start,
+                    virt_start + allocator_frames * 4096,
+                    start + hddm_offset,
+                )
+            });
+            self.count += 1;
+            added += allocator_frames;
+
+            current_start += allocator_frames * 4096;
+            remaining_frames -= allocator_frames;
+        }
+
+        self.total_frames += added;
+        self.free_frames += added;
+        added
+    }
+
     /// returns a virtual address the start of a contiguous block of frames
     #[inline]
     pub fn allocate_contiguous_pages(&mut self, pages: usize) -> Option<VirtAddr> {
@@ -341,15 +428,32 @@ impl<const N: usize, const L: usize> FrameBuddyAllocatorForest<N, L> {
             pages.is_power_of_two(),
             "Number of pages must be a power of two"
         );
+        check_allocation(AllocationKind::Frame, pages * 4096);
 
         for allocator in self.allocators[..self.count].iter_mut().flatten() {
             if let Some(virt_addr) = allocator.allocate_contiguous_frames(pages) {
+                self.allocated(pages);
                 return Some(VirtAddr::new(virt_addr));
             }
         }
         None
     }
 
+    /// frames managed by the allocators
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// frames not allocated
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    fn allocated(&mut self, frames: usize) {
+        self.free_frames -= frames;
+        reclaim::check_watermarks(self.free_frames, self.total_frames);
+    }
+
     /// deallocates a contiguous block of frames at the given virtual address
     /// # Safety
     /// The caller must ensure that the address was allocated by this allocator and is not in use.
@@ -364,6 +468,7 @@ impl<const N: usize, const L: usize> FrameBuddyAllocatorForest<N, L> {
         for allocator in self.allocators[..self.count].iter_mut().flatten() {
             if addr >= allocator.virt_start && addr < allocator.virt_end {
                 unsafe { allocator.deallocate_contiguous_frames(virt_addr.as_u64(), pages) };
+                self.free_frames += pages;
                 return;
             }
         }
@@ -383,6 +488,60 @@ impl<const N: usize, const L: usize> FrameBuddyAllocatorForest<N, L> {
         })
     }
 
+    /// allocates contiguous physical frames ending below `limit`
+    ///
+    /// Used for memory the CPU or firmware must reach before long mode is up,
+    /// like real mode trampolines.
+    pub fn allocate_contiguous_frames_below(
+        &mut self,
+        frames: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysAddr> {
+        assert!(
+            frames.is_power_of_two(),
+            "Number of frames must be a power of two"
+        );
+        check_allocation(AllocationKind::Frame, frames * 4096);
+
+        let hddm_offset = self.hddm_offset as usize;
+        for allocator in self.allocators[..self.count].iter_mut().flatten() {
+            if allocator.virt_end - hddm_offset > limit.as_u64() as usize {
+                continue;
+            }
+            if let Some(virt_addr) = allocator.allocate_contiguous_frames(frames) {
+                self.allocated(frames);
+                return Some(PhysAddr::new(virt_addr - self.hddm_offset));
+            }
+        }
+        None
+    }
+
+    /// calls `f` with the physical address and size in frames of every free block
+    ///
+    /// Used to find the frames in use, e.g. when taking a hibernation image.
+    pub fn for_each_free_block(&self, mut f: impl FnMut(PhysAddr, usize)) {
+        for allocator in self.allocators[..self.count].iter().flatten() {
+            allocator.for_each_free_block(&mut |virt_addr, frames| {
+                f(PhysAddr::new(virt_addr as u64 - self.hddm_offset), frames)
+            });
+        }
+    }
+
+    /// calls `f` with the start and end physical address of every allocator,
+    /// plus the address its buddy blocks are aligned to
+    ///
+    /// Used to find blocks that could be freed up by compaction.
+    pub fn for_each_region(&self, mut f: impl FnMut(PhysAddr, PhysAddr, PhysAddr)) {
+        for allocator in self.allocators[..self.count].iter().flatten() {
+            let phys = |virt_addr: usize| PhysAddr::new(virt_addr as u64 - self.hddm_offset);
+            f(
+                phys(allocator.virt_start),
+                phys(allocator.virt_end),
+                phys(allocator.page_list_start),
+            );
+        }
+    }
+
     /// deallocates contiguous physical frames
     ///
     /// # Safety
@@ -424,12 +583,12 @@ impl<const N: usize, const L: usize> FrameDeallocator<Size4KiB>
 /// This function must only be called once, before any frame allocations occur.
 ///
 /// reserved_region is a tuple of (start, end) in bytes, which is reserved for the page list.
-pub unsafe fn init_frame_allocator(memory_map: &'static [&'static Entry], hddm_offset: u64) {
+pub unsafe fn init_frame_allocator(memory_map: &[&Entry], hddm_offset: u64) {
     if FRAME_ALLOCATOR.lock().is_some() {
         panic!("Frame allocator already initialized");
     }
 
-    let allocator = FrameBuddyAllocatorForest::init(memory_map, 0b10000, hddm_offset);
+    let allocator = FrameBuddyAllocatorForest::init(memory_map, MIN_ALLOCATOR_FRAMES, hddm_offset);
     FRAME_ALLOCATOR.lock().replace(allocator);
 
     info!("frame allocator initialized");
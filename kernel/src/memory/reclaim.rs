@@ -0,0 +1,163 @@
+//! Background page reclaim
+//!
+//! The reclaim task keeps a reserve of free frames so allocations don't stall
+//! on reclaim at the moment memory runs out. Once an allocation leaves fewer
+//! than the low watermark of frames free, the next timer tick wakes the task,
+//! which asks the registered shrinkers to give frames back until the high
+//! watermark is reached or none of them has anything left to free.
+//!
+//! Subsystems holding memory they can drop or write back, like caches,
+//! register a `Shrinker`. Dirty data is written back by the shrinker itself
+//! before it frees the frames holding it.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    debug,
+    memory::FRAME_ALLOCATOR,
+    sync::Mutex,
+    tasks::scheduler::{kcreate_task, wait_for_event, wake_event_waiters},
+};
+
+/// Fraction of all frames below which the reclaim task is woken
+const LOW_WATERMARK_DIVISOR: usize = 64;
+
+/// Fraction of all frames the reclaim task frees up to
+const HIGH_WATERMARK_DIVISOR: usize = 32;
+
+/// Something holding frames it can give back under memory pressure
+pub struct Shrinker {
+    pub name: &'static str,
+    /// Frames that could be freed right now
+    pub count: fn() -> usize,
+    /// Free up to this many frames, returning how many were freed
+    pub scan: fn(usize) -> usize,
+}
+
+static SHRINKERS: Mutex<Vec<&'static Shrinker>> = Mutex::new("SHRINKERS", Vec::new());
+
+/// Set by the frame allocator when free frames drop below the low watermark
+static PRESSURE: AtomicBool = AtomicBool::new(false);
+
+static WAKEUPS: AtomicU64 = AtomicU64::new(0);
+static RECLAIMED: AtomicU64 = AtomicU64::new(0);
+
+/// Reclaim activity since boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReclaimStats {
+    /// Times the reclaim task was woken
+    pub wakeups: u64,
+    /// Frames freed by shrinkers
+    pub reclaimed: u64,
+}
+
+pub fn stats() -> ReclaimStats {
+    ReclaimStats {
+        wakeups: WAKEUPS.load(Ordering::Relaxed),
+        reclaimed: RECLAIMED.load(Ordering::Relaxed),
+    }
+}
+
+/// Make a shrinker available to the reclaim task
+pub fn register_shrinker(shrinker: &'static Shrinker) {
+    interrupts::without_interrupts(|| SHRINKERS.lock().push(shrinker));
+}
+
+/// Low and high watermarks for `total_frames` frames
+pub fn watermarks(total_frames: usize) -> (usize, usize) {
+    (
+        total_frames / LOW_WATERMARK_DIVISOR,
+        total_frames / HIGH_WATERMARK_DIVISOR,
+    )
+}
+
+/// Called by the frame allocator after handing out frames
+///
+/// Only records the pressure, since the allocator lock can't be held while
+/// waking a task.
+pub fn check_watermarks(free_frames: usize, total_frames: usize) {
+    if free_frames < watermarks(total_frames).0 {
+        PRESSURE.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Wake the reclaim task if memory is low, called from the timer interrupt
+/// handler
+pub fn timer_tick() {
+    if PRESSURE.swap(false, Ordering::Relaxed) {
+        wake_event_waiters(event_key());
+    }
+}
+
+fn event_key() -> usize {
+    &raw const PRESSURE as usize
+}
+
+/// Free and total frames
+fn frame_counts() -> (usize, usize) {
+    FRAME_ALLOCATOR.lock().as_ref().map_or((0, 0), |allocator| {
+        (allocator.free_frames(), allocator.total_frames())
+    })
+}
+
+/// Start the reclaim task
+pub fn spawn_daemon() {
+    interrupts::without_interrupts(|| kcreate_task(reclaim_task, "reclaim"));
+}
+
+fn reclaim_task() -> ! {
+    loop {
+        // a wakeup missed while balancing is signalled again by the next
+        // allocation under the low watermark
+        interrupts::disable();
+        wait_for_event(event_key());
+
+        // normal tasks also run while waiting, so check the pressure is real
+        let (free, total) = interrupts::without_interrupts(frame_counts);
+        if free < watermarks(total).0 {
+            WAKEUPS.fetch_add(1, Ordering::Relaxed);
+            balance();
+        }
+    }
+}
+
+/// Run the shrinkers until the high watermark is reached
+///
+/// Gives up once no shrinker frees anything.
+fn balance() {
+    loop {
+        let (free, total) = interrupts::without_interrupts(frame_counts);
+        let high = watermarks(total).1;
+        if free >= high {
+            return;
+        }
+
+        let shrinkers = interrupts::without_interrupts(|| SHRINKERS.lock().clone());
+        let mut wanted = high - free;
+        let mut freed = 0;
+        for shrinker in shrinkers {
+            if wanted == 0 {
+                break;
+            }
+            if (shrinker.count)() == 0 {
+                continue;
+            }
+            let scanned = (shrinker.scan)(wanted);
+            debug!("reclaim: {} freed {} frame(s)", shrinker.name, scanned);
+            wanted = wanted.saturating_sub(scanned);
+            freed += scanned;
+        }
+        RECLAIMED.fetch_add(freed as u64, Ordering::Relaxed);
+
+        if freed == 0 {
+            debug!(
+                "reclaim: {} frames free, below the {} frame watermark, and nothing left to reclaim",
+                free, high
+            );
+            return;
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Physical ranges kept out of the frame allocator
+//!
+//! The frame allocator takes every `USABLE` entry of the memory map, so a
+//! buggy map marking device memory usable would have frames handed out that
+//! alias the framebuffer or device registers. Before the allocator is built,
+//! `init` records the ranges that must never be handed out:
+//!
+//! - the framebuffers given by Limine
+//! - the local APIC registers, at the base in `IA32_APIC_BASE`
+//! - the interrupt window at 0xFEC0_0000-0xFEFF_FFFF, holding the default
+//!   I/O APIC, HPET and local APIC addresses
+//! - the I/O APICs of the MADT and the ECAM regions of the MCFG, if the ACPI
+//!   tables are in the direct map
+//!
+//! A warning is logged for every one the memory map marks usable, and
+//! `for_each_usable` walks usable memory with all of them cut out.
+//!
+//! Firmware often keeps the ACPI tables in memory the direct map doesn't
+//! cover, so `check` is called again for the I/O APICs and ECAM regions once
+//! ACPI is up, warning if one of them lies in memory the allocator manages.
+
+use limine::memory_map::{Entry, EntryType};
+use x86_64::{PhysAddr, registers::model_specific::Msr};
+
+use crate::{debug, info, memory::FRAME_ALLOCATOR, sync::Mutex, warn};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Most ranges recorded, there is no heap yet when they are
+const MAX_RESERVED: usize = 32;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+
+/// Physical address bits of `IA32_APIC_BASE`
+const APIC_BASE_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Where chipsets put the I/O APICs, the HPET and the local APICs
+const INTERRUPT_WINDOW: (u64, u64) = (0xFEC0_0000, 0xFF00_0000);
+
+/// Size of an I/O APIC's registers
+const IOAPIC_SIZE: u64 = 0x20;
+
+/// A physical range, page aligned
+#[derive(Debug, Clone, Copy)]
+pub struct Range {
+    pub start: u64,
+    pub end: u64,
+    pub what: &'static str,
+}
+
+impl Range {
+    /// The pages covering `len` bytes at `start`
+    pub fn new(start: u64, len: u64, what: &'static str) -> Self {
+        Self {
+            start: start & !(PAGE_SIZE - 1),
+            end: start.saturating_add(len).next_multiple_of(PAGE_SIZE),
+            what,
+        }
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+struct Reserved {
+    ranges: [Range; MAX_RESERVED],
+    count: usize,
+}
+
+static RESERVED: Mutex<Reserved> = Mutex::new(
+    "RESERVED_RANGES",
+    Reserved {
+        ranges: [Range {
+            start: 0,
+            end: 0,
+            what: "",
+        }; MAX_RESERVED],
+        count: 0,
+    },
+);
+
+/// Keep `len` bytes at physical address `start` out of the frame allocator
+///
+/// Only has an effect before the allocator is built.
+pub fn reserve(start: u64, len: u64, what: &'static str) {
+    if len == 0 {
+        return;
+    }
+    let mut reserved = RESERVED.lock();
+    if reserved.count == MAX_RESERVED {
+        warn!(
+            "too many reserved ranges, not reserving the {} at {:#x}",
+            what, start
+        );
+        return;
+    }
+    let count = reserved.count;
+    reserved.ranges[count] = Range::new(start, len, what);
+    reserved.count += 1;
+}
+
+/// Record the local APIC, the interrupt window and the ACPI devices, then
+/// warn about every reserved range the memory map marks usable
+///
+/// Framebuffers must have been given to `reserve` already.
+pub fn init(entries: &[&Entry], hhdm_offset: u64, rsdp_addr: Option<usize>) {
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() } & APIC_BASE_MASK;
+    reserve(apic_base, PAGE_SIZE, "local APIC");
+    let (start, end) = INTERRUPT_WINDOW;
+    reserve(start, end - start, "interrupt window");
+
+    let acpi = Acpi {
+        entries,
+        hhdm_offset,
+    };
+    if rsdp_addr
+        .and_then(|rsdp_addr| acpi.scan(rsdp_addr as u64))
+        .is_none()
+    {
+        info!("ACPI tables not readable yet, checking devices once ACPI is up");
+    }
+
+    let reserved = RESERVED.lock();
+    for range in &reserved.ranges[..reserved.count] {
+        debug!(
+            "reserved {:#x}-{:#x} for the {}",
+            range.start, range.end, range.what
+        );
+        for entry in entries {
+            if entry.entry_type == EntryType::USABLE
+                && range.overlaps(entry.base, entry.base + entry.length)
+            {
+                warn!(
+                    "memory map marks {:#x}-{:#x} usable, but it overlaps the {} at {:#x}-{:#x}, leaving that out",
+                    entry.base,
+                    entry.base + entry.length,
+                    range.what,
+                    range.start,
+                    range.end
+                );
+            }
+        }
+    }
+}
+
+/// Call `f` with the start and end of every usable range of the memory map,
+/// page aligned and with the reserved ranges cut out
+pub fn for_each_usable(entries: &[&Entry], mut f: impl FnMut(u64, u64)) {
+    let reserved = RESERVED.lock();
+    let ranges = &reserved.ranges[..reserved.count];
+    for entry in entries {
+        if entry.entry_type != EntryType::USABLE {
+            continue;
+        }
+        let start = entry.base.next_multiple_of(PAGE_SIZE);
+        let end = (entry.base + entry.length) & !(PAGE_SIZE - 1);
+        cut(start, end, ranges, &mut f);
+    }
+}
+
+/// Call `f` with the pieces of `start..end` outside all of `ranges`, in
+/// order
+pub fn cut(start: u64, end: u64, ranges: &[Range], f: &mut impl FnMut(u64, u64)) {
+    let mut cursor = start;
+    while cursor < end {
+        let next = ranges
+            .iter()
+            .filter(|range| range.overlaps(cursor, end))
+            .min_by_key(|range| range.start);
+        let Some(next) = next else {
+            f(cursor, end);
+            return;
+        };
+        if next.start > cursor {
+            f(cursor, next.start);
+        }
+        cursor = next.end;
+    }
+}
+
+/// Warn if a device's registers, found after the frame allocator was built,
+/// lie in memory it manages
+///
+/// The frames may already have been handed out, so nothing but the warning
+/// can be done about it.
+pub fn check(start: PhysAddr, len: u64, what: &'static str) {
+    let range = Range::new(start.as_u64(), len, what);
+    {
+        let reserved = RESERVED.lock();
+        if reserved.ranges[..reserved.count]
+            .iter()
+            .any(|known| known.start <= range.start && range.end <= known.end)
+        {
+            return;
+        }
+    }
+
+    let mut overlaps = false;
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_ref() {
+        allocator.for_each_region(|_, end, list_start| {
+            overlaps |= range.overlaps(list_start.as_u64(), end.as_u64());
+        });
+    }
+    if overlaps {
+        warn!(
+            "the {} at {:#x}-{:#x} lies in memory given to the frame allocator, the memory map marks it usable",
+            what, range.start, range.end
+        );
+    }
+}
+
+/// Reads ACPI tables through the direct map, before anything can be mapped
+struct Acpi<'a> {
+    entries: &'a [&'a Entry],
+    hhdm_offset: u64,
+}
+
+impl Acpi<'_> {
+    /// Whether `len` bytes at `phys` are in the direct map
+    ///
+    /// Limine maps every entry of the memory map but reserved and bad
+    /// memory.
+    fn is_mapped(&self, phys: u64, len: u64) -> bool {
+        self.entries.iter().any(|entry| {
+            entry.entry_type != EntryType::RESERVED
+                && entry.entry_type != EntryType::BAD_MEMORY
+                && entry.base <= phys
+                && phys.saturating_add(len) <= entry.base + entry.length
+        })
+    }
+
+    fn read<T: Copy>(&self, phys: u64) -> Option<T> {
+        if !self.is_mapped(phys, size_of::<T>() as u64) {
+            return None;
+        }
+        Some(unsafe { ((phys + self.hhdm_offset) as *const T).read_unaligned() })
+    }
+
+    /// A table's length, if all of it is mapped
+    fn table_length(&self, phys: u64) -> Option<u64> {
+        let length = self.read::<u32>(phys + 4)? as u64;
+        self.is_mapped(phys, length).then_some(length)
+    }
+
+    /// Reserve the I/O APICs and ECAM regions, or `None` if the tables
+    /// couldn't be read
+    fn scan(&self, rsdp: u64) -> Option<()> {
+        if self.read::<[u8; 8]>(rsdp)? != *b"RSD PTR " {
+            warn!("no RSDP signature at {:#x}", rsdp);
+            return Some(());
+        }
+        let (root, entry_size) = if self.read::<u8>(rsdp + 15)? >= 2 {
+            (self.read::<u64>(rsdp + 24)?, 8)
+        } else {
+            (self.read::<u32>(rsdp + 16)? as u64, 4)
+        };
+
+        let length = self.table_length(root)?;
+        let mut entry = root + 36;
+        while entry + entry_size <= root + length {
+            let table = if entry_size == 8 {
+                self.read::<u64>(entry)?
+            } else {
+                self.read::<u32>(entry)? as u64
+            };
+            match &self.read::<[u8; 4]>(table)? {
+                b"APIC" => self.scan_madt(table)?,
+                b"MCFG" => self.scan_mcfg(table)?,
+                _ => {}
+            }
+            entry += entry_size;
+        }
+        Some(())
+    }
+
+    fn scan_madt(&self, madt: u64) -> Option<()> {
+        const IOAPIC: u8 = 1;
+
+        let end = madt + self.table_length(madt)?;
+        let mut entry = madt + 44;
+        while entry + 2 <= end {
+            let [kind, length] = self.read::<[u8; 2]>(entry)?;
+            if length < 2 {
+                break;
+            }
+            if kind == IOAPIC && length >= 12 {
+                reserve(self.read::<u32>(entry + 4)? as u64, IOAPIC_SIZE, "I/O APIC");
+            }
+            entry += length as u64;
+        }
+        Some(())
+    }
+
+    fn scan_mcfg(&self, mcfg: u64) -> Option<()> {
+        let end = mcfg + self.table_length(mcfg)?;
+        let mut entry = mcfg + 44;
+        while entry + 16 <= end {
+            let base = self.read::<u64>(entry)?;
+            let end_bus = self.read::<u8>(entry + 11)?;
+            // the base is for bus 0, though `mcfg` maps it from the start
+            // bus, so both are covered
+            reserve(base, (end_bus as u64 + 1) << 20, "ECAM region");
+            entry += 16;
+        }
+        Some(())
+    }
+}
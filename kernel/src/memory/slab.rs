@@ -0,0 +1,79 @@
+use core::{alloc::Layout, ptr::NonNull};
+
+use super::freelist::FreeList;
+
+/// Object sizes served by the slab caches, one cache per size.
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Size of the pages the caches are refilled with.
+pub const SLAB_SIZE: usize = 4096;
+
+/// A slab allocator for small, fixed-size heap objects.
+///
+/// Each size class keeps a free list of objects carved out of 4 KiB slabs.
+/// Slabs are obtained from a backing allocator (the buddy heap) and are never
+/// returned to it, so the caches only ever grow to the high-water mark of
+/// each size class. Requests larger than the biggest size class are not
+/// handled here and must go directly to the backing allocator.
+pub struct SlabAlloc {
+    caches: [FreeList; SIZE_CLASSES.len()],
+}
+
+// Safety: All access to the caches is protected by a Mutex in the Locked wrapper
+unsafe impl Send for SlabAlloc {}
+unsafe impl Sync for SlabAlloc {}
+
+impl Default for SlabAlloc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SlabAlloc {
+    /// Creates a slab allocator with empty caches
+    pub const fn new() -> Self {
+        Self {
+            caches: [FreeList::new(); SIZE_CLASSES.len()],
+        }
+    }
+
+    /// Returns the index of the smallest size class fitting `layout`, or None
+    /// if the allocation is too large for the slab caches.
+    pub fn size_class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES.iter().position(|&class| class >= size)
+    }
+
+    /// Pops an object from the cache of the given size class.
+    ///
+    /// `refill` is called to obtain a fresh slab when the cache is empty.
+    pub fn allocate(
+        &mut self,
+        class: usize,
+        refill: impl FnOnce() -> Option<NonNull<u8>>,
+    ) -> Option<NonNull<u8>> {
+        if self.caches[class].is_empty() {
+            let slab = refill()?;
+            let object_size = SIZE_CLASSES[class];
+            for offset in (0..SLAB_SIZE).step_by(object_size) {
+                let object = unsafe { slab.add(offset) };
+                self.caches[class].push(object.cast());
+            }
+        }
+
+        self.caches[class].pop().map(NonNull::cast)
+    }
+
+    /// Returns an object to the cache of the given size class.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by `allocate` for the same size class.
+    pub unsafe fn deallocate(&mut self, class: usize, ptr: NonNull<u8>) {
+        self.caches[class].push(ptr.cast());
+    }
+
+    /// Returns the number of free objects cached for each size class
+    pub fn free_objects(&self) -> [(usize, usize); SIZE_CLASSES.len()] {
+        core::array::from_fn(|i| (SIZE_CLASSES[i], self.caches[i].len()))
+    }
+}
@@ -23,3 +23,95 @@ fn test_growing_vec() {
         v.push(i);
     }
 }
+
+#[test_case]
+fn test_emergency_pool() {
+    use core::alloc::Layout;
+
+    use super::irqsafe::{EMERGENCY_BLOCK_SIZE, EMERGENCY_POOL};
+
+    let in_use = EMERGENCY_POOL.in_use();
+    let layout = Layout::from_size_align(64, 16).unwrap();
+    let a = EMERGENCY_POOL.alloc(layout).unwrap();
+    let b = EMERGENCY_POOL.alloc(layout).unwrap();
+    assert_ne!(a, b);
+    assert!(EMERGENCY_POOL.contains(a) && EMERGENCY_POOL.contains(b));
+    assert_eq!(EMERGENCY_POOL.in_use(), in_use + 2);
+
+    let too_big = Layout::from_size_align(EMERGENCY_BLOCK_SIZE + 1, 8).unwrap();
+    assert!(EMERGENCY_POOL.alloc(too_big).is_none());
+
+    unsafe {
+        EMERGENCY_POOL.dealloc(a);
+        EMERGENCY_POOL.dealloc(b);
+    }
+    assert_eq!(EMERGENCY_POOL.in_use(), in_use);
+}
+
+#[test_case]
+fn test_snapshot_catches_heap_leak() {
+    use alloc::boxed::Box;
+
+    use crate::testing::snapshot::{Difference, Resource, Snapshot};
+
+    let before = Snapshot::take();
+    let leaked = Box::leak(Box::new([0u64; 8]));
+    let differences = before.diff(&Snapshot::take());
+    assert!(differences.iter().any(|difference| matches!(
+        difference,
+        Difference::Count { resource: Resource::Heap, before, after } if after - before == 64
+    )));
+
+    drop(unsafe { Box::from_raw(leaked) });
+    before.assert_unchanged(&[Resource::Heap, Resource::Frames]);
+}
+
+#[test_case]
+fn test_reserved_ranges_cut_out() {
+    use alloc::vec::Vec;
+
+    use super::reserved::{Range, cut};
+
+    let ranges = [
+        Range::new(0x5000, 0x1000, "b"),
+        Range::new(0x2000, 0x800, "a"),
+        Range::new(0x4800, 0x1000, "c"),
+        Range::new(0x9000, 0x1000, "outside"),
+    ];
+    let mut pieces = Vec::new();
+    cut(0x1000, 0x8000, &ranges, &mut |start, end| pieces.push((start, end)));
+    assert_eq!(
+        pieces,
+        [(0x1000, 0x2000), (0x3000, 0x4000), (0x6000, 0x8000)]
+    );
+}
+
+#[test_case]
+fn test_bootmem_bumps_aligned() {
+    use super::bootmem::Bump;
+
+    let mut bump = Bump::new(0x10_0000, 0x10_3000);
+    assert_eq!(bump.alloc(24, 8), Some(0x10_0000));
+    assert_eq!(bump.alloc(64, 64), Some(0x10_0040));
+    assert_eq!(bump.alloc(0x1000, 0x1000), Some(0x10_1000));
+    assert_eq!(bump.alloc(0x2000, 8), None);
+    assert_eq!(bump.allocations, 3);
+    // only whole pages after the last allocation are given back
+    assert_eq!(bump.unused_pages(), (0x10_2000, 0x10_3000));
+}
+
+#[test_case]
+fn test_user_copy_of_unmapped_page_fails() {
+    use super::uaccess::{copy_from_user, copy_to_user, in_user_window};
+    use crate::error::KError;
+
+    // in the lower half, where nothing is mapped while the tests run
+    let unmapped = 0x0000_7000_dead_0000;
+    let mut buffer = [0u8; 16];
+    assert_eq!(
+        copy_from_user(&mut buffer, unmapped),
+        Err(KError::BadAddress)
+    );
+    assert_eq!(copy_to_user(unmapped, &buffer), Err(KError::BadAddress));
+    assert!(!in_user_window());
+}
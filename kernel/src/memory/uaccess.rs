@@ -0,0 +1,206 @@
+//! Access to user memory
+//!
+//! SMEP and SMAP are turned on at boot when the CPU has them, so the kernel
+//! faults when it executes a user page, or reads or writes one outside a
+//! user-access window. A user pointer dereferenced by mistake then faults
+//! loudly instead of letting a task point the kernel at memory it picked.
+//! `copy_from_user` and `copy_to_user` are the only code opening a window,
+//! with `stac` and `clac` around the copy, after checking that the range is
+//! in the lower half. Everything else reaching user memory goes through them.
+//!
+//! A range in the lower half may still not be mapped. The copy is a single
+//! `rep movsb` in `uaccess_copy`, and a page fault on it that isn't the
+//! user stack growing is fixed up by the page fault handler: it resumes at
+//! the end of the copy, which then reports the bytes it didn't copy, and
+//! the caller gets `KError::BadAddress`. Whether a window is open is
+//! tracked per CPU, since RFLAGS.AC only means anything with SMAP, and
+//! windows are opened with interrupts disabled so the flag can't leak to
+//! another task.
+//!
+//! UMIP is turned on too, so `sgdt`, `sidt` and the like fault in user mode
+//! instead of giving away kernel addresses.
+
+use core::{
+    arch::{
+        asm, global_asm,
+        x86_64::{__cpuid, __cpuid_count},
+    },
+    mem::size_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{vec, vec::Vec};
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    registers::control::{Cr4, Cr4Flags},
+};
+
+use crate::{cpu, error::KError, info};
+
+/// Highest user address, exclusive
+pub const USER_LIMIT: u64 = 0x0000_8000_0000_0000;
+
+/// CPUID leaf 7 feature bits
+mod features {
+    /// EBX
+    pub const SMEP: u32 = 1 << 7;
+    /// EBX
+    pub const SMAP: u32 = 1 << 20;
+    /// ECX
+    pub const UMIP: u32 = 1 << 2;
+}
+
+/// Whether SMAP is on, `stac` and `clac` are undefined without it
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// Turn on whichever of SMEP, SMAP and UMIP the CPU supports
+pub fn init() {
+    let mut flags = Cr4Flags::empty();
+    if unsafe { __cpuid(0) }.eax >= 7 {
+        let leaf = unsafe { __cpuid_count(7, 0) };
+        if leaf.ebx & features::SMEP != 0 {
+            flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+        }
+        if leaf.ebx & features::SMAP != 0 {
+            flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+        }
+        if leaf.ecx & features::UMIP != 0 {
+            flags |= Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION;
+        }
+    }
+
+    unsafe { Cr4::update(|cr4| cr4.insert(flags)) };
+    SMAP.store(
+        flags.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+        Ordering::Relaxed,
+    );
+    info!("user memory protection: {:?}", flags);
+}
+
+/// Whether SMAP is on
+pub fn smap_enabled() -> bool {
+    SMAP.load(Ordering::Relaxed)
+}
+
+/// Whether `len` bytes at `addr` are all in the lower half
+pub fn is_user_range(addr: u64, len: usize) -> bool {
+    addr.checked_add(len as u64)
+        .is_some_and(|end| end <= USER_LIMIT)
+}
+
+/// Whether the CPU running this code is copying to or from user memory
+pub fn in_user_window() -> bool {
+    cpu::this_cpu().user_access.load(Ordering::Relaxed)
+}
+
+/// Lets the kernel access user pages while alive
+///
+/// The asm has no `nomem` option, so the compiler doesn't move memory
+/// accesses across opening or closing the window.
+struct Window {
+    interrupts: bool,
+}
+
+impl Window {
+    fn open() -> Self {
+        let window = Self {
+            interrupts: interrupts::are_enabled(),
+        };
+        interrupts::disable();
+        cpu::this_cpu().user_access.store(true, Ordering::Relaxed);
+        if smap_enabled() {
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        window
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        if smap_enabled() {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+        cpu::this_cpu().user_access.store(false, Ordering::Relaxed);
+        if self.interrupts {
+            interrupts::enable();
+        }
+    }
+}
+
+global_asm!(
+    r#"
+    .global uaccess_copy
+    .global uaccess_copy_fault
+    .global uaccess_copy_fixup
+// rdi: destination, rsi: source, rdx: length; returns the bytes not copied
+uaccess_copy:
+    mov rcx, rdx
+uaccess_copy_fault:
+    rep movsb
+uaccess_copy_fixup:
+    mov rax, rcx
+    ret
+"#
+);
+
+unsafe extern "C" {
+    fn uaccess_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    static uaccess_copy_fault: u8;
+    static uaccess_copy_fixup: u8;
+}
+
+/// Where to resume after a page fault at `rip` the copy can't recover from,
+/// None if the faulting instruction isn't the copy
+pub fn fixup(rip: VirtAddr) -> Option<VirtAddr> {
+    let fault = VirtAddr::from_ptr(&raw const uaccess_copy_fault);
+    (rip == fault).then(|| VirtAddr::from_ptr(&raw const uaccess_copy_fixup))
+}
+
+/// Copy `len` bytes in a window, failing if part of the range isn't mapped
+fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), KError> {
+    let _window = Window::open();
+    match unsafe { uaccess_copy(dst, src, len) } {
+        0 => Ok(()),
+        _ => Err(KError::BadAddress),
+    }
+}
+
+/// Copy `dst.len()` bytes from user address `src`
+///
+/// The user stack grows into pages of the range that aren't mapped yet;
+/// any other page that isn't mapped fails the copy.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), KError> {
+    if !is_user_range(src, dst.len()) {
+        return Err(KError::BadAddress);
+    }
+    copy(dst.as_mut_ptr(), src as *const u8, dst.len())
+}
+
+/// Copy `src` to user address `dst`
+///
+/// The user stack grows into pages of the range that aren't mapped yet;
+/// any other page that isn't mapped fails the copy.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), KError> {
+    if !is_user_range(dst, src.len()) {
+        return Err(KError::BadAddress);
+    }
+    copy(dst as *mut u8, src.as_ptr(), src.len())
+}
+
+/// Copy `len` bytes from user address `src` into a new buffer
+pub fn read_user(src: u64, len: usize) -> Result<Vec<u8>, KError> {
+    if !is_user_range(src, len) {
+        return Err(KError::BadAddress);
+    }
+    let mut buffer = vec![0; len];
+    copy_from_user(&mut buffer, src)?;
+    Ok(buffer)
+}
+
+/// Copy `value` to user address `dst`
+pub fn put_user<T: Copy>(dst: u64, value: &T) -> Result<(), KError> {
+    let bytes =
+        unsafe { core::slice::from_raw_parts((value as *const T).cast::<u8>(), size_of::<T>()) };
+    copy_to_user(dst, bytes)
+}
@@ -0,0 +1,209 @@
+//! Virtual memory areas of user tasks
+//!
+//! Each region set up in a user task's half of the address space is
+//! recorded as an area when it is mapped: the code it was loaded with, its
+//! stack along with the room the stack may grow into, and its ring. Pages of
+//! an area needn't all be mapped, so the resident size is counted from the
+//! task's page table whenever the areas are listed.
+
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+};
+
+use crate::{memory::FRAME_ALLOCATOR, sync::Mutex, tasks::scheduler::with_user_page_table};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Areas of every user task, sorted by start address
+static AREAS: Mutex<BTreeMap<u64, Vec<Vma>>> = Mutex::new("VMAS", BTreeMap::new());
+
+/// What an area holds
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Code copied in when the task was created
+    Code = 0,
+    /// The user stack, mapped on demand as it grows down
+    Stack = 1,
+    /// The submission and completion ring shared with the kernel
+    Ring = 2,
+}
+
+impl Backing {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backing::Code => "code",
+            Backing::Stack => "stack",
+            Backing::Ring => "ring",
+        }
+    }
+}
+
+/// A range of user pages mapped the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: VirtAddr,
+    /// End address, exclusive
+    pub end: VirtAddr,
+    pub writable: bool,
+    pub executable: bool,
+    pub backing: Backing,
+}
+
+impl Vma {
+    /// The area covering the pages of `start..end`
+    pub fn new(start: VirtAddr, end: VirtAddr, flags: PageTableFlags, backing: Backing) -> Self {
+        Self {
+            start: start.align_down(PAGE_SIZE),
+            end: end.align_up(PAGE_SIZE),
+            writable: flags.contains(PageTableFlags::WRITABLE),
+            executable: !flags.contains(PageTableFlags::NO_EXECUTE),
+            backing,
+        }
+    }
+
+    /// Permissions as in `/proc/<pid>/maps`
+    pub fn permissions(&self) -> &'static str {
+        match (self.writable, self.executable) {
+            (false, false) => "r--",
+            (true, false) => "rw-",
+            (false, true) => "r-x",
+            (true, true) => "rwx",
+        }
+    }
+
+    pub fn pages(&self) -> u64 {
+        (self.end - self.start) / PAGE_SIZE
+    }
+}
+
+/// An area with the number of its pages backed by frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmaUsage {
+    pub vma: Vma,
+    pub resident_pages: u64,
+}
+
+/// Permission bits of `VmaEntry::flags`
+pub mod vma_flags {
+    pub const READ: u32 = 1;
+    pub const WRITE: u32 = 2;
+    pub const EXEC: u32 = 4;
+}
+
+/// An area as the `pmap` syscall copies it out
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmaEntry {
+    pub start: u64,
+    pub end: u64,
+    /// `vma_flags`
+    pub flags: u32,
+    /// `Backing`
+    pub backing: u32,
+    /// Bytes backed by frames
+    pub resident: u64,
+}
+
+impl From<VmaUsage> for VmaEntry {
+    fn from(usage: VmaUsage) -> Self {
+        let vma = usage.vma;
+        let mut flags = vma_flags::READ;
+        if vma.writable {
+            flags |= vma_flags::WRITE;
+        }
+        if vma.executable {
+            flags |= vma_flags::EXEC;
+        }
+        Self {
+            start: vma.start.as_u64(),
+            end: vma.end.as_u64(),
+            flags,
+            backing: vma.backing as u32,
+            resident: usage.resident_pages * PAGE_SIZE,
+        }
+    }
+}
+
+/// Record an area mapped for task `pid`
+pub fn insert(pid: u64, vma: Vma) {
+    interrupts::without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        let areas = areas.entry(pid).or_default();
+        let index = areas.partition_point(|area| area.start < vma.start);
+        areas.insert(index, vma);
+    });
+}
+
+/// Forget the areas of a task, called when it exits
+pub fn release(pid: u64) {
+    interrupts::without_interrupts(|| AREAS.lock().remove(&pid));
+}
+
+/// Areas of user task `pid` with their resident sizes, None if it isn't a
+/// live user task
+pub fn areas(pid: u64) -> Option<Vec<VmaUsage>> {
+    let areas = interrupts::without_interrupts(|| AREAS.lock().get(&pid).cloned())?;
+    with_user_page_table(pid, |cr3| {
+        areas
+            .into_iter()
+            .map(|vma| VmaUsage {
+                vma,
+                resident_pages: unsafe { resident_pages(cr3, &vma) },
+            })
+            .collect()
+    })
+}
+
+/// Count the pages of `vma` mapped in the page tables rooted at `cr3`
+///
+/// # Safety
+/// The page tables must be valid and not change while they are walked
+unsafe fn resident_pages(cr3: PhysFrame, vma: &Vma) -> u64 {
+    let hhdm_offset = FRAME_ALLOCATOR.lock().as_ref().unwrap().hddm_offset;
+    let table = |frame: PhysFrame| unsafe {
+        &*VirtAddr::new(frame.start_address().as_u64() + hhdm_offset).as_ptr::<PageTable>()
+    };
+
+    let mut resident = 0;
+    let mut address = vma.start;
+    while address < vma.end {
+        let indices = [
+            address.p4_index(),
+            address.p3_index(),
+            address.p2_index(),
+            address.p1_index(),
+        ];
+        let mut frame = cr3;
+        // size of what an entry maps at each level, to skip holes quickly
+        let mut skip = 1 << 39;
+        let mut mapped = true;
+        for (level, index) in indices.into_iter().enumerate() {
+            let entry = &table(frame)[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                mapped = false;
+                break;
+            }
+            // user mappings are all 4 KiB pages, the rest is the kernel's
+            if level < 3 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                mapped = false;
+                break;
+            }
+            frame = entry.frame().unwrap();
+            skip >>= 9;
+        }
+        if mapped {
+            resident += 1;
+            address += PAGE_SIZE;
+        } else {
+            // jump to the next entry of the level the walk stopped at
+            let next = (address.as_u64() & !(skip - 1)) + skip;
+            address = VirtAddr::new(next.min(vma.end.as_u64()));
+        }
+    }
+    resident
+}
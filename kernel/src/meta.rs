@@ -1,4 +1,6 @@
-use crate::{print, println, serial_println, tasks::scheduler::exit_task};
+use core::fmt::{self, Write};
+
+use crate::{print, println, serial_println, tasks::scheduler::exit_task, time::rtc::DateTime};
 
 const WELCOME: &str = r"___       ________  ________  ________  ________      
 |\  \     |\   __  \|\   ____\|\   __  \|\   ____\     
@@ -12,6 +14,127 @@ const WELCOME: &str = r"___       ________  ________  ________  ________
 
 const VERSION: &str = "v0.1.0";
 
+/// What the kernel was built from, recorded by build.rs
+pub mod build {
+    pub const SYSNAME: &str = "locOS";
+    pub const RELEASE: &str = env!("CARGO_PKG_VERSION");
+    pub const MACHINE: &str = "x86_64";
+    /// Short commit hash, with `-dirty` if tracked files were modified
+    pub const GIT_HASH: &str = env!("LOCOS_GIT_HASH");
+    /// Seconds since the Unix epoch
+    pub const TIME: u64 = parse_u64(env!("LOCOS_BUILD_TIME"));
+    /// Enabled cargo features, space separated
+    pub const FEATURES: &str = env!("LOCOS_FEATURES");
+    /// `rustc --version` of the compiler
+    pub const RUSTC: &str = env!("LOCOS_RUSTC");
+
+    const fn parse_u64(digits: &str) -> u64 {
+        let digits = digits.as_bytes();
+        let mut value = 0;
+        let mut i = 0;
+        while i < digits.len() {
+            value = value * 10 + (digits[i] - b'0') as u64;
+            i += 1;
+        }
+        value
+    }
+}
+
+/// Length of the fields of `Utsname`, the terminating NUL included
+pub const UTS_LEN: usize = 65;
+
+/// Longest feature list `Utsname` holds, the terminating NUL included
+pub const UTS_FEATURES_LEN: usize = 257;
+
+/// The build as the `uname` syscall copies it out
+///
+/// Every field is a NUL-terminated string, cut short if it doesn't fit.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Utsname {
+    pub sysname: [u8; UTS_LEN],
+    pub release: [u8; UTS_LEN],
+    /// Commit hash and build time
+    pub version: [u8; UTS_LEN],
+    pub machine: [u8; UTS_LEN],
+    pub rustc: [u8; UTS_LEN],
+    /// Enabled cargo features, space separated
+    pub features: [u8; UTS_FEATURES_LEN],
+}
+
+impl Utsname {
+    pub fn new() -> Self {
+        let mut version = [0; UTS_LEN];
+        let mut writer = FieldWriter {
+            field: &mut version,
+            len: 0,
+        };
+        let _ = write!(
+            writer,
+            "{} {} UTC",
+            build::GIT_HASH,
+            DateTime::from_unix(build::TIME)
+        );
+
+        Self {
+            sysname: field(build::SYSNAME),
+            release: field(build::RELEASE),
+            version,
+            machine: field(build::MACHINE),
+            rustc: field(build::RUSTC),
+            features: field(build::FEATURES),
+        }
+    }
+}
+
+impl Default for Utsname {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `value` as a NUL-terminated field of `N` bytes
+fn field<const N: usize>(value: &str) -> [u8; N] {
+    let mut field = [0; N];
+    let len = value.len().min(N - 1);
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+    field
+}
+
+/// Formats into a NUL-terminated field, dropping what doesn't fit
+struct FieldWriter<'a> {
+    field: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for FieldWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.field.len() - 1 - self.len;
+        let take = s.len().min(room);
+        self.field[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        Ok(())
+    }
+}
+
+/// One line identifying the build, for boot logs and panic reports
+pub struct BuildLine;
+
+impl fmt::Display for BuildLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({}, built {} UTC, {}) [{}]",
+            build::SYSNAME,
+            build::RELEASE,
+            build::GIT_HASH,
+            DateTime::from_unix(build::TIME),
+            build::RUSTC,
+            build::FEATURES
+        )
+    }
+}
+
 /// Prints the welcome message to the console.
 pub fn tprint_welcome() -> ! {
     print!("\x1B[2J");
@@ -0,0 +1,53 @@
+//! Registered network interfaces
+//!
+//! Drivers register their interfaces through `kernel_api::net`. There is no
+//! network stack using them yet; `udp` is just enough of one for `upload` to
+//! send the kernel log to a TFTP server.
+
+pub mod gzip;
+pub mod tests;
+pub mod tftp;
+pub mod udp;
+pub mod upload;
+
+use alloc::{sync::Arc, vec::Vec};
+
+use kernel_api::RegisterError;
+pub use kernel_api::net::{NetError, NetInterface};
+use x86_64::instructions::interrupts;
+
+use crate::{info, sync::Mutex};
+
+static INTERFACES: Mutex<Vec<Arc<dyn NetInterface>>> = Mutex::new("NET_INTERFACES", Vec::new());
+
+/// Add an interface, failing if one with the same name exists
+pub fn register(interface: Arc<dyn NetInterface>) -> Result<(), RegisterError> {
+    interrupts::without_interrupts(|| {
+        let mut interfaces = INTERFACES.lock();
+        if interfaces
+            .iter()
+            .any(|registered| registered.name() == interface.name())
+        {
+            return Err(RegisterError::AlreadyRegistered);
+        }
+        info!("network interface {}", interface.name());
+        interfaces.push(interface);
+        Ok(())
+    })
+}
+
+/// Look an interface up by name
+pub fn find(name: &str) -> Option<Arc<dyn NetInterface>> {
+    interrupts::without_interrupts(|| {
+        INTERFACES
+            .lock()
+            .iter()
+            .find(|interface| interface.name() == name)
+            .cloned()
+    })
+}
+
+/// Every registered interface, in the order they were registered
+pub fn interfaces() -> Vec<Arc<dyn NetInterface>> {
+    interrupts::without_interrupts(|| INTERFACES.lock().clone())
+}
@@ -0,0 +1,166 @@
+//! A small gzip encoder
+//!
+//! Enough to shrink logs before they are uploaded: one deflate block with
+//! the fixed Huffman codes, and matches found through a hash of the next
+//! three bytes, keeping only the latest position for each hash. Text like a
+//! kernel log still comes out at a fraction of its size, and the output is
+//! plain gzip that `gunzip` reads.
+
+use alloc::vec::Vec;
+
+pub use crate::crc32::crc32;
+
+/// Shortest and longest match deflate can encode
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// Farthest back a match can start
+const WINDOW_SIZE: usize = 32 * 1024;
+
+const HASH_BITS: u32 = 14;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Writes bits least significant first, as deflate packs them
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Write a Huffman code, which is packed most significant bit first
+    fn write_code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+
+    /// A literal byte, or a length code past 255
+    fn symbol(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn matched(&mut self, length: usize, distance: usize) {
+        let index = LENGTH_BASE
+            .iter()
+            .rposition(|&base| base as usize <= length)
+            .unwrap_or(0);
+        self.symbol(257 + index as u16);
+        self.write(
+            (length - LENGTH_BASE[index] as usize) as u32,
+            LENGTH_EXTRA[index] as u32,
+        );
+
+        let index = DISTANCE_BASE
+            .iter()
+            .rposition(|&base| base as usize <= distance)
+            .unwrap_or(0);
+        self.write_code(index as u32, 5);
+        self.write(
+            (distance - DISTANCE_BASE[index] as usize) as u32,
+            DISTANCE_EXTRA[index] as u32,
+        );
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+    (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compress `data` into a single fixed-Huffman deflate block
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: Vec::with_capacity(data.len() / 2 + 16),
+        bits: 0,
+        count: 0,
+    };
+    // last block, fixed Huffman codes
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    // position + 1 of the last occurrence of each hash, 0 if none
+    let mut heads = alloc::vec![0u32; 1 << HASH_BITS];
+    let mut position = 0;
+    while position < data.len() {
+        let rest = &data[position..];
+        if rest.len() < MIN_MATCH {
+            writer.symbol(rest[0] as u16);
+            position += 1;
+            continue;
+        }
+
+        let hash = hash(rest);
+        let candidate = heads[hash] as usize;
+        heads[hash] = position as u32 + 1;
+        let length = match candidate.checked_sub(1) {
+            Some(start) if position - start <= WINDOW_SIZE => data[start..]
+                .iter()
+                .zip(rest)
+                .take(MAX_MATCH)
+                .take_while(|(a, b)| a == b)
+                .count(),
+            _ => 0,
+        };
+
+        if length >= MIN_MATCH {
+            writer.matched(length, position - (candidate - 1));
+            // later matches can start inside this one
+            for inside in position + 1..(position + length).min(data.len() - MIN_MATCH + 1) {
+                heads[self::hash(&data[inside..])] = inside as u32 + 1;
+            }
+            position += length;
+        } else {
+            writer.symbol(rest[0] as u16);
+            position += 1;
+        }
+    }
+    writer.symbol(256);
+    writer.finish()
+}
+
+/// Compress `data` into a gzip member
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // no file name or modification time, unknown OS
+    let mut out = Vec::from([0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
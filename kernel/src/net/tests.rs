@@ -0,0 +1,28 @@
+//! Network tests
+
+#[test_case]
+fn test_crc32_check_value() {
+    assert_eq!(super::gzip::crc32(b"123456789"), 0xcbf4_3926);
+}
+
+#[test_case]
+fn test_gzip_shrinks_repeated_text() {
+    let text = b"INFO: network interface eth0\n".repeat(64);
+    let compressed = super::gzip::gzip(&text);
+    assert_eq!(&compressed[..3], &[0x1f, 0x8b, 8]);
+    assert!(compressed.len() < text.len() / 4);
+    let trailer = &compressed[compressed.len() - 8..];
+    assert_eq!(trailer[..4], super::gzip::crc32(&text).to_le_bytes());
+    assert_eq!(trailer[4..], (text.len() as u32).to_le_bytes());
+}
+
+#[test_case]
+fn test_ip_checksum_verifies() {
+    let mut header = [
+        0x45, 0, 0, 0x73, 0, 0, 0x40, 0, 0x40, 0x11, 0, 0, 0xc0, 0xa8, 0, 1, 0xc0, 0xa8, 0, 0xc7,
+    ];
+    let checksum = super::udp::ip_checksum(&header);
+    assert_eq!(checksum, 0xb861);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    assert_eq!(super::udp::ip_checksum(&header), 0);
+}
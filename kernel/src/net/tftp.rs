@@ -0,0 +1,118 @@
+//! A TFTP client that can only write
+//!
+//! Sends a file to a TFTP server (RFC 1350) in octet mode, a block of 512
+//! bytes at a time, each retransmitted until the server acknowledges it.
+//! Servers usually have to be told to accept new files, e.g.
+//! `in.tftpd --create`.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use super::udp::{Deadline, UdpError, UdpLink};
+use crate::time;
+
+const SERVER_PORT: u16 = 69;
+
+const OPCODE_WRQ: u16 = 2;
+const OPCODE_DATA: u16 = 3;
+const OPCODE_ACK: u16 = 4;
+const OPCODE_ERROR: u16 = 5;
+
+const BLOCK_SIZE: usize = 512;
+
+/// Sends of a packet before giving up
+const ATTEMPTS: u32 = 5;
+const TIMEOUT_MS: u64 = 1000;
+
+/// Why a file couldn't be sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TftpError {
+    Udp(UdpError),
+    /// The server stopped answering
+    Timeout,
+    /// The server refused, with its error code and message
+    Server(u16, String),
+}
+
+impl From<UdpError> for TftpError {
+    fn from(error: UdpError) -> Self {
+        TftpError::Udp(error)
+    }
+}
+
+/// A packet from the server
+enum Reply {
+    Ack(u16),
+    Error(u16, String),
+}
+
+fn parse(payload: &[u8]) -> Option<Reply> {
+    let opcode = u16::from_be_bytes([*payload.first()?, *payload.get(1)?]);
+    let argument = u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]);
+    match opcode {
+        OPCODE_ACK => Some(Reply::Ack(argument)),
+        OPCODE_ERROR => {
+            let message = payload[4..].split(|&byte| byte == 0).next().unwrap_or(&[]);
+            Some(Reply::Error(
+                argument,
+                String::from_utf8_lossy(message).to_string(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Send `packet` to `port` until the server acknowledges `block`, returning
+/// the port the server answered from
+fn exchange(
+    link: &mut UdpLink,
+    local_port: u16,
+    port: u16,
+    packet: &[u8],
+    block: u16,
+) -> Result<u16, TftpError> {
+    for _ in 0..ATTEMPTS {
+        link.send(local_port, port, packet)?;
+        let mut deadline = Deadline::after_ms(TIMEOUT_MS);
+        while let Some(datagram) = link.receive(local_port, &mut deadline)? {
+            // the request goes to port 69, the server answers from another
+            if port != SERVER_PORT && datagram.source_port != port {
+                continue;
+            }
+            match parse(&datagram.payload) {
+                Some(Reply::Ack(acked)) if acked == block => return Ok(datagram.source_port),
+                Some(Reply::Error(code, message)) => return Err(TftpError::Server(code, message)),
+                // a duplicate acknowledgement of an earlier block
+                _ => {}
+            }
+        }
+    }
+    Err(TftpError::Timeout)
+}
+
+/// Write `data` to `filename` on the link's peer
+pub fn put(link: &mut UdpLink, filename: &str, data: &[u8]) -> Result<(), TftpError> {
+    let local_port = 49152 + (time::unix_time_ms() % 16384) as u16;
+
+    let mut request = Vec::new();
+    request.extend_from_slice(&OPCODE_WRQ.to_be_bytes());
+    request.extend_from_slice(filename.as_bytes());
+    request.push(0);
+    request.extend_from_slice(b"octet\0");
+    let server_port = exchange(link, local_port, SERVER_PORT, &request, 0)?;
+
+    // the last block is short, empty if the data fills every block
+    let blocks = data.len() / BLOCK_SIZE + 1;
+    for index in 0..blocks {
+        let block = (index + 1) as u16;
+        let chunk = &data[index * BLOCK_SIZE..((index + 1) * BLOCK_SIZE).min(data.len())];
+        let mut packet = Vec::with_capacity(4 + chunk.len());
+        packet.extend_from_slice(&OPCODE_DATA.to_be_bytes());
+        packet.extend_from_slice(&block.to_be_bytes());
+        packet.extend_from_slice(chunk);
+        exchange(link, local_port, server_port, &packet, block)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,307 @@
+//! UDP over a single interface, without a network stack
+//!
+//! Just enough IPv4 to talk to one peer: the next hop is resolved with ARP
+//! once, datagrams are sent unfragmented without a UDP checksum, and
+//! received frames are polled for rather than delivered by an interrupt, so
+//! a link also works with interrupts disabled, as in the panic handler.
+//! ARP requests for the link's own address are answered while polling, so
+//! the peer can send back.
+
+use core::net::Ipv4Addr;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use super::{NetError, NetInterface};
+use crate::time;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+const IPV4_HEADER_SIZE: usize = 20;
+const UDP_HEADER_SIZE: usize = 8;
+
+/// Largest frame received, with room for a VLAN tag
+const MAX_FRAME_SIZE: usize = 1522;
+
+/// ARP requests sent before giving up on the next hop
+const ARP_ATTEMPTS: u32 = 4;
+const ARP_TIMEOUT_MS: u64 = 500;
+
+/// Polls of the interface per millisecond, roughly, for when the timer is
+/// not ticking
+const POLLS_PER_MS: u64 = 1000;
+
+/// Why a datagram couldn't be sent or received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpError {
+    Interface(NetError),
+    /// The next hop didn't answer ARP
+    Unreachable,
+    /// The datagram doesn't fit in a frame
+    TooLarge,
+}
+
+impl From<NetError> for UdpError {
+    fn from(error: NetError) -> Self {
+        UdpError::Interface(error)
+    }
+}
+
+/// When to stop waiting for a frame
+///
+/// Goes by the timer, and also by the number of polls, since the timer stops
+/// while interrupts are disabled.
+pub struct Deadline {
+    tick: u64,
+    polls_left: u64,
+}
+
+impl Deadline {
+    pub fn after_ms(ms: u64) -> Self {
+        Self {
+            tick: time::ticks() + time::ms_to_ticks(ms),
+            polls_left: ms * POLLS_PER_MS,
+        }
+    }
+
+    /// Count a poll, returning whether the deadline passed
+    fn expired(&mut self) -> bool {
+        for _ in 0..64 {
+            core::hint::spin_loop();
+        }
+        self.polls_left = self.polls_left.saturating_sub(1);
+        self.polls_left == 0 || time::ticks() >= self.tick
+    }
+}
+
+/// A datagram received on a link
+pub struct Datagram {
+    pub source_port: u16,
+    pub payload: Vec<u8>,
+}
+
+/// An interface talking to one peer
+pub struct UdpLink {
+    interface: Arc<dyn NetInterface>,
+    mac: [u8; 6],
+    address: Ipv4Addr,
+    peer: Ipv4Addr,
+    /// MAC address of the peer, or of the gateway to it
+    next_hop_mac: [u8; 6],
+    next_id: u16,
+}
+
+impl UdpLink {
+    /// Use `interface` with `address` to reach `peer`, directly or through
+    /// `gateway`
+    pub fn connect(
+        interface: Arc<dyn NetInterface>,
+        address: Ipv4Addr,
+        peer: Ipv4Addr,
+        gateway: Option<Ipv4Addr>,
+    ) -> Result<Self, UdpError> {
+        if !interface.link_up() {
+            return Err(UdpError::Interface(NetError::LinkDown));
+        }
+        let mut link = Self {
+            mac: interface.mac_address(),
+            interface,
+            address,
+            peer,
+            next_hop_mac: [0xff; 6],
+            next_id: 1,
+        };
+        link.next_hop_mac = link.resolve(gateway.unwrap_or(peer))?;
+        Ok(link)
+    }
+
+    pub fn peer(&self) -> Ipv4Addr {
+        self.peer
+    }
+
+    /// MAC address of `target` on the local network
+    fn resolve(&mut self, target: Ipv4Addr) -> Result<[u8; 6], UdpError> {
+        for _ in 0..ARP_ATTEMPTS {
+            self.send_arp(1, [0xff; 6], [0; 6], target)?;
+            let mut deadline = Deadline::after_ms(ARP_TIMEOUT_MS);
+            let mut frame = vec![0; MAX_FRAME_SIZE];
+            while !deadline.expired() {
+                let Some(length) = self.interface.receive(&mut frame)? else {
+                    continue;
+                };
+                let frame = &frame[..length];
+                if let Some(mac) = self.handle_arp(frame)?
+                    && arp_sender(frame).is_some_and(|(_, ip)| ip == target)
+                {
+                    return Ok(mac);
+                }
+            }
+        }
+        Err(UdpError::Unreachable)
+    }
+
+    fn send_arp(
+        &self,
+        operation: u16,
+        destination: [u8; 6],
+        target_mac: [u8; 6],
+        target: Ipv4Addr,
+    ) -> Result<(), UdpError> {
+        let mut frame = self.ethernet_header(destination, ETHERTYPE_ARP);
+        // Ethernet, IPv4
+        frame.extend_from_slice(&[0, 1, 8, 0, 6, 4]);
+        frame.extend_from_slice(&operation.to_be_bytes());
+        frame.extend_from_slice(&self.mac);
+        frame.extend_from_slice(&self.address.octets());
+        frame.extend_from_slice(&target_mac);
+        frame.extend_from_slice(&target.octets());
+        // pad to the minimum frame size
+        frame.resize(60, 0);
+        Ok(self.interface.transmit(&frame)?)
+    }
+
+    /// Answer an ARP request for our address, returning the sender's MAC
+    /// address if `frame` is ARP
+    fn handle_arp(&self, frame: &[u8]) -> Result<Option<[u8; 6]>, UdpError> {
+        let Some((mac, ip)) = arp_sender(frame) else {
+            return Ok(None);
+        };
+        let operation = u16::from_be_bytes([frame[20], frame[21]]);
+        let target = Ipv4Addr::new(frame[38], frame[39], frame[40], frame[41]);
+        if operation == 1 && target == self.address {
+            self.send_arp(2, mac, mac, ip)?;
+        }
+        Ok(Some(mac))
+    }
+
+    fn ethernet_header(&self, destination: [u8; 6], ethertype: u16) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(MAX_FRAME_SIZE);
+        frame.extend_from_slice(&destination);
+        frame.extend_from_slice(&self.mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame
+    }
+
+    /// Send `payload` to `destination_port` of the peer
+    pub fn send(
+        &mut self,
+        source_port: u16,
+        destination_port: u16,
+        payload: &[u8],
+    ) -> Result<(), UdpError> {
+        let udp_length = UDP_HEADER_SIZE + payload.len();
+        let total_length = IPV4_HEADER_SIZE + udp_length;
+        if total_length > self.interface.mtu() {
+            return Err(UdpError::TooLarge);
+        }
+
+        let mut frame = self.ethernet_header(self.next_hop_mac, ETHERTYPE_IPV4);
+        let mut ip_header = [0u8; IPV4_HEADER_SIZE];
+        ip_header[0] = 0x45;
+        ip_header[2..4].copy_from_slice(&(total_length as u16).to_be_bytes());
+        ip_header[4..6].copy_from_slice(&self.next_id.to_be_bytes());
+        // don't fragment
+        ip_header[6] = 0x40;
+        ip_header[8] = 64;
+        ip_header[9] = IP_PROTOCOL_UDP;
+        ip_header[12..16].copy_from_slice(&self.address.octets());
+        ip_header[16..20].copy_from_slice(&self.peer.octets());
+        let checksum = ip_checksum(&ip_header);
+        ip_header[10..12].copy_from_slice(&checksum.to_be_bytes());
+        self.next_id = self.next_id.wrapping_add(1);
+
+        frame.extend_from_slice(&ip_header);
+        frame.extend_from_slice(&source_port.to_be_bytes());
+        frame.extend_from_slice(&destination_port.to_be_bytes());
+        frame.extend_from_slice(&(udp_length as u16).to_be_bytes());
+        // no checksum, which IPv4 allows
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(payload);
+        if frame.len() < 60 {
+            frame.resize(60, 0);
+        }
+        Ok(self.interface.transmit(&frame)?)
+    }
+
+    /// Wait for a datagram from the peer to `port`, or `None` once `deadline`
+    /// passes
+    pub fn receive(
+        &mut self,
+        port: u16,
+        deadline: &mut Deadline,
+    ) -> Result<Option<Datagram>, UdpError> {
+        let mut frame = vec![0; MAX_FRAME_SIZE];
+        while !deadline.expired() {
+            let Some(length) = self.interface.receive(&mut frame)? else {
+                continue;
+            };
+            let frame = &frame[..length];
+            if self.handle_arp(frame)?.is_some() {
+                continue;
+            }
+            if let Some(datagram) = self.parse_udp(frame, port) {
+                return Ok(Some(datagram));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The datagram in `frame`, if it is one from the peer to `port`
+    fn parse_udp(&self, frame: &[u8], port: u16) -> Option<Datagram> {
+        let ethertype = u16::from_be_bytes([frame.get(12).copied()?, frame.get(13).copied()?]);
+        if ethertype != ETHERTYPE_IPV4 {
+            return None;
+        }
+        let ip = frame.get(ETHERNET_HEADER_SIZE..)?;
+        let header_length = (*ip.first()? as usize & 0xf) * 4;
+        let total_length = u16::from_be_bytes([*ip.get(2)?, *ip.get(3)?]) as usize;
+        // fragments are not reassembled
+        let fragmented = u16::from_be_bytes([*ip.get(6)?, *ip.get(7)?]) & 0x3fff != 0;
+        if ip[0] >> 4 != 4
+            || fragmented
+            || *ip.get(9)? != IP_PROTOCOL_UDP
+            || ip.get(12..16)? != self.peer.octets()
+            || ip.get(16..20)? != self.address.octets()
+        {
+            return None;
+        }
+
+        let udp = ip.get(header_length..total_length)?;
+        let source_port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+        let destination_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+        let udp_length = u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]) as usize;
+        if destination_port != port {
+            return None;
+        }
+        Some(Datagram {
+            source_port,
+            payload: udp.get(UDP_HEADER_SIZE..udp_length)?.to_vec(),
+        })
+    }
+}
+
+/// Sender of an ARP packet for IPv4 over Ethernet
+fn arp_sender(frame: &[u8]) -> Option<([u8; 6], Ipv4Addr)> {
+    if frame.len() < 42 || u16::from_be_bytes([frame[12], frame[13]]) != ETHERTYPE_ARP {
+        return None;
+    }
+    let mac = frame[22..28].try_into().ok()?;
+    Some((
+        mac,
+        Ipv4Addr::new(frame[28], frame[29], frame[30], frame[31]),
+    ))
+}
+
+/// The Internet checksum of a header
+pub fn ip_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
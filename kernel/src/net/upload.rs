@@ -0,0 +1,112 @@
+//! Uploading logs and crash reports to a TFTP server
+//!
+//! For machines nobody watches the serial port of: the kernel log, or a
+//! crash report made by the panic handler, is gzipped and written to a TFTP
+//! server as `locos-<kind>-<unix time>.gz`. Configured on the command line:
+//!
+//! - `upload=<server>`: address of the TFTP server
+//! - `upload.ip=<address>`: our own address, there is no DHCP
+//! - `upload.gateway=<address>`: router to the server, if not on our network
+//! - `upload.iface=<name>`: interface to use, the first one by default
+//! - `upload.auto`: upload a crash report when the kernel panics
+//!
+//! With QEMU's user networking, `upload=10.0.2.2 upload.ip=10.0.2.15`
+//! reaches a TFTP server on the host.
+
+use core::{
+    net::Ipv4Addr,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{format, string::String, sync::Arc};
+
+use super::{
+    NetInterface, gzip,
+    tftp::{self, TftpError},
+    udp::{UdpError, UdpLink},
+};
+use crate::{bootargs, error, info, meta, output::klog, time};
+
+/// Set once the panic handler tried to upload, so a panic while uploading
+/// doesn't try again
+static PANIC_UPLOAD: AtomicBool = AtomicBool::new(false);
+
+/// Why an upload failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadError {
+    /// The boot argument is missing or not an address
+    NotConfigured(&'static str),
+    NoInterface,
+    Udp(UdpError),
+    Tftp(TftpError),
+}
+
+impl From<UdpError> for UploadError {
+    fn from(error: UdpError) -> Self {
+        UploadError::Udp(error)
+    }
+}
+
+impl From<TftpError> for UploadError {
+    fn from(error: TftpError) -> Self {
+        UploadError::Tftp(error)
+    }
+}
+
+fn address(key: &'static str) -> Result<Option<Ipv4Addr>, UploadError> {
+    bootargs::get(key)
+        .map(|value| value.parse().map_err(|_| UploadError::NotConfigured(key)))
+        .transpose()
+}
+
+fn interface() -> Result<Arc<dyn NetInterface>, UploadError> {
+    match bootargs::get("upload.iface") {
+        Some(name) => super::find(name),
+        None => super::interfaces().into_iter().next(),
+    }
+    .ok_or(UploadError::NoInterface)
+}
+
+/// Gzip `data` and write it to the server, returning the file name used
+pub fn upload(kind: &str, data: &[u8]) -> Result<String, UploadError> {
+    let server = address("upload")?.ok_or(UploadError::NotConfigured("upload"))?;
+    let ip = address("upload.ip")?.ok_or(UploadError::NotConfigured("upload.ip"))?;
+    let gateway = address("upload.gateway")?;
+
+    let filename = format!("locos-{}-{}.gz", kind, time::unix_time_ms() / 1000);
+    let compressed = gzip::gzip(data);
+    let mut link = UdpLink::connect(interface()?, ip, server, gateway)?;
+    tftp::put(&mut link, &filename, &compressed)?;
+    info!(
+        "uploaded {} ({} bytes, {} compressed) to {}",
+        filename,
+        data.len(),
+        compressed.len(),
+        link.peer()
+    );
+    Ok(filename)
+}
+
+/// Upload the kernel log
+pub fn upload_log() -> Result<String, UploadError> {
+    upload("dmesg", &klog::contents())
+}
+
+/// Upload a crash report, if `upload.auto` is given, called by the panic
+/// handler
+///
+/// The report is the panic message and the build, followed by the kernel
+/// log. Interrupts may be off and the heap may be what panicked, so this is
+/// a best effort.
+pub fn after_panic(info: &PanicInfo) {
+    if !bootargs::has_flag("upload.auto") || PANIC_UPLOAD.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let mut report = format!("{}\nbuild: {}\n\n", info, meta::BuildLine).into_bytes();
+    report.extend_from_slice(&klog::contents());
+    #[allow(unused_variables)]
+    if let Err(e) = upload("crash", &report) {
+        error!("crash report upload failed: {:?}", e);
+    }
+}
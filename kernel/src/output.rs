@@ -7,6 +7,11 @@
 //! - `framebuffer`: Provides a direct interface to the framebuffer.
 //! - `linewriter`: Implements a simple line-based writer for the console.
 //! - `flanconsole`: Provides a terminal emulator using the flanterm library.
+//! - `mirror`: Mirrors the terminal to a serial port.
+//! - `capture`: Captures a task's output, used by the shell pager.
+//! - `klog`: Keeps the kernel log in a ring buffer for `dmesg`.
+//! - `screenshot`: Copies the framebuffer and encodes it as a BMP.
+//! - `tty`: Terminal size and cursor control for full-screen programs.
 //!
 //! The main entry points are:
 //!
@@ -19,9 +24,18 @@
 //! - `FlanConsole`: A terminal emulator that provides ANSI escape sequence
 //!   support and direct framebuffer writing.
 
+pub mod capture;
+#[cfg(feature = "graphics")]
 pub mod flanconsole;
 pub mod framebuffer;
+pub mod klog;
 pub mod macros;
+#[cfg(feature = "graphics")]
+pub mod mirror;
+#[cfg(feature = "graphics")]
+pub mod screenshot;
 pub mod tests;
+pub mod tty;
 
+#[cfg(feature = "graphics")]
 pub use flanconsole::{FLANTERM, FlanConsole, flanterm_init};
@@ -0,0 +1,47 @@
+//! Capturing the output of a task.
+//!
+//! While a task's output is captured, everything it prints goes into a
+//! buffer instead of the terminal; the shell uses this to page long command
+//! output. Output of other tasks and of the kernel log is not affected.
+
+use core::{
+    fmt::{Arguments, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::string::String;
+use x86_64::instructions::interrupts;
+
+use crate::{sync::Mutex, tasks::scheduler::current_pid};
+
+/// No task is being captured
+const NONE: u64 = u64::MAX;
+
+/// Task whose output is captured
+static CAPTURING: AtomicU64 = AtomicU64::new(NONE);
+
+static BUFFER: Mutex<String> = Mutex::new("CAPTURE_BUFFER", String::new());
+
+/// Start capturing the output of the current task
+pub fn start() {
+    interrupts::without_interrupts(|| BUFFER.lock().clear());
+    CAPTURING.store(current_pid(), Ordering::Relaxed);
+}
+
+/// Stop capturing and return what was captured
+pub fn finish() -> String {
+    CAPTURING.store(NONE, Ordering::Relaxed);
+    interrupts::without_interrupts(|| core::mem::take(&mut *BUFFER.lock()))
+}
+
+/// Called by `print!`, returns true if the output was captured
+pub fn capture(args: Arguments) -> bool {
+    let capturing = CAPTURING.load(Ordering::Relaxed);
+    if capturing == NONE || capturing != current_pid() {
+        return false;
+    }
+    interrupts::without_interrupts(|| {
+        let _ = BUFFER.lock().write_fmt(args);
+    });
+    true
+}
@@ -14,17 +14,16 @@
 use core::{fmt::Write, ptr};
 
 use flanterm::sys::{flanterm_context, flanterm_fb_init, flanterm_write};
-use spin::Mutex;
 
-use crate::info;
+use crate::{info, sync::Mutex};
 
-use super::framebuffer::FramebufferInfo;
+use super::{framebuffer::FramebufferInfo, mirror, screenshot};
 
 /// Global terminal instance protected by a mutex.
 ///
 /// This static is initialized by `flanterm_init` and can be accessed
 /// throughout the kernel for terminal operations.
-pub static FLANTERM: Mutex<Option<FlanConsole>> = Mutex::new(None);
+pub static FLANTERM: Mutex<Option<FlanConsole>> = Mutex::new("FLANTERM", None);
 
 /// Initializes the global terminal instance.
 ///
@@ -42,6 +41,8 @@ pub fn flanterm_init(framebuffer: *mut u32, framebuffer_info: FramebufferInfo) {
         let mut lock = FLANTERM.lock();
         *lock = Some(FlanConsole::new(framebuffer, framebuffer_info));
     }
+    screenshot::init(framebuffer, framebuffer_info);
+    mirror::init();
     info!("flanterm initialized");
 }
 
@@ -52,6 +53,9 @@ pub fn flanterm_init(framebuffer: *mut u32, framebuffer_info: FramebufferInfo) {
 pub struct FlanConsole {
     /// Raw pointer to the flanterm context
     context: *mut flanterm_context,
+    /// Framebuffer size in pixels
+    width: usize,
+    height: usize,
 }
 
 unsafe impl Send for FlanConsole {}
@@ -70,7 +74,32 @@ impl FlanConsole {
     /// specified in framebuffer_info.
     pub fn new(framebuffer: *mut u32, framebuffer_info: FramebufferInfo) -> Self {
         let context = get_context(framebuffer, framebuffer_info);
-        FlanConsole { context }
+        FlanConsole {
+            context,
+            width: framebuffer_info.width,
+            height: framebuffer_info.height,
+        }
+    }
+
+    /// Terminal size in character rows and columns
+    pub fn size(&self) -> (usize, usize) {
+        unsafe { ((*self.context).rows, (*self.context).cols) }
+    }
+
+    /// Framebuffer size in pixels
+    pub fn pixel_size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Cursor row and column, counted from 0
+    pub fn cursor_position(&self) -> (usize, usize) {
+        let (mut column, mut row) = (0, 0);
+        unsafe {
+            if let Some(get_cursor_pos) = (*self.context).get_cursor_pos {
+                get_cursor_pos(self.context, &mut column, &mut row);
+            }
+        }
+        (row, column)
     }
 
     /// Internal print implementation that writes directly to the terminal.
@@ -82,6 +111,8 @@ impl FlanConsole {
         unsafe {
             flanterm_write(self.context, text.as_ptr() as *const i8, text.len());
         }
+        crate::input::latency::observe(text);
+        mirror::mirror(text);
     }
 }
 
@@ -0,0 +1,117 @@
+//! The kernel log ring
+//!
+//! Every message of the log macros goes to COM1 as before and is also kept
+//! here, without its colours, so the log can be read back with `dmesg` or
+//! sent off the machine after the serial output is long gone. The ring is a
+//! static buffer behind a spin lock, so logging works before the heap is up
+//! and inside interrupt handlers; once full, the oldest lines are
+//! overwritten.
+
+use core::fmt::{self, Arguments, Write};
+
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+
+use crate::sync::Mutex;
+
+/// Bytes kept
+const KLOG_SIZE: usize = 64 * 1024;
+
+struct Ring {
+    buffer: [u8; KLOG_SIZE],
+    /// Total bytes ever written, the next byte goes at `written % KLOG_SIZE`
+    written: usize,
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buffer[self.written % KLOG_SIZE] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+static KLOG: Mutex<Ring> = Mutex::new(
+    "KLOG",
+    Ring {
+        buffer: [0; KLOG_SIZE],
+        written: 0,
+    },
+);
+
+/// Log level, as shown in front of a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    /// ANSI colour of the prefix on serial
+    fn colour(self) -> &'static str {
+        match self {
+            Level::Error => "31",
+            Level::Warn => "33",
+            Level::Info | Level::Debug => "32",
+            Level::Trace => "36",
+        }
+    }
+}
+
+/// Write a message to COM1 and keep it in the ring, called by the log macros
+#[doc(hidden)]
+pub fn log(level: Level, args: Arguments) {
+    crate::serial_println!("\x1B[{}m{}:\x1B[0m {}", level.colour(), level.name(), args);
+    record(level, args);
+}
+
+/// Keep a message in the ring without printing it
+pub fn record(level: Level, args: Arguments) {
+    interrupts::without_interrupts(|| {
+        let uptime = crate::time::uptime_ms();
+        let mut ring = KLOG.lock();
+        let _ = writeln!(
+            ring,
+            "[{:>5}.{:03}] {}: {}",
+            uptime / 1000,
+            uptime % 1000,
+            level.name(),
+            args
+        );
+    });
+}
+
+/// The ring's contents, oldest line first
+///
+/// After the ring wrapped, the partial line at its start is dropped.
+pub fn contents() -> Vec<u8> {
+    interrupts::without_interrupts(|| {
+        let ring = KLOG.lock();
+        if ring.written <= KLOG_SIZE {
+            return ring.buffer[..ring.written].to_vec();
+        }
+        let start = ring.written % KLOG_SIZE;
+        let mut contents = Vec::with_capacity(KLOG_SIZE);
+        contents.extend_from_slice(&ring.buffer[start..]);
+        contents.extend_from_slice(&ring.buffer[..start]);
+        match contents.iter().position(|&byte| byte == b'\n') {
+            Some(newline) => contents.split_off(newline + 1),
+            None => contents,
+        }
+    })
+}
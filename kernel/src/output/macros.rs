@@ -1,10 +1,11 @@
 //! Macros for printing to the framebuffer using the global terminal instance.
 
 /// Global print! macro that writes to the framebuffer.
+#[cfg(feature = "graphics")]
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
-        {
+        if !$crate::output::capture::capture(format_args!($($arg)*)) {
             use core::fmt::Write;
             use $crate::output::FLANTERM;
             let mut lock = FLANTERM.lock();
@@ -15,12 +16,23 @@ macro_rules! print {
     };
 }
 
+/// Global print! macro that falls back to serial when graphics are disabled.
+#[cfg(not(feature = "graphics"))]
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        if !$crate::output::capture::capture(format_args!($($arg)*)) {
+            $crate::serial_print!($($arg)*);
+        }
+    };
+}
+
 /// Logs an error message with a red "ERROR: " prefix.
 #[cfg(feature = "log-error")]
 #[macro_export]
 macro_rules! error {
     ($($arg:tt)*) => {
-        $crate::serial_println!("\x1B[31mERROR:\x1B[0m {}", format_args!($($arg)*));
+        $crate::output::klog::log($crate::output::klog::Level::Error, format_args!($($arg)*));
     };
 }
 
@@ -36,7 +48,7 @@ macro_rules! error {
 #[macro_export]
 macro_rules! warn {
     ($($arg:tt)*) => {
-        $crate::serial_println!("\x1B[33mWARN:\x1B[0m {}", format_args!($($arg)*));
+        $crate::output::klog::log($crate::output::klog::Level::Warn, format_args!($($arg)*));
     };
 }
 
@@ -52,7 +64,7 @@ macro_rules! warn {
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => {
-        $crate::serial_println!("\x1B[32mINFO:\x1B[0m {}", format_args!($($arg)*));
+        $crate::output::klog::log($crate::output::klog::Level::Info, format_args!($($arg)*));
     };
 }
 
@@ -68,7 +80,7 @@ macro_rules! info {
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)*) => {
-        $crate::serial_println!("\x1B[32mDEBUG:\x1B[0m {}", format_args!($($arg)*));
+        $crate::output::klog::log($crate::output::klog::Level::Debug, format_args!($($arg)*));
     };
 }
 
@@ -84,7 +96,7 @@ macro_rules! debug {
 #[macro_export]
 macro_rules! trace {
     ($($arg:tt)*) => {
-        $crate::serial_println!("\x1B[36mTRACE:\x1B[0m {}", format_args!($($arg)*));
+        $crate::output::klog::log($crate::output::klog::Level::Trace, format_args!($($arg)*));
     };
 }
 
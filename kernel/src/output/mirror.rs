@@ -0,0 +1,135 @@
+//! Mirroring of the terminal to a serial port.
+//!
+//! When enabled, every byte written to the flanterm console is also sent
+//! unmodified (ANSI sequences included) to a serial port, so a session can be
+//! recorded, asserted on by integration tests, or followed remotely. The port
+//! is picked with the `mirror=com1|com2` boot argument and can be changed or
+//! turned off at runtime.
+//!
+//! Machines without legacy COM ports can mirror to a registered serial device
+//! such as a USB serial adapter instead, e.g. `mirror=ttyACM0`. The device
+//! may show up after the console starts; it is picked up when it registers.
+//! Output that doesn't fit in the device's buffer is dropped.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    bootargs,
+    serial::{SERIAL1, SERIAL2},
+    sync::Mutex,
+    tty::SerialDevice,
+    warn,
+};
+
+/// Serial ports the terminal can be mirrored to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MirrorPort {
+    /// COM1, shared with the kernel log
+    Com1 = 1,
+    /// COM2
+    Com2 = 2,
+}
+
+impl MirrorPort {
+    /// Parse a port name as used on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "com1" => Some(Self::Com1),
+            "com2" => Some(Self::Com2),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Com1 => "com1",
+            Self::Com2 => "com2",
+        }
+    }
+}
+
+/// Current mirror port, 0 when mirroring is off or to a serial device
+static MIRROR_PORT: AtomicU8 = AtomicU8::new(0);
+
+/// Serial device mirrored to instead of a COM port
+static MIRROR_TTY: Mutex<Option<Arc<dyn SerialDevice>>> = Mutex::new("MIRROR_TTY", None);
+
+/// Apply the `mirror=` boot argument
+pub fn init() {
+    let Some(name) = bootargs::get("mirror") else {
+        return;
+    };
+
+    match MirrorPort::from_name(name) {
+        Some(port) => set_mirror(Some(port)),
+        // picked up by `device_registered`
+        None if name.starts_with("tty") => {}
+        None => {
+            warn!("Unknown mirror port {:?}, mirroring disabled", name);
+        }
+    }
+}
+
+/// Start mirroring to `port`, or stop mirroring with `None`
+pub fn set_mirror(port: Option<MirrorPort>) {
+    interrupts::without_interrupts(|| *MIRROR_TTY.lock() = None);
+    MIRROR_PORT.store(port.map_or(0, |port| port as u8), Ordering::Relaxed);
+}
+
+/// Start mirroring to a serial device instead of a COM port
+pub fn set_mirror_tty(device: Arc<dyn SerialDevice>) {
+    MIRROR_PORT.store(0, Ordering::Relaxed);
+    interrupts::without_interrupts(|| *MIRROR_TTY.lock() = Some(device));
+}
+
+/// The serial device the terminal is mirrored to, if any
+pub fn mirror_tty() -> Option<Arc<dyn SerialDevice>> {
+    interrupts::without_interrupts(|| MIRROR_TTY.lock().clone())
+}
+
+/// Called when a serial device registers, to start mirroring to it if the
+/// boot argument names it, or to keep mirroring to it if it was registered
+/// again
+pub fn device_registered(device: Arc<dyn SerialDevice>) {
+    let replaces = mirror_tty().is_some_and(|current| current.name() == device.name());
+    if replaces || bootargs::get("mirror") == Some(device.name()) {
+        set_mirror_tty(device);
+    }
+}
+
+/// The port the terminal is mirrored to, if any
+pub fn mirror_port() -> Option<MirrorPort> {
+    match MIRROR_PORT.load(Ordering::Relaxed) {
+        1 => Some(MirrorPort::Com1),
+        2 => Some(MirrorPort::Com2),
+        _ => None,
+    }
+}
+
+/// Send terminal output to the mirror port, if mirroring is enabled
+///
+/// Interrupts stay off throughout, as the caller holds the console lock and
+/// an interrupt handler printing while the serial port is held would
+/// deadlock.
+pub fn mirror(text: &str) {
+    interrupts::without_interrupts(|| {
+        let Some(port) = mirror_port() else {
+            if let Some(device) = MIRROR_TTY.lock().clone() {
+                let _ = device.write(text.as_bytes());
+            }
+            return;
+        };
+
+        let mut serial = match port {
+            MirrorPort::Com1 => SERIAL1.lock(),
+            MirrorPort::Com2 => SERIAL2.lock(),
+        };
+        for byte in text.bytes() {
+            serial.send_raw(byte);
+        }
+    })
+}
@@ -0,0 +1,178 @@
+//! Framebuffer screenshots
+//!
+//! `capture` copies the framebuffer, converted from its pixel format to
+//! 8-bit RGB, while the terminal is locked so no write is caught halfway.
+//! A `Screenshot` can be encoded as a BMP, which any image viewer opens, or
+//! as raw RGB triples for comparing pixels exactly, and either can be
+//! written to a file or sent over COM1 in base64 between marker lines.
+
+use alloc::{string::String, vec::Vec};
+
+use x86_64::instructions::interrupts;
+
+use super::{FLANTERM, framebuffer::FramebufferInfo};
+use crate::{serial_println, sync::Mutex};
+
+/// The framebuffer the terminal draws to
+struct Framebuffer {
+    address: usize,
+    info: FramebufferInfo,
+}
+
+static FRAMEBUFFER: Mutex<Option<Framebuffer>> = Mutex::new("SCREENSHOT_FRAMEBUFFER", None);
+
+/// Bytes of a BMP file header and info header
+const BMP_HEADER_SIZE: usize = 14 + 40;
+
+/// Base64 characters per line sent over serial
+const SERIAL_LINE_LENGTH: usize = 76;
+
+/// Remember the framebuffer, called by `flanterm_init`
+pub fn init(framebuffer: *mut u32, info: FramebufferInfo) {
+    *FRAMEBUFFER.lock() = Some(Framebuffer {
+        address: framebuffer as usize,
+        info,
+    });
+}
+
+/// A copy of the screen
+pub struct Screenshot {
+    pub width: usize,
+    pub height: usize,
+    /// Pixels as `0x00RRGGBB`, row by row from the top
+    pub pixels: Vec<u32>,
+}
+
+/// Scale a channel of `size` bits to 8 bits
+fn channel(pixel: u32, shift: u8, size: u8) -> u32 {
+    if size == 0 {
+        return 0;
+    }
+    let value = (pixel >> shift) & ((1 << size) - 1);
+    if size >= 8 {
+        value >> (size - 8)
+    } else {
+        value * 255 / ((1 << size) - 1)
+    }
+}
+
+/// Copy the framebuffer, or `None` if there is none
+pub fn capture() -> Option<Screenshot> {
+    interrupts::without_interrupts(|| {
+        // hold the terminal so it doesn't draw while the copy is taken
+        let _terminal = FLANTERM.lock();
+        let framebuffer = FRAMEBUFFER.lock();
+        let Framebuffer { address, info } = framebuffer.as_ref()?;
+
+        let mut pixels = Vec::with_capacity(info.width * info.height);
+        for y in 0..info.height {
+            let row = address + y * info.pitch;
+            for x in 0..info.width {
+                let pixel = row + x * info.bpp;
+                let mut value = 0u32;
+                for byte in 0..info.bpp.min(4) {
+                    let byte_value = unsafe { ((pixel + byte) as *const u8).read_volatile() };
+                    value |= (byte_value as u32) << (byte * 8);
+                }
+                pixels.push(
+                    channel(value, info.red_mask_shift, info.red_mask_size) << 16
+                        | channel(value, info.green_mask_shift, info.green_mask_size) << 8
+                        | channel(value, info.blue_mask_shift, info.blue_mask_size),
+                );
+            }
+        }
+        Some(Screenshot {
+            width: info.width,
+            height: info.height,
+            pixels,
+        })
+    })
+}
+
+impl Screenshot {
+    /// The pixel at column `x` of row `y`, as `0x00RRGGBB`
+    pub fn pixel(&self, x: usize, y: usize) -> Option<u32> {
+        if x >= self.width {
+            return None;
+        }
+        self.pixels.get(y * self.width + x).copied()
+    }
+
+    /// RGB triples row by row from the top, with no header
+    pub fn raw(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|&pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8])
+            .collect()
+    }
+
+    /// A 24-bit uncompressed BMP
+    pub fn bmp(&self) -> Vec<u8> {
+        // rows are padded to 4 bytes and stored from the bottom
+        let row_size = (self.width * 3).next_multiple_of(4);
+        let image_size = row_size * self.height;
+        let file_size = BMP_HEADER_SIZE + image_size;
+
+        let mut bmp = Vec::with_capacity(file_size);
+        bmp.extend_from_slice(b"BM");
+        bmp.extend_from_slice(&(file_size as u32).to_le_bytes());
+        bmp.extend_from_slice(&[0; 4]);
+        bmp.extend_from_slice(&(BMP_HEADER_SIZE as u32).to_le_bytes());
+
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.extend_from_slice(&(self.width as i32).to_le_bytes());
+        bmp.extend_from_slice(&(self.height as i32).to_le_bytes());
+        bmp.extend_from_slice(&1u16.to_le_bytes());
+        bmp.extend_from_slice(&24u16.to_le_bytes());
+        // no compression
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&(image_size as u32).to_le_bytes());
+        // 72 dpi, no palette
+        bmp.extend_from_slice(&2835u32.to_le_bytes());
+        bmp.extend_from_slice(&2835u32.to_le_bytes());
+        bmp.extend_from_slice(&[0; 8]);
+
+        for row in self.pixels.chunks(self.width.max(1)).rev() {
+            for &pixel in row {
+                bmp.extend_from_slice(&[pixel as u8, (pixel >> 8) as u8, (pixel >> 16) as u8]);
+            }
+            bmp.resize(bmp.len() + row_size - self.width * 3, 0);
+        }
+        bmp
+    }
+}
+
+/// Encode `data` as base64, with padding
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Send `data` over COM1 in base64 between marker lines naming it
+///
+/// On the host, the lines between the markers decode with `base64 -d`.
+pub fn send_serial(name: &str, data: &[u8]) {
+    let encoded = base64(data);
+    serial_println!("-----BEGIN {}-----", name);
+    for line in encoded.as_bytes().chunks(SERIAL_LINE_LENGTH) {
+        serial_println!("{}", core::str::from_utf8(line).unwrap_or_default());
+    }
+    serial_println!("-----END {}-----", name);
+}
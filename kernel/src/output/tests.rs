@@ -3,3 +3,35 @@
 fn test_output() {
     println!("hello world!");
 }
+
+#[cfg(feature = "graphics")]
+#[test_case]
+fn test_screenshot_bmp_layout() {
+    use super::screenshot::Screenshot;
+
+    let screenshot = Screenshot {
+        width: 2,
+        height: 2,
+        pixels: alloc::vec![0xff0000, 0x00ff00, 0x0000ff, 0xffffff],
+    };
+    let bmp = screenshot.bmp();
+    // two rows of 6 bytes, padded to 8
+    assert_eq!(bmp.len(), 54 + 16);
+    assert_eq!(&bmp[..2], b"BM");
+    assert_eq!(u32::from_le_bytes(bmp[2..6].try_into().unwrap()), 70);
+    // the bottom row comes first, in BGR
+    assert_eq!(&bmp[54..60], &[0xff, 0, 0, 0xff, 0xff, 0xff]);
+    assert_eq!(&bmp[62..68], &[0, 0, 0xff, 0, 0xff, 0]);
+    assert_eq!(screenshot.pixel(1, 0), Some(0x00ff00));
+    assert_eq!(screenshot.pixel(2, 0), None);
+}
+
+#[cfg(feature = "graphics")]
+#[test_case]
+fn test_base64_padding() {
+    use super::screenshot::base64;
+
+    assert_eq!(base64(b"M"), "TQ==");
+    assert_eq!(base64(b"Ma"), "TWE=");
+    assert_eq!(base64(b"Man"), "TWFu");
+}
@@ -0,0 +1,100 @@
+//! Terminal device interface for full-screen programs
+//!
+//! Editors and pagers need to know how big the screen is and to move the
+//! cursor away and back. With graphics the size comes from the flanterm
+//! context; the serial console can't report one, so the conventional 80x24
+//! is assumed. Cursor save and restore go through the same escape sequences
+//! on both, so a mirrored serial terminal follows along.
+
+use crate::print;
+
+/// Get the window size into a `WindowSize`, as on Linux
+pub const TIOCGWINSZ: u64 = 0x5413;
+
+/// Save the cursor position and attributes, locOS specific
+pub const TIOCSAVECURSOR: u64 = 0x5480;
+
+/// Restore the cursor saved by `TIOCSAVECURSOR`, locOS specific
+pub const TIOCRESTORECURSOR: u64 = 0x5481;
+
+/// Get the cursor position into a `CursorPosition`, locOS specific
+pub const TIOCGCURSOR: u64 = 0x5482;
+
+/// Size assumed for terminals that can't report theirs
+const DEFAULT_ROWS: u16 = 24;
+const DEFAULT_COLUMNS: u16 = 80;
+
+/// Terminal size, laid out like Linux's `struct winsize`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowSize {
+    pub rows: u16,
+    pub columns: u16,
+    /// Size in pixels, 0 if unknown
+    pub width: u16,
+    pub height: u16,
+}
+
+/// Cursor row and column, counted from 0
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorPosition {
+    pub row: u16,
+    pub column: u16,
+}
+
+#[cfg(feature = "graphics")]
+pub fn window_size() -> WindowSize {
+    let console = super::FLANTERM.lock();
+    let Some(console) = console.as_ref() else {
+        return default_window_size();
+    };
+    let (rows, columns) = console.size();
+    let (width, height) = console.pixel_size();
+    WindowSize {
+        rows: rows as u16,
+        columns: columns as u16,
+        width: width as u16,
+        height: height as u16,
+    }
+}
+
+#[cfg(not(feature = "graphics"))]
+pub fn window_size() -> WindowSize {
+    default_window_size()
+}
+
+fn default_window_size() -> WindowSize {
+    WindowSize {
+        rows: DEFAULT_ROWS,
+        columns: DEFAULT_COLUMNS,
+        width: 0,
+        height: 0,
+    }
+}
+
+/// Cursor position, None if the terminal can't report it
+#[cfg(feature = "graphics")]
+pub fn cursor_position() -> Option<CursorPosition> {
+    let (row, column) = super::FLANTERM.lock().as_ref()?.cursor_position();
+    Some(CursorPosition {
+        row: row as u16,
+        column: column as u16,
+    })
+}
+
+/// Cursor position, None if the terminal can't report it
+#[cfg(not(feature = "graphics"))]
+pub fn cursor_position() -> Option<CursorPosition> {
+    None
+}
+
+/// Save the cursor position and attributes (DECSC)
+pub fn save_cursor() {
+    print!("\x1b7");
+}
+
+/// Restore the cursor saved by `save_cursor` (DECRC)
+pub fn restore_cursor() {
+    print!("\x1b8");
+}
@@ -9,23 +9,27 @@
 //! - MSI-X interrupt setup and management
 //! - Device driver interface and registration
 
+pub mod aer;
 pub mod config;
 pub mod device;
+pub mod health;
 pub mod mcfg;
 pub mod msi;
+pub mod pm;
+pub mod probe;
 pub mod vmm;
+#[cfg(any(feature = "usb", feature = "nvme"))]
 pub mod dma;
 
+#[cfg(feature = "usb")]
 pub mod usb;
+#[cfg(feature = "nvme")]
 pub mod nvme;
 
-pub use usb::init;
-
 #[cfg(test)]
 pub mod tests;
 
 use alloc::vec::Vec;
-use spin::Mutex;
 
 use crate::{
     info,
@@ -34,11 +38,15 @@ use crate::{
         vmm::PCIE_VMM,
         msi::MsiXInfo,
     },
+    sync::{Mutex, rcu::Rcu},
     warn,
 };
 
 /// Global PCIe manager instance
-pub static PCI_MANAGER: Mutex<Option<PciManager>> = Mutex::new(None);
+pub static PCI_MANAGER: Mutex<Option<PciManager>> = Mutex::new("PCI_MANAGER", None);
+
+/// Discovered PCIe devices, readable without taking `PCI_MANAGER`
+pub static PCI_DEVICES: Rcu<Vec<device::PciDevice>> = Rcu::new();
 
 /// Main PCIe management structure
 pub struct PciManager {
@@ -84,8 +92,11 @@ impl PciManager {
 
         self.enumerate_devices()?;
         info!("Discovered {} PCIe devices", self.devices.len());
+        PCI_DEVICES.update(|_| self.devices.clone());
 
         self.check_bar_assignment();
+
+        aer::init(&self.devices);
         Ok(())
     }
 
@@ -239,6 +250,17 @@ pub enum PciError {
     AllocationFailed,
 }
 
+/// Initialize the drivers for every PCIe subsystem enabled at compile time.
+///
+/// Must be called after `init_pci` and once multitasking is running, since
+/// drivers block on their completion interrupts. Each device is probed in its
+/// own task, see `probe`.
+pub fn init_drivers() {
+    aer::spawn_monitor();
+    probe::spawn_probes();
+    health::spawn_monitor();
+}
+
 /// Initialize the global PCIe manager
 pub fn init_pci(rsdp_addr: usize) -> Result<(), PciError> {
     let mut manager = PciManager::new();
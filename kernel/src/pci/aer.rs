@@ -0,0 +1,271 @@
+//! PCIe Advanced Error Reporting
+//!
+//! Every PCIe function with an AER extended capability gets error reporting
+//! enabled and its stale error status cleared at boot. Root ports forward the
+//! errors of their hierarchy as an MSI on `AER_VECTOR`, which wakes the AER
+//! monitor task; the monitor decodes, logs and clears the status registers of
+//! every function and keeps per-device error counts. Root ports without MSI
+//! can still be checked on demand with `scan`.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use kernel_api::wait::WaitQueue;
+use x86_64::instructions::interrupts;
+
+use super::{
+    config::{
+        aer_offsets, aer_root_command_bits, capability_ids, extended_capability_ids,
+        pcie_device_control_bits, pcie_offsets, pcie_port_types,
+    },
+    device::PciDevice,
+    msi::setup_msi,
+};
+use crate::{
+    debug, error, info,
+    sync::Mutex,
+    tasks::scheduler::kcreate_task,
+    warn,
+};
+
+/// Interrupt vector root ports signal AER errors on
+pub const AER_VECTOR: u8 = 0x58;
+
+/// Uncorrectable Error Status register bits
+const UNCORRECTABLE_ERRORS: &[(u32, &str)] = &[
+    (1 << 4, "Data Link Protocol Error"),
+    (1 << 5, "Surprise Down"),
+    (1 << 12, "Poisoned TLP"),
+    (1 << 13, "Flow Control Protocol Error"),
+    (1 << 14, "Completion Timeout"),
+    (1 << 15, "Completer Abort"),
+    (1 << 16, "Unexpected Completion"),
+    (1 << 17, "Receiver Overflow"),
+    (1 << 18, "Malformed TLP"),
+    (1 << 19, "ECRC Error"),
+    (1 << 20, "Unsupported Request"),
+    (1 << 21, "ACS Violation"),
+    (1 << 22, "Uncorrectable Internal Error"),
+    (1 << 23, "MC Blocked TLP"),
+    (1 << 24, "AtomicOp Egress Blocked"),
+    (1 << 25, "TLP Prefix Blocked"),
+    (1 << 26, "Poisoned TLP Egress Blocked"),
+];
+
+/// Correctable Error Status register bits
+const CORRECTABLE_ERRORS: &[(u32, &str)] = &[
+    (1 << 0, "Receiver Error"),
+    (1 << 6, "Bad TLP"),
+    (1 << 7, "Bad DLLP"),
+    (1 << 8, "REPLAY_NUM Rollover"),
+    (1 << 12, "Replay Timer Timeout"),
+    (1 << 13, "Advisory Non-Fatal Error"),
+    (1 << 14, "Corrected Internal Error"),
+    (1 << 15, "Header Log Overflow"),
+];
+
+/// A PCIe function with AER enabled
+#[derive(Debug, Clone)]
+pub struct AerDevice {
+    pub device: PciDevice,
+    /// Offset of the AER extended capability
+    pub offset: u16,
+    /// Whether this is a root port collecting errors from its hierarchy
+    pub root_port: bool,
+    /// Number of correctable errors seen
+    pub correctable: u64,
+    /// Number of uncorrectable (non-fatal or fatal) errors seen
+    pub uncorrectable: u64,
+}
+
+/// Every function AER was enabled on
+pub static AER_DEVICES: Mutex<Vec<AerDevice>> = Mutex::new("AER_DEVICES", Vec::new());
+
+/// Set by the interrupt handler until the monitor scans again, so errors
+/// signalled while it is still scanning aren't missed
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Where the monitor waits for `PENDING`
+static MONITOR: WaitQueue = WaitQueue::new();
+
+/// Enable AER on every function supporting it and clear stale errors
+pub fn init(devices: &[PciDevice]) {
+    let mut aer_devices = Vec::new();
+
+    for device in devices {
+        let (Some(pcie), Some(offset)) = (
+            device.find_capability(capability_ids::PCI_EXPRESS),
+            device.find_extended_capability(extended_capability_ids::ADVANCED_ERROR_REPORTING),
+        ) else {
+            continue;
+        };
+        let pcie = pcie as u16;
+
+        let port_type = ((device.read_config_u16(pcie + pcie_offsets::CAPABILITIES) >> 4) & 0xF) as u8;
+        let root_port = matches!(
+            port_type,
+            pcie_port_types::ROOT_PORT | pcie_port_types::ROOT_COMPLEX_EVENT_COLLECTOR
+        );
+
+        let mut aer_device = AerDevice {
+            device: device.clone(),
+            offset,
+            root_port,
+            correctable: 0,
+            uncorrectable: 0,
+        };
+
+        // errors logged by firmware before we got here
+        check_device(&mut aer_device);
+
+        let control = device.read_config_u16(pcie + pcie_offsets::DEVICE_CONTROL);
+        device.write_config_u16(
+            pcie + pcie_offsets::DEVICE_CONTROL,
+            control
+                | pcie_device_control_bits::CORRECTABLE_ERROR_REPORTING
+                | pcie_device_control_bits::NON_FATAL_ERROR_REPORTING
+                | pcie_device_control_bits::FATAL_ERROR_REPORTING
+                | pcie_device_control_bits::UNSUPPORTED_REQUEST_REPORTING,
+        );
+
+        if root_port {
+            if setup_msi(device, AER_VECTOR).is_ok() {
+                device.write_config_u32(
+                    offset + aer_offsets::ROOT_ERROR_COMMAND,
+                    aer_root_command_bits::CORRECTABLE_ERROR_REPORTING
+                        | aer_root_command_bits::NON_FATAL_ERROR_REPORTING
+                        | aer_root_command_bits::FATAL_ERROR_REPORTING,
+                );
+            } else {
+                warn!(
+                    "AER: root port {:02x}:{:02x}.{} has no MSI, errors are only polled",
+                    device.bus, device.device, device.function
+                );
+            }
+        }
+
+        debug!(
+            "AER enabled for {:02x}:{:02x}.{} (root port: {})",
+            device.bus, device.device, device.function, root_port
+        );
+        aer_devices.push(aer_device);
+    }
+
+    info!("AER enabled on {} PCIe function(s)", aer_devices.len());
+    *AER_DEVICES.lock() = aer_devices;
+}
+
+/// Called from the AER interrupt handler
+pub fn handle_interrupt() {
+    PENDING.store(true, Ordering::Release);
+    MONITOR.wake_all();
+}
+
+/// Start the task logging errors signalled by root ports
+pub fn spawn_monitor() {
+    if AER_DEVICES.lock().iter().any(|aer| aer.root_port) {
+        interrupts::without_interrupts(|| kcreate_task(aer_monitor, "pcie aer"));
+    }
+}
+
+fn aer_monitor() -> ! {
+    loop {
+        MONITOR.wait_until(|| PENDING.swap(false, Ordering::Acquire));
+        scan();
+    }
+}
+
+/// Check every AER function for errors, logging and clearing them
+///
+/// Returns the number of functions that reported errors.
+pub fn scan() -> usize {
+    AER_DEVICES
+        .lock()
+        .iter_mut()
+        .map(check_device)
+        .filter(|&reported| reported)
+        .count()
+}
+
+/// Log and clear the errors recorded by one function
+#[allow(unused_variables)]
+fn check_device(aer: &mut AerDevice) -> bool {
+    let device = &aer.device;
+    let base = aer.offset;
+
+    let uncorrectable = device.read_config_u32(base + aer_offsets::UNCORRECTABLE_STATUS);
+    let correctable = device.read_config_u32(base + aer_offsets::CORRECTABLE_STATUS);
+
+    if uncorrectable != 0 {
+        let severity = device.read_config_u32(base + aer_offsets::UNCORRECTABLE_SEVERITY);
+        let header: [u32; 4] = core::array::from_fn(|i| {
+            device.read_config_u32(base + aer_offsets::HEADER_LOG + i as u16 * 4)
+        });
+        report(device, "uncorrectable", uncorrectable, UNCORRECTABLE_ERRORS, Some(severity));
+        error!(
+            "  TLP header: {:08x} {:08x} {:08x} {:08x}",
+            header[0], header[1], header[2], header[3]
+        );
+        aer.uncorrectable += uncorrectable.count_ones() as u64;
+        device.write_config_u32(base + aer_offsets::UNCORRECTABLE_STATUS, uncorrectable);
+    }
+
+    if correctable != 0 {
+        report(device, "correctable", correctable, CORRECTABLE_ERRORS, None);
+        aer.correctable += correctable.count_ones() as u64;
+        device.write_config_u32(base + aer_offsets::CORRECTABLE_STATUS, correctable);
+    }
+
+    if aer.root_port {
+        let root_status = device.read_config_u32(base + aer_offsets::ROOT_ERROR_STATUS);
+        if root_status != 0 {
+            let source = device.read_config_u32(base + aer_offsets::ERROR_SOURCE_ID);
+            debug!(
+                "AER root port {:02x}:{:02x}.{}: status {:#x}, sources {:#x}",
+                device.bus, device.device, device.function, root_status, source
+            );
+            device.write_config_u32(base + aer_offsets::ROOT_ERROR_STATUS, root_status);
+        }
+    }
+
+    // Device Status error bits (3:0) are RW1C as well
+    if let Some(pcie) = device.find_capability(capability_ids::PCI_EXPRESS) {
+        let offset = pcie as u16 + pcie_offsets::DEVICE_STATUS;
+        let status = device.read_config_u16(offset);
+        if status & 0xF != 0 {
+            device.write_config_u16(offset, status & 0xF);
+        }
+    }
+
+    uncorrectable != 0 || correctable != 0
+}
+
+/// Log every error bit set in `status`
+#[allow(unused_variables)]
+fn report(
+    device: &PciDevice,
+    kind: &str,
+    status: u32,
+    names: &[(u32, &'static str)],
+    severity: Option<u32>,
+) {
+    for &(bit, name) in names.iter().filter(|(bit, _)| status & bit != 0) {
+        let level = match severity {
+            Some(severity) if severity & bit != 0 => "fatal",
+            Some(_) => "non-fatal",
+            None => "corrected",
+        };
+        error!(
+            "AER {:02x}:{:02x}.{}: {} error ({}): {}",
+            device.bus, device.device, device.function, kind, level, name
+        );
+    }
+
+    let unknown = names.iter().fold(status, |status, (bit, _)| status & !bit);
+    if unknown != 0 {
+        warn!(
+            "AER {:02x}:{:02x}.{}: unknown {} error bits {:#x}",
+            device.bus, device.device, device.function, kind, unknown
+        );
+    }
+}
@@ -219,6 +219,52 @@ pub mod msix_control_bits {
     pub const MSI_X_ENABLE: u16 = 1 << 15;
 }
 
+/// PCI Express capability structure offsets
+pub mod pcie_offsets {
+    pub const CAPABILITIES: u16 = 0x02;
+    pub const DEVICE_CONTROL: u16 = 0x08;
+    pub const DEVICE_STATUS: u16 = 0x0A;
+}
+
+/// PCI Express Device Control register bits
+pub mod pcie_device_control_bits {
+    pub const CORRECTABLE_ERROR_REPORTING: u16 = 1 << 0;
+    pub const NON_FATAL_ERROR_REPORTING: u16 = 1 << 1;
+    pub const FATAL_ERROR_REPORTING: u16 = 1 << 2;
+    pub const UNSUPPORTED_REQUEST_REPORTING: u16 = 1 << 3;
+}
+
+/// PCI Express device/port types (PCIe Capabilities register bits 7:4)
+pub mod pcie_port_types {
+    pub const ENDPOINT: u8 = 0x0;
+    pub const LEGACY_ENDPOINT: u8 = 0x1;
+    pub const ROOT_PORT: u8 = 0x4;
+    pub const UPSTREAM_SWITCH_PORT: u8 = 0x5;
+    pub const DOWNSTREAM_SWITCH_PORT: u8 = 0x6;
+    pub const ROOT_COMPLEX_EVENT_COLLECTOR: u8 = 0xA;
+}
+
+/// Advanced Error Reporting extended capability offsets
+pub mod aer_offsets {
+    pub const UNCORRECTABLE_STATUS: u16 = 0x04;
+    pub const UNCORRECTABLE_MASK: u16 = 0x08;
+    pub const UNCORRECTABLE_SEVERITY: u16 = 0x0C;
+    pub const CORRECTABLE_STATUS: u16 = 0x10;
+    pub const CORRECTABLE_MASK: u16 = 0x14;
+    pub const CAPABILITIES_CONTROL: u16 = 0x18;
+    pub const HEADER_LOG: u16 = 0x1C;
+    pub const ROOT_ERROR_COMMAND: u16 = 0x2C;   // Root ports only
+    pub const ROOT_ERROR_STATUS: u16 = 0x30;    // Root ports only
+    pub const ERROR_SOURCE_ID: u16 = 0x34;      // Root ports only
+}
+
+/// AER Root Error Command register bits
+pub mod aer_root_command_bits {
+    pub const CORRECTABLE_ERROR_REPORTING: u32 = 1 << 0;
+    pub const NON_FATAL_ERROR_REPORTING: u32 = 1 << 1;
+    pub const FATAL_ERROR_REPORTING: u32 = 1 << 2;
+}
+
 /// MSI-X Table Entry structure
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
@@ -14,7 +14,11 @@ use crate::debug;
 
 use super::{
     PciError,
-    mcfg::{EcamRegion, read_config_u8, read_config_u16, read_config_u32},
+    config::{capability_ids, extended_capability_ids},
+    mcfg::{
+        EcamRegion, read_config_u8, read_config_u16, read_config_u32, write_config_u16,
+        write_config_u32,
+    },
 };
 
 /// PCIe configuration space offsets
@@ -131,6 +135,8 @@ pub struct PciDevice {
     pub bars: [BarInfo; 6],
     /// Map of capability ID to capability offset
     pub capabilities: BTreeMap<u8, u8>,
+    /// Map of extended capability ID to capability offset (PCIe only)
+    pub extended_capabilities: BTreeMap<u16, u16>,
     /// Interrupt line
     pub interrupt_line: u8,
     /// Interrupt pin
@@ -208,6 +214,31 @@ impl PciDevice {
     pub fn find_capability(&self, cap_id: u8) -> Option<u8> {
         self.capabilities.get(&cap_id).copied()
     }
+
+    /// Find an extended capability by ID, returns the offset if found
+    pub fn find_extended_capability(&self, cap_id: u16) -> Option<u16> {
+        self.extended_capabilities.get(&cap_id).copied()
+    }
+
+    /// Read a 16-bit register from this device's configuration space
+    pub fn read_config_u16(&self, offset: u16) -> u16 {
+        read_config_u16(&self.ecam_region, self.bus, self.device, self.function, offset)
+    }
+
+    /// Read a 32-bit register from this device's configuration space
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        read_config_u32(&self.ecam_region, self.bus, self.device, self.function, offset)
+    }
+
+    /// Write a 16-bit register in this device's configuration space
+    pub fn write_config_u16(&self, offset: u16, value: u16) {
+        write_config_u16(&self.ecam_region, self.bus, self.device, self.function, offset, value)
+    }
+
+    /// Write a 32-bit register in this device's configuration space
+    pub fn write_config_u32(&self, offset: u16, value: u32) {
+        write_config_u32(&self.ecam_region, self.bus, self.device, self.function, offset, value)
+    }
 }
 
 impl fmt::Display for PciDevice {
@@ -323,6 +354,11 @@ pub fn probe_device(
 
     // Parse capabilities
     let capabilities = parse_capabilities(ecam_region, bus, device, function)?;
+    let extended_capabilities = if capabilities.contains_key(&capability_ids::PCI_EXPRESS) {
+        parse_extended_capabilities(ecam_region, bus, device, function)
+    } else {
+        BTreeMap::new()
+    };
 
     debug!(
         "Found PCIe device: {:02x}:{:02x}.{} [{:04x}:{:04x}] class={:02x}:{:02x}",
@@ -345,6 +381,7 @@ pub fn probe_device(
         subsystem_id,
         bars,
         capabilities,
+        extended_capabilities,
         interrupt_line,
         interrupt_pin,
     }))
@@ -461,6 +498,41 @@ fn parse_capabilities(
     Ok(capabilities)
 }
 
+/// Walk the extended capability list in the extended configuration space
+///
+/// Extended capabilities start at offset 0x100 and each header holds the
+/// capability ID (bits 15:0), version (bits 19:16) and next offset (bits 31:20).
+fn parse_extended_capabilities(
+    ecam_region: &EcamRegion,
+    bus: u8,
+    device: u8,
+    function: u8,
+) -> BTreeMap<u16, u16> {
+    let mut capabilities = BTreeMap::new();
+    let mut offset = 0x100u16;
+
+    // A well formed list can't hold more than (4096 - 256) / 4 entries
+    for _ in 0..960 {
+        let header = read_config_u32(ecam_region, bus, device, function, offset);
+        if header == 0 || header == 0xFFFF_FFFF {
+            break;
+        }
+
+        let cap_id = (header & 0xFFFF) as u16;
+        if cap_id != extended_capability_ids::NULL {
+            capabilities.entry(cap_id).or_insert(offset);
+        }
+
+        let next = ((header >> 20) & 0xFFC) as u16;
+        if next < 0x100 {
+            break;
+        }
+        offset = next;
+    }
+
+    capabilities
+}
+
 /// Determine the size of a memory BAR using the standard write-all-1s method
 fn determine_bar_size(
     ecam_region: &EcamRegion,
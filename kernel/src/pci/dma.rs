@@ -1,17 +1,49 @@
-use core::ops::Deref;
+use core::{
+    ops::Deref,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use alloc::vec::Vec;
-use spin::{Lazy, Mutex};
+use spin::Lazy;
 use x86_64::{PhysAddr, VirtAddr};
 
-use crate::memory::FRAME_ALLOCATOR;
+use crate::{
+    memory::{FRAME_ALLOCATOR, compact, oom::DMA_ALARM},
+    sync::Mutex,
+};
 
-pub(crate) static DMA_MANAGER: Lazy<Mutex<DmaManager>> =
-    Lazy::new(|| Mutex::new(DmaManager::new().expect("DMA initialization failed (OOM)")));
+pub(crate) static DMA_MANAGER: Lazy<Mutex<DmaManager>> = Lazy::new(|| {
+    Mutex::new(
+        "DMA_MANAGER",
+        DmaManager::new().expect("DMA initialization failed (OOM)"),
+    )
+});
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct DmaError;
 
+/// Frames held by DMA buffers, pooled ones included
+static DMA_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of frames held by DMA buffers
+pub(crate) fn dma_frames() -> usize {
+    DMA_FRAMES.load(Ordering::Relaxed)
+}
+
+/// Track `frames` frames being taken (positive) or returned (negative)
+fn account_dma_frames(frames: isize) {
+    let held = if frames >= 0 {
+        DMA_FRAMES.fetch_add(frames as usize, Ordering::Relaxed) + frames as usize
+    } else {
+        DMA_FRAMES.fetch_sub(frames.unsigned_abs(), Ordering::Relaxed) - frames.unsigned_abs()
+    };
+    let total = FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map_or(0, |allocator| allocator.total_frames());
+    DMA_ALARM.update(held * 4096, total * 4096);
+}
+
 #[derive(Debug)]
 pub(crate) struct DmaManager {
     pub pools_4kb: DmaPool,
@@ -31,28 +63,43 @@ impl DmaManager {
     pub fn free_buffer_4kb(&mut self, buffer: DmaBuffer) {
         self.pools_4kb.free_buffer(buffer);
     }
+
+    /// Whether `buffer` is one of the pooled 4 KiB buffers
+    pub fn is_pooled_4kb(&self, buffer: &DmaBuffer) -> bool {
+        self.pools_4kb
+            .buffers
+            .iter()
+            .any(|pooled| pooled.virt_addr == buffer.virt_addr)
+    }
+
+    /// Returns the number of pooled 4 KiB buffers handed out
+    pub fn buffers_4kb_in_use(&self) -> usize {
+        self.pools_4kb.buffers.len() - self.pools_4kb.free_buffers.len()
+    }
+
+    /// Returns the number of pooled 4 KiB buffers
+    pub fn buffers_4kb_total(&self) -> usize {
+        self.pools_4kb.buffers.len()
+    }
 }
 
 /// dynamically allocate dma
 pub(crate) fn get_zeroed_dma(frames: usize) -> Result<DynamicDmaBuffer, DmaError> {
-    let buffer = get_zeroed_dma_internal(frames)?;
+    let buffer = allocate_zeroed_dma(frames)?;
     Ok(DynamicDmaBuffer { buffer })
 }
 
-/// Helper function for internal use during DmaPool initialization
-fn get_zeroed_dma_internal(frames: usize) -> Result<DmaBuffer, DmaError> {
-    let mut lock = FRAME_ALLOCATOR.lock();
-    let allocator = lock.as_mut().ok_or(DmaError)?;
-
-    let virt = allocator
-        .allocate_contiguous_pages(frames)
-        .ok_or(DmaError)?;
+/// Allocate zeroed frames for DMA without tying them to a buffer owner
+pub(crate) fn allocate_zeroed_dma(frames: usize) -> Result<DmaBuffer, DmaError> {
+    let phys = compact::allocate_contiguous_frames(frames).ok_or(DmaError)?;
+    let hddm_offset = FRAME_ALLOCATOR.lock().as_ref().ok_or(DmaError)?.hddm_offset;
+    let virt = VirtAddr::new(phys.as_u64() + hddm_offset);
+    account_dma_frames(frames as isize);
 
     unsafe {
         core::ptr::write_bytes(virt.as_mut_ptr::<()>(), 0, frames * 4096);
     }
 
-    let phys = PhysAddr::new(virt.as_u64() - allocator.hddm_offset);
     Ok(DmaBuffer {
         phys_addr: phys,
         virt_addr: virt,
@@ -60,11 +107,14 @@ fn get_zeroed_dma_internal(frames: usize) -> Result<DmaBuffer, DmaError> {
     })
 }
 
-fn free_zeroed_dma(buffer: DmaBuffer) -> Result<(), DmaError> {
+/// Free frames from `allocate_zeroed_dma`
+pub(crate) fn free_zeroed_dma(buffer: DmaBuffer) -> Result<(), DmaError> {
     let mut lock = FRAME_ALLOCATOR.lock();
     let allocator = lock.as_mut().ok_or(DmaError)?;
 
     unsafe { allocator.deallocate_contiguous_frames(buffer.phys_addr, buffer.size) };
+    drop(lock);
+    account_dma_frames(-(buffer.size as isize));
 
     Ok(())
 }
@@ -127,7 +177,7 @@ impl DmaPool {
     pub fn new(buffer_size_frames: usize, num_buffers: usize) -> Result<Self, DmaError> {
         let mut buffers = Vec::with_capacity(num_buffers);
         for _ in 0..num_buffers {
-            let buffer = get_zeroed_dma_internal(buffer_size_frames)?;
+            let buffer = allocate_zeroed_dma(buffer_size_frames)?;
             buffers.push(buffer);
         }
 
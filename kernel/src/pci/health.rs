@@ -0,0 +1,97 @@
+//! Controller health monitoring
+//!
+//! Some controller errors aren't reported by an interrupt and aren't
+//! recovered from without a reset: an NVMe controller setting Controller
+//! Fatal Status, an xHCI controller flagging a Host Controller or Host System
+//! Error. Every `pci.health_interval_ms`, the health monitor asks the driver
+//! of each bound device whether it is healthy. A device that isn't has its
+//! diagnostics logged and is reset by its driver, which fails the requests
+//! it had in flight and brings it back as a probe would. A device whose
+//! reset fails is marked as failed and no longer checked.
+
+use alloc::format;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use super::probe::{self, PROBES, ProbeState};
+use crate::{
+    error, info,
+    sysctl::Sysctl,
+    tasks::scheduler::{kcreate_task, sleep_ticks},
+    time,
+};
+
+/// Time between checks while they are turned off, to notice them being
+/// turned back on
+const IDLE_INTERVAL_MS: u64 = 1000;
+
+static INTERVAL_MS: AtomicU64 = AtomicU64::new(1000);
+
+pub static SYSCTLS: &[Sysctl] = &[Sysctl {
+    name: "pci.health_interval_ms",
+    help: "time between controller health checks, 0 to never check",
+    get: || INTERVAL_MS.load(Ordering::Relaxed),
+    set: |value| {
+        if value != 0 && !(10..=600_000).contains(&value) {
+            return Err("must be 0 or 10-600000 ms");
+        }
+        INTERVAL_MS.store(value, Ordering::Relaxed);
+        Ok(())
+    },
+}];
+
+pub fn spawn_monitor() {
+    interrupts::without_interrupts(|| kcreate_task(health_monitor, "pci health"));
+}
+
+fn health_monitor() -> ! {
+    loop {
+        let interval_ms = INTERVAL_MS.load(Ordering::Relaxed);
+        let sleep_ms = if interval_ms == 0 {
+            IDLE_INTERVAL_MS
+        } else {
+            interval_ms
+        };
+        sleep_ticks(time::ms_to_ticks(sleep_ms).max(1));
+        if INTERVAL_MS.load(Ordering::Relaxed) != 0 {
+            check();
+        }
+    }
+}
+
+/// Check every bound device, resetting the unhealthy ones
+///
+/// Returns the number of devices reset successfully.
+#[allow(unused_variables)]
+pub fn check() -> usize {
+    let mut recovered = 0;
+    for index in probe::bound_probes() {
+        let record = PROBES.lock()[index].clone();
+        let Some((driver, device)) = probe::bound_device(&record) else {
+            continue;
+        };
+        let Err(diagnostics) = (driver.health)(&device) else {
+            continue;
+        };
+
+        let (bus, slot, function) = record.location;
+        error!(
+            "PCIe {:02x}:{:02x}.{} ({}): {}, resetting",
+            bus, slot, function, record.driver, diagnostics
+        );
+        match (driver.reset)(device) {
+            Ok(()) => {
+                info!(
+                    "PCIe {:02x}:{:02x}.{} ({}): recovered",
+                    bus, slot, function, record.driver
+                );
+                recovered += 1;
+            }
+            Err(reason) => {
+                probe::set_state(index, ProbeState::Failed(format!("reset: {reason}")))
+            }
+        }
+    }
+    recovered
+}
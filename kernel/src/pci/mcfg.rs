@@ -15,13 +15,14 @@ use x86_64::{
 use crate::{
     debug, info,
     interrupts::apic::KernelAcpiHandler,
-    memory::{FRAME_ALLOCATOR, PAGE_TABLE},
+    memory::{FRAME_ALLOCATOR, PAGE_TABLE, kaslr, reserved},
     warn,
 };
 
 use super::PciError;
 
-/// Virtual address space start for ECAM mappings
+/// Virtual address space start for ECAM mappings, before the KASLR offset is
+/// added
 const ECAM_VIRTUAL_START: u64 = 0xFFFF_F400_0000_0000;
 
 /// Enhanced Configuration Access Mechanism region
@@ -225,7 +226,7 @@ pub fn debug_ecam_region(region: &EcamRegion) {
 /// Map an entire ECAM region to virtual memory
 /// This maps the complete PCIe configuration space for all buses in the region
 pub fn map_ecam_region(region: &mut EcamRegion) -> Result<(), PciError> {
-    static mut NEXT_ECAM_VIRT: u64 = ECAM_VIRTUAL_START;
+    static mut NEXT_ECAM_OFFSET: u64 = 0;
 
     let mapping_size = region.mapping_size();
 
@@ -234,6 +235,8 @@ pub fn map_ecam_region(region: &mut EcamRegion) -> Result<(), PciError> {
         return Err(PciError::EcamMappingFailed);
     }
 
+    reserved::check(region.base_address, mapping_size, "ECAM region");
+
     let pages_needed = mapping_size.div_ceil(0x1000);
 
     // Check for reasonable page count to prevent excessive memory usage
@@ -248,15 +251,15 @@ pub fn map_ecam_region(region: &mut EcamRegion) -> Result<(), PciError> {
     }
 
     unsafe {
-        let virt_base = NEXT_ECAM_VIRT;
+        let virt_base = kaslr::region(ECAM_VIRTUAL_START) + NEXT_ECAM_OFFSET;
 
         // Check for virtual address space overflow
-        if NEXT_ECAM_VIRT.saturating_add(pages_needed * 0x1000) < NEXT_ECAM_VIRT {
+        if virt_base.saturating_add(pages_needed * 0x1000) < virt_base {
             warn!("Virtual address space overflow when mapping ECAM region");
             return Err(PciError::EcamMappingFailed);
         }
 
-        NEXT_ECAM_VIRT += pages_needed * 0x1000;
+        NEXT_ECAM_OFFSET += pages_needed * 0x1000;
 
         region.virtual_address = VirtAddr::new(virt_base);
 
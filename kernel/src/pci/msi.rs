@@ -17,14 +17,14 @@ use crate::{info, warn};
 use super::{
     PciError,
     config::{
-        MsiXTableEntry, capability_ids, msix_control_bits,
+        MsiXTableEntry, capability_ids, msi_control_bits, msi_offsets, msix_control_bits,
         msix_offsets,
     },
     device::PciDevice,
     mcfg::{read_config_u16, read_config_u32, write_config_u16},
 };
 
-/// MSI-X virtual address space start
+/// MSI-X virtual address space start, before the KASLR offset is added
 #[allow(dead_code)]
 const MSIX_VIRTUAL_START: u64 = 0xFFFF_F500_0000_0000;
 
@@ -245,17 +245,24 @@ impl MsiXInfo {
     }
 
     /// Allocate vectors
-    pub fn allocate_vectors(mut self, num_vectors: u16, base_vector: u8) -> Result<Self, PciError> {
-        if num_vectors > self.table_size {
+    pub fn allocate_vectors(self, num_vectors: u16, base_vector: u8) -> Result<Self, PciError> {
+        let vectors: Vec<u8> = (0..num_vectors).map(|i| base_vector + i as u8).collect();
+        self.assign_vectors(&vectors)
+    }
+
+    /// Point table entry `i` at `vectors[i]`, the vectors need not be
+    /// contiguous
+    pub fn assign_vectors(mut self, vectors: &[u8]) -> Result<Self, PciError> {
+        if vectors.len() > self.table_size as usize {
             return Err(PciError::MsiXSetupFailed);
         }
 
         self.vectors.clear();
 
-        for i in 0..num_vectors {
+        for (i, &vector) in vectors.iter().enumerate() {
             let vector = MsiXVector {
-                index: i,
-                vector: base_vector + i as u8,
+                index: i as u16,
+                vector,
                 enabled: false,
             };
             self.vectors.push(vector);
@@ -544,3 +551,52 @@ pub fn setup_msix(
         .allocate_vectors(num_vectors, base_vector)?
         .enable()
 }
+
+/// Setup MSI-X for a device, with table entry `i` raising `vectors[i]`
+pub fn setup_msix_vectors(device: &PciDevice, vectors: &[u8]) -> Result<MsiXInfo, PciError> {
+    let cap = device
+        .find_capability(capability_ids::MSI_X)
+        .ok_or(PciError::MsiXSetupFailed)?;
+
+    MsiXInfo::from_device(device, cap as u16)?
+        .map_structures()?
+        .zero_pba()?
+        .assign_vectors(vectors)?
+        .enable()
+}
+
+/// Number of MSI-X table entries of a device, `None` without MSI-X
+pub fn msix_table_size(device: &PciDevice) -> Option<u16> {
+    let cap = device.find_capability(capability_ids::MSI_X)?;
+    let control = device.read_config_u16(cap as u16 + msix_offsets::MESSAGE_CONTROL);
+    Some((control & msix_control_bits::TABLE_SIZE_MASK) + 1)
+}
+
+/// Setup single-vector MSI for a device without MSI-X
+pub fn setup_msi(device: &PciDevice, vector: u8) -> Result<(), PciError> {
+    let cap = device
+        .find_capability(capability_ids::MSI)
+        .ok_or(PciError::MsiXSetupFailed)? as u16;
+
+    let mut control = device.read_config_u16(cap + msi_offsets::MESSAGE_CONTROL);
+    let address = calculate_msi_address(0);
+
+    device.write_config_u32(cap + msi_offsets::MESSAGE_ADDRESS_LOW, address as u32);
+    if control & msi_control_bits::ADDRESS_64_CAPABLE != 0 {
+        device.write_config_u32(cap + msi_offsets::MESSAGE_ADDRESS_HIGH, (address >> 32) as u32);
+        device.write_config_u16(cap + msi_offsets::MESSAGE_DATA_64, calculate_msi_data(vector) as u16);
+    } else {
+        device.write_config_u16(cap + msi_offsets::MESSAGE_DATA_32, calculate_msi_data(vector) as u16);
+    }
+
+    // one message only
+    control &= !msi_control_bits::MULTIPLE_MESSAGE_ENABLE_MASK;
+    control |= msi_control_bits::MSI_ENABLE;
+    device.write_config_u16(cap + msi_offsets::MESSAGE_CONTROL, control);
+
+    info!(
+        "MSI enabled for {:02x}:{:02x}.{}: vector={}",
+        device.bus, device.device, device.function, vector
+    );
+    Ok(())
+}
@@ -1,15 +1,29 @@
+//! NVMe driver
+//!
+//! The driver reaches the kernel through `kernel_api`: logging, DMA buffers,
+//! interrupt vectors and deferred work, wait queues and sleeping. What the API
+//! doesn't cover yet still comes from the kernel itself: the PCIe device, its
+//! BARs and MSI-X table from `pci`, and `sync::Mutex` for lock checking.
+//!
+//! `cache` sits on top of the driver rather than in it; it manages frames and
+//! registers with memory reclaim, so it stays on the kernel side.
+
+pub mod block;
+pub mod cache;
 pub mod controller;
 pub mod registers;
 pub mod commands;
+pub mod queue;
+pub mod power;
+pub mod sanitize;
 
 pub use controller::{
     NvmeError, NvmeNamespace,
     read_blocks, write_blocks, get_namespaces,
-    test_nvme_io,
-    handle_admin_interrupt, handle_io_interrupt,
-    NVME_VECTOR_BASE, NVME_VECTOR_NUM, NVME_ADMIN_VECTOR, NVME_IO_VECTOR,
+    submit_admin_command, submit_io_command,
 };
 
-pub fn init() {
-    controller::nvme_init();
-}
+#[cfg(feature = "tests")]
+pub use controller::test_nvme_io;
+
+pub use controller::{health, probe, probe_candidates, reset, suspend};
@@ -0,0 +1,90 @@
+//! NVMe namespaces as block devices
+//!
+//! Each namespace of the controller is registered as `nvme0n<nsid>` once the
+//! controller is probed. Requests go through the block cache.
+
+use alloc::{format, string::String, sync::Arc};
+
+use kernel_api::{
+    block::{self, BlockDevice, BlockError, check_request},
+    warn,
+};
+
+use super::{
+    cache,
+    controller::{NvmeError, NvmeNamespace, get_namespaces},
+};
+
+/// Most blocks in one NVMe command
+const MAX_BLOCKS: usize = u16::MAX as usize;
+
+struct NvmeBlockDevice {
+    name: String,
+    namespace: NvmeNamespace,
+}
+
+impl BlockDevice for NvmeBlockDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.namespace.block_size as usize
+    }
+
+    fn block_count(&self) -> u64 {
+        self.namespace.size_blocks
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buffer.len())?;
+        let chunk_size = MAX_BLOCKS * self.block_size();
+        for (index, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+            let blocks = chunk.len() / self.block_size();
+            let lba = lba + (index * MAX_BLOCKS) as u64;
+            cache::read_blocks(self.namespace.nsid, lba, blocks as u16, chunk)
+                .map_err(block_error)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buffer.len())?;
+        let chunk_size = MAX_BLOCKS * self.block_size();
+        for (index, chunk) in buffer.chunks(chunk_size).enumerate() {
+            let blocks = chunk.len() / self.block_size();
+            let lba = lba + (index * MAX_BLOCKS) as u64;
+            cache::write_blocks(self.namespace.nsid, lba, blocks as u16, chunk)
+                .map_err(block_error)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        cache::sync().map_err(block_error)
+    }
+}
+
+fn block_error(error: NvmeError) -> BlockError {
+    match error {
+        NvmeError::ControllerNotFound | NvmeError::NoIoQueue => BlockError::NotReady,
+        NvmeError::BufferTooSmall => BlockError::BufferSize,
+        error => {
+            warn!("nvme: block request failed: {:?}", error);
+            BlockError::Io
+        }
+    }
+}
+
+/// Register every namespace of the controller as a block device
+pub fn register_namespaces() {
+    for namespace in get_namespaces() {
+        let device = NvmeBlockDevice {
+            name: format!("nvme0n{}", namespace.nsid),
+            namespace,
+        };
+        if let Err(e) = block::register(Arc::new(device)) {
+            warn!("nvme: failed to register a block device: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,660 @@
+//! Block cache for NVMe namespaces
+//!
+//! Namespaces are cached in 4 KiB chunks held in frames of their own. Reads
+//! are served from the cache when possible, and a namespace read sequentially
+//! gets the chunks after the ones read fetched ahead of time by the read-ahead
+//! task, with a window that doubles on every sequential read. Writes only
+//! update the cache and mark chunks dirty; the flusher task writes them back
+//! in batches of contiguous chunks, once a second or as soon as too many
+//! chunks are dirty. `sync` writes everything back right away.
+//!
+//! Clean chunks are given back to the reclaim task under memory pressure, and
+//! the least recently used ones are dropped once the cache is full.
+//!
+//! Namespaces whose block size doesn't divide 4 KiB bypass the cache.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    vec,
+    vec::Vec,
+};
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+};
+
+use super::controller::{self, NvmeError, NvmeNamespace};
+use crate::{
+    debug,
+    memory::{
+        FRAME_ALLOCATOR,
+        reclaim::{self, Shrinker},
+    },
+    sync::Mutex,
+    tasks::scheduler::{kcreate_task, sleep_ticks, wait_for_event, wake_event_waiters},
+    time, warn,
+};
+
+/// Bytes per cached chunk
+const CHUNK_SIZE: u64 = 4096;
+
+/// Most chunks kept, 16 MiB
+const MAX_CHUNKS: usize = 4096;
+
+/// Read-ahead window bounds in chunks
+const MIN_READAHEAD: u64 = 4;
+const MAX_READAHEAD: u64 = 32;
+
+/// Most chunks written back with one command
+const MAX_WRITE_BATCH: u64 = 32;
+
+/// Dirty chunks that make the flusher write back before its interval is up
+const DIRTY_LIMIT: usize = 256;
+
+/// How often the flusher writes back dirty chunks
+const FLUSH_INTERVAL_MS: u64 = 1000;
+
+/// Cache activity since boot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Chunks found in the cache
+    pub hits: u64,
+    /// Chunks read from the device on demand
+    pub misses: u64,
+    /// Chunks read ahead of time
+    pub readahead: u64,
+    /// Chunks written back
+    pub written: u64,
+    /// Chunks in the cache now
+    pub cached: usize,
+    /// Dirty chunks in the cache now
+    pub dirty: usize,
+}
+
+struct Chunk {
+    frame: PhysFrame,
+    dirty: bool,
+    /// Value of the cache clock when last used
+    last_used: u64,
+}
+
+/// Sequential read detection for one namespace
+struct Stream {
+    nsid: u32,
+    /// Chunk a sequential read would start at
+    next: u64,
+    /// Chunks to keep read ahead, 0 if the reads aren't sequential
+    window: u64,
+    /// First chunk not requested from the read-ahead task yet
+    ahead: u64,
+}
+
+struct Cache {
+    /// Chunks by namespace and chunk index
+    chunks: BTreeMap<(u32, u64), Chunk>,
+    streams: Vec<Stream>,
+    /// Read-ahead requests as namespace, first chunk and chunk count
+    readahead: VecDeque<(u32, u64, u64)>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(
+    "NVME_CACHE",
+    Cache {
+        chunks: BTreeMap::new(),
+        streams: Vec::new(),
+        readahead: VecDeque::new(),
+        clock: 0,
+        stats: CacheStats {
+            hits: 0,
+            misses: 0,
+            readahead: 0,
+            written: 0,
+            cached: 0,
+            dirty: 0,
+        },
+    },
+);
+
+/// Key the read-ahead task waits on
+static READAHEAD_WORK: u8 = 0;
+
+fn readahead_key() -> usize {
+    &raw const READAHEAD_WORK as usize
+}
+
+static SHRINKER: Shrinker = Shrinker {
+    name: "nvme cache",
+    count: clean_chunks,
+    scan: shrink,
+};
+
+fn hhdm_offset() -> u64 {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map_or(0, |allocator| allocator.hddm_offset)
+}
+
+fn chunk_data(frame: PhysFrame, hhdm_offset: u64) -> *mut u8 {
+    VirtAddr::new(frame.start_address().as_u64() + hhdm_offset).as_mut_ptr()
+}
+
+/// Namespace if it can be cached
+fn cacheable(nsid: u32) -> Result<Option<NvmeNamespace>, NvmeError> {
+    let namespace = controller::get_namespaces()
+        .into_iter()
+        .find(|ns| ns.nsid == nsid)
+        .ok_or(NvmeError::InvalidNamespace)?;
+    let block_size = namespace.block_size as u64;
+    Ok(
+        (block_size != 0 && block_size <= CHUNK_SIZE && CHUNK_SIZE % block_size == 0)
+            .then_some(namespace),
+    )
+}
+
+/// Chunks of a namespace, the last one may be partial
+fn chunk_count(namespace: &NvmeNamespace) -> u64 {
+    (namespace.size_blocks * namespace.block_size as u64).div_ceil(CHUNK_SIZE)
+}
+
+/// Read `count` chunks starting at `first` from the device into new frames
+fn fetch(namespace: &NvmeNamespace, first: u64, count: u64) -> Result<Vec<PhysFrame>, NvmeError> {
+    let block_size = namespace.block_size as u64;
+    let lba = first * CHUNK_SIZE / block_size;
+    let blocks = (count * CHUNK_SIZE / block_size).min(namespace.size_blocks - lba);
+    let mut buffer = vec![0; (count * CHUNK_SIZE) as usize];
+    controller::read_blocks(namespace.nsid, lba, blocks as u16, &mut buffer)?;
+
+    let hhdm_offset = hhdm_offset();
+    let mut frames = Vec::with_capacity(count as usize);
+    for data in buffer.chunks(CHUNK_SIZE as usize) {
+        let Some(frame) =
+            interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame())
+        else {
+            free_frames(&frames);
+            return Err(NvmeError::AllocationFailed);
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                chunk_data(frame, hhdm_offset),
+                data.len(),
+            )
+        };
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+fn free_frames(frames: &[PhysFrame]) {
+    interrupts::without_interrupts(|| {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        if let Some(allocator) = allocator.as_mut() {
+            for &frame in frames {
+                unsafe { allocator.deallocate_frame(frame) };
+            }
+        }
+    });
+}
+
+/// Part of a request falling in `chunk`, as the offset into the chunk, the
+/// offset into the request buffer and the length
+fn overlap(chunk: u64, start: u64, length: u64) -> (usize, usize, usize) {
+    let chunk_start = chunk * CHUNK_SIZE;
+    let from = start.max(chunk_start);
+    let to = (start + length).min(chunk_start + CHUNK_SIZE);
+    (
+        (from - chunk_start) as usize,
+        (from - start) as usize,
+        (to - from) as usize,
+    )
+}
+
+impl Cache {
+    /// Add fetched chunks, keeping any copy already cached since it may be
+    /// newer. Returns the frames that weren't used.
+    fn insert(&mut self, nsid: u32, first: u64, frames: Vec<PhysFrame>) -> Vec<PhysFrame> {
+        let mut unused = Vec::new();
+        for (chunk, frame) in (first..).zip(frames) {
+            if self.chunks.contains_key(&(nsid, chunk)) {
+                unused.push(frame);
+                continue;
+            }
+            self.clock += 1;
+            self.chunks.insert(
+                (nsid, chunk),
+                Chunk {
+                    frame,
+                    dirty: false,
+                    last_used: self.clock,
+                },
+            );
+        }
+        unused
+    }
+
+    fn touch(&mut self, nsid: u32, chunk: u64) -> Option<&mut Chunk> {
+        self.clock += 1;
+        let clock = self.clock;
+        let cached = self.chunks.get_mut(&(nsid, chunk))?;
+        cached.last_used = clock;
+        Some(cached)
+    }
+
+    /// Drop least recently used clean chunks while the cache is over capacity
+    fn evict_over_capacity(&mut self) -> Vec<PhysFrame> {
+        let excess = self.chunks.len().saturating_sub(MAX_CHUNKS);
+        self.evict_clean(excess)
+    }
+
+    /// Drop up to `count` clean chunks, least recently used first
+    fn evict_clean(&mut self, count: usize) -> Vec<PhysFrame> {
+        if count == 0 {
+            return Vec::new();
+        }
+        let mut clean: Vec<(u64, (u32, u64))> = self
+            .chunks
+            .iter()
+            .filter(|(_, chunk)| !chunk.dirty)
+            .map(|(&key, chunk)| (chunk.last_used, key))
+            .collect();
+        clean.sort_unstable();
+        clean
+            .into_iter()
+            .take(count)
+            .filter_map(|(_, key)| self.chunks.remove(&key))
+            .map(|chunk| chunk.frame)
+            .collect()
+    }
+
+    /// Record a read of chunks `first..=last`, queueing read-ahead if the
+    /// namespace is read sequentially. Returns true if read-ahead was queued.
+    fn note_read(&mut self, namespace: &NvmeNamespace, first: u64, last: u64) -> bool {
+        let nsid = namespace.nsid;
+        if !self.streams.iter().any(|stream| stream.nsid == nsid) {
+            self.streams.push(Stream {
+                nsid,
+                next: u64::MAX,
+                window: 0,
+                ahead: 0,
+            });
+        }
+        let stream = self
+            .streams
+            .iter_mut()
+            .find(|stream| stream.nsid == nsid)
+            .unwrap();
+
+        // reads landing in the window read ahead still count as sequential
+        let sequential = first == stream.next
+            || (stream.window > 0 && (stream.next..stream.ahead).contains(&first));
+        stream.next = last + 1;
+        if !sequential {
+            stream.window = 0;
+            stream.ahead = last + 1;
+            return false;
+        }
+
+        stream.window = (stream.window * 2).clamp(MIN_READAHEAD, MAX_READAHEAD);
+        let start = stream.ahead.max(last + 1);
+        let end = (last + 1 + stream.window).min(chunk_count(namespace));
+        if start >= end {
+            return false;
+        }
+        stream.ahead = end;
+        self.readahead.push_back((nsid, start, end - start));
+        true
+    }
+
+    fn dirty_count(&self) -> usize {
+        self.chunks.values().filter(|chunk| chunk.dirty).count()
+    }
+
+    /// First run of up to `MAX_WRITE_BATCH` contiguous dirty chunks, marked
+    /// clean, with their data copied out
+    fn take_dirty_run(&mut self, hhdm_offset: u64) -> Option<(u32, u64, Vec<u8>)> {
+        let (&(nsid, first), _) = self.chunks.iter().find(|(_, chunk)| chunk.dirty)?;
+        let mut data = Vec::new();
+        for chunk in first..first + MAX_WRITE_BATCH {
+            let Some(cached) = self
+                .chunks
+                .get_mut(&(nsid, chunk))
+                .filter(|cached| cached.dirty)
+            else {
+                break;
+            };
+            let source = chunk_data(cached.frame, hhdm_offset);
+            data.extend_from_slice(unsafe {
+                core::slice::from_raw_parts(source, CHUNK_SIZE as usize)
+            });
+            cached.dirty = false;
+        }
+        Some((nsid, first, data))
+    }
+}
+
+/// Check a request against the namespace, returning its byte offset and
+/// length
+fn check_request(
+    namespace: &NvmeNamespace,
+    lba: u64,
+    blocks: u16,
+    buffer_len: usize,
+) -> Result<(u64, u64), NvmeError> {
+    let block_size = namespace.block_size as u64;
+    let length = blocks as u64 * block_size;
+    if (buffer_len as u64) < length {
+        return Err(NvmeError::BufferTooSmall);
+    }
+    if lba + blocks as u64 > namespace.size_blocks {
+        return Err(NvmeError::InvalidNamespace);
+    }
+    Ok((lba * block_size, length))
+}
+
+/// Read blocks through the cache
+///
+/// Same arguments as `controller::read_blocks`.
+pub fn read_blocks(nsid: u32, lba: u64, blocks: u16, buffer: &mut [u8]) -> Result<(), NvmeError> {
+    let Some(namespace) = cacheable(nsid)? else {
+        return controller::read_blocks(nsid, lba, blocks, buffer);
+    };
+    let (start, length) = check_request(&namespace, lba, blocks, buffer.len())?;
+    if length == 0 {
+        return Ok(());
+    }
+    let first = start / CHUNK_SIZE;
+    let last = (start + length - 1) / CHUNK_SIZE;
+
+    if interrupts::without_interrupts(|| CACHE.lock().note_read(&namespace, first, last)) {
+        wake_event_waiters(readahead_key());
+    }
+
+    let hhdm_offset = hhdm_offset();
+    let copy_out = |chunk: u64, frame: PhysFrame, buffer: &mut [u8]| {
+        let (offset, position, len) = overlap(chunk, start, length);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                chunk_data(frame, hhdm_offset).add(offset),
+                buffer[position..].as_mut_ptr(),
+                len,
+            )
+        };
+    };
+
+    let mut chunk = first;
+    while chunk <= last {
+        // copy what is cached, up to the first missing chunk
+        chunk = interrupts::without_interrupts(|| {
+            let mut cache = CACHE.lock();
+            while chunk <= last {
+                let Some(cached) = cache.touch(nsid, chunk) else {
+                    break;
+                };
+                let frame = cached.frame;
+                copy_out(chunk, frame, buffer);
+                cache.stats.hits += 1;
+                chunk += 1;
+            }
+            chunk
+        });
+        if chunk > last {
+            break;
+        }
+
+        // then read the run of missing chunks with one command
+        let run_end = interrupts::without_interrupts(|| {
+            let cache = CACHE.lock();
+            (chunk..=last.min(chunk + MAX_READAHEAD - 1))
+                .find(|&next| cache.chunks.contains_key(&(nsid, next)))
+                .unwrap_or((last + 1).min(chunk + MAX_READAHEAD))
+        });
+        let frames = fetch(&namespace, chunk, run_end - chunk)?;
+        for (next, &frame) in (chunk..).zip(frames.iter()) {
+            copy_out(next, frame, buffer);
+        }
+        let unused = interrupts::without_interrupts(|| {
+            let mut cache = CACHE.lock();
+            cache.stats.misses += frames.len() as u64;
+            let mut unused = cache.insert(nsid, chunk, frames);
+            unused.extend(cache.evict_over_capacity());
+            unused
+        });
+        free_frames(&unused);
+        chunk = run_end;
+    }
+    Ok(())
+}
+
+/// Write blocks into the cache, the flusher task writes them back later
+///
+/// Same arguments as `controller::write_blocks`.
+pub fn write_blocks(nsid: u32, lba: u64, blocks: u16, buffer: &[u8]) -> Result<(), NvmeError> {
+    let Some(namespace) = cacheable(nsid)? else {
+        return controller::write_blocks(nsid, lba, blocks, buffer);
+    };
+    let (start, length) = check_request(&namespace, lba, blocks, buffer.len())?;
+    if length == 0 {
+        return Ok(());
+    }
+    let first = start / CHUNK_SIZE;
+    let last = (start + length - 1) / CHUNK_SIZE;
+    let hhdm_offset = hhdm_offset();
+
+    for chunk in first..=last {
+        let (offset, position, len) = overlap(chunk, start, length);
+        let cached =
+            interrupts::without_interrupts(|| CACHE.lock().chunks.contains_key(&(nsid, chunk)));
+        let frames = if cached {
+            Vec::new()
+        } else if len < CHUNK_SIZE as usize {
+            // the rest of the chunk has to come from the device
+            fetch(&namespace, chunk, 1)?
+        } else {
+            let frame = interrupts::without_interrupts(|| {
+                FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+            })
+            .ok_or(NvmeError::AllocationFailed)?;
+            vec![frame]
+        };
+
+        let unused = interrupts::without_interrupts(|| {
+            let mut cache = CACHE.lock();
+            let mut unused = cache.insert(nsid, chunk, frames);
+            // present since insert only ever adds chunks
+            let cached = cache.touch(nsid, chunk).unwrap();
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    buffer[position..].as_ptr(),
+                    chunk_data(cached.frame, hhdm_offset).add(offset),
+                    len,
+                )
+            };
+            cached.dirty = true;
+            unused.extend(cache.evict_over_capacity());
+            unused
+        });
+        free_frames(&unused);
+    }
+    Ok(())
+}
+
+/// Write back dirty chunks, at most `limit` runs of them
+///
+/// Returns the number of chunks written.
+fn writeback(limit: usize) -> Result<usize, NvmeError> {
+    let hhdm_offset = hhdm_offset();
+    let mut written = 0;
+    for _ in 0..limit {
+        let Some((nsid, first, data)) =
+            interrupts::without_interrupts(|| CACHE.lock().take_dirty_run(hhdm_offset))
+        else {
+            break;
+        };
+        let chunks = data.len() as u64 / CHUNK_SIZE;
+
+        let result = cacheable(nsid).and_then(|namespace| {
+            let namespace = namespace.ok_or(NvmeError::InvalidNamespace)?;
+            let block_size = namespace.block_size as u64;
+            let lba = first * CHUNK_SIZE / block_size;
+            let blocks = (data.len() as u64 / block_size).min(namespace.size_blocks - lba);
+            controller::write_blocks(nsid, lba, blocks as u16, &data)
+        });
+
+        if let Err(e) = result {
+            // keep the data around to try again later
+            interrupts::without_interrupts(|| {
+                let mut cache = CACHE.lock();
+                for chunk in first..first + chunks {
+                    if let Some(cached) = cache.chunks.get_mut(&(nsid, chunk)) {
+                        cached.dirty = true;
+                    }
+                }
+            });
+            return Err(e);
+        }
+        interrupts::without_interrupts(|| CACHE.lock().stats.written += chunks);
+        written += chunks as usize;
+    }
+    Ok(written)
+}
+
+/// Write every dirty chunk back to the device
+pub fn sync() -> Result<(), NvmeError> {
+    writeback(usize::MAX).map(|_| ())
+}
+
+/// Drop every chunk, dirty ones included, after the device contents were
+/// replaced behind the cache's back
+pub fn discard() {
+    let frames: Vec<PhysFrame> = interrupts::without_interrupts(|| {
+        let mut cache = CACHE.lock();
+        cache.streams.clear();
+        cache.readahead.clear();
+        core::mem::take(&mut cache.chunks)
+            .into_values()
+            .map(|chunk| chunk.frame)
+            .collect()
+    });
+    free_frames(&frames);
+}
+
+/// Cache counters and current size
+pub fn stats() -> CacheStats {
+    interrupts::without_interrupts(|| {
+        let cache = CACHE.lock();
+        CacheStats {
+            cached: cache.chunks.len(),
+            dirty: cache.dirty_count(),
+            ..cache.stats
+        }
+    })
+}
+
+fn clean_chunks() -> usize {
+    interrupts::without_interrupts(|| {
+        let cache = CACHE.lock();
+        cache.chunks.len() - cache.dirty_count()
+    })
+}
+
+/// Give up to `frames` frames back, writing dirty chunks back if there aren't
+/// enough clean ones
+fn shrink(frames: usize) -> usize {
+    let mut evicted = interrupts::without_interrupts(|| CACHE.lock().evict_clean(frames));
+    if evicted.len() < frames {
+        #[allow(unused_variables)]
+        if let Err(e) = writeback(usize::MAX) {
+            warn!("nvme cache: writeback failed: {:?}", e);
+        }
+        let wanted = frames - evicted.len();
+        evicted.extend(interrupts::without_interrupts(|| {
+            CACHE.lock().evict_clean(wanted)
+        }));
+    }
+    free_frames(&evicted);
+    evicted.len()
+}
+
+/// Start the read-ahead and flusher tasks and hook the cache into reclaim
+pub fn init() {
+    reclaim::register_shrinker(&SHRINKER);
+    interrupts::without_interrupts(|| {
+        kcreate_task(readahead_task, "nvme readahead");
+        kcreate_task(flusher_task, "nvme flusher");
+    });
+}
+
+fn readahead_task() -> ! {
+    loop {
+        interrupts::disable();
+        let request = CACHE.lock().readahead.pop_front();
+        let Some((nsid, first, count)) = request else {
+            wait_for_event(readahead_key());
+            continue;
+        };
+        interrupts::enable();
+
+        let Ok(Some(namespace)) = cacheable(nsid) else {
+            continue;
+        };
+        // skip what got cached since the request was queued
+        let mut chunk = first;
+        while chunk < first + count {
+            let (start, end) = interrupts::without_interrupts(|| {
+                let cache = CACHE.lock();
+                let start = (chunk..first + count)
+                    .find(|&next| !cache.chunks.contains_key(&(nsid, next)))
+                    .unwrap_or(first + count);
+                let end = (start..first + count)
+                    .find(|&next| cache.chunks.contains_key(&(nsid, next)))
+                    .unwrap_or(first + count);
+                (start, end)
+            });
+            if start == end {
+                break;
+            }
+
+            match fetch(&namespace, start, end - start) {
+                Ok(frames) => {
+                    let unused = interrupts::without_interrupts(|| {
+                        let mut cache = CACHE.lock();
+                        cache.stats.readahead += frames.len() as u64;
+                        let mut unused = cache.insert(nsid, start, frames);
+                        unused.extend(cache.evict_over_capacity());
+                        unused
+                    });
+                    free_frames(&unused);
+                }
+                #[allow(unused_variables)]
+                Err(e) => {
+                    debug!("nvme cache: read-ahead of chunk {} failed: {:?}", start, e);
+                    break;
+                }
+            }
+            chunk = end;
+        }
+    }
+}
+
+fn flusher_task() -> ! {
+    let interval = time::ms_to_ticks(FLUSH_INTERVAL_MS);
+    loop {
+        // flush early once too many chunks are dirty
+        let deadline = time::ticks() + interval;
+        while time::ticks() < deadline
+            && interrupts::without_interrupts(|| CACHE.lock().dirty_count()) <= DIRTY_LIMIT
+        {
+            sleep_ticks(1);
+        }
+
+        #[allow(unused_variables)]
+        if let Err(e) = writeback(usize::MAX) {
+            warn!("nvme cache: writeback failed: {:?}", e);
+            sleep_ticks(interval);
+        }
+    }
+}
@@ -3,6 +3,8 @@
 //! This module provides command and completion structures for NVMe operations,
 //! following the same pattern as the xHCI TRB helpers.
 
+use alloc::vec::Vec;
+
 use super::registers::opcodes;
 
 /// NVMe Submission Queue Entry (64 bytes)
@@ -141,10 +143,110 @@ impl NvmeCommand {
         cmd
     }
     
+    /// Create a SET FEATURES command
+    pub fn set_features(fid: u8, value: u32, buffer_addr: u64) -> Self {
+        let mut cmd = Self::new();
+        cmd.set_opcode(opcodes::ADMIN_SET_FEATURES);
+        cmd.prp1 = buffer_addr;
+        cmd.cdw10 = fid as u32;                    // FID, SV = 0
+        cmd.cdw11 = value;
+        cmd
+    }
+
+    /// Create a GET FEATURES command returning the current value
+    pub fn get_features(fid: u8, buffer_addr: u64) -> Self {
+        let mut cmd = Self::new();
+        cmd.set_opcode(opcodes::ADMIN_GET_FEATURES);
+        cmd.prp1 = buffer_addr;
+        cmd.cdw10 = fid as u32;                    // FID, SEL = 0 (current)
+        cmd
+    }
+
+    /// Create a GET LOG PAGE command reading `dwords` dwords of log page `lid`
+    pub fn get_log_page(lid: u8, dwords: u32, buffer_addr: u64) -> Self {
+        let mut cmd = Self::new();
+        cmd.set_opcode(opcodes::ADMIN_GET_LOG_PAGE);
+        cmd.nsid = 0xFFFF_FFFF;          // Controller wide
+        cmd.prp1 = buffer_addr;
+        cmd.cdw10 = ((dwords - 1) & 0xFFFF) << 16 | lid as u32; // NUMDL | LID
+        cmd.cdw11 = (dwords - 1) >> 16;  // NUMDU
+        cmd
+    }
+
+    /// Create a SANITIZE command performing `action` (SANACT)
+    pub fn sanitize(action: u32, allow_unrestricted_exit: bool) -> Self {
+        let mut cmd = Self::new();
+        cmd.set_opcode(opcodes::ADMIN_SANITIZE);
+        cmd.cdw10 = action & 0x7 | (allow_unrestricted_exit as u32) << 3; // SANACT | AUSE
+        cmd
+    }
+
     /// Set up PRP2 for transfers larger than one page
     pub fn set_prp2(&mut self, addr: u64) {
         self.prp2 = addr;
     }
+
+    /// Describe the data buffer with an SGL descriptor instead of PRPs
+    ///
+    /// Sets PSDT in CDW0 and stores the descriptor in the data pointer (PRP1/PRP2).
+    pub fn set_sgl(&mut self, descriptor: SglDescriptor) {
+        self.cdw0 = (self.cdw0 & !psdt::MASK) | psdt::SGL_MPTR_CONTIGUOUS;
+        self.prp1 = descriptor.address;
+        self.prp2 = descriptor.length as u64 | (descriptor.sgl_id as u64) << 56;
+    }
+}
+
+/// Sanitize operations a controller supports
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SanitizeCapabilities {
+    pub crypto_erase: bool,
+    pub block_erase: bool,
+    pub overwrite: bool,
+}
+
+/// Sanitize actions (SANACT field of CDW10)
+pub mod sanitize_action {
+    pub const EXIT_FAILURE_MODE: u32 = 0x1;
+    pub const BLOCK_ERASE: u32 = 0x2;
+    pub const OVERWRITE: u32 = 0x3;
+    pub const CRYPTO_ERASE: u32 = 0x4;
+}
+
+/// PRP or SGL for Data Transfer (PSDT) field of CDW0 (bits 14-15)
+pub mod psdt {
+    pub const MASK: u32 = 0x3 << 14;
+    pub const PRP: u32 = 0 << 14;                       // PRPs are used
+    pub const SGL_MPTR_CONTIGUOUS: u32 = 1 << 14;       // SGLs, MPTR is a contiguous buffer
+}
+
+/// SGL descriptor type, stored in the upper nibble of the SGL identifier
+///
+/// I/O buffers are physically contiguous, so only Data Block descriptors are
+/// built.
+pub mod sgl_type {
+    pub const DATA_BLOCK: u8 = 0x0;
+}
+
+/// Scatter Gather List descriptor (16 bytes)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SglDescriptor {
+    pub address: u64,       // Address of the data block or segment
+    pub length: u32,        // Length in bytes
+    pub _reserved: [u8; 3],
+    pub sgl_id: u8,         // Descriptor type (bits 4-7) and sub type (bits 0-3)
+}
+
+impl SglDescriptor {
+    /// Create a Data Block descriptor covering a physically contiguous buffer
+    pub const fn data_block(address: u64, length: u32) -> Self {
+        Self {
+            address,
+            length,
+            _reserved: [0; 3],
+            sgl_id: sgl_type::DATA_BLOCK << 4,
+        }
+    }
 }
 
 impl NvmeCompletion {
@@ -274,6 +376,78 @@ pub struct IdentifyController {
     pub vs: [u8; 1024],     // Vendor Specific
 }
 
+impl IdentifyController {
+    /// Check whether the controller supports SGLs for NVM command set I/O (SGLS bits 0-1)
+    pub fn supports_sgl(&self) -> bool {
+        self.sgls & 0x3 != 0
+    }
+
+    /// Check whether the controller supports Autonomous Power State Transitions (APSTA bit 0)
+    pub fn supports_apst(&self) -> bool {
+        self.apsta & 0x1 != 0
+    }
+
+    /// Sanitize operations supported (SANICAP bits 0-2)
+    pub fn sanitize_capabilities(&self) -> SanitizeCapabilities {
+        SanitizeCapabilities {
+            crypto_erase: self.sanicap & 0x1 != 0,
+            block_erase: self.sanicap & 0x2 != 0,
+            overwrite: self.sanicap & 0x4 != 0,
+        }
+    }
+
+    /// Parse the power state descriptors (NPSS is a 0's based count)
+    pub fn power_states(&self) -> Vec<PowerStateDescriptor> {
+        self.psd
+            .chunks_exact(32)
+            .take(self.npss as usize + 1)
+            .map(PowerStateDescriptor::parse)
+            .collect()
+    }
+}
+
+/// Power State Descriptor (32 bytes each, up to 32 per controller)
+#[derive(Debug, Clone, Copy)]
+pub struct PowerStateDescriptor {
+    pub max_power: u16,     // Maximum Power (MP)
+    pub max_power_scale: bool, // Max Power Scale (MXPS): 0.0001 W units when set, else 0.01 W
+    pub non_operational: bool, // Non-Operational State (NOPS)
+    pub entry_latency: u32, // Entry Latency (ENLAT) in microseconds
+    pub exit_latency: u32,  // Exit Latency (EXLAT) in microseconds
+    pub rrt: u8,            // Relative Read Throughput
+    pub rrl: u8,            // Relative Read Latency
+    pub rwt: u8,            // Relative Write Throughput
+    pub rwl: u8,            // Relative Write Latency
+}
+
+impl PowerStateDescriptor {
+    /// Parse a descriptor from its 32-byte on-wire layout
+    pub fn parse(bytes: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Self {
+            max_power: u16_at(0),
+            max_power_scale: bytes[3] & 0x1 != 0,
+            non_operational: bytes[3] & 0x2 != 0,
+            entry_latency: u32_at(4),
+            exit_latency: u32_at(8),
+            rrt: bytes[12] & 0x1F,
+            rrl: bytes[13] & 0x1F,
+            rwt: bytes[14] & 0x1F,
+            rwl: bytes[15] & 0x1F,
+        }
+    }
+
+    /// Maximum power in microwatts
+    pub fn max_power_uw(&self) -> u32 {
+        if self.max_power_scale {
+            self.max_power as u32 * 100
+        } else {
+            self.max_power as u32 * 10_000
+        }
+    }
+}
+
 /// Namespace Identify Data Structure (4096 bytes)
 /// Simplified version with essential fields
 #[repr(C)]
@@ -3,42 +3,95 @@
 //! This module handles NVMe controller initialization and management,
 //! following the same patterns as the xHCI implementation.
 
-use alloc::vec::Vec;
-use spin::Mutex;
-use x86_64::{PhysAddr, VirtAddr};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use kernel_api::{
+    attr::{self, Attribute},
+    cpu, debug,
+    dma::{DmaBuffer, DmaError},
+    info,
+    irq::{self, Work},
+    warn,
+};
 
 use super::{
-    commands::{IdentifyController, IdentifyNamespace, NvmeCommand, NvmeCompletion},
-    registers::NvmeRegisters,
+    commands::{
+        IdentifyController, IdentifyNamespace, NvmeCommand, NvmeCompletion, PowerStateDescriptor,
+        SanitizeCapabilities, SglDescriptor,
+    },
+    power,
+    queue::{
+        CommandQueue, IO_QUEUE_COUNT, MAX_IO_QUEUES, NVME_ADMIN_QUEUE, NVME_IO_QUEUES, NvmeQueue,
+        abort, execute, io_queue, reap_completions,
+    },
+    registers::{NvmeRegisters, csts_bits, feature_ids},
 };
 use crate::{
-    debug, info,
-    memory::FRAME_ALLOCATOR,
     pci::{
-        config::device_classes, device::{BarInfo, PciDevice}, dma::{get_zeroed_dma, DmaError, DMA_MANAGER}, msi::{setup_msix, MsiXInfo}, vmm::map_bar, PCI_MANAGER
+        config::device_classes, device::{BarInfo, PciDevice}, msi::{msix_table_size, setup_msix_vectors, MsiXInfo}, vmm::map_bar, PCI_MANAGER
     },
-    tasks::scheduler::kyield_task,
-    warn,
+    sync::Mutex,
 };
 
 /// Global NVMe controller instance
-pub static NVME_CONTROLLER: Mutex<Option<NvmeController>> = Mutex::new(None);
+///
+/// Only holds controller state; commands are issued through the admin and I/O
+/// queues, which are locked separately.
+pub static NVME_CONTROLLER: Mutex<Option<NvmeController>> =
+    Mutex::new("NVME_CONTROLLER", None);
 
-pub const NVME_VECTOR_BASE: u8 = 0x50;
-pub const NVME_ADMIN_VECTOR: u8 = NVME_VECTOR_BASE;
-pub const NVME_IO_VECTOR: u8 = NVME_VECTOR_BASE + 1;
-pub const NVME_VECTOR_NUM: u16 = 2;
+/// Most MSI-X vectors used, one for the admin queue and one per I/O queue
+const MAX_VECTORS: usize = MAX_IO_QUEUES + 1;
 
-pub fn handle_admin_interrupt() {
-    crate::tasks::scheduler::wake_tasks(NVME_ADMIN_VECTOR);
+/// MSI-X vectors in use, table entry `i` is bit `i` of `PENDING_VECTORS`
+static VECTOR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Vectors that fired since the completions were last drained
+static PENDING_VECTORS: AtomicU32 = AtomicU32::new(0);
+
+/// Drains the queues of the vectors that fired
+static COMPLETIONS: Work = Work::new("nvme completions", reap_pending);
+
+/// Interrupt handler of MSI-X table entry `index`
+fn handle_interrupt(index: usize) {
+    PENDING_VECTORS.fetch_or(1 << index, Ordering::AcqRel);
+    COMPLETIONS.schedule();
 }
 
-pub fn handle_io_interrupt() {
-    crate::tasks::scheduler::wake_tasks(NVME_IO_VECTOR);
+/// MSI-X table entry interrupting for I/O queue `queue_id` (1-based)
+///
+/// The admin queue has entry 0. With a vector per queue, I/O queue `n` has
+/// entry `n`; with fewer, the I/O queues take turns over all of them, sharing
+/// with the admin queue and each other.
+pub fn queue_vector(queue_id: usize, vectors: usize, queues: usize) -> usize {
+    if vectors > queues {
+        queue_id
+    } else {
+        (queue_id - 1) % vectors
+    }
+}
+
+fn reap_pending() {
+    let pending = PENDING_VECTORS.swap(0, Ordering::AcqRel);
+    let vectors = VECTOR_COUNT.load(Ordering::Acquire).max(1);
+    let queues = IO_QUEUE_COUNT.load(Ordering::Acquire);
+
+    if pending & 1 != 0 {
+        reap_completions(&NVME_ADMIN_QUEUE);
+    }
+    for queue_id in 1..=queues {
+        if pending & (1 << queue_vector(queue_id, vectors, queues)) != 0 {
+            reap_completions(&NVME_IO_QUEUES[queue_id - 1]);
+        }
+    }
 }
 
 /// NVMe controller errors
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NvmeError {
     ControllerNotFound,
     ControllerResetTimeout,
@@ -52,6 +105,9 @@ pub enum NvmeError {
     PciError,
     NoIoQueue,
     BufferTooSmall,
+    ShutdownTimeout,
+    /// The queue was torn down before the command completed
+    Aborted,
 }
 
 impl From<DmaError> for NvmeError {
@@ -60,33 +116,6 @@ impl From<DmaError> for NvmeError {
     }
 }
 
-/// Queue management structure
-#[derive(Debug)]
-pub struct NvmeQueue {
-    /// Submission queue entries
-    pub sq_entries: VirtAddr,
-    /// Submission queue physical address
-    pub sq_phys: PhysAddr,
-    /// Completion queue entries
-    pub cq_entries: VirtAddr,
-    /// Completion queue physical address
-    pub cq_phys: PhysAddr,
-    /// Queue size (number of entries)
-    pub size: u16,
-    /// Submission queue head
-    pub sq_head: u16,
-    /// Submission queue tail
-    pub sq_tail: u16,
-    /// Completion queue head
-    pub cq_head: u16,
-    /// Completion queue phase bit
-    pub cq_phase: bool,
-    /// Queue ID
-    pub queue_id: u16,
-    /// MSI-X interrupt vector for this queue (None for admin queue using polling)
-    pub interrupt_vector: Option<u8>,
-}
-
 /// NVMe namespace information
 #[derive(Debug, Clone)]
 pub struct NvmeNamespace {
@@ -102,12 +131,6 @@ pub struct NvmeController {
     pub pci_device: PciDevice,
     /// Memory-mapped registers
     pub registers: &'static mut NvmeRegisters,
-    /// Admin queue (queue ID 0)
-    pub admin_queue: NvmeQueue,
-    /// I/O queue (queue ID 1)
-    pub io_queue: Option<NvmeQueue>,
-    /// Next command ID to use
-    pub next_command_id: u16,
     /// Discovered namespaces
     pub namespaces: Vec<NvmeNamespace>,
     /// Controller capabilities
@@ -115,89 +138,14 @@ pub struct NvmeController {
     pub doorbell_stride: u32,
     /// MSI-X interrupt information
     pub msix_info: Option<MsiXInfo>,
-}
-
-impl NvmeQueue {
-    /// Create a new queue pair
-    pub fn new(queue_id: u16, size: u16) -> Result<Self, NvmeError> {
-        let sq_size = size as usize * 64; // 64 bytes per SQ entry
-        let cq_size = size as usize * 16; // 16 bytes per CQ entry
-        let total_size = sq_size + cq_size;
-        let pages_needed = total_size.div_ceil(4096);
-
-        let buffer = get_zeroed_dma(pages_needed)?;
-        let sq_virt = buffer.virt_addr;
-        let sq_phys = buffer.phys_addr;
-        let cq_virt = VirtAddr::new(sq_virt.as_u64() + sq_size as u64);
-        let cq_phys = PhysAddr::new(sq_phys.as_u64() + sq_size as u64);
-
-        debug!(
-            "Created NVMe queue {}: SQ at {:#x}, CQ at {:#x}",
-            queue_id,
-            sq_virt.as_u64(),
-            cq_virt.as_u64()
-        );
-
-        Ok(Self {
-            sq_entries: sq_virt,
-            sq_phys,
-            cq_entries: cq_virt,
-            cq_phys,
-            size,
-            sq_head: 0,
-            sq_tail: 0,
-            cq_head: 0,
-            cq_phase: true,
-            queue_id,
-            interrupt_vector: None,
-        })
-    }
-
-    /// Submit a command to the submission queue
-    pub fn submit_command(&mut self, mut cmd: NvmeCommand) -> Result<u16, NvmeError> {
-        let next_tail = (self.sq_tail + 1) % self.size;
-        if next_tail == self.sq_head {
-            return Err(NvmeError::QueueFull);
-        }
-
-        let cid = self.sq_tail;
-        cmd.set_command_id(cid);
-
-        unsafe {
-            let entry_ptr = self
-                .sq_entries
-                .as_mut_ptr::<NvmeCommand>()
-                .add(self.sq_tail as usize);
-            core::ptr::write_volatile(entry_ptr, cmd);
-        }
-
-        self.sq_tail = next_tail;
-
-        Ok(cid)
-    }
-
-    /// Check for completion queue entries
-    pub fn check_completion(&mut self) -> Option<NvmeCompletion> {
-        let entry_ptr = unsafe {
-            self.cq_entries
-                .as_ptr::<NvmeCompletion>()
-                .add(self.cq_head as usize)
-        };
-
-        let completion = unsafe { core::ptr::read_volatile(entry_ptr) };
-
-        if completion.is_valid(self.cq_phase) {
-            self.cq_head = (self.cq_head + 1) % self.size;
-
-            if self.cq_head == 0 {
-                self.cq_phase = !self.cq_phase;
-            }
-
-            Some(completion)
-        } else {
-            None
-        }
-    }
+    /// Whether I/O data buffers are described with SGLs instead of PRPs
+    pub sgl_supported: bool,
+    /// Power state descriptors from Identify Controller
+    pub power_states: Vec<PowerStateDescriptor>,
+    /// Whether Autonomous Power State Transitions are supported
+    pub apst_supported: bool,
+    /// Sanitize operations supported
+    pub sanitize: SanitizeCapabilities,
 }
 
 impl NvmeController {
@@ -241,18 +189,17 @@ impl NvmeController {
         debug!("  Min Page Size: {} bytes", registers.min_page_size());
         debug!("  Max Page Size: {} bytes", registers.max_page_size());
 
-        let admin_queue = NvmeQueue::new(0, core::cmp::min(max_queue_entries, 64))?;
-
         let mut controller = Self {
             pci_device,
             registers,
-            admin_queue,
-            io_queue: None,
-            next_command_id: 1,
             namespaces: Vec::new(),
             max_queue_entries,
             doorbell_stride,
             msix_info: None,
+            sgl_supported: false,
+            power_states: Vec::new(),
+            apst_supported: false,
+            sanitize: SanitizeCapabilities::default(),
         };
 
         controller.initialize()?;
@@ -264,6 +211,8 @@ impl NvmeController {
     fn initialize(&mut self) -> Result<(), NvmeError> {
         info!("Initializing NVMe controller");
 
+        COMPLETIONS.register();
+
         if self.registers.is_ready() {
             self.reset_controller()?;
         }
@@ -276,10 +225,20 @@ impl NvmeController {
 
         self.identify_controller()?;
 
+        if self.apst_supported {
+            match power::enable_apst(&self.power_states) {
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Failed to enable NVMe APST: {:?}", e);
+                }
+            }
+        }
+
         self.discover_namespaces()?;
 
         if !self.namespaces.is_empty() {
-            self.create_io_queues()?;
+            let queues = self.request_io_queues()?;
+            self.create_io_queues(queues)?;
         }
 
         info!("NVMe controller initialization complete");
@@ -287,24 +246,73 @@ impl NvmeController {
     }
 
     /// Setup MSI-X interrupts for the controller
+    ///
+    /// Takes a vector for the admin queue and one for each I/O queue that
+    /// could be created, as far as the MSI-X table and the free vectors go.
+    /// Only the admin queue's is enabled here; vectors left over once the
+    /// controller granted its queues are given back by `create_io_queues`.
     fn setup_msix(&mut self) -> Result<(), NvmeError> {
-        let mut msix_info = setup_msix(&self.pci_device, NVME_VECTOR_NUM, NVME_VECTOR_BASE)
-            .map_err(|_| NvmeError::PciError)?;
+        let wanted = (1 + cpu::online_cpus()).min(MAX_VECTORS);
+        let table_size = msix_table_size(&self.pci_device).ok_or(NvmeError::PciError)? as usize;
+
+        let mut allocated = Vec::new();
+        for index in 0..wanted.min(table_size) {
+            match irq::allocate_vector("nvme", handle_interrupt, index) {
+                Some(vector) => allocated.push(vector),
+                None => break,
+            }
+        }
+        if allocated.is_empty() {
+            warn!("No free interrupt vectors for the NVMe controller");
+            return Err(NvmeError::PciError);
+        }
+        if allocated.len() < wanted {
+            info!(
+                "NVMe controller got {} of {} vectors (table size {}, {} free), queues will share them",
+                allocated.len(),
+                wanted,
+                table_size,
+                irq::available_vectors()
+            );
+        }
+
+        let msix_info = match setup_msix_vectors(&self.pci_device, &allocated) {
+            Ok(msix_info) => msix_info,
+            Err(_) => {
+                allocated.iter().for_each(|&vector| irq::release_vector(vector));
+                return Err(NvmeError::PciError);
+            }
+        };
+        VECTOR_COUNT.store(allocated.len(), Ordering::Release);
 
         info!(
-            "MSI-X enabled for NVMe controller with {} vectors (base={:#x})",
-            NVME_VECTOR_NUM, NVME_VECTOR_BASE
+            "MSI-X enabled for NVMe controller with {} vectors: {:x?}",
+            allocated.len(),
+            allocated
         );
 
-        msix_info
+        // kept before enabling, so the vectors are given back if that fails
+        self.msix_info
+            .insert(msix_info)
             .enable_vector(0)
-            .map_err(|_| NvmeError::PciError)?;
-        msix_info
-            .enable_vector(1)
-            .map_err(|_| NvmeError::PciError)?;
+            .map_err(|_| NvmeError::PciError)
+    }
 
-        self.msix_info = Some(msix_info);
-        Ok(())
+    /// Ask for an I/O queue pair per CPU, returning how many can be created
+    fn request_io_queues(&mut self) -> Result<usize, NvmeError> {
+        let wanted = cpu::online_cpus().min(MAX_IO_QUEUES);
+        let count = (wanted - 1) as u32;
+        let cmd = NvmeCommand::set_features(feature_ids::NUMBER_OF_QUEUES, count << 16 | count, 0);
+        let completion = submit_admin_command(cmd)?;
+
+        // NSQA and NCQA, 0-based
+        let submission = (completion.dw0 & 0xFFFF) as usize + 1;
+        let completion = (completion.dw0 >> 16) as usize + 1;
+        let granted = submission.min(completion).min(wanted);
+        if granted < wanted {
+            info!("NVMe controller granted {} of {} I/O queues", granted, wanted);
+        }
+        Ok(granted)
     }
 
     /// Reset the NVMe controller
@@ -332,25 +340,43 @@ impl NvmeController {
         Ok(())
     }
 
+    /// Notify the controller of a shutdown and wait for it to complete
+    fn shutdown(&mut self) -> Result<(), NvmeError> {
+        info!("Shutting down NVMe controller");
+
+        self.registers.request_shutdown();
+
+        let timeout = 100000; // Busy wait iterations
+        for _ in 0..timeout {
+            if self.registers.shutdown_complete() {
+                info!("Controller shutdown complete");
+                return Ok(());
+            }
+            for _ in 0..1000 {
+                core::hint::spin_loop();
+            }
+        }
+
+        Err(NvmeError::ShutdownTimeout)
+    }
+
     /// Set up admin submission and completion queues
     fn setup_admin_queues(&mut self) -> Result<(), NvmeError> {
         info!("Setting up admin queues");
 
-        let sq_phys = PhysAddr::new(
-            self.admin_queue.sq_entries.as_u64()
-                - FRAME_ALLOCATOR.lock().as_ref().unwrap().hddm_offset,
-        );
-        let cq_phys = PhysAddr::new(
-            self.admin_queue.cq_entries.as_u64()
-                - FRAME_ALLOCATOR.lock().as_ref().unwrap().hddm_offset,
-        );
+        let admin_queue = NvmeQueue::new(0, core::cmp::min(self.max_queue_entries, 64))?;
+        let sq_phys = admin_queue.sq_phys;
+        let cq_phys = admin_queue.cq_phys;
 
         self.registers
-            .set_admin_queue_attributes(self.admin_queue.size, self.admin_queue.size);
+            .set_admin_queue_attributes(admin_queue.size, admin_queue.size);
 
         self.registers.set_admin_sq_base(sq_phys.as_u64());
         self.registers.set_admin_cq_base(cq_phys.as_u64());
 
+        let doorbells = self.registers.queue_doorbells(0);
+        *NVME_ADMIN_QUEUE.lock() = Some(CommandQueue::new(admin_queue, doorbells));
+
         info!(
             "Admin queues configured: SQ={:#x}, CQ={:#x}",
             sq_phys.as_u64(),
@@ -380,40 +406,16 @@ impl NvmeController {
         Err(NvmeError::ControllerEnableTimeout)
     }
 
-    /// Submit an admin command and yield to scheduler for completion
-    ///
-    /// will issue msi-x interrupt when command completes
-    fn submit_admin_command(&mut self, cmd: NvmeCommand) -> Result<NvmeCompletion, NvmeError> {
-        // Submit command to admin queue
-        let cid = self.admin_queue.submit_command(cmd)?;
-
-        self.registers
-            .ring_doorbell(0, false, self.admin_queue.sq_tail);
-
-        kyield_task(NVME_ADMIN_VECTOR);
-
-        let completion = self
-            .admin_queue
-            .check_completion()
-            .ok_or(NvmeError::CommandNotCompleted)?;
-
-        if !completion.is_success() {
-            return Err(NvmeError::CommandFailed(completion.status_code()));
-        }
-
-        Ok(completion)
-    }
-
     /// Identify the controller and get basic information
     fn identify_controller(&mut self) -> Result<(), NvmeError> {
         info!("Identifying NVMe controller");
 
-        let buffer = DMA_MANAGER.lock().get_pool_4kb().ok_or(NvmeError::AllocationFailed)?;
+        let buffer = DmaBuffer::new_zeroed(1)?;
 
-        let cmd = NvmeCommand::identify_controller(buffer.phys_addr.as_u64());
-        let _completion = self.submit_admin_command(cmd)?;
+        let cmd = NvmeCommand::identify_controller(buffer.phys_addr());
+        let _completion = submit_admin_command(cmd)?;
 
-        let identify_data = unsafe { &*(buffer.virt_addr.as_ptr::<IdentifyController>()) };
+        let identify_data = unsafe { &*(buffer.as_mut_ptr() as *const IdentifyController) };
 
         let model = core::str::from_utf8(&identify_data.mn)
             .unwrap_or("Unknown")
@@ -434,6 +436,15 @@ impl NvmeController {
         info!("  Firmware: {}", firmware);
         info!("  Version: {:#x}", identify_data.ver);
         info!("  Namespaces: {}", identify_data.nn);
+        info!("  SGL Support: {:#x}", identify_data.sgls);
+
+        info!("  Power States: {}", identify_data.npss as u32 + 1);
+        info!("  APST Support: {}", identify_data.supports_apst());
+
+        self.sgl_supported = identify_data.supports_sgl();
+        self.power_states = identify_data.power_states();
+        self.apst_supported = identify_data.supports_apst();
+        self.sanitize = identify_data.sanitize_capabilities();
 
         Ok(())
     }
@@ -460,13 +471,13 @@ impl NvmeController {
     fn identify_namespace(&mut self, nsid: u32) -> Result<NvmeNamespace, NvmeError> {
         debug!("Identifying namespace {}", nsid);
 
-        let buffer = get_zeroed_dma(1)?;
+        let buffer = DmaBuffer::new_zeroed(1)?;
 
-        let cmd = NvmeCommand::identify_namespace(nsid, buffer.phys_addr.as_u64());
+        let cmd = NvmeCommand::identify_namespace(nsid, buffer.phys_addr());
 
-        let _completion = self.submit_admin_command(cmd)?;
+        let _completion = submit_admin_command(cmd)?;
 
-        let identify_data = unsafe { &*(buffer.virt_addr.as_ptr::<IdentifyNamespace>()) };
+        let identify_data = unsafe { &*(buffer.as_mut_ptr() as *const IdentifyNamespace) };
 
         if identify_data.nsze == 0 {
             return Err(NvmeError::InvalidNamespace);
@@ -493,144 +504,72 @@ impl NvmeController {
         })
     }
 
-    /// Create I/O submission and completion queues
-    fn create_io_queues(&mut self) -> Result<(), NvmeError> {
-        info!("Creating I/O queues");
+    /// Create `queues` I/O submission and completion queue pairs
+    fn create_io_queues(&mut self, queues: usize) -> Result<(), NvmeError> {
+        info!("Creating {} I/O queue pair(s)", queues);
 
         let queue_size = core::cmp::min(self.max_queue_entries, 64);
-        let mut io_queue = NvmeQueue::new(1, queue_size)?;
-
-        let msix_info = self.msix_info.as_ref().ok_or(NvmeError::PciError)?;
+        let msix_info = self.msix_info.as_mut().ok_or(NvmeError::PciError)?;
 
-        let io_vector = msix_info.vectors.get(1).ok_or(NvmeError::PciError)?;
-
-        io_queue.interrupt_vector = Some(io_vector.vector);
-
-        info!(
-            "Creating I/O Completion Queue with MSI-X interrupt vector {:#x}",
-            io_vector.vector
-        );
-        let create_cq_cmd = NvmeCommand::create_io_cq_with_interrupt(
-            1,
-            queue_size,
-            io_queue.cq_phys.as_u64(),
-            io_vector.index,
-        );
-
-        self.submit_admin_command(create_cq_cmd)?;
-        info!("I/O Completion Queue created");
-
-        let create_sq_cmd = NvmeCommand::create_io_sq(1, 1, queue_size, io_queue.sq_phys.as_u64());
-        self.submit_admin_command(create_sq_cmd)?;
-        info!("I/O Submission Queue created");
-
-        self.io_queue = Some(io_queue);
-        info!("I/O queues ready");
-        Ok(())
-    }
-
-    /// Submit an I/O command and yield current task to scheduler for completion
-    ///
-    /// Controller will issue an msi-x interrupt when ths command complete
-    /// The interrupt vector is configured in the I/O completion queue.
-    fn submit_io_command(&mut self, cmd: NvmeCommand) -> Result<NvmeCompletion, NvmeError> {
-        let io_queue = self.io_queue.as_mut().ok_or(NvmeError::NoIoQueue)?;
-
-        let cid = io_queue.submit_command(cmd)?;
-
-        self.registers.ring_doorbell(1, false, io_queue.sq_tail);
-
-        kyield_task(NVME_IO_VECTOR);
-
-        let completion = io_queue
-            .check_completion()
-            .ok_or(NvmeError::CommandNotCompleted)?;
-
-        if !completion.is_success() {
-            return Err(NvmeError::CommandFailed(completion.status_code()));
-        }
-
-        Ok(completion)
-    }
-
-    /// Read blocks from a namespace
-    pub fn read_blocks(
-        &mut self,
-        nsid: u32,
-        lba: u64,
-        blocks: u16,
-        buffer: &mut [u8],
-    ) -> Result<(), NvmeError> {
-        if !self.namespaces.iter().any(|ns| ns.nsid == nsid) {
-            return Err(NvmeError::InvalidNamespace);
-        }
-
-        let namespace = self.namespaces.iter().find(|ns| ns.nsid == nsid).unwrap();
-        let required_size = blocks as usize * namespace.block_size as usize;
-
-        if buffer.len() < required_size {
-            return Err(NvmeError::BufferTooSmall);
+        // vectors beyond one per queue are never raised
+        for unused in msix_info.vectors.drain((queues + 1).min(msix_info.vectors.len())..) {
+            irq::release_vector(unused.vector);
         }
+        let vector_count = msix_info.vectors.len();
+        VECTOR_COUNT.store(vector_count, Ordering::Release);
+
+        for queue_id in 1..=queues {
+            let index = queue_vector(queue_id, vector_count, queues);
+            let io_vector = msix_info.vectors[index].clone();
+            if !io_vector.enabled {
+                msix_info
+                    .enable_vector(io_vector.index)
+                    .map_err(|_| NvmeError::PciError)?;
+            }
 
-        let pages_needed = (required_size + 4095) / 4096;
-        let dma_buffer = get_zeroed_dma(pages_needed)?;
+            let mut io_queue = NvmeQueue::new(queue_id as u16, queue_size)?;
+            io_queue.interrupt_vector = Some(io_vector.vector);
 
-        let cmd = NvmeCommand::read(nsid, lba, blocks, dma_buffer.phys_addr.as_u64());
-        self.submit_io_command(cmd)?;
+            info!(
+                "Creating I/O queue pair {} with MSI-X vector {:#x}",
+                queue_id, io_vector.vector
+            );
+            let create_cq_cmd = NvmeCommand::create_io_cq_with_interrupt(
+                queue_id as u16,
+                queue_size,
+                io_queue.cq_phys.as_u64(),
+                io_vector.index,
+            );
+            submit_admin_command(create_cq_cmd)?;
 
-        unsafe {
-            core::ptr::copy_nonoverlapping(
-                dma_buffer.virt_addr.as_ptr::<u8>(),
-                buffer.as_mut_ptr(),
-                required_size,
+            let create_sq_cmd = NvmeCommand::create_io_sq(
+                queue_id as u16,
+                queue_id as u16,
+                queue_size,
+                io_queue.sq_phys.as_u64(),
             );
+            submit_admin_command(create_sq_cmd)?;
+
+            let doorbells = self.registers.queue_doorbells(queue_id as u16);
+            *NVME_IO_QUEUES[queue_id - 1].lock() = Some(CommandQueue::new(io_queue, doorbells));
         }
 
-        debug!(
-            "Read {} blocks from LBA {} (namespace {})",
-            blocks, lba, nsid
-        );
+        IO_QUEUE_COUNT.store(queues, Ordering::Release);
+        info!("I/O queues ready");
         Ok(())
     }
+}
 
-    /// Write blocks to a namespace
-    pub fn write_blocks(
-        &mut self,
-        nsid: u32,
-        lba: u64,
-        blocks: u16,
-        buffer: &[u8],
-    ) -> Result<(), NvmeError> {
-        if !self.namespaces.iter().any(|ns| ns.nsid == nsid) {
-            return Err(NvmeError::InvalidNamespace);
-        }
-
-        let namespace = self.namespaces.iter().find(|ns| ns.nsid == nsid).unwrap();
-        let required_size = blocks as usize * namespace.block_size as usize;
-
-        if buffer.len() < required_size {
-            return Err(NvmeError::BufferTooSmall);
-        }
-
-        let pages_needed = (required_size + 4095) / 4096;
-        let dma_buffer = get_zeroed_dma(pages_needed)?;
-
-        unsafe {
-            core::ptr::copy_nonoverlapping(
-                buffer.as_ptr(),
-                dma_buffer.virt_addr.as_mut_ptr::<u8>(),
-                required_size,
-            );
+impl Drop for NvmeController {
+    /// Give back the interrupt vectors, the controller is probed again after
+    /// a sleep state and takes new ones
+    fn drop(&mut self) {
+        if let Some(msix_info) = &self.msix_info {
+            for vector in &msix_info.vectors {
+                irq::release_vector(vector.vector);
+            }
         }
-
-        let cmd = NvmeCommand::write(nsid, lba, blocks, dma_buffer.phys_addr.as_u64());
-        self.submit_io_command(cmd)?;
-
-        debug!(
-            "Wrote {} blocks to LBA {} (namespace {})",
-            blocks, lba, nsid
-        );
-        Ok(())
+        VECTOR_COUNT.store(0, Ordering::Release);
     }
 }
 
@@ -653,26 +592,227 @@ pub fn find_nvme_controllers() -> Vec<PciDevice> {
     nvme_devices
 }
 
-/// Initialize NVMe subsystem (main entry point)
-pub fn nvme_init() {
-    let controllers = find_nvme_controllers();
+/// Controllers to probe
+///
+/// Only one controller is driven at a time, so only the first is returned.
+pub fn probe_candidates() -> Vec<PciDevice> {
+    find_nvme_controllers().into_iter().take(1).collect()
+}
+
+/// Name of the controller under `/sys/devices`
+const SYSFS_NAME: &str = "nvme0";
+
+/// Attributes of the controller under `/sys/devices/nvme0`
+static ATTRIBUTES: &[Attribute] = &[
+    Attribute {
+        name: "io_queues",
+        show: |_| Ok(IO_QUEUE_COUNT.load(Ordering::Acquire).to_string()),
+        store: None,
+    },
+    Attribute {
+        name: "queue_depth",
+        show: show_queue_depth,
+        store: Some(store_queue_depth),
+    },
+    Attribute {
+        name: "vectors",
+        show: |_| Ok(VECTOR_COUNT.load(Ordering::Acquire).to_string()),
+        store: None,
+    },
+];
+
+/// Commands in flight at once per I/O queue, and the most the queues allow
+fn show_queue_depth(_context: usize) -> Result<String, &'static str> {
+    let queue = NVME_IO_QUEUES[0].lock();
+    let queue = queue.as_ref().ok_or("no I/O queues")?;
+    Ok(format!("{} (max {})", queue.depth(), queue.max_depth()))
+}
+
+/// Limit the commands in flight at once on every I/O queue
+fn store_queue_depth(_context: usize, value: &str) -> Result<(), &'static str> {
+    let depth: usize = value.parse().map_err(|_| "not a number")?;
+    let queues = IO_QUEUE_COUNT.load(Ordering::Acquire);
+    if queues == 0 {
+        return Err("no I/O queues");
+    }
+    for queue in &NVME_IO_QUEUES[..queues] {
+        let mut queue = queue.lock();
+        let queue = queue.as_mut().ok_or("no I/O queues")?;
+        if depth == 0 || depth > queue.max_depth() {
+            return Err("depth out of range");
+        }
+        queue.set_depth(depth);
+    }
+    Ok(())
+}
+
+/// Initialize an NVMe controller and make it the global controller
+pub fn probe(device: PciDevice) -> Result<(), String> {
+    let controller = NvmeController::new(device).map_err(|e| format!("{e:?}"))?;
+    info!("NVMe controller initialized successfully");
+    *NVME_CONTROLLER.lock() = Some(controller);
+    super::block::register_namespaces();
+    if attr::register(SYSFS_NAME, ATTRIBUTES, 0).is_err() {
+        warn!("NVMe attributes already registered");
+    }
+    Ok(())
+}
+
+/// Shut the controller down before a sleep state
+///
+/// Refuses while commands are outstanding. The queues and the controller state
+/// are dropped, so the controller comes back by being probed again.
+pub fn suspend(_device: &PciDevice) -> Result<(), String> {
+    {
+        let mut io_queues: Vec<_> = NVME_IO_QUEUES.iter().map(|queue| queue.lock()).collect();
+        let mut admin_queue = NVME_ADMIN_QUEUE.lock();
+        let outstanding = io_queues
+            .iter()
+            .chain(core::iter::once(&admin_queue))
+            .flat_map(|queue| queue.as_ref())
+            .map(CommandQueue::outstanding)
+            .sum::<usize>();
+        if outstanding > 0 {
+            return Err(format!("{outstanding} command(s) outstanding"));
+        }
+        attr::unregister(SYSFS_NAME);
+        IO_QUEUE_COUNT.store(0, Ordering::Release);
+        io_queues.iter_mut().for_each(|queue| **queue = None);
+        *admin_queue = None;
+    }
+
+    let mut controller = NVME_CONTROLLER
+        .lock()
+        .take()
+        .ok_or_else(|| format!("{:?}", NvmeError::ControllerNotFound))?;
+    controller.shutdown().map_err(|e| format!("{e:?}"))
+}
+
+/// Check the controller for a fatal status, describing it if set
+pub fn health(_device: &PciDevice) -> Result<(), String> {
+    // the queues are locked before the controller, as in `suspend`
+    let outstanding = NVME_IO_QUEUES
+        .iter()
+        .chain(core::iter::once(&NVME_ADMIN_QUEUE))
+        .map(|queue| {
+            x86_64::instructions::interrupts::without_interrupts(|| {
+                queue.lock().as_ref().map_or(0, CommandQueue::outstanding)
+            })
+        })
+        .sum::<usize>();
 
-    if controllers.is_empty() {
-        info!("No NVMe controllers found");
-        return;
+    let controller = NVME_CONTROLLER.lock();
+    let Some(controller) = controller.as_ref() else {
+        return Ok(());
+    };
+    let status = controller.registers.status();
+    if status & csts_bits::CFS == 0 {
+        return Ok(());
     }
+    Err(format!(
+        "controller fatal status (CSTS {:#x}, CC {:#x}, {} command(s) outstanding)",
+        status, controller.registers.cc, outstanding
+    ))
+}
 
-    match NvmeController::new(controllers[0].clone()) {
-        Ok(controller) => {
-            info!("NVMe controller initialized successfully");
-            *NVME_CONTROLLER.lock() = Some(controller);
+/// Bring the controller back after a fatal status
+///
+/// Unlike `suspend`, doesn't wait for outstanding commands, which the
+/// controller will never complete: they fail instead. Once their submitters
+/// have given up, the controller is disabled, which clears the fatal status,
+/// and probed again.
+pub fn reset(device: PciDevice) -> Result<(), String> {
+    attr::unregister(SYSFS_NAME);
+    IO_QUEUE_COUNT.store(0, Ordering::Release);
+    NVME_IO_QUEUES.iter().for_each(abort);
+    abort(&NVME_ADMIN_QUEUE);
+
+    if let Some(mut controller) = NVME_CONTROLLER.lock().take()
+        && let Err(e) = controller.reset_controller()
+    {
+        warn!("NVMe controller didn't disable: {:?}", e);
+    }
+    probe(device)
+}
+
+/// Submit an admin command and sleep until it completes
+pub fn submit_admin_command(cmd: NvmeCommand) -> Result<NvmeCompletion, NvmeError> {
+    execute(&NVME_ADMIN_QUEUE, 0, cmd, NvmeError::ControllerNotFound)
+}
+
+/// Submit an I/O command for a namespace and sleep until it completes
+///
+/// The command is queued behind other requests for the same namespace and
+/// dispatched once the I/O submission queue has room. No lock is held while
+/// waiting, so other tasks can submit commands in the meantime.
+pub fn submit_io_command(nsid: u32, cmd: NvmeCommand) -> Result<NvmeCompletion, NvmeError> {
+    let queue = io_queue().ok_or(NvmeError::NoIoQueue)?;
+    execute(queue, nsid, cmd, NvmeError::NoIoQueue)
+}
+
+/// Check whether I/O commands should use SGLs
+fn sgl_supported() -> bool {
+    NVME_CONTROLLER
+        .lock()
+        .as_ref()
+        .is_some_and(|controller| controller.sgl_supported)
+}
+
+/// Point a command's data pointer at a physically contiguous DMA buffer
+///
+/// Uses a single SGL data block descriptor if the controller supports SGLs.
+/// Otherwise PRP1/PRP2 are used, with a PRP list for transfers spanning more
+/// than two pages. The returned buffer holds the PRP list and must be kept
+/// alive until the command completes.
+fn set_data_pointer(
+    cmd: &mut NvmeCommand,
+    buffer: &DmaBuffer,
+    length: usize,
+) -> Result<Option<DmaBuffer>, NvmeError> {
+    let base = buffer.phys_addr();
+
+    if sgl_supported() {
+        cmd.set_sgl(SglDescriptor::data_block(base, length as u32));
+        return Ok(None);
+    }
+
+    cmd.prp1 = base;
+    let pages = length.div_ceil(4096);
+    match pages {
+        0 | 1 => Ok(None),
+        2 => {
+            cmd.set_prp2(base + 4096);
+            Ok(None)
         }
-        Err(e) => {
-            warn!("Failed to initialize NVMe controller: {:?}", e);
+        _ => {
+            let entries = pages - 1;
+            if entries > 4096 / size_of::<u64>() {
+                return Err(NvmeError::BufferTooSmall);
+            }
+
+            let prp_list = DmaBuffer::new_zeroed(1)?;
+            let list = prp_list.as_mut_ptr() as *mut u64;
+            for i in 0..entries {
+                unsafe { list.add(i).write_volatile(base + (i as u64 + 1) * 4096) };
+            }
+            cmd.set_prp2(prp_list.phys_addr());
+            Ok(Some(prp_list))
         }
     }
 }
 
+/// Look up a namespace by ID
+fn find_namespace(nsid: u32) -> Result<NvmeNamespace, NvmeError> {
+    let controller = NVME_CONTROLLER.lock();
+    let controller = controller.as_ref().ok_or(NvmeError::ControllerNotFound)?;
+    controller
+        .namespaces
+        .iter()
+        .find(|ns| ns.nsid == nsid)
+        .cloned()
+        .ok_or(NvmeError::InvalidNamespace)
+}
+
 /// Read blocks from the NVMe device
 ///
 /// # Arguments
@@ -681,9 +821,27 @@ pub fn nvme_init() {
 /// * `blocks` - Number of blocks to read
 /// * `buffer` - Buffer to read data into
 pub fn read_blocks(nsid: u32, lba: u64, blocks: u16, buffer: &mut [u8]) -> Result<(), NvmeError> {
-    let mut controller = NVME_CONTROLLER.lock();
-    let controller = controller.as_mut().ok_or(NvmeError::ControllerNotFound)?;
-    controller.read_blocks(nsid, lba, blocks, buffer)
+    let namespace = find_namespace(nsid)?;
+    let required_size = blocks as usize * namespace.block_size as usize;
+
+    if buffer.len() < required_size {
+        return Err(NvmeError::BufferTooSmall);
+    }
+
+    let pages_needed = required_size.div_ceil(4096);
+    let dma_buffer = DmaBuffer::new_zeroed(pages_needed)?;
+
+    let mut cmd = NvmeCommand::read(nsid, lba, blocks, dma_buffer.phys_addr());
+    let _prp_list = set_data_pointer(&mut cmd, &dma_buffer, required_size)?;
+    submit_io_command(nsid, cmd)?;
+
+    buffer[..required_size].copy_from_slice(&dma_buffer.as_slice()[..required_size]);
+
+    debug!(
+        "Read {} blocks from LBA {} (namespace {})",
+        blocks, lba, nsid
+    );
+    Ok(())
 }
 
 /// Write blocks to the NVMe device
@@ -694,9 +852,26 @@ pub fn read_blocks(nsid: u32, lba: u64, blocks: u16, buffer: &mut [u8]) -> Resul
 /// * `blocks` - Number of blocks to write
 /// * `buffer` - Buffer containing data to write
 pub fn write_blocks(nsid: u32, lba: u64, blocks: u16, buffer: &[u8]) -> Result<(), NvmeError> {
-    let mut controller = NVME_CONTROLLER.lock();
-    let controller = controller.as_mut().ok_or(NvmeError::ControllerNotFound)?;
-    controller.write_blocks(nsid, lba, blocks, buffer)
+    let namespace = find_namespace(nsid)?;
+    let required_size = blocks as usize * namespace.block_size as usize;
+
+    if buffer.len() < required_size {
+        return Err(NvmeError::BufferTooSmall);
+    }
+
+    let pages_needed = required_size.div_ceil(4096);
+    let mut dma_buffer = DmaBuffer::new_zeroed(pages_needed)?;
+    dma_buffer.as_mut_slice()[..required_size].copy_from_slice(&buffer[..required_size]);
+
+    let mut cmd = NvmeCommand::write(nsid, lba, blocks, dma_buffer.phys_addr());
+    let _prp_list = set_data_pointer(&mut cmd, &dma_buffer, required_size)?;
+    submit_io_command(nsid, cmd)?;
+
+    debug!(
+        "Wrote {} blocks to LBA {} (namespace {})",
+        blocks, lba, nsid
+    );
+    Ok(())
 }
 
 /// Get information about available namespaces
@@ -715,7 +890,10 @@ pub fn get_namespaces() -> Vec<NvmeNamespace> {
 /// 1. Reads a block from LBA 0
 /// 2. Writes a test pattern to LBA 1
 /// 3. Reads back LBA 1 to verify the write
+#[cfg(feature = "tests")]
 pub fn test_nvme_io() -> Result<(), NvmeError> {
+    use crate::testing::snapshot::{Resource, Snapshot};
+
     info!("Starting NVMe I/O test");
 
     let namespaces = get_namespaces();
@@ -736,6 +914,9 @@ pub fn test_nvme_io() -> Result<(), NvmeError> {
     let mut write_buffer = alloc::vec![0u8; block_size];
     let mut verify_buffer = alloc::vec![0u8; block_size];
 
+    // every DMA buffer used for the I/O must be freed again
+    let dma = Snapshot::take();
+
     // Test 1: Read from LBA 0
     info!("Test 1: Reading from LBA 0");
     read_blocks(ns.nsid, 0, 1, &mut read_buffer)?;
@@ -783,6 +964,7 @@ pub fn test_nvme_io() -> Result<(), NvmeError> {
         warn!("✗ Verification failed! {} mismatches found", mismatches);
         return Err(NvmeError::CommandFailed(0));
     }
+    dma.assert_unchanged(&[Resource::Dma]);
 
     info!("NVMe I/O test completed successfully");
     Ok(())
@@ -0,0 +1,107 @@
+//! NVMe power management
+//!
+//! Power states are described by the controller's Identify data. If the
+//! controller supports Autonomous Power State Transitions (APST), it is
+//! handed a table telling it which non-operational state to drop into after
+//! how long an idle period, following the same policy as Linux: every state
+//! transitions to the deepest non-operational state whose exit latency is
+//! acceptable, after an idle time of 50 times its total transition latency.
+
+use alloc::vec::Vec;
+use kernel_api::{debug, dma::DmaBuffer, info};
+
+use super::{
+    commands::{NvmeCommand, PowerStateDescriptor},
+    controller::{NVME_CONTROLLER, NvmeError, submit_admin_command},
+    registers::feature_ids,
+};
+
+/// Largest exit latency tolerated for autonomous transitions, in microseconds
+pub const APST_MAX_LATENCY_US: u32 = 100_000;
+
+/// Number of entries in the APST data structure
+const APST_ENTRIES: usize = 32;
+
+/// Build the APST table for the given power states
+///
+/// Entry `n` holds the idle transition for power state `n`: the Idle
+/// Transition Power State in bits 7:3 and the Idle Time Prior to Transition
+/// (in milliseconds) in bits 31:8. Returns None if no non-operational state
+/// is shallow enough to be used.
+pub fn build_apst_table(states: &[PowerStateDescriptor]) -> Option<[u64; APST_ENTRIES]> {
+    let mut table = [0u64; APST_ENTRIES];
+    let mut target = 0u64;
+
+    for (index, state) in states.iter().enumerate().take(APST_ENTRIES).rev() {
+        table[index] = target;
+
+        if !state.non_operational || state.exit_latency > APST_MAX_LATENCY_US {
+            continue;
+        }
+
+        let total_latency_us = state.entry_latency as u64 + state.exit_latency as u64;
+        let idle_ms = total_latency_us.div_ceil(20).min((1 << 24) - 1);
+        target = ((index as u64) << 3) | (idle_ms << 8);
+    }
+
+    (target != 0).then_some(table)
+}
+
+/// Program and enable APST for the given power states
+pub fn enable_apst(states: &[PowerStateDescriptor]) -> Result<bool, NvmeError> {
+    let Some(table) = build_apst_table(states) else {
+        info!("NVMe APST: no usable non-operational power state");
+        return Ok(false);
+    };
+
+    let buffer = DmaBuffer::new_zeroed(1)?;
+    let entries = buffer.as_mut_ptr() as *mut u64;
+    for (i, entry) in table.iter().enumerate() {
+        unsafe { entries.add(i).write_volatile(*entry) };
+        if *entry != 0 {
+            debug!(
+                "  APST PS{} -> PS{} after {} ms",
+                i,
+                (*entry >> 3) & 0x1F,
+                *entry >> 8
+            );
+        }
+    }
+
+    let cmd = NvmeCommand::set_features(
+        feature_ids::AUTONOMOUS_POWER_STATE_TRANSITION,
+        1, // APSTE
+        buffer.phys_addr(),
+    );
+    submit_admin_command(cmd)?;
+
+    info!("NVMe APST enabled");
+    Ok(true)
+}
+
+/// Power state descriptors reported by the controller
+pub fn power_states() -> Vec<PowerStateDescriptor> {
+    NVME_CONTROLLER
+        .lock()
+        .as_ref()
+        .map(|controller| controller.power_states.clone())
+        .unwrap_or_default()
+}
+
+/// Read the controller's current power state (Get Features, Power Management)
+pub fn current_power_state() -> Result<u8, NvmeError> {
+    let cmd = NvmeCommand::get_features(feature_ids::POWER_MANAGEMENT, 0);
+    let completion = submit_admin_command(cmd)?;
+    Ok((completion.dw0 & 0x1F) as u8)
+}
+
+/// Check whether APST is currently enabled (Get Features, APST)
+pub fn apst_enabled() -> Result<bool, NvmeError> {
+    let buffer = DmaBuffer::new_zeroed(1)?;
+    let cmd = NvmeCommand::get_features(
+        feature_ids::AUTONOMOUS_POWER_STATE_TRANSITION,
+        buffer.phys_addr(),
+    );
+    let completion = submit_admin_command(cmd)?;
+    Ok(completion.dw0 & 0x1 != 0)
+}
@@ -0,0 +1,421 @@
+//! NVMe queue pairs and request dispatch
+//!
+//! Admin and I/O commands go through separate `CommandQueue`s, each behind its
+//! own lock, so a long transfer never blocks admin commands. There is an I/O
+//! queue pair per CPU, as many as the controller grants, and commands go to
+//! the pair of the CPU submitting them. Submitters only hold a queue lock
+//! while touching the rings: requests are queued per namespace, dispatched
+//! round-robin whenever submission slots are free, and the submitting task
+//! sleeps until its completion has been matched back to it by command id.
+//!
+//! Completion queues are only drained by `reap_completions`, which the
+//! interrupt handlers defer to the deferred-work task. Submitters never poll
+//! the rings themselves: they collect what a drain parked for them, and are
+//! woken after every drain of their queue.
+//!
+//! Each `CommandQueue` has a generation, new for every queue created, so a
+//! submitter whose queue was aborted and replaced while it slept doesn't
+//! collect completions from the replacement, whose request ids start over.
+
+use alloc::collections::{btree_map::BTreeMap, vec_deque::VecDeque};
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use kernel_api::{
+    cpu, debug,
+    dma::DmaBuffer,
+    wait::{self, WaitQueue},
+    warn,
+};
+use x86_64::{PhysAddr, VirtAddr, instructions::interrupts};
+
+use super::{
+    commands::{NvmeCommand, NvmeCompletion},
+    controller::NvmeError,
+    registers::QueueDoorbells,
+};
+use crate::sync::Mutex;
+
+/// Admin queue pair (queue ID 0)
+pub static NVME_ADMIN_QUEUE: Mutex<Option<CommandQueue>> = Mutex::new("NVME_ADMIN_QUEUE", None);
+
+/// Most I/O queue pairs created
+pub const MAX_IO_QUEUES: usize = 8;
+
+/// I/O queue pairs, entry `i` has queue ID `i + 1`
+pub static NVME_IO_QUEUES: [Mutex<Option<CommandQueue>>; MAX_IO_QUEUES] =
+    [const { Mutex::new("NVME_IO_QUEUE", None) }; MAX_IO_QUEUES];
+
+/// Number of I/O queue pairs created, from the start of `NVME_IO_QUEUES`
+pub static IO_QUEUE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Submitters waiting on the admin queue and each I/O queue, by queue ID
+static WAITERS: [WaitQueue; MAX_IO_QUEUES + 1] = [const { WaitQueue::new() }; MAX_IO_QUEUES + 1];
+
+/// Submitters in `execute` on the admin queue and each I/O queue, by queue ID
+static SUBMITTERS: [AtomicUsize; MAX_IO_QUEUES + 1] =
+    [const { AtomicUsize::new(0) }; MAX_IO_QUEUES + 1];
+
+/// Generation of the next `CommandQueue` created
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// I/O queue pair for commands submitted on this CPU, `None` before any was
+/// created
+pub fn io_queue() -> Option<&'static Mutex<Option<CommandQueue>>> {
+    match IO_QUEUE_COUNT.load(Ordering::Acquire) {
+        0 => None,
+        count => Some(&NVME_IO_QUEUES[cpu::current_cpu() % count]),
+    }
+}
+
+/// Queue management structure
+#[derive(Debug)]
+pub struct NvmeQueue {
+    /// Submission queue entries
+    pub sq_entries: VirtAddr,
+    /// Submission queue physical address
+    pub sq_phys: PhysAddr,
+    /// Completion queue entries
+    pub cq_entries: VirtAddr,
+    /// Completion queue physical address
+    pub cq_phys: PhysAddr,
+    /// Queue size (number of entries)
+    pub size: u16,
+    /// Submission queue head
+    pub sq_head: u16,
+    /// Submission queue tail
+    pub sq_tail: u16,
+    /// Completion queue head
+    pub cq_head: u16,
+    /// Completion queue phase bit
+    pub cq_phase: bool,
+    /// Queue ID
+    pub queue_id: u16,
+    /// MSI-X interrupt vector for this queue (None for admin queue using polling)
+    pub interrupt_vector: Option<u8>,
+    /// Next command identifier to hand out
+    next_cid: u16,
+    /// Backing memory of both rings
+    _buffer: DmaBuffer,
+}
+
+impl NvmeQueue {
+    /// Create a new queue pair
+    pub fn new(queue_id: u16, size: u16) -> Result<Self, NvmeError> {
+        let sq_size = size as usize * 64; // 64 bytes per SQ entry
+        let cq_size = size as usize * 16; // 16 bytes per CQ entry
+        let total_size = sq_size + cq_size;
+        let pages_needed = total_size.div_ceil(4096);
+
+        let buffer = DmaBuffer::new_zeroed(pages_needed)?;
+        let sq_virt = VirtAddr::from_ptr(buffer.as_mut_ptr());
+        let sq_phys = PhysAddr::new(buffer.phys_addr());
+        let cq_virt = VirtAddr::new(sq_virt.as_u64() + sq_size as u64);
+        let cq_phys = PhysAddr::new(sq_phys.as_u64() + sq_size as u64);
+
+        debug!(
+            "Created NVMe queue {}: SQ at {:#x}, CQ at {:#x}",
+            queue_id,
+            sq_virt.as_u64(),
+            cq_virt.as_u64()
+        );
+
+        Ok(Self {
+            sq_entries: sq_virt,
+            sq_phys,
+            cq_entries: cq_virt,
+            cq_phys,
+            size,
+            sq_head: 0,
+            sq_tail: 0,
+            cq_head: 0,
+            cq_phase: true,
+            queue_id,
+            interrupt_vector: None,
+            next_cid: 0,
+            _buffer: buffer,
+        })
+    }
+
+    /// Check whether the submission queue has no free slot left
+    pub fn is_full(&self) -> bool {
+        (self.sq_tail + 1) % self.size == self.sq_head
+    }
+
+    /// Submit a command to the submission queue
+    pub fn submit_command(&mut self, mut cmd: NvmeCommand) -> Result<u16, NvmeError> {
+        if self.is_full() {
+            return Err(NvmeError::QueueFull);
+        }
+
+        let cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+        cmd.set_command_id(cid);
+
+        unsafe {
+            let entry_ptr = self
+                .sq_entries
+                .as_mut_ptr::<NvmeCommand>()
+                .add(self.sq_tail as usize);
+            core::ptr::write_volatile(entry_ptr, cmd);
+        }
+
+        self.sq_tail = (self.sq_tail + 1) % self.size;
+
+        Ok(cid)
+    }
+
+    /// Check for completion queue entries
+    pub fn check_completion(&mut self) -> Option<NvmeCompletion> {
+        let entry_ptr = unsafe {
+            self.cq_entries
+                .as_ptr::<NvmeCompletion>()
+                .add(self.cq_head as usize)
+        };
+
+        let completion = unsafe { core::ptr::read_volatile(entry_ptr) };
+
+        if completion.is_valid(self.cq_phase) {
+            self.cq_head = (self.cq_head + 1) % self.size;
+
+            if self.cq_head == 0 {
+                self.cq_phase = !self.cq_phase;
+            }
+
+            Some(completion)
+        } else {
+            None
+        }
+    }
+}
+
+/// A queue pair shared by every task issuing commands on it.
+///
+/// Requests wait in a per-namespace FIFO until a submission slot is free,
+/// and completions are parked by request id until their submitter collects
+/// them, so any number of tasks can have commands in flight at once.
+pub struct CommandQueue {
+    queue: NvmeQueue,
+    doorbells: QueueDoorbells,
+    next_request: u64,
+    /// Requests waiting for a submission slot, keyed by namespace
+    pending: BTreeMap<u32, VecDeque<(u64, NvmeCommand)>>,
+    /// Namespace that was dispatched from last, for round-robin fairness
+    last_namespace: u32,
+    /// Request ids of submitted commands, keyed by command id
+    in_flight: BTreeMap<u16, u64>,
+    /// Completions not yet collected by their submitter
+    completed: BTreeMap<u64, NvmeCompletion>,
+    /// Most commands in flight at once, at most one less than the queue size
+    depth: usize,
+    /// Tells this queue apart from the ones before and after it in its slot
+    generation: u64,
+}
+
+impl CommandQueue {
+    /// Wrap a queue pair
+    pub fn new(queue: NvmeQueue, doorbells: QueueDoorbells) -> Self {
+        let depth = queue.size as usize - 1;
+        Self {
+            queue,
+            doorbells,
+            next_request: 0,
+            pending: BTreeMap::new(),
+            last_namespace: 0,
+            in_flight: BTreeMap::new(),
+            completed: BTreeMap::new(),
+            depth,
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Most commands in flight at once
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Largest depth the queue size allows
+    pub fn max_depth(&self) -> usize {
+        self.queue.size as usize - 1
+    }
+
+    /// Limit the commands in flight at once, requests beyond wait in the
+    /// pending queues
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth.clamp(1, self.max_depth());
+        self.dispatch();
+    }
+
+    /// Queue a command for a namespace, returning the id of the request
+    pub fn enqueue(&mut self, nsid: u32, cmd: NvmeCommand) -> u64 {
+        let request = self.next_request;
+        self.next_request += 1;
+
+        self.pending.entry(nsid).or_default().push_back((request, cmd));
+        self.dispatch();
+        request
+    }
+
+    /// Move pending requests into free submission slots, one namespace at a time
+    fn dispatch(&mut self) {
+        let mut submitted = false;
+
+        while !self.queue.is_full() && self.in_flight.len() < self.depth {
+            let Some(nsid) = self.next_pending_namespace() else {
+                break;
+            };
+
+            let requests = self.pending.get_mut(&nsid).unwrap();
+            let (request, cmd) = requests.pop_front().unwrap();
+            if requests.is_empty() {
+                self.pending.remove(&nsid);
+            }
+
+            let cid = self.queue.submit_command(cmd).unwrap();
+            self.in_flight.insert(cid, request);
+            submitted = true;
+        }
+
+        if submitted {
+            self.doorbells.ring_sq(self.queue.sq_tail);
+        }
+    }
+
+    /// Pick the next namespace with pending requests after the last one served
+    fn next_pending_namespace(&mut self) -> Option<u32> {
+        let nsid = self
+            .pending
+            .range(self.last_namespace.wrapping_add(1)..)
+            .chain(self.pending.iter())
+            .map(|(&nsid, _)| nsid)
+            .next()?;
+
+        self.last_namespace = nsid;
+        Some(nsid)
+    }
+
+    /// Drain the completion queue, then refill the freed submission slots
+    ///
+    /// Returns whether any command completed.
+    fn reap(&mut self) -> bool {
+        let mut reaped = false;
+
+        while let Some(completion) = self.queue.check_completion() {
+            self.queue.sq_head = completion.sq_head;
+            match self.in_flight.remove(&completion.cid) {
+                Some(request) => {
+                    self.completed.insert(request, completion);
+                }
+                None => {
+                    warn!(
+                        "NVMe queue {}: completion for unknown command id {}",
+                        self.queue.queue_id, completion.cid
+                    );
+                }
+            }
+            reaped = true;
+        }
+
+        if reaped {
+            self.doorbells.ring_cq(self.queue.cq_head);
+            self.dispatch();
+        }
+        reaped
+    }
+
+    /// Collect the completion of a request, if it has completed
+    pub fn take_completion(&mut self, request: u64) -> Option<NvmeCompletion> {
+        self.completed.remove(&request)
+    }
+
+    /// Number of requests queued or in flight
+    pub fn outstanding(&self) -> usize {
+        self.pending.values().map(VecDeque::len).sum::<usize>() + self.in_flight.len()
+    }
+}
+
+/// Queue a command and sleep until it completes
+///
+/// `missing` is returned if the queue has not been created, and
+/// `NvmeError::Aborted` if it was aborted before the command completed.
+pub fn execute(
+    queue: &Mutex<Option<CommandQueue>>,
+    nsid: u32,
+    cmd: NvmeCommand,
+    missing: NvmeError,
+) -> Result<NvmeCompletion, NvmeError> {
+    let submitters = &SUBMITTERS[slot(queue)];
+    submitters.fetch_add(1, Ordering::AcqRel);
+    let result = wait_for(queue, nsid, cmd, missing);
+    submitters.fetch_sub(1, Ordering::AcqRel);
+
+    let completion = result?;
+    if !completion.is_success() {
+        return Err(NvmeError::CommandFailed(completion.status_code()));
+    }
+    Ok(completion)
+}
+
+/// Queue a command and sleep until it completes or its queue goes away
+fn wait_for(
+    queue: &Mutex<Option<CommandQueue>>,
+    nsid: u32,
+    cmd: NvmeCommand,
+    missing: NvmeError,
+) -> Result<NvmeCompletion, NvmeError> {
+    let (generation, request) = {
+        let mut lock = queue.lock();
+        let queue = lock.as_mut().ok_or(missing)?;
+        (queue.generation, queue.enqueue(nsid, cmd))
+    };
+
+    // the check runs with interrupts off until the task is marked waiting,
+    // so a drain can't slip in between looking and going to sleep
+    let mut result = None;
+    waiters(queue).wait_until(|| {
+        result = match queue.lock().as_mut() {
+            Some(queue) if queue.generation == generation => queue.take_completion(request).map(Ok),
+            _ => Some(Err(NvmeError::Aborted)),
+        };
+        result.is_some()
+    });
+    result.expect("woken without a completion")
+}
+
+/// Drop `queue`, failing the requests still on it
+///
+/// For a controller that stopped answering: its submitters are woken and
+/// find the queue gone. Returns once every one of them has given up, so the
+/// slot can take a new queue.
+pub fn abort(queue: &Mutex<Option<CommandQueue>>) {
+    interrupts::without_interrupts(|| *queue.lock() = None);
+    waiters(queue).wake_all();
+    while SUBMITTERS[slot(queue)].load(Ordering::Acquire) != 0 {
+        wait::sleep_ms(1);
+    }
+}
+
+/// Index of `queue` in `WAITERS` and `SUBMITTERS`, its queue ID
+fn slot(queue: &Mutex<Option<CommandQueue>>) -> usize {
+    NVME_IO_QUEUES
+        .iter()
+        .position(|io_queue| ptr::eq(io_queue, queue))
+        .map_or(0, |index| index + 1)
+}
+
+/// Submitters waiting on `queue`
+fn waiters(queue: &Mutex<Option<CommandQueue>>) -> &'static WaitQueue {
+    &WAITERS[slot(queue)]
+}
+
+/// Drain the completions of `queue` and wake the tasks waiting on it
+///
+/// Runs as deferred work of the queue's interrupt.
+pub fn reap_completions(queue: &Mutex<Option<CommandQueue>>) {
+    let reaped =
+        interrupts::without_interrupts(|| queue.lock().as_mut().is_some_and(CommandQueue::reap));
+    if reaped {
+        waiters(queue).wake_all();
+    }
+}
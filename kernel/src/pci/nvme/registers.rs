@@ -67,6 +67,11 @@ impl NvmeRegisters {
         (self.csts & csts_bits::RDY) != 0
     }
     
+    /// Read the Controller Status register
+    pub fn status(&self) -> u32 {
+        unsafe { core::ptr::read_volatile(&self.csts) }
+    }
+
     /// Check if the controller has a fatal status
     pub fn is_fatal(&self) -> bool {
         (self.csts & csts_bits::CFS) != 0
@@ -82,6 +87,17 @@ impl NvmeRegisters {
         self.cc &= !cc_bits::EN;
     }
     
+    /// Notify the controller of a normal shutdown (CC.SHN = 01b)
+    pub fn request_shutdown(&mut self) {
+        self.cc = (self.cc & !cc_bits::SHN_MASK) | cc_bits::SHN_NORMAL;
+    }
+    
+    /// Check if shutdown processing is complete (CSTS.SHST = 10b)
+    pub fn shutdown_complete(&self) -> bool {
+        let csts = unsafe { core::ptr::read_volatile(&self.csts) };
+        (csts & csts_bits::SHST_MASK) == csts_bits::SHST_COMPLETE
+    }
+    
     /// Set admin queue attributes
     pub fn set_admin_queue_attributes(&mut self, sq_size: u16, cq_size: u16) {
         // Both sizes are 0-based (actual size - 1)
@@ -120,6 +136,40 @@ impl NvmeRegisters {
             }
         }
     }
+
+    /// Get the doorbell pair of a queue, so it can be rung without access to the registers
+    pub fn queue_doorbells(&mut self, queue_id: u16) -> QueueDoorbells {
+        let stride = self.doorbell_stride() as usize;
+        let base = self.doorbells.as_mut_ptr() as *mut u8;
+        unsafe {
+            QueueDoorbells {
+                sq_tail: base.add(2 * queue_id as usize * stride) as *mut u32,
+                cq_head: base.add((2 * queue_id as usize + 1) * stride) as *mut u32,
+            }
+        }
+    }
+}
+
+/// Submission tail and completion head doorbells of a single queue pair
+#[derive(Debug)]
+pub struct QueueDoorbells {
+    sq_tail: *mut u32,
+    cq_head: *mut u32,
+}
+
+// Safety: the doorbells are MMIO registers that are only written by the queue owning them
+unsafe impl Send for QueueDoorbells {}
+
+impl QueueDoorbells {
+    /// Ring the submission queue tail doorbell
+    pub fn ring_sq(&self, tail: u16) {
+        unsafe { core::ptr::write_volatile(self.sq_tail, tail as u32) };
+    }
+
+    /// Ring the completion queue head doorbell
+    pub fn ring_cq(&self, head: u16) {
+        unsafe { core::ptr::write_volatile(self.cq_head, head as u32) };
+    }
 }
 
 /// Controller Capabilities Register (CAP) bit definitions
@@ -143,6 +193,8 @@ pub mod cc_bits {
     pub const MPS_SHIFT: u32 = 7;                // Memory Page Size
     pub const AMS_SHIFT: u32 = 11;               // Arbitration Mechanism Selected
     pub const SHN_SHIFT: u32 = 14;               // Shutdown Notification
+    pub const SHN_MASK: u32 = 0x3 << SHN_SHIFT;  // Shutdown Notification mask
+    pub const SHN_NORMAL: u32 = 0x1 << SHN_SHIFT; // Normal shutdown notification
     pub const IOSQES_SHIFT: u32 = 16;            // I/O Submission Queue Entry Size
     pub const IOCQES_SHIFT: u32 = 20;            // I/O Completion Queue Entry Size
 }
@@ -152,6 +204,7 @@ pub mod csts_bits {
     pub const RDY: u32 = 1 << 0;                 // Ready
     pub const CFS: u32 = 1 << 1;                 // Controller Fatal Status
     pub const SHST_MASK: u32 = 0x3 << 2;         // Shutdown Status
+    pub const SHST_COMPLETE: u32 = 0x2 << 2;     // Shutdown processing complete
     pub const NSSRO: u32 = 1 << 4;               // NVM Subsystem Reset Occurred
     pub const PP: u32 = 1 << 5;                  // Processing Paused
 }
@@ -175,6 +228,7 @@ pub mod opcodes {
     pub const ADMIN_ABORT: u8 = 0x08;
     pub const ADMIN_SET_FEATURES: u8 = 0x09;
     pub const ADMIN_GET_FEATURES: u8 = 0x0A;
+    pub const ADMIN_SANITIZE: u8 = 0x84;
     
     // NVM commands
     pub const NVM_FLUSH: u8 = 0x00;
@@ -186,6 +240,24 @@ pub mod opcodes {
     pub const NVM_DATASET_MANAGEMENT: u8 = 0x09;
 }
 
+/// Feature identifiers for SET/GET FEATURES
+pub mod feature_ids {
+    pub const ARBITRATION: u8 = 0x01;
+    pub const POWER_MANAGEMENT: u8 = 0x02;
+    pub const TEMPERATURE_THRESHOLD: u8 = 0x04;
+    pub const NUMBER_OF_QUEUES: u8 = 0x07;
+    pub const INTERRUPT_COALESCING: u8 = 0x08;
+    pub const AUTONOMOUS_POWER_STATE_TRANSITION: u8 = 0x0C;
+}
+
+/// Log page identifiers for GET LOG PAGE
+pub mod log_ids {
+    pub const ERROR_INFORMATION: u8 = 0x01;
+    pub const SMART_HEALTH: u8 = 0x02;
+    pub const FIRMWARE_SLOT: u8 = 0x03;
+    pub const SANITIZE_STATUS: u8 = 0x81;
+}
+
 /// IDENTIFY command CNS (Controller or Namespace Structure) values
 pub mod identify_cns {
     pub const NAMESPACE: u32 = 0x00;             // Identify Namespace
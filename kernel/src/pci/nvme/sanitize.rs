@@ -0,0 +1,197 @@
+//! NVMe sanitize
+//!
+//! A sanitize operation erases every namespace of the controller, including
+//! data in caches and unallocated blocks. The controller runs it in the
+//! background after the Sanitize command completes, reporting progress in the
+//! Sanitize Status log page; I/O commands fail until it is done. The block
+//! cache is discarded as soon as the operation starts, since nothing cached is
+//! on the device anymore.
+
+use core::fmt;
+use kernel_api::{dma::DmaBuffer, info, wait};
+
+use super::{
+    cache,
+    commands::{NvmeCommand, SanitizeCapabilities, sanitize_action},
+    controller::{NVME_CONTROLLER, NvmeError, submit_admin_command},
+    registers::log_ids,
+};
+
+/// Size of the Sanitize Status log page
+const STATUS_LOG_SIZE: u32 = 512;
+
+/// How the data is erased
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeAction {
+    /// Erase the media blocks
+    BlockErase,
+    /// Change the media encryption key, making the old data unreadable
+    CryptoErase,
+}
+
+impl SanitizeAction {
+    fn sanact(self) -> u32 {
+        match self {
+            SanitizeAction::BlockErase => sanitize_action::BLOCK_ERASE,
+            SanitizeAction::CryptoErase => sanitize_action::CRYPTO_ERASE,
+        }
+    }
+
+    fn supported(self, capabilities: SanitizeCapabilities) -> bool {
+        match self {
+            SanitizeAction::BlockErase => capabilities.block_erase,
+            SanitizeAction::CryptoErase => capabilities.crypto_erase,
+        }
+    }
+}
+
+/// Outcome of the most recent sanitize operation (SSTAT bits 0-2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeState {
+    Never,
+    Completed,
+    InProgress,
+    Failed,
+    /// Completed, but the blocks weren't deallocated
+    CompletedNoDeallocate,
+    Unknown(u8),
+}
+
+impl From<u16> for SanitizeState {
+    fn from(sstat: u16) -> Self {
+        match sstat & 0x7 {
+            0 => SanitizeState::Never,
+            1 => SanitizeState::Completed,
+            2 => SanitizeState::InProgress,
+            3 => SanitizeState::Failed,
+            4 => SanitizeState::CompletedNoDeallocate,
+            other => SanitizeState::Unknown(other as u8),
+        }
+    }
+}
+
+/// Contents of the Sanitize Status log page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SanitizeStatus {
+    pub state: SanitizeState,
+    /// Fraction of the operation in progress done, out of 65536
+    pub progress: u16,
+    /// Whether no user data was written since the last successful sanitize
+    pub global_data_erased: bool,
+    /// Estimated seconds a block erase takes, if known
+    pub block_erase_estimate: Option<u32>,
+    /// Estimated seconds a crypto erase takes, if known
+    pub crypto_erase_estimate: Option<u32>,
+}
+
+impl SanitizeStatus {
+    fn parse(log: &[u8]) -> Self {
+        let u16_at = |offset: usize| u16::from_le_bytes([log[offset], log[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                log[offset],
+                log[offset + 1],
+                log[offset + 2],
+                log[offset + 3],
+            ])
+        };
+        let estimate = |offset: usize| Some(u32_at(offset)).filter(|&time| time != u32::MAX);
+
+        let sstat = u16_at(2);
+        Self {
+            state: SanitizeState::from(sstat),
+            progress: u16_at(0),
+            global_data_erased: sstat & (1 << 8) != 0,
+            block_erase_estimate: estimate(12),
+            crypto_erase_estimate: estimate(16),
+        }
+    }
+
+    /// Progress of the operation in progress in percent
+    pub fn percent(&self) -> u32 {
+        self.progress as u32 * 100 / 65536
+    }
+}
+
+/// Why a sanitize operation couldn't be started or checked
+#[derive(Debug, Clone, Copy)]
+pub enum SanitizeError {
+    NoController,
+    /// The controller doesn't support this action
+    Unsupported,
+    /// Another sanitize operation is still running
+    InProgress,
+    Nvme(NvmeError),
+}
+
+impl From<NvmeError> for SanitizeError {
+    fn from(error: NvmeError) -> Self {
+        SanitizeError::Nvme(error)
+    }
+}
+
+impl fmt::Display for SanitizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SanitizeError::NoController => write!(f, "no controller"),
+            SanitizeError::Unsupported => write!(f, "not supported by the controller"),
+            SanitizeError::InProgress => write!(f, "a sanitize operation is in progress"),
+            SanitizeError::Nvme(e) => write!(f, "{e:?}"),
+        }
+    }
+}
+
+/// Sanitize operations the controller supports
+pub fn capabilities() -> Result<SanitizeCapabilities, SanitizeError> {
+    NVME_CONTROLLER
+        .lock()
+        .as_ref()
+        .map(|controller| controller.sanitize)
+        .ok_or(SanitizeError::NoController)
+}
+
+/// Read the Sanitize Status log page
+pub fn status() -> Result<SanitizeStatus, SanitizeError> {
+    let buffer = DmaBuffer::new_zeroed(1).map_err(NvmeError::from)?;
+    let cmd = NvmeCommand::get_log_page(
+        log_ids::SANITIZE_STATUS,
+        STATUS_LOG_SIZE / 4,
+        buffer.phys_addr(),
+    );
+    submit_admin_command(cmd)?;
+
+    Ok(SanitizeStatus::parse(
+        &buffer.as_slice()[..STATUS_LOG_SIZE as usize],
+    ))
+}
+
+/// Start erasing every namespace with `action`
+///
+/// Returns once the controller accepted the command; use `wait` to follow
+/// the operation.
+pub fn start(action: SanitizeAction) -> Result<(), SanitizeError> {
+    if !action.supported(capabilities()?) {
+        return Err(SanitizeError::Unsupported);
+    }
+    if status()?.state == SanitizeState::InProgress {
+        return Err(SanitizeError::InProgress);
+    }
+
+    submit_admin_command(NvmeCommand::sanitize(action.sanact(), false))?;
+    cache::discard();
+    info!("NVMe sanitize ({:?}) started", action);
+    Ok(())
+}
+
+/// Poll the status once a second until no sanitize operation is in
+/// progress, passing every status read to `progress`
+pub fn wait(mut progress: impl FnMut(&SanitizeStatus)) -> Result<SanitizeStatus, SanitizeError> {
+    loop {
+        let status = status()?;
+        if status.state != SanitizeState::InProgress {
+            return Ok(status);
+        }
+        progress(&status);
+        wait::sleep_ms(1000);
+    }
+}
@@ -0,0 +1,50 @@
+//! Configuration space save and restore across sleep states
+//!
+//! Firmware resets PCIe functions on the way out of S3, so BARs, the command
+//! register and interrupt routing have to be written back before drivers
+//! touch their devices again.
+
+use alloc::vec::Vec;
+
+use super::{PCI_DEVICES, device::PciDevice};
+
+/// Dwords of the standard configuration header
+const HEADER_DWORDS: u16 = 16;
+
+/// Saved configuration header of one device
+pub struct SavedConfig {
+    device: PciDevice,
+    header: [u32; HEADER_DWORDS as usize],
+}
+
+/// Save the configuration header of every discovered device
+pub fn save_config() -> Vec<SavedConfig> {
+    let Some(devices) = PCI_DEVICES.read() else {
+        return Vec::new();
+    };
+
+    devices
+        .iter()
+        .map(|device| SavedConfig {
+            device: device.clone(),
+            header: core::array::from_fn(|dword| device.read_config_u32(dword as u16 * 4)),
+        })
+        .collect()
+}
+
+/// Write saved configuration headers back
+///
+/// Dwords are written from the end of the header so the command register,
+/// which enables decoding, is restored after the BARs. Only dwords that
+/// changed are written, and the read-only IDs are skipped.
+pub fn restore_config(saved: &[SavedConfig]) {
+    for config in saved {
+        for dword in (1..HEADER_DWORDS).rev() {
+            let offset = dword * 4;
+            let value = config.header[dword as usize];
+            if config.device.read_config_u32(offset) != value {
+                config.device.write_config_u32(offset, value);
+            }
+        }
+    }
+}
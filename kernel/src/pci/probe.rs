@@ -0,0 +1,243 @@
+//! Parallel PCIe driver probing
+//!
+//! Every device claimed by a driver gets its own kernel task, so a slow probe
+//! (a controller reset, an NVMe spinning up) doesn't hold up the others and a
+//! failed probe only takes out its own device. Progress is recorded per device
+//! and logged as each probe changes state.
+
+use alloc::{collections::vec_deque::VecDeque, format, string::String, vec::Vec};
+use x86_64::instructions::interrupts;
+
+use super::{PCI_DEVICES, device::PciDevice};
+use crate::{
+    info,
+    sync::Mutex,
+    tasks::scheduler::{exit_task, kcreate_task},
+    warn,
+};
+
+/// A driver that can bind to PCIe devices
+pub struct PciDriver {
+    /// Driver name, used in logs
+    pub name: &'static str,
+    /// Returns the devices this driver wants to probe
+    pub find: fn() -> Vec<PciDevice>,
+    /// Initializes one device, returning a description of the failure if any
+    pub probe: fn(PciDevice) -> Result<(), String>,
+    /// Quiesces a bound device before a sleep state
+    pub suspend: fn(&PciDevice) -> Result<(), String>,
+    /// Brings a suspended device back
+    pub resume: fn(PciDevice) -> Result<(), String>,
+    /// Checks a bound device for errors it can't recover from by itself,
+    /// describing them if any
+    pub health: fn(&PciDevice) -> Result<(), String>,
+    /// Brings a device back after its health check failed
+    pub reset: fn(PciDevice) -> Result<(), String>,
+}
+
+/// All drivers enabled at compile time
+static DRIVERS: &[PciDriver] = &[
+    #[cfg(feature = "nvme")]
+    PciDriver {
+        name: "nvme",
+        find: super::nvme::probe_candidates,
+        probe: super::nvme::probe,
+        suspend: super::nvme::suspend,
+        resume: super::nvme::probe,
+        health: super::nvme::health,
+        reset: super::nvme::reset,
+    },
+    #[cfg(feature = "usb")]
+    PciDriver {
+        name: "xhci",
+        find: super::usb::probe_candidates,
+        probe: super::usb::probe,
+        suspend: super::usb::suspend,
+        resume: super::usb::probe,
+        health: super::usb::health,
+        reset: super::usb::reset,
+    },
+];
+
+/// State of a device probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeState {
+    /// Waiting for its probe task to run
+    Queued,
+    /// Driver is initializing the device
+    Probing,
+    /// Driver bound successfully
+    Bound,
+    /// Driver failed to initialize the device
+    Failed(String),
+}
+
+/// Probe progress of one device
+#[derive(Debug, Clone)]
+pub struct ProbeRecord {
+    /// Bus, device and function of the device
+    pub location: (u8, u8, u8),
+    /// Name of the driver probing the device
+    pub driver: &'static str,
+    pub state: ProbeState,
+}
+
+/// Probe progress of every device claimed by a driver
+pub static PROBES: Mutex<Vec<ProbeRecord>> = Mutex::new("PCI_PROBES", Vec::new());
+
+/// Probes waiting for a task to pick them up: (record index, driver, device)
+static PROBE_QUEUE: Mutex<VecDeque<(usize, &'static PciDriver, PciDevice)>> =
+    Mutex::new("PCI_PROBE_QUEUE", VecDeque::new());
+
+/// Queue every device claimed by a driver and spawn one probe task per device.
+///
+/// Must be called after `init_pci`. Returns immediately; use `PROBES` or
+/// `probes_finished` to follow progress.
+pub fn spawn_probes() {
+    for driver in DRIVERS {
+        for device in (driver.find)() {
+            let index = {
+                let mut probes = PROBES.lock();
+                probes.push(ProbeRecord {
+                    location: (device.bus, device.device, device.function),
+                    driver: driver.name,
+                    state: ProbeState::Queued,
+                });
+                probes.len() - 1
+            };
+            PROBE_QUEUE.lock().push_back((index, driver, device));
+
+            interrupts::without_interrupts(|| kcreate_task(probe_task, "pci probe"));
+        }
+    }
+
+    info!("Spawned {} PCIe probe task(s)", PROBES.lock().len());
+}
+
+/// Returns true once every queued probe has bound or failed
+pub fn probes_finished() -> bool {
+    PROBES
+        .lock()
+        .iter()
+        .all(|probe| matches!(probe.state, ProbeState::Bound | ProbeState::Failed(_)))
+}
+
+#[allow(unused_variables)]
+pub(super) fn set_state(index: usize, state: ProbeState) {
+    let mut probes = PROBES.lock();
+    probes[index].state = state;
+
+    let done = probes
+        .iter()
+        .filter(|probe| matches!(probe.state, ProbeState::Bound | ProbeState::Failed(_)))
+        .count();
+    let probe = &probes[index];
+    let (bus, device, function) = probe.location;
+    match &probe.state {
+        ProbeState::Failed(reason) => {
+            warn!(
+                "PCIe probe {:02x}:{:02x}.{} ({}): failed: {} [{}/{}]",
+                bus, device, function, probe.driver, reason, done, probes.len()
+            );
+        }
+        state => {
+            info!(
+                "PCIe probe {:02x}:{:02x}.{} ({}): {:?} [{}/{}]",
+                bus, device, function, probe.driver, state, done, probes.len()
+            );
+        }
+    }
+}
+
+/// Takes one queued probe and runs it
+fn probe_task() -> ! {
+    let job = interrupts::without_interrupts(|| PROBE_QUEUE.lock().pop_front());
+
+    if let Some((index, driver, device)) = job {
+        set_state(index, ProbeState::Probing);
+        let state = match (driver.probe)(device) {
+            Ok(()) => ProbeState::Bound,
+            Err(reason) => ProbeState::Failed(reason),
+        };
+        set_state(index, state);
+    }
+
+    exit_task();
+}
+
+/// Returns the driver and current device of a probe record
+pub(super) fn bound_device(probe: &ProbeRecord) -> Option<(&'static PciDriver, PciDevice)> {
+    let driver = DRIVERS.iter().find(|driver| driver.name == probe.driver)?;
+    let device = PCI_DEVICES
+        .read()?
+        .iter()
+        .find(|device| (device.bus, device.device, device.function) == probe.location)?
+        .clone();
+    Some((driver, device))
+}
+
+/// Indices of the records whose driver is bound
+pub(super) fn bound_probes() -> Vec<usize> {
+    PROBES
+        .lock()
+        .iter()
+        .enumerate()
+        .filter(|(_, probe)| probe.state == ProbeState::Bound)
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Brings one suspended device back, marking it failed if it doesn't
+fn resume_device(index: usize) {
+    let probe = PROBES.lock()[index].clone();
+    let result = bound_device(&probe)
+        .ok_or_else(|| String::from("device disappeared"))
+        .and_then(|(driver, device)| (driver.resume)(device));
+    match result {
+        #[allow(unused_variables)]
+        Ok(()) => {
+            let (bus, device, function) = probe.location;
+            info!("PCIe {:02x}:{:02x}.{} ({}): resumed", bus, device, function, probe.driver);
+        }
+        Err(reason) => set_state(index, ProbeState::Failed(format!("resume: {reason}"))),
+    }
+}
+
+/// Quiesce every bound device before a sleep state, in reverse probe order
+///
+/// If a driver refuses, the devices already suspended are resumed and its
+/// reason is returned.
+pub fn suspend_drivers() -> Result<(), String> {
+    let mut suspended = Vec::new();
+
+    for index in bound_probes().into_iter().rev() {
+        let probe = PROBES.lock()[index].clone();
+        let (bus, device, function) = probe.location;
+        let result = bound_device(&probe)
+            .ok_or_else(|| String::from("device disappeared"))
+            .and_then(|(driver, device)| (driver.suspend)(&device));
+
+        if let Err(reason) = result {
+            for &index in suspended.iter().rev() {
+                resume_device(index);
+            }
+            return Err(format!(
+                "{:02x}:{:02x}.{} ({}): {}",
+                bus, device, function, probe.driver, reason
+            ));
+        }
+        info!("PCIe {:02x}:{:02x}.{} ({}): suspended", bus, device, function, probe.driver);
+        suspended.push(index);
+    }
+
+    Ok(())
+}
+
+/// Bring every bound device back after a sleep state, in probe order
+///
+/// Devices that fail to come back are marked as failed.
+pub fn resume_drivers() {
+    for index in bound_probes() {
+        resume_device(index);
+    }
+}
@@ -318,3 +318,86 @@ fn test_vmm_error_conditions() {
         _ => panic!("Expected InvalidDevice error for zero size"),
     }
 }
+
+#[cfg(any(feature = "usb", feature = "nvme"))]
+#[test_case]
+fn test_dma_buffers_returned() {
+    use crate::testing::snapshot::check_leaks;
+
+    use super::dma::{DMA_MANAGER, get_zeroed_dma};
+
+    check_leaks(|| {
+        let buffer = get_zeroed_dma(4).unwrap();
+        assert_eq!(buffer.size, 4);
+        drop(buffer);
+
+        let pooled = DMA_MANAGER.lock().get_pool_4kb().unwrap();
+        DMA_MANAGER.lock().free_buffer_4kb(pooled);
+    });
+}
+
+#[cfg(feature = "nvme")]
+#[test_case]
+fn test_nvme_queues_share_vectors() {
+    use super::nvme::controller::queue_vector;
+
+    // a vector per queue, the admin queue keeps entry 0
+    assert_eq!(
+        (1..=4).map(|queue| queue_vector(queue, 5, 4)).collect::<alloc::vec::Vec<_>>(),
+        [1, 2, 3, 4]
+    );
+    // fewer vectors than queues, every entry is used
+    assert_eq!(
+        (1..=4).map(|queue| queue_vector(queue, 2, 4)).collect::<alloc::vec::Vec<_>>(),
+        [0, 1, 0, 1]
+    );
+    // one vector for everything
+    assert_eq!(queue_vector(3, 1, 4), 0);
+}
+
+#[cfg(feature = "usb")]
+#[test_case]
+fn test_command_ring_grows_when_full() {
+    use alloc::vec::Vec;
+
+    use super::usb::{
+        init_helpers::{MAX_TRBS_PER_SEGMENT, Trb},
+        ring::CommandRing,
+        xhci::UsbError,
+    };
+
+    let mut ring = CommandRing::new().unwrap();
+    // nothing completes, so a segment is added each time the enqueue index
+    // would move into the one the controller is still in
+    let addrs: Vec<u64> = (0..MAX_TRBS_PER_SEGMENT * 2)
+        .map(|_| ring.push(Trb::no_op_command(false)).unwrap())
+        .collect();
+    assert_eq!(ring.segments(), 3);
+
+    // once the controller caught up, the segments are reused
+    ring.completed(*addrs.last().unwrap());
+    for _ in 0..MAX_TRBS_PER_SEGMENT * 2 {
+        ring.push(Trb::no_op_command(false)).unwrap();
+    }
+    assert_eq!(ring.segments(), 3);
+
+    // a ring that can't grow any further refuses commands
+    let result = loop {
+        match ring.push(Trb::no_op_command(false)) {
+            Ok(_) => continue,
+            result => break result,
+        }
+    };
+    assert_eq!(result, Err(UsbError::RingFull));
+}
+
+#[cfg(feature = "usb")]
+#[test_case]
+fn test_event_ring_segments() {
+    use super::usb::ring::EventRing;
+
+    let mut ring = EventRing::new(4).unwrap();
+    assert_eq!(ring.segments(), 4);
+    // the controller wrote nothing yet
+    assert!(ring.pop().is_none());
+}
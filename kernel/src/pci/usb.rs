@@ -1,8 +1,47 @@
-pub mod xhci;
+pub mod cdc_acm;
+pub mod context;
+pub mod descriptors;
+pub mod device;
 pub mod init_helpers;
+pub mod isoch;
+pub mod pm;
+pub mod ring;
+pub mod xhci;
 pub mod xhci_registers;
 
+use alloc::{string::String, vec::Vec};
+
+use super::device::PciDevice;
+use crate::bootargs;
+
+/// xHCI controllers to probe
+///
+/// None unless the `usb` boot argument is given, so booting without it
+/// leaves the controller alone. Only one controller is driven at a time, so
+/// only the first is returned.
+pub fn probe_candidates() -> Vec<PciDevice> {
+    if !bootargs::has_flag("usb") {
+        return Vec::new();
+    }
+    xhci::find_xhci_devices().into_iter().take(1).collect()
+}
+
+/// see xhci
+pub fn probe(device: PciDevice) -> Result<(), String> {
+    xhci::xhci_init(device)
+}
+
+/// see xhci
+pub fn suspend(_device: &PciDevice) -> Result<(), String> {
+    xhci::xhci_halt()
+}
+
+/// see xhci
+pub fn health(_device: &PciDevice) -> Result<(), String> {
+    xhci::health()
+}
+
 /// see xhci
-pub fn init() {
-    xhci::xhci_init();
+pub fn reset(device: PciDevice) -> Result<(), String> {
+    xhci::reset(device)
 }
@@ -0,0 +1,371 @@
+//! USB CDC-ACM serial adapters
+//!
+//! An ACM device has a communications interface taking line coding and
+//! control line requests, and a data interface with a bulk IN and a bulk OUT
+//! endpoint carrying the bytes. Each one is registered as a `ttyACM<n>`
+//! serial device.
+//!
+//! Bytes are buffered both ways and moved by one kernel task per device,
+//! which keeps a bulk IN transfer in flight and sends whatever was queued for
+//! writing. Writers wake the task through `XHCI_VECTOR`, which it sleeps on
+//! between transfer completions anyway. The task exits once the controller
+//! goes away; the device is registered again under the same name when the
+//! controller comes back.
+
+use alloc::{collections::vec_deque::VecDeque, format, string::String, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use kernel_api::tty::{LineCoding, Parity, SerialDevice, StopBits, TtyError};
+use x86_64::instructions::interrupts;
+
+use super::{
+    descriptors::{EndpointDescriptor, SetupPacket, TransferType, class_codes, request_types},
+    device::UsbDevice,
+    xhci::{Transfer, UsbError, XHCI_VECTOR},
+};
+use crate::{
+    info,
+    pci::dma::{DynamicDmaBuffer, get_zeroed_dma},
+    sync::Mutex,
+    tasks::scheduler::{exit_task, kcreate_task, kyield_task, wake_tasks},
+    warn,
+};
+
+/// Abstract Control Model subclass of the communications class
+const ACM_SUBCLASS: u8 = 0x02;
+
+/// Class requests of the communications interface
+mod acm_requests {
+    pub const SET_LINE_CODING: u8 = 0x20;
+    pub const SET_CONTROL_LINE_STATE: u8 = 0x22;
+}
+
+/// DTR and RTS in SET_CONTROL_LINE_STATE
+const CONTROL_LINES_ACTIVE: u16 = 0x3;
+
+/// Bytes buffered each way before writes are cut short or received bytes
+/// are dropped
+const BUFFER_SIZE: usize = 4096;
+
+/// Devices waiting for their task to start
+static PENDING: Mutex<VecDeque<Arc<AcmDevice>>> = Mutex::new("ACM_PENDING", VecDeque::new());
+
+/// Controller generation and number of devices registered in it, so devices
+/// get the same names each time the controller is probed
+static REGISTERED: Mutex<(u64, usize)> = Mutex::new("ACM_REGISTERED", (0, 0));
+
+/// A bound ACM device
+struct AcmDevice {
+    name: String,
+    usb: UsbDevice,
+    /// Number of the communications interface, which class requests go to
+    interface: u8,
+    bulk_in: EndpointDescriptor,
+    bulk_out: EndpointDescriptor,
+    coding: Mutex<LineCoding>,
+    rx: Mutex<VecDeque<u8>>,
+    tx: Mutex<VecDeque<u8>>,
+    disconnected: AtomicBool,
+}
+
+impl AcmDevice {
+    fn class_request(&self, request: u8, value: u16, data: &[u8]) -> Result<(), UsbError> {
+        self.usb.control_out(
+            SetupPacket {
+                request_type: request_types::CLASS | request_types::TO_INTERFACE,
+                request,
+                value,
+                index: self.interface as u16,
+                length: 0,
+            },
+            data,
+        )
+    }
+
+    fn send_line_coding(&self, coding: LineCoding) -> Result<(), UsbError> {
+        let stop_bits = match coding.stop_bits {
+            StopBits::One => 0,
+            StopBits::OneAndHalf => 1,
+            StopBits::Two => 2,
+        };
+        let parity = match coding.parity {
+            Parity::None => 0,
+            Parity::Odd => 1,
+            Parity::Even => 2,
+            Parity::Mark => 3,
+            Parity::Space => 4,
+        };
+        let baud = coding.baud.to_le_bytes();
+        self.class_request(
+            acm_requests::SET_LINE_CODING,
+            0,
+            &[
+                baud[0],
+                baud[1],
+                baud[2],
+                baud[3],
+                stop_bits,
+                parity,
+                coding.data_bits,
+            ],
+        )
+    }
+}
+
+impl SerialDevice for AcmDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn line_coding(&self) -> LineCoding {
+        interrupts::without_interrupts(|| *self.coding.lock())
+    }
+
+    /// Sleeps until the device accepted the coding, so this must be called
+    /// from a task with interrupts enabled
+    fn set_line_coding(&self, coding: LineCoding) -> Result<(), TtyError> {
+        if self.disconnected.load(Ordering::Relaxed) {
+            return Err(TtyError::Disconnected);
+        }
+        if coding.baud == 0 || !matches!(coding.data_bits, 5..=8 | 16) {
+            return Err(TtyError::Unsupported);
+        }
+        match self.send_line_coding(coding) {
+            Ok(()) => {
+                interrupts::without_interrupts(|| *self.coding.lock() = coding);
+                Ok(())
+            }
+            Err(UsbError::Stalled) => Err(TtyError::Unsupported),
+            Err(UsbError::Gone | UsbError::NotInitialized) => Err(TtyError::Disconnected),
+            Err(_) => Err(TtyError::Io),
+        }
+    }
+
+    fn write(&self, bytes: &[u8]) -> Result<usize, TtyError> {
+        if self.disconnected.load(Ordering::Relaxed) {
+            return Err(TtyError::Disconnected);
+        }
+        let written = interrupts::without_interrupts(|| {
+            let mut tx = self.tx.lock();
+            let written = bytes.len().min(BUFFER_SIZE - tx.len());
+            tx.extend(&bytes[..written]);
+            written
+        });
+        if written > 0 {
+            interrupts::without_interrupts(|| wake_tasks(XHCI_VECTOR));
+        }
+        Ok(written)
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, TtyError> {
+        let read = interrupts::without_interrupts(|| {
+            let mut rx = self.rx.lock();
+            let read = buffer.len().min(rx.len());
+            for (byte, received) in buffer.iter_mut().zip(rx.drain(..read)) {
+                *byte = received;
+            }
+            read
+        });
+        if read == 0 && self.disconnected.load(Ordering::Relaxed) {
+            return Err(TtyError::Disconnected);
+        }
+        Ok(read)
+    }
+}
+
+/// The first bulk endpoint going the given way
+fn bulk_endpoint(endpoints: &[EndpointDescriptor], is_in: bool) -> Option<EndpointDescriptor> {
+    endpoints
+        .iter()
+        .find(|endpoint| {
+            endpoint.transfer_type() == TransferType::Bulk && endpoint.is_in() == is_in
+        })
+        .copied()
+}
+
+/// Claim `device` if it has an ACM communications interface and a data
+/// interface to go with it
+#[allow(unused_variables)]
+pub fn probe(device: &UsbDevice) -> bool {
+    let interfaces = &device.configuration.interfaces;
+    let Some(comm) = interfaces.iter().find(|interface| {
+        interface.class == class_codes::COMMUNICATIONS && interface.subclass == ACM_SUBCLASS
+    }) else {
+        return false;
+    };
+    let Some((bulk_in, bulk_out)) = interfaces
+        .iter()
+        .filter(|interface| interface.class == class_codes::CDC_DATA)
+        .find_map(|interface| {
+            Some((
+                bulk_endpoint(&interface.endpoints, true)?,
+                bulk_endpoint(&interface.endpoints, false)?,
+            ))
+        })
+    else {
+        return false;
+    };
+
+    match bind(device, comm.number, bulk_in, bulk_out) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("cdc-acm: slot {}: {:?}", device.slot_id, e);
+            false
+        }
+    }
+}
+
+fn bind(
+    device: &UsbDevice,
+    interface: u8,
+    bulk_in: EndpointDescriptor,
+    bulk_out: EndpointDescriptor,
+) -> Result<(), UsbError> {
+    device.configure(&[bulk_in, bulk_out])?;
+
+    let index = interrupts::without_interrupts(|| {
+        let mut registered = REGISTERED.lock();
+        if registered.0 != device.generation {
+            *registered = (device.generation, 0);
+        }
+        registered.1 += 1;
+        registered.1 - 1
+    });
+    let acm = Arc::new(AcmDevice {
+        name: format!("ttyACM{index}"),
+        usb: device.clone(),
+        interface,
+        bulk_in,
+        bulk_out,
+        coding: Mutex::new("ACM_CODING", LineCoding::DEFAULT),
+        rx: Mutex::new("ACM_RX", VecDeque::new()),
+        tx: Mutex::new("ACM_TX", VecDeque::new()),
+        disconnected: AtomicBool::new(false),
+    });
+    acm.send_line_coding(LineCoding::DEFAULT)?;
+    acm.class_request(
+        acm_requests::SET_CONTROL_LINE_STATE,
+        CONTROL_LINES_ACTIVE,
+        &[],
+    )?;
+
+    interrupts::without_interrupts(|| {
+        PENDING.lock().push_back(acm.clone());
+        kcreate_task(acm_task, "cdc-acm");
+    });
+    // a device of the previous generation under the same name is replaced
+    let _ = kernel_api::tty::register(acm);
+    Ok(())
+}
+
+/// A transfer with the DMA page it moves bytes through
+struct InFlight {
+    transfer: Transfer,
+    buffer: DynamicDmaBuffer,
+}
+
+#[allow(unused_variables)]
+fn acm_task() -> ! {
+    let acm = interrupts::without_interrupts(|| PENDING.lock().pop_front())
+        .expect("cdc-acm task started without a device");
+
+    let result = run(&acm);
+    interrupts::enable();
+    if let Err(e) = result {
+        info!("{}: stopped: {:?}", acm.name, e);
+    }
+    acm.disconnected.store(true, Ordering::Relaxed);
+    exit_task();
+}
+
+/// Move bytes until the device fails
+fn run(acm: &AcmDevice) -> Result<(), UsbError> {
+    let mut receiving = receive(acm, get_zeroed_dma(1)?)?;
+    let mut sending: Option<InFlight> = None;
+    let mut spare = Some(get_zeroed_dma(1)?);
+
+    loop {
+        // interrupts stay off until kyield_task has marked us as waiting, so
+        // neither a completion nor a write can slip in unnoticed
+        interrupts::disable();
+        let mut progress = false;
+
+        match receiving.transfer.poll() {
+            Ok(Some(received)) => {
+                let bytes = unsafe {
+                    core::slice::from_raw_parts(receiving.buffer.virt_addr.as_ptr::<u8>(), received)
+                };
+                let mut rx = acm.rx.lock();
+                let room = BUFFER_SIZE - rx.len();
+                rx.extend(&bytes[..received.min(room)]);
+                drop(rx);
+                receiving = receive(acm, receiving.buffer)?;
+                progress = true;
+            }
+            Ok(None) => {}
+            Err(UsbError::Stalled) => {
+                acm.usb.reset_endpoint(&acm.bulk_in)?;
+                receiving = receive(acm, receiving.buffer)?;
+                progress = true;
+            }
+            Err(e) => {
+                interrupts::enable();
+                return Err(e);
+            }
+        }
+
+        if let Some(in_flight) = &sending {
+            match in_flight.transfer.poll() {
+                Ok(Some(_)) => {
+                    spare = sending.take().map(|in_flight| in_flight.buffer);
+                    progress = true;
+                }
+                Ok(None) => {}
+                Err(UsbError::Stalled) => {
+                    // the bytes are lost, the line carries on
+                    acm.usb.reset_endpoint(&acm.bulk_out)?;
+                    spare = sending.take().map(|in_flight| in_flight.buffer);
+                    progress = true;
+                }
+                Err(e) => {
+                    interrupts::enable();
+                    return Err(e);
+                }
+            }
+        }
+
+        if sending.is_none()
+            && let Some(buffer) = spare.take()
+        {
+            // the lock is dropped before submitting: anything the submission
+            // logs is written back to this device
+            let length = {
+                let mut tx = acm.tx.lock();
+                let length = tx.len().min(4096);
+                for (index, byte) in tx.drain(..length).enumerate() {
+                    unsafe { buffer.virt_addr.as_mut_ptr::<u8>().add(index).write(byte) };
+                }
+                length
+            };
+            if length > 0 {
+                let transfer = acm.usb.submit(&acm.bulk_out, &buffer, length)?;
+                sending = Some(InFlight { transfer, buffer });
+                progress = true;
+            } else {
+                spare = Some(buffer);
+            }
+        }
+
+        if progress {
+            interrupts::enable();
+        } else {
+            kyield_task(XHCI_VECTOR);
+        }
+    }
+}
+
+/// Start a bulk IN transfer filling `buffer`
+fn receive(acm: &AcmDevice, buffer: DynamicDmaBuffer) -> Result<InFlight, UsbError> {
+    let transfer = acm.usb.submit(&acm.bulk_in, &buffer, 4096)?;
+    Ok(InFlight { transfer, buffer })
+}
@@ -0,0 +1,206 @@
+//! Device and input contexts
+//!
+//! The controller keeps the state of a device slot in its device context: a
+//! slot context followed by one endpoint context per Device Context Index
+//! (DCI). Software never writes it directly but describes the wanted state in
+//! an input context, which has an input control context in front saying which
+//! contexts to add or drop, and passes it to a command.
+//!
+//! Contexts are 32 or 64 bytes depending on the controller (HCCPARAMS1.CSZ);
+//! only the first 32 bytes are used either way.
+
+use core::ptr::write_bytes;
+
+use super::descriptors::{EndpointDescriptor, TransferType};
+use crate::pci::dma::{DmaError, DynamicDmaBuffer, get_zeroed_dma};
+
+/// Dwords of a context that are used
+const CONTEXT_DWORDS: usize = 8;
+
+/// Endpoint types of an endpoint context
+pub mod endpoint_types {
+    pub const ISOCH_OUT: u32 = 1;
+    pub const BULK_OUT: u32 = 2;
+    pub const INTERRUPT_OUT: u32 = 3;
+    pub const CONTROL: u32 = 4;
+    pub const ISOCH_IN: u32 = 5;
+    pub const BULK_IN: u32 = 6;
+    pub const INTERRUPT_IN: u32 = 7;
+}
+
+/// Device Context Index of an endpoint address: the default control
+/// endpoint is 1, then OUT and IN endpoints alternate
+pub fn endpoint_dci(address: u8) -> u8 {
+    let number = address & 0x0F;
+    if number == 0 {
+        1
+    } else {
+        number * 2 + (address >> 7)
+    }
+}
+
+/// Default max packet size of the control endpoint for a port speed, until
+/// the device descriptor says otherwise
+pub fn default_max_packet_size(speed: u8) -> u16 {
+    match speed {
+        // low speed, full speed
+        1 | 2 => 8,
+        // high speed
+        3 => 64,
+        // SuperSpeed and faster
+        _ => 512,
+    }
+}
+
+/// An input context, passed to Address Device, Configure Endpoint and
+/// Evaluate Context commands
+#[derive(Debug)]
+pub struct InputContext {
+    buffer: DynamicDmaBuffer,
+    context_size: usize,
+}
+
+impl InputContext {
+    pub(crate) fn new(context_size: usize) -> Result<Self, DmaError> {
+        Ok(Self {
+            buffer: get_zeroed_dma(1)?,
+            context_size,
+        })
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.buffer.phys_addr.as_u64()
+    }
+
+    /// Forget what the previous command was given
+    pub fn clear(&mut self) {
+        unsafe { write_bytes(self.buffer.virt_addr.as_mut_ptr::<u8>(), 0, 4096) };
+    }
+
+    fn context(&mut self, index: usize) -> &mut [u32] {
+        unsafe {
+            let start = self
+                .buffer
+                .virt_addr
+                .as_mut_ptr::<u8>()
+                .add(index * self.context_size);
+            core::slice::from_raw_parts_mut(start.cast::<u32>(), CONTEXT_DWORDS)
+        }
+    }
+
+    /// Mark the slot context (`dci` 0) or an endpoint context as added
+    pub fn add(&mut self, dci: u8) {
+        self.context(0)[1] |= 1 << dci;
+    }
+
+    /// Describe the slot of a device on a root hub port
+    ///
+    /// `last_dci` is the highest DCI with a valid endpoint context.
+    pub fn set_slot(&mut self, speed: u8, root_port: u8, last_dci: u8) {
+        let slot = self.context(1);
+        slot[0] = (speed as u32 & 0xF) << 20 | (last_dci as u32 & 0x1F) << 27;
+        slot[1] = (root_port as u32) << 16;
+        // interrupter 0
+        slot[2] = 0;
+    }
+
+    /// Describe the default control endpoint
+    pub fn set_control_endpoint(&mut self, max_packet_size: u16, dequeue: u64, cycle: bool) {
+        let endpoint = self.context(2);
+        endpoint[0] = 0;
+        endpoint[1] = 3 << 1 | endpoint_types::CONTROL << 3 | (max_packet_size as u32) << 16;
+        endpoint[2] = dequeue as u32 & !0xF | cycle as u32;
+        endpoint[3] = (dequeue >> 32) as u32;
+        // average TRB length, as recommended for control endpoints
+        endpoint[4] = 8;
+    }
+
+    /// Describe an endpoint from its descriptor
+    ///
+    /// `speed` is the port speed, which decides how the descriptor's interval
+    /// is encoded.
+    pub fn set_endpoint(
+        &mut self,
+        descriptor: &EndpointDescriptor,
+        speed: u8,
+        dequeue: u64,
+        cycle: bool,
+    ) {
+        let transfer_type = descriptor.transfer_type();
+        let endpoint_type = match (transfer_type, descriptor.is_in()) {
+            (TransferType::Control, _) => endpoint_types::CONTROL,
+            (TransferType::Isochronous, false) => endpoint_types::ISOCH_OUT,
+            (TransferType::Isochronous, true) => endpoint_types::ISOCH_IN,
+            (TransferType::Bulk, false) => endpoint_types::BULK_OUT,
+            (TransferType::Bulk, true) => endpoint_types::BULK_IN,
+            (TransferType::Interrupt, false) => endpoint_types::INTERRUPT_OUT,
+            (TransferType::Interrupt, true) => endpoint_types::INTERRUPT_IN,
+        };
+        let max_packet_size = descriptor.packet_size() as u32;
+        let max_burst = descriptor.max_burst() as u32;
+        let mult = descriptor.mult() as u32;
+        let periodic = matches!(
+            transfer_type,
+            TransferType::Isochronous | TransferType::Interrupt
+        );
+        let interval = if periodic {
+            periodic_interval(descriptor, speed)
+        } else {
+            0
+        };
+        let error_count = if transfer_type == TransferType::Isochronous {
+            0
+        } else {
+            3
+        };
+        let average_trb_length = match transfer_type {
+            TransferType::Interrupt => 1024,
+            _ => 3072,
+        };
+        let max_esit_payload = if periodic {
+            descriptor.max_esit_payload()
+        } else {
+            0
+        };
+
+        let endpoint = self.context(1 + endpoint_dci(descriptor.address) as usize);
+        endpoint[0] = mult << 8 | interval << 16 | (max_esit_payload >> 16) << 24;
+        endpoint[1] =
+            error_count << 1 | endpoint_type << 3 | max_burst << 8 | max_packet_size << 16;
+        endpoint[2] = dequeue as u32 & !0xF | cycle as u32;
+        endpoint[3] = (dequeue >> 32) as u32;
+        endpoint[4] = average_trb_length | (max_esit_payload & 0xFFFF) << 16;
+    }
+}
+
+/// Interval of a periodic endpoint as the exponent of 125 µs units the
+/// endpoint context wants
+pub fn periodic_interval(descriptor: &EndpointDescriptor, speed: u8) -> u32 {
+    let interval = descriptor.interval.max(1) as u32;
+    match (speed, descriptor.transfer_type()) {
+        // full- and low-speed interrupt endpoints give frames (1 ms = 8 units)
+        (1 | 2, TransferType::Interrupt) => (interval * 8).ilog2().clamp(3, 10),
+        // full-speed isochronous endpoints give 2^(interval-1) frames
+        (1 | 2, _) => (interval + 2).clamp(3, 18),
+        // high speed and faster give 2^(interval-1) microframes
+        _ => (interval - 1).min(15),
+    }
+}
+
+/// The device context the controller keeps a slot's state in
+#[derive(Debug)]
+pub struct DeviceContext {
+    buffer: DynamicDmaBuffer,
+}
+
+impl DeviceContext {
+    pub(crate) fn new() -> Result<Self, DmaError> {
+        Ok(Self {
+            buffer: get_zeroed_dma(1)?,
+        })
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.buffer.phys_addr.as_u64()
+    }
+}
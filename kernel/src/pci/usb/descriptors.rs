@@ -0,0 +1,365 @@
+//! USB requests and descriptors
+//!
+//! Control transfers start with an 8-byte setup packet naming the request.
+//! Devices describe themselves with descriptors: a device descriptor, and
+//! configuration descriptors that are followed by their interface, endpoint
+//! and class-specific descriptors in one block.
+
+use alloc::vec::Vec;
+
+/// bmRequestType values
+pub mod request_types {
+    /// Data flows from the device to the host
+    pub const DEVICE_TO_HOST: u8 = 0x80;
+    pub const STANDARD: u8 = 0x00;
+    pub const CLASS: u8 = 0x20;
+    pub const VENDOR: u8 = 0x40;
+    pub const TO_DEVICE: u8 = 0x00;
+    pub const TO_INTERFACE: u8 = 0x01;
+    pub const TO_ENDPOINT: u8 = 0x02;
+}
+
+/// Standard bRequest values
+pub mod requests {
+    pub const GET_STATUS: u8 = 0;
+    pub const CLEAR_FEATURE: u8 = 1;
+    pub const SET_FEATURE: u8 = 3;
+    pub const GET_DESCRIPTOR: u8 = 6;
+    pub const GET_CONFIGURATION: u8 = 8;
+    pub const SET_CONFIGURATION: u8 = 9;
+    pub const SET_INTERFACE: u8 = 11;
+}
+
+/// Feature selectors of SET_FEATURE and CLEAR_FEATURE
+pub mod features {
+    /// To the interface, USB 3 only
+    pub const FUNCTION_SUSPEND: u16 = 0;
+    /// To the device, USB 2 only
+    pub const DEVICE_REMOTE_WAKEUP: u16 = 1;
+}
+
+/// bDescriptorType values
+pub mod descriptor_types {
+    pub const DEVICE: u8 = 1;
+    pub const CONFIGURATION: u8 = 2;
+    pub const STRING: u8 = 3;
+    pub const INTERFACE: u8 = 4;
+    pub const ENDPOINT: u8 = 5;
+    pub const INTERFACE_ASSOCIATION: u8 = 11;
+    pub const SS_ENDPOINT_COMPANION: u8 = 48;
+    /// Class-specific interface descriptor
+    pub const CS_INTERFACE: u8 = 0x24;
+}
+
+/// bInterfaceClass values
+pub mod class_codes {
+    pub const COMMUNICATIONS: u8 = 0x02;
+    pub const HID: u8 = 0x03;
+    pub const MASS_STORAGE: u8 = 0x08;
+    pub const HUB: u8 = 0x09;
+    pub const CDC_DATA: u8 = 0x0A;
+}
+
+/// The setup stage of a control transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    /// Bytes in the data stage
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// GET_DESCRIPTOR for the descriptor `kind` number `index`
+    pub fn get_descriptor(kind: u8, index: u8, length: u16) -> Self {
+        Self {
+            request_type: request_types::DEVICE_TO_HOST,
+            request: requests::GET_DESCRIPTOR,
+            value: (kind as u16) << 8 | index as u16,
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn set_configuration(value: u8) -> Self {
+        Self {
+            request_type: request_types::TO_DEVICE,
+            request: requests::SET_CONFIGURATION,
+            value: value as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// SET_FEATURE to the device (`TO_DEVICE`) or an interface or endpoint,
+    /// numbered by `index`
+    pub fn set_feature(recipient: u8, feature: u16, index: u16) -> Self {
+        Self {
+            request_type: request_types::STANDARD | recipient,
+            request: requests::SET_FEATURE,
+            value: feature,
+            index,
+            length: 0,
+        }
+    }
+
+    /// Whether the data stage, if any, reads from the device
+    pub fn is_in(&self) -> bool {
+        self.request_type & request_types::DEVICE_TO_HOST != 0
+    }
+
+    /// The packet as the immediate data of a Setup Stage TRB
+    pub fn to_u64(&self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// The standard device descriptor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    /// BCD, e.g. 0x0200 for USB 2.0
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// Max packet size of the default control endpoint; an exponent of 2 for
+    /// USB 3 devices
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const SIZE: usize = 18;
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE || bytes[1] != descriptor_types::DEVICE {
+            return None;
+        }
+        Some(Self {
+            usb_version: u16_at(bytes, 2),
+            class: bytes[4],
+            subclass: bytes[5],
+            protocol: bytes[6],
+            max_packet_size0: bytes[7],
+            vendor_id: u16_at(bytes, 8),
+            product_id: u16_at(bytes, 10),
+            device_version: u16_at(bytes, 12),
+            num_configurations: bytes[17],
+        })
+    }
+
+    /// Max packet size of the default control endpoint in bytes
+    pub fn control_max_packet_size(&self) -> u16 {
+        if self.usb_version >= 0x0300 {
+            1 << self.max_packet_size0.min(15)
+        } else {
+            self.max_packet_size0 as u16
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferType {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    /// Endpoint number, with bit 7 set for IN endpoints
+    pub address: u8,
+    pub attributes: u8,
+    /// Bits 0-10 are the packet size, bits 11-12 the extra transactions per
+    /// microframe of high-speed periodic endpoints
+    pub max_packet_size: u16,
+    pub interval: u8,
+    /// Only given by SuperSpeed devices
+    pub companion: Option<EndpointCompanion>,
+}
+
+/// SuperSpeed Endpoint Companion descriptor, following the endpoint
+/// descriptor it extends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointCompanion {
+    /// Packets per burst, minus one
+    pub max_burst: u8,
+    /// Bits 0-1 of isochronous endpoints are the bursts per service
+    /// interval, minus one
+    pub attributes: u8,
+    /// Bytes moved per service interval of a periodic endpoint
+    pub bytes_per_interval: u16,
+}
+
+impl EndpointDescriptor {
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+
+    pub fn transfer_type(&self) -> TransferType {
+        match self.attributes & 0x3 {
+            0 => TransferType::Control,
+            1 => TransferType::Isochronous,
+            2 => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        }
+    }
+
+    /// Bytes per packet
+    pub fn packet_size(&self) -> u16 {
+        self.max_packet_size & 0x7FF
+    }
+
+    /// Packets per burst, minus one
+    pub fn max_burst(&self) -> u8 {
+        match (self.companion, self.transfer_type()) {
+            (Some(companion), _) => companion.max_burst,
+            // high-speed high-bandwidth endpoints burst their extra
+            // transactions within a microframe
+            (None, TransferType::Isochronous | TransferType::Interrupt) => {
+                (self.max_packet_size >> 11 & 0x3) as u8
+            }
+            (None, _) => 0,
+        }
+    }
+
+    /// Bursts per service interval, minus one
+    pub fn mult(&self) -> u8 {
+        match (self.companion, self.transfer_type()) {
+            (Some(companion), TransferType::Isochronous) => companion.attributes & 0x3,
+            _ => 0,
+        }
+    }
+
+    /// Bytes moved per service interval of a periodic endpoint
+    pub fn max_esit_payload(&self) -> u32 {
+        match self.companion {
+            Some(companion) => companion.bytes_per_interval as u32,
+            None => {
+                self.packet_size() as u32 * (self.max_burst() as u32 + 1) * (self.mult() as u32 + 1)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate_setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+    /// Class-specific descriptors following the interface descriptor, each
+    /// with its length and type bytes
+    pub class_descriptors: Vec<Vec<u8>>,
+}
+
+/// A configuration with its interfaces
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigurationDescriptor {
+    /// Value to pass to SET_CONFIGURATION
+    pub value: u8,
+    /// Bit 5 says the device supports remote wakeup
+    pub attributes: u8,
+    /// In units of 2 mA, or 8 mA for USB 3 devices
+    pub max_power: u8,
+    pub interfaces: Vec<InterfaceDescriptor>,
+}
+
+impl ConfigurationDescriptor {
+    /// Size of the configuration descriptor itself
+    pub const HEADER_SIZE: usize = 9;
+
+    pub fn remote_wakeup(&self) -> bool {
+        self.attributes & 0x20 != 0
+    }
+
+    /// wTotalLength of a configuration descriptor header
+    pub fn total_length(header: &[u8]) -> Option<u16> {
+        (header.len() >= 4 && header[1] == descriptor_types::CONFIGURATION)
+            .then(|| u16_at(header, 2))
+    }
+
+    /// Parse a configuration descriptor with everything following it
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::HEADER_SIZE || bytes[1] != descriptor_types::CONFIGURATION {
+            return None;
+        }
+        let mut configuration = Self {
+            value: bytes[5],
+            attributes: bytes[7],
+            max_power: bytes[8],
+            interfaces: Vec::new(),
+        };
+
+        let total = (u16_at(bytes, 2) as usize).min(bytes.len());
+        let mut offset = bytes[0] as usize;
+        while offset + 2 <= total {
+            let length = bytes[offset] as usize;
+            if length < 2 || offset + length > total {
+                break;
+            }
+            let descriptor = &bytes[offset..offset + length];
+            match descriptor[1] {
+                descriptor_types::INTERFACE if length >= 9 => {
+                    configuration.interfaces.push(InterfaceDescriptor {
+                        number: descriptor[2],
+                        alternate_setting: descriptor[3],
+                        class: descriptor[5],
+                        subclass: descriptor[6],
+                        protocol: descriptor[7],
+                        endpoints: Vec::new(),
+                        class_descriptors: Vec::new(),
+                    });
+                }
+                descriptor_types::ENDPOINT if length >= 7 => {
+                    if let Some(interface) = configuration.interfaces.last_mut() {
+                        interface.endpoints.push(EndpointDescriptor {
+                            address: descriptor[2],
+                            attributes: descriptor[3],
+                            max_packet_size: u16_at(descriptor, 4),
+                            interval: descriptor[6],
+                            companion: None,
+                        });
+                    }
+                }
+                descriptor_types::SS_ENDPOINT_COMPANION if length >= 6 => {
+                    if let Some(endpoint) = configuration
+                        .interfaces
+                        .last_mut()
+                        .and_then(|interface| interface.endpoints.last_mut())
+                    {
+                        endpoint.companion = Some(EndpointCompanion {
+                            max_burst: descriptor[2],
+                            attributes: descriptor[3],
+                            bytes_per_interval: u16_at(descriptor, 4),
+                        });
+                    }
+                }
+                descriptor_types::CS_INTERFACE => {
+                    if let Some(interface) = configuration.interfaces.last_mut() {
+                        interface.class_descriptors.push(descriptor.to_vec());
+                    }
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        Some(configuration)
+    }
+}
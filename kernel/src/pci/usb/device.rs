@@ -0,0 +1,325 @@
+//! USB device enumeration
+//!
+//! Each connected root hub port is reset, its device gets a slot and an
+//! address, and its device and first configuration descriptors are read.
+//! The device is then offered to the class drivers in `DRIVERS` until one
+//! claims it; the claiming driver picks the configuration and endpoints it
+//! needs. Hubs aren't supported, so only devices plugged straight into the
+//! controller are found.
+
+use alloc::{vec, vec::Vec};
+
+use x86_64::instructions::interrupts;
+
+use super::{
+    cdc_acm,
+    context::{default_max_packet_size, endpoint_dci},
+    descriptors::{
+        ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, SetupPacket,
+        descriptor_types, features, request_types,
+    },
+    xhci::{
+        self, Transfer, UsbError, address_device, control_transfer, disable_slot, max_ports,
+        port_sc, set_control_max_packet_size, start_port_reset,
+    },
+};
+use crate::{
+    debug, info,
+    pci::dma::{DynamicDmaBuffer, get_zeroed_dma},
+    sync::Mutex,
+    tasks::scheduler::sleep_ticks,
+    time, warn,
+};
+
+/// Longest a port may take to come out of reset
+const PORT_RESET_TIMEOUT_MS: u64 = 500;
+
+/// Time a device gets to recover from a reset (USB 2.0, 7.1.7.3)
+const RESET_RECOVERY_MS: u64 = 10;
+
+/// A class driver
+pub struct UsbDriver {
+    /// Driver name, used in logs
+    pub name: &'static str,
+    /// Returns whether the driver claimed the device
+    pub probe: fn(&UsbDevice) -> bool,
+}
+
+/// All class drivers, tried in order
+static DRIVERS: &[UsbDriver] = &[UsbDriver {
+    name: "cdc-acm",
+    probe: cdc_acm::probe,
+}];
+
+/// Devices enumerated since the controller was last probed
+static DEVICES: Mutex<Vec<UsbDevice>> = Mutex::new("USB_DEVICES", Vec::new());
+
+/// An addressed device
+#[derive(Debug, Clone)]
+pub struct UsbDevice {
+    /// Controller generation the device was enumerated in
+    pub generation: u64,
+    pub slot_id: u8,
+    /// Root hub port, 1-based
+    pub port: u8,
+    /// Port speed, as in PORTSC
+    pub speed: u8,
+    pub descriptor: DeviceDescriptor,
+    /// The first configuration
+    pub configuration: ConfigurationDescriptor,
+}
+
+impl UsbDevice {
+    /// Run a control request reading up to `data.len()` bytes into `data`,
+    /// returning the number read
+    pub fn control_in(&self, setup: SetupPacket, data: &mut [u8]) -> Result<usize, UsbError> {
+        let buffer = bounce_buffer(data.len())?;
+        let setup = SetupPacket {
+            length: data.len() as u16,
+            ..setup
+        };
+        let read = control_transfer(
+            self.generation,
+            self.slot_id,
+            setup,
+            buffer.phys_addr.as_u64(),
+        )?;
+        let read = read.min(data.len());
+        data[..read].copy_from_slice(unsafe {
+            core::slice::from_raw_parts(buffer.virt_addr.as_ptr::<u8>(), read)
+        });
+        Ok(read)
+    }
+
+    /// Run a control request writing `data` in its data stage
+    pub fn control_out(&self, setup: SetupPacket, data: &[u8]) -> Result<(), UsbError> {
+        let buffer = bounce_buffer(data.len())?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                buffer.virt_addr.as_mut_ptr::<u8>(),
+                data.len(),
+            );
+        }
+        let setup = SetupPacket {
+            length: data.len() as u16,
+            ..setup
+        };
+        control_transfer(
+            self.generation,
+            self.slot_id,
+            setup,
+            buffer.phys_addr.as_u64(),
+        )
+        .map(|_| ())
+    }
+
+    /// Select the first configuration, after configuring `endpoints` of it
+    /// with the controller
+    ///
+    /// Remote wakeup is enabled if the configuration supports it, which lets
+    /// the device be suspended while idle (see `pm`).
+    pub fn configure(&self, endpoints: &[EndpointDescriptor]) -> Result<(), UsbError> {
+        xhci::configure_endpoints(self.generation, self.slot_id, self.port, endpoints)?;
+        self.control_out(
+            SetupPacket::set_configuration(self.configuration.value),
+            &[],
+        )?;
+
+        if self.configuration.remote_wakeup() {
+            match self.enable_remote_wakeup() {
+                Ok(()) => xhci::set_remote_wakeup(self.generation, self.slot_id, true)?,
+                // the device still works, it just stays awake
+                Err(UsbError::Stalled) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn enable_remote_wakeup(&self) -> Result<(), UsbError> {
+        let setup = if self.speed >= 4 {
+            // USB 3 enables it per function, with bit 1 of the options in
+            // the index's high byte; the first interface stands for the
+            // function
+            let interface = self
+                .configuration
+                .interfaces
+                .first()
+                .map_or(0, |interface| interface.number);
+            SetupPacket::set_feature(
+                request_types::TO_INTERFACE,
+                features::FUNCTION_SUSPEND,
+                0x2 << 8 | interface as u16,
+            )
+        } else {
+            SetupPacket::set_feature(request_types::TO_DEVICE, features::DEVICE_REMOTE_WAKEUP, 0)
+        };
+        self.control_out(setup, &[])
+    }
+
+    /// Start a bulk or interrupt transfer on `endpoint`, from or to the first
+    /// `length` bytes of `buffer`
+    pub(crate) fn submit(
+        &self,
+        endpoint: &EndpointDescriptor,
+        buffer: &DynamicDmaBuffer,
+        length: usize,
+    ) -> Result<Transfer, UsbError> {
+        if length > buffer.size * 4096 {
+            return Err(UsbError::BufferTooLarge);
+        }
+        xhci::submit_transfer(
+            self.generation,
+            self.slot_id,
+            endpoint_dci(endpoint.address),
+            buffer.phys_addr.as_u64(),
+            length as u32,
+        )
+    }
+
+    /// Clear a halted endpoint, dropping the transfers queued on it
+    pub fn reset_endpoint(&self, endpoint: &EndpointDescriptor) -> Result<(), UsbError> {
+        xhci::reset_endpoint(
+            self.generation,
+            self.slot_id,
+            endpoint_dci(endpoint.address),
+        )
+    }
+}
+
+/// A DMA buffer for the data stage of a control transfer
+fn bounce_buffer(length: usize) -> Result<DynamicDmaBuffer, UsbError> {
+    if length > 4096 {
+        return Err(UsbError::BufferTooLarge);
+    }
+    Ok(get_zeroed_dma(1)?)
+}
+
+/// Enumerate the devices connected to every root hub port and bind drivers
+/// to them
+#[allow(unused_variables)]
+pub fn enumerate_ports(generation: u64) {
+    let Ok(ports) = max_ports(generation) else {
+        return;
+    };
+    interrupts::without_interrupts(|| DEVICES.lock().clear());
+    for port in 1..=ports {
+        match port_sc(generation, port) {
+            Ok(portsc) if portsc.current_connect_status() => {}
+            Ok(_) => {
+                debug!("Port {}: No device connected", port);
+                continue;
+            }
+            Err(_) => return,
+        }
+
+        let device = match enumerate(generation, port) {
+            Ok(device) => device,
+            Err(e) => {
+                warn!("USB: port {}: enumeration failed: {:?}", port, e);
+                continue;
+            }
+        };
+        info!(
+            "USB: port {}: device {:04x}:{:04x} (class {:02x}, slot {})",
+            port,
+            device.descriptor.vendor_id,
+            device.descriptor.product_id,
+            device.descriptor.class,
+            device.slot_id
+        );
+
+        interrupts::without_interrupts(|| DEVICES.lock().push(device.clone()));
+        if let Some(driver) = DRIVERS.iter().find(|driver| (driver.probe)(&device)) {
+            info!("USB: port {}: bound to {}", port, driver.name);
+        } else {
+            debug!("USB: port {}: no driver", port);
+        }
+    }
+}
+
+/// Devices enumerated on the running controller
+pub fn devices() -> Vec<UsbDevice> {
+    interrupts::without_interrupts(|| DEVICES.lock().clone())
+}
+
+/// Reset a port, returning the speed of its device
+fn reset_port(generation: u64, port: u8) -> Result<u8, UsbError> {
+    // USB 3 ports enable themselves once the link trained
+    if !port_sc(generation, port)?.port_enabled() {
+        start_port_reset(generation, port)?;
+        let deadline = time::ticks() + time::ms_to_ticks(PORT_RESET_TIMEOUT_MS);
+        loop {
+            let portsc = port_sc(generation, port)?;
+            if !portsc.port_reset() && portsc.port_enabled() {
+                break;
+            }
+            if time::ticks() >= deadline {
+                return Err(UsbError::Timeout);
+            }
+            sleep_ticks(1);
+        }
+    }
+    sleep_ticks(time::ms_to_ticks(RESET_RECOVERY_MS).max(1));
+    Ok(port_sc(generation, port)?.port_speed())
+}
+
+/// Address the device on `port` and read its descriptors
+fn enumerate(generation: u64, port: u8) -> Result<UsbDevice, UsbError> {
+    let speed = reset_port(generation, port)?;
+    let slot_id = address_device(generation, port, speed, default_max_packet_size(speed))?;
+    let mut device = UsbDevice {
+        generation,
+        slot_id,
+        port,
+        speed,
+        descriptor: DeviceDescriptor::default(),
+        configuration: ConfigurationDescriptor::default(),
+    };
+
+    let result = read_descriptors(&mut device);
+    if result.is_err() {
+        let _ = disable_slot(generation, slot_id);
+    }
+    result.map(|()| device)
+}
+
+fn read_descriptors(device: &mut UsbDevice) -> Result<(), UsbError> {
+    // the first 8 bytes fit in any max packet size and hold the real one
+    let mut bytes = [0; DeviceDescriptor::SIZE];
+    device.control_in(
+        SetupPacket::get_descriptor(descriptor_types::DEVICE, 0, 8),
+        &mut bytes[..8],
+    )?;
+    let partial = DeviceDescriptor {
+        usb_version: u16::from_le_bytes([bytes[2], bytes[3]]),
+        max_packet_size0: bytes[7],
+        ..Default::default()
+    };
+    let max_packet_size = partial.control_max_packet_size();
+    if max_packet_size != default_max_packet_size(device.speed) && max_packet_size > 0 {
+        set_control_max_packet_size(device.generation, device.slot_id, max_packet_size)?;
+    }
+
+    let read = device.control_in(
+        SetupPacket::get_descriptor(descriptor_types::DEVICE, 0, 0),
+        &mut bytes,
+    )?;
+    device.descriptor = DeviceDescriptor::parse(&bytes[..read]).ok_or(UsbError::BadDescriptor)?;
+
+    let mut header = [0; ConfigurationDescriptor::HEADER_SIZE];
+    device.control_in(
+        SetupPacket::get_descriptor(descriptor_types::CONFIGURATION, 0, 0),
+        &mut header,
+    )?;
+    let total = ConfigurationDescriptor::total_length(&header).ok_or(UsbError::BadDescriptor)?;
+    let mut configuration = vec![0; (total as usize).clamp(header.len(), 4096)];
+    let read = device.control_in(
+        SetupPacket::get_descriptor(descriptor_types::CONFIGURATION, 0, 0),
+        &mut configuration,
+    )?;
+    device.configuration =
+        ConfigurationDescriptor::parse(&configuration[..read]).ok_or(UsbError::BadDescriptor)?;
+    Ok(())
+}
@@ -1,42 +1,112 @@
+use alloc::vec::Vec;
 use core::fmt;
 
-use x86_64::{PhysAddr, VirtAddr};
-
-use crate::{debug, pci::{dma::DMA_MANAGER, usb::xhci_registers::{CommandRingControl, XhciRegisters}}};
+use crate::{
+    debug,
+    pci::{
+        dma::{DmaError, DynamicDmaBuffer, get_zeroed_dma},
+        usb::{
+            ring::{CommandRing, EventRing},
+            xhci_registers::{CommandRingControl, InterrupterManagement, InterrupterModeration, XhciRegisters},
+        },
+    },
+};
+
+/// Interrupt moderation interval in 250 ns units (1 ms)
+const INTERRUPT_MODERATION: u16 = 4000;
+
+/// Event ring segments of interrupter 0, if the controller takes that many,
+/// so a burst of port changes and completions while a hub enumerates doesn't
+/// fill it
+const EVENT_RING_SEGMENTS: usize = 4;
+
+/// The Device Context Base Address Array (DCBAA) and the scratchpad buffers
+/// its first entry hands to the controller
+#[derive(Debug)]
+pub struct Dcbaa {
+    buffer: DynamicDmaBuffer,
+    _scratchpad_array: Option<DynamicDmaBuffer>,
+    _scratchpad: Vec<DynamicDmaBuffer>,
+}
 
-/// command ring size in 64 trbs
-const COMMAND_RING_SIZE: usize = 256;
+impl Dcbaa {
+    /// Point the controller to the device context of a slot, or clear the
+    /// entry with 0
+    pub fn set(&mut self, slot_id: u8, device_context: u64) {
+        unsafe {
+            core::ptr::write_volatile(
+                self.buffer.virt_addr.as_mut_ptr::<u64>().add(slot_id as usize),
+                device_context,
+            );
+        }
+    }
+}
 
 /// Initialize the Device Context Base Address Array (DCBAA)
-/// 
-/// Should pass in a xchi registers ref
-pub fn init_dcbaa(xhci_regs: &mut XhciRegisters) {
-    let needed_entries = xhci_regs.capability().hcs_params1.max_device_slots() + 1;
-
-    let buffer = DMA_MANAGER.lock().get_pool_4kb().expect("Could not allocate DMA");
-    let dcbaa_phys = buffer.phys_addr;
+///
+/// Also allocates the scratchpad buffers the controller asks for.
+pub(crate) fn init_dcbaa(xhci_regs: &mut XhciRegisters) -> Result<Dcbaa, DmaError> {
+    let needed_entries = xhci_regs.capability().hcs_params1.max_device_slots() as usize + 1;
+
+    let mut dcbaa = Dcbaa {
+        buffer: get_zeroed_dma(1)?,
+        _scratchpad_array: None,
+        _scratchpad: Vec::new(),
+    };
+
+    let scratchpad_count = xhci_regs.capability().hcs_params2.max_scratchpad_buffers() as usize;
+    if scratchpad_count > 0 {
+        let array = get_zeroed_dma((scratchpad_count * 8).div_ceil(4096))?;
+        for index in 0..scratchpad_count {
+            let page = get_zeroed_dma(1)?;
+            unsafe {
+                array.virt_addr.as_mut_ptr::<u64>().add(index).write(page.phys_addr.as_u64());
+            }
+            dcbaa._scratchpad.push(page);
+        }
+        dcbaa.set(0, array.phys_addr.as_u64());
+        dcbaa._scratchpad_array = Some(array);
+    }
 
-    xhci_regs.set_device_context_base_addr(dcbaa_phys.as_u64());
-    debug!("Allocated DCBAA at {:#x} with {} entries", dcbaa_phys, needed_entries);
+    xhci_regs.set_device_context_base_addr(dcbaa.buffer.phys_addr.as_u64());
+    debug!(
+        "Allocated DCBAA at {:#x} with {} entries, {} scratchpad buffers",
+        dcbaa.buffer.phys_addr, needed_entries, scratchpad_count
+    );
+    Ok(dcbaa)
 }
 
 /// Initialize the trb command ring
-/// 
-/// uses COMMAND_RING_SIZE
-pub fn init_command_ring(xhci_regs: &mut XhciRegisters) {
-    let buffer = DMA_MANAGER.lock().get_pool_4kb()
-        .expect("Failed to allocate command ring memory from 4KB pool");
-    let ring_phys = buffer.phys_addr;
-    let ring_virt = buffer.virt_addr;
-
-    let first_trb = ring_virt.as_mut_ptr::<Trb>();
-    let first_link_trb = unsafe { first_trb.add(COMMAND_RING_SIZE - 1) };
-    unsafe {
-        (*first_link_trb) = Trb::link(ring_phys.as_u64(), true, false)
-    }
-
-    xhci_regs.set_command_ring_ctrl(CommandRingControl::new(ring_phys.as_u64(), true));
-    debug!("Allocated command ring at {:#x} with {} TRBs", ring_phys, COMMAND_RING_SIZE);
+pub(crate) fn init_command_ring(xhci_regs: &mut XhciRegisters) -> Result<CommandRing, DmaError> {
+    let ring = CommandRing::new()?;
+    xhci_regs.set_command_ring_ctrl(CommandRingControl::new(ring.phys_addr(), ring.cycle()));
+    debug!("Allocated command ring at {:#x} with {} TRBs", ring.phys_addr(), MAX_TRBS_PER_SEGMENT);
+    Ok(ring)
+}
+
+/// Initialize the event ring of interrupter 0 and enable its interrupts
+pub(crate) fn init_event_ring(xhci_regs: &mut XhciRegisters) -> Result<EventRing, DmaError> {
+    let max_segments = 1 << xhci_regs.capability().hcs_params2.erst_max();
+    let ring = EventRing::new(EVENT_RING_SEGMENTS.min(max_segments))?;
+    xhci_regs.set_event_ring_segment_table_size(0, ring.segments());
+    xhci_regs.set_event_ring_dequeue_pointer(0, ring.dequeue_pointer());
+    xhci_regs.set_event_ring_segment_table_base(0, ring.table_addr());
+
+    let mut imod = InterrupterModeration(0);
+    imod.set_interrupt_moderation_interval(INTERRUPT_MODERATION);
+    xhci_regs.set_interrupter_moderation(0, imod);
+
+    let mut iman = InterrupterManagement(0);
+    iman.clear_interrupt_pending();
+    iman.set_interrupt_enable(true);
+    xhci_regs.set_interrupter_management(0, iman);
+
+    debug!(
+        "Allocated event ring of {} segments with table at {:#x}",
+        ring.segments(),
+        ring.table_addr()
+    );
+    Ok(ring)
 }
 
 /// A single TRB
@@ -189,6 +259,15 @@ impl Trb {
         }
     }
 
+    /// Set the interrupt on short packet flag (bit 2 of control field)
+    pub fn set_interrupt_on_short_packet(&mut self, isp: bool) {
+        if isp {
+            self.control |= 0x4;
+        } else {
+            self.control &= !0x4;
+        }
+    }
+
     /// Get the immediate data flag (bit 6 of control field)
     pub fn immediate_data(&self) -> bool {
         (self.control & 0x40) != 0
@@ -311,6 +390,27 @@ impl Trb {
         trb
     }
 
+    /// Create an Evaluate Context Command TRB
+    pub fn evaluate_context_command(input_context_ptr: u64, slot_id: u8, cycle: bool) -> Self {
+        let mut trb = Self::new();
+        trb.data = input_context_ptr & !0x3F; // Must be 64-byte aligned
+        trb.set_trb_type(TrbType::EvaluateContext);
+        trb.set_slot_id(slot_id);
+        trb.set_cycle_bit(cycle);
+        trb
+    }
+
+    /// Create a Set TR Dequeue Pointer Command TRB
+    pub fn set_tr_dequeue_pointer_command(dequeue_ptr: u64, dequeue_cycle: bool, slot_id: u8, endpoint_id: u8, cycle: bool) -> Self {
+        let mut trb = Self::new();
+        trb.data = (dequeue_ptr & !0xF) | dequeue_cycle as u64; // DCS in bit 0
+        trb.set_trb_type(TrbType::SetTrDequeuePointer);
+        trb.set_slot_id(slot_id);
+        trb.set_endpoint_id(endpoint_id);
+        trb.set_cycle_bit(cycle);
+        trb
+    }
+
     /// Create a Reset Device Command TRB
     pub fn reset_device_command(slot_id: u8, cycle: bool) -> Self {
         let mut trb = Self::new();
@@ -357,14 +457,44 @@ impl Trb {
         trb
     }
 
+    /// Create an Isoch TRB, the first TRB of an isochronous TD
+    ///
+    /// `burst_count` and `last_burst_packets` are the TBC and TLBPC fields.
+    /// `frame_id` is the 1 ms frame the TD is due in; `None` sets SIA so the
+    /// controller schedules it as soon as possible.
+    pub fn isoch_transfer(
+        buffer_ptr: u64,
+        length: u32,
+        burst_count: u8,
+        last_burst_packets: u8,
+        frame_id: Option<u16>,
+        cycle: bool,
+    ) -> Self {
+        let mut trb = Self::new();
+        trb.data = buffer_ptr;
+        trb.set_transfer_length(length);
+        trb.set_trb_type(TrbType::Isoch);
+        trb.control |= ((burst_count & 0x3) as u32) << 7; // TBC in bits 7-8
+        trb.control |= ((last_burst_packets & 0xF) as u32) << 16; // TLBPC in bits 16-19
+        match frame_id {
+            Some(frame) => trb.control |= ((frame & 0x7FF) as u32) << 20, // Frame ID in bits 20-30
+            None => trb.control |= 1 << 31, // SIA
+        }
+        trb.set_cycle_bit(cycle);
+        trb
+    }
+
     /// Create a Setup Stage TRB for control transfers
-    pub fn setup_stage(setup_data: u64, transfer_length: u32, immediate_data: bool, cycle: bool) -> Self {
+    ///
+    /// `transfer_type` tells whether a data stage follows: 0 for none, 2 for
+    /// OUT, 3 for IN.
+    pub fn setup_stage(setup_data: u64, transfer_length: u32, immediate_data: bool, transfer_type: u8, cycle: bool) -> Self {
         let mut trb = Self::new();
         trb.data = setup_data;
         trb.set_transfer_length(transfer_length);
         trb.set_trb_type(TrbType::SetupStage);
         trb.set_immediate_data(immediate_data);
-        trb.control |= 0x80; // TRT (Transfer Type) = Setup (bits 16-17 = 00, but we set IDT)
+        trb.control |= ((transfer_type & 0x3) as u32) << 16; // TRT in bits 16-17
         trb.set_cycle_bit(cycle);
         trb
     }
@@ -0,0 +1,358 @@
+//! Isochronous streams
+//!
+//! An isochronous endpoint moves up to a fixed payload every service
+//! interval and never retries: data that misses its slot is lost. A stream
+//! queues one TD per frame, or per interval when that is longer, each due in
+//! a frame computed from the microframe index. The controller only takes a
+//! TD queued at least the isochronous scheduling threshold (IST) before its
+//! frame, and completes the ones it couldn't fit in time with Missed Service.
+//!
+//! When the queue runs dry, the controller reports an underrun (an overrun
+//! for IN endpoints) and loses the schedule, so the stream starts it again
+//! from the current frame.
+//!
+//! Each TD is one Isoch TRB, so a TD moves at most 64 KiB; streams needing
+//! more per frame aren't supported.
+
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+use core::ptr::copy_nonoverlapping;
+
+use super::{
+    context::{endpoint_dci, periodic_interval},
+    descriptors::{EndpointDescriptor, TransferType},
+    device::UsbDevice,
+    init_helpers::{CompletionCode, Trb},
+    xhci::{
+        UsbError, forget_completions, isoch_scheduling_threshold, microframe_index,
+        poll_completion, stop_endpoint, submit_isoch, take_ran_empty, wait_completion,
+    },
+};
+use crate::pci::dma::{DynamicDmaBuffer, get_zeroed_dma};
+
+/// Frame IDs wrap at 2^11 frames
+const FRAME_MASK: u16 = 0x7FF;
+
+/// Furthest ahead a TD may be scheduled, in frames
+const MAX_FRAMES_AHEAD: u16 = 895;
+
+/// Bytes a TRB's buffer can't cross a multiple of
+const TD_BOUNDARY: u64 = 64 * 1024;
+
+/// Attempts at allocating a TD buffer that doesn't cross `TD_BOUNDARY`
+const BUFFER_ATTEMPTS: usize = 4;
+
+/// How a TD completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsochStatus {
+    Ok,
+    /// The controller couldn't service the TD in its frame
+    Missed,
+    /// The transaction failed with this completion code
+    Error(u8),
+}
+
+/// A completed TD
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsochTd {
+    /// Bytes received, empty for OUT endpoints
+    pub data: Vec<u8>,
+    /// Bytes moved
+    pub transferred: usize,
+    pub status: IsochStatus,
+}
+
+/// Counters of a stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IsochStats {
+    pub completed: u64,
+    pub missed: u64,
+    pub errors: u64,
+    /// Times the queue ran dry and the schedule restarted
+    pub underruns: u64,
+    pub bytes: u64,
+}
+
+/// A TD handed to the controller
+#[derive(Debug)]
+struct QueuedTd {
+    trb: u64,
+    buffer: DynamicDmaBuffer,
+    length: u32,
+}
+
+/// TDs flowing on one isochronous endpoint
+#[derive(Debug)]
+pub struct IsochStream {
+    device: UsbDevice,
+    endpoint: EndpointDescriptor,
+    dci: u8,
+    /// Frames between consecutive TDs
+    frames_per_td: u16,
+    /// Most bytes a TD moves
+    td_size: usize,
+    /// Frame the next TD is due in, once scheduled
+    next_frame: Option<u16>,
+    queued: VecDeque<QueuedTd>,
+    /// Buffers of collected TDs, for reuse
+    spare: Vec<DynamicDmaBuffer>,
+    stats: IsochStats,
+}
+
+impl IsochStream {
+    /// Start a stream on an isochronous endpoint configured with
+    /// `UsbDevice::configure`
+    pub fn new(device: &UsbDevice, endpoint: EndpointDescriptor) -> Result<Self, UsbError> {
+        if endpoint.transfer_type() != TransferType::Isochronous {
+            return Err(UsbError::WrongTransferType);
+        }
+        let interval = 1u32 << periodic_interval(&endpoint, device.speed);
+        // endpoints serviced more than once a frame get one TD per frame,
+        // covering every interval in it
+        let intervals_per_td = (8 / interval).max(1);
+        let td_size = (endpoint.max_esit_payload() * intervals_per_td) as usize;
+        if td_size == 0 {
+            return Err(UsbError::BadDescriptor);
+        }
+        if td_size as u64 > TD_BOUNDARY {
+            return Err(UsbError::BufferTooLarge);
+        }
+        Ok(Self {
+            device: device.clone(),
+            endpoint,
+            dci: endpoint_dci(endpoint.address),
+            frames_per_td: (interval / 8).max(1) as u16,
+            td_size,
+            next_frame: None,
+            queued: VecDeque::new(),
+            spare: Vec::new(),
+            stats: IsochStats::default(),
+        })
+    }
+
+    /// Most bytes one TD moves
+    pub fn td_size(&self) -> usize {
+        self.td_size
+    }
+
+    /// TDs queued and not collected yet
+    pub fn queued(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn stats(&self) -> IsochStats {
+        self.stats
+    }
+
+    /// Queue a TD sending `data`, at most `td_size` bytes, in the frame after
+    /// the previous TD
+    pub fn queue_out(&mut self, data: &[u8]) -> Result<(), UsbError> {
+        if self.endpoint.is_in() {
+            return Err(UsbError::WrongTransferType);
+        }
+        if data.len() > self.td_size {
+            return Err(UsbError::BufferTooLarge);
+        }
+        let buffer = self.buffer()?;
+        unsafe {
+            copy_nonoverlapping(
+                data.as_ptr(),
+                buffer.virt_addr.as_mut_ptr::<u8>(),
+                data.len(),
+            );
+        }
+        self.queue(buffer, data.len())
+    }
+
+    /// Queue a TD receiving up to `td_size` bytes in the frame after the
+    /// previous TD
+    pub fn queue_in(&mut self) -> Result<(), UsbError> {
+        if !self.endpoint.is_in() {
+            return Err(UsbError::WrongTransferType);
+        }
+        let buffer = self.buffer()?;
+        self.queue(buffer, self.td_size)
+    }
+
+    /// A buffer for one TD that doesn't cross a 64 KiB boundary
+    fn buffer(&mut self) -> Result<DynamicDmaBuffer, UsbError> {
+        if let Some(buffer) = self.spare.pop() {
+            return Ok(buffer);
+        }
+        let frames = self.td_size.div_ceil(4096).max(1);
+        // a crossing buffer is kept until the end, so the next attempt gets
+        // different frames
+        let mut crossing = Vec::new();
+        for _ in 0..BUFFER_ATTEMPTS {
+            let buffer = get_zeroed_dma(frames)?;
+            let start = buffer.phys_addr.as_u64();
+            if start / TD_BOUNDARY == (start + self.td_size as u64 - 1) / TD_BOUNDARY {
+                return Ok(buffer);
+            }
+            crossing.push(buffer);
+        }
+        Err(UsbError::AllocationFailed)
+    }
+
+    fn queue(&mut self, buffer: DynamicDmaBuffer, length: usize) -> Result<(), UsbError> {
+        let start = buffer.phys_addr.as_u64();
+        let generation = self.device.generation;
+        if take_ran_empty(generation, self.device.slot_id, self.dci)? {
+            self.stats.underruns += 1;
+            self.next_frame = None;
+        }
+        let frame = self.schedule()?;
+
+        let packet_size = self.endpoint.packet_size().max(1) as usize;
+        let burst = self.endpoint.max_burst() as usize + 1;
+        let packets = length.div_ceil(packet_size).max(1);
+        let burst_count = packets.div_ceil(burst) - 1;
+        let last_burst_packets = match packets % burst {
+            0 => burst - 1,
+            residue => residue - 1,
+        };
+
+        let mut trb = Trb::isoch_transfer(
+            start,
+            length as u32,
+            burst_count as u8,
+            last_burst_packets as u8,
+            Some(frame),
+            false,
+        );
+        trb.set_interrupt_on_completion(true);
+        if self.endpoint.is_in() {
+            trb.set_interrupt_on_short_packet(true);
+        }
+        let trb = match submit_isoch(generation, self.device.slot_id, self.dci, trb) {
+            Ok(trb) => trb,
+            Err(e) => {
+                self.spare.push(buffer);
+                return Err(e);
+            }
+        };
+
+        self.next_frame = Some((frame + self.frames_per_td) & FRAME_MASK);
+        self.queued.push_back(QueuedTd {
+            trb,
+            buffer,
+            length: length as u32,
+        });
+        Ok(())
+    }
+
+    /// Frame the next TD is due in: the one after the previous TD, unless
+    /// that is too close or the schedule was lost
+    fn schedule(&self) -> Result<u16, UsbError> {
+        let generation = self.device.generation;
+        let now = microframe_index(generation)?;
+        let current = (now >> 3) as u16 & FRAME_MASK;
+        let earliest =
+            (((now + isoch_scheduling_threshold(generation)?) >> 3) as u16 + 1) & FRAME_MASK;
+        let lead = earliest.wrapping_sub(current) & FRAME_MASK;
+
+        Ok(match self.next_frame {
+            Some(frame) => {
+                let ahead = frame.wrapping_sub(current) & FRAME_MASK;
+                if (lead..=MAX_FRAMES_AHEAD).contains(&ahead) {
+                    frame
+                } else {
+                    earliest
+                }
+            }
+            None => earliest,
+        })
+    }
+
+    /// Collect the TDs that completed, oldest first
+    pub fn reap(&mut self) -> Result<Vec<IsochTd>, UsbError> {
+        let mut completed = Vec::new();
+        // TDs complete in the order they were queued
+        while let Some(queued) = self.queued.front() {
+            let Some(event) = poll_completion(self.device.generation, &[queued.trb])? else {
+                break;
+            };
+            let queued = self.queued.pop_front().unwrap();
+            completed.push(self.complete(queued, event));
+        }
+        Ok(completed)
+    }
+
+    /// Sleep until the oldest TD completes and collect it, if any is queued
+    ///
+    /// Must be called from a task with interrupts enabled.
+    pub fn wait(&mut self) -> Result<Option<IsochTd>, UsbError> {
+        let Some(queued) = self.queued.front() else {
+            return Ok(None);
+        };
+        let event = wait_completion(self.device.generation, &[queued.trb])?;
+        let queued = self.queued.pop_front().unwrap();
+        Ok(Some(self.complete(queued, event)))
+    }
+
+    fn complete(&mut self, queued: QueuedTd, event: Trb) -> IsochTd {
+        let code = event.completion_code();
+        let (transferred, status) = match code {
+            code if code == CompletionCode::Success as u8
+                || code == CompletionCode::ShortPacket as u8 =>
+            {
+                let residue = event.transfer_length().min(queued.length);
+                ((queued.length - residue) as usize, IsochStatus::Ok)
+            }
+            code if code == CompletionCode::MissedServiceError as u8 => (0, IsochStatus::Missed),
+            code => (0, IsochStatus::Error(code)),
+        };
+        match status {
+            IsochStatus::Ok => self.stats.completed += 1,
+            IsochStatus::Missed => self.stats.missed += 1,
+            IsochStatus::Error(_) => self.stats.errors += 1,
+        }
+        self.stats.bytes += transferred as u64;
+
+        let data = if self.endpoint.is_in() {
+            unsafe {
+                core::slice::from_raw_parts(queued.buffer.virt_addr.as_ptr::<u8>(), transferred)
+            }
+            .to_vec()
+        } else {
+            Vec::new()
+        };
+        self.spare.push(queued.buffer);
+        IsochTd {
+            data,
+            transferred,
+            status,
+        }
+    }
+
+    /// Abandon the TDs in flight
+    ///
+    /// Must be called from a task with interrupts enabled.
+    pub fn stop(&mut self) -> Result<(), UsbError> {
+        self.abandon()?;
+        let buffers = self.queued.drain(..).map(|queued| queued.buffer);
+        self.spare.extend(buffers);
+        Ok(())
+    }
+
+    fn abandon(&mut self) -> Result<(), UsbError> {
+        if self.queued.is_empty() {
+            return Ok(());
+        }
+        stop_endpoint(self.device.generation, self.device.slot_id, self.dci)?;
+        let trbs: Vec<u64> = self.queued.iter().map(|queued| queued.trb).collect();
+        forget_completions(self.device.generation, &trbs)?;
+        self.next_frame = None;
+        Ok(())
+    }
+}
+
+impl Drop for IsochStream {
+    fn drop(&mut self) {
+        match self.abandon() {
+            // the controller is gone or was reset, nothing writes the buffers
+            Ok(()) | Err(UsbError::Gone | UsbError::NotInitialized) => {}
+            // the controller may still write to the buffers
+            Err(_) => self.queued.drain(..).for_each(core::mem::forget),
+        }
+    }
+}
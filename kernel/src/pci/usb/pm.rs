@@ -0,0 +1,85 @@
+//! USB selective suspend
+//!
+//! Devices that may wake themselves up are suspended once they submitted no
+//! transfer for `usb.autosuspend_ms`: their endpoints are stopped and their
+//! root hub port is put in U3. A new transfer resumes the device before it is
+//! queued, and a device signalling a remote wakeup is resumed by the PM task,
+//! which checks for both every tick.
+//!
+//! Devices without remote wakeup are only suspended on request, since they
+//! couldn't report incoming data while asleep.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use super::xhci::{self, generation, idle_slots, resume_slot, suspend_slot, take_wake_requests};
+use crate::{
+    debug,
+    sysctl::Sysctl,
+    tasks::scheduler::{kcreate_task, sleep_ticks},
+    time, warn,
+};
+
+static AUTOSUSPEND_MS: AtomicU64 = AtomicU64::new(2000);
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+pub static SYSCTLS: &[Sysctl] = &[Sysctl {
+    name: "usb.autosuspend_ms",
+    help: "idle time before a USB device with remote wakeup is suspended, 0 to never",
+    get: || AUTOSUSPEND_MS.load(Ordering::Relaxed),
+    set: |value| {
+        if value != 0 && !(100..=600_000).contains(&value) {
+            return Err("must be 0 or 100-600000 ms");
+        }
+        AUTOSUSPEND_MS.store(value, Ordering::Relaxed);
+        Ok(())
+    },
+}];
+
+/// Start the PM task, once
+pub fn spawn_task() {
+    if !STARTED.swap(true, Ordering::Relaxed) {
+        interrupts::without_interrupts(|| kcreate_task(pm_task, "usb pm"));
+    }
+}
+
+#[allow(unused_variables)]
+fn pm_task() -> ! {
+    loop {
+        sleep_ticks(1);
+        let Ok(generation) = generation() else {
+            continue;
+        };
+
+        for slot_id in take_wake_requests(generation).unwrap_or_default() {
+            match resume_slot(generation, slot_id) {
+                Ok(()) => {
+                    debug!("USB: slot {} woke up", slot_id);
+                }
+                Err(e) => {
+                    warn!("USB: slot {}: resume failed: {:?}", slot_id, e);
+                }
+            }
+        }
+
+        let autosuspend_ms = AUTOSUSPEND_MS.load(Ordering::Relaxed);
+        if autosuspend_ms == 0 {
+            continue;
+        }
+        let idle_ticks = time::ms_to_ticks(autosuspend_ms).max(1);
+        for slot_id in idle_slots(generation, idle_ticks).unwrap_or_default() {
+            match suspend_slot(generation, slot_id) {
+                Ok(()) => {
+                    debug!("USB: slot {} suspended", slot_id);
+                }
+                Err(e) => {
+                    // keep it from being retried every tick
+                    let _ = xhci::set_remote_wakeup(generation, slot_id, false);
+                    warn!("USB: slot {}: suspend failed: {:?}", slot_id, e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! TRB rings
+//!
+//! Commands and transfers are handed to the controller on producer rings:
+//! software writes TRBs at the enqueue index and flips their cycle bit to
+//! give them to the controller, which follows the link TRB at the end of the
+//! segment to the next, toggling the cycle state it expects after the last.
+//! Events come back on the event ring, where the roles are swapped.
+
+use alloc::vec::Vec;
+use core::{
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{Ordering, fence},
+};
+
+use super::{
+    init_helpers::{MAX_TRBS_PER_SEGMENT, Trb},
+    xhci::UsbError,
+};
+use crate::pci::dma::{DmaError, DynamicDmaBuffer, get_zeroed_dma};
+
+/// Index of the link TRB closing a producer ring's segment
+const LINK_INDEX: usize = MAX_TRBS_PER_SEGMENT - 1;
+
+/// Segments the command ring may grow to
+const MAX_COMMAND_SEGMENTS: usize = 16;
+
+/// A command or transfer ring
+///
+/// Starts with one segment. Each segment ends in a link TRB to the next,
+/// the last one's toggling the cycle state and leading back to the first.
+#[derive(Debug)]
+pub struct TransferRing {
+    /// Segments in the order the controller follows them
+    segments: Vec<DynamicDmaBuffer>,
+    /// Segment and index the next TRB is written at
+    enqueue: (usize, usize),
+    /// Cycle bit of TRBs handed to the controller in this pass
+    cycle: bool,
+}
+
+impl TransferRing {
+    pub(crate) fn new() -> Result<Self, DmaError> {
+        let mut ring = Self {
+            segments: Vec::from([get_zeroed_dma(1)?]),
+            enqueue: (0, 0),
+            cycle: true,
+        };
+        // a link TRB's cycle bit is set each time the ring passes it
+        ring.write(0, LINK_INDEX, Trb::link(ring.phys_addr(), true, false));
+        Ok(ring)
+    }
+
+    /// Physical address of the first segment, where the controller starts
+    pub fn phys_addr(&self) -> u64 {
+        self.segment_addr(0)
+    }
+
+    /// Cycle state the controller starts with, for the Dequeue Cycle State
+    /// of an endpoint context or the Ring Cycle State of CRCR
+    pub fn cycle(&self) -> bool {
+        self.cycle
+    }
+
+    /// Number of segments
+    pub fn segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Physical address and cycle state of the next TRB to be written, for
+    /// moving the controller's dequeue pointer past abandoned TRBs
+    pub fn enqueue_pointer(&self) -> (u64, bool) {
+        let (segment, index) = self.enqueue;
+        (self.trb_addr(segment, index), self.cycle)
+    }
+
+    fn segment_addr(&self, segment: usize) -> u64 {
+        self.segments[segment].phys_addr.as_u64()
+    }
+
+    fn trb_addr(&self, segment: usize, index: usize) -> u64 {
+        self.segment_addr(segment) + (index * size_of::<Trb>()) as u64
+    }
+
+    /// Segment and index of the TRB at `addr`, if it is on the ring
+    fn position(&self, addr: u64) -> Option<(usize, usize)> {
+        self.segments.iter().enumerate().find_map(|(segment, buffer)| {
+            let offset = addr.checked_sub(buffer.phys_addr.as_u64())? as usize;
+            (offset < LINK_INDEX * size_of::<Trb>()).then_some((segment, offset / size_of::<Trb>()))
+        })
+    }
+
+    /// Position of the TRB the controller takes after the one at `position`,
+    /// following the link TRB at the end of its segment
+    fn after(&self, (segment, index): (usize, usize)) -> (usize, usize) {
+        if index + 1 == LINK_INDEX {
+            ((segment + 1) % self.segments.len(), 0)
+        } else {
+            (segment, index + 1)
+        }
+    }
+
+    fn write(&mut self, segment: usize, index: usize, trb: Trb) {
+        let slot = unsafe { self.segments[segment].virt_addr.as_mut_ptr::<Trb>().add(index) };
+        // the control word holds the cycle bit, so it goes last: the controller
+        // must not see the TRB as its own before the rest is written
+        unsafe {
+            write_volatile(&raw mut (*slot).data, trb.data);
+            write_volatile(&raw mut (*slot).status, trb.status);
+            fence(Ordering::Release);
+            write_volatile(&raw mut (*slot).control, trb.control);
+        }
+    }
+
+    /// Hand `trb` to the controller, returning its physical address
+    ///
+    /// The controller only picks it up once the doorbell is rung.
+    pub fn push(&mut self, mut trb: Trb) -> u64 {
+        let (segment, index) = self.enqueue;
+        trb.set_cycle_bit(self.cycle);
+        let addr = self.trb_addr(segment, index);
+        self.write(segment, index, trb);
+
+        self.enqueue = self.after(self.enqueue);
+        if index + 1 == LINK_INDEX {
+            // the link is written as it is passed, so it always leads to the
+            // current next segment. A TD continuing past the end of the
+            // segment keeps its chain through it
+            let last = segment == self.segments.len() - 1;
+            let next = self.segment_addr(self.enqueue.0);
+            let mut link = Trb::link(next, last, self.cycle);
+            link.set_chain_bit(trb.chain_bit());
+            self.write(segment, LINK_INDEX, link);
+            if last {
+                self.cycle = !self.cycle;
+            }
+        }
+        addr
+    }
+
+    /// Insert an empty segment after the one being written
+    ///
+    /// The link TRB leading out of that segment isn't the controller's until
+    /// the enqueue index passes it, so the controller can't be following the
+    /// old link. If the segment was the last, the new one takes over toggling
+    /// the cycle state.
+    fn grow(&mut self) -> Result<(), DmaError> {
+        let segment = get_zeroed_dma(1)?;
+        let (current, _) = self.enqueue;
+        self.segments.insert(current + 1, segment);
+        // the controller stops at the first TRB without the current cycle
+        // bit, which a zeroed TRB has only on odd passes
+        if !self.cycle {
+            for index in 0..MAX_TRBS_PER_SEGMENT {
+                self.write(current + 1, index, Trb::with_values(0, 0, 1));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The command ring, which grows by a segment instead of overflowing when
+/// commands are queued faster than the controller completes them
+#[derive(Debug)]
+pub struct CommandRing {
+    ring: TransferRing,
+    /// Physical address of the oldest command not completed yet, the
+    /// enqueue pointer when none is
+    dequeue: u64,
+}
+
+impl CommandRing {
+    pub(crate) fn new() -> Result<Self, DmaError> {
+        let ring = TransferRing::new()?;
+        let dequeue = ring.enqueue_pointer().0;
+        Ok(Self { ring, dequeue })
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.ring.phys_addr()
+    }
+
+    pub fn cycle(&self) -> bool {
+        self.ring.cycle()
+    }
+
+    pub fn segments(&self) -> usize {
+        self.ring.segments()
+    }
+
+    /// Queue `trb`, returning its physical address
+    ///
+    /// The controller hasn't necessarily moved on from a segment it still has
+    /// commands in, so the enqueue index never enters it: a segment is put in
+    /// between instead, up to `MAX_COMMAND_SEGMENTS`. Past that, commands
+    /// are refused while the ring is full.
+    pub fn push(&mut self, trb: Trb) -> Result<u64, UsbError> {
+        let current = self.ring.enqueue;
+        let dequeue = self.ring.position(self.dequeue).unwrap_or(current);
+        let mut next = self.ring.after(current);
+        if next.1 == 0
+            && dequeue != current
+            && dequeue.0 == next.0
+            && self.ring.segments() < MAX_COMMAND_SEGMENTS
+        {
+            self.ring.grow()?;
+            next = self.ring.after(self.ring.enqueue);
+        }
+        let dequeue = self.ring.position(self.dequeue).unwrap_or(current);
+        if next == dequeue {
+            return Err(UsbError::RingFull);
+        }
+        Ok(self.ring.push(trb))
+    }
+
+    /// Note that the command at `addr` completed, so the controller is past
+    /// it
+    pub fn completed(&mut self, addr: u64) {
+        if let Some(position) = self.ring.position(addr) {
+            let (segment, index) = self.ring.after(position);
+            self.dequeue = self.ring.trb_addr(segment, index);
+        }
+    }
+}
+
+/// Entry of the Event Ring Segment Table
+#[repr(C)]
+struct EventRingSegment {
+    base: u64,
+    size: u32,
+    _reserved: u32,
+}
+
+/// The event ring of an interrupter, with its segment table
+///
+/// The controller fills the segments in table order and toggles the cycle
+/// bit it writes after the last one.
+#[derive(Debug)]
+pub struct EventRing {
+    segments: Vec<DynamicDmaBuffer>,
+    table: DynamicDmaBuffer,
+    /// Segment and index of the next event to read
+    dequeue: (usize, usize),
+    /// Cycle bit of events the controller wrote in this pass
+    cycle: bool,
+}
+
+impl EventRing {
+    /// Allocate an event ring of `segments` segments, at most as many as fit
+    /// the one page segment table
+    pub(crate) fn new(segments: usize) -> Result<Self, DmaError> {
+        let segments = segments.clamp(1, 4096 / size_of::<EventRingSegment>());
+        let table = get_zeroed_dma(1)?;
+        let segments = (0..segments)
+            .map(|_| get_zeroed_dma(1))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (index, segment) in segments.iter().enumerate() {
+            unsafe {
+                write_volatile(
+                    table.virt_addr.as_mut_ptr::<EventRingSegment>().add(index),
+                    EventRingSegment {
+                        base: segment.phys_addr.as_u64(),
+                        size: MAX_TRBS_PER_SEGMENT as u32,
+                        _reserved: 0,
+                    },
+                );
+            }
+        }
+        Ok(Self {
+            segments,
+            table,
+            dequeue: (0, 0),
+            cycle: true,
+        })
+    }
+
+    /// Physical address of the segment table, for ERSTBA
+    pub fn table_addr(&self) -> u64 {
+        self.table.phys_addr.as_u64()
+    }
+
+    /// Number of entries in the segment table, for ERSTSZ
+    pub fn segments(&self) -> u16 {
+        self.segments.len() as u16
+    }
+
+    /// Physical address of the next event to read, for ERDP
+    pub fn dequeue_pointer(&self) -> u64 {
+        let (segment, index) = self.dequeue;
+        self.segments[segment].phys_addr.as_u64() + (index * size_of::<Trb>()) as u64
+    }
+
+    /// Take the next event, if the controller wrote one
+    pub fn pop(&mut self) -> Option<Trb> {
+        let (segment, index) = self.dequeue;
+        let slot = unsafe { self.segments[segment].virt_addr.as_ptr::<Trb>().add(index) };
+        let control = unsafe { read_volatile(&raw const (*slot).control) };
+        if (control & 0x1 != 0) != self.cycle {
+            return None;
+        }
+        // the rest of the event is only valid once the cycle bit matches
+        fence(Ordering::Acquire);
+        let event = unsafe { read_volatile(slot) };
+
+        self.dequeue = if index + 1 < MAX_TRBS_PER_SEGMENT {
+            (segment, index + 1)
+        } else if segment + 1 < self.segments.len() {
+            (segment + 1, 0)
+        } else {
+            self.cycle = !self.cycle;
+            (0, 0)
+        };
+        Some(event)
+    }
+}
@@ -1,17 +1,947 @@
-use alloc::vec::Vec;
-use spin::Mutex;
+//! xHCI host controller
+//!
+//! The controller is reset, given its rings and started at probe, then every
+//! device connected to a root hub port is enumerated (see `device`). Commands
+//! and transfers are submitted on TRB rings; their completion events come
+//! back on the event ring of interrupter 0, which signals `XHCI_VECTOR`.
+//!
+//! Events are collected by whichever task is waiting for one: it drains the
+//! event ring into `completions`, keyed by the address of the TRB each event
+//! completes, takes its own and goes back to sleep if it isn't there yet.
+//!
+//! Every probe starts a new generation; devices remember the generation they
+//! were enumerated in, so requests for a device that went away with a
+//! suspend fail instead of reaching whatever took its slot.
 
-use super::{xhci_registers::XhciRegisters, init_helpers::{init_dcbaa, init_command_ring}};
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    format,
+    string::String,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering, fence};
+
+use x86_64::instructions::interrupts;
+
+use super::{
+    context::{DeviceContext, InputContext, endpoint_dci},
+    descriptors::{EndpointDescriptor, SetupPacket},
+    device,
+    init_helpers::{CompletionCode, Dcbaa, Trb, TrbType, init_command_ring, init_dcbaa, init_event_ring},
+    pm,
+    ring::{CommandRing, EventRing, TransferRing},
+    xhci_registers::{PortSc, UsbSts, XhciRegisters, link_states},
+};
 use crate::{
+    fs::sysfs::{self, Attribute},
     info,
     pci::{
         PCI_MANAGER,
+        config::command_bits,
         device::{BarInfo, PciDevice},
+        dma::DmaError,
+        msi::{MsiXInfo, setup_msix},
         vmm::map_bar,
     },
+    sync::Mutex,
+    tasks::scheduler::{kyield_task, sleep_ticks, wake_tasks},
+    time, warn,
 };
 
-pub static XHCI_REGS: Mutex<Option<XhciRegisters>> = Mutex::new(None);
+/// The controller being driven, if it is running
+pub static XHCI: Mutex<Option<XhciController>> = Mutex::new("XHCI", None);
+
+pub const XHCI_VECTOR: u8 = 0x5A;
+
+/// Generation of the running controller, see the module docs
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Events read before the dequeue pointer is advanced mid-drain
+const EVENT_ACK_BATCH: u32 = 64;
+
+/// Polls of USBSTS while waiting for the controller to halt
+const HALT_POLLS: u32 = 1_000_000;
+
+/// PCI command register offset
+const PCI_COMMAND: u16 = 0x04;
+
+/// Bytes a Normal TRB can transfer; its buffer also can't cross a multiple
+/// of this
+const MAX_TRB_TRANSFER: u64 = 64 * 1024;
+
+/// Drain the event ring and wake the tasks waiting for an event
+///
+/// If a task is using the controller, it drains the ring itself.
+pub fn handle_interrupt() {
+    if let Some(mut controller) = XHCI.try_lock()
+        && let Some(controller) = controller.as_mut()
+    {
+        controller.process_events();
+    }
+    wake_tasks(XHCI_VECTOR);
+}
+
+/// Why a USB request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbError {
+    /// No controller is running
+    NotInitialized,
+    /// The controller was reset since the device was enumerated
+    Gone,
+    AllocationFailed,
+    NoSuchSlot,
+    /// The endpoint wasn't configured
+    NoSuchEndpoint,
+    /// The buffer is too large for one transfer, or crosses a 64 KiB boundary
+    BufferTooLarge,
+    Timeout,
+    /// The device refused the request or its endpoint halted
+    Stalled,
+    /// The controller completed the request with this completion code
+    Completion(u8),
+    /// The device sent a descriptor that doesn't make sense
+    BadDescriptor,
+    /// The endpoint's transfer type doesn't support the request
+    WrongTransferType,
+    /// The device's port is suspended; transfers resume it first
+    Suspended,
+    /// The command ring is full and can't grow any further
+    RingFull,
+}
+
+impl From<DmaError> for UsbError {
+    fn from(_: DmaError) -> Self {
+        UsbError::AllocationFailed
+    }
+}
+
+/// Completion code of an event as a result
+fn check(event: Trb) -> Result<Trb, UsbError> {
+    match event.completion_code() {
+        code if code == CompletionCode::Success as u8 => Ok(event),
+        code if code == CompletionCode::ShortPacket as u8 => Ok(event),
+        code if code == CompletionCode::StallError as u8 => Err(UsbError::Stalled),
+        code => Err(UsbError::Completion(code)),
+    }
+}
+
+/// Power state of a device, see `suspend_slot`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    Active,
+    Suspending,
+    /// The port is in U3 and the endpoints are stopped
+    Suspended,
+    Resuming,
+}
+
+/// A device slot enabled by the controller
+#[derive(Debug)]
+struct Slot {
+    /// Root hub port, 1-based
+    port: u8,
+    /// Port speed, as in PORTSC
+    speed: u8,
+    /// Kept alive for the controller, which writes the slot's state into it
+    _context: DeviceContext,
+    input: InputContext,
+    /// Transfer rings by DCI
+    rings: BTreeMap<u8, TransferRing>,
+    /// DCIs of isochronous endpoints whose ring ran empty since last asked
+    ran_empty: BTreeSet<u8>,
+    power: PowerState,
+    /// Tick of the last transfer submitted
+    last_active: u64,
+    /// Whether the device may wake itself up from suspend
+    remote_wakeup: bool,
+}
+
+/// Event counters of a controller
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XhciStats {
+    /// Events read from the event ring
+    pub events: u64,
+    /// Times the controller found the event ring full and dropped events
+    pub event_ring_full: u64,
+    /// Isochronous underruns and overruns: an endpoint was due for service
+    /// with no TD queued
+    pub ring_empty: u64,
+    /// Times the command ring was full and grew by a segment
+    pub command_ring_growths: u64,
+}
+
+pub struct XhciController {
+    pub regs: XhciRegisters,
+    pub generation: u64,
+    /// Kept for the vector mapping, which must stay enabled
+    _msix_info: MsiXInfo,
+    /// Context size in bytes, 32 or 64
+    context_size: usize,
+    dcbaa: Dcbaa,
+    command_ring: CommandRing,
+    event_ring: EventRing,
+    /// Events not collected yet, by the address of the TRB they complete
+    completions: BTreeMap<u64, Trb>,
+    slots: BTreeMap<u8, Slot>,
+    /// Slots of suspended devices that signalled a remote wakeup
+    wake_requests: BTreeSet<u8>,
+    stats: XhciStats,
+}
+
+impl XhciController {
+    /// Drain the event ring into `completions`, acknowledging port changes
+    ///
+    /// Runs in the interrupt handler too, so it must not log.
+    fn process_events(&mut self) {
+        let mut unacknowledged = 0;
+        while let Some(event) = self.event_ring.pop() {
+            self.stats.events += 1;
+            unacknowledged += 1;
+            match event.trb_type() {
+                kind if kind == TrbType::CommandCompletionEvent as u8 => {
+                    self.command_ring.completed(event.command_trb_pointer());
+                    self.completions.insert(event.command_trb_pointer(), event);
+                }
+                kind if kind == TrbType::TransferEvent as u8 => {
+                    let code = event.completion_code();
+                    if code == CompletionCode::RingUnderrun as u8
+                        || code == CompletionCode::RingOverrun as u8
+                    {
+                        // these complete no TD, they only report the ring
+                        self.stats.ring_empty += 1;
+                        if let Some(slot) = self.slots.get_mut(&event.slot_id()) {
+                            slot.ran_empty.insert(event.endpoint_id());
+                        }
+                    } else if code == CompletionCode::Stopped as u8
+                        || code == CompletionCode::StoppedLengthInvalid as u8
+                        || code == CompletionCode::StoppedShortPacket as u8
+                    {
+                        // the TD carries on once the endpoint is restarted,
+                        // or is abandoned by whoever stopped it
+                    } else {
+                        self.completions.insert(event.trb_pointer(), event);
+                    }
+                }
+                kind if kind == TrbType::PortStatusChangeEvent as u8 => {
+                    let port = event.port_id();
+                    let portsc = self.regs.port_sc(port);
+                    self.regs
+                        .set_port_sc(port, PortSc(portsc.neutral().0 | portsc.changes()));
+                    // a suspended device leaving U3 by itself woke up remotely
+                    let link_state = portsc.port_link_state();
+                    if link_state == link_states::RESUME || link_state == link_states::U0 {
+                        for (slot_id, slot) in &self.slots {
+                            if slot.port == port && slot.power == PowerState::Suspended {
+                                self.wake_requests.insert(*slot_id);
+                            }
+                        }
+                    }
+                }
+                kind if kind == TrbType::HostControllerEvent as u8 => {
+                    if event.completion_code() == CompletionCode::EventRingFullError as u8 {
+                        self.stats.event_ring_full += 1;
+                    }
+                }
+                _ => {}
+            }
+
+            // hand consumed entries back early, so a burst of isochronous
+            // completions doesn't fill the ring while it is being drained
+            if unacknowledged == EVENT_ACK_BATCH {
+                self.regs
+                    .set_event_ring_dequeue_pointer(0, self.event_ring.dequeue_pointer());
+                unacknowledged = 0;
+            }
+        }
+
+        if unacknowledged > 0 {
+            self.regs
+                .set_event_ring_dequeue_pointer(0, self.event_ring.dequeue_pointer());
+        }
+        let mut usb_sts = UsbSts(0);
+        usb_sts.clear_event_interrupt();
+        self.regs.set_usb_sts(usb_sts);
+    }
+
+    fn slot(&mut self, slot_id: u8) -> Result<&mut Slot, UsbError> {
+        self.slots.get_mut(&slot_id).ok_or(UsbError::NoSuchSlot)
+    }
+}
+
+/// Run `f` on the controller of `generation`
+fn with_controller<R>(
+    generation: u64,
+    f: impl FnOnce(&mut XhciController) -> Result<R, UsbError>,
+) -> Result<R, UsbError> {
+    interrupts::without_interrupts(|| match XHCI.lock().as_mut() {
+        Some(controller) if controller.generation == generation => f(controller),
+        Some(_) => Err(UsbError::Gone),
+        None => Err(UsbError::NotInitialized),
+    })
+}
+
+/// Take the completion event of one of `trbs`, if the controller wrote it
+pub fn poll_completion(generation: u64, trbs: &[u64]) -> Result<Option<Trb>, UsbError> {
+    with_controller(generation, |controller| {
+        controller.process_events();
+        Ok(trbs
+            .iter()
+            .find_map(|trb| controller.completions.remove(trb)))
+    })
+}
+
+/// Sleep until one of `trbs` completes, returning its completion event
+///
+/// Must be called from a task with interrupts enabled.
+pub fn wait_completion(generation: u64, trbs: &[u64]) -> Result<Trb, UsbError> {
+    loop {
+        // interrupts stay off until kyield_task has marked us as waiting, so
+        // the completion interrupt can't slip in between polling and sleeping
+        interrupts::disable();
+        let event = match poll_completion(generation, trbs) {
+            Ok(event) => event,
+            Err(e) => {
+                interrupts::enable();
+                return Err(e);
+            }
+        };
+        if let Some(event) = event {
+            interrupts::enable();
+            return Ok(event);
+        }
+        kyield_task(XHCI_VECTOR);
+    }
+}
+
+/// Run a command and wait for it to complete
+fn command(generation: u64, trb: Trb) -> Result<Trb, UsbError> {
+    let addr = with_controller(generation, |controller| {
+        let segments = controller.command_ring.segments();
+        let addr = controller.command_ring.push(trb)?;
+        if controller.command_ring.segments() > segments {
+            controller.stats.command_ring_growths += 1;
+        }
+        fence(Ordering::SeqCst);
+        controller.regs.ring_hc_doorbell(0);
+        Ok(addr)
+    })?;
+    check(wait_completion(generation, &[addr])?)
+}
+
+/// Generation of the running controller
+pub fn generation() -> Result<u64, UsbError> {
+    interrupts::without_interrupts(|| {
+        XHCI.lock()
+            .as_ref()
+            .map(|controller| controller.generation)
+            .ok_or(UsbError::NotInitialized)
+    })
+}
+
+/// Number of root hub ports
+pub fn max_ports(generation: u64) -> Result<u8, UsbError> {
+    with_controller(generation, |controller| {
+        Ok(controller.regs.capability().hcs_params1.max_ports())
+    })
+}
+
+pub fn port_sc(generation: u64, port: u8) -> Result<PortSc, UsbError> {
+    with_controller(generation, |controller| Ok(controller.regs.port_sc(port)))
+}
+
+/// Start resetting a root hub port
+pub fn start_port_reset(generation: u64, port: u8) -> Result<(), UsbError> {
+    with_controller(generation, |controller| {
+        let mut portsc = controller.regs.port_sc(port).neutral();
+        portsc.set_port_reset(true);
+        controller.regs.set_port_sc(port, portsc);
+        Ok(())
+    })
+}
+
+/// Turn the power of a root hub port on or off
+///
+/// Only controllers with port power control can; on others the ports are
+/// always powered.
+pub fn set_port_power(generation: u64, port: u8, on: bool) -> Result<(), &'static str> {
+    with_controller(generation, |controller| {
+        if !controller.regs.capability().hcc_params1.ppc() {
+            return Ok(Err("controller has no port power control"));
+        }
+        let mut portsc = controller.regs.port_sc(port).neutral();
+        portsc.set_port_power(on);
+        controller.regs.set_port_sc(port, portsc);
+        Ok(Ok(()))
+    })
+    .map_err(|_| "controller not running")?
+}
+
+/// Attributes of every root hub port, under `/sys/devices/xhci0-port<N>`,
+/// the context being the port
+static PORT_ATTRIBUTES: &[Attribute] = &[
+    Attribute {
+        name: "connected",
+        show: |port| {
+            let portsc = current_port_sc(port)?;
+            Ok(String::from(if portsc.current_connect_status() { "1" } else { "0" }))
+        },
+        store: None,
+    },
+    Attribute {
+        name: "power",
+        show: |port| {
+            let portsc = current_port_sc(port)?;
+            Ok(String::from(if portsc.port_power() { "on" } else { "off" }))
+        },
+        store: Some(|port, value| {
+            let on = match value {
+                "on" | "1" => true,
+                "off" | "0" => false,
+                _ => return Err("expected on or off"),
+            };
+            let generation = generation().map_err(|_| "controller not running")?;
+            set_port_power(generation, port as u8, on)
+        }),
+    },
+    Attribute {
+        name: "speed",
+        show: |port| Ok(format!("{}", current_port_sc(port)?.port_speed())),
+        store: None,
+    },
+];
+
+/// PORTSC of `port` on the running controller
+fn current_port_sc(port: usize) -> Result<PortSc, &'static str> {
+    generation()
+        .and_then(|generation| port_sc(generation, port as u8))
+        .map_err(|_| "controller not running")
+}
+
+fn port_sysfs_name(port: u8) -> String {
+    format!("xhci0-port{port}")
+}
+
+/// Enable a device slot for the device on `port` and give it an address
+///
+/// Returns the slot ID.
+pub fn address_device(
+    generation: u64,
+    port: u8,
+    speed: u8,
+    max_packet_size: u16,
+) -> Result<u8, UsbError> {
+    let event = command(generation, Trb::enable_slot_command(0, false))?;
+    let slot_id = event.slot_id();
+
+    let input_addr = with_controller(generation, |controller| {
+        let context = DeviceContext::new()?;
+        let mut input = InputContext::new(controller.context_size)?;
+        let ring = TransferRing::new()?;
+
+        input.add(0);
+        input.add(1);
+        input.set_slot(speed, port, 1);
+        input.set_control_endpoint(max_packet_size, ring.phys_addr(), ring.cycle());
+
+        controller.dcbaa.set(slot_id, context.phys_addr());
+        let input_addr = input.phys_addr();
+        controller.slots.insert(
+            slot_id,
+            Slot {
+                port,
+                speed,
+                _context: context,
+                input,
+                rings: BTreeMap::from([(1, ring)]),
+                ran_empty: BTreeSet::new(),
+                power: PowerState::Active,
+                last_active: time::ticks(),
+                remote_wakeup: false,
+            },
+        );
+        Ok(input_addr)
+    })?;
+
+    if let Err(e) = command(
+        generation,
+        Trb::address_device_command(input_addr, slot_id, false, false),
+    ) {
+        let _ = disable_slot(generation, slot_id);
+        return Err(e);
+    }
+    Ok(slot_id)
+}
+
+/// Update the max packet size of the default control endpoint, once the
+/// device descriptor gave it
+pub fn set_control_max_packet_size(
+    generation: u64,
+    slot_id: u8,
+    max_packet_size: u16,
+) -> Result<(), UsbError> {
+    let input_addr = with_controller(generation, |controller| {
+        let slot = controller.slot(slot_id)?;
+        let (dequeue, cycle) = slot.rings[&1].enqueue_pointer();
+        slot.input.clear();
+        slot.input.add(1);
+        slot.input.set_control_endpoint(max_packet_size, dequeue, cycle);
+        Ok(slot.input.phys_addr())
+    })?;
+    command(
+        generation,
+        Trb::evaluate_context_command(input_addr, slot_id, false),
+    )
+    .map(|_| ())
+}
+
+/// Configure the endpoints given by their descriptors, giving each a
+/// transfer ring
+pub fn configure_endpoints(
+    generation: u64,
+    slot_id: u8,
+    root_port: u8,
+    endpoints: &[EndpointDescriptor],
+) -> Result<(), UsbError> {
+    let input_addr = with_controller(generation, |controller| {
+        let slot = controller.slot(slot_id)?;
+        let speed = slot.speed;
+        slot.input.clear();
+        slot.input.add(0);
+
+        for endpoint in endpoints {
+            let dci = endpoint_dci(endpoint.address);
+            let ring = TransferRing::new()?;
+            slot.input.add(dci);
+            slot.input
+                .set_endpoint(endpoint, speed, ring.phys_addr(), ring.cycle());
+            slot.rings.insert(dci, ring);
+        }
+        let last_dci = slot.rings.keys().copied().max().unwrap_or(1);
+        slot.input.set_slot(speed, root_port, last_dci);
+        Ok(slot.input.phys_addr())
+    })?;
+    command(
+        generation,
+        Trb::configure_endpoint_command(input_addr, slot_id, false, false),
+    )
+    .map(|_| ())
+}
+
+/// Disable a slot, freeing its contexts and rings
+pub fn disable_slot(generation: u64, slot_id: u8) -> Result<(), UsbError> {
+    let result = command(generation, Trb::disable_slot_command(slot_id, false));
+    with_controller(generation, |controller| {
+        controller.dcbaa.set(slot_id, 0);
+        controller.slots.remove(&slot_id);
+        Ok(())
+    })?;
+    result.map(|_| ())
+}
+
+/// Clear a halted endpoint so it accepts transfers again, dropping the TRBs
+/// that were queued on it
+pub fn reset_endpoint(generation: u64, slot_id: u8, dci: u8) -> Result<(), UsbError> {
+    command(
+        generation,
+        Trb::reset_endpoint_command(slot_id, dci, false),
+    )?;
+    let (dequeue, cycle) = with_controller(generation, |controller| {
+        let ring = controller
+            .slot(slot_id)?
+            .rings
+            .get(&dci)
+            .ok_or(UsbError::NoSuchEndpoint)?;
+        Ok(ring.enqueue_pointer())
+    })?;
+    command(
+        generation,
+        Trb::set_tr_dequeue_pointer_command(dequeue, cycle, slot_id, dci, false),
+    )
+    .map(|_| ())
+}
+
+/// Queue TRBs on the transfer ring of an endpoint and ring its doorbell,
+/// returning the TRB addresses
+///
+/// A suspended device is resumed first.
+fn submit(generation: u64, slot_id: u8, dci: u8, trbs: &[Trb]) -> Result<Vec<u64>, UsbError> {
+    loop {
+        match try_submit(generation, slot_id, dci, trbs) {
+            Err(UsbError::Suspended) => resume_slot(generation, slot_id)?,
+            result => return result,
+        }
+    }
+}
+
+fn try_submit(generation: u64, slot_id: u8, dci: u8, trbs: &[Trb]) -> Result<Vec<u64>, UsbError> {
+    with_controller(generation, |controller| {
+        let slot = controller.slot(slot_id)?;
+        if slot.power != PowerState::Active {
+            return Err(UsbError::Suspended);
+        }
+        slot.last_active = time::ticks();
+        let ring = slot.rings.get_mut(&dci).ok_or(UsbError::NoSuchEndpoint)?;
+        let addrs = trbs.iter().map(|trb| ring.push(*trb)).collect();
+        fence(Ordering::SeqCst);
+        controller.regs.ring_doorbell(slot_id, dci, 0);
+        Ok(addrs)
+    })
+}
+
+/// Run a control transfer on the default control endpoint
+///
+/// `data` is the physical address of the data stage buffer, at least
+/// `setup.length` bytes long. Returns the number of bytes transferred.
+pub fn control_transfer(
+    generation: u64,
+    slot_id: u8,
+    setup: SetupPacket,
+    data: u64,
+) -> Result<usize, UsbError> {
+    let length = setup.length as u32;
+    let (transfer_type, status_in) = match (length, setup.is_in()) {
+        (0, _) => (0, true),
+        (_, true) => (3, false),
+        (_, false) => (2, true),
+    };
+
+    let mut trbs = Vec::with_capacity(3);
+    trbs.push(Trb::setup_stage(setup.to_u64(), 8, true, transfer_type, false));
+    if length > 0 {
+        let mut data_stage = Trb::data_stage(data, length, setup.is_in(), false);
+        data_stage.set_interrupt_on_short_packet(true);
+        trbs.push(data_stage);
+    }
+    trbs.push(Trb::status_stage(status_in, true, false));
+
+    let addrs = submit(generation, slot_id, 1, &trbs)?;
+    let mut transferred = length as usize;
+    loop {
+        let event = wait_completion(generation, &addrs);
+        let event = match event.and_then(check) {
+            Ok(event) => event,
+            Err(UsbError::Stalled) => {
+                // a stall only halts the control endpoint until the next
+                // setup packet, but the controller still needs a reset
+                reset_endpoint(generation, slot_id, 1)?;
+                return Err(UsbError::Stalled);
+            }
+            Err(e) => return Err(e),
+        };
+        if length > 0 && event.trb_pointer() == addrs[1] {
+            // a short data stage, the status stage completes separately
+            transferred = (length - event.transfer_length()) as usize;
+            continue;
+        }
+        return Ok(transferred);
+    }
+}
+
+/// A bulk or interrupt transfer in flight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    generation: u64,
+    trb: u64,
+    length: u32,
+}
+
+impl Transfer {
+    /// Bytes transferred, if the transfer completed
+    pub fn poll(&self) -> Result<Option<usize>, UsbError> {
+        poll_completion(self.generation, &[self.trb])?
+            .map(|event| self.transferred(event))
+            .transpose()
+    }
+
+    /// Sleep until the transfer completes, returning the bytes transferred
+    pub fn wait(&self) -> Result<usize, UsbError> {
+        let event = wait_completion(self.generation, &[self.trb])?;
+        self.transferred(event)
+    }
+
+    fn transferred(&self, event: Trb) -> Result<usize, UsbError> {
+        check(event).map(|event| (self.length - event.transfer_length().min(self.length)) as usize)
+    }
+}
+
+/// Start a bulk or interrupt transfer of `length` bytes at physical address
+/// `buffer` on the endpoint with DCI `dci`
+pub fn submit_transfer(
+    generation: u64,
+    slot_id: u8,
+    dci: u8,
+    buffer: u64,
+    length: u32,
+) -> Result<Transfer, UsbError> {
+    let length_u64 = length as u64;
+    if length_u64 > MAX_TRB_TRANSFER
+        || (length > 0 && buffer / MAX_TRB_TRANSFER != (buffer + length_u64 - 1) / MAX_TRB_TRANSFER)
+    {
+        return Err(UsbError::BufferTooLarge);
+    }
+    let mut trb = Trb::normal_transfer(buffer, length, 0, true, false);
+    trb.set_interrupt_on_short_packet(true);
+    let addrs = submit(generation, slot_id, dci, &[trb])?;
+    Ok(Transfer {
+        generation,
+        trb: addrs[0],
+        length,
+    })
+}
+
+/// Queue an isochronous TD made of one Isoch TRB, returning its address
+pub fn submit_isoch(generation: u64, slot_id: u8, dci: u8, trb: Trb) -> Result<u64, UsbError> {
+    Ok(submit(generation, slot_id, dci, &[trb])?[0])
+}
+
+/// Whether an isochronous endpoint's ring ran empty since the last call
+///
+/// The controller then drops out of sync with the endpoint's schedule, so
+/// the next TD has to be scheduled afresh.
+pub fn take_ran_empty(generation: u64, slot_id: u8, dci: u8) -> Result<bool, UsbError> {
+    with_controller(generation, |controller| {
+        controller.process_events();
+        Ok(controller.slot(slot_id)?.ran_empty.remove(&dci))
+    })
+}
+
+/// Drop the completion events of `trbs`, for TDs nobody waits for anymore
+pub fn forget_completions(generation: u64, trbs: &[u64]) -> Result<(), UsbError> {
+    with_controller(generation, |controller| {
+        controller.process_events();
+        for trb in trbs {
+            controller.completions.remove(trb);
+        }
+        Ok(())
+    })
+}
+
+/// Stop an endpoint and move its dequeue pointer past everything queued,
+/// abandoning the TDs in flight
+pub fn stop_endpoint(generation: u64, slot_id: u8, dci: u8) -> Result<(), UsbError> {
+    command(
+        generation,
+        Trb::stop_endpoint_command(slot_id, dci, false, false),
+    )?;
+    let (dequeue, cycle) = with_controller(generation, |controller| {
+        let slot = controller.slot(slot_id)?;
+        slot.ran_empty.remove(&dci);
+        let ring = slot.rings.get(&dci).ok_or(UsbError::NoSuchEndpoint)?;
+        Ok(ring.enqueue_pointer())
+    })?;
+    command(
+        generation,
+        Trb::set_tr_dequeue_pointer_command(dequeue, cycle, slot_id, dci, false),
+    )
+    .map(|_| ())
+}
+
+/// Current microframe, counting 125 µs units modulo 2^14
+pub fn microframe_index(generation: u64) -> Result<u32, UsbError> {
+    with_controller(generation, |controller| Ok(controller.regs.mfindex() & 0x3FFF))
+}
+
+/// How many microframes ahead of the current one an isochronous TD must be
+/// queued for the controller to still schedule it (IST)
+pub fn isoch_scheduling_threshold(generation: u64) -> Result<u32, UsbError> {
+    with_controller(generation, |controller| {
+        let ist = controller.regs.capability().hcs_params2.ist() as u32;
+        // bit 3 says the threshold is given in whole frames
+        Ok(if ist & 0x8 != 0 {
+            (ist & 0x7) * 8
+        } else {
+            ist & 0x7
+        })
+    })
+}
+
+/// Longest a port may take to enter or leave U3
+const LINK_TRANSITION_TIMEOUT_MS: u64 = 100;
+
+/// Time the host drives resume signalling on a USB 2 port (USB 2.0, 7.1.7.7)
+const RESUME_SIGNALLING_MS: u64 = 20;
+
+/// Power state of a device
+pub fn power_state(generation: u64, slot_id: u8) -> Result<PowerState, UsbError> {
+    with_controller(generation, |controller| Ok(controller.slot(slot_id)?.power))
+}
+
+/// Let a suspended device wake itself up, once it was told it may
+pub fn set_remote_wakeup(generation: u64, slot_id: u8, enabled: bool) -> Result<(), UsbError> {
+    with_controller(generation, |controller| {
+        controller.slot(slot_id)?.remote_wakeup = enabled;
+        Ok(())
+    })
+}
+
+/// Slots of active devices that may wake themselves up and submitted no
+/// transfer for `idle_ticks`
+pub fn idle_slots(generation: u64, idle_ticks: u64) -> Result<Vec<u8>, UsbError> {
+    let now = time::ticks();
+    with_controller(generation, |controller| {
+        Ok(controller
+            .slots
+            .iter()
+            .filter(|(_, slot)| {
+                slot.power == PowerState::Active
+                    && slot.remote_wakeup
+                    && now.saturating_sub(slot.last_active) >= idle_ticks
+            })
+            .map(|(slot_id, _)| *slot_id)
+            .collect())
+    })
+}
+
+/// Slots of suspended devices that signalled a remote wakeup since the last
+/// call
+pub fn take_wake_requests(generation: u64) -> Result<Vec<u8>, UsbError> {
+    with_controller(generation, |controller| {
+        controller.process_events();
+        Ok(core::mem::take(&mut controller.wake_requests)
+            .into_iter()
+            .collect())
+    })
+}
+
+/// Move a port to a link state and wait until it gets there
+fn set_link_state(generation: u64, port: u8, link_state: u8) -> Result<(), UsbError> {
+    with_controller(generation, |controller| {
+        let mut portsc = controller.regs.port_sc(port).neutral();
+        portsc.set_port_link_state(link_state);
+        portsc.set_port_link_state_write_strobe(true);
+        controller.regs.set_port_sc(port, portsc);
+        Ok(())
+    })?;
+    wait_link_state(generation, port, link_state)
+}
+
+fn wait_link_state(generation: u64, port: u8, link_state: u8) -> Result<(), UsbError> {
+    let deadline = time::ticks() + time::ms_to_ticks(LINK_TRANSITION_TIMEOUT_MS).max(1);
+    while port_sc(generation, port)?.port_link_state() != link_state {
+        if time::ticks() >= deadline {
+            return Err(UsbError::Timeout);
+        }
+        sleep_ticks(1);
+    }
+    Ok(())
+}
+
+/// Change a slot's power state from `from` to `to`, returning whether it was
+/// in `from`
+fn transition(
+    generation: u64,
+    slot_id: u8,
+    from: PowerState,
+    to: PowerState,
+) -> Result<bool, UsbError> {
+    with_controller(generation, |controller| {
+        let slot = controller.slot(slot_id)?;
+        let matched = slot.power == from;
+        if matched {
+            slot.power = to;
+        }
+        Ok(matched)
+    })
+}
+
+/// Ring the doorbell of every endpoint of a slot, restarting stopped ones
+fn restart_endpoints(generation: u64, slot_id: u8) -> Result<(), UsbError> {
+    with_controller(generation, |controller| {
+        let dcis: Vec<u8> = controller.slot(slot_id)?.rings.keys().copied().collect();
+        for dci in dcis {
+            controller.regs.ring_doorbell(slot_id, dci, 0);
+        }
+        Ok(())
+    })
+}
+
+/// Suspend an active device: stop its endpoints and put its port in U3
+///
+/// Transfers in flight carry on after the device resumes, which happens when
+/// a new transfer is submitted or the device wakes itself up.
+pub fn suspend_slot(generation: u64, slot_id: u8) -> Result<(), UsbError> {
+    if !transition(generation, slot_id, PowerState::Active, PowerState::Suspending)? {
+        return Ok(());
+    }
+    let result = stop_and_suspend(generation, slot_id);
+    let power = if result.is_ok() {
+        PowerState::Suspended
+    } else {
+        let _ = restart_endpoints(generation, slot_id);
+        PowerState::Active
+    };
+    transition(generation, slot_id, PowerState::Suspending, power)?;
+    result
+}
+
+fn stop_and_suspend(generation: u64, slot_id: u8) -> Result<(), UsbError> {
+    let (port, dcis) = with_controller(generation, |controller| {
+        let slot = controller.slot(slot_id)?;
+        Ok((slot.port, slot.rings.keys().copied().collect::<Vec<u8>>()))
+    })?;
+    for dci in dcis {
+        match command(generation, Trb::stop_endpoint_command(slot_id, dci, true, false)) {
+            // an endpoint that isn't running has nothing to stop
+            Ok(_) => {}
+            Err(UsbError::Completion(code)) if code == CompletionCode::ContextStateError as u8 => {}
+            Err(e) => return Err(e),
+        }
+    }
+    set_link_state(generation, port, link_states::U3)
+}
+
+/// Bring a suspended device back to U0 and restart its endpoints
+///
+/// Waits for a suspend or resume in progress to finish first.
+pub fn resume_slot(generation: u64, slot_id: u8) -> Result<(), UsbError> {
+    loop {
+        match power_state(generation, slot_id)? {
+            PowerState::Active => return Ok(()),
+            PowerState::Suspended => {
+                if transition(generation, slot_id, PowerState::Suspended, PowerState::Resuming)? {
+                    break;
+                }
+            }
+            PowerState::Suspending | PowerState::Resuming => sleep_ticks(1),
+        }
+    }
+
+    let result = wake_port(generation, slot_id);
+    // a device that can't be woken is left for re-enumeration to pick up,
+    // marked active so transfers fail instead of retrying forever
+    transition(generation, slot_id, PowerState::Resuming, PowerState::Active)?;
+    result?;
+    restart_endpoints(generation, slot_id)?;
+    with_controller(generation, |controller| {
+        controller.slot(slot_id)?.last_active = time::ticks();
+        Ok(())
+    })
+}
+
+fn wake_port(generation: u64, slot_id: u8) -> Result<(), UsbError> {
+    let (port, speed) = with_controller(generation, |controller| {
+        let slot = controller.slot(slot_id)?;
+        Ok((slot.port, slot.speed))
+    })?;
+    let link_state = port_sc(generation, port)?.port_link_state();
+    // SuperSpeed links go straight back to U0; USB 2 ports need resume
+    // signalling first, which the device already started on a remote wakeup
+    if speed < 4 && link_state != link_states::U0 {
+        if link_state == link_states::U3 {
+            set_link_state(generation, port, link_states::RESUME)?;
+        }
+        sleep_ticks(time::ms_to_ticks(RESUME_SIGNALLING_MS).max(1));
+    }
+    if port_sc(generation, port)?.port_link_state() != link_states::U0 {
+        set_link_state(generation, port, link_states::U0)?;
+    }
+    Ok(())
+}
+
+/// Event counters of the running controller
+pub fn stats() -> Option<XhciStats> {
+    interrupts::without_interrupts(|| XHCI.lock().as_ref().map(|controller| controller.stats))
+}
 
 #[allow(clippy::let_and_return)]
 pub fn find_xhci_devices() -> Vec<PciDevice> {
@@ -30,24 +960,18 @@ pub fn find_xhci_devices() -> Vec<PciDevice> {
     xhci_devices
 }
 
-/// find xhci devices and resets the controller.
-/// 
-/// allocates the dcbas
-/// 
-/// at the end, populates the XHCI_REGS static.
-pub fn xhci_init() {
-    let devices = find_xhci_devices();
-    let Some(primary_device) = devices.first() else {
-        info!("No XHCI devices found");
-        return;
-    };
-
-    assert!(
-        primary_device.supports_msix(),
-        "XHCI device does not support MSI-X"
-    );
+/// resets an xhci controller.
+///
+/// allocates the dcbaa and the rings, starts the controller and enumerates
+/// the devices on its ports.
+///
+/// populates the XHCI static before enumerating.
+pub fn xhci_init(primary_device: PciDevice) -> Result<(), String> {
+    if !primary_device.supports_msix() {
+        return Err("XHCI device does not support MSI-X".into());
+    }
 
-    let memory_bar = &primary_device
+    let memory_bar = primary_device
         .bars
         .iter()
         .find_map(|bar| {
@@ -57,9 +981,16 @@ pub fn xhci_init() {
                 None
             }
         })
-        .unwrap();
+        .ok_or("XHCI device has no memory BAR")?;
 
-    let mapped_bar = map_bar(memory_bar).unwrap();
+    let mapped_bar = map_bar(memory_bar).map_err(|e| format!("failed to map BAR: {e:?}"))?;
+
+    // the controller reads and writes its rings and contexts itself
+    let command = primary_device.read_config_u16(PCI_COMMAND);
+    primary_device.write_config_u16(
+        PCI_COMMAND,
+        command | command_bits::MEMORY_SPACE | command_bits::BUS_MASTER,
+    );
 
     // Create xHCI register accessor
     let mut xhci_regs = unsafe { XhciRegisters::new(mapped_bar.virtual_address) };
@@ -131,30 +1062,136 @@ pub fn xhci_init() {
     }
     info!("Controller reset complete and ready");
 
+    // rings and contexts are allocated one 4 KiB frame at a time
+    if xhci_regs.page_size() & 0x1 == 0 {
+        return Err("XHCI controller does not support 4 KiB pages".into());
+    }
+
     let max_slots = xhci_regs.capability().hcs_params1.max_device_slots();
     let mut config = xhci_regs.config();
     config.set_max_device_slots_enabled(max_slots);
     xhci_regs.set_config(config);
     info!("Configured {} device slots", max_slots);
 
-    init_dcbaa(&mut xhci_regs);
+    let allocation_failed = |_| String::from("failed to allocate DMA memory");
+    let dcbaa = init_dcbaa(&mut xhci_regs).map_err(allocation_failed)?;
+    let command_ring = init_command_ring(&mut xhci_regs).map_err(allocation_failed)?;
+    let event_ring = init_event_ring(&mut xhci_regs).map_err(allocation_failed)?;
 
-    init_command_ring(&mut xhci_regs);
+    let mut msix_info = setup_msix(&primary_device, 1, XHCI_VECTOR)
+        .map_err(|e| format!("failed to set up MSI-X: {e:?}"))?;
+    msix_info
+        .enable_vector(0)
+        .map_err(|e| format!("failed to enable MSI-X vector: {e:?}"))?;
 
-    let max_ports = xhci_regs.capability().hcs_params1.max_ports();
-    for port in 1..=max_ports {
-        let portsc = xhci_regs.port_sc(port);
-        if portsc.current_connect_status() {
-            info!(
-                "Port {}: Device connected (speed: {})",
-                port,
-                portsc.port_speed()
-            );
-        } else {
-            info!("Port {}: No device connected", port);
+    let mut usb_cmd = xhci_regs.usb_cmd();
+    usb_cmd.set_interrupter_enable(true);
+    usb_cmd.set_run_stop(true);
+    xhci_regs.set_usb_cmd(usb_cmd);
+    for _ in 0..HALT_POLLS {
+        if !xhci_regs.usb_sts().hc_halted() {
+            break;
         }
+        core::hint::spin_loop();
+    }
+    if xhci_regs.usb_sts().hc_halted() {
+        return Err("XHCI controller did not start".into());
+    }
+
+    let context_size = if xhci_regs.capability().hcc_params1.csz() {
+        64
+    } else {
+        32
+    };
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    let controller = XhciController {
+        regs: xhci_regs,
+        generation,
+        _msix_info: msix_info,
+        context_size,
+        dcbaa,
+        command_ring,
+        event_ring,
+        completions: BTreeMap::new(),
+        slots: BTreeMap::new(),
+        wake_requests: BTreeSet::new(),
+        stats: XhciStats::default(),
+    };
+    let max_ports = controller.regs.capability().hcs_params1.max_ports();
+    interrupts::without_interrupts(|| *XHCI.lock() = Some(controller));
+    info!("xHCI controller running");
+    for port in 1..=max_ports {
+        let _ = sysfs::register(&port_sysfs_name(port), PORT_ATTRIBUTES, port as usize);
     }
 
-    *XHCI_REGS.lock() = Some(xhci_regs);
+    pm::spawn_task();
+    device::enumerate_ports(generation);
     info!("xHCI initialization complete");
+    Ok(())
+}
+
+/// halts the xhci controller and drops its state.
+///
+/// the controller is brought back by calling xhci_init again.
+pub fn xhci_halt() -> Result<(), String> {
+    let controller = interrupts::without_interrupts(|| XHCI.lock().take())
+        .ok_or("XHCI controller not initialized")?;
+    // tasks waiting for a transfer find the controller gone
+    interrupts::without_interrupts(|| wake_tasks(XHCI_VECTOR));
+    let xhci_regs = &controller.regs;
+    for port in 1..=xhci_regs.capability().hcs_params1.max_ports() {
+        sysfs::unregister(&port_sysfs_name(port));
+    }
+
+    let mut usb_cmd = xhci_regs.usb_cmd();
+    usb_cmd.set_run_stop(false);
+    xhci_regs.set_usb_cmd(usb_cmd);
+
+    for _ in 0..HALT_POLLS {
+        if xhci_regs.usb_sts().hc_halted() {
+            info!("Controller halted for suspend");
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+
+    // the rings may still be in use, so they can't be freed
+    core::mem::forget(controller);
+    Err("XHCI controller did not halt".into())
+}
+
+/// Check the controller for a Host Controller or Host System Error,
+/// describing it if set
+pub fn health() -> Result<(), String> {
+    let status = interrupts::without_interrupts(|| {
+        XHCI.lock()
+            .as_ref()
+            .map(|controller| controller.regs.usb_sts())
+    });
+    match status {
+        Some(status) if status.hc_error() || status.host_system_error() => Err(format!(
+            "{} (USBSTS {:#x}, halted: {})",
+            if status.hc_error() {
+                "host controller error"
+            } else {
+                "host system error"
+            },
+            status.0,
+            status.hc_halted()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Bring the controller back after an error by halting it and probing it
+/// again
+///
+/// The devices are enumerated again in a new generation, so requests for
+/// the old ones fail with `Gone`.
+pub fn reset(device: PciDevice) -> Result<(), String> {
+    #[allow(unused_variables)]
+    if let Err(e) = xhci_halt() {
+        warn!("{}, resetting it anyway", e);
+    }
+    xhci_init(device)
 }
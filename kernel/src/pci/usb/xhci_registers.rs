@@ -581,6 +581,15 @@ impl Config {
     }
 }
 
+/// Port link states (PORTSC.PLS)
+pub mod link_states {
+    pub const U0: u8 = 0;
+    /// Suspended
+    pub const U3: u8 = 3;
+    /// USB 2 resume signalling, driven by the host or the device
+    pub const RESUME: u8 = 15;
+}
+
 /// Port Status and Control Register (PORTSC)
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
@@ -796,6 +805,21 @@ impl PortSc {
         (self.0 & 0x80000000) != 0
     }
 
+    /// The register value to write back without changing anything
+    ///
+    /// Keeps the bits that are written as read (power, indicators, wake
+    /// enables) and clears the ones that act when written as 1: the change
+    /// bits, which would be cleared, and Port Enabled, which would disable
+    /// the port.
+    pub fn neutral(&self) -> PortSc {
+        PortSc(self.0 & 0x0E00C3E0)
+    }
+
+    /// All change bits that are set, to be cleared by writing them back
+    pub fn changes(&self) -> u32 {
+        self.0 & 0x00FE0000
+    }
+
     pub fn set_warm_port_reset(&mut self, value: bool) {
         if value {
             self.0 |= 0x80000000;
@@ -1084,6 +1108,45 @@ impl XhciRegisters {
         }
     }
 
+    fn interrupter(&self, interrupter: u16) -> *mut InterrupterRegisterSet {
+        assert!(
+            interrupter < self.capability_regs.hcs_params1.max_interrupters(),
+            "Interrupter {interrupter} out of range"
+        );
+        // the register sets follow each other, RuntimeRegisters only declares the first
+        unsafe {
+            (&raw mut (*self.runtime_regs).interrupters)
+                .cast::<InterrupterRegisterSet>()
+                .add(interrupter as usize)
+        }
+    }
+
+    /// Set Interrupter Moderation register for a specific interrupter
+    pub fn set_interrupter_moderation(&self, interrupter: u16, imod: InterrupterModeration) {
+        unsafe { write_volatile(&raw mut (*self.interrupter(interrupter)).imod, imod) }
+    }
+
+    /// Set Event Ring Segment Table Size register (number of segments)
+    pub fn set_event_ring_segment_table_size(&self, interrupter: u16, segments: u16) {
+        unsafe { write_volatile(&raw mut (*self.interrupter(interrupter)).erstsz, segments as u32) }
+    }
+
+    /// Set Event Ring Segment Table Base Address register
+    ///
+    /// Enables the event ring, so the table size and the dequeue pointer must
+    /// be set first.
+    pub fn set_event_ring_segment_table_base(&self, interrupter: u16, addr: u64) {
+        assert_eq!(addr & 0x3F, 0, "Event ring segment table must be 64-byte aligned");
+        unsafe { write_volatile(&raw mut (*self.interrupter(interrupter)).erstba, addr) }
+    }
+
+    /// Set Event Ring Dequeue Pointer register, also clearing the Event
+    /// Handler Busy bit (bit 3, write 1 to clear)
+    pub fn set_event_ring_dequeue_pointer(&self, interrupter: u16, addr: u64) {
+        assert_eq!(addr & 0xF, 0, "Event ring dequeue pointer must be 16-byte aligned");
+        unsafe { write_volatile(&raw mut (*self.interrupter(interrupter)).erdp, addr | 0x8) }
+    }
+
     /// Ring doorbell for a specific slot/endpoint
     pub fn ring_doorbell(&self, slot_id: u8, endpoint: u8, stream_id: u16) {
         let doorbell_offset = slot_id as u64 * 4;
@@ -4,7 +4,6 @@
 //! PCIe device Base Address Registers (BARs) to virtual memory. It manages
 //! a large contiguous virtual address space using a bitmap to track allocated pages.
 
-use spin::Mutex;
 use x86_64::{
     PhysAddr, VirtAddr,
     structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
@@ -12,13 +11,15 @@ use x86_64::{
 
 use crate::{
     info,
-    memory::{FRAME_ALLOCATOR, PAGE_TABLE},
+    memory::{FRAME_ALLOCATOR, PAGE_TABLE, kaslr},
     pci::device::MemoryBar,
+    sync::Mutex,
 };
 
 use super::PciError;
 
-/// Virtual address space start for PCIe BAR mappings
+/// Virtual address space start for PCIe BAR mappings, before the KASLR offset
+/// is added
 /// Moved further away from ECAM space to avoid collisions
 const PCIE_VMM_START: u64 = 0xFFFF_FA00_0000_0000;
 /// Size of the PCIe VMM region (16GB)
@@ -31,12 +32,10 @@ const PCIE_VMM_PAGES: usize = (PCIE_VMM_SIZE / PAGE_SIZE) as usize;
 const BITMAP_WORDS: usize = PCIE_VMM_PAGES.div_ceil(128);
 
 /// Global PCIe VMM instance
-pub static PCIE_VMM: Mutex<PcieVmm> = Mutex::new(PcieVmm::new());
+pub static PCIE_VMM: Mutex<PcieVmm> = Mutex::new("PCIE_VMM", PcieVmm::new());
 
 /// PCIe Virtual Memory Manager
 pub struct PcieVmm {
-    /// Base virtual address of the managed region
-    base_address: VirtAddr,
     /// Bitmap tracking allocated pages (1 = allocated, 0 = free)
     page_bitmap: [u128; BITMAP_WORDS],
     /// Next page to start searching from (for allocation optimization)
@@ -60,12 +59,16 @@ impl PcieVmm {
     /// Create a new PCIe VMM instance
     pub const fn new() -> Self {
         Self {
-            base_address: VirtAddr::new(PCIE_VMM_START),
             page_bitmap: [0u128; BITMAP_WORDS],
             next_search_start: 0,
         }
     }
 
+    /// Base virtual address of the managed region
+    fn base_address(&self) -> VirtAddr {
+        VirtAddr::new(kaslr::region(PCIE_VMM_START))
+    }
+
     /// Map a memory BAR to virtual memory
     pub fn map_memory_bar(
         &mut self,
@@ -87,7 +90,7 @@ impl PcieVmm {
 
         // Calculate virtual address
         let virtual_address =
-            VirtAddr::new(self.base_address.as_u64() + (start_page as u64 * PAGE_SIZE));
+            VirtAddr::new(self.base_address().as_u64() + (start_page as u64 * PAGE_SIZE));
 
         // Map the pages
         self.map_pages(
@@ -127,7 +130,7 @@ impl PcieVmm {
     /// Unmap a previously mapped BAR
     pub fn unmap_bar(&mut self, mapped_bar: &MappedBar) -> Result<(), PciError> {
         let pages_to_unmap = mapped_bar.size.div_ceil(PAGE_SIZE) as usize;
-        let start_page = ((mapped_bar.virtual_address.as_u64() - self.base_address.as_u64())
+        let start_page = ((mapped_bar.virtual_address.as_u64() - self.base_address().as_u64())
             / PAGE_SIZE) as usize;
 
         // Unmap the pages
@@ -0,0 +1,147 @@
+//! Power management
+//!
+//! Suspend-to-RAM (ACPI S3): drivers quiesce their devices through the
+//! `suspend` callback of the driver model, PCIe configuration space and the
+//! interrupt controllers are saved, and the platform is put to sleep with
+//! memory kept powered. On wakeup the firmware jumps to the trampoline in
+//! `wake`, which returns to `suspend` with the CPU state restored, and
+//! everything is brought back in reverse order.
+//!
+//! The timer doesn't run while asleep, so uptime doesn't count the time spent
+//! in S3. In QEMU, S3 has to be enabled with `-global ICH9-LPC.disable_s3=0`
+//! (or `PIIX4_PM.disable_s3=0`) and the guest is woken with `system_wakeup`
+//! in the monitor.
+//!
+//! Hibernation (suspend to disk) lives in `hibernate` and shares the device
+//! handling and the resume path with S3.
+
+#[cfg(feature = "nvme")]
+pub mod hibernate;
+pub mod sleep;
+pub mod wake;
+
+use alloc::string::String;
+use core::fmt;
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    info,
+    interrupts::apic,
+    pci::{self, probe},
+    ps2, serial,
+    sync::Mutex,
+    warn,
+};
+use wake::WakeMemory;
+
+/// Why the system couldn't be suspended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SuspendError {
+    /// The platform or the kernel can't do S3
+    Unsupported(&'static str),
+    /// A driver refused to suspend its device
+    Driver(String),
+    /// The platform didn't go to sleep
+    Firmware,
+}
+
+impl fmt::Display for SuspendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SuspendError::Unsupported(reason) => write!(f, "S3 not supported: {reason}"),
+            SuspendError::Driver(reason) => write!(f, "driver failed to suspend: {reason}"),
+            SuspendError::Firmware => write!(f, "the platform did not enter S3"),
+        }
+    }
+}
+
+/// Memory for the wakeup path, or why S3 can't be used
+static WAKE_MEMORY: Mutex<Result<WakeMemory, &'static str>> =
+    Mutex::new("WAKE_MEMORY", Err("power management not initialized"));
+
+/// Find the ACPI sleep registers and reserve memory for the wakeup path
+///
+/// Must be called after the frame allocator and the APIC are set up.
+pub fn init(rsdp_addr: usize) {
+    let support = sleep::init(rsdp_addr)
+        .and_then(|()| {
+            if !sleep::supported() {
+                return Err("the DSDT has no _S3 package");
+            }
+            wake::check_cpu()
+        })
+        .and_then(|()| WakeMemory::reserve().ok_or("no free memory below 1 MiB"));
+
+    match &support {
+        #[allow(unused_variables)]
+        Ok(memory) => {
+            info!("S3 supported, waking vector at {:#x}", memory.phys.as_u64());
+        }
+        #[allow(unused_variables)]
+        Err(reason) => {
+            info!("S3 not available: {}", reason);
+        }
+    }
+    *WAKE_MEMORY.lock() = support;
+}
+
+/// Suspend the system to RAM, returning once it has woken up again
+///
+/// Must be called from a task with interrupts enabled.
+pub fn suspend() -> Result<(), SuspendError> {
+    let memory = (*WAKE_MEMORY.lock()).map_err(SuspendError::Unsupported)?;
+    if !sleep::supported() {
+        return Err(SuspendError::Unsupported("no ACPI sleep registers"));
+    }
+
+    let resumed = with_devices_suspended(|| {
+        info!("entering S3");
+        memory.prepare();
+        sleep::set_waking_vector(Some(memory.phys));
+        let resumed = unsafe { wake::save_and_sleep(sleep::enter_s3) } == 0;
+        sleep::set_waking_vector(None);
+        resumed
+    })
+    .map_err(SuspendError::Driver)?;
+
+    if resumed {
+        info!("resumed from S3");
+        Ok(())
+    } else {
+        Err(SuspendError::Firmware)
+    }
+}
+
+/// Quiesce every device, run `sleep` with interrupts disabled, then bring the
+/// devices back
+///
+/// `sleep` returns true if the system went through the wakeup path, in which
+/// case the interrupt controllers, PCIe configuration space and legacy
+/// devices are restored as well. Returns what `sleep` returned, or the reason
+/// a driver refused to suspend.
+fn with_devices_suspended(sleep: impl FnOnce() -> bool) -> Result<bool, String> {
+    probe::suspend_drivers()?;
+    let config = pci::pm::save_config();
+    let apic_state = apic::save_state();
+
+    let resumed = interrupts::without_interrupts(|| {
+        let resumed = sleep();
+        if resumed {
+            unsafe { apic::restore_state(apic_state) };
+            serial::resume();
+        }
+        resumed
+    });
+
+    if resumed {
+        pci::pm::restore_config(&config);
+        #[allow(unused_variables)]
+        if let Err(e) = ps2::resume() {
+            warn!("PS/2 controller did not come back: {}", e);
+        }
+    }
+    probe::resume_drivers();
+
+    Ok(resumed)
+}
@@ -0,0 +1,812 @@
+//! Hibernation (suspend to disk)
+//!
+//! `hibernate` quiesces every device and, with interrupts disabled, copies
+//! each page in use into a free frame. The CPU state is saved by
+//! `wake::save_and_sleep` just before, so the copy is a consistent image of
+//! the whole system. Devices are then brought back, the image is written to
+//! the resume area and the platform is turned off.
+//!
+//! At the next boot, the resume task reads the image into frames that the
+//! image doesn't cover, shuts devices down and copies every page back to where
+//! it was. Execution continues in `wake::resume_entry`, so `save_and_sleep`
+//! returns a second time inside the restored `hibernate`.
+//!
+//! The resume area is a range of blocks on a registered block device, given
+//! with `resume=<device>:<lba>` on the command line, or `resume=<nsid>:<lba>`
+//! for a namespace of the NVMe controller; it is overwritten without further
+//! checks. An image is only restored by the same kernel binary loaded at the
+//! same address, with the same memory map; anything else is left alone. With
+//! KASLR the load address changes every boot, so resuming needs `kaslr: no`
+//! in limine.conf and `nokaslr` on the command line.
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use core::{
+    arch::naked_asm,
+    fmt, mem, ptr, slice,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use limine::memory_map::{Entry, EntryType};
+use x86_64::{PhysAddr, VirtAddr, instructions::interrupts, structures::paging::Translate};
+
+use super::{sleep, wake, with_devices_suspended};
+use crate::{
+    block::{self, BlockDevice, BlockError},
+    bootargs, info,
+    interrupts::apic::LAPIC_TIMER_VECTOR,
+    memory::{FRAME_ALLOCATOR, MEMORY_MAP, PAGE_TABLE, kaslr},
+    pci::probe,
+    tasks::scheduler::{exit_task, kcreate_task},
+    warn,
+};
+
+const MAGIC: [u8; 8] = *b"LOCOSHIB";
+const VERSION: u32 = 2;
+
+const PAGE_SIZE: usize = 4096;
+const HUGE_PAGE_SIZE: u64 = 0x20_0000;
+
+/// Physical page addresses stored in one page of the image's page table
+const ADDRESSES_PER_PAGE: usize = PAGE_SIZE / mem::size_of::<u64>();
+
+/// (source, destination) pairs in one page of the restore list, after the
+/// count and the pointer to the next page
+const PAIRS_PER_PAGE: usize = PAGE_SIZE / 16 - 1;
+
+/// Page table entry bits used by the restore page table
+const PRESENT_WRITABLE: u64 = 0b11;
+const HUGE_PAGE: u64 = 1 << 7;
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Returned by `take_snapshot` when the image was taken, and when it wasn't
+const SNAPSHOT_TAKEN: u64 = 1;
+const SNAPSHOT_FAILED: u64 = 2;
+
+/// Frames that may be allocated between counting the pages in use and taking
+/// the image, mostly for the buffers used to take it
+const SNAPSHOT_SLACK: usize = 256;
+
+unsafe extern "C" {
+    static __kernel_start: u8;
+    static __rodata_end: u8;
+    static __kernel_end: u8;
+}
+
+/// Why the system couldn't hibernate or resume
+#[derive(Debug, Clone)]
+pub enum HibernateError {
+    /// This setup can't be hibernated
+    Unsupported(&'static str),
+    /// No `resume=` on the command line
+    NoResumeArea,
+    /// `resume=` doesn't describe a usable area
+    InvalidResumeArea(&'static str),
+    /// Not enough free memory to take or load the image
+    NotEnoughMemory,
+    /// A driver refused to suspend its device
+    Driver(String),
+    /// The memory in use changed too much while taking the image
+    Snapshot,
+    /// Reading or writing the resume area failed
+    Io(BlockError),
+    /// The image was written by another kernel or for another memory map
+    Mismatch(&'static str),
+    /// The image is damaged
+    Corrupt(&'static str),
+    /// The platform didn't turn off after writing the image
+    PowerOff,
+}
+
+impl fmt::Display for HibernateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HibernateError::Unsupported(reason) => write!(f, "not supported: {reason}"),
+            HibernateError::NoResumeArea => write!(f, "no resume=<device>:<lba> boot argument"),
+            HibernateError::InvalidResumeArea(reason) => {
+                write!(f, "invalid resume area: {reason}")
+            }
+            HibernateError::NotEnoughMemory => write!(f, "not enough free memory for the image"),
+            HibernateError::Driver(reason) => write!(f, "driver failed to suspend: {reason}"),
+            HibernateError::Snapshot => write!(f, "memory changed while taking the image"),
+            HibernateError::Io(e) => write!(f, "I/O error: {e:?}"),
+            HibernateError::Mismatch(what) => write!(f, "image is for a different {what}"),
+            HibernateError::Corrupt(reason) => write!(f, "image is corrupt: {reason}"),
+            HibernateError::PowerOff => write!(f, "the platform did not turn off"),
+        }
+    }
+}
+
+/// First page of the image, followed by the page table and the pages
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ImageHeader {
+    magic: [u8; 8],
+    version: u32,
+    _reserved: u32,
+    /// Hash of the kernel text and read-only data
+    kernel_id: u64,
+    /// Virtual address the kernel was loaded at
+    kernel_base: u64,
+    /// Hash of the bootloader memory map
+    memory_map_id: u64,
+    hhdm_offset: u64,
+    /// Number of saved pages
+    pages: u64,
+    /// Hash of the page table and the pages
+    checksum: u64,
+}
+
+/// 64-bit FNV-1a, used to identify kernels and check images
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+fn kernel_id() -> u64 {
+    let start = &raw const __kernel_start;
+    let len = &raw const __rodata_end as usize - start as usize;
+    let mut hash = Fnv::new();
+    hash.write(unsafe { slice::from_raw_parts(start, len) });
+    hash.0
+}
+
+fn memory_map() -> &'static [&'static Entry] {
+    MEMORY_MAP.get().copied().unwrap_or(&[])
+}
+
+fn memory_map_id() -> u64 {
+    let mut hash = Fnv::new();
+    for &entry in memory_map() {
+        let bytes = (entry as *const Entry).cast::<u8>();
+        hash.write(unsafe { slice::from_raw_parts(bytes, mem::size_of::<Entry>()) });
+    }
+    hash.0
+}
+
+fn hhdm_offset() -> Result<u64, HibernateError> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map(|allocator| allocator.hddm_offset)
+        .ok_or(HibernateError::Unsupported("no frame allocator"))
+}
+
+/// Memory map entries whose pages are saved, if in use
+fn saved_entries() -> impl Iterator<Item = &'static Entry> {
+    memory_map().iter().copied().filter(|entry| {
+        [
+            EntryType::USABLE,
+            EntryType::BOOTLOADER_RECLAIMABLE,
+            EntryType::EXECUTABLE_AND_MODULES,
+        ]
+        .contains(&entry.entry_type)
+    })
+}
+
+/// Calls `f` with every page in a saved entry that is neither in a free block
+/// nor in `exclude`
+///
+/// `free` holds (start, frames) sorted by start, `exclude` is sorted.
+fn for_each_page_in_use(free: &[(u64, usize)], exclude: &[u64], mut f: impl FnMut(u64)) {
+    let is_free = |page: u64| {
+        let index = free.partition_point(|&(start, _)| start <= page);
+        index > 0 && page < free[index - 1].0 + (free[index - 1].1 * PAGE_SIZE) as u64
+    };
+
+    for entry in saved_entries() {
+        let start = entry.base.next_multiple_of(PAGE_SIZE as u64);
+        let end = (entry.base + entry.length) & !(PAGE_SIZE as u64 - 1);
+        for page in (start..end).step_by(PAGE_SIZE) {
+            if !is_free(page) && exclude.binary_search(&page).is_err() {
+                f(page);
+            }
+        }
+    }
+}
+
+/// Fills `free` with the free blocks of the frame allocator, sorted
+///
+/// Never grows `free`; returns false if it ran out of capacity.
+fn collect_free_blocks(free: &mut Vec<(u64, usize)>) -> bool {
+    free.clear();
+    let mut complete = true;
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_ref() {
+        allocator.for_each_free_block(|start, frames| {
+            if free.len() < free.capacity() {
+                free.push((start.as_u64(), frames));
+            } else {
+                complete = false;
+            }
+        });
+    }
+    free.sort_unstable();
+    complete
+}
+
+/// Buffers for taking the image, allocated before interrupts are disabled
+struct Snapshot {
+    hhdm_offset: u64,
+    /// Free frames the pages are copied to, sorted
+    copies: Vec<u64>,
+    /// Pages saved in the image, copied to `copies` in order
+    pages: Vec<u64>,
+    /// Free blocks of the frame allocator: (start, frames)
+    free: Vec<(u64, usize)>,
+}
+
+/// The snapshot `take_snapshot` works on, which can't take arguments
+static SNAPSHOT: AtomicPtr<Snapshot> = AtomicPtr::new(ptr::null_mut());
+
+impl Snapshot {
+    /// Count the pages in use and allocate a free frame for each of them
+    fn allocate() -> Result<Self, HibernateError> {
+        let hhdm_offset = hhdm_offset()?;
+
+        let mut free_blocks = 0;
+        if let Some(allocator) = FRAME_ALLOCATOR.lock().as_ref() {
+            allocator.for_each_free_block(|_, _| free_blocks += 1);
+        }
+        // allocating the copies splits blocks, so leave room for more of them
+        let mut free = Vec::with_capacity(free_blocks * 2 + SNAPSHOT_SLACK);
+        if !collect_free_blocks(&mut free) {
+            return Err(HibernateError::Snapshot);
+        }
+
+        let mut in_use = 0;
+        for_each_page_in_use(&free, &[], |_| in_use += 1);
+        let count = in_use + in_use / 8 + SNAPSHOT_SLACK;
+
+        let mut snapshot = Self {
+            hhdm_offset,
+            copies: Vec::with_capacity(count),
+            pages: Vec::new(),
+            free,
+        };
+        for _ in 0..count {
+            let frame = FRAME_ALLOCATOR
+                .lock()
+                .as_mut()
+                .and_then(|allocator| allocator.allocate_contiguous_frames(1))
+                .ok_or(HibernateError::NotEnoughMemory)?;
+            snapshot.copies.push(frame.as_u64());
+        }
+        snapshot.copies.sort_unstable();
+        snapshot.pages = Vec::with_capacity(count);
+        Ok(snapshot)
+    }
+
+    /// Find the pages in use right now, without allocating
+    fn collect(&mut self) -> bool {
+        let Self {
+            copies,
+            pages,
+            free,
+            ..
+        } = self;
+
+        if !collect_free_blocks(free) {
+            return false;
+        }
+        pages.clear();
+        let mut complete = true;
+        for_each_page_in_use(free, copies, |page| {
+            if pages.len() < pages.capacity() {
+                pages.push(page);
+            } else {
+                complete = false;
+            }
+        });
+        complete
+    }
+
+    /// Contents of the copy of the `index`th saved page
+    fn copy(&self, index: usize) -> &[u8] {
+        let virt = (self.copies[index] + self.hhdm_offset) as *const u8;
+        unsafe { slice::from_raw_parts(virt, PAGE_SIZE) }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        if let Some(allocator) = allocator.as_mut() {
+            for &frame in &self.copies {
+                unsafe { allocator.deallocate_contiguous_frames(PhysAddr::new(frame), 1) };
+            }
+        }
+    }
+}
+
+/// Copy every page in use to its frame in `SNAPSHOT`
+///
+/// Called by `wake::save_and_sleep` with interrupts disabled. No lock may be
+/// held while copying, or it would stay held in the restored system.
+extern "C" fn take_snapshot() -> u64 {
+    let snapshot = unsafe { &mut *SNAPSHOT.load(Ordering::Relaxed) };
+    if !snapshot.collect() {
+        return SNAPSHOT_FAILED;
+    }
+
+    for (&page, &copy) in snapshot.pages.iter().zip(&snapshot.copies) {
+        unsafe {
+            ptr::copy_nonoverlapping(
+                (page + snapshot.hhdm_offset) as *const u8,
+                (copy + snapshot.hhdm_offset) as *mut u8,
+                PAGE_SIZE,
+            );
+        }
+    }
+    SNAPSHOT_TAKEN
+}
+
+/// Where the image is stored
+#[derive(Clone)]
+struct ResumeArea {
+    device: Arc<dyn BlockDevice>,
+    lba: u64,
+    blocks_per_page: u64,
+}
+
+impl ResumeArea {
+    /// Parse `resume=<device>:<lba>` and look the device up
+    ///
+    /// A number instead of a device name is a namespace of the NVMe
+    /// controller.
+    fn from_cmdline() -> Result<Self, HibernateError> {
+        let arg = bootargs::get("resume").ok_or(HibernateError::NoResumeArea)?;
+        let (name, lba) = arg
+            .split_once(':')
+            .ok_or(HibernateError::InvalidResumeArea("expected <device>:<lba>"))?;
+        let lba = lba
+            .parse::<u64>()
+            .map_err(|_| HibernateError::InvalidResumeArea("invalid LBA"))?;
+
+        let device = match name.parse::<u32>() {
+            Ok(nsid) => block::find(&format!("nvme0n{nsid}")),
+            Err(_) => block::find(name),
+        }
+        .ok_or(HibernateError::InvalidResumeArea("no such block device"))?;
+        let block_size = device.block_size();
+        if block_size == 0 || PAGE_SIZE % block_size != 0 {
+            return Err(HibernateError::InvalidResumeArea(
+                "block size does not divide the page size",
+            ));
+        }
+
+        Ok(Self {
+            device,
+            lba,
+            blocks_per_page: (PAGE_SIZE / block_size) as u64,
+        })
+    }
+
+    /// Fails if an image of `pages` pages doesn't fit
+    fn check_fits(&self, pages: usize) -> Result<(), HibernateError> {
+        let blocks = pages as u64 * self.blocks_per_page;
+        match self.lba.checked_add(blocks) {
+            Some(end) if end <= self.device.block_count() => Ok(()),
+            _ => Err(HibernateError::InvalidResumeArea("the image does not fit")),
+        }
+    }
+
+    fn page_lba(&self, page: usize) -> u64 {
+        self.lba + page as u64 * self.blocks_per_page
+    }
+
+    fn read_page(&self, page: usize, buffer: &mut [u8]) -> Result<(), HibernateError> {
+        self.device
+            .read_blocks(self.page_lba(page), &mut buffer[..PAGE_SIZE])
+            .map_err(HibernateError::Io)
+    }
+
+    fn write_page(&self, page: usize, buffer: &[u8]) -> Result<(), HibernateError> {
+        self.device
+            .write_blocks(self.page_lba(page), &buffer[..PAGE_SIZE])
+            .map_err(HibernateError::Io)
+    }
+
+    fn flush(&self) -> Result<(), HibernateError> {
+        self.device.flush().map_err(HibernateError::Io)
+    }
+
+    /// Clear the header so the image isn't restored again
+    fn invalidate(&self) -> Result<(), HibernateError> {
+        self.write_page(0, &[0; PAGE_SIZE])?;
+        self.flush()
+    }
+}
+
+/// Number of pages in an image of `pages` saved pages, header included
+fn image_pages(pages: usize) -> usize {
+    1 + pages.div_ceil(ADDRESSES_PER_PAGE) + pages
+}
+
+/// Write the image taken in `snapshot`
+///
+/// The header goes last, so an interrupted write never leaves a valid image.
+fn write_image(area: &ResumeArea, snapshot: &Snapshot) -> Result<(), HibernateError> {
+    let pages = snapshot.pages.len();
+    let table_pages = pages.div_ceil(ADDRESSES_PER_PAGE);
+    area.check_fits(image_pages(pages))?;
+    area.invalidate()?;
+
+    let mut checksum = Fnv::new();
+    let mut buffer = vec![0u8; PAGE_SIZE];
+    for (index, chunk) in snapshot.pages.chunks(ADDRESSES_PER_PAGE).enumerate() {
+        buffer.fill(0);
+        for (bytes, page) in buffer.chunks_exact_mut(8).zip(chunk) {
+            bytes.copy_from_slice(&page.to_le_bytes());
+        }
+        checksum.write(&buffer);
+        area.write_page(1 + index, &buffer)?;
+    }
+    for index in 0..pages {
+        let data = snapshot.copy(index);
+        checksum.write(data);
+        area.write_page(1 + table_pages + index, data)?;
+    }
+
+    let header = ImageHeader {
+        magic: MAGIC,
+        version: VERSION,
+        _reserved: 0,
+        kernel_id: kernel_id(),
+        kernel_base: kaslr::kernel_base(),
+        memory_map_id: memory_map_id(),
+        hhdm_offset: snapshot.hhdm_offset,
+        pages: pages as u64,
+        checksum: checksum.0,
+    };
+    buffer.fill(0);
+    unsafe { ptr::write_unaligned(buffer.as_mut_ptr().cast::<ImageHeader>(), header) };
+    area.write_page(0, &buffer)
+}
+
+/// Write the whole system to the resume area and turn the platform off
+///
+/// Returns Ok once the system has been restored from the image at a later
+/// boot. Must be called from a task with interrupts enabled.
+pub fn hibernate() -> Result<(), HibernateError> {
+    wake::check_cpu().map_err(HibernateError::Unsupported)?;
+    let area = ResumeArea::from_cmdline()?;
+    // the image is lost if the system doesn't resume from it
+    area.flush()?;
+
+    let mut snapshot = Snapshot::allocate()?;
+    area.check_fits(image_pages(snapshot.copies.len()))?;
+    SNAPSHOT.store(&raw mut snapshot, Ordering::Relaxed);
+
+    let mut result = SNAPSHOT_FAILED;
+    let restored = with_devices_suspended(|| {
+        info!("taking hibernation image");
+        result = unsafe { wake::save_and_sleep(take_snapshot) };
+        result == 0
+    })
+    .map_err(HibernateError::Driver)?;
+
+    if restored {
+        info!("resumed from hibernation image");
+        #[allow(unused_variables)]
+        if let Err(e) = area.invalidate() {
+            warn!("failed to clear the hibernation image: {}", e);
+        }
+        return Ok(());
+    }
+    if result != SNAPSHOT_TAKEN {
+        return Err(HibernateError::Snapshot);
+    }
+
+    info!(
+        "writing hibernation image of {} pages",
+        snapshot.pages.len()
+    );
+    write_image(&area, &snapshot)?;
+    area.flush()?;
+
+    info!("hibernation image written, powering off");
+    sleep::power_off();
+
+    // still running, so the image must not be restored over what happens next
+    let _ = area.invalidate();
+    Err(HibernateError::PowerOff)
+}
+
+/// Frames for loading an image, never one the image will be copied over
+struct SafeFrames<'a> {
+    hhdm_offset: u64,
+    /// Pages the image covers, sorted
+    targets: &'a [u64],
+    /// Every frame taken, including the unsafe ones, freed if restoring fails
+    allocated: Vec<u64>,
+}
+
+impl SafeFrames<'_> {
+    fn allocate(&mut self) -> Result<u64, HibernateError> {
+        loop {
+            let frame = FRAME_ALLOCATOR
+                .lock()
+                .as_mut()
+                .and_then(|allocator| allocator.allocate_contiguous_frames(1))
+                .ok_or(HibernateError::NotEnoughMemory)?
+                .as_u64();
+            self.allocated.push(frame);
+            if self.targets.binary_search(&frame).is_err() {
+                return Ok(frame);
+            }
+        }
+    }
+
+    fn allocate_zeroed(&mut self) -> Result<u64, HibernateError> {
+        let frame = self.allocate()?;
+        self.page(frame).fill(0);
+        Ok(frame)
+    }
+
+    fn virt(&self, frame: u64) -> u64 {
+        frame + self.hhdm_offset
+    }
+
+    fn page(&mut self, frame: u64) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt(frame) as *mut u8, PAGE_SIZE) }
+    }
+
+    fn entries(&mut self, frame: u64) -> &mut [u64] {
+        unsafe { slice::from_raw_parts_mut(self.virt(frame) as *mut u64, ADDRESSES_PER_PAGE) }
+    }
+}
+
+impl Drop for SafeFrames<'_> {
+    fn drop(&mut self) {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        if let Some(allocator) = allocator.as_mut() {
+            for &frame in &self.allocated {
+                unsafe { allocator.deallocate_contiguous_frames(PhysAddr::new(frame), 1) };
+            }
+        }
+    }
+}
+
+/// Linked pages of (source, destination) pairs for `restore_pages`, all
+/// through the direct map
+///
+/// Each page holds the number of pairs, the next page (or 0) and the pairs.
+struct RestoreList {
+    head: u64,
+    tail: u64,
+}
+
+impl RestoreList {
+    fn new(frames: &mut SafeFrames) -> Result<Self, HibernateError> {
+        let head = frames.allocate_zeroed()?;
+        Ok(Self { head, tail: head })
+    }
+
+    fn push(
+        &mut self,
+        frames: &mut SafeFrames,
+        source: u64,
+        target: u64,
+    ) -> Result<(), HibernateError> {
+        if frames.entries(self.tail)[0] as usize == PAIRS_PER_PAGE {
+            let next = frames.allocate_zeroed()?;
+            frames.entries(self.tail)[1] = frames.virt(next);
+            self.tail = next;
+        }
+
+        let virt_source = frames.virt(source);
+        let virt_target = frames.virt(target);
+        let entries = frames.entries(self.tail);
+        let count = entries[0] as usize;
+        entries[2 + count * 2] = virt_source;
+        entries[3 + count * 2] = virt_target;
+        entries[0] += 1;
+        Ok(())
+    }
+}
+
+/// Set the entry mapping `virt` in the table tree at `pml4`, creating tables
+/// down to the level of the entry
+fn map(frames: &mut SafeFrames, pml4: u64, virt: u64, entry: u64) -> Result<(), HibernateError> {
+    let shifts: &[u64] = if entry & HUGE_PAGE != 0 {
+        &[39, 30]
+    } else {
+        &[39, 30, 21]
+    };
+    let last_shift = shifts[shifts.len() - 1] - 9;
+
+    let mut table = pml4;
+    for &shift in shifts {
+        let index = ((virt >> shift) & 0x1ff) as usize;
+        if frames.entries(table)[index] & 1 == 0 {
+            let next = frames.allocate_zeroed()?;
+            frames.entries(table)[index] = next | PRESENT_WRITABLE;
+        }
+        table = frames.entries(table)[index] & ADDRESS_MASK;
+    }
+    frames.entries(table)[((virt >> last_shift) & 0x1ff) as usize] = entry;
+    Ok(())
+}
+
+/// Build the page table `restore_pages` runs on: the direct map over all
+/// saved memory and the kernel, in frames the image doesn't cover
+fn build_restore_table(frames: &mut SafeFrames) -> Result<u64, HibernateError> {
+    let kernel_start = &raw const __kernel_start as u64;
+    let kernel_end = &raw const __kernel_end as u64;
+    let kernel_pages = {
+        let page_table = PAGE_TABLE.lock();
+        let page_table = page_table
+            .as_ref()
+            .ok_or(HibernateError::Unsupported("no page table"))?;
+        (kernel_start..kernel_end)
+            .step_by(PAGE_SIZE)
+            .map(|virt| {
+                page_table
+                    .translate_addr(VirtAddr::new(virt))
+                    .map(|phys| (virt, phys.as_u64()))
+                    .ok_or(HibernateError::Unsupported("kernel is not fully mapped"))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    let pml4 = frames.allocate_zeroed()?;
+
+    let memory_end = saved_entries()
+        .map(|entry| entry.base + entry.length)
+        .max()
+        .unwrap_or(0);
+    for phys in (0..memory_end).step_by(HUGE_PAGE_SIZE as usize) {
+        let virt = frames.virt(phys);
+        map(frames, pml4, virt, phys | HUGE_PAGE | PRESENT_WRITABLE)?;
+    }
+    for (virt, phys) in kernel_pages {
+        map(frames, pml4, virt, phys | PRESENT_WRITABLE)?;
+    }
+
+    Ok(pml4)
+}
+
+/// Copy every page of the image into place, then enter the restored system
+///
+/// Runs with interrupts disabled and without a stack, on the page table from
+/// `build_restore_table`. The kernel text it runs from is overwritten with the
+/// same bytes on the way.
+#[unsafe(naked)]
+unsafe extern "C" fn restore_pages(cr3: u64, list: u64, entry: u64) -> ! {
+    naked_asm!(
+        "mov cr3, rdi",
+        // clearing PGE also drops the global TLB entries of this kernel
+        "mov rax, cr4",
+        "btr rax, 7",
+        "mov cr4, rax",
+        "cld",
+        "mov r8, rsi",
+        "mov r9, rdx",
+        "2:",
+        "test r8, r8",
+        "jz 5f",
+        "mov rdx, [r8]",
+        "lea r10, [r8 + 16]",
+        "3:",
+        "test rdx, rdx",
+        "jz 4f",
+        "mov rsi, [r10]",
+        "mov rdi, [r10 + 8]",
+        "mov ecx, 512",
+        "rep movsq",
+        "add r10, 16",
+        "dec rdx",
+        "jmp 3b",
+        "4:",
+        "mov r8, [r8 + 8]",
+        "jmp 2b",
+        "5:",
+        "jmp r9",
+    );
+}
+
+/// Look for an image in the resume area and restore it
+///
+/// Only returns if there is no image, Ok, or it can't be restored.
+fn restore() -> Result<(), HibernateError> {
+    wake::check_cpu().map_err(HibernateError::Unsupported)?;
+    let area = ResumeArea::from_cmdline()?;
+    let hhdm_offset = hhdm_offset()?;
+
+    let mut buffer = vec![0u8; PAGE_SIZE];
+    area.read_page(0, &mut buffer)?;
+    let header = unsafe { ptr::read_unaligned(buffer.as_ptr().cast::<ImageHeader>()) };
+    if header.magic != MAGIC {
+        info!("no hibernation image in the resume area");
+        return Ok(());
+    }
+    if header.version != VERSION {
+        return Err(HibernateError::Mismatch("image version"));
+    }
+    if header.kernel_id != kernel_id() {
+        return Err(HibernateError::Mismatch("kernel"));
+    }
+    if header.kernel_base != kaslr::kernel_base() {
+        return Err(HibernateError::Mismatch("kernel load address"));
+    }
+    if header.memory_map_id != memory_map_id() || header.hhdm_offset != hhdm_offset {
+        return Err(HibernateError::Mismatch("memory map"));
+    }
+
+    let pages = header.pages as usize;
+    let table_pages = pages.div_ceil(ADDRESSES_PER_PAGE);
+    area.check_fits(image_pages(pages))?;
+    info!("restoring hibernation image of {} pages", pages);
+
+    let mut checksum = Fnv::new();
+    let mut targets = Vec::with_capacity(pages);
+    for index in 0..table_pages {
+        area.read_page(1 + index, &mut buffer)?;
+        checksum.write(&buffer);
+        let remaining = pages - targets.len();
+        targets.extend(
+            buffer
+                .chunks_exact(8)
+                .take(remaining)
+                .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap())),
+        );
+    }
+    if !targets.is_sorted() {
+        return Err(HibernateError::Corrupt("page table is not sorted"));
+    }
+
+    let mut frames = SafeFrames {
+        hhdm_offset,
+        targets: &targets,
+        allocated: Vec::new(),
+    };
+    let mut list = RestoreList::new(&mut frames)?;
+    for (index, &target) in targets.iter().enumerate() {
+        let frame = frames.allocate()?;
+        let data = frames.page(frame);
+        area.read_page(1 + table_pages + index, data)?;
+        checksum.write(data);
+        list.push(&mut frames, frame, target)?;
+    }
+    if checksum.0 != header.checksum {
+        return Err(HibernateError::Corrupt("checksum mismatch"));
+    }
+
+    let cr3 = build_restore_table(&mut frames)?;
+    let list = frames.virt(list.head);
+
+    // nothing may write to memory behind the copy's back
+    probe::suspend_drivers().map_err(HibernateError::Driver)?;
+    info!("entering the restored system");
+    interrupts::disable();
+    unsafe { restore_pages(cr3, list, wake::resume_entry()) }
+}
+
+/// Start the task restoring a hibernation image, if `resume=` was given
+pub fn spawn_resume_task() {
+    if bootargs::get("resume").is_some() {
+        interrupts::without_interrupts(|| kcreate_task(resume_task, "hibernate resume"));
+    }
+}
+
+fn resume_task() -> ! {
+    // the resume area is read through the NVMe driver
+    while !probe::probes_finished() {
+        unsafe { core::arch::asm!("int {}", const LAPIC_TIMER_VECTOR) };
+    }
+
+    #[allow(unused_variables)]
+    if let Err(e) = restore() {
+        warn!("not resuming from hibernation image: {}", e);
+    }
+    exit_task();
+}
@@ -0,0 +1,268 @@
+//! ACPI sleep registers
+//!
+//! Without an AML interpreter, the sleep type values are read straight out of
+//! the `\_S3_` package in the DSDT, the way most small kernels do it, and the
+//! `_PTS`/`_WAK` methods are never run. That is enough for QEMU and many
+//! desktop boards.
+
+use acpi::{AcpiHandler, AcpiTables, address::AddressSpace, fadt::Fadt};
+use x86_64::{
+    PhysAddr,
+    instructions::{interrupts, port::Port},
+};
+
+use crate::{interrupts::apic::KernelAcpiHandler, sync::Mutex};
+
+/// PM1 control register bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+/// PM1 status register wake bit, cleared by writing 1
+const WAK_STS: u16 = 1 << 15;
+
+/// Offsets into the FACS
+const FACS_WAKING_VECTOR: usize = 12;
+const FACS_X_WAKING_VECTOR: usize = 24;
+const FACS_X_WAKING_VECTOR_MIN_LENGTH: u32 = 32;
+
+/// AML opcodes used by the `_S3_` package
+const AML_NAME_OP: u8 = 0x08;
+const AML_ROOT_PREFIX: u8 = b'\\';
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+/// Polls of the PM1 control register while waiting for ACPI mode
+const ACPI_ENABLE_POLLS: u32 = 1_000_000;
+
+/// Polls of the wake status after asking the platform to sleep
+const SLEEP_POLLS: u32 = 10_000_000;
+
+/// Everything needed to put the platform into S3
+#[derive(Debug, Clone, Copy)]
+struct SleepRegisters {
+    pm1a_control: u16,
+    pm1b_control: Option<u16>,
+    pm1a_status: u16,
+    pm1b_status: Option<u16>,
+    /// `SLP_TYPa` and `SLP_TYPb` for S3, if the DSDT has them
+    sleep_type: Option<(u8, u8)>,
+    /// `SLP_TYPa` and `SLP_TYPb` for S5, if the DSDT has them
+    soft_off_type: Option<(u8, u8)>,
+    /// Port and value that switch the platform to ACPI mode
+    smi_command: u16,
+    acpi_enable: u8,
+    /// Virtual address of the FACS
+    facs: u64,
+    facs_length: u32,
+}
+
+static SLEEP_REGISTERS: Mutex<Option<SleepRegisters>> = Mutex::new("SLEEP_REGISTERS", None);
+
+/// Port of a PM1 register block, only system I/O blocks are supported
+fn io_port(address: acpi::address::GenericAddress) -> Result<u16, &'static str> {
+    match address.address_space {
+        AddressSpace::SystemIo => Ok(address.address as u16),
+        _ => Err("PM1 registers outside of I/O space are not supported"),
+    }
+}
+
+/// Read one integer of a package, returning it and the bytes it took
+fn aml_integer(aml: &[u8]) -> Option<(u8, usize)> {
+    match *aml.first()? {
+        AML_ZERO_OP => Some((0, 1)),
+        AML_ONE_OP => Some((1, 1)),
+        AML_BYTE_PREFIX => Some((*aml.get(1)?, 2)),
+        _ => None,
+    }
+}
+
+/// Find `Name(_Sx_, Package() { SLP_TYPa, SLP_TYPb, ... })` in an AML stream
+fn find_sleep_type(aml: &[u8], name: &[u8; 4]) -> Option<(u8, u8)> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|&(_, window)| window == name)
+        .find_map(|(position, _)| {
+            let named = match position {
+                0 => false,
+                1 => aml[0] == AML_NAME_OP,
+                _ => {
+                    aml[position - 1] == AML_NAME_OP
+                        || (aml[position - 1] == AML_ROOT_PREFIX
+                            && aml[position - 2] == AML_NAME_OP)
+                }
+            };
+            let package = aml.get(position + 4..)?;
+            if !named || *package.first()? != AML_PACKAGE_OP {
+                return None;
+            }
+
+            // the two high bits of the first PkgLength byte count the bytes after it
+            let length_bytes = (*package.get(1)? >> 6) as usize + 1;
+            // skip the opcode, PkgLength and NumElements
+            let elements = package.get(1 + length_bytes + 1..)?;
+            let (a, used) = aml_integer(elements)?;
+            let (b, _) = aml_integer(elements.get(used..)?)?;
+            Some((a, b))
+        })
+}
+
+/// Read the sleep registers and the S3 sleep type from the ACPI tables
+///
+/// Returns a description of why S3 can't be used if it can't.
+pub fn init(rsdp_addr: usize) -> Result<(), &'static str> {
+    let tables = unsafe { AcpiTables::from_rsdp(KernelAcpiHandler, rsdp_addr) }
+        .map_err(|_| "no ACPI tables")?;
+    let fadt = tables.find_table::<Fadt>().map_err(|_| "no FADT")?;
+    if { fadt.flags }.system_is_hw_reduced_acpi() {
+        return Err("hardware-reduced ACPI platforms are not supported");
+    }
+
+    let pm1a_control = io_port(
+        fadt.pm1a_control_block()
+            .map_err(|_| "no PM1a control block")?,
+    )?;
+    let pm1b_control = fadt
+        .pm1b_control_block()
+        .map_err(|_| "invalid PM1b control block")?
+        .map(io_port)
+        .transpose()?;
+    let pm1a_status = io_port(fadt.pm1a_event_block().map_err(|_| "no PM1a event block")?)?;
+    let pm1b_status = fadt
+        .pm1b_event_block()
+        .map_err(|_| "invalid PM1b event block")?
+        .map(io_port)
+        .transpose()?;
+
+    let dsdt = tables.dsdt().map_err(|_| "no DSDT")?;
+    let aml =
+        unsafe { KernelAcpiHandler.map_physical_region::<u8>(dsdt.address, dsdt.length as usize) };
+    let aml =
+        unsafe { core::slice::from_raw_parts(aml.virtual_start().as_ptr(), dsdt.length as usize) };
+    let sleep_type = find_sleep_type(aml, b"_S3_");
+    let soft_off_type = find_sleep_type(aml, b"_S5_");
+    if sleep_type.is_none() && soft_off_type.is_none() {
+        return Err("the DSDT has no _S3 or _S5 package");
+    }
+
+    let facs_address = fadt.facs_address().map_err(|_| "no FACS")?;
+    let facs = unsafe { KernelAcpiHandler.map_physical_region::<u32>(facs_address, 64) };
+    let facs_length = unsafe { facs.virtual_start().as_ptr().add(1).read_volatile() };
+
+    *SLEEP_REGISTERS.lock() = Some(SleepRegisters {
+        pm1a_control,
+        pm1b_control,
+        pm1a_status,
+        pm1b_status,
+        sleep_type,
+        soft_off_type,
+        smi_command: { fadt.smi_cmd_port } as u16,
+        acpi_enable: fadt.acpi_enable,
+        facs: facs.virtual_start().as_ptr() as u64,
+        facs_length,
+    });
+    Ok(())
+}
+
+/// Returns true if `init` found everything needed for S3
+pub fn supported() -> bool {
+    SLEEP_REGISTERS
+        .lock()
+        .is_some_and(|registers| registers.sleep_type.is_some())
+}
+
+/// Point the firmware at the wakeup code, or clear the vector with None
+pub fn set_waking_vector(vector: Option<PhysAddr>) {
+    let Some(registers) = *SLEEP_REGISTERS.lock() else {
+        return;
+    };
+
+    let facs = registers.facs as *mut u8;
+    unsafe {
+        facs.add(FACS_WAKING_VECTOR)
+            .cast::<u32>()
+            .write_volatile(vector.map_or(0, |vector| vector.as_u64() as u32));
+        // a 64-bit vector would take precedence over the real mode one
+        if registers.facs_length >= FACS_X_WAKING_VECTOR_MIN_LENGTH {
+            facs.add(FACS_X_WAKING_VECTOR)
+                .cast::<u64>()
+                .write_volatile(0);
+        }
+    }
+}
+
+/// Put the platform into S3
+///
+/// Only returns, with a nonzero value, if the platform didn't go to sleep.
+/// Called by `wake::save_and_sleep` with interrupts disabled.
+pub extern "C" fn enter_s3() -> u64 {
+    let Some(registers) = *SLEEP_REGISTERS.lock() else {
+        return 1;
+    };
+    if let Some(sleep_type) = registers.sleep_type {
+        enter_state(&registers, sleep_type);
+    }
+    1
+}
+
+/// Turn the platform off (S5)
+///
+/// Only returns if the platform has no S5 sleep type or didn't turn off.
+pub fn power_off() {
+    let Some(registers) = *SLEEP_REGISTERS.lock() else {
+        return;
+    };
+    if let Some(soft_off_type) = registers.soft_off_type {
+        interrupts::without_interrupts(|| enter_state(&registers, soft_off_type));
+    }
+}
+
+/// Write a sleep type to the PM1 control registers and wait for it to take
+fn enter_state(registers: &SleepRegisters, sleep_type: (u8, u8)) {
+    unsafe {
+        let mut pm1a_control = Port::<u16>::new(registers.pm1a_control);
+        if pm1a_control.read() & SCI_EN == 0 && registers.smi_command != 0 {
+            Port::<u8>::new(registers.smi_command).write(registers.acpi_enable);
+            for _ in 0..ACPI_ENABLE_POLLS {
+                if pm1a_control.read() & SCI_EN != 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        for status in core::iter::once(registers.pm1a_status).chain(registers.pm1b_status) {
+            Port::<u16>::new(status).write(WAK_STS);
+        }
+
+        core::arch::asm!("wbinvd");
+
+        // program both sleep types before setting SLP_EN in either register
+        let (type_a, type_b) = sleep_type;
+        let controls = [
+            Some((registers.pm1a_control, type_a)),
+            registers.pm1b_control.map(|control| (control, type_b)),
+        ];
+        let mut values = [0; 2];
+        for (value, &(control, sleep_type)) in values.iter_mut().zip(controls.iter().flatten()) {
+            let mut port = Port::<u16>::new(control);
+            *value =
+                (port.read() & !(SLP_TYP_MASK | SLP_EN)) | ((sleep_type as u16) << SLP_TYP_SHIFT);
+            port.write(*value);
+        }
+        for (value, &(control, _)) in values.iter().zip(controls.iter().flatten()) {
+            Port::<u16>::new(control).write(value | SLP_EN);
+        }
+
+        let mut pm1a_status = Port::<u16>::new(registers.pm1a_status);
+        for _ in 0..SLEEP_POLLS {
+            if pm1a_status.read() & WAK_STS != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,367 @@
+//! Wakeup path from ACPI sleep states
+//!
+//! Firmware resumes from S3 in real mode at the waking vector, with every CPU
+//! register lost. The trampoline below is copied to a page under 1 MiB; it
+//! switches to protected mode, enables long mode with a small page table that
+//! identity maps the trampoline and shares the kernel half of the kernel page
+//! table, and jumps to `wake_resume`. That restores the control registers and
+//! the stack saved by `save_and_sleep`, which then returns a second time.
+
+use core::{
+    arch::{global_asm, naked_asm},
+    mem::offset_of,
+    ptr,
+};
+
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::{
+        control::{Cr0, Cr3, Cr4, Cr4Flags},
+        model_specific::Msr,
+    },
+};
+
+use crate::{gdt, interrupts::init_idt, memory::FRAME_ALLOCATOR};
+
+/// Pages reserved for the wakeup path: trampoline code, PML4, PDPT and PD
+const WAKE_PAGES: usize = 4;
+
+/// The trampoline must be reachable from real mode
+const REAL_MODE_LIMIT: u64 = 0x10_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Page table entry flags used by the trampoline page table
+const PRESENT_WRITABLE: u64 = 0b11;
+const HUGE_PAGE: u64 = 1 << 7;
+
+/// MSRs the firmware doesn't preserve: EFER, STAR, LSTAR, SFMASK, FS base,
+/// GS base, kernel GS base and PAT
+const SAVED_MSRS: [u32; 8] = [
+    0xC000_0080,
+    0xC000_0081,
+    0xC000_0082,
+    0xC000_0084,
+    0xC000_0100,
+    0xC000_0101,
+    0xC000_0102,
+    0x277,
+];
+
+global_asm!(
+    r#"
+    .section .rodata.wake_trampoline, "a"
+    .balign 16
+    .global wake_trampoline_start
+wake_trampoline_start:
+    .code16
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    xor ebx, ebx
+    mov bx, ax
+    shl ebx, 4
+    lgdt [WAKE_GDTR_OFFSET]
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+    // far jump to wake_code32, the target is patched in
+    .byte 0x66, 0xea
+    .global wake_jump32
+wake_jump32:
+    .long 0
+    .word 0x08
+
+    .code32
+    .global wake_code32
+wake_code32:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov eax, [ebx + WAKE_CR3_OFFSET]
+    mov cr3, eax
+    // EFER.LME and EFER.NXE, the kernel page table uses no-execute bits
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+    mov eax, cr0
+    or eax, 0x80000001
+    mov cr0, eax
+    // far jump to wake_code64, the target is patched in
+    .byte 0xea
+    .global wake_jump64
+wake_jump64:
+    .long 0
+    .word 0x18
+
+    .code64
+    .global wake_code64
+wake_code64:
+    mov ebx, ebx
+    mov rax, [rbx + WAKE_ENTRY_OFFSET]
+    jmp rax
+
+    .balign 8
+    .global wake_gdt
+wake_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+    .quad 0x00AF9A000000FFFF
+    .global wake_gdtr
+wake_gdtr:
+    .word wake_gdtr - wake_gdt - 1
+    .long 0
+    .balign 8
+    .global wake_cr3
+wake_cr3:
+    .long 0
+    .balign 8
+    .global wake_entry
+wake_entry:
+    .quad 0
+    .global wake_trampoline_end
+wake_trampoline_end:
+
+    // offsets into the trampoline, it runs wherever it was copied to
+    .set WAKE_GDTR_OFFSET, wake_gdtr - wake_trampoline_start
+    .set WAKE_CR3_OFFSET, wake_cr3 - wake_trampoline_start
+    .set WAKE_ENTRY_OFFSET, wake_entry - wake_trampoline_start
+    .text
+"#
+);
+
+unsafe extern "C" {
+    static wake_trampoline_start: u8;
+    static wake_trampoline_end: u8;
+    static wake_jump32: u8;
+    static wake_code32: u8;
+    static wake_jump64: u8;
+    static wake_code64: u8;
+    static wake_gdt: u8;
+    static wake_gdtr: u8;
+    static wake_cr3: u8;
+    static wake_entry: u8;
+}
+
+/// CPU state saved before sleeping
+#[repr(C)]
+struct Context {
+    rsp: u64,
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    msrs: [u64; SAVED_MSRS.len()],
+}
+
+static mut CONTEXT: Context = Context {
+    rsp: 0,
+    cr0: 0,
+    cr3: 0,
+    cr4: 0,
+    msrs: [0; SAVED_MSRS.len()],
+};
+
+/// Pages reserved for the wakeup path
+#[derive(Debug, Clone, Copy)]
+pub struct WakeMemory {
+    /// Physical address of the first page, also the waking vector
+    pub phys: PhysAddr,
+    virt: VirtAddr,
+}
+
+impl WakeMemory {
+    /// Reserve the wakeup pages, returns None if there is no free memory
+    /// reachable from real mode
+    pub fn reserve() -> Option<Self> {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut()?;
+        let phys = allocator
+            .allocate_contiguous_frames_below(WAKE_PAGES, PhysAddr::new(REAL_MODE_LIMIT))?;
+        Some(Self {
+            phys,
+            virt: VirtAddr::new(phys.as_u64() + allocator.hddm_offset),
+        })
+    }
+
+    fn page(&self, index: u64) -> (PhysAddr, *mut u64) {
+        (
+            self.phys + index * PAGE_SIZE,
+            (self.virt + index * PAGE_SIZE).as_mut_ptr(),
+        )
+    }
+
+    /// Copy the trampoline in and build its page table
+    ///
+    /// Done before every sleep, since the kernel half of the page table can
+    /// gain entries at any time.
+    pub fn prepare(&self) {
+        let start = &raw const wake_trampoline_start as u64;
+        let offset = |symbol: *const u8| symbol as u64 - start;
+        let base = self.phys.as_u64();
+        let (_, code) = self.page(0);
+        let code = code as *mut u8;
+
+        let (pml4_phys, pml4) = self.page(1);
+        let (pdpt_phys, pdpt) = self.page(2);
+        let (pd_phys, pd) = self.page(3);
+
+        unsafe {
+            let len = &raw const wake_trampoline_end as usize - start as usize;
+            ptr::copy_nonoverlapping(start as *const u8, code, len);
+
+            let patch_u32 = |symbol: *const u8, value: u64| {
+                code.add(offset(symbol) as usize)
+                    .cast::<u32>()
+                    .write_unaligned(value as u32)
+            };
+            patch_u32(
+                &raw const wake_jump32,
+                base + offset(&raw const wake_code32),
+            );
+            patch_u32(
+                &raw const wake_jump64,
+                base + offset(&raw const wake_code64),
+            );
+            patch_u32(
+                (&raw const wake_gdtr).add(2),
+                base + offset(&raw const wake_gdt),
+            );
+            patch_u32(&raw const wake_cr3, pml4_phys.as_u64());
+            code.add(offset(&raw const wake_entry) as usize)
+                .cast::<u64>()
+                .write_unaligned(wake_resume as usize as u64);
+
+            // identity map the first 2 MiB, which holds the trampoline
+            ptr::write_bytes(pml4, 0, 512);
+            ptr::write_bytes(pdpt, 0, 512);
+            ptr::write_bytes(pd, 0, 512);
+            pd.write(HUGE_PAGE | PRESENT_WRITABLE);
+            pdpt.write(pd_phys.as_u64() | PRESENT_WRITABLE);
+            pml4.write(pdpt_phys.as_u64() | PRESENT_WRITABLE);
+
+            // share the kernel half with the kernel page table
+            let hhdm = self.virt.as_u64() - self.phys.as_u64();
+            let kernel = (Cr3::read().0.start_address().as_u64() + hhdm) as *const u64;
+            ptr::copy_nonoverlapping(kernel.add(256), pml4.add(256), 256);
+        }
+    }
+}
+
+/// Returns an error message if this CPU setup can't be resumed
+pub fn check_cpu() -> Result<(), &'static str> {
+    if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+        return Err("5-level paging is not supported by the wakeup path");
+    }
+    Ok(())
+}
+
+/// Save the CPU state, then call `enter` to put the platform to sleep
+///
+/// Returns whatever `enter` returns if the platform didn't sleep, or 0 once
+/// the system has resumed through the trampoline.
+///
+/// # Safety
+/// Interrupts must be disabled and `WakeMemory::prepare` must have been
+/// called with the waking vector pointing at it.
+pub unsafe fn save_and_sleep(enter: extern "C" fn() -> u64) -> u64 {
+    unsafe {
+        let context = &raw mut CONTEXT;
+        let (frame, flags) = Cr3::read_raw();
+        (*context).cr0 = Cr0::read_raw();
+        (*context).cr3 = frame.start_address().as_u64() | flags as u64;
+        (*context).cr4 = Cr4::read_raw();
+        for (value, &msr) in (*context).msrs.iter_mut().zip(SAVED_MSRS.iter()) {
+            *value = Msr::new(msr).read();
+        }
+
+        save_registers(enter)
+    }
+}
+
+/// Push the callee-saved registers and record the stack, then call `enter`
+#[unsafe(naked)]
+unsafe extern "C" fn save_registers(enter: extern "C" fn() -> u64) -> u64 {
+    naked_asm!(
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // keep the stack 16 byte aligned for the call
+        "sub rsp, 8",
+        "mov [rip + {context} + {rsp}], rsp",
+        "call rdi",
+        // only reached if the platform didn't sleep
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "ret",
+        context = sym CONTEXT,
+        rsp = const offset_of!(Context, rsp),
+    );
+}
+
+/// Entered from the trampoline in long mode, on its page table and GDT, or
+/// from the hibernation restore code
+#[unsafe(naked)]
+unsafe extern "C" fn wake_resume() {
+    naked_asm!(
+        "mov rax, [rip + {context} + {cr4}]",
+        "mov cr4, rax",
+        "mov rax, [rip + {context} + {cr3}]",
+        "mov cr3, rax",
+        "mov rax, [rip + {context} + {cr0}]",
+        "mov cr0, rax",
+        "mov rsp, [rip + {context} + {rsp}]",
+        "call {restore}",
+        // return from save_registers as if `enter` returned 0
+        "xor eax, eax",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "ret",
+        context = sym CONTEXT,
+        cr0 = const offset_of!(Context, cr0),
+        cr3 = const offset_of!(Context, cr3),
+        cr4 = const offset_of!(Context, cr4),
+        rsp = const offset_of!(Context, rsp),
+        restore = sym restore_cpu,
+    );
+}
+
+/// Address to jump to, in long mode with interrupts disabled, to continue from
+/// the state saved by `save_and_sleep`
+///
+/// Only the kernel has to be mapped. Used by hibernation, which restores
+/// memory itself and then enters the same path as the trampoline.
+pub fn resume_entry() -> u64 {
+    wake_resume as usize as u64
+}
+
+/// Restore the descriptor tables and MSRs lost in the sleep state
+extern "C" fn restore_cpu() {
+    unsafe {
+        let context = &raw const CONTEXT;
+        for (&value, &msr) in (*context).msrs.iter().zip(SAVED_MSRS.iter()) {
+            Msr::new(msr).write(value);
+        }
+        gdt::reload();
+    }
+    init_idt();
+}
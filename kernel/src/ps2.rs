@@ -9,14 +9,33 @@
 
 pub mod keyboard;
 
-use crate::{info, warn};
-use x86_64::instructions::port::Port;
+#[cfg(test)]
+pub mod tests;
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::{
+    info,
+    interrupts::apic::KEYBOARD_VECTOR,
+    tasks::scheduler::{SchedPolicy, kcreate_task, kyield_task, set_policy},
+    warn,
+};
+use x86_64::instructions::{interrupts, port::Port};
 
 /// PS/2 controller data port (read/write)
 const PS2_DATA_PORT: u16 = 0x60;
 /// PS/2 controller command/status port
 const PS2_COMMAND_PORT: u16 = 0x64;
 
+/// Number of polls before giving up on a response from the controller
+const RESPONSE_TIMEOUT_POLLS: u32 = 100_000;
+
+/// Times the controller has been re-initialized after boot
+static RESETS: AtomicU32 = AtomicU32::new(0);
+
+/// Whether a device answered the second port test at the last initialization
+static SECOND_PORT_PRESENT: AtomicBool = AtomicBool::new(false);
+
 /// PS/2 controller status register bits
 pub mod status_bits {
     /// Output buffer full (data available to read)
@@ -57,6 +76,8 @@ pub mod commands {
     pub const DISABLE_FIRST_PORT: u8 = 0xAD;
     /// Enable first PS/2 port
     pub const ENABLE_FIRST_PORT: u8 = 0xAE;
+    /// Write the next byte to the output buffer as if the first port sent it
+    pub const WRITE_FIRST_PORT_OUTPUT: u8 = 0xD2;
 }
 
 /// PS/2 keyboard commands
@@ -184,10 +205,26 @@ impl Ps2Controller {
         self.send_command(command);
         self.read_data()
     }
+
+    /// Read data from the PS/2 controller, giving up if nothing arrives in time
+    pub fn try_read_data(&mut self) -> Option<u8> {
+        for _ in 0..RESPONSE_TIMEOUT_POLLS {
+            if self.output_buffer_full() {
+                return Some(unsafe { self.data_port.read() });
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
 }
 
 /// Initialize the PS/2 subsystem
 pub fn init() -> Result<(), &'static str> {
+    init_with(&mut keyboard::KEYBOARD.lock())
+}
+
+/// Initialize the controller, replacing the keyboard driver state in `keyboard_state`
+fn init_with(keyboard_state: &mut Option<keyboard::KeyboardDriver>) -> Result<(), &'static str> {
     info!("Initializing PS/2 subsystem");
     
     let mut controller = Ps2Controller::new();
@@ -215,6 +252,10 @@ pub fn init() -> Result<(), &'static str> {
         return Err("PS/2 controller self-test failed");
     }
     
+    let second_port = detect_second_port(&mut controller);
+    SECOND_PORT_PRESENT.store(second_port, Ordering::Relaxed);
+    info!("PS/2 second port (mouse): {}", if second_port { "present" } else { "absent" });
+
     let port_test = controller.send_command_with_response(commands::TEST_FIRST_PORT);
     if port_test != 0x00 {
         warn!("PS/2 keyboard port test failed: 0x{:02X}", port_test);
@@ -224,7 +265,7 @@ pub fn init() -> Result<(), &'static str> {
     controller.send_command(commands::ENABLE_FIRST_PORT);
     
     // Initialize keyboard
-    keyboard::init(&mut controller)?;
+    keyboard::init(&mut controller, keyboard_state)?;
 
     // Re-enable interrupts for the first PS/2 port (keyboard)
     let config = controller.send_command_with_response(commands::READ_CONFIG);
@@ -242,4 +283,109 @@ pub fn init() -> Result<(), &'static str> {
 
     info!("PS/2 subsystem initialized successfully");
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Check for a working second PS/2 port, leaving it disabled
+///
+/// There is no mouse driver yet, so a device found there is only reported.
+fn detect_second_port(controller: &mut Ps2Controller) -> bool {
+    controller.send_command(commands::ENABLE_SECOND_PORT);
+    let config = controller.send_command_with_response(commands::READ_CONFIG);
+    controller.send_command(commands::DISABLE_SECOND_PORT);
+
+    // the clock stays disabled on single channel controllers
+    if config & config_bits::SECOND_PORT_CLOCK_DISABLED != 0 {
+        return false;
+    }
+
+    controller.send_command_with_response(commands::TEST_SECOND_PORT) == 0x00
+}
+
+/// Re-initialize the controller and re-detect the devices behind it
+///
+/// Runs with interrupts disabled so the keyboard interrupt handler can't
+/// consume the responses of the initialization sequence.
+pub fn reset() -> Result<(), &'static str> {
+    warn!("Resetting PS/2 controller");
+    RESETS.fetch_add(1, Ordering::Relaxed);
+    reinit()
+}
+
+/// Bring the controller back after a sleep state, which powered it off
+///
+/// Unlike `reset`, this isn't counted as a recovery.
+pub fn resume() -> Result<(), &'static str> {
+    reinit()
+}
+
+/// Initialize the controller again, from a task with interrupts enabled
+fn reinit() -> Result<(), &'static str> {
+    // the keyboard lock sleeps, so it is taken before interrupts are masked
+    let mut keyboard_state = keyboard::KEYBOARD.lock();
+    interrupts::without_interrupts(|| init_with(&mut keyboard_state))?;
+    drop(keyboard_state);
+
+    // the keyboard reset restored its default typematic settings
+    keyboard::set_typematic(crate::input::repeat_settings());
+    Ok(())
+}
+
+/// Make `scancode` appear as if the keyboard sent it, for tests
+///
+/// Uses the controller's loopback, which not every controller has; QEMU's
+/// does. The byte goes through the keyboard interrupt like any other, so it
+/// must not be sent while the keyboard is answering a command.
+pub fn inject_scancode(scancode: u8) -> Result<(), &'static str> {
+    let mut controller = Ps2Controller::new();
+    // wait for the interrupt handler to take the previous byte
+    let mut polls = 0;
+    while controller.output_buffer_full() {
+        polls += 1;
+        if polls == RESPONSE_TIMEOUT_POLLS {
+            return Err("Output buffer never emptied");
+        }
+        core::hint::spin_loop();
+    }
+    controller.send_command(commands::WRITE_FIRST_PORT_OUTPUT);
+    controller.write_data(scancode);
+    Ok(())
+}
+
+/// Number of controller resets since boot
+pub fn reset_count() -> u32 {
+    RESETS.load(Ordering::Relaxed)
+}
+
+/// Whether a device was found on the second port
+pub fn second_port_present() -> bool {
+    SECOND_PORT_PRESENT.load(Ordering::Relaxed)
+}
+
+/// Real-time priority of the recovery task, so input comes back quickly even
+/// when the system is busy
+const RECOVERY_PRIORITY: u8 = 50;
+
+/// Start the task resetting the controller when the keyboard reports too many errors
+pub fn spawn_recovery_task() {
+    let pid = interrupts::without_interrupts(|| kcreate_task(recovery_task, "ps2 recovery"));
+    set_policy(pid, SchedPolicy::Fifo(RECOVERY_PRIORITY)).expect("recovery task vanished");
+}
+
+fn recovery_task() -> ! {
+    loop {
+        // woken by the keyboard's deferred work once errors pile up
+        kyield_task(KEYBOARD_VECTOR);
+
+        if keyboard::needs_reset() {
+            match reset() {
+                Ok(()) => {
+                    info!("PS/2 controller recovered");
+                }
+                #[allow(unused_variables)]
+                Err(e) => {
+                    warn!("PS/2 reset failed: {}", e);
+                }
+            }
+        }
+    }
+}
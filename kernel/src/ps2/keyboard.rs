@@ -1,17 +1,94 @@
 //! PS/2 keyboard driver implementation.
 //!
-//! This module handles PS/2 keyboard initialization, interrupt handling,
-//! and provides an interface for reading keyboard input.
+//! This module handles PS/2 keyboard initialization and interrupt handling,
+//! and reports the decoded key events to `input::devices`.
+//!
+//! The interrupt handler only takes the bytes off the controller. Decoding
+//! them, reporting the events and answering the keyboard run as deferred work
+//! on the real-time deferred-work task, so input stays responsive under load
+//! without doing the work with interrupts masked.
 
-use crate::{info, warn, debug};
+use crate::{
+    info, warn, debug,
+    input::{self, RepeatSettings, SoftRepeat, devices::DeviceId},
+    interrupts::apic::KEYBOARD_VECTOR,
+    sync::{Mutex, SleepMutex},
+    tasks::{
+        deferred::{self, Work},
+        scheduler::wake_tasks,
+    },
+    time,
+};
 use alloc::collections::VecDeque;
-use spin::Mutex;
-use x86_64::instructions::port::Port;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use x86_64::instructions::{interrupts, port::Port};
+
+use super::{Ps2Controller, keyboard_commands, responses, status_bits};
+
+/// Outstanding communication errors after which the controller is reset
+const ERROR_THRESHOLD: u32 = 8;
+
+/// Times a command is retried when the keyboard asks for a resend
+const COMMAND_RETRIES: usize = 3;
+
+/// Command bytes that can be queued at once
+const COMMAND_QUEUE_SIZE: usize = 16;
+
+/// Bytes the interrupt handler can hold for the deferred work
+const RECEIVED_SIZE: usize = 64;
+
+/// Communication errors seen since boot
+static TOTAL_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// Bytes taken off the controller, waiting to be processed
+static RECEIVED: Mutex<Received> = Mutex::new(
+    "PS2_RECEIVED",
+    Received {
+        bytes: [(0, 0); RECEIVED_SIZE],
+        head: 0,
+        len: 0,
+        overrun: false,
+    },
+);
 
-use super::{Ps2Controller, keyboard_commands, responses};
+/// Processes the received bytes and drives software key repeat
+static KEYBOARD_WORK: Work = Work::new("ps2 keyboard", process_received);
 
-/// Maximum size of the keyboard input buffer
-const KEYBOARD_BUFFER_SIZE: usize = 256;
+/// Whether a held key is repeated in software, so the timer has to schedule
+/// `KEYBOARD_WORK`
+static REPEATING: AtomicBool = AtomicBool::new(false);
+
+/// Bytes read by the interrupt handler, with the status they came with
+struct Received {
+    bytes: [(u8, u8); RECEIVED_SIZE],
+    head: usize,
+    len: usize,
+    /// Whether bytes were dropped since the last `pop`
+    overrun: bool,
+}
+
+impl Received {
+    fn push(&mut self, status: u8, byte: u8) {
+        if self.len == RECEIVED_SIZE {
+            self.overrun = true;
+            return;
+        }
+        self.bytes[(self.head + self.len) % RECEIVED_SIZE] = (status, byte);
+        self.len += 1;
+    }
+
+    /// Next byte and its status, and whether bytes were dropped before it
+    fn pop(&mut self) -> (Option<(u8, u8)>, bool) {
+        let overrun = core::mem::take(&mut self.overrun);
+        if self.len == 0 {
+            return (None, overrun);
+        }
+        let received = self.bytes[self.head];
+        self.head = (self.head + 1) % RECEIVED_SIZE;
+        self.len -= 1;
+        (Some(received), overrun)
+    }
+}
 
 /// Keyboard scan codes (Set 1)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -42,8 +119,12 @@ pub enum ScanCode {
     LeftShift = 0x2A,
     RightShift = 0x36,
     LeftCtrl = 0x1D,
+    // extended 0x1D, numbered apart from the left one
+    RightCtrl = 0x9D,
     LeftAlt = 0x38,
     CapsLock = 0x3A,
+    NumLock = 0x45,
+    ScrollLock = 0x46,
     
     // Punctuation
     Minus = 0x0C,
@@ -92,6 +173,14 @@ impl ScanCode {
         )
     }
 
+    /// Check if this scancode is a modifier or lock key, which never repeats
+    pub fn is_modifier(&self) -> bool {
+        matches!(self,
+            ScanCode::LeftShift | ScanCode::RightShift | ScanCode::LeftCtrl | ScanCode::RightCtrl |
+            ScanCode::LeftAlt | ScanCode::CapsLock | ScanCode::NumLock | ScanCode::ScrollLock
+        )
+    }
+
     /// Convert scancode to character, considering shift state
     /// Returns None if the scancode doesn't represent a printable character
     pub fn to_char(&self, shift_pressed: bool, caps_lock: bool) -> Option<char> {
@@ -167,13 +256,16 @@ pub enum KeyEvent {
 }
 
 /// Keyboard state tracking modifier keys
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct KeyboardState {
     pub left_shift: bool,
     pub right_shift: bool,
     pub left_ctrl: bool,
+    pub right_ctrl: bool,
     pub left_alt: bool,
     pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
 }
 
 impl KeyboardState {
@@ -181,7 +273,17 @@ impl KeyboardState {
     pub fn shift_pressed(&self) -> bool {
         self.left_shift || self.right_shift
     }
+
+    /// Check if any ctrl key is pressed
+    pub fn ctrl_pressed(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
     
+    /// Lock key state in the format of the Set LEDs command
+    pub fn leds(&self) -> u8 {
+        (self.scroll_lock as u8) | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+
     /// Update state based on key event
     pub fn update(&mut self, event: KeyEvent) {
         match event {
@@ -190,8 +292,11 @@ impl KeyboardState {
                     ScanCode::LeftShift => self.left_shift = true,
                     ScanCode::RightShift => self.right_shift = true,
                     ScanCode::LeftCtrl => self.left_ctrl = true,
+                    ScanCode::RightCtrl => self.right_ctrl = true,
                     ScanCode::LeftAlt => self.left_alt = true,
                     ScanCode::CapsLock => self.caps_lock = !self.caps_lock,
+                    ScanCode::NumLock => self.num_lock = !self.num_lock,
+                    ScanCode::ScrollLock => self.scroll_lock = !self.scroll_lock,
                     _ => {}
                 }
             }
@@ -200,6 +305,7 @@ impl KeyboardState {
                     ScanCode::LeftShift => self.left_shift = false,
                     ScanCode::RightShift => self.right_shift = false,
                     ScanCode::LeftCtrl => self.left_ctrl = false,
+                    ScanCode::RightCtrl => self.right_ctrl = false,
                     ScanCode::LeftAlt => self.left_alt = false,
                     _ => {}
                 }
@@ -209,28 +315,153 @@ impl KeyboardState {
     }
 }
 
-/// Global keyboard state and input buffer
-pub static KEYBOARD: Mutex<Option<KeyboardDriver>> = Mutex::new(None);
+/// Global keyboard driver state
+///
+/// Only taken from tasks: the deferred work processing the keyboard's bytes
+/// runs at real-time priority, so a normal task holding it, like one changing
+/// the typematic settings, is boosted until it lets go.
+pub static KEYBOARD: SleepMutex<Option<KeyboardDriver>> = SleepMutex::new(None);
 
 /// Keyboard driver state
 pub struct KeyboardDriver {
-    input_buffer: VecDeque<KeyEvent>,
-    state: KeyboardState,
+    /// Input device the keyboard's events are reported as
+    device: DeviceId,
+    /// Lock key state last sent to the keyboard, as in Set LEDs
+    leds: u8,
     extended_scancode: bool,
+    /// Communication errors not yet offset by valid scancodes
+    errors: u32,
+    /// Command bytes waiting to be sent to the keyboard
+    commands: VecDeque<u8>,
+    /// Byte sent to the keyboard that hasn't been acknowledged yet
+    awaiting_ack: Option<u8>,
+    /// Resends requested for the byte awaiting acknowledgement
+    resends: usize,
+    /// Software key repeat, see `input`
+    repeat: SoftRepeat,
 }
 
 impl KeyboardDriver {
     /// Create a new keyboard driver
-    fn new() -> Self {
+    fn new(device: DeviceId) -> Self {
         Self {
-            input_buffer: VecDeque::with_capacity(KEYBOARD_BUFFER_SIZE),
-            state: KeyboardState::default(),
+            device,
+            leds: 0,
             extended_scancode: false,
+            errors: 0,
+            commands: VecDeque::with_capacity(COMMAND_QUEUE_SIZE),
+            awaiting_ack: None,
+            resends: 0,
+            repeat: SoftRepeat::default(),
+        }
+    }
+
+    /// Generate a software repeat of the held key if one is due
+    fn repeat_tick(&mut self, now: u64) {
+        if let Some(scancode) = self.repeat.poll(now) {
+            self.push_event(KeyEvent::KeyDown(scancode));
         }
     }
+
+    /// Report an event, updating the LEDs if it toggled a lock key
+    fn push_event(&mut self, event: KeyEvent) {
+        if let Some(state) = input::devices::report(self.device, event)
+            && state.leds() != self.leds
+        {
+            self.leds = state.leds();
+            self.sync_leds();
+        }
+    }
+
+    /// Queue a command (with its data bytes) for the keyboard
+    ///
+    /// Bytes are sent one at a time, each after the previous one has been
+    /// acknowledged, so this never waits for the keyboard.
+    pub fn queue_command(&mut self, bytes: &[u8]) {
+        if self.commands.len() + bytes.len() > COMMAND_QUEUE_SIZE {
+            warn!("Keyboard command queue full, dropping command {:#x?}", bytes);
+            return;
+        }
+        self.commands.extend(bytes);
+        if self.awaiting_ack.is_none() {
+            self.send_next_command_byte();
+        }
+    }
+
+    fn send_next_command_byte(&mut self) {
+        self.awaiting_ack = self.commands.pop_front();
+        self.resends = 0;
+        if let Some(byte) = self.awaiting_ack {
+            Ps2Controller::new().write_data(byte);
+        }
+    }
+
+    /// Handle an ACK or resend request for the byte awaiting acknowledgement
+    fn handle_command_response(&mut self, response: u8) {
+        let Some(byte) = self.awaiting_ack else {
+            // nothing was sent, so the keyboard is confused
+            self.record_error();
+            return;
+        };
+
+        if response == responses::ACK {
+            self.send_next_command_byte();
+        } else if self.resends < COMMAND_RETRIES {
+            self.resends += 1;
+            Ps2Controller::new().write_data(byte);
+        } else {
+            warn!("Keyboard kept requesting resends of 0x{:02X}, dropping command", byte);
+            self.record_error();
+            self.commands.clear();
+            self.awaiting_ack = None;
+        }
+    }
+
+    /// Handle a byte received with a parity or timeout error
+    ///
+    /// If a command byte is awaiting acknowledgement the garbled byte was
+    /// probably its ACK, so the command byte is sent again.
+    pub fn handle_corrupt_byte(&mut self) {
+        self.record_error();
+        if self.awaiting_ack.is_some() {
+            self.handle_command_response(responses::RESEND);
+        }
+    }
+
+    /// Update the keyboard LEDs to match the lock key state
+    fn sync_leds(&mut self) {
+        self.queue_command(&[keyboard_commands::SET_LEDS, self.leds]);
+    }
+
+    /// Record a communication error (parity/timeout, resend request, overrun)
+    pub fn record_error(&mut self) {
+        self.errors += 1;
+        TOTAL_ERRORS.fetch_add(1, Ordering::Relaxed);
+        self.extended_scancode = false;
+    }
+
+    /// Whether enough errors have piled up to warrant a controller reset
+    pub fn needs_reset(&self) -> bool {
+        self.errors >= ERROR_THRESHOLD
+    }
     
     /// Process a raw scancode from the keyboard
     pub fn process_scancode(&mut self, scancode: u8) {
+        match scancode {
+            responses::ACK | responses::RESEND => {
+                self.handle_command_response(scancode);
+                return;
+            }
+            // key detection error / buffer overrun, self-test failure
+            0x00 | 0xFF | responses::SELF_TEST_FAILED => {
+                debug!("Keyboard error response: 0x{:02X}", scancode);
+                self.record_error();
+                return;
+            }
+            _ => {}
+        }
+        self.errors = self.errors.saturating_sub(1);
+
         if scancode == 0xE0 {
             self.extended_scancode = true;
             return;
@@ -243,10 +474,7 @@ impl KeyboardDriver {
             None => {
                 debug!("Unknown scancode: 0x{:02X} (extended: {})", base_scancode, self.extended_scancode);
                 self.extended_scancode = false;
-                let event = KeyEvent::Unknown(scancode);
-                if self.input_buffer.len() < KEYBOARD_BUFFER_SIZE {
-                    self.input_buffer.push_back(event);
-                }
+                self.push_event(KeyEvent::Unknown(scancode));
                 return;
             }
         };
@@ -257,21 +485,24 @@ impl KeyboardDriver {
             KeyEvent::KeyDown(scan_code)
         };
         
-        self.state.update(event);
-        
-        if self.input_buffer.len() < KEYBOARD_BUFFER_SIZE {
-            self.input_buffer.push_back(event);
-        } else {
-            warn!("Keyboard input buffer overflow");
-        }
-        
         self.extended_scancode = false;
+
+        if input::soft_repeat_ps2() {
+            // a press of the key already held is the keyboard's own repeat
+            if event == KeyEvent::KeyDown(scan_code) && self.repeat.held() == Some(scan_code) {
+                return;
+            }
+            self.repeat.key(event, time::uptime_ms());
+        }
+
+        self.push_event(event);
     }
     
     /// Convert raw scancode to ScanCode enum
     fn scancode_to_enum(&self, scancode: u8, extended: bool) -> Option<ScanCode> {
         if extended {
             match scancode {
+                0x1D => Some(ScanCode::RightCtrl),
                 0x48 => Some(ScanCode::UpArrow),
                 0x50 => Some(ScanCode::DownArrow),
                 0x4B => Some(ScanCode::LeftArrow),
@@ -311,6 +542,7 @@ impl KeyboardDriver {
                 
                 0x2A => Some(ScanCode::LeftShift), 0x36 => Some(ScanCode::RightShift),
                 0x1D => Some(ScanCode::LeftCtrl), 0x38 => Some(ScanCode::LeftAlt), 0x3A => Some(ScanCode::CapsLock),
+                0x45 => Some(ScanCode::NumLock), 0x46 => Some(ScanCode::ScrollLock),
                 
                 0x0C => Some(ScanCode::Minus), 0x0D => Some(ScanCode::Equals), 0x1A => Some(ScanCode::LeftBracket),
                 0x1B => Some(ScanCode::RightBracket), 0x27 => Some(ScanCode::Semicolon), 0x28 => Some(ScanCode::Quote),
@@ -321,126 +553,174 @@ impl KeyboardDriver {
             }
         }
     }
-    
-    /// Read the next key event from the buffer
-    pub fn read_key(&mut self) -> Option<KeyEvent> {
-        self.input_buffer.pop_front()
-    }
-    
-    /// Get the current keyboard state
-    pub fn get_state(&self) -> KeyboardState {
-        self.state
-    }
-    
-    /// Check if there are pending key events
-    pub fn has_key(&self) -> bool {
-        !self.input_buffer.is_empty()
-    }
 }
 
-/// Initialize the keyboard
-pub fn init(controller: &mut Ps2Controller) -> Result<(), &'static str> {
+/// Initialize the keyboard, replacing the driver state in `keyboard`
+pub fn init(
+    controller: &mut Ps2Controller,
+    keyboard_state: &mut Option<KeyboardDriver>,
+) -> Result<(), &'static str> {
     info!("Initializing PS/2 keyboard");
     
-    controller.write_data(keyboard_commands::RESET);
-    let response = controller.read_data();
-    if response != responses::ACK {
-        warn!("Keyboard reset failed to ACK: 0x{:02X}", response);
-        return Err("Keyboard reset failed");
-    }
+    send_command(controller, keyboard_commands::RESET).inspect_err(|_| {
+        warn!("Keyboard reset failed to ACK");
+    })?;
     
-    let self_test = controller.read_data();
-    if self_test != responses::SELF_TEST_PASSED {
-        warn!("Keyboard self-test failed: 0x{:02X}", self_test);
+    let self_test = controller.try_read_data();
+    if self_test != Some(responses::SELF_TEST_PASSED) {
+        warn!("Keyboard self-test failed: {:?}", self_test);
         return Err("Keyboard self-test failed");
     }
     
-    controller.write_data(keyboard_commands::SCANCODE_SET);
-    let ack = controller.read_data();
-    if ack != responses::ACK {
-        warn!("Scancode set command failed: 0x{:02X}", ack);
-        return Err("Scancode set command failed");
-    }
-    
-    controller.write_data(0x01); // Set 1
-    let ack2 = controller.read_data();
-    if ack2 != responses::ACK {
-        warn!("Scancode set 1 selection failed: 0x{:02X}", ack2);
-        return Err("Scancode set selection failed");
-    }
+    send_command(controller, keyboard_commands::SCANCODE_SET)?;
+    send_command(controller, 0x01)?; // Set 1
+    send_command(controller, keyboard_commands::ENABLE_SCANNING)?;
     
-    controller.write_data(keyboard_commands::ENABLE_SCANNING);
-    let ack3 = controller.read_data();
-    if ack3 != responses::ACK {
-        warn!("Enable scanning failed: 0x{:02X}", ack3);
-        return Err("Enable scanning failed");
+    // a reset keyboard is the same input device, with its keys released
+    let device = match keyboard_state.as_ref() {
+        Some(keyboard) => {
+            input::devices::release_all(keyboard.device);
+            keyboard.device
+        }
+        None => input::devices::register("ps2 keyboard"),
+    };
+    let mut keyboard = KeyboardDriver::new(device);
+    let leds = input::devices::state(device).unwrap_or_default().leds();
+    if leds != 0 {
+        keyboard.leds = leds;
+        keyboard.sync_leds();
     }
-    
-    let mut keyboard_lock = KEYBOARD.lock();
-    *keyboard_lock = Some(KeyboardDriver::new());
+    *keyboard_state = Some(keyboard);
+    deferred::register(&KEYBOARD_WORK);
     
     info!("PS/2 keyboard initialized successfully");
     Ok(())
 }
 
+/// Send a byte to the keyboard and wait for its ACK, resending on request
+#[allow(unused_variables)]
+fn send_command(controller: &mut Ps2Controller, byte: u8) -> Result<(), &'static str> {
+    for _ in 0..COMMAND_RETRIES {
+        controller.write_data(byte);
+        match controller.try_read_data() {
+            Some(responses::ACK) => return Ok(()),
+            Some(responses::RESEND) => continue,
+            Some(response) => {
+                warn!("Keyboard command 0x{:02X} failed: 0x{:02X}", byte, response);
+                return Err("Keyboard command failed");
+            }
+            None => {
+                warn!("Keyboard command 0x{:02X} timed out", byte);
+                return Err("Keyboard command timed out");
+            }
+        }
+    }
+    Err("Keyboard kept requesting resends")
+}
+
 /// Handle keyboard interrupt (called from interrupt handler)
 #[inline(always)]
 pub fn handle_interrupt() {
     let mut data_port = Port::<u8>::new(0x60);
     let mut status_port = Port::<u8>::new(0x64);
 
-    while unsafe { status_port.read() } & 0x01 != 0 { // While output buffer full
+    let mut received = RECEIVED.lock();
+    loop {
+        let status = unsafe { status_port.read() };
+        if status & status_bits::OUTPUT_BUFFER_FULL == 0 {
+            break;
+        }
         let scancode = unsafe { data_port.read() };
+        received.push(status, scancode);
+    }
+    drop(received);
+
+    deferred::schedule(&KEYBOARD_WORK);
+}
 
-        let mut keyboard_lock = KEYBOARD.lock();
-        if let Some(ref mut keyboard) = *keyboard_lock {
+/// Process the bytes the interrupt handler received, run as deferred work
+fn process_received() {
+    let mut keyboard_lock = KEYBOARD.lock();
+    let Some(ref mut keyboard) = *keyboard_lock else {
+        // no keyboard to make sense of them
+        while let (Some(_), _) = interrupts::without_interrupts(|| RECEIVED.lock().pop()) {}
+        return;
+    };
+
+    loop {
+        let (received, overrun) = interrupts::without_interrupts(|| RECEIVED.lock().pop());
+        if overrun {
+            keyboard.record_error();
+        }
+        let Some((status, scancode)) = received else {
+            break;
+        };
+        if status & (status_bits::PARITY_ERROR | status_bits::TIMEOUT_ERROR) != 0 {
+            keyboard.handle_corrupt_byte();
+        } else {
             keyboard.process_scancode(scancode);
         }
     }
-}
 
-/// Read the next key event
-pub fn read_key() -> Option<KeyEvent> {
-    let mut keyboard_lock = KEYBOARD.lock();
-    if let Some(ref mut keyboard) = *keyboard_lock {
-        keyboard.read_key()
-    } else {
-        None
+    keyboard.repeat_tick(time::uptime_ms());
+    REPEATING.store(keyboard.repeat.held().is_some(), Ordering::Relaxed);
+    let needs_reset = keyboard.needs_reset();
+    drop(keyboard_lock);
+
+    if needs_reset {
+        interrupts::without_interrupts(|| wake_tasks(KEYBOARD_VECTOR));
     }
 }
 
-/// Check if there are pending key events
-pub fn has_key() -> bool {
-    let keyboard_lock = KEYBOARD.lock();
-    if let Some(ref keyboard) = *keyboard_lock {
-        keyboard.has_key()
-    } else {
-        false
+/// Drive software key repeat, called from the timer interrupt handler
+pub fn timer_tick() {
+    if REPEATING.load(Ordering::Relaxed) {
+        deferred::schedule(&KEYBOARD_WORK);
     }
 }
 
-/// Get current keyboard state (public API)
-pub fn get_keyboard_state() -> Option<KeyboardState> {
-    let keyboard_lock = KEYBOARD.lock();
-    (*keyboard_lock).as_ref().map(|keyboard| keyboard.get_state())
+/// Encode a repeat rate and delay as the Set Repeat command byte
+///
+/// The delay is rounded to the nearest of 250/500/750/1000 ms and the rate to
+/// the closest one the keyboard supports (2 to 30 Hz).
+pub fn typematic_byte(settings: RepeatSettings) -> u8 {
+    let delay = (settings.delay_ms.clamp(250, 1000) + 125) / 250 - 1;
+
+    // repeat period is (8 + A) * 2^B * 4.17 ms for rate bits BBAAA
+    let millihertz = |rate: u32| 100_000_000 / (((8 + (rate & 7)) << ((rate >> 3) & 3)) * 417);
+    let target = settings.rate_hz * 1000;
+    let rate = (0..32)
+        .min_by_key(|&rate| millihertz(rate).abs_diff(target))
+        .unwrap();
+
+    (delay << 5 | rate) as u8
 }
 
-/// Convert a key event to a character if possible
-/// Returns None for non-character keys or key releases
-pub fn key_event_to_char(event: KeyEvent) -> Option<char> {
-    match event {
-        KeyEvent::KeyDown(scancode) => {
-            if let Some(state) = get_keyboard_state() {
-                scancode.to_char(state.shift_pressed(), state.caps_lock)
-            } else {
-                // Fallback if keyboard state is not available
-                scancode.to_char(false, false)
-            }
-        }
-        _ => None, // Key releases and unknown keys don't produce characters
+/// Set the key repeat delay and rate
+///
+/// Programs the PS/2 keyboard's typematic settings and the software repeat
+/// used by keyboards without hardware repeat.
+pub fn set_typematic(settings: RepeatSettings) {
+    input::set_repeat_settings(settings);
+
+    if let Some(ref mut keyboard) = *KEYBOARD.lock() {
+        keyboard.queue_command(&[keyboard_commands::SET_REPEAT, typematic_byte(settings)]);
     }
 }
 
+/// Whether the keyboard has reported enough errors to need a controller reset
+pub fn needs_reset() -> bool {
+    KEYBOARD
+        .lock()
+        .as_ref()
+        .is_some_and(KeyboardDriver::needs_reset)
+}
+
+/// Communication errors seen since boot
+pub fn error_count() -> u32 {
+    TOTAL_ERRORS.load(Ordering::Relaxed)
+}
+
 /// Check if a key event represents a printable character
 pub fn is_character_key(event: KeyEvent) -> bool {
     match event {
@@ -0,0 +1,69 @@
+//! PS/2 keyboard tests
+
+use crate::input::{RepeatSettings, SoftRepeat, repeat_settings};
+
+use super::keyboard::{KeyEvent, KeyboardState, ScanCode, typematic_byte};
+
+#[test_case]
+fn test_typematic_byte_limits() {
+    let fastest = RepeatSettings { delay_ms: 250, rate_hz: 30 };
+    let slowest = RepeatSettings { delay_ms: 1000, rate_hz: 2 };
+    assert_eq!(typematic_byte(fastest), 0x00);
+    assert_eq!(typematic_byte(slowest), 0x7F);
+}
+
+#[test_case]
+fn test_typematic_byte_rounding() {
+    // 10 Hz is closest to 9.99 Hz (0x0C), 600 ms rounds to 500 ms
+    let settings = RepeatSettings { delay_ms: 600, rate_hz: 10 };
+    assert_eq!(typematic_byte(settings), 0x2C);
+}
+
+#[test_case]
+fn test_lock_key_leds() {
+    let mut state = KeyboardState::default();
+    assert_eq!(state.leds(), 0);
+
+    state.update(KeyEvent::KeyDown(ScanCode::CapsLock));
+    state.update(KeyEvent::KeyDown(ScanCode::ScrollLock));
+    assert_eq!(state.leds(), 0b101);
+
+    state.update(KeyEvent::KeyDown(ScanCode::CapsLock));
+    state.update(KeyEvent::KeyDown(ScanCode::NumLock));
+    assert_eq!(state.leds(), 0b011);
+}
+
+#[test_case]
+fn test_either_ctrl_key() {
+    let mut state = KeyboardState::default();
+    state.update(KeyEvent::KeyDown(ScanCode::RightCtrl));
+    assert!(state.ctrl_pressed() && !state.left_ctrl);
+
+    state.update(KeyEvent::KeyUp(ScanCode::RightCtrl));
+    assert!(!state.ctrl_pressed());
+}
+
+#[test_case]
+fn test_soft_repeat() {
+    let settings = repeat_settings();
+    let delay = settings.delay_ms as u64;
+    let period = 1000 / settings.rate_hz as u64;
+
+    let mut repeat = SoftRepeat::default();
+    repeat.key(KeyEvent::KeyDown(ScanCode::A), 100);
+    assert_eq!(repeat.poll(100), None);
+    assert_eq!(repeat.poll(100 + delay), Some(ScanCode::A));
+    assert_eq!(repeat.poll(100 + delay), None);
+
+    // a late poll doesn't push back the repeats after it
+    assert_eq!(repeat.poll(100 + delay + period + 5), Some(ScanCode::A));
+    assert_eq!(repeat.poll(100 + delay + 2 * period - 1), None);
+    assert_eq!(repeat.poll(100 + delay + 2 * period), Some(ScanCode::A));
+
+    repeat.key(KeyEvent::KeyUp(ScanCode::A), 200);
+    assert_eq!(repeat.poll(100_000), None);
+
+    // modifiers never repeat
+    repeat.key(KeyEvent::KeyDown(ScanCode::LeftShift), 300);
+    assert_eq!(repeat.held(), None);
+}
@@ -0,0 +1,81 @@
+//! QEMU debug channels
+//!
+//! Two paravirtual devices are used when their boot argument is given:
+//!
+//! - `debugcon` copies everything written to COM1, the kernel log included,
+//!   to the debug console at port 0xE9 (`-debugcon file:boot.log`). The port
+//!   needs no setup, so output is captured from the moment the command line
+//!   is read, even if the serial port never comes up.
+//! - `pvpanic` reports kernel panics to the host through the pvpanic device
+//!   (`-device pvpanic`), which QEMU turns into a `GUEST_PANICKED` event, so
+//!   CI can stop a run on the first panic instead of waiting for a timeout.
+//!   It is ignored if the device isn't there.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::instructions::port::Port;
+
+use crate::bootargs;
+
+/// I/O port of the debug console
+const DEBUGCON_PORT: u16 = 0xE9;
+
+/// I/O port of the ISA pvpanic device
+const PVPANIC_PORT: u16 = 0x505;
+
+/// pvpanic event bits, also read back as the events the device supports
+mod pvpanic_events {
+    pub const PANICKED: u8 = 1 << 0;
+}
+
+static DEBUGCON: AtomicBool = AtomicBool::new(false);
+static PVPANIC: AtomicBool = AtomicBool::new(false);
+
+/// Enable the channels picked on the command line, once it was recorded
+pub fn init() {
+    DEBUGCON.store(bootargs::has_flag("debugcon"), Ordering::Relaxed);
+
+    if bootargs::has_flag("pvpanic") {
+        // an unused port reads as all ones
+        let supported = unsafe { Port::<u8>::new(PVPANIC_PORT).read() };
+        let present = supported != 0xFF && supported & pvpanic_events::PANICKED != 0;
+        PVPANIC.store(present, Ordering::Relaxed);
+    }
+}
+
+pub fn debugcon_enabled() -> bool {
+    DEBUGCON.load(Ordering::Relaxed)
+}
+
+pub fn pvpanic_enabled() -> bool {
+    PVPANIC.load(Ordering::Relaxed)
+}
+
+struct Debugcon;
+
+impl Write for Debugcon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut port = Port::<u8>::new(DEBUGCON_PORT);
+        for byte in s.bytes() {
+            unsafe { port.write(byte) };
+        }
+        Ok(())
+    }
+}
+
+/// Write to the debug console, if enabled
+pub fn debugcon_write(args: fmt::Arguments) {
+    if debugcon_enabled() {
+        let _ = Debugcon.write_fmt(args);
+    }
+}
+
+/// Tell the host the kernel panicked, if pvpanic is enabled
+pub fn notify_panic() {
+    if pvpanic_enabled() {
+        unsafe { Port::<u8>::new(PVPANIC_PORT).write(pvpanic_events::PANICKED) };
+    }
+}
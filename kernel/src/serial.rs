@@ -1,24 +1,45 @@
 use conquer_once::spin::Lazy;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
+use crate::sync::Mutex;
+
 /// Serial port for writing to the serial interface in QEMU.
 pub static SERIAL1: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
     let mut serial_port = unsafe { SerialPort::new(0x3F8) };
     serial_port.init();
-    Mutex::new(serial_port)
+    Mutex::new("SERIAL1", serial_port)
+});
+
+/// Second serial port, used for mirroring the terminal.
+pub static SERIAL2: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
+    let mut serial_port = unsafe { SerialPort::new(0x2F8) };
+    serial_port.init();
+    Mutex::new("SERIAL2", serial_port)
 });
 
+/// Reinitialize the serial ports after a sleep state, which reset them.
+pub fn resume() {
+    SERIAL1.lock().init();
+    SERIAL2.lock().init();
+}
+
+/// Write to COM1, and to the QEMU debug console if enabled.
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    let mut serial = SERIAL1.lock();
+    let _ = serial.write_fmt(args);
+    // under the COM1 lock, so output of different CPUs doesn't interleave
+    crate::qemu::debugcon_write(args);
+}
+
 /// Global print! macro that writes to the serial interface in QEMU.
 #[macro_export]
 macro_rules! serial_print {
-    ($($arg:tt)*) => {{
-        // Use absolute paths to prevent conflicts
-        let _ = ::core::fmt::Write::write_fmt(
-            &mut *$crate::serial::SERIAL1.lock(),
-            format_args!($($arg)*)
-        );
-    }};
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*))
+    };
 }
 
 /// Global println! macro that writes to the serial interface in QEMU.
@@ -1 +1,4 @@
+pub mod commands;
+pub mod editor;
+pub mod pager;
 pub mod task;
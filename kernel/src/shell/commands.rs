@@ -0,0 +1,289 @@
+//! Built-in shell commands
+//!
+//! Each command is an entry in `COMMANDS`; subsystems that can be compiled
+//! out keep their commands behind the same feature flag as the subsystem.
+
+mod aer;
+mod bench;
+mod chrt;
+mod dmesg;
+mod free;
+mod group;
+#[cfg(feature = "nvme")]
+mod hibernate;
+mod input;
+mod install;
+mod ionice;
+mod kill;
+mod lsirq;
+mod lspci;
+#[cfg(feature = "usb")]
+mod lsusb;
+#[cfg(feature = "graphics")]
+mod mirror;
+#[cfg(feature = "nvme")]
+mod nvme;
+mod pmap;
+mod ps;
+mod ps2;
+mod runbin;
+#[cfg(feature = "graphics")]
+mod screenshot;
+mod stat;
+mod strace;
+mod suspend;
+mod sysctl;
+mod sysfs;
+mod taskset;
+mod tty;
+mod typematic;
+mod uname;
+#[cfg(feature = "net")]
+mod upload;
+mod vmstat;
+
+use alloc::vec::Vec;
+use kernel_api::RegisterError;
+pub use kernel_api::shell::ShellCommand;
+use x86_64::instructions::interrupts;
+
+use crate::{println, shell::pager, sync::Mutex};
+
+/// All built-in commands
+pub static COMMANDS: &[ShellCommand] = &[
+    ShellCommand {
+        name: "help",
+        help: "list available commands",
+        run: help,
+    },
+    ShellCommand {
+        name: "aer",
+        help: "check PCIe devices for errors and show error counts",
+        run: aer::run,
+    },
+    ShellCommand {
+        name: "bench",
+        help: "bench io <device | file> [--rw <pattern>] [--bs <size>] [--jobs <n>] [--time <s>] | input [--samples <n>] [--gap <ms>] - measure I/O, or key press to echo latency",
+        run: bench::run,
+    },
+    ShellCommand {
+        name: "chrt",
+        help: "chrt <pid> [normal | fifo <priority>] - show or set a task's scheduling class",
+        run: chrt::run,
+    },
+    ShellCommand {
+        name: "dmesg",
+        help: "show the kernel log",
+        run: dmesg::run,
+    },
+    ShellCommand {
+        name: "free",
+        help: "show heap, frame, page allocator and DMA usage, and memory per user task",
+        run: free::run,
+    },
+    ShellCommand {
+        name: "group",
+        help: "group [create | quota | move | remove] - manage task groups and their CPU quotas",
+        run: group::run,
+    },
+    #[cfg(feature = "nvme")]
+    ShellCommand {
+        name: "hibernate",
+        help: "write the system to the resume= area and power off",
+        run: hibernate::run,
+    },
+    ShellCommand {
+        name: "input",
+        help: "list input devices and event subscribers with their event counts",
+        run: input::run,
+    },
+    ShellCommand {
+        name: "install",
+        help: "install <device> - partition and format a disk and copy the boot files to it, so it boots with UEFI",
+        run: install::run,
+    },
+    ShellCommand {
+        name: "ionice",
+        help: "ionice <pid> [realtime|best-effort <level> | idle | limit <KiB/s>|off] - I/O priority",
+        run: ionice::run,
+    },
+    ShellCommand {
+        name: "kill",
+        help: "kill -stop|-cont <pid> - stop a user task or let it run again",
+        run: kill::run,
+    },
+    ShellCommand {
+        name: "lsirq",
+        help: "list interrupt vectors in use, with their owners and interrupt counts",
+        run: lsirq::run,
+    },
+    ShellCommand {
+        name: "lspci",
+        help: "list PCIe devices and driver probe status",
+        run: lspci::run,
+    },
+    #[cfg(feature = "usb")]
+    ShellCommand {
+        name: "lsusb",
+        help: "lsusb [suspend <port> | resume <port>] - list USB devices or change their power state",
+        run: lsusb::run,
+    },
+    #[cfg(feature = "graphics")]
+    ShellCommand {
+        name: "mirror",
+        help: "mirror [on | off | com1 | com2 | <tty>] - copy terminal output to a serial port",
+        run: mirror::run,
+    },
+    #[cfg(feature = "nvme")]
+    ShellCommand {
+        name: "nvme",
+        help: "nvme [power | cache | sync | sanitize [block | crypto]] - NVMe power states, block cache, or erase the drive",
+        run: nvme::run,
+    },
+    ShellCommand {
+        name: "pmap",
+        help: "pmap <pid> - list a user task's memory areas and their resident sizes",
+        run: pmap::run,
+    },
+    ShellCommand {
+        name: "ps",
+        help: "list running tasks",
+        run: ps::run,
+    },
+    ShellCommand {
+        name: "ps2",
+        help: "ps2 status | reset - show or re-initialize the PS/2 controller",
+        run: ps2::run,
+    },
+    ShellCommand {
+        name: "runbin",
+        help: "runbin <path> <address> - load a flat binary into a new user task and run it",
+        run: runbin::run,
+    },
+    #[cfg(feature = "graphics")]
+    ShellCommand {
+        name: "screenshot",
+        help: "screenshot [--raw] <path | serial> - save the screen as a BMP, or raw RGB",
+        run: screenshot::run,
+    },
+    ShellCommand {
+        name: "stat",
+        help: "stat <path> - show a file's size, timestamps and extended attributes",
+        run: stat::run,
+    },
+    ShellCommand {
+        name: "strace",
+        help: "strace [<pid> | off <pid> | show [pid] | clear] - trace a task's syscalls",
+        run: strace::run,
+    },
+    ShellCommand {
+        name: "suspend",
+        help: "suspend the system to RAM (ACPI S3)",
+        run: suspend::run,
+    },
+    ShellCommand {
+        name: "sysctl",
+        help: "sysctl [<key> | <key>=<value>] - show or set kernel tunables",
+        run: sysctl::run,
+    },
+    ShellCommand {
+        name: "sysfs",
+        help: "sysfs [<device> [<attribute> [<value>]]] - show or set device attributes",
+        run: sysfs::run,
+    },
+    ShellCommand {
+        name: "taskset",
+        help: "taskset <pid> [mask] - show or set the CPUs a task may run on",
+        run: taskset::run,
+    },
+    ShellCommand {
+        name: "tty",
+        help: "tty [<name> <baud> | <name> send <text>] - list or use serial devices",
+        run: tty::run,
+    },
+    ShellCommand {
+        name: "typematic",
+        help: "typematic [<rate_hz> <delay_ms> | soft on|off] - key repeat settings",
+        run: typematic::run,
+    },
+    ShellCommand {
+        name: "uname",
+        help: "uname [-s | -r | -v | -m | -a] - show the kernel's release, commit, build time and features",
+        run: uname::run,
+    },
+    #[cfg(feature = "net")]
+    ShellCommand {
+        name: "upload",
+        help: "upload [dmesg | <path>] - gzip the kernel log or a file and send it to the TFTP server set by upload=",
+        run: upload::run,
+    },
+    ShellCommand {
+        name: "vmstat",
+        help: "vmstat [interval_s] - show allocation, page fault and context switch rates until a key is pressed",
+        run: vmstat::run,
+    },
+];
+
+/// Parse and run a command line
+///
+/// A line ending in `| less` or `| more` has its output paged.
+pub fn execute(line: &str) {
+    let Some((line, pipe)) = line.split_once('|') else {
+        run(line);
+        return;
+    };
+    match pipe.trim() {
+        "less" | "more" => pager::run(|| run(line)),
+        _ => println!("only `| less` and `| more` are supported after a command"),
+    }
+}
+
+/// Run a command line without a pipe
+fn run(line: &str) {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return;
+    };
+    let args: Vec<&str> = words.collect();
+
+    match find(name) {
+        Some(command) => (command.run)(&args),
+        None => println!("{}: command not found", name),
+    }
+}
+
+/// Commands added at runtime through `kernel_api::shell`
+static REGISTERED: Mutex<Vec<&'static ShellCommand>> = Mutex::new("SHELL_COMMANDS", Vec::new());
+
+/// Add a command, failing if one with the same name exists
+pub fn register(command: &'static ShellCommand) -> Result<(), RegisterError> {
+    interrupts::without_interrupts(|| {
+        let mut registered = REGISTERED.lock();
+        if COMMANDS
+            .iter()
+            .chain(registered.iter().copied())
+            .any(|existing| existing.name == command.name)
+        {
+            return Err(RegisterError::AlreadyRegistered);
+        }
+        registered.push(command);
+        Ok(())
+    })
+}
+
+/// Built-in commands followed by registered ones
+fn commands() -> Vec<&'static ShellCommand> {
+    let registered = interrupts::without_interrupts(|| REGISTERED.lock().clone());
+    COMMANDS.iter().chain(registered).collect()
+}
+
+fn find(name: &str) -> Option<&'static ShellCommand> {
+    commands().into_iter().find(|command| command.name == name)
+}
+
+fn help(_args: &[&str]) {
+    for command in commands() {
+        println!("  {:<10} {}", command.name, command.help);
+    }
+    println!("append `| less` to a command to page its output");
+}
@@ -0,0 +1,31 @@
+use crate::{
+    pci::aer::{AER_DEVICES, scan},
+    println,
+};
+
+/// Check for PCIe errors and show per-device error counts
+pub fn run(_args: &[&str]) {
+    let reported = scan();
+    if reported > 0 {
+        println!("{} device(s) reported new errors", reported);
+    }
+
+    let devices = AER_DEVICES.lock();
+    if devices.is_empty() {
+        println!("aer: no PCIe functions with AER");
+        return;
+    }
+
+    println!("device      root  correctable  uncorrectable");
+    for aer in devices.iter() {
+        println!(
+            "{:02x}:{:02x}.{}     {}  {:>11}  {:>13}",
+            aer.device.bus,
+            aer.device.device,
+            aer.device.function,
+            if aer.root_port { "yes" } else { "no " },
+            aer.correctable,
+            aer.uncorrectable,
+        );
+    }
+}
@@ -0,0 +1,506 @@
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    block::{self, BlockDevice},
+    fs::{self, Inode, NodeKind},
+    print, println,
+    shell::task::read_line,
+    sync::Mutex,
+    tasks::scheduler::{exit_task, kcreate_task, wait_for_event, wake_event_waiters},
+    time,
+};
+
+const MAX_JOBS: usize = 16;
+const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+const MAX_TIME_S: u64 = 300;
+
+/// What has to be typed before a device is written to
+const CONFIRMATION: &str = "overwrite";
+
+/// The run the workers pick up when they start
+static RUN: Mutex<Option<Arc<Run>>> = Mutex::new("BENCH_RUN", None);
+
+pub fn run(args: &[&str]) {
+    match args {
+        ["io", target, options @ ..] => io(target, options),
+        #[cfg(feature = "graphics")]
+        ["input", options @ ..] => input(options),
+        _ => {
+            println!(
+                "usage: bench io <device | file> [--rw read|write|randread|randwrite] [--bs <size>] [--jobs <n>] [--time <s>]"
+            );
+            #[cfg(feature = "graphics")]
+            println!("       bench input [--samples <n>] [--gap <ms>]");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pattern {
+    Read,
+    Write,
+    RandRead,
+    RandWrite,
+}
+
+impl Pattern {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Self::Read),
+            "write" => Some(Self::Write),
+            "randread" => Some(Self::RandRead),
+            "randwrite" => Some(Self::RandWrite),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::RandRead => "randread",
+            Self::RandWrite => "randwrite",
+        }
+    }
+
+    fn writes(self) -> bool {
+        matches!(self, Self::Write | Self::RandWrite)
+    }
+
+    fn random(self) -> bool {
+        matches!(self, Self::RandRead | Self::RandWrite)
+    }
+}
+
+/// Where the I/O goes
+enum Target {
+    /// Through the block layer, and so the I/O scheduler
+    Device(Arc<dyn BlockDevice>),
+    /// Through the VFS
+    File(Arc<dyn Inode>),
+}
+
+impl Target {
+    fn size(&self) -> u64 {
+        match self {
+            Self::Device(device) => device.block_count() * device.block_size() as u64,
+            Self::File(inode) => inode.size(),
+        }
+    }
+
+    /// Size requests must be a multiple of
+    fn granularity(&self) -> usize {
+        match self {
+            Self::Device(device) => device.block_size(),
+            Self::File(_) => 1,
+        }
+    }
+
+    fn transfer(&self, pattern: Pattern, offset: u64, buffer: &mut [u8]) -> bool {
+        match self {
+            Self::Device(device) => {
+                let lba = offset / device.block_size() as u64;
+                if pattern.writes() {
+                    device.write_blocks(lba, buffer).is_ok()
+                } else {
+                    device.read_blocks(lba, buffer).is_ok()
+                }
+            }
+            Self::File(inode) => {
+                let done = if pattern.writes() {
+                    inode.write_at(offset, buffer)
+                } else {
+                    inode.read_at(offset, buffer)
+                };
+                done.is_ok_and(|done| done == buffer.len())
+            }
+        }
+    }
+}
+
+struct Options {
+    pattern: Pattern,
+    block_size: usize,
+    jobs: usize,
+    time_s: u64,
+}
+
+impl Options {
+    fn parse(args: &[&str]) -> Result<Self, &'static str> {
+        let mut options = Self {
+            pattern: Pattern::RandRead,
+            block_size: 4096,
+            jobs: 1,
+            time_s: 10,
+        };
+        let mut args = args.iter();
+        while let Some(&option) = args.next() {
+            let value = *args.next().ok_or("option without a value")?;
+            match option {
+                "--rw" => options.pattern = Pattern::parse(value).ok_or("unknown --rw")?,
+                "--bs" => {
+                    options.block_size = parse_size(value)
+                        .filter(|size| (1..=MAX_BLOCK_SIZE).contains(size))
+                        .ok_or("--bs must be 1 byte to 1m")?
+                }
+                "--jobs" => {
+                    options.jobs = value
+                        .parse()
+                        .ok()
+                        .filter(|jobs| (1..=MAX_JOBS).contains(jobs))
+                        .ok_or("--jobs must be 1-16")?
+                }
+                "--time" => {
+                    options.time_s = value
+                        .strip_suffix('s')
+                        .unwrap_or(value)
+                        .parse()
+                        .ok()
+                        .filter(|time| (1..=MAX_TIME_S).contains(time))
+                        .ok_or("--time must be 1-300 s")?
+                }
+                _ => return Err("unknown option"),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// A size in bytes, with an optional k, m or g suffix
+fn parse_size(size: &str) -> Option<usize> {
+    let (digits, unit) = match size.as_bytes().last()? {
+        b'k' | b'K' => (&size[..size.len() - 1], 1 << 10),
+        b'm' | b'M' => (&size[..size.len() - 1], 1 << 20),
+        b'g' | b'G' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+/// Latencies in TSC cycles, in buckets of 8 per power of two, so
+/// percentiles are within 12.5%
+struct Histogram {
+    buckets: Vec<u64>,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    const SUB_BITS: u32 = 3;
+    const BUCKETS: usize =
+        ((64 - Self::SUB_BITS as usize) << Self::SUB_BITS) + (1 << Self::SUB_BITS);
+
+    fn new() -> Self {
+        Self {
+            buckets: vec![0; Self::BUCKETS],
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    fn index(value: u64) -> usize {
+        let sub = 1 << Self::SUB_BITS;
+        if value < sub {
+            return value as usize;
+        }
+        let exponent = value.ilog2();
+        let shift = exponent - Self::SUB_BITS;
+        ((((shift + 1) as u64) << Self::SUB_BITS) + ((value >> shift) & (sub - 1))) as usize
+    }
+
+    /// Highest value counted in bucket `index`
+    fn upper_bound(index: usize) -> u64 {
+        let sub = 1 << Self::SUB_BITS;
+        if index < sub {
+            return index as u64;
+        }
+        let shift = (index >> Self::SUB_BITS) as u32 - 1;
+        let low = ((sub + (index & (sub - 1))) as u64) << shift;
+        low + ((1u64 << shift) - 1)
+    }
+
+    fn record(&mut self, value: u64) {
+        self.buckets[Self::index(value)] += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += count;
+        }
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Value at or below which `per_mille` of the samples are
+    fn percentile(&self, per_mille: u64) -> u64 {
+        let rank = (self.count() * per_mille).div_ceil(1000).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::upper_bound(index).clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// One benchmark run, shared by its workers
+struct Run {
+    target: Target,
+    pattern: Pattern,
+    block_size: usize,
+    jobs: usize,
+    /// Uptime the workers stop at
+    deadline_ms: u64,
+    next_worker: AtomicUsize,
+    finished: AtomicUsize,
+    ios: AtomicU64,
+    errors: AtomicU64,
+    latencies: Mutex<Histogram>,
+}
+
+impl Run {
+    fn key(&self) -> usize {
+        self as *const Run as usize
+    }
+}
+
+fn io(target_name: &str, args: &[&str]) {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("bench: {}", e);
+            return;
+        }
+    };
+
+    let target = match block::find(target_name) {
+        Some(device) => Target::Device(device),
+        None => match fs::resolve(target_name) {
+            Ok(inode) if inode.kind() == NodeKind::File => Target::File(inode),
+            Ok(_) => {
+                println!("bench: {}: not a file", target_name);
+                return;
+            }
+            Err(e) => {
+                println!("bench: {}: {:?}", target_name, e);
+                return;
+            }
+        },
+    };
+    if options.block_size % target.granularity() != 0 {
+        println!(
+            "bench: --bs must be a multiple of the {} byte blocks of {}",
+            target.granularity(),
+            target_name
+        );
+        return;
+    }
+    if target.size() < options.block_size as u64 {
+        println!("bench: {} is smaller than one request", target_name);
+        return;
+    }
+    if options.pattern.writes() && matches!(target, Target::Device(_)) {
+        print!(
+            "This overwrites data on {}, type \"{}\" to continue: ",
+            target_name, CONFIRMATION
+        );
+        if read_line().trim() != CONFIRMATION {
+            println!("bench: cancelled");
+            return;
+        }
+    }
+
+    println!(
+        "bench io {}: {}, bs {}, {} jobs, {} s",
+        target_name,
+        options.pattern.name(),
+        options.block_size,
+        options.jobs,
+        options.time_s
+    );
+
+    let start_ms = time::uptime_ms();
+    let start_tsc = unsafe { _rdtsc() };
+    let run = Arc::new(Run {
+        target,
+        pattern: options.pattern,
+        block_size: options.block_size,
+        jobs: options.jobs,
+        deadline_ms: start_ms + options.time_s * 1000,
+        next_worker: AtomicUsize::new(0),
+        finished: AtomicUsize::new(0),
+        ios: AtomicU64::new(0),
+        errors: AtomicU64::new(0),
+        latencies: Mutex::new("BENCH_LATENCIES", Histogram::new()),
+    });
+    interrupts::without_interrupts(|| *RUN.lock() = Some(run.clone()));
+    for _ in 0..options.jobs {
+        interrupts::without_interrupts(|| kcreate_task(worker, "bench io"));
+    }
+
+    loop {
+        interrupts::disable();
+        if run.finished.load(Ordering::Relaxed) == run.jobs {
+            interrupts::enable();
+            break;
+        }
+        wait_for_event(run.key());
+    }
+    interrupts::without_interrupts(|| *RUN.lock() = None);
+
+    let elapsed_ms = (time::uptime_ms() - start_ms).max(1);
+    let tsc_per_us = ((unsafe { _rdtsc() } - start_tsc) / elapsed_ms / 1000).max(1);
+    report(&run, elapsed_ms, tsc_per_us);
+}
+
+fn report(run: &Run, elapsed_ms: u64, tsc_per_us: u64) {
+    let ios = run.ios.load(Ordering::Relaxed);
+    let bytes = ios * run.block_size as u64;
+    println!(
+        "  {} ios, {} IOPS, {} KiB/s, {} errors",
+        ios,
+        ios * 1000 / elapsed_ms,
+        bytes * 1000 / elapsed_ms / 1024,
+        run.errors.load(Ordering::Relaxed)
+    );
+
+    let latencies = interrupts::without_interrupts(|| {
+        let latencies = run.latencies.lock();
+        if latencies.count() == 0 {
+            return None;
+        }
+        Some(
+            [0, 500, 900, 990, 999, 1000]
+                .map(|per_mille| latencies.percentile(per_mille) / tsc_per_us),
+        )
+    });
+    if let Some([min, p50, p90, p99, p999, max]) = latencies {
+        println!(
+            "  latency us: min {} p50 {} p90 {} p99 {} p99.9 {} max {}",
+            min, p50, p90, p99, p999, max
+        );
+    }
+}
+
+/// Time key presses from the keyboard to the shell's echo on the terminal
+#[cfg(feature = "graphics")]
+fn input(args: &[&str]) {
+    use crate::input::latency::{self, LatencyError};
+
+    let (mut samples, mut gap_ms) = (100, 20);
+    let mut args = args.iter();
+    while let Some(&option) = args.next() {
+        let value = args.next().and_then(|value| value.parse::<u64>().ok());
+        match (option, value) {
+            ("--samples", Some(value @ 1..=10_000)) => samples = value as usize,
+            ("--gap", Some(value @ 0..=10_000)) => gap_ms = value,
+            _ => {
+                println!("bench: --samples must be 1-10000, --gap 0-10000 ms");
+                return;
+            }
+        }
+    }
+
+    println!(
+        "bench input: {} presses of {:?}, {} ms apart",
+        samples,
+        latency::PROBE_KEY,
+        gap_ms
+    );
+    // echo the presses like the shell does, the line is dropped afterwards
+    let echo = || {
+        if let Some((scancode, state)) = crate::shell::task::poll_key()
+            && let Some(character) = scancode.to_char(state.shift_pressed(), state.caps_lock)
+        {
+            print!("{}", character);
+        }
+    };
+    let latencies = latency::measure(samples, gap_ms, echo);
+    println!();
+    match latencies {
+        Ok(latencies) => {
+            println!(
+                "  latency us: min {} avg {} p50 {} p99 {} max {}",
+                latencies.min(),
+                latencies.average(),
+                latencies.percentile(500),
+                latencies.percentile(990),
+                latencies.max()
+            );
+            if latencies.percentile(990) > latency::BUDGET_US {
+                println!("  p99 is over the budget of {} us", latency::BUDGET_US);
+            }
+        }
+        Err(LatencyError::Inject(e)) => println!("bench: {}", e),
+        Err(LatencyError::Timeout) => println!("bench: a press never reached the terminal"),
+    }
+}
+
+/// xorshift64, plenty for picking offsets
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn worker() -> ! {
+    let run = interrupts::without_interrupts(|| RUN.lock().clone());
+    if let Some(run) = run {
+        work(&run);
+        run.finished.fetch_add(1, Ordering::Relaxed);
+        wake_event_waiters(run.key());
+    }
+    exit_task();
+}
+
+fn work(run: &Run) {
+    let index = run.next_worker.fetch_add(1, Ordering::Relaxed) as u64;
+    let block_size = run.block_size as u64;
+    let requests = run.target.size() / block_size;
+    // sequential workers each start in their own part of the target
+    let mut next = requests * index / run.jobs as u64;
+    let mut random = (unsafe { _rdtsc() } ^ (index + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1;
+
+    let mut buffer = vec![0xA5u8; run.block_size];
+    let mut latencies = Histogram::new();
+    let (mut ios, mut errors) = (0, 0);
+    while time::uptime_ms() < run.deadline_ms {
+        let request = if run.pattern.random() {
+            next_random(&mut random) % requests
+        } else {
+            let request = next;
+            next = (next + 1) % requests;
+            request
+        };
+
+        let start = unsafe { _rdtsc() };
+        let done = run
+            .target
+            .transfer(run.pattern, request * block_size, &mut buffer);
+        latencies.record(unsafe { _rdtsc() } - start);
+        if done {
+            ios += 1;
+        } else {
+            errors += 1;
+        }
+    }
+
+    run.ios.fetch_add(ios, Ordering::Relaxed);
+    run.errors.fetch_add(errors, Ordering::Relaxed);
+    interrupts::without_interrupts(|| run.latencies.lock().merge(&latencies));
+}
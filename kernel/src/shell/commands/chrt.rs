@@ -0,0 +1,42 @@
+use crate::{
+    println,
+    tasks::scheduler::{PolicyError, SchedPolicy, policy, set_policy},
+};
+
+pub fn run(args: &[&str]) {
+    let Some(pid) = args.first().and_then(|pid| pid.parse::<u64>().ok()) else {
+        println!("usage: chrt <pid> [normal | fifo <priority>]");
+        return;
+    };
+
+    let new_policy = match args[1..] {
+        [] => {
+            match policy(pid) {
+                Some((policy, Some(boost))) => {
+                    println!("task {}: {:?}, inherited priority {}", pid, policy, boost)
+                }
+                Some((policy, None)) => println!("task {}: {:?}", pid, policy),
+                None => println!("chrt: no task {}", pid),
+            }
+            return;
+        }
+        ["normal"] => SchedPolicy::Normal,
+        ["fifo", priority] => match priority.parse() {
+            Ok(priority) => SchedPolicy::Fifo(priority),
+            Err(_) => {
+                println!("chrt: invalid priority {}", priority);
+                return;
+            }
+        },
+        _ => {
+            println!("usage: chrt <pid> [normal | fifo <priority>]");
+            return;
+        }
+    };
+
+    match set_policy(pid, new_policy) {
+        Ok(()) => {}
+        Err(PolicyError::NoSuchTask) => println!("chrt: no task {}", pid),
+        Err(PolicyError::InvalidPriority) => println!("chrt: priority must be 1-99"),
+    }
+}
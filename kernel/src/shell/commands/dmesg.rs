@@ -0,0 +1,17 @@
+use crate::{output::klog, println};
+
+pub fn run(args: &[&str]) {
+    if !args.is_empty() {
+        println!("usage: dmesg");
+        return;
+    }
+    let log = klog::contents();
+    if log.is_empty() {
+        println!("dmesg: the log is empty, build with a log-* feature to fill it");
+        return;
+    }
+    println!(
+        "{}",
+        alloc::string::String::from_utf8_lossy(&log).trim_end()
+    );
+}
@@ -0,0 +1,94 @@
+use x86_64::instructions::interrupts;
+
+use crate::{
+    memory::{
+        FRAME_ALLOCATOR,
+        alloc::{ALLOCATOR, HEAP_SIZE, PAGE_ALLOCATOR},
+    },
+    println,
+    tasks::scheduler::user_task_memory,
+};
+
+const PAGE_SIZE: usize = 4096;
+
+/// Show how much of each kernel memory pool is used
+pub fn run(_args: &[&str]) {
+    let (total_frames, free_frames) = interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().as_ref().map_or((0, 0), |allocator| {
+            (allocator.total_frames(), allocator.free_frames())
+        })
+    });
+    let (total_pages, used_pages) = interrupts::without_interrupts(|| {
+        PAGE_ALLOCATOR.lock().as_ref().map_or((0, 0), |allocator| {
+            (allocator.total_pages(), allocator.pages_in_use())
+        })
+    });
+    let heap_used = ALLOCATOR.in_use();
+
+    println!(
+        "{:<12} {:>12} {:>12} {:>12}",
+        "KiB", "total", "used", "free"
+    );
+    row(
+        "frames",
+        total_frames * PAGE_SIZE,
+        (total_frames - free_frames) * PAGE_SIZE,
+    );
+    row("heap", HEAP_SIZE, heap_used);
+    row(
+        "page alloc",
+        total_pages * PAGE_SIZE,
+        used_pages * PAGE_SIZE,
+    );
+    dma();
+    println!(
+        "heap: {} KiB live in allocations, the rest is slab and block slack",
+        ALLOCATOR.live() / 1024
+    );
+
+    let tasks = user_task_memory();
+    let frames: usize = tasks.iter().map(|task| task.frames).sum();
+    println!(
+        "user tasks: {} KiB in {} tasks",
+        frames * PAGE_SIZE / 1024,
+        tasks.len()
+    );
+    for task in tasks {
+        println!(
+            "  {:>4}  {:<16} {:>8} KiB",
+            task.pid,
+            task.name,
+            task.frames * PAGE_SIZE / 1024
+        );
+    }
+}
+
+fn row(name: &str, total: usize, used: usize) {
+    println!(
+        "{:<12} {:>12} {:>12} {:>12}",
+        name,
+        total / 1024,
+        used / 1024,
+        total.saturating_sub(used) / 1024
+    );
+}
+
+/// DMA buffers have no fixed pool, only the 4 KiB buffers are pooled
+#[cfg(any(feature = "usb", feature = "nvme"))]
+fn dma() {
+    use crate::pci::dma::{DMA_MANAGER, dma_frames};
+
+    let (pooled, in_use) = interrupts::without_interrupts(|| {
+        let manager = DMA_MANAGER.lock();
+        (manager.buffers_4kb_total(), manager.buffers_4kb_in_use())
+    });
+    println!(
+        "dma: {} KiB held, {} of {} pooled 4 KiB buffers in use",
+        dma_frames() * PAGE_SIZE / 1024,
+        in_use,
+        pooled
+    );
+}
+
+#[cfg(not(any(feature = "usb", feature = "nvme")))]
+fn dma() {}
@@ -0,0 +1,90 @@
+use alloc::{format, string::String};
+
+use crate::{
+    println,
+    tasks::{
+        group::{GroupError, create_group, groups, remove_group, set_quota},
+        scheduler::{group_task_count, set_group},
+    },
+};
+
+const USAGE: &str = "usage: group [create <name> [quota] | quota <group> <percent | none> | move <pid> <group> | remove <group>]";
+
+fn parse_quota(quota: &str) -> Option<Option<u8>> {
+    match quota {
+        "none" => Some(None),
+        quota => quota.trim_end_matches('%').parse().ok().map(Some),
+    }
+}
+
+fn print_error(error: GroupError) {
+    match error {
+        GroupError::NoSuchGroup => println!("group: no such group"),
+        GroupError::NoSuchTask => println!("group: no such task"),
+        GroupError::InvalidQuota => println!("group: quota must be 1-100 or none"),
+        GroupError::RootGroup => println!("group: the root group can't be changed"),
+        GroupError::NotEmpty => println!("group: group still has tasks"),
+    }
+}
+
+fn list() {
+    println!(
+        "{:>4}  {:<16} {:>5} {:>5} {:>5}",
+        "ID", "NAME", "QUOTA", "USED", "TASKS"
+    );
+    for group in groups() {
+        let quota = match group.quota {
+            Some(quota) => format!("{quota}%"),
+            None => String::from("-"),
+        };
+        println!(
+            "{:>4}  {:<16} {:>5} {:>4}% {:>5}{}",
+            group.id,
+            group.name,
+            quota,
+            group.usage,
+            group_task_count(group.id),
+            if group.throttled { "  throttled" } else { "" }
+        );
+    }
+}
+
+pub fn run(args: &[&str]) {
+    let result = match args {
+        [] => {
+            list();
+            Ok(())
+        }
+        ["create", name] => {
+            create_group(String::from(*name), None).map(|id| println!("created group {}", id))
+        }
+        ["create", name, quota] => match parse_quota(quota) {
+            Some(quota) => {
+                create_group(String::from(*name), quota).map(|id| println!("created group {}", id))
+            }
+            None => Err(GroupError::InvalidQuota),
+        },
+        ["quota", id, quota] => match (id.parse(), parse_quota(quota)) {
+            (Ok(id), Some(quota)) => set_quota(id, quota),
+            (Err(_), _) => Err(GroupError::NoSuchGroup),
+            (_, None) => Err(GroupError::InvalidQuota),
+        },
+        ["move", pid, id] => match (pid.parse(), id.parse()) {
+            (Ok(pid), Ok(id)) => set_group(pid, id),
+            (Err(_), _) => Err(GroupError::NoSuchTask),
+            (_, Err(_)) => Err(GroupError::NoSuchGroup),
+        },
+        ["remove", id] => id
+            .parse()
+            .map_err(|_| GroupError::NoSuchGroup)
+            .and_then(remove_group),
+        _ => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    };
+
+    if let Err(error) = result {
+        print_error(error);
+    }
+}
@@ -0,0 +1,12 @@
+use crate::{power::hibernate, println};
+
+/// Hibernate, returning to the prompt once restored at a later boot
+pub fn run(args: &[&str]) {
+    match args {
+        [] => match hibernate::hibernate() {
+            Ok(()) => println!("hibernate: resumed"),
+            Err(e) => println!("hibernate: {}", e),
+        },
+        _ => println!("usage: hibernate"),
+    }
+}
@@ -0,0 +1,40 @@
+use alloc::{format, string::String};
+
+use crate::{input::devices, println};
+
+pub fn run(_args: &[&str]) {
+    let devices = devices::devices();
+    if devices.is_empty() {
+        println!("no input devices");
+    }
+    for device in devices {
+        println!(
+            "{:<3} {:<16} events {} debounced {} dropped {}{}",
+            device.id,
+            device.name,
+            device.stats.events,
+            device.stats.debounced,
+            device.stats.dropped,
+            if device.grabbed { " (grabbed)" } else { "" }
+        );
+        if !device.pressed.is_empty() {
+            println!("    pressed: {:?}", device.pressed);
+        }
+    }
+
+    for subscriber in devices::subscribers() {
+        let kind = match subscriber.priority {
+            Some(priority) => format!("intercept {priority}"),
+            None => String::from("all"),
+        };
+        println!(
+            "subscriber {:<3} {:<16} {:<13} queued {} delivered {} dropped {}",
+            subscriber.id,
+            subscriber.name,
+            kind,
+            subscriber.queued,
+            subscriber.delivered,
+            subscriber.dropped
+        );
+    }
+}
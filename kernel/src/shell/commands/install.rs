@@ -0,0 +1,107 @@
+//! Install the running system to a disk
+//!
+//! The target gets a new GPT with a single EFI System Partition, formatted
+//! FAT32 and holding every file Limine loaded, which includes the kernel,
+//! `BOOTX64.EFI` and limine.conf as long as limine.conf lists the last two
+//! as modules. UEFI firmware then boots the disk like the image it came
+//! from. There is no BIOS boot code, so the disk only boots on UEFI.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    block::{
+        self, BlockDevice,
+        gpt::{self, NewPartition, Partition},
+    },
+    fs::{fat, initramfs},
+    print, println,
+    shell::task::read_line,
+};
+
+/// What has to be typed to start installing
+const CONFIRMATION: &str = "erase all data";
+
+/// Size of the EFI System Partition, or the whole disk when it is smaller
+const ESP_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Files the disk can't boot without
+const REQUIRED: &[&str] = &[
+    "boot/kernel.elf",
+    "EFI/BOOT/BOOTX64.EFI",
+    "boot/limine/limine.conf",
+];
+
+pub fn run(args: &[&str]) {
+    let [name] = args else {
+        println!("usage: install <device>");
+        return;
+    };
+    let Some(device) = block::find(name) else {
+        println!("install: no block device {}", name);
+        return;
+    };
+
+    let missing: Vec<&str> = REQUIRED
+        .iter()
+        .copied()
+        .filter(|path| initramfs::find(path).is_none())
+        .collect();
+    if !missing.is_empty() {
+        for path in missing {
+            println!("install: /{} wasn't loaded at boot", path);
+        }
+        println!("install: limine.conf needs a module_path for each of them");
+        return;
+    }
+
+    let size = device.block_count() * device.block_size() as u64;
+    println!(
+        "This erases ALL data on {} ({} MiB).",
+        name,
+        size / (1024 * 1024)
+    );
+    print!("Type \"{}\" to continue: ", CONFIRMATION);
+    if read_line().trim() != CONFIRMATION {
+        println!("install: cancelled");
+        return;
+    }
+
+    // take the whole disk if the partition wouldn't leave room for the
+    // table and alignment
+    let esp_size = (size >= 2 * ESP_SIZE).then_some(ESP_SIZE);
+    let partitions = [NewPartition {
+        kind: gpt::EFI_SYSTEM,
+        name: "EFI system partition",
+        size: esp_size,
+    }];
+    let ranges = match gpt::write(device.as_ref(), &partitions) {
+        Ok(ranges) => ranges,
+        Err(e) => {
+            println!("install: failed to partition {}: {:?}", name, e);
+            return;
+        }
+    };
+    let esp: Arc<dyn BlockDevice> = Arc::new(Partition::new(device, 1, ranges[0]));
+    println!(
+        "{}: EFI system partition, {} MiB",
+        esp.name(),
+        esp.block_count() * esp.block_size() as u64 / (1024 * 1024)
+    );
+
+    let files: Vec<(&str, &[u8])> = initramfs::files()
+        .iter()
+        .map(|file| (file.path.as_str(), file.data))
+        .collect();
+    if let Err(e) = fat::mkfs(esp.as_ref(), &files, "LOCOS") {
+        println!("install: failed to format {}: {:?}", esp.name(), e);
+        return;
+    }
+    for (path, data) in &files {
+        println!("  /{} ({} KiB)", path, data.len().div_ceil(1024));
+    }
+
+    if let Err(e) = block::register(esp.clone()) {
+        println!("install: failed to register {}: {:?}", esp.name(), e);
+    }
+    println!("install: {} is ready to boot with UEFI", name);
+}
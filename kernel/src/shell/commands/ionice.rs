@@ -0,0 +1,78 @@
+use crate::{
+    block::sched::{
+        IoClass, IoPriority, IoSettingsError, io_limit, io_priority, set_io_limit, set_io_priority,
+    },
+    println,
+};
+
+const USAGE: &str = "usage: ionice <pid> [realtime <level> | best-effort <level> | idle | limit <KiB/s> | limit off]";
+
+fn print_error(pid: u64, error: IoSettingsError) {
+    match error {
+        IoSettingsError::NoSuchTask => println!("ionice: no task {}", pid),
+        IoSettingsError::InvalidLevel => println!("ionice: level must be 0-7"),
+        IoSettingsError::InvalidLimit => println!("ionice: limit must be at least 1 KiB/s"),
+    }
+}
+
+fn parse_level(class: IoClass, level: &str) -> Option<IoPriority> {
+    let level = level.parse().ok()?;
+    Some(IoPriority { class, level })
+}
+
+pub fn run(args: &[&str]) {
+    let Some(pid) = args.first().and_then(|pid| pid.parse::<u64>().ok()) else {
+        println!("{}", USAGE);
+        return;
+    };
+
+    let result = match args[1..] {
+        [] => {
+            match io_limit(pid) {
+                Some(limit) => println!(
+                    "task {}: {}, limited to {} KiB/s",
+                    pid,
+                    io_priority(pid),
+                    limit / 1024
+                ),
+                None => println!("task {}: {}", pid, io_priority(pid)),
+            }
+            return;
+        }
+        ["realtime", level] | ["best-effort", level] => {
+            let class = if args[1] == "realtime" {
+                IoClass::RealTime
+            } else {
+                IoClass::BestEffort
+            };
+            let Some(priority) = parse_level(class, level) else {
+                println!("ionice: invalid level {}", level);
+                return;
+            };
+            set_io_priority(pid, priority)
+        }
+        ["idle"] => set_io_priority(
+            pid,
+            IoPriority {
+                class: IoClass::Idle,
+                level: 0,
+            },
+        ),
+        ["limit", "off"] => set_io_limit(pid, None),
+        ["limit", kib] => match kib.parse::<u64>() {
+            Ok(kib) => set_io_limit(pid, Some(kib.saturating_mul(1024))),
+            Err(_) => {
+                println!("ionice: invalid limit {}", kib);
+                return;
+            }
+        },
+        _ => {
+            println!("{}", USAGE);
+            return;
+        }
+    };
+
+    if let Err(e) = result {
+        print_error(pid, e);
+    }
+}
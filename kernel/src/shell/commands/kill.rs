@@ -0,0 +1,32 @@
+use crate::{
+    println,
+    tasks::scheduler::{KillError, continue_task, stop_task},
+};
+
+const USAGE: &str = "usage: kill -stop <pid> | kill -cont <pid>";
+
+pub fn run(args: &[&str]) {
+    let [signal, pid] = args else {
+        println!("{}", USAGE);
+        return;
+    };
+    let Ok(pid) = pid.parse::<u64>() else {
+        println!("kill: invalid pid {}", pid);
+        return;
+    };
+
+    let result = match *signal {
+        "-stop" => stop_task(pid),
+        "-cont" => continue_task(pid),
+        _ => {
+            println!("{}", USAGE);
+            return;
+        }
+    };
+    match result {
+        Ok(()) => {}
+        Err(KillError::NoSuchTask) => println!("kill: no task {}", pid),
+        Err(KillError::KernelTask) => println!("kill: task {} is a kernel task", pid),
+        Err(KillError::Busy) => println!("kill: task {} is running in the kernel", pid),
+    }
+}
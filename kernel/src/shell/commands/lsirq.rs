@@ -0,0 +1,20 @@
+use crate::{interrupts::vectors, println};
+
+pub fn run(_args: &[&str]) {
+    println!("VECTOR  OWNER            INTERRUPTS");
+    for info in vectors::usage() {
+        match info.interrupts {
+            Some(interrupts) => {
+                println!("{:#04x}    {:<16} {}", info.vector, info.owner, interrupts)
+            }
+            None => println!("{:#04x}    {:<16} -", info.vector, info.owner),
+        }
+    }
+    println!(
+        "{} of {} dynamic vectors free ({:#04x}-{:#04x})",
+        vectors::available(),
+        vectors::DYNAMIC_COUNT,
+        vectors::DYNAMIC_START,
+        vectors::DYNAMIC_START as usize + vectors::DYNAMIC_COUNT - 1
+    );
+}
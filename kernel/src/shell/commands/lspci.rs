@@ -0,0 +1,33 @@
+use crate::{
+    pci::{
+        PCI_DEVICES,
+        probe::{PROBES, ProbeState, probes_finished},
+    },
+    println,
+};
+
+/// List PCIe devices along with the state of their driver probe
+pub fn run(_args: &[&str]) {
+    let probes = PROBES.lock().clone();
+    let Some(devices) = PCI_DEVICES.read().map(|devices| devices.clone()) else {
+        println!("lspci: PCIe not initialized");
+        return;
+    };
+
+    for device in &devices {
+        let location = (device.bus, device.device, device.function);
+        match probes.iter().find(|probe| probe.location == location) {
+            Some(probe) => match &probe.state {
+                ProbeState::Failed(reason) => {
+                    println!("{}  [{}: failed: {}]", device, probe.driver, reason)
+                }
+                state => println!("{}  [{}: {:?}]", device, probe.driver, state),
+            },
+            None => println!("{}", device),
+        }
+    }
+
+    if !probes_finished() {
+        println!("(driver probes still running)");
+    }
+}
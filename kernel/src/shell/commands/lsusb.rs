@@ -0,0 +1,65 @@
+use crate::{
+    pci::usb::{
+        device::{UsbDevice, devices},
+        xhci::{PowerState, power_state, resume_slot, suspend_slot},
+    },
+    println,
+};
+
+/// List USB devices, or suspend and resume one by root hub port
+pub fn run(args: &[&str]) {
+    match args {
+        [] => {
+            let devices = devices();
+            if devices.is_empty() {
+                println!("no USB devices");
+            }
+            for device in &devices {
+                let power =
+                    power_state(device.generation, device.slot_id).map_or("gone", |state| {
+                        match state {
+                            PowerState::Active => "active",
+                            PowerState::Suspending => "suspending",
+                            PowerState::Suspended => "suspended",
+                            PowerState::Resuming => "resuming",
+                        }
+                    });
+                println!(
+                    "port {:2}  slot {:2}  {:04x}:{:04x}  class {:02x}  {}",
+                    device.port,
+                    device.slot_id,
+                    device.descriptor.vendor_id,
+                    device.descriptor.product_id,
+                    device.descriptor.class,
+                    power
+                );
+            }
+        }
+        ["suspend", port] => {
+            if let Some(device) = find(port)
+                && let Err(e) = suspend_slot(device.generation, device.slot_id)
+            {
+                println!("lsusb: suspend failed: {:?}", e);
+            }
+        }
+        ["resume", port] => {
+            if let Some(device) = find(port)
+                && let Err(e) = resume_slot(device.generation, device.slot_id)
+            {
+                println!("lsusb: resume failed: {:?}", e);
+            }
+        }
+        _ => println!("usage: lsusb [suspend <port> | resume <port>]"),
+    }
+}
+
+fn find(port: &str) -> Option<UsbDevice> {
+    let device = port
+        .parse::<u8>()
+        .ok()
+        .and_then(|port| devices().into_iter().find(|device| device.port == port));
+    if device.is_none() {
+        println!("lsusb: no device on port {}", port);
+    }
+    device
+}
@@ -0,0 +1,22 @@
+use crate::{
+    output::mirror::{MirrorPort, mirror_port, mirror_tty, set_mirror, set_mirror_tty},
+    println, tty,
+};
+
+pub fn run(args: &[&str]) {
+    match args {
+        [] => match (mirror_port(), mirror_tty()) {
+            (Some(port), _) => println!("mirroring to {}", port.name()),
+            (None, Some(device)) => println!("mirroring to {}", device.name()),
+            (None, None) => println!("mirroring off"),
+        },
+        ["off"] => set_mirror(None),
+        ["on"] => set_mirror(Some(mirror_port().unwrap_or(MirrorPort::Com2))),
+        [name] => match (MirrorPort::from_name(name), tty::find(name)) {
+            (Some(port), _) => set_mirror(Some(port)),
+            (None, Some(device)) => set_mirror_tty(device),
+            (None, None) => println!("mirror: unknown port {}", name),
+        },
+        _ => println!("usage: mirror [on | off | com1 | com2 | <tty>]"),
+    }
+}
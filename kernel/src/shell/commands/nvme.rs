@@ -0,0 +1,159 @@
+use crate::{
+    pci::nvme::{
+        cache, get_namespaces,
+        power::{apst_enabled, current_power_state, power_states},
+        sanitize::{self, SanitizeAction, SanitizeState, SanitizeStatus},
+    },
+    print, println,
+    shell::task::read_line,
+};
+
+/// What has to be typed to start a sanitize operation
+const CONFIRMATION: &str = "erase all data";
+
+pub fn run(args: &[&str]) {
+    match args {
+        ["power"] => power(),
+        ["cache"] => cache_stats(),
+        ["sync"] => match cache::sync() {
+            Ok(()) => println!("nvme: cache written back"),
+            Err(e) => println!("nvme: writeback failed: {:?}", e),
+        },
+        ["sanitize"] => sanitize_status(),
+        ["sanitize", "block"] => sanitize(SanitizeAction::BlockErase),
+        ["sanitize", "crypto"] => sanitize(SanitizeAction::CryptoErase),
+        _ => println!("usage: nvme [power | cache | sync | sanitize [block | crypto]]"),
+    }
+}
+
+/// Print block cache counters
+fn cache_stats() {
+    let stats = cache::stats();
+    println!(
+        "cached: {} KiB ({} KiB dirty)",
+        stats.cached * 4,
+        stats.dirty * 4
+    );
+    println!(
+        "hits: {}  misses: {}  read ahead: {}  written back: {}",
+        stats.hits, stats.misses, stats.readahead, stats.written
+    );
+}
+
+/// Print the controller's power states, marking the current one
+fn power() {
+    let states = power_states();
+    if states.is_empty() {
+        println!("nvme: no controller");
+        return;
+    }
+
+    let current = current_power_state().ok();
+
+    println!("   PS  max power  type    entry lat   exit lat");
+    for (index, state) in states.iter().enumerate() {
+        let marker = if current == Some(index as u8) {
+            '*'
+        } else {
+            ' '
+        };
+        let uw = state.max_power_uw();
+        println!(
+            "{}  {:>2}  {:>3}.{:04} W  {}  {:>8} us {:>8} us",
+            marker,
+            index,
+            uw / 1_000_000,
+            uw % 1_000_000 / 100,
+            if state.non_operational {
+                "non-op"
+            } else {
+                "op    "
+            },
+            state.entry_latency,
+            state.exit_latency,
+        );
+    }
+
+    match apst_enabled() {
+        Ok(enabled) => println!("APST: {}", if enabled { "enabled" } else { "disabled" }),
+        Err(e) => println!("APST: unavailable ({:?})", e),
+    }
+}
+
+fn print_sanitize_status(status: &SanitizeStatus) {
+    match status.state {
+        SanitizeState::InProgress => println!("sanitize: in progress, {}% done", status.percent()),
+        state => println!("sanitize: last operation {:?}", state),
+    }
+    if status.global_data_erased {
+        println!("no data written since the last sanitize");
+    }
+}
+
+/// Print what the controller supports and the state of the last operation
+fn sanitize_status() {
+    let capabilities = match sanitize::capabilities() {
+        Ok(capabilities) => capabilities,
+        Err(e) => {
+            println!("nvme: {}", e);
+            return;
+        }
+    };
+    println!(
+        "supported: block erase: {}  crypto erase: {}  overwrite: {}",
+        capabilities.block_erase, capabilities.crypto_erase, capabilities.overwrite
+    );
+
+    match sanitize::status() {
+        Ok(status) => {
+            print_sanitize_status(&status);
+            let estimates = [
+                ("block erase", status.block_erase_estimate),
+                ("crypto erase", status.crypto_erase_estimate),
+            ];
+            for (action, estimate) in estimates {
+                if let Some(seconds) = estimate {
+                    println!("{} takes about {} s", action, seconds);
+                }
+            }
+        }
+        Err(e) => println!("nvme: failed to read the sanitize status: {}", e),
+    }
+}
+
+/// Erase every namespace after asking for confirmation, then follow the
+/// operation until it is done
+fn sanitize(action: SanitizeAction) {
+    let namespaces = get_namespaces();
+    if namespaces.is_empty() {
+        println!("nvme: no controller");
+        return;
+    }
+
+    println!("This erases ALL data on every namespace of the controller:");
+    for namespace in &namespaces {
+        println!(
+            "  nvme0n{}: {} MiB",
+            namespace.nsid,
+            namespace.size_blocks * namespace.block_size as u64 / (1024 * 1024)
+        );
+    }
+    print!("Type \"{}\" to continue: ", CONFIRMATION);
+    if read_line().trim() != CONFIRMATION {
+        println!("nvme: sanitize cancelled");
+        return;
+    }
+
+    if let Err(e) = sanitize::start(action) {
+        println!("nvme: sanitize failed to start: {}", e);
+        return;
+    }
+    let result = sanitize::wait(|status| {
+        print!("\rsanitizing: {:>3}%", status.percent());
+    });
+    println!();
+    match result {
+        Ok(status) => print_sanitize_status(&status),
+        Err(e) => println!("nvme: failed to read the sanitize status: {}", e),
+    }
+}
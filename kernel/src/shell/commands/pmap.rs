@@ -0,0 +1,35 @@
+use crate::{memory::vma, println};
+
+/// List the memory areas of a user task
+pub fn run(args: &[&str]) {
+    let [pid] = args else {
+        println!("usage: pmap <pid>");
+        return;
+    };
+    let Ok(pid) = pid.parse::<u64>() else {
+        println!("pmap: invalid pid {}", pid);
+        return;
+    };
+    let Some(areas) = vma::areas(pid) else {
+        println!("pmap: no user task {}", pid);
+        return;
+    };
+
+    println!("START             END               PERM     SIZE      RSS  BACKING");
+    let (mut total, mut resident) = (0, 0);
+    for usage in areas {
+        let vma = usage.vma;
+        println!(
+            "{:016x}  {:016x}  {}  {:>6}K  {:>6}K  {}",
+            vma.start.as_u64(),
+            vma.end.as_u64(),
+            vma.permissions(),
+            vma.pages() * 4,
+            usage.resident_pages * 4,
+            vma.backing.name()
+        );
+        total += vma.pages();
+        resident += usage.resident_pages;
+    }
+    println!("total {}K, resident {}K", total * 4, resident * 4);
+}
@@ -0,0 +1,23 @@
+use crate::{
+    println,
+    tasks::scheduler::{TASKS, current_pid, is_stopped},
+};
+
+/// List running tasks
+pub fn run(_args: &[&str]) {
+    let tasks = TASKS.read().map(|tasks| tasks.clone()).unwrap_or_default();
+    let current = current_pid();
+
+    println!("  PID  PPID  TYPE    NAME");
+    for task in tasks {
+        println!(
+            "{} {:>4}  {:>4}  {:<6}  {}{}",
+            if task.pid == current { '*' } else { ' ' },
+            task.pid,
+            task.parent,
+            if task.user { "user" } else { "kernel" },
+            task.name,
+            if is_stopped(task.pid) { " (stopped)" } else { "" }
+        );
+    }
+}
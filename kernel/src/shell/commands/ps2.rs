@@ -0,0 +1,28 @@
+use crate::{
+    println,
+    ps2::{keyboard, reset, reset_count, second_port_present},
+};
+
+pub fn run(args: &[&str]) {
+    match args {
+        [] | ["status"] => status(),
+        ["reset"] => match reset() {
+            Ok(()) => println!("ps2: controller reset"),
+            Err(e) => println!("ps2: reset failed: {}", e),
+        },
+        _ => println!("usage: ps2 [status | reset]"),
+    }
+}
+
+fn status() {
+    println!("keyboard errors: {}", keyboard::error_count());
+    println!("controller resets: {}", reset_count());
+    println!(
+        "second port: {}",
+        if second_port_present() {
+            "present"
+        } else {
+            "absent"
+        }
+    );
+}
@@ -0,0 +1,106 @@
+use alloc::{boxed::Box, string::ToString, vec, vec::Vec};
+use x86_64::{VirtAddr, instructions::interrupts};
+
+use crate::{
+    fs::{self, NodeKind},
+    println,
+    sync::Mutex,
+    syscall::uring::RING_ADDRESS,
+    tasks::scheduler::ucreate_task,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Largest binary loaded, the whole file is read into the heap first
+const MAX_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Task names handed out so far, tasks keep theirs for good, so each file
+/// name is leaked once and reused
+static NAMES: Mutex<Vec<&'static str>> = Mutex::new("RUNBIN_NAMES", Vec::new());
+
+fn task_name(path: &str) -> &'static str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    interrupts::without_interrupts(|| {
+        let mut names = NAMES.lock();
+        if let Some(&known) = names.iter().find(|&&known| known == name) {
+            return known;
+        }
+        let leaked: &'static str = Box::leak(name.to_string().into_boxed_str());
+        names.push(leaked);
+        leaked
+    })
+}
+
+fn parse_address(address: &str) -> Option<u64> {
+    match address.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => address.parse().ok(),
+    }
+}
+
+/// Load a flat binary into a new user task and start it at its first byte
+pub fn run(args: &[&str]) {
+    let [path, address] = args else {
+        println!("usage: runbin <path> <address>");
+        return;
+    };
+    let Some(address) = parse_address(address) else {
+        println!("runbin: invalid address {}", address);
+        return;
+    };
+    // the code is copied to the start of each page, and the page at 0 stays
+    // unmapped to catch null pointers
+    if address == 0 || address % PAGE_SIZE != 0 {
+        println!("runbin: address must be page-aligned and not 0");
+        return;
+    }
+
+    let inode = match fs::resolve(path) {
+        Ok(inode) => inode,
+        Err(e) => {
+            println!("runbin: {}: {:?}", path, e);
+            return;
+        }
+    };
+    if inode.kind() != NodeKind::File {
+        println!("runbin: {}: not a file", path);
+        return;
+    }
+    let size = inode.size();
+    if size == 0 || size > MAX_SIZE {
+        println!(
+            "runbin: {}: size must be 1 byte to {} MiB",
+            path,
+            MAX_SIZE >> 20
+        );
+        return;
+    }
+    // the ring and the stack live above RING_ADDRESS
+    if address
+        .checked_add(size)
+        .is_none_or(|end| end > RING_ADDRESS)
+    {
+        println!("runbin: the binary must end below {:#x}", RING_ADDRESS);
+        return;
+    }
+
+    let mut code = vec![0; size as usize];
+    match inode.read_at(0, &mut code) {
+        Ok(read) if read == code.len() => {}
+        Ok(read) => {
+            println!("runbin: {}: short read, {} of {} bytes", path, read, size);
+            return;
+        }
+        Err(e) => {
+            println!("runbin: {}: {:?}", path, e);
+            return;
+        }
+    }
+
+    let name = task_name(path);
+    let entry = VirtAddr::new(address);
+    match interrupts::without_interrupts(|| ucreate_task(entry, Some(&code), name)) {
+        Ok(pid) => println!("runbin: started {} as pid {} at {:#x}", name, pid, address),
+        Err(e) => println!("runbin: {}: {}", path, e),
+    }
+}
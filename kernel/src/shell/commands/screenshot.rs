@@ -0,0 +1,75 @@
+use alloc::{format, vec::Vec};
+
+use crate::{
+    fs::{self, flags},
+    output::screenshot::{self, Screenshot},
+    println,
+    tasks::scheduler::current_pid,
+};
+
+pub fn run(args: &[&str]) {
+    let (raw, target) = match args {
+        [target] => (false, *target),
+        ["--raw", target] | [target, "--raw"] => (true, *target),
+        _ => {
+            println!("usage: screenshot [--raw] <path | serial>");
+            return;
+        }
+    };
+
+    let Some(screenshot) = screenshot::capture() else {
+        println!("screenshot: no framebuffer");
+        return;
+    };
+    let data = encode(&screenshot, raw);
+
+    if target == "serial" {
+        let format = if raw { "rgb" } else { "bmp" };
+        screenshot::send_serial(
+            &format!(
+                "SCREENSHOT {}x{} {}",
+                screenshot.width, screenshot.height, format
+            ),
+            &data,
+        );
+        println!("sent {} bytes to COM1", data.len());
+        return;
+    }
+
+    match write_file(target, &data) {
+        Ok(()) => println!(
+            "{}x{} screenshot written to {} ({} bytes)",
+            screenshot.width,
+            screenshot.height,
+            target,
+            data.len()
+        ),
+        Err(e) => println!("screenshot: {}: {:?}", target, e),
+    }
+}
+
+fn encode(screenshot: &Screenshot, raw: bool) -> Vec<u8> {
+    if raw {
+        screenshot.raw()
+    } else {
+        screenshot.bmp()
+    }
+}
+
+fn write_file(path: &str, data: &[u8]) -> Result<(), fs::FsError> {
+    let pid = current_pid();
+    let fd = fs::open(pid, path, flags::O_WRONLY | flags::O_CREAT | flags::O_TRUNC)?;
+    let mut written = 0;
+    let result = loop {
+        if written == data.len() {
+            break Ok(());
+        }
+        match fs::write(pid, fd, &data[written..]) {
+            Ok(0) => break Err(fs::FsError::NoSpace),
+            Ok(count) => written += count,
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = fs::close(pid, fd);
+    result
+}
@@ -0,0 +1,52 @@
+use crate::{
+    fs::{self, NodeKind},
+    println,
+    time::rtc::DateTime,
+};
+
+pub fn run(args: &[&str]) {
+    let [path] = args else {
+        println!("usage: stat <path>");
+        return;
+    };
+
+    let stat = match fs::stat(path) {
+        Ok(stat) => stat,
+        Err(e) => {
+            println!("stat: {}: {:?}", path, e);
+            return;
+        }
+    };
+
+    let kind = match stat.kind {
+        NodeKind::File => "file",
+        NodeKind::Directory => "directory",
+    };
+    println!("  File: {}", path);
+    println!(
+        "  Type: {}  Size: {}  Allocated: {}",
+        kind, stat.size, stat.allocated
+    );
+    for (label, time) in [
+        ("Access", stat.accessed),
+        ("Modify", stat.modified),
+        ("Change", stat.changed),
+    ] {
+        println!(
+            "{}: {}.{:03} UTC",
+            label,
+            DateTime::from_unix(time / 1000),
+            time % 1000
+        );
+    }
+
+    for name in fs::list_xattrs(path).unwrap_or_default() {
+        match fs::get_xattr(path, &name) {
+            Ok(value) => match core::str::from_utf8(&value) {
+                Ok(text) => println!("  {}=\"{}\"", name, text),
+                Err(_) => println!("  {}=<{} bytes>", name, value.len()),
+            },
+            Err(e) => println!("  {}: {:?}", name, e),
+        }
+    }
+}
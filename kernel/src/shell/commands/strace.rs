@@ -0,0 +1,60 @@
+use crate::{
+    println,
+    syscall::trace,
+    tasks::scheduler::{TASKS, TaskEntry},
+};
+
+const USAGE: &str = "usage: strace [<pid> | off <pid> | show [pid] | clear]";
+
+fn find_task(pid: &str) -> Option<TaskEntry> {
+    let pid = pid.parse::<u64>().ok()?;
+    TASKS.read()?.iter().find(|task| task.pid == pid).copied()
+}
+
+fn show(pid: Option<u64>) {
+    let (records, dropped) = trace::records(pid);
+    if dropped > 0 {
+        println!("({} older records dropped)", dropped);
+    }
+    for record in records {
+        println!("{}", record);
+    }
+}
+
+pub fn run(args: &[&str]) {
+    match args {
+        [] => {
+            let traced = trace::traced();
+            if traced.is_empty() {
+                println!("no task is traced");
+            }
+            for pid in traced {
+                println!("tracing task {}", pid);
+            }
+        }
+        ["show"] => show(None),
+        ["show", pid] => match pid.parse::<u64>() {
+            Ok(pid) => show(Some(pid)),
+            Err(_) => println!("strace: invalid pid {}", pid),
+        },
+        ["clear"] => trace::clear(),
+        ["off", pid] => match pid.parse::<u64>() {
+            Ok(pid) => trace::set_traced(pid, false),
+            Err(_) => println!("strace: invalid pid {}", pid),
+        },
+        [pid] => match find_task(pid) {
+            Some(task) if !task.user => {
+                println!("strace: task {} is a kernel task", task.pid)
+            }
+            Some(task) => {
+                trace::set_traced(task.pid, true);
+                println!(
+                    "tracing task {} ({}), see strace show {}",
+                    task.pid, task.name, task.pid
+                );
+            }
+            None => println!("strace: no task {}", pid),
+        },
+        _ => println!("{}", USAGE),
+    }
+}
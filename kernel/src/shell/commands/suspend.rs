@@ -0,0 +1,12 @@
+use crate::{power, println};
+
+/// Suspend to RAM, returning to the prompt after wakeup
+pub fn run(args: &[&str]) {
+    match args {
+        [] => match power::suspend() {
+            Ok(()) => println!("suspend: resumed"),
+            Err(e) => println!("suspend: {}", e),
+        },
+        _ => println!("usage: suspend"),
+    }
+}
@@ -0,0 +1,34 @@
+use crate::{
+    println,
+    sysctl::{self, SysctlError},
+};
+
+pub fn run(args: &[&str]) {
+    match args {
+        [] => {
+            for sysctl in sysctl::all() {
+                println!("{} = {}  ({})", sysctl.name, (sysctl.get)(), sysctl.help);
+            }
+        }
+        [setting] => match setting.split_once('=') {
+            None => match sysctl::get(setting) {
+                Some(value) => println!("{} = {}", setting, value),
+                None => println!("sysctl: unknown key {}", setting),
+            },
+            Some((name, value)) => {
+                let Ok(value) = value.trim().parse::<u64>() else {
+                    println!("sysctl: invalid value {}", value);
+                    return;
+                };
+                match sysctl::set(name.trim(), value) {
+                    Ok(()) => {}
+                    Err(SysctlError::NoSuchKey) => println!("sysctl: unknown key {}", name),
+                    Err(SysctlError::Invalid(reason)) => {
+                        println!("sysctl: invalid value for {}: {}", name, reason)
+                    }
+                }
+            }
+        },
+        _ => println!("usage: sysctl [<key> | <key>=<value>]"),
+    }
+}
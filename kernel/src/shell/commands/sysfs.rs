@@ -0,0 +1,63 @@
+use alloc::{format, string::String, vec};
+
+use crate::{
+    fs::{self, FsError},
+    println,
+};
+
+pub fn run(args: &[&str]) {
+    match args {
+        [] => list("/sys/devices"),
+        [device] => show_device(device),
+        [device, attribute] => match read(&format!("/sys/devices/{device}/{attribute}")) {
+            Ok(value) => println!("{}", value),
+            Err(e) => println!("sysfs: {}/{}: {:?}", device, attribute, e),
+        },
+        [device, attribute, value] => {
+            let path = format!("/sys/devices/{device}/{attribute}");
+            match fs::resolve(&path).and_then(|node| node.write_at(0, value.as_bytes())) {
+                Ok(_) => {}
+                Err(FsError::DeviceAttribute(reason)) => {
+                    println!("sysfs: {}/{}: {}", device, attribute, reason)
+                }
+                Err(e) => println!("sysfs: {}/{}: {:?}", device, attribute, e),
+            }
+        }
+        _ => println!("usage: sysfs [<device> [<attribute> [<value>]]]"),
+    }
+}
+
+fn list(path: &str) {
+    match fs::resolve(path).and_then(|node| node.entries()) {
+        Ok(entries) => entries.iter().for_each(|entry| println!("{}", entry)),
+        Err(e) => println!("sysfs: {}: {:?}", path, e),
+    }
+}
+
+/// Every attribute of a device with its value
+fn show_device(device: &str) {
+    let path = format!("/sys/devices/{device}");
+    let entries = match fs::resolve(&path).and_then(|node| node.entries()) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("sysfs: {}: {:?}", device, e);
+            return;
+        }
+    };
+    for attribute in entries {
+        match read(&format!("{path}/{attribute}")) {
+            Ok(value) => println!("{} = {}", attribute, value),
+            Err(FsError::DeviceAttribute(reason)) => println!("{} = ({})", attribute, reason),
+            Err(e) => println!("{} = ({:?})", attribute, e),
+        }
+    }
+}
+
+/// The value of an attribute without its trailing newline
+fn read(path: &str) -> Result<String, FsError> {
+    let node = fs::resolve(path)?;
+    let mut buf = vec![0; 4096];
+    let count = node.read_at(0, &mut buf)?;
+    buf.truncate(count);
+    Ok(String::from_utf8_lossy(&buf).trim_end().into())
+}
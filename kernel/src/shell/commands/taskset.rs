@@ -0,0 +1,34 @@
+use crate::{
+    println,
+    tasks::scheduler::{AffinityError, affinity, set_affinity},
+};
+
+fn parse_mask(mask: &str) -> Option<u64> {
+    match mask.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => mask.parse().ok(),
+    }
+}
+
+pub fn run(args: &[&str]) {
+    let Some(pid) = args.first().and_then(|pid| pid.parse::<u64>().ok()) else {
+        println!("usage: taskset <pid> [mask]");
+        return;
+    };
+
+    match args[1..] {
+        [] => match affinity(pid) {
+            Some(mask) => println!("task {} affinity mask: {:#x}", pid, mask),
+            None => println!("taskset: no task {}", pid),
+        },
+        [mask] => match parse_mask(mask).map(|mask| set_affinity(pid, mask)) {
+            Some(Ok(())) => {}
+            Some(Err(AffinityError::NoSuchTask)) => println!("taskset: no task {}", pid),
+            Some(Err(AffinityError::NoOnlineCpu)) => {
+                println!("taskset: mask contains no online CPU")
+            }
+            None => println!("taskset: invalid mask {}", mask),
+        },
+        _ => println!("usage: taskset <pid> [mask]"),
+    }
+}
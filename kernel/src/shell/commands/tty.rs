@@ -0,0 +1,72 @@
+use alloc::{format, string::String};
+
+use crate::{
+    println,
+    tty::{self, LineCoding, Parity, StopBits},
+};
+
+pub fn run(args: &[&str]) {
+    match args {
+        [] => {
+            let devices = tty::devices();
+            if devices.is_empty() {
+                println!("no serial devices");
+            }
+            for device in devices {
+                println!("{:<10} {}", device.name(), describe(device.line_coding()));
+            }
+        }
+        [name, "send", text @ ..] => {
+            let Some(device) = tty::find(name) else {
+                println!("tty: no device {}", name);
+                return;
+            };
+            let mut line = text.join(" ");
+            line.push_str("\r\n");
+            match device.write(line.as_bytes()) {
+                Ok(written) if written < line.len() => {
+                    println!("tty: {}: sent {} of {} bytes", name, written, line.len());
+                }
+                Ok(_) => {}
+                Err(e) => println!("tty: {}: {:?}", name, e),
+            }
+        }
+        [name, baud] => {
+            let Some(device) = tty::find(name) else {
+                println!("tty: no device {}", name);
+                return;
+            };
+            let Ok(baud) = baud.parse::<u32>() else {
+                println!("tty: invalid baud rate {}", baud);
+                return;
+            };
+            let coding = LineCoding {
+                baud,
+                ..device.line_coding()
+            };
+            if let Err(e) = device.set_line_coding(coding) {
+                println!("tty: {}: {:?}", name, e);
+            }
+        }
+        _ => println!("usage: tty [<name> <baud> | <name> send <text>]"),
+    }
+}
+
+fn describe(coding: LineCoding) -> String {
+    let parity = match coding.parity {
+        Parity::None => 'N',
+        Parity::Odd => 'O',
+        Parity::Even => 'E',
+        Parity::Mark => 'M',
+        Parity::Space => 'S',
+    };
+    let stop_bits = match coding.stop_bits {
+        StopBits::One => "1",
+        StopBits::OneAndHalf => "1.5",
+        StopBits::Two => "2",
+    };
+    format!(
+        "{} {}{}{}",
+        coding.baud, coding.data_bits, parity, stop_bits
+    )
+}
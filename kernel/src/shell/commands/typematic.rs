@@ -0,0 +1,28 @@
+use crate::{
+    input::{RepeatSettings, repeat_settings, set_soft_repeat_ps2, soft_repeat_ps2},
+    println,
+    ps2::keyboard::set_typematic,
+};
+
+pub fn run(args: &[&str]) {
+    match args {
+        [] => {
+            let settings = repeat_settings();
+            println!(
+                "delay {} ms, rate {} Hz, software repeat {}",
+                settings.delay_ms,
+                settings.rate_hz,
+                if soft_repeat_ps2() { "on" } else { "off" }
+            );
+        }
+        ["soft", "on"] => set_soft_repeat_ps2(true),
+        ["soft", "off"] => set_soft_repeat_ps2(false),
+        [rate, delay] => match (rate.parse::<u32>(), delay.parse::<u32>()) {
+            (Ok(rate_hz @ 2..=30), Ok(delay_ms @ 250..=1000)) => {
+                set_typematic(RepeatSettings { delay_ms, rate_hz });
+            }
+            _ => println!("typematic: rate must be 2-30 Hz and delay 250-1000 ms"),
+        },
+        _ => println!("usage: typematic [<rate_hz> <delay_ms> | soft on|off]"),
+    }
+}
@@ -0,0 +1,21 @@
+use crate::{
+    meta::{BuildLine, build},
+    println,
+    time::rtc::DateTime,
+};
+
+/// Print what the running kernel was built from
+pub fn run(args: &[&str]) {
+    match args {
+        [] | ["-s"] => println!("{}", build::SYSNAME),
+        ["-r"] => println!("{}", build::RELEASE),
+        ["-v"] => println!(
+            "{} {} UTC",
+            build::GIT_HASH,
+            DateTime::from_unix(build::TIME)
+        ),
+        ["-m"] => println!("{}", build::MACHINE),
+        ["-a"] => println!("{}", BuildLine),
+        _ => println!("usage: uname [-s | -r | -v | -m | -a]"),
+    }
+}
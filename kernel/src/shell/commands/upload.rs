@@ -0,0 +1,45 @@
+use alloc::vec::Vec;
+
+use crate::{
+    fs::{self, flags},
+    net::upload,
+    println,
+    tasks::scheduler::current_pid,
+};
+
+pub fn run(args: &[&str]) {
+    let result = match args {
+        [] | ["dmesg"] => upload::upload_log(),
+        [path] => match read_file(path) {
+            Ok(data) => upload::upload("file", &data),
+            Err(e) => {
+                println!("upload: {}: {:?}", path, e);
+                return;
+            }
+        },
+        _ => {
+            println!("usage: upload [dmesg | <path>]");
+            return;
+        }
+    };
+    match result {
+        Ok(filename) => println!("uploaded as {}", filename),
+        Err(e) => println!("upload: {:?}", e),
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, fs::FsError> {
+    let pid = current_pid();
+    let fd = fs::open(pid, path, flags::O_RDONLY)?;
+    let mut data = Vec::new();
+    let mut buf = [0; 4096];
+    let result = loop {
+        match fs::read(pid, fd, &mut buf) {
+            Ok(0) => break Ok(data),
+            Ok(count) => data.extend_from_slice(&buf[..count]),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = fs::close(pid, fd);
+    result
+}
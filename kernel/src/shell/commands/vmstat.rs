@@ -0,0 +1,79 @@
+use x86_64::instructions::interrupts;
+
+use crate::{
+    memory::{FRAME_ALLOCATOR, alloc::ALLOCATOR},
+    println,
+    shell::task::input_pending,
+    stats::{Counter, Sample},
+    tasks::scheduler::sleep_ticks,
+    time,
+};
+
+const MAX_INTERVAL_S: u64 = 3600;
+
+/// Rows printed between headers
+const HEADER_EVERY: usize = 20;
+
+/// Time slept between checks for a key press
+const POLL_MS: u64 = 50;
+
+/// Print allocation, page fault and context switch rates every interval
+/// until a key is pressed
+pub fn run(args: &[&str]) {
+    let interval_s = match args {
+        [] => 1,
+        [interval] => match interval.parse() {
+            Ok(interval @ 1..=MAX_INTERVAL_S) => interval,
+            _ => {
+                println!("vmstat: interval must be 1-{} s", MAX_INTERVAL_S);
+                return;
+            }
+        },
+        _ => {
+            println!("usage: vmstat [interval_s]");
+            return;
+        }
+    };
+
+    let mut previous = Sample::take();
+    for row in 0.. {
+        if row % HEADER_EVERY == 0 {
+            println!(
+                "{:>10} {:>10} {:>10} {:>10} {:>10}",
+                "free KiB", "heap KiB", "allocs/s", "faults/s", "cs/s"
+            );
+        }
+        if !wait(interval_s * 1000) {
+            return;
+        }
+
+        let sample = Sample::take();
+        let free_frames = interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR
+                .lock()
+                .as_ref()
+                .map_or(0, |allocator| allocator.free_frames())
+        });
+        println!(
+            "{:>10} {:>10} {:>10} {:>10} {:>10}",
+            free_frames * 4,
+            ALLOCATOR.in_use() / 1024,
+            sample.rate_since(&previous, Counter::Allocations),
+            sample.rate_since(&previous, Counter::PageFaults),
+            sample.rate_since(&previous, Counter::ContextSwitches)
+        );
+        previous = sample;
+    }
+}
+
+/// Sleep for `ms`, returning false early if a key was pressed
+fn wait(ms: u64) -> bool {
+    let deadline = time::uptime_ms() + ms;
+    while time::uptime_ms() < deadline {
+        if input_pending() {
+            return false;
+        }
+        sleep_ticks(time::ms_to_ticks(POLL_MS).max(1));
+    }
+    !input_pending()
+}
@@ -0,0 +1,55 @@
+//! Line editing for the interactive shell
+
+use alloc::string::String;
+
+use crate::{clipboard, print, println};
+
+/// The line being typed at the prompt, echoed as it is edited
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    line: String,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self { line: String::new() }
+    }
+
+    /// The current contents of the line
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Append a printable character
+    pub fn insert(&mut self, character: char) {
+        self.line.push(character);
+        print!("{}", character);
+    }
+
+    /// Erase the last character, if any
+    pub fn backspace(&mut self) {
+        if self.line.pop().is_some() {
+            print!("\x08 \x08");
+        }
+    }
+
+    /// Finish the line, returning its contents and leaving the editor empty
+    pub fn submit(&mut self) -> String {
+        println!();
+        core::mem::take(&mut self.line)
+    }
+
+    /// Copy the current line to the clipboard
+    pub fn copy(&self) {
+        clipboard::copy(&self.line);
+    }
+
+    /// Insert the clipboard contents, skipping anything that isn't printable
+    ///
+    /// Newlines are skipped too, so a paste never runs a command by itself.
+    pub fn paste(&mut self) {
+        for character in clipboard::paste().chars().filter(|c| !c.is_control()) {
+            self.insert(character);
+        }
+    }
+}
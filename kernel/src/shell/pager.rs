@@ -0,0 +1,84 @@
+//! Paging of long command output
+//!
+//! A command line ending in `| less` (or `| more`) has its output captured
+//! and shown a screenful at a time, sized to the terminal. At the prompt,
+//! space shows the next screenful, enter the next line and q quits.
+
+use crate::{
+    output::{capture, tty},
+    print, println,
+    shell::task::wait_key,
+};
+
+enum Next {
+    Page,
+    Line,
+    Quit,
+}
+
+/// Run `command` with its output paged
+pub fn run(command: impl FnOnce()) {
+    capture::start();
+    command();
+    let output = capture::finish();
+    page(&output);
+}
+
+/// Show `text` a screenful at a time
+fn page(text: &str) {
+    let size = tty::window_size();
+    // keep the last row for the prompt
+    let rows = (size.rows as usize).saturating_sub(1).max(1);
+    let columns = (size.columns as usize).max(1);
+
+    let mut remaining = rows;
+    for line in text.lines() {
+        if remaining == 0 {
+            remaining = match prompt() {
+                Next::Page => rows,
+                Next::Line => 1,
+                Next::Quit => return,
+            };
+        }
+        println!("{}", line);
+        remaining = remaining.saturating_sub(screen_lines(line, columns));
+    }
+}
+
+/// Wait for space, enter or q
+fn prompt() -> Next {
+    print!("\x1b[7m--More-- (space: page, enter: line, q: quit)\x1b[0m");
+    let next = loop {
+        let (scancode, state) = wait_key();
+        match scancode.to_char(state.shift_pressed(), state.caps_lock) {
+            Some(' ') => break Next::Page,
+            Some('\n') => break Next::Line,
+            Some('q' | 'Q') => break Next::Quit,
+            _ => {}
+        }
+    };
+    // erase the prompt
+    print!("\r\x1b[K");
+    next
+}
+
+/// Rows a line takes on a terminal `columns` wide, ignoring escape sequences
+fn screen_lines(line: &str, columns: usize) -> usize {
+    let mut width: usize = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip a CSI sequence up to its final byte
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+        width += 1;
+    }
+    width.div_ceil(columns).max(1)
+}
@@ -1,29 +1,162 @@
-use crate::{print, ps2::keyboard::{KeyEvent, KEYBOARD}};
-use x86_64::instructions::interrupts;
+use alloc::string::String;
+use spin::Lazy;
 
-/// consumes input from the keyboard buffer
+use crate::{
+    input::devices::{self, Subscription},
+    print,
+    ps2::keyboard::{KeyboardState, ScanCode},
+    shell::{commands, editor::LineEditor},
+    tasks::{
+        children::{self, ChildEvent},
+        scheduler::current_pid,
+    },
+};
+
+const PROMPT: &str = "> ";
+
+/// The shell's queue of key events, shared by the commands it runs
+static KEYS: Lazy<Subscription> = Lazy::new(|| devices::subscribe("shell"));
+
+/// A key pressed on the keyboard, or a character received from the serial
+/// device the terminal is mirrored to
+enum Input {
+    Key(ScanCode, KeyboardState),
+    Char(char),
+    /// A task the shell started exited
+    ChildExited(ChildEvent),
+}
+
+impl Input {
+    fn to_char(&self) -> Option<char> {
+        match self {
+            Input::Key(scancode, state) => scancode.to_char(state.shift_pressed(), state.caps_lock),
+            Input::Char(character) => Some(*character),
+            Input::ChildExited(_) => None,
+        }
+    }
+}
+
+/// consumes input from the keyboards and runs commands line by line
 pub fn locos_shell() -> ! {
+    Lazy::force(&KEYS);
+    let mut editor = LineEditor::new();
+    print!("{}", PROMPT);
+
     loop {
-        let (event, state) = interrupts::without_interrupts(|| {
-            let mut keyboard_lock = KEYBOARD.lock();
-            if let Some(ref mut keyboard) = *keyboard_lock {
-                let event = keyboard.read_key();
-                let state = keyboard.get_state();
-                (event, state)
-            } else {
-                (None, Default::default())
+        let input = wait_input(true);
+        if let Input::ChildExited(event) = input {
+            report_exit(&event);
+            print!("{}{}", PROMPT, editor.line());
+            continue;
+        }
+
+        if let Input::Key(scancode, state) = input
+            && state.ctrl_pressed()
+            && state.shift_pressed()
+        {
+            match scancode {
+                ScanCode::C => editor.copy(),
+                ScanCode::V => editor.paste(),
+                _ => {}
             }
-        });
-
-        if let Some(KeyEvent::KeyDown(scancode)) = event
-            && let Some(character) = scancode.to_char(state.shift_pressed(), state.caps_lock) {
-                if character == '\x08' {
-                    print!("\x08 \x08");
-                } else {
-                    print!("{}", character);
-                }
-            } else {
-                core::hint::spin_loop();
+            continue;
+        }
+
+        match input.to_char() {
+            Some('\x08') => editor.backspace(),
+            Some('\n') => {
+                commands::execute(&editor.submit());
+                print!("{}", PROMPT);
             }
+            Some(character) => editor.insert(character),
+            None => {}
+        }
+    }
+}
+
+/// Read a line typed at the keyboard, for commands asking a question
+pub fn read_line() -> String {
+    let mut editor = LineEditor::new();
+    loop {
+        match wait_input(false).to_char() {
+            Some('\x08') => editor.backspace(),
+            Some('\n') => return editor.submit(),
+            Some(character) => editor.insert(character),
+            None => {}
+        }
+    }
+}
+
+/// Wait for the next key press
+pub fn wait_key() -> (ScanCode, KeyboardState) {
+    loop {
+        if let Some(key) = poll_key() {
+            return key;
+        }
+        core::hint::spin_loop();
     }
 }
+
+/// Whether a key was pressed or a character received, for commands running
+/// until interrupted; the input is consumed
+pub fn input_pending() -> bool {
+    poll_key().is_some() || poll_serial().is_some()
+}
+
+/// Tell that a task started from the shell, like by `runbin`, exited
+fn report_exit(event: &ChildEvent) {
+    print!("\n");
+    if event.killed != 0 {
+        print!("[{}] killed\n", event.pid);
+    } else {
+        print!("[{}] exited with code {}\n", event.pid, event.code);
+    }
+}
+
+/// Wait for the next key press or character from the mirrored serial device,
+/// or with `reap`, for a task the shell started to exit
+fn wait_input(reap: bool) -> Input {
+    loop {
+        if reap && let Ok(event) = children::try_wait(current_pid(), None) {
+            return Input::ChildExited(event);
+        }
+        if let Some((scancode, state)) = poll_key() {
+            return Input::Key(scancode, state);
+        }
+        if let Some(character) = poll_serial() {
+            return Input::Char(character);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Next key press on a keyboard nobody grabbed, that no hotkey took
+pub fn poll_key() -> Option<(ScanCode, KeyboardState)> {
+    while let Some(event) = KEYS.read_event() {
+        if event.pressed {
+            return Some((event.key, event.state));
+        }
+    }
+    None
+}
+
+/// Next character typed on the serial device the terminal is mirrored to
+#[cfg(feature = "graphics")]
+fn poll_serial() -> Option<char> {
+    let device = crate::output::mirror::mirror_tty()?;
+    let mut byte = [0];
+    if device.read(&mut byte).ok()? == 0 {
+        return None;
+    }
+    match byte[0] {
+        b'\r' | b'\n' => Some('\n'),
+        0x08 | 0x7f => Some('\x08'),
+        byte @ 0x20..=0x7e => Some(byte as char),
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "graphics"))]
+fn poll_serial() -> Option<char> {
+    None
+}
@@ -0,0 +1,61 @@
+//! System-wide event counters
+//!
+//! Each counter only goes up, from boot on; rates come from reading them
+//! twice, as `vmstat` does. Counting is a relaxed atomic add, cheap enough for
+//! the allocator and the scheduler.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Something counted by `count`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    /// Kernel heap allocations
+    Allocations,
+    /// Page faults, those resolved by growing a stack included
+    PageFaults,
+    /// Switches from one task to another
+    ContextSwitches,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 3] = [
+        Counter::Allocations,
+        Counter::PageFaults,
+        Counter::ContextSwitches,
+    ];
+}
+
+static COUNTS: [AtomicU64; Counter::ALL.len()] = [const { AtomicU64::new(0) }; Counter::ALL.len()];
+
+/// Count one event
+pub fn count(counter: Counter) {
+    COUNTS[counter as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Events counted since boot
+pub fn get(counter: Counter) -> u64 {
+    COUNTS[counter as usize].load(Ordering::Relaxed)
+}
+
+/// All counters at one point in time
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub uptime_ms: u64,
+    pub counts: [u64; Counter::ALL.len()],
+}
+
+impl Sample {
+    pub fn take() -> Self {
+        Self {
+            uptime_ms: crate::time::uptime_ms(),
+            counts: Counter::ALL.map(get),
+        }
+    }
+
+    /// Events per second of `counter` between `earlier` and this sample
+    pub fn rate_since(&self, earlier: &Sample, counter: Counter) -> u64 {
+        let elapsed_ms = self.uptime_ms.saturating_sub(earlier.uptime_ms).max(1);
+        let events = self.counts[counter as usize].saturating_sub(earlier.counts[counter as usize]);
+        events * 1000 / elapsed_ms
+    }
+}
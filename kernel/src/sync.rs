@@ -0,0 +1,141 @@
+//! Kernel synchronization primitives
+//!
+//! `Mutex` is a drop-in replacement for `spin::Mutex` for the kernel's global
+//! locks. Every mutex belongs to a named lock class; with the `lockdep` feature
+//! enabled, acquisitions are checked against the lock order observed so far
+//! and the kernel panics as soon as two classes are taken in opposite orders.
+//! Without the feature it compiles down to a plain spin lock.
+
+use core::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
+
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod rcu;
+mod sleep;
+
+pub use sleep::{SleepMutex, SleepMutexGuard};
+
+/// A spin lock belonging to a named lock class
+pub struct Mutex<T: ?Sized> {
+    /// Lock class name, shown in lockdep reports
+    name: &'static str,
+    /// Lockdep class index plus one, 0 until first locked
+    #[cfg(feature = "lockdep")]
+    class: AtomicUsize,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            #[cfg(feature = "lockdep")]
+            class: AtomicUsize::new(0),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Lock class name
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Spin until the lock is acquired
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        let held = lockdep::acquire(self.class(), Location::caller());
+
+        MutexGuard {
+            inner: self.inner.lock(),
+            #[cfg(feature = "lockdep")]
+            held,
+        }
+    }
+
+    /// Acquire the lock if it is free
+    ///
+    /// A failed attempt can't deadlock, so only successful ones are checked.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let inner = self.inner.try_lock()?;
+        Some(MutexGuard {
+            inner,
+            #[cfg(feature = "lockdep")]
+            held: lockdep::acquire(self.class(), Location::caller()),
+        })
+    }
+
+    #[cfg(feature = "lockdep")]
+    fn class(&self) -> usize {
+        match self.class.load(Ordering::Relaxed) {
+            0 => {
+                let class = lockdep::register_class(self.name);
+                self.class.store(class + 1, Ordering::Relaxed);
+                class
+            }
+            class => class - 1,
+        }
+    }
+}
+
+/// Guard releasing a `Mutex` when dropped
+pub struct MutexGuard<'a, T: ?Sized> {
+    inner: spin::MutexGuard<'a, T>,
+    #[cfg(feature = "lockdep")]
+    held: lockdep::HeldLock,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(&self.held);
+    }
+}
+
+/// Interrupt handler nesting depth
+static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Mark the start of interrupt handling
+///
+/// Locks taken by an interrupt handler are tracked separately from the locks
+/// held by the task it interrupted, and allocations are served from the
+/// emergency pool.
+#[inline]
+pub fn irq_enter() {
+    IRQ_DEPTH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Mark the end of interrupt handling
+#[inline]
+pub fn irq_exit() {
+    IRQ_DEPTH.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Returns true while an interrupt handler is running
+#[inline]
+pub fn in_irq() -> bool {
+    IRQ_DEPTH.load(Ordering::Relaxed) > 0
+}
@@ -0,0 +1,375 @@
+//! Lock dependency tracking (lockdep-lite)
+//!
+//! Every task and the interrupt context keep a stack of the lock classes they
+//! hold. Acquiring class B while holding class A records the dependency A -> B
+//! together with where both locks were taken. If B can already reach A through
+//! recorded dependencies, the two orders can deadlock against each other, and
+//! the kernel panics right away with the current lock stack and the chain of
+//! acquisitions that established the opposite order, even if the deadlock
+//! never actually happened.
+//!
+//! All state lives in fixed-size tables so tracking never allocates; running
+//! out of room disables lockdep with a warning.
+
+use core::{
+    fmt,
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use crate::{serial_println, sync::in_irq, tasks::scheduler::current_pid, warn};
+
+/// Maximum number of lock classes
+const MAX_CLASSES: usize = 128;
+
+/// Words in a set of lock classes
+const CLASS_WORDS: usize = MAX_CLASSES / 64;
+
+/// Maximum number of tasks holding locks at the same time
+const MAX_CONTEXTS: usize = 64;
+
+/// Maximum number of locks held at once by one task
+const MAX_HELD: usize = 16;
+
+/// Context id of interrupt handlers
+const IRQ_CONTEXT: u64 = u64::MAX;
+
+/// Set once lockdep has reported a problem or run out of room
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+static LOCKDEP: spin::Mutex<Lockdep> = spin::Mutex::new(Lockdep::new());
+
+/// A lock held by a task, returned to the guard so it can be released from
+/// the context that acquired it
+#[derive(Debug, Clone, Copy)]
+pub struct HeldLock {
+    class: usize,
+    context: u64,
+    tracked: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    class: usize,
+    at: &'static Location<'static>,
+}
+
+/// First observation of a dependency between two classes
+#[derive(Clone, Copy)]
+struct Dependency {
+    /// Where the lock already held was acquired
+    held_at: &'static Location<'static>,
+    /// Where the second lock was acquired
+    acquired_at: &'static Location<'static>,
+    context: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Context {
+    owner: Option<u64>,
+    held: [Option<Held>; MAX_HELD],
+    depth: usize,
+}
+
+impl Context {
+    const EMPTY: Self = Self {
+        owner: None,
+        held: [None; MAX_HELD],
+        depth: 0,
+    };
+
+    fn held(&self) -> impl Iterator<Item = Held> + '_ {
+        self.held[..self.depth].iter().flatten().copied()
+    }
+}
+
+struct Lockdep {
+    classes: [Option<&'static str>; MAX_CLASSES],
+    /// Bit `b` of `after[a]` is set if `b` was acquired while holding `a`
+    after: [[u64; CLASS_WORDS]; MAX_CLASSES],
+    dependencies: [[Option<Dependency>; MAX_CLASSES]; MAX_CLASSES],
+    contexts: [Context; MAX_CONTEXTS],
+}
+
+impl Lockdep {
+    const fn new() -> Self {
+        Self {
+            classes: [None; MAX_CLASSES],
+            after: [[0; CLASS_WORDS]; MAX_CLASSES],
+            dependencies: [[None; MAX_CLASSES]; MAX_CLASSES],
+            contexts: [Context::EMPTY; MAX_CONTEXTS],
+        }
+    }
+
+    fn name(&self, class: usize) -> &'static str {
+        self.classes[class].unwrap_or("?")
+    }
+
+    fn is_after(&self, first: usize, second: usize) -> bool {
+        self.after[first][second / 64] & (1 << (second % 64)) != 0
+    }
+
+    /// Find the lock stack of a context, claiming a free slot if it has none
+    fn context(&mut self, id: u64) -> Option<&mut Context> {
+        let index = self
+            .contexts
+            .iter()
+            .position(|context| context.owner == Some(id))
+            .or_else(|| self.contexts.iter().position(|context| context.owner.is_none()))?;
+
+        let context = &mut self.contexts[index];
+        context.owner = Some(id);
+        Some(context)
+    }
+
+    /// Shortest chain of dependencies leading from `from` to `to`
+    ///
+    /// Returns the classes on the chain, `from` first, and the chain length.
+    /// Classes are kept as bytes so the search stays small on the stack.
+    fn path(&self, from: usize, to: usize) -> Option<([u8; MAX_CLASSES], usize)> {
+        let mut parent = [u8::MAX; MAX_CLASSES];
+        let mut queue = [0u8; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from as u8;
+        parent[from] = from as u8;
+
+        while head < tail {
+            let class = queue[head] as usize;
+            head += 1;
+            if class == to {
+                let mut len = 1;
+                let mut current = to;
+                while current != from {
+                    len += 1;
+                    current = parent[current] as usize;
+                }
+
+                let mut path = [0; MAX_CLASSES];
+                let mut current = to;
+                for slot in path[..len].iter_mut().rev() {
+                    *slot = current as u8;
+                    current = parent[current] as usize;
+                }
+                return Some((path, len));
+            }
+
+            for (next, next_parent) in parent.iter_mut().enumerate() {
+                if self.is_after(class, next) && *next_parent == u8::MAX {
+                    *next_parent = class as u8;
+                    queue[tail] = next as u8;
+                    tail += 1;
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Check acquiring `class` in context `id` against the recorded order,
+    /// then record the new dependencies and push it on the lock stack
+    fn acquire(&mut self, id: u64, class: usize, at: &'static Location<'static>) -> bool {
+        let Some(context) = self.context(id) else {
+            disable("too many tasks holding locks");
+            return false;
+        };
+        let context = *context;
+
+        for held in context.held() {
+            if held.class == class {
+                self.report_recursion(id, held, at);
+            }
+            if let Some((path, len)) = self.path(class, held.class) {
+                self.report_inversion(id, &context, class, at, &path[..len]);
+            }
+        }
+
+        for held in context.held() {
+            if self.dependencies[held.class][class].is_none() {
+                self.after[held.class][class / 64] |= 1 << (class % 64);
+                self.dependencies[held.class][class] = Some(Dependency {
+                    held_at: held.at,
+                    acquired_at: at,
+                    context: id,
+                });
+            }
+        }
+
+        let context = self.context(id).unwrap();
+        if context.depth == MAX_HELD {
+            disable("too many locks held at once");
+            return false;
+        }
+        context.held[context.depth] = Some(Held { class, at });
+        context.depth += 1;
+        true
+    }
+
+    /// Remove the most recent acquisition of `class` from the lock stack
+    fn release(&mut self, id: u64, class: usize) {
+        let Some(context) = self
+            .contexts
+            .iter_mut()
+            .find(|context| context.owner == Some(id))
+        else {
+            return;
+        };
+
+        if let Some(index) = context.held[..context.depth]
+            .iter()
+            .rposition(|held| held.is_some_and(|held| held.class == class))
+        {
+            // locks don't have to be released in order
+            context.held.copy_within(index + 1..context.depth, index);
+            context.depth -= 1;
+            context.held[context.depth] = None;
+        }
+
+        if context.depth == 0 {
+            context.owner = None;
+        }
+    }
+
+    fn report_recursion(&self, id: u64, held: Held, at: &'static Location<'static>) -> ! {
+        DISABLED.store(true, Ordering::Relaxed);
+        serial_println!("==================================================");
+        serial_println!("lockdep: recursive locking detected");
+        serial_println!(
+            "{} is acquiring {} at {}",
+            ContextName(id),
+            self.name(held.class),
+            at
+        );
+        serial_println!("but already holds it, acquired at {}", held.at);
+        serial_println!("==================================================");
+        panic!("lockdep: recursive locking of {}", self.name(held.class));
+    }
+
+    fn report_inversion(
+        &self,
+        id: u64,
+        context: &Context,
+        class: usize,
+        at: &'static Location<'static>,
+        path: &[u8],
+    ) -> ! {
+        DISABLED.store(true, Ordering::Relaxed);
+        serial_println!("==================================================");
+        serial_println!("lockdep: possible deadlock detected");
+        serial_println!(
+            "{} is acquiring {} at {}",
+            ContextName(id),
+            self.name(class),
+            at
+        );
+        serial_println!("while holding:");
+        for (depth, held) in context.held().enumerate() {
+            serial_println!("  #{} {} acquired at {}", depth, self.name(held.class), held.at);
+        }
+
+        serial_println!("but the opposite order has been seen before:");
+        for pair in path.windows(2) {
+            let (first, second) = (pair[0] as usize, pair[1] as usize);
+            if let Some(dependency) = self.dependencies[first][second] {
+                serial_println!(
+                    "  {} held {} (acquired at {}) and took {} at {}",
+                    ContextName(dependency.context),
+                    self.name(first),
+                    dependency.held_at,
+                    self.name(second),
+                    dependency.acquired_at
+                );
+            }
+        }
+        serial_println!("==================================================");
+
+        panic!(
+            "lockdep: lock order inversion between {} and {}",
+            self.name(path[path.len() - 1] as usize),
+            self.name(class)
+        );
+    }
+}
+
+struct ContextName(u64);
+
+impl fmt::Display for ContextName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            IRQ_CONTEXT => write!(f, "interrupt context"),
+            pid => write!(f, "task {}", pid),
+        }
+    }
+}
+
+fn current_context() -> u64 {
+    if in_irq() {
+        IRQ_CONTEXT
+    } else {
+        current_pid()
+    }
+}
+
+#[allow(unused_variables)]
+fn disable(reason: &str) {
+    if !DISABLED.swap(true, Ordering::Relaxed) {
+        warn!("lockdep: {}, lock tracking disabled", reason);
+    }
+}
+
+/// Register a lock class, returning its index
+pub fn register_class(name: &'static str) -> usize {
+    // locks first taken while lockdep reports or gives up, the console ones
+    // among them, must not wait for the table the report holds
+    if DISABLED.load(Ordering::Relaxed) {
+        return 0;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut lockdep = LOCKDEP.lock();
+        if let Some(class) = lockdep.classes.iter().position(|&class| class == Some(name)) {
+            return class;
+        }
+
+        match lockdep.classes.iter().position(Option::is_none) {
+            Some(class) => {
+                lockdep.classes[class] = Some(name);
+                class
+            }
+            None => {
+                disable("too many lock classes");
+                0
+            }
+        }
+    })
+}
+
+/// Record the acquisition of a lock of the given class
+///
+/// Panics if the acquisition could deadlock with an order seen before.
+pub fn acquire(class: usize, at: &'static Location<'static>) -> HeldLock {
+    let context = current_context();
+    if DISABLED.load(Ordering::Relaxed) {
+        return HeldLock {
+            class,
+            context,
+            tracked: false,
+        };
+    }
+
+    let tracked =
+        interrupts::without_interrupts(|| LOCKDEP.lock().acquire(context, class, at));
+    HeldLock {
+        class,
+        context,
+        tracked,
+    }
+}
+
+/// Record the release of a lock
+pub fn release(held: &HeldLock) {
+    if held.tracked {
+        interrupts::without_interrupts(|| LOCKDEP.lock().release(held.context, held.class));
+    }
+}
@@ -0,0 +1,146 @@
+//! Read-copy-update for read-mostly data
+//!
+//! Readers of an `Rcu` never take a lock: they load the current version and
+//! keep preemption (interrupts) off while using it. Writers build a new
+//! version, publish it with a single pointer swap and free the old one once
+//! a grace period has passed, i.e. once every CPU has been seen outside a
+//! read-side section.
+//!
+//! Grace periods are epoch based: `synchronize` advances the global epoch and
+//! waits for every other online CPU to pass through the scheduler, which
+//! records the epoch it saw. With a single CPU the writer itself being outside
+//! a read-side section already ends the grace period.
+
+use alloc::boxed::Box;
+use core::{
+    marker::PhantomData,
+    ops::Deref,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use crate::cpu::{MAX_CPUS, current_cpu, online_cpus};
+
+/// Current grace period epoch
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Last epoch each CPU was seen in a quiescent state
+static CPU_EPOCHS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Read-side section nesting depth
+static READ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Record that this CPU is outside any read-side section
+///
+/// Called by the scheduler on every task switch.
+pub fn quiescent_state() {
+    CPU_EPOCHS[current_cpu()].store(EPOCH.load(Ordering::Acquire), Ordering::Release);
+}
+
+/// Wait until every reader that could see the old version of any `Rcu` is done
+///
+/// # Panics
+/// Panics if called inside a read-side section, which would never end.
+pub fn synchronize() {
+    assert_eq!(
+        READ_DEPTH.load(Ordering::Relaxed),
+        0,
+        "RCU synchronize inside a read-side section"
+    );
+
+    let epoch = EPOCH.fetch_add(1, Ordering::AcqRel) + 1;
+    quiescent_state();
+
+    for (cpu, cpu_epoch) in CPU_EPOCHS.iter().enumerate().take(online_cpus()) {
+        while cpu != current_cpu() && cpu_epoch.load(Ordering::Acquire) < epoch {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// A value read without locking and replaced by copy-and-publish
+pub struct Rcu<T> {
+    current: AtomicPtr<T>,
+    /// Serializes writers
+    writer: spin::Mutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+unsafe impl<T: Send> Send for Rcu<T> {}
+
+impl<T> Rcu<T> {
+    /// Create an `Rcu` with nothing published yet
+    pub const fn new() -> Self {
+        Self {
+            current: AtomicPtr::new(ptr::null_mut()),
+            writer: spin::Mutex::new(()),
+        }
+    }
+
+    /// Enter a read-side section and get the current version, if any
+    ///
+    /// Interrupts stay disabled until the guard is dropped, so keep the
+    /// section short.
+    pub fn read(&self) -> Option<RcuReadGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        READ_DEPTH.fetch_add(1, Ordering::Relaxed);
+
+        let guard = RcuReadGuard {
+            value: self.current.load(Ordering::Acquire),
+            enabled,
+            _rcu: PhantomData,
+        };
+        (!guard.value.is_null()).then_some(guard)
+    }
+
+    /// Publish a new version computed from the current one
+    ///
+    /// Returns once the previous version has been freed. Must not be called
+    /// from an interrupt handler or inside a read-side section.
+    pub fn update(&self, f: impl FnOnce(Option<&T>) -> T) {
+        let _writer = self.writer.lock();
+
+        let old = self.current.load(Ordering::Acquire);
+        let new = Box::into_raw(Box::new(f(unsafe { old.as_ref() })));
+        self.current.store(new, Ordering::Release);
+
+        if !old.is_null() {
+            synchronize();
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+}
+
+impl<T> Default for Rcu<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read-side section holding one version of an `Rcu`
+pub struct RcuReadGuard<'a, T> {
+    value: *const T,
+    /// Whether interrupts were enabled before the section
+    enabled: bool,
+    _rcu: PhantomData<&'a Rcu<T>>,
+}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.value }
+    }
+}
+
+impl<T> Drop for RcuReadGuard<'_, T> {
+    fn drop(&mut self) {
+        READ_DEPTH.fetch_sub(1, Ordering::Relaxed);
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
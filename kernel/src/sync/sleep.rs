@@ -0,0 +1,123 @@
+//! Sleeping mutex with priority inheritance
+//!
+//! Unlike the spin based `Mutex`, a task finding a `SleepMutex` taken is put
+//! to sleep until the owner releases it. If the waiter is a real-time task,
+//! the owner runs at the waiter's priority until it releases the lock, so a
+//! normal task holding a lock can't be starved by medium priority tasks while
+//! a high priority task waits on it.
+//!
+//! Inherited priority is dropped as soon as the owner releases a lock, even if
+//! it still holds another one a real-time task waits for.
+//!
+//! Only usable from task context with interrupts enabled.
+
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    sync::in_irq,
+    tasks::scheduler::{
+        current_pid, inherit_priority, restore_priority, wait_for_lock, wake_lock_waiters,
+    },
+};
+
+/// A mutex that puts waiting tasks to sleep
+pub struct SleepMutex<T: ?Sized> {
+    locked: AtomicBool,
+    /// Task holding the lock
+    owner: AtomicU64,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SleepMutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for SleepMutex<T> {}
+
+impl<T> SleepMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: AtomicU64::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> SleepMutex<T> {
+    fn key(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+
+    fn try_acquire(&self) -> bool {
+        let acquired = self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+        if acquired {
+            self.owner.store(current_pid(), Ordering::Relaxed);
+        }
+        acquired
+    }
+
+    /// Acquire the lock, sleeping while another task holds it
+    ///
+    /// # Panics
+    /// Panics if the lock is taken and interrupts are disabled, since the
+    /// owner could never run to release it.
+    pub fn lock(&self) -> SleepMutexGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        loop {
+            interrupts::disable();
+            if self.try_acquire() {
+                if enabled {
+                    interrupts::enable();
+                }
+                return SleepMutexGuard { mutex: self };
+            }
+
+            assert!(
+                enabled && !in_irq(),
+                "SleepMutex contended with interrupts disabled"
+            );
+            inherit_priority(self.owner.load(Ordering::Relaxed));
+            wait_for_lock(self.key());
+        }
+    }
+
+    /// Acquire the lock if it is free
+    pub fn try_lock(&self) -> Option<SleepMutexGuard<'_, T>> {
+        interrupts::without_interrupts(|| self.try_acquire())
+            .then_some(SleepMutexGuard { mutex: self })
+    }
+}
+
+/// Guard releasing a `SleepMutex` when dropped
+pub struct SleepMutexGuard<'a, T: ?Sized> {
+    mutex: &'a SleepMutex<T>,
+}
+
+impl<T: ?Sized> Deref for SleepMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SleepMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SleepMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        restore_priority();
+        wake_lock_waiters(self.mutex.key());
+    }
+}
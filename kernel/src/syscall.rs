@@ -1,21 +1,39 @@
-/// Syscall interface for user programs
-///
-/// Syscalls use the `syscall` instruction on x86_64
-/// Calling convention:
-/// - rax: syscall number
-/// - rdi: arg1
-/// - rsi: arg2
-/// - rdx: arg3
-/// - r10: arg4
-/// - r8: arg5
-/// - r9: arg6
-///   Return value in rax
+//! Syscall interface for user programs
+//!
+//! Syscalls use the `syscall` instruction on x86_64
+//! Calling convention:
+//! - rax: syscall number
+//! - rdi: arg1
+//! - rsi: arg2
+//! - rdx: arg3
+//! - r10: arg4
+//! - r8: arg5
+//! - r9: arg6
+//!   Return value in rax
+
+pub mod trace;
+pub mod uring;
+
+#[cfg(test)]
+pub mod tests;
+
+use alloc::string::String;
+use alloc::vec;
+use core::mem::offset_of;
 use x86_64::VirtAddr;
 use x86_64::registers::control::EferFlags;
 use x86_64::registers::rflags::RFlags;
 use x86_64::registers::model_specific::{LStar, Star, SFMask, Efer};
 use x86_64::structures::gdt::SegmentSelector;
-use crate::tasks::scheduler::exit_task;
+use crate::cpu::{self, PerCpu};
+use crate::error::KError;
+use crate::fs::{self, FIRST_FD, FsError, Whence};
+use crate::memory::uaccess::{copy_to_user, is_user_range, put_user, read_user};
+use crate::memory::vma::{self, VmaEntry};
+use crate::meta::Utsname;
+use crate::tasks::children::{self, ChildEvent};
+use crate::tasks::kernelslab::KernelSlabAlloc;
+use crate::tasks::scheduler::{current_pid, exit_task_with_code, set_affinity};
 use crate::{debug, info, trace};
 use crate::gdt::{KERNEL_CODE_SEGMENT_INDEX, KERNEL_DATA_SEGMENT_INDEX, USER_CODE_SEGMENT_INDEX, USER_DATA_SEGMENT_INDEX};
 
@@ -33,8 +51,10 @@ pub fn init_syscall() {
 
         Star::write(user_cs_32, user_cs, kernel_cs, kernel_ss).unwrap(); // compressed
         LStar::write(VirtAddr::from_ptr(syscall_handler as *const ()));
-        SFMask::write(RFlags::INTERRUPT_FLAG);
+        // a task could set AC to open its memory to the kernel under SMAP
+        SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::ALIGNMENT_CHECK);
     }
+    cpu::init_this_cpu();
 
     info!("Syscall support initialized");
 }
@@ -42,15 +62,19 @@ pub fn init_syscall() {
 /// Assembly syscall handler entry point
 /// Saves registers on stack (Linux pt_regs style) and calls handle_syscall
 ///
-/// TODO: NOT SMP SAFE
+/// Switches to the syscall stack of the CPU's `PerCpu`, reached through the
+/// kernel GS base. GS is swapped back as soon as the user rsp is on the new
+/// stack: interrupts are masked until then, so a task preempted during its
+/// syscall never leaves the next task with the GS bases swapped.
 #[unsafe(naked)]
 unsafe extern "C" fn syscall_handler() {
     core::arch::naked_asm!(
-        "mov [rip + {USER_RSP}], rsp",
-
-        "mov rsp, [rip + {KERNEL_SYSCALL_STACK}]",
+        "swapgs",
+        "mov qword ptr gs:[{USER_RSP}], rsp",
+        "mov rsp, qword ptr gs:[{SYSCALL_STACK}]",
+        "push qword ptr gs:[{USER_RSP}]",  // user rsp
+        "swapgs",
 
-        "push qword ptr [rip + {USER_RSP}]",  // user rsp
         "push r11", // rflags
         "push rcx", // return rip
         "push rax", // syscall number
@@ -88,30 +112,36 @@ unsafe extern "C" fn syscall_handler() {
         "pop rsp",
 
         "sysretq",
-        USER_RSP = sym USER_RSP,
-        KERNEL_SYSCALL_STACK = sym KERNEL_SYSCALL_STACK,
+        USER_RSP = const offset_of!(PerCpu, user_rsp),
+        SYSCALL_STACK = const offset_of!(PerCpu, syscall_stack),
         handle_syscall = sym handle_syscall,
     )
 }
 
-/// Temporary storage for user RSP during syscall
-/// ts very ugly
-static mut USER_RSP: u64 = 0;
-
-/// Kernel stack for syscall handling
-/// TODO: replace with something better asap
-static mut KERNEL_SYSCALL_STACK: u64 = 0;
-
-/// Set the kernel stack for syscall handling
-/// Must be called when switching to a user task
+/// Stop at a syscall the entry couldn't have handled safely
 ///
-/// # Safety
-/// This function is not SMP-safe as it modifies a global variable.
-/// It should only be called from the task scheduler with interrupts disabled.
-pub unsafe fn set_syscall_stack(stack_bottom: VirtAddr) {
-    unsafe {
-        KERNEL_SYSCALL_STACK = stack_bottom.as_u64();
+/// The entry starts every syscall at the top of the task's kernel stack, so
+/// a `syscall` made by kernel code, including from inside another syscall,
+/// wrote its frame over whatever was there. Nothing can return through that,
+/// so it is reported at once instead of when the clobbered frames are used.
+fn check_entry(regs: &SyscallRegs) -> VirtAddr {
+    if !is_user_range(regs.rip, 1) {
+        panic!(
+            "syscall {} from kernel code at {:#x}, nested syscalls aren't supported",
+            regs.rax, regs.rip
+        );
     }
+
+    let stack = cpu::syscall_stack();
+    let bounds = KernelSlabAlloc::stack_bounds(stack);
+    let frame = regs as *const SyscallRegs as u64;
+    debug_assert!(
+        (bounds.bottom.as_u64()..bounds.top.as_u64()).contains(&frame),
+        "syscall frame {:#x} is off the syscall stack ending at {:#x}",
+        frame,
+        stack.as_u64()
+    );
+    stack
 }
 
 /// Syscall register state (Linux pt_regs style)
@@ -156,6 +186,24 @@ pub enum SyscallNumber {
     Exit = 0,
     Write = 1,
     Read = 2,
+    SchedSetAffinity = 3,
+    RingSetup = 4,
+    RingEnter = 5,
+    Open = 6,
+    Close = 7,
+    Lseek = 8,
+    Stat = 9,
+    GetXattr = 10,
+    SetXattr = 11,
+    Chdir = 12,
+    Chroot = 13,
+    Getcwd = 14,
+    Mkdir = 15,
+    Ioctl = 16,
+    Pmap = 17,
+    Uname = 18,
+    Wait = 19,
+    ChildEvents = 20,
 }
 
 impl SyscallNumber {
@@ -164,6 +212,24 @@ impl SyscallNumber {
             0 => Some(SyscallNumber::Exit),
             1 => Some(SyscallNumber::Write),
             2 => Some(SyscallNumber::Read),
+            3 => Some(SyscallNumber::SchedSetAffinity),
+            4 => Some(SyscallNumber::RingSetup),
+            5 => Some(SyscallNumber::RingEnter),
+            6 => Some(SyscallNumber::Open),
+            7 => Some(SyscallNumber::Close),
+            8 => Some(SyscallNumber::Lseek),
+            9 => Some(SyscallNumber::Stat),
+            10 => Some(SyscallNumber::GetXattr),
+            11 => Some(SyscallNumber::SetXattr),
+            12 => Some(SyscallNumber::Chdir),
+            13 => Some(SyscallNumber::Chroot),
+            14 => Some(SyscallNumber::Getcwd),
+            15 => Some(SyscallNumber::Mkdir),
+            16 => Some(SyscallNumber::Ioctl),
+            17 => Some(SyscallNumber::Pmap),
+            18 => Some(SyscallNumber::Uname),
+            19 => Some(SyscallNumber::Wait),
+            20 => Some(SyscallNumber::ChildEvents),
             _ => None,
         }
     }
@@ -175,21 +241,66 @@ impl SyscallNumber {
 /// Must only be called from syscall interrupt handler
 pub unsafe extern "C" fn handle_syscall(regs: *mut SyscallRegs) -> u64 {
     let regs = unsafe { &*regs };
-    
-    let syscall = match SyscallNumber::from_u64(regs.rax) {
-        Some(s) => s,
+    let stack = check_entry(regs);
+
+    let start = trace::enter(regs);
+    let ret = match SyscallNumber::from_u64(regs.rax) {
+        Some(syscall) => {
+            debug!("Syscall: {:?}(rdi={:#x}, rsi={:#x}, rdx={:#x})", syscall, regs.rdi, regs.rsi, regs.rdx);
+            dispatch(syscall, regs)
+        }
         None => {
             debug!("Unknown syscall number: {}", regs.rax);
-            return u64::MAX; // Error
+            KError::NotImplemented.to_syscall()
         }
     };
+    if let Some(start) = start {
+        trace::exit(regs.rax, ret, start);
+    }
+    // the syscall may have slept, the scheduler has to have switched back
+    // to this task's stack
+    debug_assert_eq!(
+        cpu::syscall_stack(),
+        stack,
+        "syscall stack not restored after a task switch"
+    );
+    ret
+}
 
-    debug!("Syscall: {:?}(rdi={:#x}, rsi={:#x}, rdx={:#x})", syscall, regs.rdi, regs.rsi, regs.rdx);
-
+/// Run a syscall with the arguments in `regs`
+fn dispatch(syscall: SyscallNumber, regs: &SyscallRegs) -> u64 {
     match syscall {
         SyscallNumber::Exit => sys_exit(regs.rdi as i32),
         SyscallNumber::Write => sys_write(regs.rdi as i32, regs.rsi as usize as *const u8, regs.rdx as usize),
-        SyscallNumber::Read => unimplemented!("need to read from keyboard"),
+        SyscallNumber::Read => sys_read(regs.rdi as i32, regs.rsi as usize as *mut u8, regs.rdx as usize),
+        SyscallNumber::SchedSetAffinity => sys_sched_setaffinity(regs.rdi, regs.rsi),
+        SyscallNumber::RingSetup => sys_ring_setup(regs.rdi as u32),
+        SyscallNumber::RingEnter => sys_ring_enter(regs.rdi as u32),
+        SyscallNumber::Open => sys_open(regs.rdi as usize as *const u8, regs.rsi as usize, regs.rdx as u32),
+        SyscallNumber::Close => sys_close(regs.rdi as i32),
+        SyscallNumber::Lseek => sys_lseek(regs.rdi as i32, regs.rsi as i64, regs.rdx as u32),
+        SyscallNumber::Stat => sys_stat(regs.rdi as usize as *const u8, regs.rsi as usize, regs.rdx as usize as *mut fs::Stat),
+        SyscallNumber::GetXattr => sys_getxattr(
+            (regs.rdi as usize as *const u8, regs.rsi as usize),
+            (regs.rdx as usize as *const u8, regs.r10 as usize),
+            regs.r8 as usize as *mut u8,
+            regs.r9 as usize,
+        ),
+        SyscallNumber::SetXattr => sys_setxattr(
+            (regs.rdi as usize as *const u8, regs.rsi as usize),
+            (regs.rdx as usize as *const u8, regs.r10 as usize),
+            regs.r8 as usize as *const u8,
+            regs.r9 as usize,
+        ),
+        SyscallNumber::Chdir => sys_chdir(regs.rdi as usize as *const u8, regs.rsi as usize),
+        SyscallNumber::Chroot => sys_chroot(regs.rdi as usize as *const u8, regs.rsi as usize),
+        SyscallNumber::Getcwd => sys_getcwd(regs.rdi as usize as *mut u8, regs.rsi as usize),
+        SyscallNumber::Mkdir => sys_mkdir(regs.rdi as usize as *const u8, regs.rsi as usize),
+        SyscallNumber::Ioctl => sys_ioctl(regs.rdi as i32, regs.rsi, regs.rdx),
+        SyscallNumber::Pmap => sys_pmap(regs.rdi, regs.rsi as usize as *mut VmaEntry, regs.rdx as usize),
+        SyscallNumber::Uname => sys_uname(regs.rdi as usize as *mut Utsname),
+        SyscallNumber::Wait => sys_wait(regs.rdi as i64, regs.rsi as usize as *mut ChildEvent, regs.rdx as u32),
+        SyscallNumber::ChildEvents => sys_child_events(),
     }
 }
 
@@ -200,46 +311,65 @@ pub unsafe extern "C" fn handle_syscall(regs: *mut SyscallRegs) -> u64 {
 ///
 /// # Returns
 /// Never returns (task is terminated)
-fn sys_exit(_exit_code: i32) -> u64 {
-    trace!("Task exiting with code {}", _exit_code);
-    
-    exit_task();
+fn sys_exit(exit_code: i32) -> u64 {
+    trace!("Task exiting with code {}", exit_code);
+
+    uring::release(current_pid());
+    fs::release(current_pid());
+    vma::release(current_pid());
+    trace::release(current_pid());
+    crate::block::sched::release(current_pid());
+
+    exit_task_with_code(exit_code);
 }
 
 /// sys_write - write to a file descriptor
 ///
 /// # Arguments
-/// * `fd` - File descriptor (0=stdin, 1=stdout, 2=stderr)
+/// * `fd` - File descriptor (0=stdin, 1=stdout, 2=stderr, 3 and up for open files)
 /// * `buf` - Pointer to buffer in user space
-/// * `count` - Number of bytes to write
+/// * `count` - Number of bytes to write, at most `MAX_TRANSFER` are
 ///
 /// # Returns
-/// Number of bytes written, or -1 on error
+/// Number of bytes written, or a negated errno value on error
 fn sys_write(fd: i32, buf: *const u8, count: usize) -> u64 {
     use crate::{print, serial_print};
+
+    let count = count.min(MAX_TRANSFER);
+    if fd >= FIRST_FD as i32 {
+        let Ok(buffer) = read_user(buf as u64, count) else {
+            debug!("sys_write: invalid buffer address {:#x}", buf as usize);
+            return KError::BadAddress.to_syscall();
+        };
+        return match fs::write(current_pid(), fd as u32, &buffer) {
+            Ok(written) => written as u64,
+            #[allow(unused_variables)]
+            Err(e) => {
+                debug!("sys_write: {:?}", e);
+                fail(e)
+            }
+        };
+    }
     
     if fd != 1 && fd != 2 {
         debug!("sys_write: unsupported fd {}", fd);
-        return u64::MAX;
+        return KError::from(FsError::BadDescriptor).to_syscall();
     }
     
-    let buf_addr = buf as usize;
-    if buf_addr >= 0x0000_8000_0000_0000 || buf_addr.saturating_add(count) >= 0x0000_8000_0000_0000 {
-        debug!("sys_write: invalid buffer address {:#x}", buf_addr);
-        return u64::MAX;
-    }
+    let Ok(buffer) = read_user(buf as u64, count) else {
+        debug!("sys_write: invalid buffer address {:#x}", buf as usize);
+        return KError::BadAddress.to_syscall();
+    };
     
     if count == 0 {
         return 0;
     }
     
-    let slice = unsafe { core::slice::from_raw_parts(buf, count) };
-    
-    let output = match core::str::from_utf8(slice) {
+    let output = match core::str::from_utf8(&buffer) {
         Ok(s) => s,
         Err(_) => {
             debug!("sys_write: invalid UTF-8 in buffer");
-            return u64::MAX; // Error
+            return KError::InvalidArgument.to_syscall();
         }
     };
     
@@ -250,3 +380,555 @@ fn sys_write(fd: i32, buf: *const u8, count: usize) -> u64 {
     
     count as u64
 }
+
+/// sys_sched_setaffinity - restrict the CPUs a task may run on
+///
+/// # Arguments
+/// * `pid` - Task id, 0 for the calling task
+/// * `mask` - One bit per CPU the task may run on
+///
+/// # Returns
+/// 0 on success, or `-ESRCH` if the task doesn't exist and `-EINVAL` if the
+/// mask has no online CPU
+fn sys_sched_setaffinity(pid: u64, mask: u64) -> u64 {
+    let pid = if pid == 0 { current_pid() } else { pid };
+
+    match set_affinity(pid, mask) {
+        Ok(()) => 0,
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_sched_setaffinity: {:?}", e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_ring_setup - map a submission/completion ring into the calling task
+///
+/// # Arguments
+/// * `entries` - Slots in each queue, a power of two up to `uring::MAX_ENTRIES`
+///
+/// # Returns
+/// User address of the ring, or a negated errno value on error
+fn sys_ring_setup(entries: u32) -> u64 {
+    match uring::setup(entries) {
+        Ok(address) => address.as_u64(),
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_ring_setup: {:?}", e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_ring_enter - hand submitted requests to the kernel workers
+///
+/// # Arguments
+/// * `min_complete` - Completions to wait for before returning, 0 to not wait
+///
+/// # Returns
+/// Number of completions waiting to be consumed, or `-ENXIO` if the task has
+/// no ring
+fn sys_ring_enter(min_complete: u32) -> u64 {
+    match uring::enter(min_complete) {
+        Ok(pending) => pending as u64,
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_ring_enter: {:?}", e);
+            fail(e)
+        }
+    }
+}
+
+/// The return value of a failed syscall, the negated errno of `error`
+fn fail(error: impl Into<KError>) -> u64 {
+    error.into().to_syscall()
+}
+
+/// Most bytes a read or write moves per call, a larger count is cut short
+/// like a short read or write
+const MAX_TRANSFER: usize = 1 << 20;
+
+/// Longest path or attribute name taken from user space
+const PATH_MAX: usize = 4096;
+
+/// A copy of a UTF-8 string in user space, None if it isn't one or is longer
+/// than `PATH_MAX`
+fn user_str(ptr: *const u8, len: usize) -> Option<String> {
+    if len > PATH_MAX {
+        return None;
+    }
+    String::from_utf8(read_user(ptr as u64, len).ok()?).ok()
+}
+
+/// sys_read - read from a file descriptor
+///
+/// # Arguments
+/// * `fd` - File descriptor, 3 and up for open files
+/// * `buf` - Pointer to buffer in user space
+/// * `count` - Size of the buffer, at most `MAX_TRANSFER` bytes are read
+///
+/// # Returns
+/// Number of bytes read, 0 at the end of the file, or a negated errno value on
+/// error. Reading stdin isn't supported yet and fails with `ENOSYS`.
+fn sys_read(fd: i32, buf: *mut u8, count: usize) -> u64 {
+    if fd < 0 {
+        debug!("sys_read: invalid fd {}", fd);
+        return KError::from(FsError::BadDescriptor).to_syscall();
+    }
+    if fd < FIRST_FD as i32 {
+        debug!("sys_read: reading fd {} isn't supported", fd);
+        return KError::NotImplemented.to_syscall();
+    }
+    if !is_user_range(buf as u64, count) {
+        debug!("sys_read: invalid buffer address {:#x}", buf as usize);
+        return KError::BadAddress.to_syscall();
+    }
+
+    let mut buffer = vec![0; count.min(MAX_TRANSFER)];
+    match fs::read(current_pid(), fd as u32, &mut buffer) {
+        Ok(read) => match copy_to_user(buf as u64, &buffer[..read]) {
+            Ok(()) => read as u64,
+            Err(e) => e.to_syscall(),
+        },
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_read: {:?}", e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_open - open a file
+///
+/// # Arguments
+/// * `path` - Pointer to a UTF-8 path in user space
+/// * `len` - Length of the path in bytes
+/// * `flags` - Access mode and `O_CREAT`, `O_TRUNC` and `O_APPEND`, as on Linux
+///
+/// # Returns
+/// New file descriptor, or a negated errno value on error
+fn sys_open(path: *const u8, len: usize, flags: u32) -> u64 {
+    let Some(path) = user_str(path, len) else {
+        debug!("sys_open: invalid path");
+        return KError::BadAddress.to_syscall();
+    };
+
+    match fs::open(current_pid(), &path, flags) {
+        Ok(fd) => fd as u64,
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_open: {}: {:?}", path, e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_close - close a file descriptor
+///
+/// # Returns
+/// 0 on success, or `-EBADF` if the descriptor isn't open
+fn sys_close(fd: i32) -> u64 {
+    let Ok(fd) = u32::try_from(fd) else {
+        debug!("sys_close: invalid fd {}", fd);
+        return KError::from(FsError::BadDescriptor).to_syscall();
+    };
+    match fs::close(current_pid(), fd) {
+        Ok(()) => 0,
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_close: {:?}", e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_lseek - move the offset of an open file
+///
+/// # Arguments
+/// * `fd` - File descriptor
+/// * `offset` - Offset relative to `whence`
+/// * `whence` - `SEEK_SET`, `SEEK_CUR`, `SEEK_END`, `SEEK_DATA` or `SEEK_HOLE`, as on Linux
+///
+/// # Returns
+/// The new offset, or a negated errno value on error, which includes `SEEK_DATA` finding no data
+/// before the end of the file
+fn sys_lseek(fd: i32, offset: i64, whence: u32) -> u64 {
+    let Some(whence) = Whence::from_u32(whence) else {
+        debug!("sys_lseek: invalid whence {}", whence);
+        return KError::InvalidArgument.to_syscall();
+    };
+    let Ok(fd) = u32::try_from(fd) else {
+        debug!("sys_lseek: invalid fd {}", fd);
+        return KError::from(FsError::BadDescriptor).to_syscall();
+    };
+    match fs::lseek(current_pid(), fd, offset, whence) {
+        Ok(offset) => offset,
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_lseek: {:?}", e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_stat - get the status of a file
+///
+/// # Arguments
+/// * `path` - Pointer to a UTF-8 path in user space
+/// * `len` - Length of the path in bytes
+/// * `buf` - Where to store the `fs::Stat`
+///
+/// # Returns
+/// 0 on success, or a negated errno value on error
+fn sys_stat(path: *const u8, len: usize, buf: *mut fs::Stat) -> u64 {
+    let Some(path) = user_str(path, len) else {
+        debug!("sys_stat: invalid path");
+        return KError::BadAddress.to_syscall();
+    };
+    if !buf.is_aligned() || !is_user_range(buf as u64, size_of::<fs::Stat>()) {
+        debug!("sys_stat: invalid buffer address {:#x}", buf as usize);
+        return KError::BadAddress.to_syscall();
+    }
+
+    match fs::task_path(current_pid(), &path).and_then(|path| fs::stat(&path)) {
+        Ok(stat) => match put_user(buf as u64, &stat) {
+            Ok(()) => 0,
+            Err(e) => e.to_syscall(),
+        },
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_stat: {}: {:?}", path, e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_getxattr - read an extended attribute of a file
+///
+/// # Arguments
+/// * `path` - Pointer to and length of a UTF-8 path in user space
+/// * `name` - Pointer to and length of the UTF-8 attribute name
+/// * `buf` - Where to store the value
+/// * `size` - Size of the buffer, 0 to only get the length of the value
+///
+/// # Returns
+/// Length of the value, or a negated errno value on error, which includes a buffer too small
+fn sys_getxattr(path: (*const u8, usize), name: (*const u8, usize), buf: *mut u8, size: usize) -> u64 {
+    let (Some(path), Some(name)) = (user_str(path.0, path.1), user_str(name.0, name.1)) else {
+        debug!("sys_getxattr: invalid path or name");
+        return KError::BadAddress.to_syscall();
+    };
+    if !is_user_range(buf as u64, size) {
+        debug!("sys_getxattr: invalid buffer address {:#x}", buf as usize);
+        return KError::BadAddress.to_syscall();
+    }
+
+    match fs::task_path(current_pid(), &path).and_then(|path| fs::get_xattr(&path, &name)) {
+        Ok(value) if size == 0 => value.len() as u64,
+        Ok(value) if value.len() <= size => match copy_to_user(buf as u64, &value) {
+            Ok(()) => value.len() as u64,
+            Err(e) => e.to_syscall(),
+        },
+        Ok(_) => {
+            debug!("sys_getxattr: buffer too small");
+            KError::BufferTooSmall.to_syscall()
+        }
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_getxattr: {} {}: {:?}", path, name, e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_setxattr - create or replace an extended attribute of a file
+///
+/// # Arguments
+/// * `path` - Pointer to and length of a UTF-8 path in user space
+/// * `name` - Pointer to and length of the UTF-8 attribute name
+/// * `value` - Pointer to the value
+/// * `size` - Length of the value, up to `fs::XATTR_SIZE_MAX`
+///
+/// # Returns
+/// 0 on success, or a negated errno value on error
+fn sys_setxattr(path: (*const u8, usize), name: (*const u8, usize), value: *const u8, size: usize) -> u64 {
+    let (Some(path), Some(name)) = (user_str(path.0, path.1), user_str(name.0, name.1)) else {
+        debug!("sys_setxattr: invalid path or name");
+        return KError::BadAddress.to_syscall();
+    };
+    if size > fs::XATTR_SIZE_MAX {
+        debug!("sys_setxattr: value too large");
+        return fail(FsError::AttributeTooLarge);
+    }
+    let Ok(value) = read_user(value as u64, size) else {
+        debug!("sys_setxattr: invalid value address {:#x}", value as usize);
+        return KError::BadAddress.to_syscall();
+    };
+
+    match fs::task_path(current_pid(), &path).and_then(|path| fs::set_xattr(&path, &name, &value)) {
+        Ok(()) => 0,
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_setxattr: {} {}: {:?}", path, name, e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_chdir - change the working directory of the calling task
+///
+/// # Arguments
+/// * `path` - Pointer to a UTF-8 path in user space
+/// * `len` - Length of the path in bytes
+///
+/// # Returns
+/// 0 on success, or a negated errno value on error
+fn sys_chdir(path: *const u8, len: usize) -> u64 {
+    let Some(path) = user_str(path, len) else {
+        debug!("sys_chdir: invalid path");
+        return KError::BadAddress.to_syscall();
+    };
+
+    match fs::chdir(current_pid(), &path) {
+        Ok(()) => 0,
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_chdir: {}: {:?}", path, e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_chroot - confine the calling task to a directory
+///
+/// The directory becomes the task's root and working directory. Since paths
+/// can't leave the root, a task can only move its root further down.
+///
+/// # Arguments
+/// * `path` - Pointer to a UTF-8 path in user space
+/// * `len` - Length of the path in bytes
+///
+/// # Returns
+/// 0 on success, or a negated errno value on error
+fn sys_chroot(path: *const u8, len: usize) -> u64 {
+    let Some(path) = user_str(path, len) else {
+        debug!("sys_chroot: invalid path");
+        return KError::BadAddress.to_syscall();
+    };
+
+    match fs::chroot(current_pid(), &path) {
+        Ok(()) => 0,
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_chroot: {}: {:?}", path, e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_getcwd - get the working directory of the calling task
+///
+/// # Arguments
+/// * `buf` - Where to store the path, as seen from the task's root
+/// * `size` - Size of the buffer
+///
+/// # Returns
+/// Length of the path, `-EFAULT` if the buffer is invalid, or `-ERANGE` if it
+/// is too small
+fn sys_getcwd(buf: *mut u8, size: usize) -> u64 {
+    if !is_user_range(buf as u64, size) {
+        debug!("sys_getcwd: invalid buffer address {:#x}", buf as usize);
+        return KError::BadAddress.to_syscall();
+    }
+
+    let cwd = fs::getcwd(current_pid());
+    if cwd.len() > size {
+        debug!("sys_getcwd: buffer too small");
+        return KError::BufferTooSmall.to_syscall();
+    }
+    match copy_to_user(buf as u64, cwd.as_bytes()) {
+        Ok(()) => cwd.len() as u64,
+        Err(e) => e.to_syscall(),
+    }
+}
+
+/// sys_mkdir - create a directory
+///
+/// # Arguments
+/// * `path` - Pointer to a UTF-8 path in user space
+/// * `len` - Length of the path in bytes
+///
+/// # Returns
+/// 0 on success, or a negated errno value on error
+fn sys_mkdir(path: *const u8, len: usize) -> u64 {
+    let Some(path) = user_str(path, len) else {
+        debug!("sys_mkdir: invalid path");
+        return KError::BadAddress.to_syscall();
+    };
+
+    match fs::task_path(current_pid(), &path).and_then(|path| fs::mkdir(&path)) {
+        Ok(()) => 0,
+        #[allow(unused_variables)]
+        Err(e) => {
+            debug!("sys_mkdir: {}: {:?}", path, e);
+            fail(e)
+        }
+    }
+}
+
+/// sys_ioctl - control a device
+///
+/// Only the console (fds 0 to 2) takes requests so far, see `output::tty`.
+///
+/// # Arguments
+/// * `fd` - File descriptor
+/// * `request` - `TIOCGWINSZ`, `TIOCGCURSOR`, `TIOCSAVECURSOR` or `TIOCRESTORECURSOR`
+/// * `arg` - Pointer to the request's structure in user space, if it has one
+///
+/// # Returns
+/// 0 on success, or a negated errno value on error
+fn sys_ioctl(fd: i32, request: u64, arg: u64) -> u64 {
+    use crate::output::tty;
+
+    if !(0..FIRST_FD as i32).contains(&fd) {
+        debug!("sys_ioctl: fd {} is not a terminal", fd);
+        return KError::NotATerminal.to_syscall();
+    }
+
+    /// Copy a request's result out to `arg`
+    fn copy_out<T: Copy>(arg: u64, value: T) -> u64 {
+        let ptr = arg as usize as *mut T;
+        if !ptr.is_aligned() || put_user(arg, &value).is_err() {
+            debug!("sys_ioctl: invalid argument address {:#x}", arg);
+            return KError::BadAddress.to_syscall();
+        }
+        0
+    }
+
+    match request {
+        tty::TIOCGWINSZ => copy_out(arg, tty::window_size()),
+        tty::TIOCGCURSOR => match tty::cursor_position() {
+            Some(position) => copy_out(arg, position),
+            None => KError::NotATerminal.to_syscall(),
+        },
+        tty::TIOCSAVECURSOR => {
+            tty::save_cursor();
+            0
+        }
+        tty::TIOCRESTORECURSOR => {
+            tty::restore_cursor();
+            0
+        }
+        _ => {
+            debug!("sys_ioctl: unknown request {:#x}", request);
+            KError::InvalidArgument.to_syscall()
+        }
+    }
+}
+
+/// sys_pmap - list the memory areas of a user task
+///
+/// # Arguments
+/// * `pid` - Task to list, 0 for the calling task
+/// * `buf` - Array of `memory::vma::VmaEntry` in user space
+/// * `count` - Number of entries `buf` holds
+///
+/// # Returns
+/// Number of areas the task has, of which the first `count` are copied to
+/// `buf`, or a negated errno value on error
+fn sys_pmap(pid: u64, buf: *mut VmaEntry, count: usize) -> u64 {
+    let pid = if pid == 0 { current_pid() } else { pid };
+    let Some(len) = count.checked_mul(size_of::<VmaEntry>()) else {
+        debug!("sys_pmap: invalid count {}", count);
+        return KError::InvalidArgument.to_syscall();
+    };
+    if !buf.is_aligned() || !is_user_range(buf as u64, len) {
+        debug!("sys_pmap: invalid buffer address {:#x}", buf as usize);
+        return KError::BadAddress.to_syscall();
+    }
+
+    let Some(areas) = vma::areas(pid) else {
+        debug!("sys_pmap: no user task {}", pid);
+        return KError::NoSuchTask.to_syscall();
+    };
+    for (index, usage) in areas.iter().take(count).enumerate() {
+        let entry = VmaEntry::from(*usage);
+        if let Err(e) = put_user(buf as u64 + (index * size_of::<VmaEntry>()) as u64, &entry) {
+            return e.to_syscall();
+        }
+    }
+    areas.len() as u64
+}
+
+/// sys_uname - identify the running kernel build
+///
+/// # Arguments
+/// * `buf` - Where to store the `meta::Utsname`
+///
+/// # Returns
+/// 0 on success, or a negated errno value on error
+fn sys_uname(buf: *mut Utsname) -> u64 {
+    if !buf.is_aligned() || !is_user_range(buf as u64, size_of::<Utsname>()) {
+        debug!("sys_uname: invalid buffer address {:#x}", buf as usize);
+        return KError::BadAddress.to_syscall();
+    }
+
+    match put_user(buf as u64, &Utsname::new()) {
+        Ok(()) => 0,
+        Err(e) => e.to_syscall(),
+    }
+}
+
+/// Flag of `sys_wait`: fail with `EAGAIN` rather than sleep if no child exited
+pub const WNOHANG: u32 = 1;
+
+/// sys_wait - wait for a child task to exit
+///
+/// # Arguments
+/// * `pid` - Child to wait for, or -1 for any child
+/// * `buf` - Where to store the `tasks::children::ChildEvent`
+/// * `flags` - `WNOHANG` or 0
+///
+/// # Returns
+/// Pid of the child, or a negated errno value on error: `ECHILD` with no
+/// matching child, `EAGAIN` with `WNOHANG` if none exited yet
+fn sys_wait(pid: i64, buf: *mut ChildEvent, flags: u32) -> u64 {
+    if !buf.is_aligned() || !is_user_range(buf as u64, size_of::<ChildEvent>()) {
+        debug!("sys_wait: invalid buffer address {:#x}", buf as usize);
+        return KError::BadAddress.to_syscall();
+    }
+    if flags & !WNOHANG != 0 || pid < -1 || pid == 0 {
+        debug!("sys_wait: invalid pid {} or flags {:#x}", pid, flags);
+        return KError::InvalidArgument.to_syscall();
+    }
+
+    let child = (pid > 0).then_some(pid as u64);
+    let result = if flags & WNOHANG != 0 {
+        children::try_wait(current_pid(), child)
+    } else {
+        children::wait(current_pid(), child)
+    };
+    match result {
+        Ok(event) => match put_user(buf as u64, &event) {
+            Ok(()) => event.pid,
+            Err(e) => e.to_syscall(),
+        },
+        Err(e) => fail(e),
+    }
+}
+
+/// sys_child_events - open a descriptor reporting child exits
+///
+/// Reading it sleeps until a child exits, then fills the buffer with as many
+/// `tasks::children::ChildEvent`s as fit.
+///
+/// # Returns
+/// New file descriptor, or a negated errno value on error
+fn sys_child_events() -> u64 {
+    let pid = current_pid();
+    match fs::install(pid, children::events_file(pid), fs::flags::O_RDONLY) {
+        Ok(fd) => fd as u64,
+        Err(e) => fail(e),
+    }
+}
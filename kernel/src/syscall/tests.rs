@@ -0,0 +1,107 @@
+//! Syscall tests
+
+use alloc::boxed::Box;
+use core::{mem::size_of, sync::atomic::Ordering};
+
+use x86_64::{VirtAddr, instructions::interrupts, registers::control::Cr3};
+
+use super::{
+    sys_read,
+    uring::{CompletionEntry, Opcode, Ring, RingHeader, SubmissionEntry},
+};
+use crate::error::errno::{EBADF, EFAULT, ENOSYS};
+
+/// Stands in for the page shared with a task
+#[repr(C, align(4096))]
+struct SharedPage([u8; 4096]);
+
+#[test_case]
+fn test_ring_ignores_corrupted_header() {
+    const ENTRIES: u32 = 4;
+    let mut shared = Box::new(SharedPage([0; 4096]));
+    let page = VirtAddr::from_ptr(shared.0.as_mut_ptr());
+    let ring = Ring::new(0, Cr3::read().0, page, ENTRIES);
+
+    let sq_offset = size_of::<RingHeader>();
+    let cq_offset = sq_offset + ENTRIES as usize * size_of::<SubmissionEntry>();
+    let header = page.as_mut_ptr::<RingHeader>();
+    unsafe {
+        let mut entry: SubmissionEntry = core::mem::zeroed();
+        entry.opcode = Opcode::Nop as u8;
+        entry.user_data = 0x1234;
+        (page + sq_offset as u64)
+            .as_mut_ptr::<SubmissionEntry>()
+            .write(entry);
+        (*header).sq_tail.store(1, Ordering::Release);
+
+        // what a task trying to have the kernel write elsewhere would do
+        (*header).entries = 0;
+        (*header).sq_offset = u32::MAX;
+        (*header).cq_offset = 0x7fff_f000;
+        (*header).sq_head.store(0x8000_0003, Ordering::Relaxed);
+        (*header).cq_tail.store(0x8000_0002, Ordering::Relaxed);
+    }
+
+    interrupts::disable();
+    let ran = ring.run_one();
+    let ran_again = ring.run_one();
+    interrupts::enable();
+    assert!(ran);
+    assert!(!ran_again);
+
+    let completion = unsafe { (page + cq_offset as u64).as_ptr::<CompletionEntry>().read() };
+    assert_eq!(completion.user_data, 0x1234);
+    assert_eq!(completion.result, 0);
+    unsafe {
+        assert_eq!((*header).sq_head.load(Ordering::Acquire), 1);
+        assert_eq!((*header).cq_tail.load(Ordering::Acquire), 1);
+    }
+}
+
+#[test_case]
+fn test_ring_checks_descriptors_and_buffers() {
+    const ENTRIES: u32 = 4;
+    let mut shared = Box::new(SharedPage([0; 4096]));
+    let page = VirtAddr::from_ptr(shared.0.as_mut_ptr());
+    let ring = Ring::new(0, Cr3::read().0, page, ENTRIES);
+
+    let sq_offset = size_of::<RingHeader>();
+    let cq_offset = sq_offset + ENTRIES as usize * size_of::<SubmissionEntry>();
+    let path = b"/tmp/ring";
+    unsafe {
+        let entries = (page + sq_offset as u64).as_mut_ptr::<SubmissionEntry>();
+        let mut entry: SubmissionEntry = core::mem::zeroed();
+        entry.opcode = Opcode::Read as u8;
+        entry.fd = -1;
+        entry.len = 8;
+        entries.write(entry);
+        // a path in kernel memory must not be read on the task's behalf
+        entry.opcode = Opcode::Open as u8;
+        entry.fd = 0;
+        entry.addr = path.as_ptr() as u64;
+        entry.len = path.len() as u64;
+        entries.add(1).write(entry);
+        (*page.as_mut_ptr::<RingHeader>())
+            .sq_tail
+            .store(2, Ordering::Release);
+    }
+
+    interrupts::disable();
+    let ran = ring.run_one() && ring.run_one();
+    interrupts::enable();
+    assert!(ran);
+
+    let completions = (page + cq_offset as u64).as_ptr::<CompletionEntry>();
+    unsafe {
+        assert_eq!(completions.read().result, -EBADF);
+        assert_eq!(completions.add(1).read().result, -EFAULT);
+    }
+}
+
+#[test_case]
+fn test_read_rejects_bad_descriptors() {
+    let mut buf = [0u8; 8];
+    assert_eq!(sys_read(-1, buf.as_mut_ptr(), buf.len()), (-EBADF) as u64);
+    // stdin can't be read yet
+    assert_eq!(sys_read(0, buf.as_mut_ptr(), buf.len()), (-ENOSYS) as u64);
+}
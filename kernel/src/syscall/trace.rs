@@ -0,0 +1,331 @@
+//! Syscall tracing
+//!
+//! Tasks can be marked as traced with `set_traced`. Every syscall a traced
+//! task makes leaves an enter record, with the arguments decoded for known
+//! syscalls and string arguments copied out of user space, and an exit record
+//! with the return value and the TSC cycles the syscall took. Records go to a
+//! ring buffer that keeps the most recent `RING_CAPACITY` of them, which the
+//! `strace` shell command prints.
+//!
+//! Untraced tasks only pay for one atomic load per syscall.
+
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+use core::{
+    arch::x86_64::_rdtsc,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use super::{SyscallNumber, SyscallRegs};
+use crate::{
+    error::errno,
+    memory::uaccess::{copy_from_user, is_user_range},
+    sync::Mutex,
+    tasks::scheduler::current_pid,
+    time,
+};
+
+/// Records kept before the oldest ones are dropped
+pub const RING_CAPACITY: usize = 512;
+
+/// Bytes of a string argument kept in a record
+const STRING_MAX: usize = 32;
+
+static TRACED: Mutex<Vec<u64>> = Mutex::new("TRACED_TASKS", Vec::new());
+/// Number of traced tasks, checked before taking the lock
+static TRACED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+static RING: Mutex<TraceRing> = Mutex::new(
+    "SYSCALL_TRACE",
+    TraceRing {
+        records: VecDeque::new(),
+        dropped: 0,
+    },
+);
+
+struct TraceRing {
+    records: VecDeque<Record>,
+    /// Records overwritten before they were read
+    dropped: u64,
+}
+
+/// How an argument is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arg {
+    Dec(&'static str),
+    Signed(&'static str),
+    Hex(&'static str),
+    /// A string given as a pointer and a length, taking two registers
+    Str(&'static str),
+}
+
+/// Name and arguments of a syscall
+fn signature(number: u64) -> Option<(&'static str, &'static [Arg])> {
+    use Arg::*;
+
+    let signature: (&'static str, &'static [Arg]) = match SyscallNumber::from_u64(number)? {
+        SyscallNumber::Exit => ("exit", &[Signed("code")]),
+        SyscallNumber::Write => ("write", &[Dec("fd"), Hex("buf"), Dec("count")]),
+        SyscallNumber::Read => ("read", &[Dec("fd"), Hex("buf"), Dec("count")]),
+        SyscallNumber::SchedSetAffinity => ("sched_setaffinity", &[Dec("pid"), Hex("mask")]),
+        SyscallNumber::RingSetup => ("ring_setup", &[Dec("entries")]),
+        SyscallNumber::RingEnter => ("ring_enter", &[Dec("min_complete")]),
+        SyscallNumber::Open => ("open", &[Str("path"), Hex("flags")]),
+        SyscallNumber::Close => ("close", &[Dec("fd")]),
+        SyscallNumber::Lseek => ("lseek", &[Dec("fd"), Signed("offset"), Dec("whence")]),
+        SyscallNumber::Stat => ("stat", &[Str("path"), Hex("buf")]),
+        SyscallNumber::GetXattr => (
+            "getxattr",
+            &[Str("path"), Str("name"), Hex("value"), Dec("size")],
+        ),
+        SyscallNumber::SetXattr => (
+            "setxattr",
+            &[Str("path"), Str("name"), Hex("value"), Dec("size")],
+        ),
+        SyscallNumber::Chdir => ("chdir", &[Str("path")]),
+        SyscallNumber::Chroot => ("chroot", &[Str("path")]),
+        SyscallNumber::Getcwd => ("getcwd", &[Hex("buf"), Dec("size")]),
+        SyscallNumber::Mkdir => ("mkdir", &[Str("path")]),
+        SyscallNumber::Ioctl => ("ioctl", &[Dec("fd"), Hex("request"), Hex("arg")]),
+        SyscallNumber::Pmap => ("pmap", &[Dec("pid"), Hex("buf"), Dec("count")]),
+        SyscallNumber::Uname => ("uname", &[Hex("buf")]),
+        SyscallNumber::Wait => ("wait", &[Signed("pid"), Hex("buf"), Hex("flags")]),
+        SyscallNumber::ChildEvents => ("child_events", &[]),
+    };
+    Some(signature)
+}
+
+/// The start of a string argument, copied out of user space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserString {
+    bytes: [u8; STRING_MAX],
+    len: u8,
+    truncated: bool,
+}
+
+impl UserString {
+    fn copy(ptr: u64, len: u64) -> Option<Self> {
+        let len = len as usize;
+        if !is_user_range(ptr, len) {
+            return None;
+        }
+        let copied = len.min(STRING_MAX);
+        let mut bytes = [0; STRING_MAX];
+        copy_from_user(&mut bytes[..copied], ptr).ok()?;
+        Some(Self {
+            bytes,
+            len: copied as u8,
+            truncated: copied < len,
+        })
+    }
+
+    fn as_str(&self) -> &str {
+        let bytes = &self.bytes[..self.len as usize];
+        match core::str::from_utf8(bytes) {
+            Ok(s) => s,
+            // cut in the middle of a character
+            Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+/// One end of a traced syscall
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Enter {
+        args: [u64; 6],
+        /// String arguments in the order they appear
+        strings: [Option<UserString>; 2],
+    },
+    Exit {
+        ret: u64,
+        cycles: u64,
+    },
+}
+
+/// A syscall entered or returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub pid: u64,
+    pub tick: u64,
+    pub number: u64,
+    pub event: Event,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:>8}] {:>4} ", self.tick, self.pid)?;
+        let (name, signature) = match signature(self.number) {
+            Some((name, signature)) => (Some(name), signature),
+            None => (None, &[][..]),
+        };
+        match name {
+            Some(name) => write!(f, "{name}")?,
+            None => write!(f, "syscall_{}", self.number)?,
+        }
+
+        match self.event {
+            Event::Enter { args, strings } => {
+                write!(f, "(")?;
+                if name.is_none() {
+                    for (index, arg) in args.iter().enumerate() {
+                        let separator = if index == 0 { "" } else { ", " };
+                        write!(f, "{separator}{arg:#x}")?;
+                    }
+                    return write!(f, ")");
+                }
+
+                let mut registers = args.iter().copied();
+                let mut strings = strings.iter();
+                for (index, arg) in signature.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    let value = registers.next().unwrap_or(0);
+                    match *arg {
+                        Arg::Dec(name) => write!(f, "{name}={value}")?,
+                        Arg::Signed(name) => write!(f, "{}={}", name, value as i64)?,
+                        Arg::Hex(name) => write!(f, "{name}={value:#x}")?,
+                        Arg::Str(name) => {
+                            registers.next();
+                            match strings.next().copied().flatten() {
+                                Some(string) => {
+                                    let more = if string.truncated { "..." } else { "" };
+                                    write!(f, "{}={:?}{}", name, string.as_str(), more)?
+                                }
+                                None => write!(f, "{name}={value:#x}")?,
+                            }
+                        }
+                    }
+                }
+                write!(f, ")")
+            }
+            Event::Exit { ret, cycles } => {
+                let errno = (ret as i64).wrapping_neg();
+                if (1..=errno::MAX_ERRNO).contains(&errno) {
+                    match errno::name(errno) {
+                        Some(name) => write!(f, " = -{name}")?,
+                        None => write!(f, " = -{errno}")?,
+                    }
+                } else {
+                    write!(f, " = {ret}")?;
+                }
+                write!(f, " <{cycles} cycles>")
+            }
+        }
+    }
+}
+
+/// Turn tracing of a task on or off
+pub fn set_traced(pid: u64, traced: bool) {
+    interrupts::without_interrupts(|| {
+        let mut tasks = TRACED.lock();
+        let index = tasks.iter().position(|&task| task == pid);
+        match (index, traced) {
+            (None, true) => tasks.push(pid),
+            (Some(index), false) => {
+                tasks.swap_remove(index);
+            }
+            _ => {}
+        }
+        TRACED_COUNT.store(tasks.len(), Ordering::Relaxed);
+    });
+}
+
+pub fn is_traced(pid: u64) -> bool {
+    TRACED_COUNT.load(Ordering::Relaxed) > 0
+        && interrupts::without_interrupts(|| TRACED.lock().contains(&pid))
+}
+
+/// Traced tasks
+pub fn traced() -> Vec<u64> {
+    interrupts::without_interrupts(|| TRACED.lock().clone())
+}
+
+/// Stop tracing a task that exited
+pub fn release(pid: u64) {
+    set_traced(pid, false);
+}
+
+fn push(record: Record) {
+    interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        if ring.records.len() == RING_CAPACITY {
+            ring.records.pop_front();
+            ring.dropped += 1;
+        }
+        ring.records.push_back(record);
+    });
+}
+
+/// Record a syscall being entered, if the current task is traced
+///
+/// Returns the TSC value to pass to `exit`.
+pub fn enter(regs: &SyscallRegs) -> Option<u64> {
+    let pid = current_pid();
+    if !is_traced(pid) {
+        return None;
+    }
+
+    let args = [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9];
+    let mut strings = [None; 2];
+    if let Some((_, signature)) = signature(regs.rax) {
+        let mut register = 0;
+        let mut string = 0;
+        for arg in signature {
+            if let Arg::Str(_) = arg {
+                strings[string] = UserString::copy(args[register], args[register + 1]);
+                string += 1;
+                register += 1;
+            }
+            register += 1;
+        }
+    }
+
+    push(Record {
+        pid,
+        tick: time::ticks(),
+        number: regs.rax,
+        event: Event::Enter { args, strings },
+    });
+    Some(unsafe { _rdtsc() })
+}
+
+/// Record a syscall returning `ret`, `start` being what `enter` returned
+pub fn exit(number: u64, ret: u64, start: u64) {
+    push(Record {
+        pid: current_pid(),
+        tick: time::ticks(),
+        number,
+        event: Event::Exit {
+            ret,
+            cycles: unsafe { _rdtsc() }.saturating_sub(start),
+        },
+    });
+}
+
+/// Buffered records, of one task or all of them, oldest first, and the
+/// number of records dropped because the ring was full
+pub fn records(pid: Option<u64>) -> (Vec<Record>, u64) {
+    interrupts::without_interrupts(|| {
+        let ring = RING.lock();
+        let records = ring
+            .records
+            .iter()
+            .filter(|record| pid.is_none_or(|pid| record.pid == pid))
+            .copied()
+            .collect();
+        (records, ring.dropped)
+    })
+}
+
+/// Drop every buffered record
+pub fn clear() {
+    interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        ring.records.clear();
+        ring.dropped = 0;
+    });
+}
@@ -0,0 +1,534 @@
+//! Submission and completion rings shared with user tasks
+//!
+//! A user task sets up a ring with `sys_ring_setup`, which maps one page into
+//! its address space at `RING_ADDRESS`. The page holds a `RingHeader`
+//! followed by the submission queue and the completion queue. The task fills
+//! submission entries and advances `sq_tail`, then calls `sys_ring_enter` to
+//! kick the kernel workers and optionally wait for completions. Workers run
+//! the requests asynchronously and post a completion entry for each one,
+//! which the task consumes by advancing `cq_head`.
+//!
+//! Each index is only written by one side: the task owns `sq_tail` and
+//! `cq_head`, the kernel owns `sq_head` and `cq_tail`. Indices run freely and
+//! are masked with `entries - 1` to find the slot.
+//!
+//! The task can write anything to the page, so the kernel never trusts what
+//! it reads back: the geometry and the kernel's indices are kept in the
+//! kernel's `Ring` and only published in the header, and every index read
+//! from the page is masked before it is used.
+//!
+//! Requests of one ring run in submission order. Each task can have one ring.
+//!
+//! Reads, writes and opens go through the file system on behalf of the
+//! ring's owner, with its descriptors. Workers are kernel tasks, so they
+//! switch to the owner's page table around each copy to or from its buffers.
+
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, mapper::MapToError,
+    },
+};
+
+use super::{MAX_TRANSFER, PATH_MAX};
+use crate::{
+    debug,
+    error::{KError, errno::EINVAL},
+    fs::{self, FIRST_FD, FsError},
+    memory::{
+        FRAME_ALLOCATOR, compact,
+        uaccess::{copy_from_user, copy_to_user, is_user_range},
+        vma::{self, Backing, Vma},
+    },
+    print, serial_print,
+    sync::Mutex,
+    tasks::scheduler::{current_pid, kcreate_task, wait_for_event, wake_event_waiters},
+};
+
+/// Where the ring is mapped in the user address space
+pub const RING_ADDRESS: u64 = 0x0000_6000_0000_0000;
+
+/// Most entries a ring can have, so that both queues fit in one page
+pub const MAX_ENTRIES: u32 = 64;
+
+/// Kernel tasks running requests
+const WORKERS: usize = 2;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Start of the shared page
+#[repr(C)]
+pub struct RingHeader {
+    /// Next submission entry the kernel will take
+    pub sq_head: AtomicU32,
+    /// Next submission entry the task will fill
+    pub sq_tail: AtomicU32,
+    /// Next completion entry the task will read
+    pub cq_head: AtomicU32,
+    /// Next completion entry the kernel will fill
+    pub cq_tail: AtomicU32,
+    /// Slots in each queue, a power of two
+    pub entries: u32,
+    /// Offset of the submission queue from the start of the page
+    pub sq_offset: u32,
+    /// Offset of the completion queue from the start of the page
+    pub cq_offset: u32,
+    _reserved: u32,
+}
+
+/// Request operations
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Nop = 0,
+    Read = 1,
+    Write = 2,
+    Open = 3,
+}
+
+impl Opcode {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Opcode::Nop),
+            1 => Some(Opcode::Read),
+            2 => Some(Opcode::Write),
+            3 => Some(Opcode::Open),
+            _ => None,
+        }
+    }
+}
+
+/// Submission queue entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SubmissionEntry {
+    pub opcode: u8,
+    _reserved: [u8; 3],
+    /// File descriptor, or the flags for `Open`, as `sys_open` takes them
+    pub fd: i32,
+    /// User buffer, or path for `Open`
+    pub addr: u64,
+    /// Buffer size, at most `MAX_TRANSFER` bytes are moved, or path length
+    pub len: u64,
+    /// Copied to the completion entry
+    pub user_data: u64,
+}
+
+/// Completion queue entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CompletionEntry {
+    pub user_data: u64,
+    /// Bytes transferred, the new descriptor for `Open`, or a negated errno
+    /// value
+    pub result: i64,
+}
+
+/// Why a ring couldn't be set up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingError {
+    /// The entry count isn't a power of two up to `MAX_ENTRIES`
+    InvalidEntries,
+    /// The task already has a ring
+    AlreadySetUp,
+    /// The task has no ring
+    NotSetUp,
+    OutOfMemory,
+}
+
+/// A ring as seen by the kernel
+pub(super) struct Ring {
+    pid: u64,
+    /// Page table of the owning task, for reaching its buffers
+    cr3: PhysFrame,
+    /// The shared page through the higher half direct map
+    page: VirtAddr,
+    /// Slots in each queue, a power of two
+    entries: u32,
+    sq_offset: u32,
+    cq_offset: u32,
+    state: Mutex<RingState>,
+}
+
+/// The kernel's side of a ring
+struct RingState {
+    /// Cleared when the task exits, the page is freed along with its address
+    /// space right after
+    live: bool,
+    /// Worker running a request of the ring
+    busy: Option<u64>,
+    sq_head: u32,
+    cq_tail: u32,
+}
+
+impl Ring {
+    /// A ring with `entries` slots in each queue over the zeroed shared
+    /// `page`, filling in its header
+    pub(super) fn new(pid: u64, cr3: PhysFrame, page: VirtAddr, entries: u32) -> Self {
+        let sq_offset = size_of::<RingHeader>() as u32;
+        let cq_offset = sq_offset + entries * size_of::<SubmissionEntry>() as u32;
+        unsafe {
+            let header = &mut *page.as_mut_ptr::<RingHeader>();
+            header.entries = entries;
+            header.sq_offset = sq_offset;
+            header.cq_offset = cq_offset;
+        }
+        Self {
+            pid,
+            cr3,
+            page,
+            entries,
+            sq_offset,
+            cq_offset,
+            state: Mutex::new(
+                "URING_STATE",
+                RingState {
+                    live: true,
+                    busy: None,
+                    sq_head: 0,
+                    cq_tail: 0,
+                },
+            ),
+        }
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*self.page.as_ptr() }
+    }
+
+    fn mask(&self) -> u32 {
+        self.entries - 1
+    }
+
+    /// Key for the owner waiting on completions
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Completions the task hasn't consumed yet, as far as its `cq_head`
+    /// tells
+    fn pending_completions(&self, state: &RingState) -> u32 {
+        state
+            .cq_tail
+            .wrapping_sub(self.header().cq_head.load(Ordering::Acquire))
+    }
+
+    /// Run the next submitted request, returns false if there was none or
+    /// the completion queue is full
+    ///
+    /// Called with interrupts disabled. They are enabled while the request
+    /// runs, with the ring marked busy so the owner's exit waits for it and
+    /// no other worker takes the next request out of order.
+    pub(super) fn run_one(&self) -> bool {
+        let entry = {
+            let mut state = self.state.lock();
+            let header = self.header();
+            if !state.live
+                || state.busy.is_some()
+                || state.sq_head == header.sq_tail.load(Ordering::Acquire)
+                || self.pending_completions(&state) > self.mask()
+            {
+                return false;
+            }
+
+            let sq = self.page + self.sq_offset as u64;
+            let entry = unsafe {
+                sq.as_ptr::<SubmissionEntry>()
+                    .add((state.sq_head & self.mask()) as usize)
+                    .read_volatile()
+            };
+            state.sq_head = state.sq_head.wrapping_add(1);
+            header.sq_head.store(state.sq_head, Ordering::Release);
+            state.busy = Some(current_pid());
+            entry
+        };
+
+        interrupts::enable();
+        let result = self.execute(&entry);
+        interrupts::disable();
+
+        let mut state = self.state.lock();
+        state.busy = None;
+        if !state.live {
+            // the owner was killed to free memory the request allocated
+            return true;
+        }
+        let cq = self.page + self.cq_offset as u64;
+        unsafe {
+            cq.as_mut_ptr::<CompletionEntry>()
+                .add((state.cq_tail & self.mask()) as usize)
+                .write_volatile(CompletionEntry {
+                    user_data: entry.user_data,
+                    result,
+                });
+        }
+        state.cq_tail = state.cq_tail.wrapping_add(1);
+        self.header()
+            .cq_tail
+            .store(state.cq_tail, Ordering::Release);
+        drop(state);
+
+        wake_event_waiters(self.key());
+        true
+    }
+
+    fn execute(&self, entry: &SubmissionEntry) -> i64 {
+        let len = entry.len.min(MAX_TRANSFER as u64) as usize;
+        let result = match Opcode::from_u8(entry.opcode) {
+            Some(Opcode::Nop) => Ok(0),
+            Some(Opcode::Read) => self.read(entry.fd, entry.addr, len),
+            Some(Opcode::Write) => self.write(entry.fd, entry.addr, len),
+            Some(Opcode::Open) => self.open(entry.addr, entry.len, entry.fd as u32),
+            None => return -EINVAL,
+        };
+        match result {
+            Ok(value) => value as i64,
+            Err(e) => -e.errno(),
+        }
+    }
+
+    /// Read from a file into a user buffer, like `sys_read`
+    fn read(&self, fd: i32, addr: u64, len: usize) -> Result<usize, KError> {
+        let fd = file_descriptor(fd)?;
+        if !is_user_range(addr, len) {
+            return Err(KError::BadAddress);
+        }
+        let mut buffer = vec![0; len];
+        let read = fs::read(self.pid, fd, &mut buffer)?;
+        self.in_owner_space(|| copy_to_user(addr, &buffer[..read]))??;
+        Ok(read)
+    }
+
+    /// Write a user buffer to a file or the console, like `sys_write`
+    fn write(&self, fd: i32, addr: u64, len: usize) -> Result<usize, KError> {
+        if fd != 1 && fd != 2 {
+            let fd = file_descriptor(fd)?;
+            let buffer = self.read_user(addr, len)?;
+            return Ok(fs::write(self.pid, fd, &buffer)?);
+        }
+
+        let buffer = self.read_user(addr, len)?;
+        let output = core::str::from_utf8(&buffer).map_err(|_| KError::InvalidArgument)?;
+        serial_print!("{}", output);
+        if fd == 1 {
+            print!("{}", output);
+        }
+        Ok(len)
+    }
+
+    /// Open a file for the owner, like `sys_open`
+    fn open(&self, addr: u64, len: u64, flags: u32) -> Result<usize, KError> {
+        if len > PATH_MAX as u64 {
+            return Err(KError::InvalidArgument);
+        }
+        let path = String::from_utf8(self.read_user(addr, len as usize)?)
+            .map_err(|_| KError::InvalidArgument)?;
+        Ok(fs::open(self.pid, &path, flags)? as usize)
+    }
+
+    /// Copy a buffer out of the owner's address space
+    fn read_user(&self, addr: u64, len: usize) -> Result<Vec<u8>, KError> {
+        if !is_user_range(addr, len) {
+            return Err(KError::BadAddress);
+        }
+        let mut buffer = vec![0; len];
+        self.in_owner_space(|| copy_from_user(&mut buffer, addr))??;
+        Ok(buffer)
+    }
+
+    /// Run `f` on the owner's page table, failing if the owner has exited
+    ///
+    /// Interrupts stay disabled throughout, so the worker isn't switched out
+    /// with the wrong page table and the owner can't exit meanwhile.
+    fn in_owner_space<T>(&self, f: impl FnOnce() -> T) -> Result<T, KError> {
+        interrupts::without_interrupts(|| {
+            let state = self.state.lock();
+            if !state.live {
+                return Err(KError::BadAddress);
+            }
+            let (current, flags) = Cr3::read();
+            unsafe { Cr3::write(self.cr3, Cr3Flags::empty()) };
+            let result = f();
+            unsafe { Cr3::write(current, flags) };
+            drop(state);
+            Ok(result)
+        })
+    }
+}
+
+/// Descriptor of an open file, the console ones aren't files
+fn file_descriptor(fd: i32) -> Result<u32, KError> {
+    match u32::try_from(fd) {
+        Ok(fd) if fd >= FIRST_FD => Ok(fd),
+        _ => Err(FsError::BadDescriptor.into()),
+    }
+}
+
+/// Rings of all tasks
+static RINGS: Mutex<Vec<Arc<Ring>>> = Mutex::new("URING_RINGS", Vec::new());
+
+/// Key the workers wait on for new submissions
+static WORK: u8 = 0;
+
+fn work_key() -> usize {
+    &raw const WORK as usize
+}
+
+fn ring_of(pid: u64) -> Option<Arc<Ring>> {
+    RINGS.lock().iter().find(|ring| ring.pid == pid).cloned()
+}
+
+/// Set up a ring for the calling user task, returning its user address
+///
+/// Must be called from a syscall, on the task's page table.
+pub fn setup(entries: u32) -> Result<VirtAddr, RingError> {
+    if !entries.is_power_of_two() || entries > MAX_ENTRIES {
+        return Err(RingError::InvalidEntries);
+    }
+    let pid = current_pid();
+    if ring_of(pid).is_some() {
+        return Err(RingError::AlreadySetUp);
+    }
+
+    let cr3 = Cr3::read().0;
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or(RingError::OutOfMemory)?;
+    let hhdm_offset = allocator.hddm_offset;
+    let frame = allocator.allocate_frame().ok_or(RingError::OutOfMemory)?;
+    let page = VirtAddr::new(frame.start_address().as_u64() + hhdm_offset);
+
+    unsafe {
+        core::ptr::write_bytes(page.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize);
+
+        let l4_table: &mut PageTable =
+            &mut *VirtAddr::new(cr3.start_address().as_u64() + hhdm_offset).as_mut_ptr();
+        let mut page_table = OffsetPageTable::new(l4_table, VirtAddr::new(hhdm_offset));
+        // the frame is freed along with the address space when the task exits
+        let mapped = page_table.map_to(
+            Page::containing_address(VirtAddr::new(RING_ADDRESS)),
+            frame,
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE
+                | PageTableFlags::NO_EXECUTE
+                | compact::PINNED,
+            allocator,
+        );
+        match mapped {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                allocator.deallocate_frame(frame);
+                return Err(match e {
+                    MapToError::PageAlreadyMapped(_) => RingError::AlreadySetUp,
+                    _ => RingError::OutOfMemory,
+                });
+            }
+        }
+    }
+
+    vma::insert(
+        pid,
+        Vma::new(
+            VirtAddr::new(RING_ADDRESS),
+            VirtAddr::new(RING_ADDRESS + PAGE_SIZE),
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            Backing::Ring,
+        ),
+    );
+    let ring = Ring::new(pid, cr3, page, entries);
+    debug!(
+        "ring with {} entries set up for task {}, sq at {:#x}, cq at {:#x}",
+        entries,
+        pid,
+        RING_ADDRESS + ring.sq_offset as u64,
+        RING_ADDRESS + ring.cq_offset as u64
+    );
+    RINGS.lock().push(Arc::new(ring));
+    Ok(VirtAddr::new(RING_ADDRESS))
+}
+
+/// Kick the workers, then wait until at least `min_complete` completions are
+/// waiting to be consumed
+///
+/// Returns the number of completions waiting. Must be called from a syscall.
+pub fn enter(min_complete: u32) -> Result<u32, RingError> {
+    let ring = ring_of(current_pid()).ok_or(RingError::NotSetUp)?;
+    let min_complete = min_complete.min(ring.entries);
+
+    wake_event_waiters(work_key());
+    loop {
+        interrupts::disable();
+        let pending = ring.pending_completions(&ring.state.lock());
+        if pending >= min_complete {
+            return Ok(pending);
+        }
+        wait_for_event(ring.key());
+    }
+}
+
+/// Number of tasks with a ring set up
+pub fn ring_count() -> usize {
+    interrupts::without_interrupts(|| RINGS.lock().len())
+}
+
+/// Drop the ring of an exiting task, waiting for a request in flight to finish
+///
+/// A worker whose request ran out of memory may be the one releasing the
+/// ring, to kill its owner. It doesn't wait for itself, the request notices
+/// the ring is gone instead.
+pub fn release(pid: u64) {
+    let Some(ring) = ring_of(pid) else {
+        return;
+    };
+    let enabled = interrupts::are_enabled();
+    loop {
+        interrupts::disable();
+        let mut state = ring.state.lock();
+        if state.busy.is_none_or(|worker| worker == current_pid()) {
+            state.live = false;
+            break;
+        }
+        drop(state);
+        wait_for_event(ring.key());
+    }
+    RINGS.lock().retain(|ring| ring.pid != pid);
+    if enabled {
+        interrupts::enable();
+    }
+}
+
+/// Start the kernel tasks running requests
+pub fn spawn_workers() {
+    for _ in 0..WORKERS {
+        interrupts::without_interrupts(|| kcreate_task(worker, "ring worker"));
+    }
+}
+
+fn worker() -> ! {
+    loop {
+        interrupts::disable();
+        let rings = RINGS.lock().clone();
+        let ran = rings.iter().fold(false, |ran, ring| ring.run_one() | ran);
+        if ran {
+            interrupts::enable();
+        } else {
+            wait_for_event(work_key());
+        }
+    }
+}
+
+const _: () = assert!(
+    size_of::<RingHeader>()
+        + MAX_ENTRIES as usize * (size_of::<SubmissionEntry>() + size_of::<CompletionEntry>())
+        <= PAGE_SIZE as usize
+);
+const _: () = assert!(size_of::<SubmissionEntry>() == 32 && size_of::<CompletionEntry>() == 16);
@@ -0,0 +1,52 @@
+//! Kernel tunables
+//!
+//! Subsystems list their tunables in a table of `Sysctl`s, each read and
+//! written as a number through its own functions, and add the table to
+//! `TABLES`. The `sysctl` shell command lists and sets them by name.
+
+use crate::{block, input, interrupts::watchdog, pci, tasks::scheduler, time};
+
+/// A tunable named like `subsystem.setting`
+pub struct Sysctl {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub get: fn() -> u64,
+    /// Rejects values out of range, with the reason
+    pub set: fn(u64) -> Result<(), &'static str>,
+}
+
+/// Why a tunable couldn't be set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysctlError {
+    NoSuchKey,
+    Invalid(&'static str),
+}
+
+static TABLES: &[&[Sysctl]] = &[
+    block::sched::SYSCTLS,
+    input::devices::SYSCTLS,
+    pci::health::SYSCTLS,
+    scheduler::SYSCTLS,
+    time::SYSCTLS,
+    watchdog::SYSCTLS,
+    #[cfg(feature = "usb")]
+    crate::pci::usb::pm::SYSCTLS,
+];
+
+/// Every tunable
+pub fn all() -> impl Iterator<Item = &'static Sysctl> {
+    TABLES.iter().flat_map(|table| table.iter())
+}
+
+pub fn find(name: &str) -> Option<&'static Sysctl> {
+    all().find(|sysctl| sysctl.name == name)
+}
+
+pub fn get(name: &str) -> Option<u64> {
+    find(name).map(|sysctl| (sysctl.get)())
+}
+
+pub fn set(name: &str, value: u64) -> Result<(), SysctlError> {
+    let sysctl = find(name).ok_or(SysctlError::NoSuchKey)?;
+    (sysctl.set)(value).map_err(SysctlError::Invalid)
+}
@@ -1,3 +1,7 @@
+pub mod children;
+pub mod deferred;
+pub mod group;
 pub mod kernelslab;
 pub mod scheduler;
+#[cfg(test)]
 pub mod testing;
@@ -0,0 +1,193 @@
+//! Child task events
+//!
+//! Every task records the task that created it as its parent. When a user
+//! task exits or is killed, a `ChildEvent` with its exit code is queued for
+//! its parent and the parent is woken, so it learns about the exit without
+//! polling. Kernel tasks are the kernel's own business and report nothing.
+//!
+//! A parent takes events with `wait`, which can be limited to one child and
+//! can return at once instead of sleeping, or by reading the descriptor
+//! `events_file` makes, which blocks until a child exits and then reads as
+//! many whole events as fit. A parent that never looks keeps only the latest
+//! `MAX_PENDING` events.
+//!
+//! Until its event is taken, an exited child stays a zombie holding its pid.
+//! Taking the event, dropping it, or the parent exiting reaps the child.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    sync::Arc,
+};
+use x86_64::instructions::interrupts;
+
+use super::scheduler::{TASKS, TaskEntry, reap, wait_for_event, wake_event_waiters};
+use crate::{
+    fs::{FsError, Inode, NodeKind},
+    sync::Mutex,
+};
+
+/// Events kept per parent, older ones are dropped
+pub const MAX_PENDING: usize = 64;
+
+/// Exit code reported for a killed task
+pub const KILLED_CODE: i32 = -9;
+
+/// A child that exited, as the `wait` syscall and the events file copy it out
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChildEvent {
+    pub pid: u64,
+    /// The code passed to `exit`, or `KILLED_CODE`
+    pub code: i32,
+    /// 1 if the task was killed rather than exiting
+    pub killed: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// No child matches and no event is waiting
+    NoChildren,
+    /// A child matches but hasn't exited yet, and the caller didn't want to
+    /// sleep
+    WouldBlock,
+}
+
+/// Events not yet taken, by parent
+static PENDING: Mutex<BTreeMap<u64, VecDeque<ChildEvent>>> =
+    Mutex::new("CHILD_EVENTS", BTreeMap::new());
+
+/// Event key the parent `parent` sleeps on
+fn key(parent: u64) -> usize {
+    (&raw const PENDING as usize).wrapping_add(parent as usize)
+}
+
+/// Queue the exit of `task` for its parent, if it is still alive, called
+/// after the task left `TASKS`
+///
+/// Returns whether the event was queued, so the task stays a zombie until
+/// its parent takes it.
+pub fn notify(task: &TaskEntry, code: i32, killed: bool) -> bool {
+    let parent_alive = TASKS
+        .read()
+        .is_some_and(|tasks| tasks.iter().any(|entry| entry.pid == task.parent));
+    if !task.user || !parent_alive {
+        return false;
+    }
+    let event = ChildEvent {
+        pid: task.pid,
+        code,
+        killed: killed as u32,
+    };
+    let dropped = interrupts::without_interrupts(|| {
+        let mut pending = PENDING.lock();
+        let events = pending.entry(task.parent).or_default();
+        let dropped = if events.len() == MAX_PENDING {
+            events.pop_front()
+        } else {
+            None
+        };
+        events.push_back(event);
+        dropped
+    });
+    if let Some(dropped) = dropped {
+        reap(dropped.pid);
+    }
+    wake_event_waiters(key(task.parent));
+    true
+}
+
+/// Whether `parent` has a live user child matching `child`
+fn has_children(parent: u64, child: Option<u64>) -> bool {
+    TASKS.read().is_some_and(|tasks| {
+        tasks.iter().any(|task| {
+            task.user && task.parent == parent && child.is_none_or(|pid| pid == task.pid)
+        })
+    })
+}
+
+/// Take the oldest event of `parent` about `child`, or any child, without
+/// sleeping
+pub fn try_wait(parent: u64, child: Option<u64>) -> Result<ChildEvent, WaitError> {
+    let event = interrupts::without_interrupts(|| {
+        let mut pending = PENDING.lock();
+        let events = pending.get_mut(&parent)?;
+        let index = events
+            .iter()
+            .position(|event| child.is_none_or(|pid| pid == event.pid))?;
+        events.remove(index)
+    });
+    match event {
+        Some(event) => {
+            reap(event.pid);
+            Ok(event)
+        }
+        None if has_children(parent, child) => Err(WaitError::WouldBlock),
+        None => Err(WaitError::NoChildren),
+    }
+}
+
+/// Take the oldest event of `parent` about `child`, or any child, sleeping
+/// until there is one
+pub fn wait(parent: u64, child: Option<u64>) -> Result<ChildEvent, WaitError> {
+    loop {
+        interrupts::disable();
+        match try_wait(parent, child) {
+            Err(WaitError::WouldBlock) => wait_for_event(key(parent)),
+            result => {
+                interrupts::enable();
+                return result;
+            }
+        }
+    }
+}
+
+/// Forget the events of an exiting parent, reaping the children they were
+/// about
+pub fn release(parent: u64) {
+    let events = interrupts::without_interrupts(|| PENDING.lock().remove(&parent));
+    for event in events.into_iter().flatten() {
+        reap(event.pid);
+    }
+}
+
+/// The child events of `parent` as a file
+///
+/// Reads sleep until a child exits, then return as many whole events as fit.
+/// Once there are no children left and every event was read, reads return 0.
+pub fn events_file(parent: u64) -> Arc<dyn Inode> {
+    Arc::new(EventsFile { parent })
+}
+
+struct EventsFile {
+    parent: u64,
+}
+
+impl Inode for EventsFile {
+    fn kind(&self) -> NodeKind {
+        NodeKind::File
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        const EVENT_SIZE: usize = size_of::<ChildEvent>();
+        if buf.len() < EVENT_SIZE {
+            return Err(FsError::ShortBuffer);
+        }
+
+        let mut read = 0;
+        while buf.len() - read >= EVENT_SIZE {
+            let event = if read == 0 {
+                wait(self.parent, None)
+            } else {
+                try_wait(self.parent, None)
+            };
+            let Ok(event) = event else {
+                break;
+            };
+            let bytes =
+                unsafe { core::slice::from_raw_parts((&raw const event).cast::<u8>(), EVENT_SIZE) };
+            buf[read..read + EVENT_SIZE].copy_from_slice(bytes);
+            read += EVENT_SIZE;
+        }
+        Ok(read)
+    }
+}
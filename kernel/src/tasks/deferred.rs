@@ -0,0 +1,77 @@
+//! Deferred work
+//!
+//! Interrupt handlers only note that something happened and schedule a
+//! `Work` item; the deferred-work task runs the item's function shortly after,
+//! with interrupts enabled and free to take sleeping locks. Items are
+//! `kernel_api::irq::Work`, so drivers schedule theirs through the API.
+
+use alloc::vec::Vec;
+pub use kernel_api::irq::Work;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    sync::Mutex,
+    tasks::scheduler::{SchedPolicy, kcreate_task, set_policy, wait_for_event, wake_event_waiters},
+};
+
+/// Real-time priority of the deferred-work task, so interrupts are finished
+/// promptly even when the system is busy
+const WORKER_PRIORITY: u8 = 60;
+
+/// Items the task looks at, scheduling an unregistered item does nothing
+static WORKS: Mutex<Vec<&'static Work>> = Mutex::new("DEFERRED_WORKS", Vec::new());
+
+/// Register an item so it runs when scheduled, doing nothing if it already is
+///
+/// Must not be called from interrupt handlers.
+pub fn register(work: &'static Work) {
+    interrupts::without_interrupts(|| {
+        let mut works = WORKS.lock();
+        if !works
+            .iter()
+            .any(|registered| core::ptr::eq(*registered, work))
+        {
+            works.push(work);
+        }
+    });
+}
+
+/// Have the deferred-work task run `work`
+///
+/// May be called from interrupt handlers.
+pub fn schedule(work: &'static Work) {
+    work.schedule();
+}
+
+/// Wake the deferred-work task to look for scheduled items
+pub fn wake_worker() {
+    wake_event_waiters(event_key());
+}
+
+fn event_key() -> usize {
+    &raw const WORKS as usize
+}
+
+/// Start the deferred-work task
+pub fn spawn_worker() {
+    let pid = interrupts::without_interrupts(|| kcreate_task(worker, "deferred work"));
+    set_policy(pid, SchedPolicy::Fifo(WORKER_PRIORITY)).expect("deferred-work task vanished");
+}
+
+fn worker() -> ! {
+    loop {
+        // interrupts stay off from the check until the task is waiting, so
+        // a schedule in between isn't missed
+        interrupts::disable();
+        let works = WORKS.lock().clone();
+        if !works.iter().any(|work| work.is_pending()) {
+            wait_for_event(event_key());
+            continue;
+        }
+        interrupts::enable();
+
+        for work in works {
+            work.run_pending();
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Task groups with CPU quotas
+//!
+//! Every task belongs to a group, the root group by default. The scheduler
+//! charges each group the TSC cycles its tasks ran for, and over every
+//! accounting window a group with a quota may use at most that percentage of
+//! the CPU time spent so far in the window. Tasks of a group over its quota
+//! are skipped until the other groups catch up or the window ends, so a
+//! runaway program can't starve the kernel services in the root group.
+
+use alloc::{string::String, vec::Vec};
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    sync::{Mutex, MutexGuard},
+    time,
+};
+
+/// The group tasks start in, never throttled
+pub const ROOT_GROUP: u32 = 0;
+
+/// Length of an accounting window, in milliseconds
+pub const WINDOW_MS: u64 = 1000;
+
+/// Why a group operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupError {
+    /// No group has the given id
+    NoSuchGroup,
+    /// No task has the given id
+    NoSuchTask,
+    /// Quotas are percentages from 1 to 100
+    InvalidQuota,
+    /// The root group can't be changed or removed
+    RootGroup,
+    /// The group still has tasks
+    NotEmpty,
+}
+
+/// A group of tasks sharing a CPU quota
+struct TaskGroup {
+    id: u32,
+    name: String,
+    /// Percentage of CPU time the group may use per window, None for no limit
+    quota: Option<u8>,
+    /// Cycles used in the current window
+    used: u64,
+    /// Cycles used in the last complete window
+    last_used: u64,
+}
+
+/// Accounting state shared by all groups
+struct Groups {
+    groups: Vec<TaskGroup>,
+    next_id: u32,
+    /// Index of the current window
+    window: u64,
+    /// Cycles used by all groups in the current window
+    total: u64,
+    /// Cycles used by all groups in the last complete window
+    last_total: u64,
+}
+
+impl Groups {
+    fn find(&mut self, id: u32) -> Result<&mut TaskGroup, GroupError> {
+        self.groups
+            .iter_mut()
+            .find(|group| group.id == id)
+            .ok_or(GroupError::NoSuchGroup)
+    }
+
+    /// Start a new window if the current one is over
+    fn roll_window(&mut self) {
+        let window = time::uptime_ms() / WINDOW_MS;
+        if window == self.window {
+            return;
+        }
+
+        // a gap of more than one window means nothing ran in the last one
+        let consecutive = window == self.window + 1;
+        self.window = window;
+        self.last_total = if consecutive { self.total } else { 0 };
+        self.total = 0;
+        for group in &mut self.groups {
+            group.last_used = if consecutive { group.used } else { 0 };
+            group.used = 0;
+        }
+    }
+}
+
+static GROUPS: Mutex<Groups> = Mutex::new(
+    "TASK_GROUPS",
+    Groups {
+        groups: Vec::new(),
+        next_id: ROOT_GROUP + 1,
+        window: 0,
+        total: 0,
+        last_total: 0,
+    },
+);
+
+/// Snapshot of a group for display
+#[derive(Debug, Clone)]
+pub struct GroupInfo {
+    pub id: u32,
+    pub name: String,
+    pub quota: Option<u8>,
+    /// Share of the CPU used in the last complete window, in percent
+    pub usage: u8,
+    pub throttled: bool,
+}
+
+fn validate_quota(quota: Option<u8>) -> Result<(), GroupError> {
+    match quota {
+        Some(1..=100) | None => Ok(()),
+        Some(_) => Err(GroupError::InvalidQuota),
+    }
+}
+
+fn is_throttled(group: &TaskGroup, total: u64) -> bool {
+    group
+        .quota
+        .is_some_and(|quota| group.used * 100 > quota as u64 * total)
+}
+
+/// Create a group, returning its id
+pub fn create_group(name: String, quota: Option<u8>) -> Result<u32, GroupError> {
+    validate_quota(quota)?;
+
+    interrupts::without_interrupts(|| {
+        let mut groups = GROUPS.lock();
+        let id = groups.next_id;
+        groups.next_id += 1;
+        groups.groups.push(TaskGroup {
+            id,
+            name,
+            quota,
+            used: 0,
+            last_used: 0,
+        });
+        Ok(id)
+    })
+}
+
+/// Change the quota of a group
+pub fn set_quota(id: u32, quota: Option<u8>) -> Result<(), GroupError> {
+    validate_quota(quota)?;
+    if id == ROOT_GROUP {
+        return Err(GroupError::RootGroup);
+    }
+
+    interrupts::without_interrupts(|| {
+        GROUPS.lock().find(id)?.quota = quota;
+        Ok(())
+    })
+}
+
+/// Remove an empty group
+pub fn remove_group(id: u32) -> Result<(), GroupError> {
+    if id == ROOT_GROUP {
+        return Err(GroupError::RootGroup);
+    }
+    if super::scheduler::group_task_count(id) != 0 {
+        return Err(GroupError::NotEmpty);
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut groups = GROUPS.lock();
+        let index = groups
+            .groups
+            .iter()
+            .position(|group| group.id == id)
+            .ok_or(GroupError::NoSuchGroup)?;
+        groups.groups.remove(index);
+        Ok(())
+    })
+}
+
+/// Returns true if a group with this id exists
+pub fn exists(id: u32) -> bool {
+    id == ROOT_GROUP
+        || interrupts::without_interrupts(|| {
+            GROUPS.lock().groups.iter().any(|group| group.id == id)
+        })
+}
+
+/// Describe every group, the root group first
+pub fn groups() -> Vec<GroupInfo> {
+    interrupts::without_interrupts(|| {
+        let mut groups = GROUPS.lock();
+        groups.roll_window();
+
+        let percent = |used: u64| match groups.last_total {
+            0 => 0,
+            total => (used * 100 / total) as u8,
+        };
+        let root_used = groups.last_total
+            - groups.groups.iter().map(|group| group.last_used).sum::<u64>();
+
+        let mut info = Vec::with_capacity(groups.groups.len() + 1);
+        info.push(GroupInfo {
+            id: ROOT_GROUP,
+            name: String::from("root"),
+            quota: None,
+            usage: percent(root_used),
+            throttled: false,
+        });
+        info.extend(groups.groups.iter().map(|group| GroupInfo {
+            id: group.id,
+            name: group.name.clone(),
+            quota: group.quota,
+            usage: percent(group.last_used),
+            throttled: is_throttled(group, groups.total),
+        }));
+        info
+    })
+}
+
+/// Charge a group for CPU time, called by the scheduler on every switch
+pub(super) fn charge(id: u32, cycles: u64) {
+    let mut groups = GROUPS.lock();
+    groups.roll_window();
+    groups.total += cycles;
+    if id != ROOT_GROUP
+        && let Ok(group) = groups.find(id)
+    {
+        group.used += cycles;
+    }
+}
+
+/// Groups over their quota, held by the scheduler while picking a task
+pub(super) struct Throttled<'a>(MutexGuard<'a, Groups>);
+
+impl Throttled<'_> {
+    /// Returns true if tasks of group `id` must not run
+    pub(super) fn contains(&self, id: u32) -> bool {
+        id != ROOT_GROUP
+            && self
+                .0
+                .groups
+                .iter()
+                .any(|group| group.id == id && is_throttled(group, self.0.total))
+    }
+}
+
+/// Lock the groups to check which ones are over their quota
+///
+/// Doesn't allocate, so it's safe to call from the scheduler.
+pub(super) fn throttled() -> Throttled<'static> {
+    Throttled(GROUPS.lock())
+}
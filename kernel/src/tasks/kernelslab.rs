@@ -1,4 +1,3 @@
-use spin::Mutex;
 use x86_64::{
     VirtAddr,
     structures::paging::{
@@ -8,14 +7,16 @@ use x86_64::{
 
 use crate::{
     debug,
-    memory::{FRAME_ALLOCATOR, PAGE_TABLE},
+    memory::{FRAME_ALLOCATOR, PAGE_TABLE, kaslr},
+    sync::Mutex,
     tasks::scheduler::{KSTACK_SIZE, UserInfo},
     trace, warn,
 };
 
-pub static STACK_ALLOCATOR: Mutex<KernelSlabAlloc> = Mutex::new(KernelSlabAlloc::new());
+pub static STACK_ALLOCATOR: Mutex<KernelSlabAlloc> =
+    Mutex::new("STACK_ALLOCATOR", KernelSlabAlloc::new());
 
-/// Start address for kernel task stacks
+/// Start address for kernel task stacks, before the KASLR offset is added
 const KERNEL_TASKS_START: u64 = 0xFFFF_F300_0000_0000;
 /// start of user stack region. grows downwards
 pub const USER_STACKS_START: u64 = 0x0000_7fff_ffff_0000;
@@ -24,7 +25,7 @@ pub const USTACK_SIZE: u64 = 512;
 /// initial number of pages to allocate for user stack
 pub const INITIAL_STACK_PAGES: u64 = 4;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StackAllocError {
     FrameError,
     MapError,
@@ -41,9 +42,32 @@ impl core::fmt::Display for StackAllocError {
 
 impl core::error::Error for StackAllocError {}
 
+/// Address range of a kernel task stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStackBounds {
+    /// Start of the unmapped guard page below the stack
+    pub guard_page: VirtAddr,
+    /// Lowest usable address
+    pub bottom: VirtAddr,
+    /// End of the stack (exclusive)
+    pub top: VirtAddr,
+}
+
+impl KernelStackBounds {
+    /// Returns true if `addr` is in the usable part of the stack
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        (self.bottom..self.top).contains(&addr)
+    }
+
+    /// Returns true if `addr` is in the guard page
+    pub fn in_guard_page(&self, addr: VirtAddr) -> bool {
+        (self.guard_page..self.bottom).contains(&addr)
+    }
+}
+
 /// slab allocator for kernel task stacks
 ///
-/// supports max of 128 kernel tasks. Starts at KERNEL_TASKS_START
+/// supports max of 128 kernel tasks. Starts at `kernel_tasks_start()`
 pub struct KernelSlabAlloc {
     block_bitmap: u128,
 }
@@ -71,7 +95,7 @@ impl KernelSlabAlloc {
             return Err(StackAllocError::FrameError);
         }
 
-        let block_start = KERNEL_TASKS_START + (block_index as u64 * KSTACK_SIZE as u64 * 0x1000);
+        let block_start = kernel_tasks_start() + (block_index as u64 * KSTACK_SIZE as u64 * 0x1000);
 
         trace!("block start is {:#X}", block_start);
 
@@ -109,6 +133,20 @@ impl KernelSlabAlloc {
         Ok(VirtAddr::new(stack_top))
     }
 
+    /// bounds of the stack returned by `get_stack` as `stack_top`
+    pub fn stack_bounds(stack_top: VirtAddr) -> KernelStackBounds {
+        let block_size = KSTACK_SIZE as u64 * 0x1000;
+        let tasks_start = kernel_tasks_start();
+        let block_start =
+            tasks_start + (stack_top.as_u64() - tasks_start) / block_size * block_size;
+
+        KernelStackBounds {
+            guard_page: VirtAddr::new(block_start),
+            bottom: VirtAddr::new(block_start + 0x1000),
+            top: VirtAddr::new(block_start + block_size),
+        }
+    }
+
     /// deallocate a stack
     ///
     /// This does NOT unmap the pages or return frames to the allocator.
@@ -116,7 +154,7 @@ impl KernelSlabAlloc {
     pub fn return_stack(&mut self, stack_top: VirtAddr) {
         let stack_addr = stack_top.as_u64();
 
-        let offset = stack_addr - KERNEL_TASKS_START;
+        let offset = stack_addr - kernel_tasks_start();
         let block_index = (offset & !(KSTACK_SIZE as u64 * 0x1000 - 1)) / (KSTACK_SIZE as u64 * 0x1000);
 
         assert!(block_index < 128 && (self.block_bitmap & (1 << block_index)) != 0);
@@ -125,6 +163,11 @@ impl KernelSlabAlloc {
     }
 }
 
+/// Start of the kernel stacks region
+fn kernel_tasks_start() -> u64 {
+    kaslr::region(KERNEL_TASKS_START)
+}
+
 /// Information about a user stack
 /// 
 /// stack_start: higher in memory start of stack
@@ -1,7 +1,10 @@
-use core::{arch::naked_asm, error::Error};
+use core::{
+    arch::{naked_asm, x86_64::_rdtsc},
+    cmp::Reverse,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use alloc::{boxed::Box, collections::vec_deque::VecDeque, format};
-use spin::Mutex;
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
 use x86_64::{
     VirtAddr,
     instructions::interrupts::{self},
@@ -14,10 +17,83 @@ use x86_64::{
 };
 
 use crate::{
-    debug, gdt::{USER_CODE_SEGMENT_INDEX, USER_DATA_SEGMENT_INDEX, set_kernel_stack}, info, interrupts::apic::LAPIC_TIMER_VECTOR, memory::FRAME_ALLOCATOR, syscall::set_syscall_stack, tasks::kernelslab::{INITIAL_STACK_PAGES, STACK_ALLOCATOR, get_user_stack, return_user_stack}, trace
+    cpu, time, debug, error::KError, gdt::{USER_CODE_SEGMENT_INDEX, USER_DATA_SEGMENT_INDEX, set_kernel_stack}, info, interrupts::apic::{LAPIC_TIMER_VECTOR, reload_lapic_timer}, stats::{self, Counter}, sysctl::Sysctl, memory::{self, FRAME_ALLOCATOR, vma::{self, Backing, Vma}}, tasks::{children, group::{self, GroupError, ROOT_GROUP}, kernelslab::{INITIAL_STACK_PAGES, KernelSlabAlloc, KernelStackBounds, STACK_ALLOCATOR, get_user_stack, return_user_stack}}, trace, sync::{self, Mutex, rcu::{self, Rcu}}
 };
 
-static TASK_SCHEDULER: Mutex<TaskScheduler> = Mutex::new(TaskScheduler::new());
+static TASK_SCHEDULER: Mutex<TaskScheduler> = Mutex::new("TASK_SCHEDULER", TaskScheduler::new());
+
+/// Id handed to the next task created, 0 is the boot task
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+/// Id of the task currently running
+static CURRENT_PID: AtomicU64 = AtomicU64::new(0);
+
+/// TSC value at the last task switch, for charging task groups
+static LAST_SWITCH: AtomicU64 = AtomicU64::new(0);
+
+/// Time a task runs before the LAPIC timer preempts it
+static QUANTUM_MS: AtomicU64 = AtomicU64::new(10);
+
+pub static SYSCTLS: &[Sysctl] = &[Sysctl {
+    name: "sched.quantum_ms",
+    help: "time slice a task runs for before it is preempted",
+    get: quantum_ms,
+    set: |value| {
+        if !(1..=1000).contains(&value) {
+            return Err("must be 1-1000 ms");
+        }
+        QUANTUM_MS.store(value, Ordering::Relaxed);
+        interrupts::without_interrupts(reload_lapic_timer);
+        Ok(())
+    },
+}];
+
+/// Current time slice, see `sched.quantum_ms`
+pub fn quantum_ms() -> u64 {
+    QUANTUM_MS.load(Ordering::Relaxed)
+}
+
+/// Returns the id of the task currently running
+pub fn current_pid() -> u64 {
+    CURRENT_PID.load(Ordering::Relaxed)
+}
+
+/// A task as listed in `TASKS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskEntry {
+    pub pid: u64,
+    pub name: &'static str,
+    pub user: bool,
+    /// The task that created this one
+    pub parent: u64,
+}
+
+/// Live tasks, readable without taking the scheduler lock
+///
+/// Only changes when a task is created or exits, so listing tasks doesn't
+/// contend with the scheduler. Task state still lives behind the lock.
+pub static TASKS: Rcu<Vec<TaskEntry>> = Rcu::new();
+
+fn add_task_entry(entry: TaskEntry) {
+    TASKS.update(|tasks| {
+        let mut tasks = tasks.cloned().unwrap_or_default();
+        tasks.push(entry);
+        tasks
+    });
+}
+
+/// Drop task `pid` from `TASKS`, returning its entry to tell its parent
+fn remove_task_entry(pid: u64) -> Option<TaskEntry> {
+    let entry = TASKS
+        .read()
+        .and_then(|tasks| tasks.iter().find(|task| task.pid == pid).copied());
+    TASKS.update(|tasks| {
+        let mut tasks = tasks.cloned().unwrap_or_default();
+        tasks.retain(|task| task.pid != pid);
+        tasks
+    });
+    entry
+}
 
 /// stack size of kernel task in pages. Must be power of 2
 pub const KSTACK_SIZE: u8 = 4;
@@ -51,14 +127,28 @@ pub fn kinit_multitasking() {
 
     let mut scheduler = TASK_SCHEDULER.lock();
     let current_task = ProcessControlBlock {
+        pid: 0,
+        name: "kernel",
+        affinity: u64::MAX,
+        policy: SchedPolicy::Normal,
+        boost: None,
+        group: ROOT_GROUP,
         task_type: TaskType::Kernel {
             stack_start: None,
         },
         regs: current_regs,
         state: TaskState::Running,        // Mark as currently running
         cr3: Cr3::read().0,
+        reaped: false,
     };
     scheduler.task_list.push_front(current_task);
+    drop(scheduler);
+    add_task_entry(TaskEntry {
+        pid: 0,
+        name: "kernel",
+        user: false,
+        parent: 0,
+    });
     debug!(
         "Added current kernel task to scheduler with uninit registers",
     );
@@ -67,13 +157,19 @@ pub fn kinit_multitasking() {
 /// adds a new kernel task to the scheduler
 /// Each kernel task has a stack size of KSTACK_SIZE - 1, for a guard page
 ///
-/// task should be a pointer to the function to run
-pub fn kcreate_task(task_ptr: fn() -> !, name: &str) {
+/// task should be a pointer to the function to run. Returns the new task's id
+pub fn kcreate_task(task_ptr: fn() -> !, name: &'static str) -> u64 {
     let mut stack_allocator = STACK_ALLOCATOR.lock();
     let stack_start = stack_allocator.get_stack().expect("Failed to allocate kernel stack");
 
     let mut scheduler = TASK_SCHEDULER.lock();
     let task = ProcessControlBlock {
+        pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+        name,
+        affinity: u64::MAX,
+        policy: SchedPolicy::Normal,
+        boost: None,
+        group: ROOT_GROUP,
         task_type: TaskType::Kernel {
             stack_start: Some(stack_start),
         },
@@ -102,10 +198,19 @@ pub fn kcreate_task(task_ptr: fn() -> !, name: &str) {
         },
         state: TaskState::Ready,
         cr3: Cr3::read().0,
+        reaped: false,
     };
     scheduler.task_list.push_back(task);
+    drop(scheduler);
+    add_task_entry(TaskEntry {
+        pid: task.pid,
+        name,
+        user: false,
+        parent: current_pid(),
+    });
     info!("created task {:?}", name);
     trace!("created task {:?}", task);
+    task.pid
 }
 
 /// Reconstructs an OffsetPageTable from a CR3 value
@@ -150,6 +255,25 @@ unsafe fn deallocate_user_page_table_recursive(table_frame: PhysFrame, level: u8
     }
 }
 
+/// Counts the frames mapped in the user space portion (entries 0-255) of a page
+/// table hierarchy, including the page tables themselves
+///
+/// # Safety
+/// The page table must be valid and not change while it is walked
+unsafe fn count_user_frames(table_frame: PhysFrame, level: u8) -> usize {
+    let hhdm_offset = FRAME_ALLOCATOR.lock().as_ref().unwrap().hddm_offset;
+    let table_virt = VirtAddr::new(table_frame.start_address().as_u64() + hhdm_offset);
+    let table: &PageTable = unsafe { &*table_virt.as_ptr() };
+
+    table.iter().take(256).filter(|entry| entry.flags().contains(PageTableFlags::PRESENT)).map(|entry| {
+        if level > 1 {
+            1 + unsafe { count_user_frames(entry.frame().unwrap(), level - 1) }
+        } else {
+            1
+        }
+    }).sum()
+}
+
 /// Creates a new user page table by copying the kernel's page table
 ///
 /// Returns the physical frame of the new page table
@@ -186,9 +310,12 @@ fn create_user_page_table() -> PhysFrame {
 /// * `entry_point` - Virtual address where the user code starts
 /// * `code` - Optional program code to load at entry_point address
 /// * `name` - Name of the task for debugging
-pub fn ucreate_task(entry_point: VirtAddr, code: Option<&[u8]>, name: &str) -> Result<(), Box<dyn Error>> {
+///
+/// Returns the new task's id, or `KError::BadAddress` if the entry point
+/// isn't in user space
+pub fn ucreate_task(entry_point: VirtAddr, code: Option<&[u8]>, name: &'static str) -> Result<u64, KError> {
     if entry_point.as_u64() >= 0x0000_8000_0000_0000 {
-        return Err("Entry point must be in user address space (< 0x0000_8000_0000_0000)".into());
+        return Err(KError::BadAddress);
     }
 
     let user_cr3 = create_user_page_table();
@@ -208,7 +335,7 @@ pub fn ucreate_task(entry_point: VirtAddr, code: Option<&[u8]>, name: &str) -> R
                 let mut frame_allocator = FRAME_ALLOCATOR.lock();
                 frame_allocator.as_mut().unwrap()
                     .allocate_frame()
-                    .ok_or("Failed to allocate frame for code")?
+                    .ok_or(KError::OutOfMemory)?
             };
             
             unsafe {
@@ -217,7 +344,7 @@ pub fn ucreate_task(entry_point: VirtAddr, code: Option<&[u8]>, name: &str) -> R
                     frame,
                     PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE,
                     FRAME_ALLOCATOR.lock().as_mut().unwrap(),
-                ).map_err(|e| format!("Failed to map code page: {e:?}"))?
+                )?
                 .flush();
             }
             
@@ -246,7 +373,7 @@ pub fn ucreate_task(entry_point: VirtAddr, code: Option<&[u8]>, name: &str) -> R
         }
     };
 
-    let kernel_stack = STACK_ALLOCATOR.lock().get_stack().map_err(|e| -> Box<dyn Error> {
+    let kernel_stack = STACK_ALLOCATOR.lock().get_stack().map_err(|e| -> KError {
         unsafe {
             let mut user_page_table = get_user_page_table_from_cr3(user_cr3);
             return_user_stack(&mut user_page_table, UserInfo {
@@ -262,6 +389,12 @@ pub fn ucreate_task(entry_point: VirtAddr, code: Option<&[u8]>, name: &str) -> R
 
     let mut scheduler = TASK_SCHEDULER.lock();
     let task = ProcessControlBlock {
+        pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+        name,
+        affinity: u64::MAX,
+        policy: SchedPolicy::Normal,
+        boost: None,
+        group: ROOT_GROUP,
         task_type: TaskType::User(UserInfo {
             stack_start: stack_allocation.stack_start,
             stack_end: stack_allocation.stack_end,
@@ -293,11 +426,33 @@ pub fn ucreate_task(entry_point: VirtAddr, code: Option<&[u8]>, name: &str) -> R
         },
         state: TaskState::Ready,
         cr3: user_cr3,
+        reaped: false,
     };
     scheduler.task_list.push_back(task);
+    drop(scheduler);
+    add_task_entry(TaskEntry {
+        pid: task.pid,
+        name,
+        user: true,
+        parent: current_pid(),
+    });
+    if let Some(code_data) = code {
+        vma::insert(task.pid, Vma::new(
+            entry_point,
+            entry_point + code_data.len() as u64,
+            PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE,
+            Backing::Code,
+        ));
+    }
+    vma::insert(task.pid, Vma::new(
+        stack_allocation.stack_end,
+        stack_allocation.stack_start,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+        Backing::Stack,
+    ));
     info!("created user task {:?} at {:#x}", name, entry_point);
     trace!("created user task {:?}", task);
-    Ok(())
+    Ok(task.pid)
 }
 
 /// Get the current task's stack bounds and CR3
@@ -352,16 +507,10 @@ pub unsafe fn try_grow_user_stack(fault_addr: VirtAddr) -> Result<(), StackGrowt
 
     let mut user_page_table = unsafe { get_user_page_table_from_cr3(user_cr3) };
 
-    let frame = {
-        let mut frame_allocator = FRAME_ALLOCATOR.lock();
-        let frame_allocator = frame_allocator.as_mut().unwrap();
-        match frame_allocator.allocate_frame() {
-            Some(frame) => frame,
-            None => {
-                debug!("Failed to allocate frame for stack growth");
-                return Err(StackGrowthError::Other);
-            }
-        }
+    let allocate = || FRAME_ALLOCATOR.lock().as_mut().unwrap().allocate_frame();
+    let Some(frame) = allocate().or_else(|| memory::oom::out_of_memory("user stack growth").then(allocate).flatten()) else {
+        debug!("Failed to allocate frame for stack growth");
+        return Err(StackGrowthError::Other);
     };
 
     match unsafe {
@@ -396,6 +545,533 @@ pub unsafe fn try_grow_user_stack(fault_addr: VirtAddr) -> Result<(), StackGrowt
     }
 }
 
+/// Identity and stacks of a task, for diagnostics
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    pub pid: u64,
+    pub name: &'static str,
+    /// Kernel stack, None for the boot task running on the bootloader's stack
+    pub kernel_stack: Option<KernelStackBounds>,
+    /// User stack bounds (lowest address, top) for user tasks
+    pub user_stack: Option<(VirtAddr, VirtAddr)>,
+}
+
+/// Describe the task currently running
+///
+/// Returns None if the scheduler lock is held, since this is called from
+/// fault handlers that may have interrupted the scheduler itself.
+pub fn current_task_info() -> Option<TaskInfo> {
+    let scheduler = TASK_SCHEDULER.try_lock()?;
+    let task = scheduler
+        .task_list
+        .iter()
+        .find(|task| task.pid == current_pid())?;
+
+    let (kernel_stack, user_stack) = match task.task_type {
+        TaskType::Kernel { stack_start } => (stack_start.map(KernelSlabAlloc::stack_bounds), None),
+        TaskType::User(user_info) => (
+            Some(KernelSlabAlloc::stack_bounds(user_info.kernel_stack)),
+            Some((user_info.stack_end, user_info.stack_start)),
+        ),
+    };
+
+    Some(TaskInfo {
+        pid: task.pid,
+        name: task.name,
+        kernel_stack,
+        user_stack,
+    })
+}
+
+/// Scheduling class of a task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    /// Round-robin time sharing
+    Normal,
+    /// Real-time FIFO with a priority from 1 (lowest) to 99
+    ///
+    /// Ready real-time tasks always run before normal ones, the highest
+    /// priority first, and keep the CPU until they block or a higher
+    /// priority task becomes ready.
+    Fifo(u8),
+}
+
+/// Highest real-time priority
+pub const MAX_RT_PRIORITY: u8 = 99;
+
+/// Why a scheduling policy couldn't be applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyError {
+    /// No task has the given id
+    NoSuchTask,
+    /// Real-time priorities range from 1 to `MAX_RT_PRIORITY`
+    InvalidPriority,
+}
+
+/// Change the scheduling class of a task
+pub fn set_policy(pid: u64, policy: SchedPolicy) -> Result<(), PolicyError> {
+    if let SchedPolicy::Fifo(priority) = policy
+        && !(1..=MAX_RT_PRIORITY).contains(&priority)
+    {
+        return Err(PolicyError::InvalidPriority);
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = TASK_SCHEDULER.lock();
+        let task = scheduler.task_mut(pid).ok_or(PolicyError::NoSuchTask)?;
+        task.policy = policy;
+        Ok(())
+    })
+}
+
+/// Returns the scheduling class of a task and the priority it inherited, if any
+pub fn policy(pid: u64) -> Option<(SchedPolicy, Option<u8>)> {
+    interrupts::without_interrupts(|| {
+        TASK_SCHEDULER
+            .lock()
+            .task_list
+            .iter()
+            .find(|task| task.pid == pid)
+            .map(|task| (task.policy, task.boost))
+    })
+}
+
+/// Lend the current task's real-time priority to `owner`, which holds a lock
+/// the current task is about to wait for
+///
+/// Does nothing if the current task isn't real-time or `owner` already runs
+/// at a higher priority.
+pub fn inherit_priority(owner: u64) {
+    lend_priority(current_pid(), owner);
+}
+
+/// Lend the real-time priority of task `from` to task `to`, see
+/// `inherit_priority`
+pub(crate) fn lend_priority(from: u64, to: u64) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = TASK_SCHEDULER.lock();
+        let Some(priority) = scheduler.task_mut(from).and_then(|task| task.priority()) else {
+            return;
+        };
+        if let Some(owner) = scheduler.task_mut(to)
+            && owner.priority() < Some(priority)
+        {
+            trace!("task {} inherits priority {} from task {}", owner.pid, priority, from);
+            owner.boost = Some(priority);
+        }
+    });
+}
+
+/// Drop any priority the current task inherited
+pub fn restore_priority() {
+    interrupts::without_interrupts(|| {
+        if let Some(task) = TASK_SCHEDULER.lock().task_mut(current_pid()) {
+            task.boost = None;
+        }
+    });
+}
+
+/// Sleep until the lock identified by `key` is released
+///
+/// Must be called with interrupts disabled, right after finding the lock
+/// taken, so the release can't slip in before the task is marked waiting.
+/// Returns with interrupts enabled.
+pub fn wait_for_lock(key: usize) {
+    {
+        let mut scheduler = TASK_SCHEDULER.lock();
+        let current_task = scheduler.task_mut(current_pid()).unwrap();
+        current_task.set_state(TaskState::Waiting(WaitReason::Lock(key)));
+    }
+    interrupts::enable();
+
+    unsafe {
+        core::arch::asm!("int {}", const LAPIC_TIMER_VECTOR);
+    }
+}
+
+/// Wake every task waiting for the lock identified by `key`
+pub fn wake_lock_waiters(key: usize) {
+    interrupts::without_interrupts(|| TASK_SCHEDULER.lock().wake(WaitReason::Lock(key)));
+}
+
+/// Sleep until `wake_event_waiters` is called with `key`
+///
+/// Must be called with interrupts disabled, right after finding there is
+/// nothing to do, so the wakeup can't slip in before the task is marked
+/// waiting. Returns with interrupts enabled.
+pub fn wait_for_event(key: usize) {
+    {
+        let mut scheduler = TASK_SCHEDULER.lock();
+        let current_task = scheduler.task_mut(current_pid()).unwrap();
+        current_task.set_state(TaskState::Waiting(WaitReason::Event(key)));
+    }
+    interrupts::enable();
+
+    unsafe {
+        core::arch::asm!("int {}", const LAPIC_TIMER_VECTOR);
+    }
+}
+
+/// Wake every task waiting for the event identified by `key`
+pub fn wake_event_waiters(key: usize) {
+    interrupts::without_interrupts(|| TASK_SCHEDULER.lock().wake(WaitReason::Event(key)));
+}
+
+/// Calls `f` with the page tables of every user task that isn't running
+///
+/// The tasks can't be scheduled until `f` returns, and their page tables are
+/// reloaded when they are, so `f` may change their mappings without flushing
+/// any TLB. Taken before `FRAME_ALLOCATOR` if `f` needs it.
+pub fn with_idle_user_tasks<R>(f: impl FnOnce(&[PhysFrame]) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let scheduler = TASK_SCHEDULER.lock();
+        let page_tables: Vec<PhysFrame> = scheduler
+            .task_list
+            .iter()
+            .filter(|task| matches!(task.task_type, TaskType::User(_)))
+            .filter(|task| task.state != TaskState::Running && !task.state.exited())
+            .map(|task| task.cr3)
+            .collect();
+        f(&page_tables)
+    })
+}
+
+/// Calls `f` with the page table of user task `pid`, None if it isn't a live
+/// user task
+///
+/// The task's mappings may change while `f` runs if it is running, but its
+/// page tables aren't freed until `f` returns. Taken before
+/// `FRAME_ALLOCATOR` if `f` needs it.
+pub fn with_user_page_table<R>(pid: u64, f: impl FnOnce(PhysFrame) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let scheduler = TASK_SCHEDULER.lock();
+        let task = scheduler.task_list.iter().find(|task| task.pid == pid)?;
+        if !matches!(task.task_type, TaskType::User(_)) || task.state.exited() {
+            return None;
+        }
+        Some(f(task.cr3))
+    })
+}
+
+/// Sleep for at least `ticks` timer ticks
+pub fn sleep_ticks(ticks: u64) {
+    let deadline = time::ticks() + ticks;
+    while time::ticks() < deadline {
+        interrupts::disable();
+        {
+            let mut scheduler = TASK_SCHEDULER.lock();
+            let current_task = scheduler.task_mut(current_pid()).unwrap();
+            current_task.set_state(TaskState::Waiting(WaitReason::Sleep(deadline)));
+        }
+        interrupts::enable();
+
+        unsafe {
+            core::arch::asm!("int {}", const LAPIC_TIMER_VECTOR);
+        }
+    }
+}
+
+/// Why an affinity mask couldn't be applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffinityError {
+    /// No task has the given id
+    NoSuchTask,
+    /// The mask doesn't contain any online CPU
+    NoOnlineCpu,
+}
+
+/// Restrict the CPUs a task may run on
+///
+/// `mask` has one bit per CPU; bits of CPUs that aren't online are kept so
+/// the task can use them once they come up.
+pub fn set_affinity(pid: u64, mask: u64) -> Result<(), AffinityError> {
+    if mask & cpu::online_mask() == 0 {
+        return Err(AffinityError::NoOnlineCpu);
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = TASK_SCHEDULER.lock();
+        let task = scheduler
+            .task_list
+            .iter_mut()
+            .find(|task| task.pid == pid)
+            .ok_or(AffinityError::NoSuchTask)?;
+        task.affinity = mask;
+        Ok(())
+    })
+}
+
+/// Returns the affinity mask of a task
+pub fn affinity(pid: u64) -> Option<u64> {
+    interrupts::without_interrupts(|| {
+        TASK_SCHEDULER
+            .lock()
+            .task_list
+            .iter()
+            .find(|task| task.pid == pid)
+            .map(|task| task.affinity)
+    })
+}
+
+/// Move a task to another group
+pub fn set_group(pid: u64, group: u32) -> Result<(), GroupError> {
+    if !group::exists(group) {
+        return Err(GroupError::NoSuchGroup);
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = TASK_SCHEDULER.lock();
+        let task = scheduler.task_mut(pid).ok_or(GroupError::NoSuchTask)?;
+        task.group = group;
+        Ok(())
+    })
+}
+
+/// Returns the group of a task
+pub fn group(pid: u64) -> Option<u32> {
+    interrupts::without_interrupts(|| {
+        TASK_SCHEDULER
+            .lock()
+            .task_list
+            .iter()
+            .find(|task| task.pid == pid)
+            .map(|task| task.group)
+    })
+}
+
+/// Number of tasks in a group
+pub fn group_task_count(group: u32) -> usize {
+    interrupts::without_interrupts(|| {
+        TASK_SCHEDULER
+            .lock()
+            .task_list
+            .iter()
+            .filter(|task| task.group == group)
+            .count()
+    })
+}
+
+/// Memory held by a user task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserTaskMemory {
+    pub pid: u64,
+    pub name: &'static str,
+    /// Effective real-time priority, None for normal tasks
+    pub priority: Option<u8>,
+    /// Frames mapped in its half of the address space, page tables included
+    pub frames: usize,
+}
+
+impl ProcessControlBlock {
+    /// A user task that was preempted in user mode holds no kernel locks and
+    /// has nothing in flight on its kernel stack, so it can be removed as is
+    fn is_killable(&self) -> bool {
+        matches!(self.task_type, TaskType::User(_))
+            && matches!(self.state, TaskState::Ready | TaskState::Waiting(_) | TaskState::Stopped)
+            && self.regs.interrupt_cs & 3 == 3
+    }
+}
+
+/// Memory held by every user task `kill_task` can remove right now
+pub fn killable_user_tasks() -> Vec<UserTaskMemory> {
+    interrupts::without_interrupts(|| {
+        TASK_SCHEDULER
+            .lock()
+            .task_list
+            .iter()
+            .filter(|task| task.is_killable())
+            .map(|task| UserTaskMemory {
+                pid: task.pid,
+                name: task.name,
+                priority: task.priority(),
+                frames: 1 + unsafe { count_user_frames(task.cr3, 4) },
+            })
+            .collect()
+    })
+}
+
+/// Memory held by every user task
+pub fn user_task_memory() -> Vec<UserTaskMemory> {
+    interrupts::without_interrupts(|| {
+        TASK_SCHEDULER
+            .lock()
+            .task_list
+            .iter()
+            .filter(|task| matches!(task.task_type, TaskType::User(_)) && !task.state.exited())
+            .map(|task| UserTaskMemory {
+                pid: task.pid,
+                name: task.name,
+                priority: task.priority(),
+                frames: 1 + unsafe { count_user_frames(task.cr3, 4) },
+            })
+            .collect()
+    })
+}
+
+/// Why a task couldn't be killed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KillError {
+    /// No task has the given id
+    NoSuchTask,
+    /// Kernel tasks are never killed
+    KernelTask,
+    /// The task is running or was interrupted in the kernel
+    Busy,
+}
+
+/// Find user task `pid` for job control, checking it could be killed
+fn user_task_mut(scheduler: &mut TaskScheduler, pid: u64) -> Result<&mut ProcessControlBlock, KillError> {
+    let task = scheduler.task_mut(pid).ok_or(KillError::NoSuchTask)?;
+    if !matches!(task.task_type, TaskType::User(_)) {
+        return Err(KillError::KernelTask);
+    }
+    if !task.is_killable() {
+        return Err(KillError::Busy);
+    }
+    Ok(task)
+}
+
+/// End a user task and free its memory without letting it run again
+///
+/// Only tasks preempted in user mode can be killed, see
+/// `killable_user_tasks`. Taken before `FRAME_ALLOCATOR`.
+pub fn kill_task(pid: u64) -> Result<(), KillError> {
+    let entry = remove_task_entry(pid);
+    interrupts::disable();
+    let killed = user_task_mut(&mut TASK_SCHEDULER.lock(), pid).map(|task| task.set_state(TaskState::Zombie));
+    if let Err(e) = killed {
+        interrupts::enable();
+        if let Some(entry) = entry {
+            add_task_entry(entry);
+        }
+        return Err(e);
+    }
+    bury(pid, entry, children::KILLED_CODE, true);
+    free_exited(|task| task.pid == pid);
+    interrupts::enable();
+
+    children::release(pid);
+    Ok(())
+}
+
+/// Tell the parent of zombie `pid` that it ended, or terminate it right away
+/// if nobody will wait for it
+///
+/// Called with interrupts disabled, so the parent can't wait for it or exit
+/// in between.
+fn bury(pid: u64, entry: Option<TaskEntry>, code: i32, killed: bool) {
+    if !entry.is_some_and(|entry| children::notify(&entry, code, killed)) {
+        reap(pid);
+    }
+}
+
+/// Stop a user task until `continue_task` is called with it
+///
+/// Only tasks `kill_task` could kill can be stopped.
+pub fn stop_task(pid: u64) -> Result<(), KillError> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = TASK_SCHEDULER.lock();
+        let task = user_task_mut(&mut scheduler, pid)?;
+        if task.state != TaskState::Stopped {
+            task.set_state(TaskState::Stopped);
+        }
+        Ok(())
+    })
+}
+
+/// Let a task stopped by `stop_task` run again
+///
+/// A task that was waiting is woken, it finds out whether what it waited for
+/// happened.
+pub fn continue_task(pid: u64) -> Result<(), KillError> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = TASK_SCHEDULER.lock();
+        let task = user_task_mut(&mut scheduler, pid)?;
+        if task.state == TaskState::Stopped {
+            task.set_state(TaskState::Ready);
+        }
+        Ok(())
+    })
+}
+
+/// Whether task `pid` is stopped by job control
+pub fn is_stopped(pid: u64) -> bool {
+    interrupts::without_interrupts(|| {
+        TASK_SCHEDULER
+            .lock()
+            .task_mut(pid)
+            .is_some_and(|task| task.state == TaskState::Stopped)
+    })
+}
+
+/// Key the reaper task waits on
+fn reaper_key() -> usize {
+    reaper as fn() -> ! as usize
+}
+
+/// Start the reaper task, which frees the memory of tasks that exited
+///
+/// Until it is started, exited tasks stay in the task list with their memory.
+pub fn spawn_reaper() {
+    interrupts::without_interrupts(|| kcreate_task(reaper, "reaper"));
+}
+
+fn reaper() -> ! {
+    loop {
+        interrupts::disable();
+        if free_exited(|_| true) {
+            interrupts::enable();
+        } else {
+            wait_for_event(reaper_key());
+        }
+    }
+}
+
+/// Free the memory of the first exited task matching `filter` that still
+/// holds it, returning whether there was one
+///
+/// A zombie stays in the task list for its parent to take its exit code.
+/// Must be called with interrupts disabled, so the parent can't drop it in
+/// between.
+fn free_exited(filter: impl Fn(&ProcessControlBlock) -> bool) -> bool {
+    let exited = {
+        let mut scheduler = TASK_SCHEDULER.lock();
+        let index = scheduler
+            .task_list
+            .iter()
+            .position(|task| task.state.exited() && !task.reaped && filter(task));
+        index.map(|index| {
+            if scheduler.task_list[index].state == TaskState::Terminated {
+                scheduler.task_list.remove(index).unwrap()
+            } else {
+                scheduler.task_list[index].reaped = true;
+                scheduler.task_list[index]
+            }
+        })
+    };
+    // the task is off its stacks and page tables, and running no more
+    exited.inspect(|task| unsafe { free_task_memory(task) }).is_some()
+}
+
+/// Terminate zombie `pid` once its parent took its exit code, dropping it
+/// from the task list if the reaper already freed its memory
+pub fn reap(pid: u64) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = TASK_SCHEDULER.lock();
+        let Some(index) = scheduler.task_list.iter().position(|task| task.pid == pid) else {
+            return;
+        };
+        let task = &mut scheduler.task_list[index];
+        if task.state != TaskState::Zombie {
+            return;
+        }
+        task.set_state(TaskState::Terminated);
+        if task.reaped {
+            scheduler.task_list.remove(index);
+        }
+    });
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StackGrowthError {
     StackOverflow,
@@ -410,7 +1086,7 @@ pub fn kyield_task(interrupt: u8) {
     {
         let mut scheduler = TASK_SCHEDULER.lock();
         let current_task = scheduler.task_list.front_mut().unwrap();
-        current_task.state = TaskState::Waiting(WaitReason::Interrupt(interrupt));
+        current_task.set_state(TaskState::Waiting(WaitReason::Interrupt(interrupt)));
     }
     interrupts::enable();
 
@@ -423,12 +1099,7 @@ pub fn kyield_task(interrupt: u8) {
 /// 
 /// O(n) but doesnt matter in this stage
 pub fn wake_tasks(interrupt: u8) {
-    let mut scheduler = TASK_SCHEDULER.lock();
-    scheduler
-        .task_list
-        .iter_mut()
-        .filter(|x| x.state == TaskState::Waiting(WaitReason::Interrupt(interrupt)))
-        .for_each(|x| x.state = TaskState::Ready);
+    TASK_SCHEDULER.lock().wake(WaitReason::Interrupt(interrupt));
 }
 
 /// Terminates the current task, handing control to the scheduler
@@ -436,12 +1107,23 @@ pub fn wake_tasks(interrupt: u8) {
 /// should be called at the end of every running task when it wants to terminate
 #[inline]
 pub fn exit_task() -> ! {
+    exit_task_with_code(0)
+}
+
+/// Terminates the current task with an exit code for its parent, see
+/// `children`
+pub fn exit_task_with_code(code: i32) -> ! {
+    let pid = current_pid();
+    let entry = remove_task_entry(pid);
+    children::release(pid);
+
     interrupts::disable();
     {
         let mut scheduler = TASK_SCHEDULER.lock();
         let current_task = scheduler.task_list.front_mut().unwrap();
-        current_task.state = TaskState::Terminated;
+        current_task.set_state(TaskState::Zombie);
     }
+    bury(pid, entry, code, false);
     interrupts::enable();
 
     unsafe {
@@ -461,35 +1143,150 @@ impl TaskScheduler {
             task_list: VecDeque::new(),
         }
     }
+
+    /// Index of the real-time task to run next on `cpu`, if any is ready
+    ///
+    /// The highest priority wins. Among equal priorities the task that was
+    /// just running keeps the CPU (FIFO), otherwise the one queued first runs.
+    /// Tasks of throttled groups are skipped.
+    fn pick_realtime(&self, cpu: usize, previous: u64, throttled: &group::Throttled) -> Option<usize> {
+        self.task_list
+            .iter()
+            .enumerate()
+            .filter(|(_, task)| {
+                task.allowed_on(cpu)
+                    && !task.is_waiting()
+                    && !task.state.parked()
+                    && !throttled.contains(task.group)
+            })
+            .filter_map(|(index, task)| task.priority().map(|priority| (index, task.pid, priority)))
+            .max_by_key(|&(index, pid, priority)| (priority, pid == previous, Reverse(index)))
+            .map(|(index, _, _)| index)
+    }
+
+    fn task_mut(&mut self, pid: u64) -> Option<&mut ProcessControlBlock> {
+        self.task_list.iter_mut().find(|task| task.pid == pid)
+    }
+
+    /// Make every task waiting for `reason` ready
+    fn wake(&mut self, reason: WaitReason) {
+        self.task_list
+            .iter_mut()
+            .filter(|task| task.state == TaskState::Waiting(reason))
+            .for_each(|task| task.set_state(TaskState::Ready));
+    }
+}
+
+impl ProcessControlBlock {
+    /// Effective real-time priority, including inherited priority
+    fn priority(&self) -> Option<u8> {
+        let base = match self.policy {
+            SchedPolicy::Fifo(priority) => Some(priority),
+            SchedPolicy::Normal => None,
+        };
+        base.max(self.boost)
+    }
+
+    fn allowed_on(&self, cpu: usize) -> bool {
+        self.affinity & (1 << cpu) != 0
+    }
+
+    fn is_waiting(&self) -> bool {
+        matches!(self.state, TaskState::Waiting(_))
+    }
+
+    fn set_state(&mut self, next: TaskState) {
+        debug_assert!(
+            self.state.can_become(next),
+            "task {} can't go from {:?} to {:?}",
+            self.pid,
+            self.state,
+            next
+        );
+        self.state = next;
+    }
 }
 
 /// Stores information about a running process
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(C)]
 struct ProcessControlBlock {
+    pub pid: u64,
+    pub name: &'static str,
+    /// CPUs the task may run on, one bit per CPU
+    pub affinity: u64,
+    pub policy: SchedPolicy,
+    /// Real-time priority inherited from a task waiting on a lock it holds
+    pub boost: Option<u8>,
+    /// Task group charged for the CPU time the task uses
+    pub group: u32,
     pub task_type: TaskType,
     pub regs: TaskRegisters,
     pub state: TaskState,
     /// page table for process
     pub cr3: PhysFrame,
+    /// Whether the reaper freed the memory of the exited task, which stays
+    /// in the task list as long as it is a zombie
+    pub reaped: bool,
 }
 
 /// State of a task
 /// - Ready: Task is ready to run
 /// - Running: Task is currently running
+/// - Waiting: Task sleeps until it is woken, see `WaitReason`
+/// - Stopped: Task was stopped by job control and runs again once continued
+/// - Zombie: Task exited, its exit code is kept until its parent waits for it
 /// - Terminated: Task has finished running
+///
+/// Zombie and Terminated tasks stay in the task list until the reaper task
+/// frees their memory, and a zombie until its parent took its exit code.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum TaskState {
     Ready,
     Running,
-    Terminated,
     Waiting(WaitReason),
+    Stopped,
+    Zombie,
+    Terminated,
+}
+
+impl TaskState {
+    /// Whether a task in this state may move to `next`
+    fn can_become(self, next: TaskState) -> bool {
+        use TaskState::*;
+        matches!(
+            (self, next),
+            (Ready | Waiting(_), Running)
+                | (Running, Ready | Waiting(_))
+                | (Ready | Running | Waiting(_), Stopped)
+                | (Ready | Running | Waiting(_) | Stopped, Zombie | Terminated)
+                | (Waiting(_) | Stopped, Ready)
+                | (Zombie, Terminated)
+        )
+    }
+
+    /// Whether the task exited and never runs again
+    fn exited(self) -> bool {
+        matches!(self, TaskState::Zombie | TaskState::Terminated)
+    }
+
+    /// Whether the task can't be scheduled until something else changes its
+    /// state
+    fn parked(self) -> bool {
+        matches!(self, TaskState::Stopped) || self.exited()
+    }
 }
 
 /// Why are we waiting
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum WaitReason {
     Interrupt(u8),
+    /// Waiting for the sleeping lock at this address to be released
+    Lock(usize),
+    /// Waiting for `wake_event_waiters` with this key
+    Event(usize),
+    /// Sleeping until this tick
+    Sleep(u64),
 }
 
 /// Information about a user task's stack
@@ -589,42 +1386,60 @@ pub unsafe extern "x86-interrupt" fn schedule() {
     );
 }
 
+/// Returns the stacks and, for user tasks, the address space of a task that
+/// ended
+///
+/// # Safety
+/// The task must not run again and its stacks and page tables must not be in use
+unsafe fn free_task_memory(task: &ProcessControlBlock) {
+    match task.task_type {
+        TaskType::Kernel { stack_start: Some(stack_start) } => {
+            STACK_ALLOCATOR.lock().return_stack(stack_start);
+        }
+        TaskType::User(user_info) => {
+            STACK_ALLOCATOR.lock().return_stack(user_info.kernel_stack);
+
+            debug!("User task terminated, deallocating all user memory");
+
+            unsafe {
+                deallocate_user_page_table_recursive(task.cr3, 4);
+            }
+            debug!("User task page tables and all mapped frames deallocated");
+
+            unsafe {
+                use x86_64::structures::paging::FrameDeallocator;
+                FRAME_ALLOCATOR.lock().as_mut().unwrap().deallocate_frame(task.cr3);
+            }
+            debug!("User task CR3 frame deallocated at {:#x}", task.cr3.start_address());
+        }
+        _ => {}
+    }
+}
+
 /// inner function to switch tasks
 unsafe extern "C" fn schedule_inner(current_task_context: *mut TaskRegisters) {
+    sync::irq_enter();
     let mut scheduler = TASK_SCHEDULER.lock();
 
     // save current task context first
     let mut current_task = scheduler.task_list.pop_front().unwrap();
 
-    if current_task.state == TaskState::Terminated {
-        trace!("task ended at {:#X}", current_task.regs.interrupt_rsp);
-        match current_task.task_type {
-            TaskType::Kernel { stack_start: Some(stack_start) } => {
-                STACK_ALLOCATOR.lock().return_stack(stack_start);
-            }
-            TaskType::User(user_info) => {
-                STACK_ALLOCATOR.lock().return_stack(user_info.kernel_stack);
-
-                debug!("User task terminated, deallocating all user memory");
-
-                unsafe {
-                    deallocate_user_page_table_recursive(current_task.cr3, 4);
-                }
-                debug!("User task page tables and all mapped frames deallocated");
+    let now = unsafe { _rdtsc() };
+    group::charge(current_task.group, now.saturating_sub(LAST_SWITCH.swap(now, Ordering::Relaxed)));
 
-                unsafe {
-                    use x86_64::structures::paging::FrameDeallocator;
-                    FRAME_ALLOCATOR.lock().as_mut().unwrap().deallocate_frame(current_task.cr3);
-                }
-                debug!("User task CR3 frame deallocated at {:#x}", current_task.cr3.start_address());
-            }
-            _ => {}
-        }
-    } else if let TaskState::Waiting(WaitReason::Interrupt(_interrupt)) = current_task.state {
+    if current_task.state.exited() {
+        // freeing its memory takes locks an interrupt handler can't wait
+        // for, the reaper does it once the task is off its stack
+        trace!("task ended at {:#X}", current_task.regs.interrupt_rsp);
+        scheduler.task_list.push_back(current_task);
+        scheduler.wake(WaitReason::Event(reaper_key()));
+    } else if let TaskState::Waiting(_) = current_task.state {
         current_task.regs = unsafe { *current_task_context };
         scheduler.task_list.push_back(current_task);
     } else {
-        current_task.state = TaskState::Ready;
+        if current_task.state == TaskState::Running {
+            current_task.set_state(TaskState::Ready);
+        }
         current_task.regs = unsafe { *current_task_context };
         trace!("task registers: {:?}", current_task.regs);
         scheduler.task_list.push_back(current_task);
@@ -636,7 +1451,35 @@ unsafe extern "C" fn schedule_inner(current_task_context: *mut TaskRegisters) {
         );
     }
 
-    // run front task
+    let now = time::ticks();
+    scheduler
+        .task_list
+        .iter_mut()
+        .filter(|task| matches!(task.state, TaskState::Waiting(WaitReason::Sleep(deadline)) if deadline <= now))
+        .for_each(|task| task.set_state(TaskState::Ready));
+
+    let cpu = cpu::current_cpu();
+    let throttled = group::throttled();
+    let runnable = |task: &ProcessControlBlock| {
+        task.allowed_on(cpu)
+            && !task.state.parked()
+            && !(task.priority().is_some() && task.is_waiting())
+    };
+    if let Some(index) = scheduler.pick_realtime(cpu, current_task.pid, &throttled) {
+        // real-time tasks jump the queue without disturbing round-robin order
+        let task = scheduler.task_list.remove(index).unwrap();
+        scheduler.task_list.push_front(task);
+    } else if let Some(index) = scheduler
+        .task_list
+        .iter()
+        .position(|task| runnable(task) && !throttled.contains(task.group))
+        .or_else(|| scheduler.task_list.iter().position(runnable))
+    {
+        // run the first normal task allowed on this CPU, ignoring quotas only
+        // if every such task is throttled
+        scheduler.task_list.rotate_left(index);
+    }
+    drop(throttled);
     let next_task = scheduler.task_list.front_mut().unwrap();
 
     #[cfg(test)]
@@ -649,12 +1492,17 @@ unsafe extern "C" fn schedule_inner(current_task_context: *mut TaskRegisters) {
 
     trace!("task for next: {:?}", next_task);
     trace!("next task at {:#X}", next_task.regs.interrupt_rsp);
-    next_task.state = TaskState::Running;
+    next_task.set_state(TaskState::Running);
+    if next_task.pid != current_task.pid {
+        stats::count(Counter::ContextSwitches);
+    }
+    CURRENT_PID.store(next_task.pid, Ordering::Relaxed);
+    rcu::quiescent_state();
 
     if let TaskType::User(user_info) = next_task.task_type {
         unsafe {
             set_kernel_stack(user_info.kernel_stack);
-            set_syscall_stack(user_info.kernel_stack);
+            cpu::set_syscall_stack(user_info.kernel_stack);
         }
     }
 
@@ -667,4 +1515,5 @@ unsafe extern "C" fn schedule_inner(current_task_context: *mut TaskRegisters) {
     }
 
     unsafe { *current_task_context = next_task.regs };
+    sync::irq_exit();
 }
@@ -1,6 +1,8 @@
+use x86_64::instructions::interrupts;
+
 use crate::{
     println,
-    tasks::scheduler::exit_task,
+    tasks::scheduler::{SchedPolicy, exit_task, kcreate_task, lend_priority, policy, set_policy},
 };
 
 #[test_case]
@@ -26,3 +28,27 @@ fn do_something_else() -> ! {
 
     exit_task();
 }
+
+#[test_case]
+fn test_lock_holder_inherits_waiter_priority() {
+    let (holder, waiter, low) = interrupts::without_interrupts(|| {
+        (
+            kcreate_task(do_something, "lock holder"),
+            kcreate_task(do_something, "lock waiter"),
+            kcreate_task(do_something, "low priority waiter"),
+        )
+    });
+    set_policy(waiter, SchedPolicy::Fifo(40)).unwrap();
+    set_policy(low, SchedPolicy::Fifo(10)).unwrap();
+
+    // a normal task has nothing to lend
+    lend_priority(holder, low);
+    assert_eq!(policy(low), Some((SchedPolicy::Fifo(10), None)));
+
+    lend_priority(waiter, holder);
+    assert_eq!(policy(holder), Some((SchedPolicy::Normal, Some(40))));
+
+    // a lower priority waiter doesn't take the boost away
+    lend_priority(low, holder);
+    assert_eq!(policy(holder), Some((SchedPolicy::Normal, Some(40))));
+}
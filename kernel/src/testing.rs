@@ -1,3 +1,5 @@
+pub mod snapshot;
+
 use crate::{serial_print, serial_println};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
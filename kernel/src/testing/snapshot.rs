@@ -0,0 +1,209 @@
+//! Kernel state snapshots for leak checks
+//!
+//! A test takes a `Snapshot` before exercising some code and checks that the
+//! state it records is back to the same values afterwards, turning leaked
+//! frames, heap allocations, DMA buffers, open files, rings or tasks into
+//! failures instead of memory that quietly goes missing.
+//!
+//! Other tasks keep running while a test does, so tests run after
+//! multitasking started should only check the resources they own, like DMA
+//! buffers for a driver.
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt;
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    fs,
+    memory::{FRAME_ALLOCATOR, alloc::ALLOCATOR},
+    syscall::uring,
+    tasks::scheduler::{TASKS, TaskEntry},
+};
+
+/// Kind of state recorded in a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// Free frames
+    Frames,
+    /// Bytes allocated from the kernel heap
+    Heap,
+    /// Frames held by DMA buffers and pooled buffers handed out
+    Dma,
+    /// Files open across all tasks
+    Files,
+    /// Tasks with a ring set up
+    Rings,
+    /// Live tasks
+    Tasks,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 6] = [
+        Resource::Frames,
+        Resource::Heap,
+        Resource::Dma,
+        Resource::Files,
+        Resource::Rings,
+        Resource::Tasks,
+    ];
+}
+
+/// Something that didn't return to the value in the snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Difference {
+    Count {
+        resource: Resource,
+        before: usize,
+        after: usize,
+    },
+    TaskStarted(TaskEntry),
+    TaskEnded(TaskEntry),
+}
+
+impl Difference {
+    pub fn resource(&self) -> Resource {
+        match self {
+            Difference::Count { resource, .. } => *resource,
+            Difference::TaskStarted(_) | Difference::TaskEnded(_) => Resource::Tasks,
+        }
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Count {
+                resource,
+                before,
+                after,
+            } => write!(f, "{resource:?}: {before} before, {after} after"),
+            Difference::TaskStarted(task) => {
+                write!(f, "task {} ({}) still running", task.pid, task.name)
+            }
+            Difference::TaskEnded(task) => write!(f, "task {} ({}) ended", task.pid, task.name),
+        }
+    }
+}
+
+/// Kernel state at one point in time
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    free_frames: usize,
+    heap: usize,
+    /// DMA frames plus pooled buffers handed out
+    dma: usize,
+    files: usize,
+    rings: usize,
+    tasks: Vec<TaskEntry>,
+}
+
+impl Snapshot {
+    pub fn take() -> Self {
+        let dma = dma_in_use();
+        // read before the task list is copied, so the copy isn't counted
+        let heap = ALLOCATOR.live();
+        let free_frames = interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR
+                .lock()
+                .as_ref()
+                .map_or(0, |allocator| allocator.free_frames())
+        });
+        let files = fs::open_file_count();
+        let rings = uring::ring_count();
+        let tasks = TASKS.read().map_or_else(Vec::new, |tasks| tasks.to_vec());
+
+        Self {
+            free_frames,
+            heap,
+            dma,
+            files,
+            rings,
+            tasks,
+        }
+    }
+
+    /// Heap bytes held by this snapshot, which were allocated after it
+    /// recorded the heap
+    fn own_heap(&self) -> usize {
+        self.tasks.capacity() * size_of::<TaskEntry>()
+    }
+
+    /// What changed between this snapshot and `after`
+    pub fn diff(&self, after: &Snapshot) -> Vec<Difference> {
+        let counts = [
+            (Resource::Frames, self.free_frames, after.free_frames),
+            (
+                Resource::Heap,
+                self.heap,
+                after.heap.saturating_sub(self.own_heap()),
+            ),
+            (Resource::Dma, self.dma, after.dma),
+            (Resource::Files, self.files, after.files),
+            (Resource::Rings, self.rings, after.rings),
+        ];
+        let mut differences: Vec<Difference> = counts
+            .into_iter()
+            .filter(|(_, before, after)| before != after)
+            .map(|(resource, before, after)| Difference::Count {
+                resource,
+                before,
+                after,
+            })
+            .collect();
+
+        differences.extend(
+            after
+                .tasks
+                .iter()
+                .filter(|task| !self.tasks.contains(task))
+                .map(|&task| Difference::TaskStarted(task)),
+        );
+        differences.extend(
+            self.tasks
+                .iter()
+                .filter(|task| !after.tasks.contains(task))
+                .map(|&task| Difference::TaskEnded(task)),
+        );
+        differences
+    }
+
+    /// Panic if any of `resources` changed since the snapshot was taken
+    pub fn assert_unchanged(&self, resources: &[Resource]) {
+        let after = Snapshot::take();
+        let differences: Vec<String> = self
+            .diff(&after)
+            .iter()
+            .filter(|difference| resources.contains(&difference.resource()))
+            .map(|difference| format!("\n  {difference}"))
+            .collect();
+        assert!(
+            differences.is_empty(),
+            "kernel state changed:{}",
+            differences.concat()
+        );
+    }
+}
+
+/// Run `f` and panic if it leaves any kernel state behind
+pub fn check_leaks<R>(f: impl FnOnce() -> R) -> R {
+    let before = Snapshot::take();
+    let result = f();
+    before.assert_unchanged(&Resource::ALL);
+    result
+}
+
+#[cfg(any(feature = "usb", feature = "nvme"))]
+fn dma_in_use() -> usize {
+    use crate::pci::dma::{DMA_MANAGER, dma_frames};
+
+    // taking the lock sets up the pools on first use, before the frames
+    // are counted
+    let pooled = DMA_MANAGER.lock().buffers_4kb_in_use();
+    dma_frames() + pooled
+}
+
+#[cfg(not(any(feature = "usb", feature = "nvme")))]
+fn dma_in_use() -> usize {
+    0
+}
@@ -0,0 +1,119 @@
+//! Kernel time keeping.
+//!
+//! The PIT interrupt fires `timer_hz()` times a second and advances a global
+//! tick counter. The frequency can be changed at runtime through the
+//! `timer.hz` sysctl, so each tick also adds its actual length to the
+//! uptime, and conversions between ticks and time use the current frequency.
+//! Timeouts already counted in ticks when the frequency changes end early or
+//! late by the same factor.
+//!
+//! Wall clock time is the RTC time read at boot plus the uptime since.
+
+pub mod rtc;
+
+#[cfg(test)]
+pub mod tests;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::{info, interrupts::apic, sysctl::Sysctl};
+
+/// Frequency of the PIT interrupt at boot
+pub const DEFAULT_TIMER_HZ: u64 = 20;
+
+/// Frequency the PIT counts down at
+pub const PIT_HZ: u64 = 1_193_182;
+
+/// Lowest timer frequency, the PIT's reload value being 16 bits
+pub const MIN_TIMER_HZ: u64 = PIT_HZ.div_ceil(u16::MAX as u64);
+
+pub const MAX_TIMER_HZ: u64 = 1000;
+
+static TIMER_HZ: AtomicU64 = AtomicU64::new(DEFAULT_TIMER_HZ);
+
+/// Actual length of a tick, the PIT dividing its clock by a whole number
+static TICK_NS: AtomicU64 = AtomicU64::new(tick_ns(DEFAULT_TIMER_HZ));
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+static UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+pub static SYSCTLS: &[Sysctl] = &[Sysctl {
+    name: "timer.hz",
+    help: "timer interrupts per second, the resolution of timeouts and sleeps",
+    get: timer_hz,
+    set: |value| {
+        if !(MIN_TIMER_HZ..=MAX_TIMER_HZ).contains(&value) {
+            return Err("must be 19-1000 Hz");
+        }
+        set_timer_hz(value);
+        Ok(())
+    },
+}];
+
+/// Unix time in milliseconds when the tick counter was at zero
+static BOOT_TIME_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Read the wall clock time from the RTC
+pub fn init() {
+    let now = rtc::read();
+    BOOT_TIME_MS.store(
+        (now.to_unix() * 1000).saturating_sub(uptime_ms()),
+        Ordering::Relaxed,
+    );
+    info!("RTC time is {} UTC", now);
+}
+
+/// PIT reload value for a frequency of `hz`
+pub const fn pit_reload(hz: u64) -> u16 {
+    (PIT_HZ / hz) as u16
+}
+
+const fn tick_ns(hz: u64) -> u64 {
+    pit_reload(hz) as u64 * 1_000_000_000 / PIT_HZ
+}
+
+/// Current frequency of the timer interrupt
+pub fn timer_hz() -> u64 {
+    TIMER_HZ.load(Ordering::Relaxed)
+}
+
+/// Change the frequency of the timer interrupt, reprogramming the PIT
+///
+/// `hz` must be between `MIN_TIMER_HZ` and `MAX_TIMER_HZ`.
+pub fn set_timer_hz(hz: u64) {
+    interrupts::without_interrupts(|| {
+        TIMER_HZ.store(hz, Ordering::Relaxed);
+        TICK_NS.store(tick_ns(hz), Ordering::Relaxed);
+        apic::set_pit_reload(pit_reload(hz));
+    });
+    info!("Timer frequency set to {} Hz", hz);
+}
+
+/// Advance the tick counter, called from the PIT interrupt handler
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    UPTIME_NS.fetch_add(TICK_NS.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Ticks since the timer was started
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the timer was started
+pub fn uptime_ms() -> u64 {
+    UPTIME_NS.load(Ordering::Relaxed) / 1_000_000
+}
+
+/// Convert milliseconds to ticks, rounding up to at least one tick
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * timer_hz()).div_ceil(1000).max(1)
+}
+
+/// Milliseconds since the Unix epoch
+pub fn unix_time_ms() -> u64 {
+    BOOT_TIME_MS.load(Ordering::Relaxed) + uptime_ms()
+}
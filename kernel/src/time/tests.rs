@@ -0,0 +1,50 @@
+//! Time keeping tests
+
+use super::rtc::{DateTime, civil_from_days, days_from_civil};
+
+#[test_case]
+fn test_epoch_is_day_zero() {
+    assert_eq!(days_from_civil(1970, 1, 1), 0);
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(-1), (1969, 12, 31));
+}
+
+#[test_case]
+fn test_leap_days() {
+    // 2000 is a leap year, being divisible by 400
+    assert_eq!(days_from_civil(2000, 2, 29), 11016);
+    assert_eq!(civil_from_days(11016), (2000, 2, 29));
+    assert_eq!(civil_from_days(11017), (2000, 3, 1));
+    // 2100 isn't, being divisible by 100
+    assert_eq!(
+        days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28),
+        1
+    );
+    assert_eq!(
+        days_from_civil(2024, 3, 1) - days_from_civil(2024, 2, 28),
+        2
+    );
+}
+
+#[test_case]
+fn test_civil_round_trip() {
+    for days in (-800_000..800_000).step_by(997) {
+        let (year, month, day) = civil_from_days(days);
+        assert!((1..=12).contains(&month) && (1..=31).contains(&day));
+        assert_eq!(days_from_civil(year, month, day), days);
+    }
+}
+
+#[test_case]
+fn test_unix_time_round_trip() {
+    let time = DateTime {
+        year: 2000,
+        month: 2,
+        day: 29,
+        hour: 23,
+        minute: 59,
+        second: 58,
+    };
+    assert_eq!(time.to_unix(), 951_868_798);
+    assert_eq!(DateTime::from_unix(951_868_798), time);
+}
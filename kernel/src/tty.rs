@@ -0,0 +1,45 @@
+//! Registered serial terminals
+//!
+//! Drivers register their serial devices through `kernel_api::tty`, and the
+//! rest of the kernel looks them up here by name. A device named by the
+//! `mirror=` boot argument gets the terminal mirrored to it as soon as it is
+//! registered.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use kernel_api::RegisterError;
+pub use kernel_api::tty::{LineCoding, Parity, SerialDevice, StopBits, TtyError};
+use x86_64::instructions::interrupts;
+
+use crate::{info, sync::Mutex};
+
+static DEVICES: Mutex<Vec<Arc<dyn SerialDevice>>> = Mutex::new("TTY_DEVICES", Vec::new());
+
+/// Add a device, replacing one registered under the same name
+pub fn register(device: Arc<dyn SerialDevice>) -> Result<(), RegisterError> {
+    info!("serial device {}", device.name());
+    interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        devices.retain(|registered| registered.name() != device.name());
+        devices.push(device.clone());
+    });
+    #[cfg(feature = "graphics")]
+    crate::output::mirror::device_registered(device);
+    Ok(())
+}
+
+/// Look a device up by name
+pub fn find(name: &str) -> Option<Arc<dyn SerialDevice>> {
+    interrupts::without_interrupts(|| {
+        DEVICES
+            .lock()
+            .iter()
+            .find(|device| device.name() == name)
+            .cloned()
+    })
+}
+
+/// Every registered device
+pub fn devices() -> Vec<Arc<dyn SerialDevice>> {
+    interrupts::without_interrupts(|| DEVICES.lock().clone())
+}